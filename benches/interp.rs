@@ -0,0 +1,88 @@
+//! Benchmarks of the interpreter: calls, arithmetic, and list operations.
+//!
+//! Run with `cargo bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate rusty_scheme;
+
+use criterion::Criterion;
+
+const FIB: &'static str = "(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))";
+
+const TAK: &'static str = "(define (tak x y z)
+                             (if (< y x)
+                                 (tak (tak (- x 1) y z) (tak (- y 1) z x) (tak (- z 1) x y))
+                                 z))";
+
+/// The number of ways to place `n` queens on an `n` by `n` board.
+const NQUEENS: &'static str = "
+(define (ok? row dist placed)
+  (if (null? placed)
+      #t
+      (if (= (car placed) (+ row dist))
+          #f
+          (if (= (car placed) (- row dist))
+              #f
+              (ok? row (+ dist 1) (cdr placed))))))
+(define (try-it x y z)
+  (if (null? x)
+      (if (null? y) 1 0)
+      (+ (if (ok? (car x) 1 z) (try-it (append (cdr x) y) '() (cons (car x) z)) 0)
+         (try-it (cdr x) (cons (car x) y) z))))
+(define (queens n)
+  (let loop ((i n) (board '()))
+    (if (= i 0) (try-it board '() '()) (loop (- i 1) (cons i board)))))";
+
+/// Builds a list of the first `n` integers, and sums it.
+const LIST_SUM: &'static str = "
+(define (iota n)
+  (let loop ((i n) (acc '()))
+    (if (< 0 i) (loop (- i 1) (cons (- i 1) acc)) acc)))
+(define (sum xs)
+  (let loop ((xs xs) (acc 0))
+    (if (null? xs) acc (loop (cdr xs) (+ acc (car xs))))))";
+
+/// A fresh interpreter, after running `definitions`.
+fn interpreter(definitions: &str) -> rusty_scheme::State {
+    let mut s = rusty_scheme::State::new();
+    s.set_history_length(0);
+    s.eval_interactive(definitions).unwrap();
+    s.drop().unwrap();
+    s
+}
+
+/// Benchmarks evaluating `expr`, whose value must be `expected`.  The
+/// expression is compiled once, into a procedure that each iteration calls.
+fn bench(c: &mut Criterion, name: &str, definitions: &str, expr: &str, expected: usize) {
+    let mut s = interpreter(definitions);
+    s.eval_interactive(expr).unwrap();
+    assert_eq!(s.pop::<usize>(), Ok(expected));
+    s.eval_interactive(&format!("(lambda () {})", expr)).unwrap();
+    c.bench_function(name, move |b| {
+        b.iter(|| {
+            s.load(0);
+            s.enqueue(0).unwrap();
+            s.poll_pending_work().unwrap()
+        })
+    });
+}
+
+fn fib(c: &mut Criterion) {
+    bench(c, "fib 20", FIB, "(fib 20)", 6765)
+}
+
+fn tak(c: &mut Criterion) {
+    bench(c, "tak 18 12 6", TAK, "(tak 18 12 6)", 7)
+}
+
+fn nqueens(c: &mut Criterion) {
+    bench(c, "nqueens 8", NQUEENS, "(queens 8)", 92)
+}
+
+fn list_sum(c: &mut Criterion) {
+    bench(c, "list sum 1000", LIST_SUM, "(sum (iota 1000))", 499500)
+}
+
+criterion_group!(benches, fib, tak, nqueens, list_sum);
+criterion_main!(benches);
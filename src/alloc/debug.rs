@@ -3,29 +3,29 @@
 use value;
 use value::{Value, HEADER_TAG, Tags};
 use symbol;
-use super::{PAIR, VECTOR, BYTECODE, RUSTDATA};
+use super::Layout;
 
-/// Consistency checks on the whole heap (in debug mode only) – sloooow.
-pub unsafe fn consistency_check(heap: &[Value]) {
+/// Consistency checks on one space of the heap (in debug mode only) –
+/// sloooow.  `spaces` are all of the spaces that pointers may point into.
+pub unsafe fn consistency_check(heap: &[Value], spaces: &[&[Value]]) {
     if cfg!(debug_assertions) {
         let mut index = 0;
         while index < heap.len() {
             let current = heap[index].clone();
             let len = current.get() as usize & !HEADER_TAG;
-            assert!(len > 1);
-            index += 1;
-            match current.get() as usize & HEADER_TAG {
-                PAIR | VECTOR => {
+            assert!(len > 1, "bad object length {:x} at index {:x}", len, index);
+            match super::layout(current.get(), heap[index + 1].get()) {
+                Some(Layout::Values) => {
                     for x in 1..len {
-                        debug_assert_valid_value(heap, index, x, len);
-                        index += 1;
+                        debug_assert_valid_value(heap, spaces, index + x, x);
                     }
                 }
-                BYTECODE | RUSTDATA => {
+                Some(_) => {
                     // do nothing, these are not scanned
                 }
-                _ => bug!("Strange header {:x}", current.get() as usize),
+                None => bug!("Strange header {:x}", current.get() as usize),
             }
+            index += super::align_word_size(len)
         }
     }
 }
@@ -34,11 +34,17 @@ pub unsafe fn consistency_check(heap: &[Value]) {
 ///
 /// Parameters:
 ///
-/// - `heap`: the current tospace
+/// - `heap`: the space being checked
+/// - `spaces`: all of the spaces that pointers may point into
 /// - `index`: the index into the heap
-unsafe fn debug_assert_valid_value(heap: &[Value], index: usize, x: usize, len: usize) {
+unsafe fn debug_assert_valid_value(heap: &[Value],
+                                   spaces: &[&[Value]],
+                                   index: usize,
+                                   x: usize) {
     let current = heap[index].clone();
-    if current.get() < 0xFF {
+    // Special immediates and characters are not pointers, whatever their
+    // tag bits say.
+    if current.get() < 0xFF || current.as_char().is_some() {
         return;
     }
     match current.tag() {
@@ -47,7 +53,7 @@ unsafe fn debug_assert_valid_value(heap: &[Value], index: usize, x: usize, len:
         }
         Tags::Pair => {
             assert!(current.get() & 0b111 == 0b111);
-            assert_valid_heap_pointer(heap, &current);
+            assert_valid_heap_pointer(spaces, &current);
             if (*current.as_ptr()).get() != value::PAIR_HEADER {
                 bug!("BAD PAIR: header length is \
                       0x{:x} and not \
@@ -58,38 +64,34 @@ unsafe fn debug_assert_valid_value(heap: &[Value], index: usize, x: usize, len:
                      index,
                      x);
             }
-            for i in 1..3 {
-                assert_valid_heap_pointer(heap,
-                                         &*(current.as_ptr().offset(i as isize) as *const Value))
-            }
+            super::visit_children(&current, |child| assert_valid_heap_pointer(spaces, child))
         }
-        Tags::Vector => {
-            assert_valid_heap_pointer(heap, &current);
-            for i in 1..len {
-                assert_valid_heap_pointer(heap, &*current.as_ptr().offset(i as isize))
-            }
+        Tags::Vector | Tags::Function => {
+            assert_valid_heap_pointer(spaces, &current);
+            super::visit_children(&current, |child| assert_valid_heap_pointer(spaces, child))
         }
         Tags::Symbol => {
-            let aligned_size = super::align_word_size(size_of!(symbol::Symbol) /
-                                                      size_of!(usize));
-            assert!(len == aligned_size,
-                    "len = {:x}, aligned_size = {:x}", len, aligned_size);
-            assert_valid_heap_pointer(heap, &current);
-            assert_valid_heap_pointer(heap, &*current.as_ptr().offset(1))
+            // Symbols are not on the heap, so they have no header, but
+            // their value and property list must be valid.
+            let symbol = current.as_ptr() as *const symbol::Symbol;
+            assert_valid_heap_pointer(spaces, &*(*symbol).contents.get());
+            assert_valid_heap_pointer(spaces, &*(*symbol).plist.get())
         }
-        Tags::RustData => /* not scanned */ {}
-        Tags::Function|Tags::RustFunc => panic!("not yet implemented: tag {:?} of {:x}", current.tag(), current.get())
+        Tags::RustData | Tags::RustFunc => /* not scanned */ {}
     }
 }
 
-pub fn assert_valid_heap_pointer(vec: &[Value], i: &Value) {
+pub fn assert_valid_heap_pointer(spaces: &[&[Value]], i: &Value) {
     if cfg!(debug_assertions) {
-        let lower_limit = vec.as_ptr() as usize;
-        let upper_limit = lower_limit + vec.len() * size_of!(usize);
         let contents = i.contents.get();
         let untagged = contents & !0b111;
+        let in_heap = spaces.iter().any(|vec| {
+            let lower_limit = vec.as_ptr() as usize;
+            let upper_limit = lower_limit + vec.len() * size_of!(usize);
+            untagged >= lower_limit && untagged < upper_limit
+        });
         if !(contents & 0b11 == 0 || contents < 0xFF || contents & 0b111 == 0b110 ||
-             (untagged >= lower_limit && untagged < upper_limit)) {
+             contents & 0b111 == value::RUST_FUNC_TAG || i.as_char().is_some() || in_heap) {
             let contents = contents;
             bug!("argument not fixnum or pointing into \
                   tospace: {:x}",
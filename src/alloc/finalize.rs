@@ -0,0 +1,189 @@
+//! Resources: heap objects that own native data, and finalize it when they
+//! die.
+//!
+//! A resource is a heap object with the `Finalized` header tag.  It holds a
+//! pointer to data on the Rust heap, and the function that drops it.  The GC
+//! does not scan resources, since none of their words are `Value`s.
+//!
+//! All live resources are on an intrusive linked list, whose head is
+//! `Heap::resources`.  The GC does not relocate the links.  Instead, after a
+//! collection, `sweep` walks the list (following the links to the *old*
+//! copies of the resources), relinks the resources that survived at their new
+//! addresses, and unlinks the ones that did not.  Their finalizers are run
+//! once the collection is over.
+
+use std::ptr;
+use value::{self, Value, HEADER_TAG};
+use super::Evacuated;
+
+/// Drops the data of a resource.
+pub type Finalizer = unsafe fn(*mut ());
+
+/// The layout of a resource.
+#[repr(C)]
+pub struct Resource {
+    header: usize,
+
+    /// The next resource on the list, or null.
+    next: *mut Resource,
+
+    /// Drops `data`.
+    finalizer: Finalizer,
+
+    /// The native data.
+    data: *mut (),
+}
+
+/// The size of a resource in words.
+pub const SIZEOF_RESOURCE: usize = 4;
+
+/// A finalizer that has to be run.
+pub struct Pending(Finalizer, *mut ());
+
+impl Pending {
+    /// Runs the finalizer.
+    pub fn run(self) {
+        unsafe { (self.0)(self.1) }
+    }
+}
+
+/// Drops a `Box<T>` created by `Box::into_raw`.
+pub unsafe fn drop_box<T>(data: *mut ()) {
+    drop(Box::from_raw(data as *mut T))
+}
+
+/// Initializes the freshly allocated resource at `object`, and links it into
+/// the list starting at `*head`.
+pub unsafe fn init(object: *mut Resource,
+                   head: &mut *mut Resource,
+                   finalizer: Finalizer,
+                   data: *mut ()) {
+    (*object).next = *head;
+    (*object).finalizer = finalizer;
+    (*object).data = data;
+    *head = object
+}
+
+/// The data of the resource `x`, if it is one.
+pub fn data(x: &Value) -> Option<*mut ()> {
+    if x.tag() != value::Tags::RustData {
+        return None
+    }
+    unsafe {
+        let object = x.as_ptr() as *const Resource;
+        if (*object).header & HEADER_TAG == value::HeaderTag::Finalized as usize {
+            Some((*object).data)
+        } else {
+            None
+        }
+    }
+}
+
+/// The data of the resource `x`, if it is one created by
+/// `Heap::alloc_resource` with data of type `T`.  The type is told by the
+/// finalizer, `drop_box::<T>`.  Types that drop the same way may share it, so
+/// `T` should implement `Drop` itself.
+pub fn downcast<T: 'static>(x: &Value) -> Option<&T> {
+    let data = match data(x) {
+        Some(data) => data,
+        None => return None,
+    };
+    unsafe {
+        let object = x.as_ptr() as *const Resource;
+        if (*object).finalizer as usize == drop_box::<T> as usize {
+            Some(&*(data as *const T))
+        } else {
+            None
+        }
+    }
+}
+
+/// Relinks the list starting at `*head` after a collection that evacuated
+/// `from`, returning the finalizers of the resources that died.
+pub unsafe fn sweep(head: &mut *mut Resource, from: &Evacuated) -> Vec<Pending> {
+    let mut dead = vec![];
+    let mut link: *mut *mut Resource = head;
+    let mut current = *head;
+    while !current.is_null() {
+        let resource = if !from.contains(current as usize) {
+            // Not moved by this collection.
+            current
+        } else if (*current).header == HEADER_TAG {
+            // Forwarded, so it survived.
+            let forwarded = &*(current as *const Value).offset(1);
+            forwarded.as_ptr() as *mut Resource
+        } else {
+            dead.push(Pending((*current).finalizer, (*current).data));
+            current = (*current).next;
+            continue
+        };
+        *link = resource;
+        link = &mut (*resource).next;
+        current = (*resource).next;
+    }
+    *link = ptr::null_mut();
+    dead
+}
+
+/// Unlinks all resources on the list starting at `*head`, returning their
+/// finalizers.  Used when the heap itself is dropped.
+pub unsafe fn sweep_all(head: &mut *mut Resource) -> Vec<Pending> {
+    let mut dead = vec![];
+    let mut current = *head;
+    while !current.is_null() {
+        dead.push(Pending((*current).finalizer, (*current).data));
+        current = (*current).next
+    }
+    *head = ptr::null_mut();
+    dead
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use alloc::{self, Heap};
+    use value::NIL;
+
+    struct Counter(Rc<Cell<usize>>);
+
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1)
+        }
+    }
+
+    #[test]
+    fn dead_resources_are_finalized() {
+        let count = Rc::new(Cell::new(0));
+        let mut heap = Heap::new(1 << 8);
+        for _ in 0..3 {
+            heap.alloc_resource(Counter(count.clone())).unwrap();
+        }
+        // Keep the middle one.
+        let keep = heap.stack[1].clone();
+        heap.stack.clear();
+        heap.stack.push(keep);
+        alloc::collect_nursery(&mut heap);
+        assert_eq!(count.get(), 2);
+        alloc::collect(&mut heap);
+        assert_eq!(count.get(), 2);
+        assert!(super::data(&heap.stack[0]).is_some());
+        heap.stack[0] = ::value::Value::new(NIL);
+        alloc::collect(&mut heap);
+        assert_eq!(count.get(), 3);
+    }
+
+    #[test]
+    fn dropping_the_heap_finalizes_everything() {
+        let count = Rc::new(Cell::new(0));
+        {
+            let mut heap = Heap::new(1 << 8);
+            heap.alloc_resource(Counter(count.clone())).unwrap();
+            alloc::collect(&mut heap);
+            heap.alloc_resource(Counter(count.clone())).unwrap();
+            assert_eq!(count.get(), 0);
+        }
+        assert_eq!(count.get(), 2);
+    }
+}
@@ -0,0 +1,224 @@
+//! Heap images: the live objects and the symbols of a heap, in a form that
+//! can be restored into another heap, in another process.
+//!
+//! An image is taken right after a major collection, when all live objects
+//! are in the tenured generation, back to back.  Its words are saved as
+//! they are, except for the `Value`s in objects and symbols, which are
+//! encoded:
+//!
+//! - immediates are unchanged;
+//! - pointers to heap objects keep their tag, but their address becomes the
+//!   offset of the object in words;
+//! - pointers to symbols and to native procedures, which are not on the GC
+//!   heap, become indices into `Image::symbols` and `Image::natives`.
+//!
+//! Offsets and indices are shifted up until they cannot look like an
+//! immediate.  Resources and shared strings (see `shared`) cannot be saved,
+//! since they are not on the GC heap either.
+//!
+//! Restoring checks that every encoded pointer is in range, but not that it
+//! points to the start of an object.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use builtins;
+use value::{self, Value, Tags, HEADER_TAG};
+use super::{align_word_size, collect, layout, Heap, Layout, FINALIZED};
+
+/// Added to offsets and indices before they are tagged.
+const SHIFT: usize = 0x100;
+
+/// A heap image.
+#[derive(Debug, Default)]
+pub struct Image {
+    /// The tenured generation, encoded.
+    pub words: Vec<usize>,
+
+    /// The names of the symbols, and their encoded values and property
+    /// lists.
+    pub symbols: Vec<(String, usize, usize)>,
+
+    /// The names of the native procedures referred to.
+    pub natives: Vec<String>,
+
+    /// `Heap::record_type_count`.
+    pub record_type_count: usize,
+}
+
+/// The `Value`s of the object at the start of `words`, as a range of
+/// offsets, and the size of the object in words.
+fn values(words: &[usize]) -> Result<(Range<usize>, usize), String> {
+    let header = words[0];
+    let size = header & !HEADER_TAG;
+    let len = align_word_size(size);
+    if size < 2 || len > words.len() {
+        return Err("bad object size".to_owned())
+    }
+    let values = match layout(header, words[1]) {
+        Some(Layout::Values) => 1..size,
+        Some(Layout::Weak) => 2..3,
+        Some(Layout::Opaque) if header & HEADER_TAG == FINALIZED => {
+            return Err("resources cannot be saved".to_owned())
+        }
+        Some(Layout::Opaque) => 0..0,
+        Some(Layout::Bytecode) => return Err("bytecode objects cannot be saved".to_owned()),
+        None => return Err("bad object header".to_owned()),
+    };
+    Ok((values, len))
+}
+
+/// Calls `f` on each `Value` in the objects in `words`.
+fn for_each_value<F>(words: &mut [usize], mut f: F) -> Result<(), String>
+    where F: FnMut(&mut usize) -> Result<(), String>
+{
+    let mut offset = 0;
+    while offset < words.len() {
+        let (values, len) = try!(values(&words[offset..]));
+        for word in &mut words[offset + values.start..offset + values.end] {
+            try!(f(word))
+        }
+        offset += len
+    }
+    Ok(())
+}
+
+fn shift(index: usize, tag: usize) -> usize {
+    (index + SHIFT) << 3 | tag
+}
+
+fn unshift(word: usize, len: usize) -> Result<usize, String> {
+    match (word >> 3).checked_sub(SHIFT) {
+        Some(index) if index < len => Ok(index),
+        _ => Err("pointer out of range".to_owned()),
+    }
+}
+
+impl Heap {
+    /// Collects all garbage, and takes an image of what is left.  Only
+    /// objects reachable from symbols are guaranteed to be in it: the values
+    /// on the stack and in handles are roots of the collection, but are not
+    /// saved.
+    pub fn image(&mut self) -> Result<Image, String> {
+        // Every global and every symbol with properties is saved, even one
+        // that nothing refers to any more (which a collection would
+        // otherwise reclaim).
+        let len = self.stack.len();
+        let globals: Vec<_> = self.symbol_table
+                                  .contents
+                                  .values()
+                                  .filter(|symbol| unsafe {
+                                      (*symbol.contents.get()).get() != value::UNBOUND ||
+                                      !(*symbol.plist.get()).is_nil()
+                                  })
+                                  .map(|symbol| {
+                                      Value::new(&**symbol as *const _ as usize |
+                                                 value::SYMBOL_TAG)
+                                  })
+                                  .collect();
+        self.stack.extend(globals);
+        collect(self);
+        self.stack.truncate(len);
+        let base = self.tospace.as_ptr() as usize;
+        let end = base + self.tospace.len() * size_of!(Value);
+        let shared = {
+            let space = self.shared_space();
+            let start = space.as_ptr() as usize;
+            start..start + space.len() * size_of!(Value)
+        };
+        let mut symbols = HashMap::new();
+        let mut image = Image { record_type_count: self.record_type_count, ..Image::default() };
+        for (name, symbol) in &self.symbol_table.contents {
+            symbols.insert(&**symbol as *const _ as usize, image.symbols.len());
+            let (value, plist) = unsafe {
+                ((*symbol.contents.get()).get(), (*symbol.plist.get()).get())
+            };
+            image.symbols.push((name.as_str().to_owned(), value, plist));
+        }
+        let mut natives = HashMap::new();
+        let mut encode = |word: &mut usize| {
+            let x = Value::new(*word);
+            if x.immediatep() {
+                return Ok(())
+            }
+            let (ptr, tag) = (*word & !0b111, *word & 0b111);
+            *word = match x.tag() {
+                Tags::Symbol => shift(*try!(symbols.get(&ptr).ok_or("unknown symbol")), tag),
+                Tags::RustFunc => {
+                    let name = builtins::descriptor(&x).name;
+                    let len = natives.len();
+                    shift(*natives.entry(name).or_insert(len), tag)
+                }
+                _ if ptr >= base && ptr < end => shift((ptr - base) / size_of!(Value), tag),
+                _ if ptr >= shared.start && ptr < shared.end => {
+                    return Err("shared constants cannot be saved".to_owned())
+                }
+                _ => return Err("pointer outside of the heap".to_owned()),
+            };
+            Ok(())
+        };
+        image.words = self.tospace.iter().map(|x| x.get()).collect();
+        try!(for_each_value(&mut image.words, &mut encode));
+        for &mut (_, ref mut value, ref mut plist) in &mut image.symbols {
+            try!(encode(value));
+            try!(encode(plist))
+        }
+        let mut names: Vec<_> = natives.into_iter().collect();
+        names.sort_by_key(|&(_, i)| i);
+        image.natives = names.into_iter().map(|(name, _)| name.to_owned()).collect();
+        Ok(image)
+    }
+
+    /// Restores `image` into this heap, which must be empty.
+    pub fn restore(&mut self, image: &Image) -> Result<(), String> {
+        if !self.tospace.is_empty() || !self.nursery.is_empty() ||
+           !self.symbol_table.contents.is_empty() {
+            return Err("can only restore an image into an empty heap".to_owned())
+        }
+        let symbols: Vec<_> = image.symbols
+                                   .iter()
+                                   .map(|&(ref name, _, _)| self.symbol_table.intern(name))
+                                   .collect();
+        let natives: Vec<_> = try!(image.natives
+                                        .iter()
+                                        .map(|name| {
+                                            builtins::find(name).ok_or_else(|| {
+                                                format!("no native procedure {}", name)
+                                            })
+                                        })
+                                        .collect());
+        let len = image.words.len();
+        // The tospace must not move once pointers into it are decoded.
+        let room = len + self.nursery.capacity();
+        self.tospace.reserve(room);
+        let base = self.tospace.as_ptr() as usize;
+        let decode = |word: &mut usize| {
+            let x = Value::new(*word);
+            if x.immediatep() {
+                return Ok(())
+            }
+            let tag = *word & 0b111;
+            *word = match x.tag() {
+                Tags::Symbol => symbols[try!(unshift(*word, symbols.len()))] as usize | tag,
+                Tags::RustFunc => natives[try!(unshift(*word, natives.len()))].to_value().get(),
+                _ => (base + try!(unshift(*word, len)) * size_of!(Value)) | tag,
+            };
+            Ok(())
+        };
+        let mut words = image.words.clone();
+        try!(for_each_value(&mut words, &decode).map_err(|e| format!("bad heap image: {}", e)));
+        for (&(_, value, plist), &symbol) in image.symbols.iter().zip(&symbols) {
+            let (mut value, mut plist) = (value, plist);
+            try!(decode(&mut value).map_err(|e| format!("bad heap image: {}", e)));
+            try!(decode(&mut plist).map_err(|e| format!("bad heap image: {}", e)));
+            unsafe {
+                *(*symbol).contents.get() = Value::new(value);
+                *(*symbol).plist.get() = Value::new(plist)
+            }
+        }
+        self.tospace.extend(words.into_iter().map(Value::new));
+        self.record_type_count = image.record_type_count;
+        self.last_mem_use = self.tospace.len() + 8 * self.symbol_table.contents.len();
+        Ok(())
+    }
+}
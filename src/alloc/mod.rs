@@ -1,12 +1,41 @@
 //! # The RustyScheme memory allocator and garbage collector.
 //!
 //! This module contains the `RustyScheme` allocator and garbage collector.
-//! The collector is a simple, two-space copying collector using Cheney's
-//! algorithm.
+//! The collector is a generational copying collector using Cheney's
+//! algorithm.  Cheney's algorithm scans the copies in tospace in order,
+//! rather than following pointers recursively, so it needs no stack:
+//! however deeply objects are nested, collecting them cannot overflow the
+//! Rust stack.
+//!
+//! ## Generations
+//!
+//! New objects are allocated in the nursery.  When the nursery fills up, a
+//! minor collection copies the live objects in it into the tenured
+//! generation, which is a pair of semispaces.  When the tenured generation
+//! fills up (or a full collection is requested), a major collection copies
+//! everything live into the other tenured semispace.
+//!
+//! A minor collection does not scan the tenured generation.  Instead, every
+//! store of a pointer into a heap object must call `Heap::write_barrier`,
+//! which records tenured objects that point into the nursery in the
+//! remembered set.  The remembered set is scanned as an extra root.
+//! Symbols live outside of the GC heap, so their values and property lists
+//! are always roots of a minor collection.  A major collection reclaims the
+//! symbols that nothing refers to, except those with a value or a property
+//! list, which are roots.
+//!
+//! ## Roots
+//!
+//! The roots are the VM stack, the symbols, the `RootStack`, and the
+//! `PersistentRoots`.  A `Value`
+//! in a Rust local is *not* a root: any allocation may move its object.
+//! Code that must keep such a value across an allocation roots it in a
+//! `ScratchFrame` (inside the VM) or a `HandleScope` (in embedders).
 //!
 //! ## Finalizer support
 //!
-//! Finalizers for custom objects are supported by:
+//! Finalizers for custom objects (resources, see `finalize`) are supported
+//! by:
 //!
 //! 1. Create an intrusive linked list of objects with finalizers.
 //! 2. During GC, do not relocate the finalizer list pointers.
@@ -29,16 +58,44 @@
 //! TODO finish this.
 
 extern crate libc;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::mem;
 use std::ptr;
+use std::rc::Rc;
 use std::slice;
+use std::sync::Arc;
+use std::time::Instant;
 use super::value;
-use value::{Value, SIZEOF_PAIR, HEADER_TAG, SYMBOL_TAG, Kind};
+use value::{Value, SIZEOF_PAIR, HEADER_TAG, Kind};
+use shared::SharedTable;
 use symbol;
+use flonum;
+use ratio;
 use bytecode;
+use closure;
 
 mod debug;
+mod finalize;
+mod image;
+mod roots;
+mod rust_data;
+mod stats;
+mod trace;
+mod visit;
+mod weak;
+
+pub use self::image::Image;
+pub use self::roots::{PersistentRoots, RootStack, ScratchFrame};
+pub use self::weak::{WeakBox, as_weak_box};
+pub use self::finalize::downcast as resource;
+pub use self::rust_data::RustData;
+pub use self::rust_data::downcast as rust_data;
+pub use self::rust_data::{equal as rust_data_equal, hash as rust_data_hash};
+pub use self::stats::{GcKind, GcStats, HeapStats};
+pub use self::trace::GcTrace;
+pub use self::visit::{Layout, kind, layout, visit_children};
 
 //mod iter;
 /// An allocator for `RustyScheme` objects
@@ -65,7 +122,7 @@ pub trait Allocator {
     fn alloc_port(&mut self, File) -> value::IOPort;
 
     /// Allocates a rustdata, which contains an arbitrary Rust object
-    fn alloc_rustdata<T>(&mut self, object: &T) -> value::RustData;
+    fn alloc_rustdata<T>(&mut self, object: &T) -> RustData;
 
     // /// Allocates a boxed float on the top of the stack.
     // fn alloc_float(&mut self, float: f64) -> value::Float;
@@ -75,6 +132,32 @@ const PAIR: usize = value::HeaderTag::Pair as usize;
 const RUSTDATA: usize = value::HeaderTag::RustData as usize;
 const VECTOR: usize = value::HeaderTag::Vector as usize;
 const BYTECODE: usize = value::HeaderTag::Bytecode as usize;
+const RECORD: usize = value::HeaderTag::Record as usize;
+const CLOSURE: usize = value::HeaderTag::Closure as usize;
+const FINALIZED: usize = value::HeaderTag::Finalized as usize;
+
+/// The most elements a vector can have, so that its size in bytes fits in
+/// an `isize` on any platform.
+pub const MAX_VECTOR_LEN: usize = (::std::isize::MAX as usize >> 3) - 2;
+
+/// The error returned when an allocation would exceed the heap limit.
+///
+/// This is recoverable: the allocation did not happen, but the heap is
+/// still consistent.  It is reported to Scheme code as an error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("out of memory: heap limit exceeded")
+    }
+}
+
+impl From<OutOfMemory> for String {
+    fn from(err: OutOfMemory) -> String {
+        err.to_string()
+    }
+}
 
 /// An instance of the garbage-collected Scheme heap.
 #[derive(Debug)]
@@ -82,40 +165,55 @@ pub struct Heap {
     /// The symbol table
     pub symbol_table: symbol::SymbolTable,
 
-    /// The tospace.
+    /// The nursery, where new objects are allocated.
+    nursery: Vec<Value>,
+
+    /// The tospace of the tenured generation.
     tospace: Vec<Value>,
 
-    /// The fromspace.
+    /// The fromspace of the tenured generation.
     fromspace: Vec<Value>,
-    /// The environment of the current closure.
-    pub environment: *mut value::Vector,
 
-    /// The constants vector of the current closure.
-    pub constants: *const value::Vector,
+    /// The remembered set: tenured objects that may point into the nursery.
+    remembered: HashSet<usize>,
 
     /// The execution stack.
     pub stack: self::Stack,
 
+    /// Local roots, held by handles.
+    pub roots: Rc<RootStack>,
+
+    /// Persistent roots, held by persistent handles.
+    pub persistent: Arc<PersistentRoots>,
+
     /// The approximate amount of memory used last
-    last_mem_use: usize
-}
+    last_mem_use: usize,
+
+    /// The maximum number of words in use, if any.
+    limit: Option<usize>,
+
+    /// The number of record types created so far.  Used to give each record
+    /// type a unique id (see `record`).
+    pub record_type_count: usize,
 
-#[repr(packed)]
-pub struct FinalizedObject {
-    /// The standard header
-    header: usize,
+    /// The list of resources (see `finalize`).
+    resources: *mut finalize::Resource,
 
-    /// Size of user struct
-    user_struct_size: usize,
+    /// Hooks run after each collection.
+    gc_hooks: stats::GcHooks,
 
-    /// Number of associated fields
-    private_fields: usize,
+    /// The shared constants, if any (see `shared`).
+    shared: Option<Arc<SharedTable>>,
 
-    /// Link in finalized object chain
-    link: *const FinalizedObject,
+    /// The traces of the collections since the log was last taken (see
+    /// `trace`).
+    gc_log: Vec<GcTrace>,
 
-    /// User payload
-    payload: Drop,
+    /// The number of words allocated since the heap was created.
+    allocated: u64,
+
+    /// What the collections have done so far (see `stats`).
+    totals: stats::Totals,
 }
 
 use std::cell;
@@ -153,46 +251,90 @@ fn align_word_size(size: usize) -> usize {
     x
 }
 
+/// The address ranges evacuated by a collection.
+///
+/// A minor collection evacuates only the nursery; a major collection
+/// evacuates both the nursery and the tenured fromspace.  Pointers outside
+/// of these ranges are left alone – during a minor collection, these point
+/// into the tenured generation, which does not move.
+struct Evacuated {
+    ranges: [(usize, usize); 2],
+
+    /// What the collection did, if it is traced (see `trace`).
+    trace: trace::Counters,
+}
+
+impl Evacuated {
+    fn new(first: &[Value], second: &[Value]) -> Self {
+        let range = |space: &[Value]| {
+            let start = space.as_ptr() as usize;
+            (start, start + space.len() * size_of!(Value))
+        };
+        Evacuated {
+            ranges: [range(first), range(second)],
+            trace: trace::Counters::default(),
+        }
+    }
+
+    fn contains(&self, pointer: usize) -> bool {
+        self.ranges.iter().any(|&(start, end)| pointer >= start && pointer < end)
+    }
+}
+
 /// Relocates a `Value` in the heap.
 ///
-/// This function relocates a `Value` in the Scheme heap.  It takes two
-/// arguments: `current`, the `Value` being relocated, and `end`, the current
-/// end of tospace.
+/// This function relocates a `Value` in the Scheme heap.  It takes three
+/// arguments: `current`, the `Value` being relocated, `tospace`, the space
+/// being copied into, and `from`, the ranges being evacuated.
 ///
 /// This function takes raw pointers because of aliasing concerns.
-unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mut Vec<Value>) {
-    debug_assert!(tospace.capacity() >= fromspace.len());
-    if false {
-        debug!("Tospace capacity: {}, Fromspace length: {}",
-               tospace.capacity(),
-               fromspace.len());
-    }
+unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, from: &Evacuated) {
     let size_of_value: usize = size_of!(Value);
     (*current).size().map(|size| {
         if size == 0 && (*current).tag() == value::Tags::Symbol {
             // Symbols.
             // These need to be treated specially, since they are not copied.
-            let mut current = current;
-            let mut chain_length = 0;
-            loop {
-                let ptr = (*current).as_ptr() as *mut symbol::Symbol;
-                if (*ptr).alive.get() { return }
-                chain_length += 1;
-                (*ptr).alive.set(true);
-                current = (*ptr).contents.get();
-                if (*current).tag() != value::Tags::Symbol {
-                    debug!("Current: current = {:p}, *current = {:x}",
-                           current,
-                           (*current).get());
-                    debug!("Chain length: {}", chain_length);
-                    return relocate(current, tospace, fromspace)
+            // A symbol's value or property list can itself be a symbol, so
+            // chains of them are followed with a work list rather than by
+            // recursion, which could overflow the stack.
+            let mut pending = vec![];
+            let mut next = Some(current);
+            while let Some(mut current) = next.take().or_else(|| pending.pop()) {
+                let mut chain_length = 0;
+                loop {
+                    let ptr = (*current).as_ptr() as *mut symbol::Symbol;
+                    if (*ptr).alive.get() { break }
+                    chain_length += 1;
+                    (*ptr).alive.set(true);
+                    let plist = (*ptr).plist.get();
+                    if (*plist).tag() == value::Tags::Symbol {
+                        pending.push(plist)
+                    } else {
+                        relocate(plist, tospace, from)
+                    }
+                    current = (*ptr).contents.get();
+                    if (*current).tag() != value::Tags::Symbol {
+                        debug!("Current: current = {:p}, *current = {:x}",
+                               current,
+                               (*current).get());
+                        debug!("Chain length: {}", chain_length);
+                        relocate(current, tospace, from);
+                        break
+                    }
                 }
             }
+            return
         }
         // pointer to head of object being copied
         let pointer: *mut Value = (*current).as_ptr();
 
-        //debug!("HEADER_TAG is {:b}\n", HEADER_TAG);
+        // Objects that are not being evacuated stay where they are.
+        if !from.contains(pointer as usize) {
+            if cfg!(feature = "gc-trace") {
+                from.trace.untouched()
+            }
+            return
+        }
 
         let header = (*pointer).get();
         // Assert that the object header is nonzero.
@@ -202,8 +344,14 @@ unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mu
             debug_assert!(header == HEADER_TAG, "Bad header: {ptr:x}\n", ptr = header);
             // Forwarding pointer detected (this header tag is otherwise absurd,
             // since no object can have a size of zero).
-            *current = (&*pointer.offset(1)).clone()
+            *current = (&*pointer.offset(1)).clone();
+            if cfg!(feature = "gc-trace") {
+                from.trace.forwarded()
+            }
         } else {
+            if cfg!(feature = "gc-trace") {
+                from.trace.copied(header)
+            }
             let len = tospace.len();
 
             // End pointer
@@ -219,16 +367,13 @@ unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mu
             debug_assert!(end as usize & 0b111 == 0,
                           "internal error: relocate: misaligned end pointer");
 
-            // Check that the pointer really is to fromspace
-            debug_assert!((pointer as usize) <
-                          fromspace.as_ptr() as usize + fromspace.len() * size_of!(usize),
-                          "internal error: relocate: attempt to relocate pointer not to fromspace");
-            debug_assert!(pointer as usize >= fromspace.as_ptr() as usize);
+            // Tospace must never be reallocated during a collection.
+            debug_assert!(amount_to_copy + len <= tospace.capacity(),
+                          "internal error: relocate: tospace full");
 
             if cfg!(feature = "memcpy-gc") {
                 let words_to_copy = amount_to_copy * size_of_value;
                 // The amount to copy
-                debug_assert!(amount_to_copy + len <= tospace.capacity());
                 debug_assert!(pointer as usize >= end as usize + words_to_copy ||
                               pointer as usize + words_to_copy <= end as usize);
                 // NOTE: reverse pointer argument order from `memcpy`.
@@ -248,97 +393,228 @@ unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mu
     });
 }
 
-/// Process the heap.
-unsafe fn scavange_heap(tospace: &mut Vec<Value>, fromspace: &mut Vec<Value>) {
-    let mut offset: isize = 0;
-    use std::isize;
-    assert!(tospace.len() <= isize::MAX as usize);
-    assert!(fromspace.len() <= isize::MAX as usize);
-    let current = tospace.as_mut_ptr();
-    while offset < tospace.len() as isize {
-        let header = (*current.offset(offset)).get();
-        let size = header & !HEADER_TAG;
-        let tag = header & HEADER_TAG;
-        assert!(size > 0);
-        offset += 1;
-        match tag {
-            value::HEADER_TAG => /* Forwarding pointer */
-                bug!("Forwarding pointer in tospace"),
-            PAIR => /* Pair */ {
-                debug_assert!(size == 3)
-            }
-            RUSTDATA => /* Rustdata – not scanned by the GC */ {
-                offset += size as isize - 1;
-                continue;
-            }
-            VECTOR => /* Vector-like object */ { }
-            BYTECODE => /* Bytecode object */ {
-                let ptr: *mut bytecode::BCO = current.offset(-1) as *mut _;
-                relocate(bytecode::get_constants_vector(&*ptr).get(), tospace,
-                         fromspace);
-                offset += size as isize - 1;
-                continue;
+/// Relocates the fields of the object starting at `object`.
+///
+/// Returns the size of the object in words.
+unsafe fn scavange_object(object: *mut Value,
+                          tospace: &mut Vec<Value>,
+                          from: &Evacuated) -> usize {
+    let header = (*object).get();
+    let size = header & !HEADER_TAG;
+    let tag = header & HEADER_TAG;
+    assert!(size > 0);
+    match layout(header, (*object.offset(1)).get()) {
+        Some(Layout::Values) => /* Pair or vector-like object */ {
+            debug_assert!(tag != PAIR || size == 3);
+            for i in 1..size {
+                relocate(object.offset(i as isize), tospace, from)
             }
-            _ => bug!("Strange header type {:x}", tag),
         }
+        // Rustdata is not scanned by the GC; weak boxes are fixed up after
+        // the collection.
+        Some(Layout::Opaque) | Some(Layout::Weak) => {}
+        Some(Layout::Bytecode) => {
+            let ptr = object as *mut bytecode::BCO;
+            relocate(bytecode::get_constants_vector(&*ptr).get(), tospace, from)
+        }
+        None if tag == value::HEADER_TAG => bug!("Forwarding pointer in tospace"),
+        None => bug!("Strange header type {:x}", tag),
+    }
+    align_word_size(size)
+}
 
-        if !(*current).leafp() {
-            if !(*current).raw_tag() != SYMBOL_TAG {
-                for _ in 1..size {
-                    relocate(current.offset(offset), tospace, fromspace);
-                    offset += 1
-                }
-            } else {
-                relocate(current.offset(offset), tospace, fromspace);
-                offset += size as isize - 1
-            }
-            offset = align_word_size(offset as usize) as isize
+/// Process the part of the heap starting at `start`.
+unsafe fn scavange_heap(tospace: &mut Vec<Value>,
+                        from: &Evacuated,
+                        start: usize,
+                        weak: &mut Vec<*mut WeakBox>) {
+    use std::isize;
+    assert!(tospace.len() <= isize::MAX as usize);
+    let mut offset = start;
+    while offset < tospace.len() {
+        let object = tospace.as_mut_ptr().offset(offset as isize);
+        if weak::is_weak_box_object(object) {
+            weak.push(object as *mut WeakBox)
         }
+        offset += scavange_object(object, tospace, from)
     }
 }
 
 /// Handles all of the data on the stack.
 unsafe fn scavange_stack(stack: &mut Vec<Value>,
                          tospace: &mut Vec<Value>,
-                         fromspace: &mut Vec<Value>) {
+                         from: &Evacuated) {
     for i in stack.iter_mut() {
-        relocate(i, tospace, fromspace);
+        relocate(i, tospace, from);
     }
 }
 
+/// Handles all of the local roots.
+unsafe fn scavange_roots(roots: &RootStack,
+                         tospace: &mut Vec<Value>,
+                         from: &Evacuated) {
+    roots.for_each(|i| relocate(i, tospace, from))
+}
+
+/// Handles all of the persistent roots.
+unsafe fn scavange_persistent_roots(roots: &PersistentRoots,
+                                    tospace: &mut Vec<Value>,
+                                    from: &Evacuated) {
+    roots.for_each(|i| relocate(i, tospace, from))
+}
+
+/// Checks (in debug mode) that all roots and both generations are sane.
+fn check_heap(heap: &Heap) {
+    if cfg!(debug_assertions) {
+        let spaces: &[&[Value]] = &[&heap.tospace, &heap.nursery, heap.shared_space()];
+        for i in &heap.stack.innards {
+            debug::assert_valid_heap_pointer(spaces, i)
+        }
+        heap.roots.for_each(|i| unsafe { debug::assert_valid_heap_pointer(spaces, &*i) });
+        heap.persistent.for_each(|i| unsafe { debug::assert_valid_heap_pointer(spaces, &*i) });
+        unsafe {
+            debug::consistency_check(&heap.tospace, spaces);
+            debug::consistency_check(&heap.nursery, spaces);
+        }
+    }
+}
+
+/// Performs a minor garbage collection.
+///
+/// Every live object in the nursery is promoted to the tenured generation.
+/// The roots of a minor collection are the stack, the local roots, the
+/// values of all symbols, and the remembered set – tenured objects that
+/// have been modified to point into the nursery.
+///
+/// The tenured generation must have room for the whole nursery, since it
+/// cannot be moved by a minor collection.
+pub fn collect_nursery(heap: &mut Heap) {
+    debug!("Initiated minor garbage collection");
+    let started = Instant::now();
+    let words_before = heap.words_in_use();
+    check_heap(heap);
+    debug_assert!(heap.tospace.capacity() - heap.tospace.len() >= heap.nursery.len());
+    let start = heap.tospace.len();
+    let from = Evacuated::new(&heap.nursery, &[]);
+    let roots = if cfg!(feature = "gc-trace") { trace::roots(heap, GcKind::Minor) } else { vec![] };
+    let dead = unsafe {
+        scavange_stack(&mut heap.stack, &mut heap.tospace, &from);
+        scavange_roots(&heap.roots, &mut heap.tospace, &from);
+        scavange_persistent_roots(&heap.persistent, &mut heap.tospace, &from);
+        for symbol in heap.symbol_table.contents.values() {
+            relocate(symbol.contents.get(), &mut heap.tospace, &from);
+            relocate(symbol.plist.get(), &mut heap.tospace, &from)
+        }
+        for &object in &heap.remembered {
+            scavange_object(object as *mut Value, &mut heap.tospace, &from);
+        }
+        debug!("Roots scavanged");
+        let mut weak = vec![];
+        scavange_heap(&mut heap.tospace, &from, start, &mut weak);
+        debug!("Promoted objects scavanged");
+        weak::fix_weak_boxes(&weak, &from, false);
+        finalize::sweep(&mut heap.resources, &from)
+    };
+    heap.remembered.clear();
+    heap.nursery.clear();
+    // Symbols are only reclaimed by major collections, since a minor
+    // collection does not see references from the tenured generation.
+    for symbol in heap.symbol_table.contents.values() {
+        symbol.alive.set(false)
+    }
+    check_heap(heap);
+    if cfg!(feature = "gc-trace") {
+        trace::record(heap, GcKind::Minor, roots, &from.trace)
+    }
+    finish_collection(heap, GcKind::Minor, started, words_before, dead)
+}
+
 /// Performs a full garbage collection
 pub fn collect(heap: &mut Heap) {
     debug!("Initiated garbage collection");
-    unsafe {
-        if cfg!(debug_assertions) {
-            for i in &heap.stack.innards {
-                debug::assert_valid_heap_pointer(&heap.tospace, i)
-            }
-            debug::consistency_check(&heap.tospace);
-        }
-        debug!("Completed first consistency check");
-        mem::swap(&mut heap.tospace, &mut heap.fromspace);
-        heap.tospace.reserve(heap.fromspace.len() + heap.fromspace.len() / 2);
-        debug!("Fromspace size is {}",
-               heap.fromspace.len() + heap.fromspace.len() / 2);
-        heap.tospace.resize(0, Value::new(0));
-        debug!("Tospace resized to {}", heap.tospace.capacity());
-        debug!("Stack size is {}", heap.stack.len());
-        scavange_stack(&mut heap.stack, &mut heap.tospace, &mut heap.fromspace);
+    let started = Instant::now();
+    let words_before = heap.words_in_use();
+    check_heap(heap);
+    debug!("Completed first consistency check");
+    let live = heap.tospace.len() + heap.nursery.len();
+    mem::swap(&mut heap.tospace, &mut heap.fromspace);
+    heap.tospace.clear();
+    // Leave room for the next minor collection to promote a full nursery.
+    // The heap grows by half of the live data, unless that would exceed
+    // the limit.
+    let needed = live + heap.nursery.capacity();
+    let wanted = needed + live / 2;
+    heap.tospace.reserve(match heap.limit {
+        Some(limit) => ::std::cmp::max(needed, ::std::cmp::min(wanted, limit)),
+        None => wanted,
+    });
+    debug!("Tospace resized to {}", heap.tospace.capacity());
+    debug!("Stack size is {}", heap.stack.len());
+    let from = Evacuated::new(&heap.fromspace, &heap.nursery);
+    let roots = if cfg!(feature = "gc-trace") { trace::roots(heap, GcKind::Major) } else { vec![] };
+    let dead = unsafe {
+        scavange_stack(&mut heap.stack, &mut heap.tospace, &from);
         debug!("Stack scavanged");
-        scavange_heap(&mut heap.tospace, &mut heap.fromspace);
-        debug!("Heap scavanged");
-        heap.symbol_table.fixup();
-        debug!("Fixed up symbol table");
-        if cfg!(debug_assertions) {
-            for i in &heap.stack.innards {
-                debug::assert_valid_heap_pointer(&heap.tospace, i)
+        scavange_roots(&heap.roots, &mut heap.tospace, &from);
+        scavange_persistent_roots(&heap.persistent, &mut heap.tospace, &from);
+        // A symbol with a value or a property list is live even if nothing
+        // refers to it, since interning its name again must find them.
+        for symbol in heap.symbol_table.contents.values() {
+            if (*symbol.contents.get()).get() != value::UNBOUND ||
+               !(*symbol.plist.get()).is_nil() {
+                symbol.alive.set(true);
+                relocate(symbol.contents.get(), &mut heap.tospace, &from);
+                relocate(symbol.plist.get(), &mut heap.tospace, &from)
             }
-            debug::consistency_check(&heap.tospace);
         }
-        debug!("Completed second consistency check");
-        heap.fromspace.resize(0, Value::new(0));
-        heap.last_mem_use = heap.fromspace.capacity() + 8*heap.symbol_table.contents.len()
+        debug!("Roots scavanged");
+        let mut weak = vec![];
+        scavange_heap(&mut heap.tospace, &from, 0, &mut weak);
+        debug!("Heap scavanged");
+        weak::fix_weak_boxes(&weak, &from, true);
+        finalize::sweep(&mut heap.resources, &from)
+    };
+    heap.symbol_table.fixup();
+    debug!("Fixed up symbol table");
+    heap.remembered.clear();
+    heap.nursery.clear();
+    heap.fromspace.clear();
+    check_heap(heap);
+    debug!("Completed second consistency check");
+    heap.last_mem_use = heap.tospace.len() + 8*heap.symbol_table.contents.len();
+    if cfg!(feature = "gc-trace") {
+        trace::record(heap, GcKind::Major, roots, &from.trace)
+    }
+    finish_collection(heap, GcKind::Major, started, words_before, dead)
+}
+
+/// Runs the finalizers of the resources that died in a collection, and
+/// then the `on_gc` hooks.
+fn finish_collection(heap: &mut Heap,
+                     kind: GcKind,
+                     started: Instant,
+                     words_before: usize,
+                     dead: Vec<finalize::Pending>) {
+    let finalized = dead.len();
+    for finalizer in dead {
+        finalizer.run()
+    }
+    let stats = GcStats {
+        kind: kind,
+        words_before: words_before,
+        words_after: heap.words_in_use(),
+        finalized: finalized,
+        duration: started.elapsed(),
+    };
+    heap.totals.record(&stats, heap.allocated);
+    stats::GcHooks::run(&mut heap.gc_hooks, &stats)
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for finalizer in unsafe { finalize::sweep_all(&mut self.resources) } {
+            finalizer.run()
+        }
     }
 }
 
@@ -369,38 +645,42 @@ impl Heap {
     /// Allocates a Scheme pair, which must be rooted by the caller.
     ///
     /// The arguments are stack indexes.
-    pub fn alloc_pair(&mut self, car: usize, cdr: usize) {
+    pub fn alloc_pair(&mut self, car: usize, cdr: usize) -> Result<(), OutOfMemory> {
         if cfg!(debug_assertions) {
             for i in &[car, cdr] {
-                debug::assert_valid_heap_pointer(&self.tospace, &self.stack[*i])
+                debug::assert_valid_heap_pointer(&[&self.tospace,
+                                                   &self.nursery,
+                                                   self.shared_space()],
+                                                 &self.stack[*i])
             }
         }
         // unsafe { consistency_check(&self.tospace) }
         let x = SIZEOF_PAIR;
-        self.alloc_raw(x, value::HeaderTag::Pair);
+        try!(self.alloc_raw(x, value::HeaderTag::Pair));
         let len = if size_of!(usize) < 8 {
-            self.tospace.extend_from_slice(&[self.stack[car].clone(),
+            self.nursery.extend_from_slice(&[self.stack[car].clone(),
                                              self.stack[cdr].clone(),
                                              Value::new(1)]);
-            self.tospace.len() - 4
+            self.nursery.len() - 4
         } else {
-            self.tospace.extend_from_slice(&[self.stack[car].clone(), self.stack[cdr].clone()]);
-            self.tospace.len() - 3
+            self.nursery.extend_from_slice(&[self.stack[car].clone(), self.stack[cdr].clone()]);
+            self.nursery.len() - 3
         };
         let new_value = Value::new(unsafe {
-            self.tospace.as_ptr().offset(len as isize) as usize | value::PAIR_TAG
+            self.nursery.as_ptr().offset(len as isize) as usize | value::PAIR_TAG
         });
         if cfg!(debug_assertions) {
-            debug::assert_valid_heap_pointer(&self.tospace, &new_value);
+            debug::assert_valid_heap_pointer(&[&self.nursery], &new_value);
         }
         self.stack.push(new_value);
         // unsafe { consistency_check(&self.tospace) }
         // debug!("Allocated a pair")
+        Ok(())
     }
 
     pub fn check_must_collect(&mut self) {
         let should_collect = 8*self.symbol_table.contents.len() +
-            self.tospace.capacity() >
+            self.tospace.len() >
             ((2*self.last_mem_use) + if cfg!(debug_assertions) {
                 1
             } else{
@@ -411,87 +691,341 @@ impl Heap {
         }
     }
 
-    /// FIXME use enum for tag
+    /// Makes room in the nursery for an object of `words` words.
+    fn make_room(&mut self, words: usize) -> Result<(), OutOfMemory> {
+        if self.tospace.capacity() - self.tospace.len() < self.nursery.len() {
+            // The tenured generation has no room to promote the nursery
+            // into, so collect everything.
+            collect(self)
+        } else {
+            collect_nursery(self)
+        }
+        if self.nursery.capacity() < words {
+            // The nursery is empty, so growing it moves nothing.
+            if self.limit.map_or(false, |limit| self.tospace.capacity() + words > limit) {
+                return Err(OutOfMemory)
+            }
+            self.nursery.reserve(words)
+        }
+        Ok(())
+    }
+
+    /// The number of words in use, including garbage not yet collected.
+    fn words_in_use(&self) -> usize {
+        self.tospace.len() + self.nursery.len()
+    }
+
+    /// Makes this heap use the shared constants `table`: string literals and
+    /// symbol names in it are not copied into the heap from now on.
+    pub fn share(&mut self, table: Arc<SharedTable>) {
+        self.symbol_table.names.share(table.clone());
+        self.shared = Some(table)
+    }
+
+    /// The shared string equal to `s`, if there is one.
+    pub fn shared_string(&self, s: &str) -> Option<Value> {
+        self.shared.as_ref().and_then(|table| table.string(s))
+    }
+
+    /// Is `x` a shared string, which must not be changed?
+    pub fn is_shared(&self, x: &Value) -> bool {
+        let space = self.shared_space();
+        let start = space.as_ptr() as usize;
+        let ptr = x.get() & !0b111;
+        !x.immediatep() && ptr >= start && ptr < start + space.len() * size_of!(Value)
+    }
+
+    /// The shared strings, if any, as a space.
+    fn shared_space(&self) -> &[Value] {
+        self.shared.as_ref().map_or(&[], |table| table.space())
+    }
+
+    /// Takes the traces of the collections since the last call, oldest
+    /// first.  Collections are only traced with the `gc-trace` feature.
+    pub fn take_gc_log(&mut self) -> Vec<GcTrace> {
+        mem::replace(&mut self.gc_log, vec![])
+    }
+
+    /// Registers `hook` to be run after every collection, with statistics
+    /// about it.  Hooks cannot access the heap.
+    pub fn on_gc<F: FnMut(&GcStats) + 'static>(&mut self, hook: F) {
+        self.gc_hooks.push(Box::new(hook))
+    }
+
+    /// The number of bytes allocated since the heap was created, including
+    /// those since collected.
+    pub fn bytes_allocated(&self) -> u64 {
+        self.allocated * size_of!(Value) as u64
+    }
+
+    /// Statistics about the heap: what it holds, and what the collections
+    /// so far have done.  Counting the objects walks the whole heap.
+    pub fn stats(&self) -> HeapStats {
+        let mut objects = BTreeMap::new();
+        for space in &[&self.tospace, &self.nursery] {
+            let mut offset = 0;
+            while offset < space.len() {
+                let header = space[offset].get();
+                *objects.entry(kind(header)).or_insert(0) += 1;
+                offset += align_word_size(header & !HEADER_TAG)
+            }
+        }
+        let totals = &self.totals;
+        HeapStats {
+            live_bytes: totals.words_after * size_of!(Value),
+            bytes_in_use: self.words_in_use() * size_of!(Value),
+            allocations_since_gc: totals.allocations,
+            bytes_since_gc: (self.allocated - totals.allocated_before) * size_of!(Value) as u64,
+            bytes_allocated: self.bytes_allocated(),
+            minor_collections: totals.minor_collections,
+            major_collections: totals.major_collections,
+            total_pause: totals.total_pause,
+            max_pause: totals.max_pause,
+            last_pause: totals.last_pause,
+            objects: objects,
+        }
+    }
+
+    /// Opens a scratch frame, in which `Value`s held in Rust locals can be
+    /// rooted across allocations.
+    pub fn scratch_frame(&self) -> ScratchFrame {
+        RootStack::scratch_frame(&self.roots)
+    }
+
+    /// Sets the maximum size of the heap in bytes, or removes the limit if
+    /// `limit` is `None`.
+    ///
+    /// Allocations that would take the heap past the limit, even after a
+    /// full collection, fail with `OutOfMemory`.  The limit does not
+    /// include the stack or the symbol table.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit.map(|bytes| bytes / size_of!(Value))
+    }
+
+    /// The maximum size of the heap in bytes, if any.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit.map(|words| words * size_of!(Value))
+    }
+
+    /// Allocates the header of an object of `space` words.
+    ///
+    /// Returns a pointer to the object, and the length the nursery must
+    /// have once the caller has pushed the rest of the object.
     pub fn alloc_raw(&mut self, space: usize,
-                     tag: value::HeaderTag) -> (*mut libc::c_void, usize) {
+                     tag: value::HeaderTag) -> Result<(*mut libc::c_void, usize), OutOfMemory> {
         debug_assert!(space > 1);
         let real_space = align_word_size(space);
-        let tospace_space = self.tospace.capacity() - self.tospace.len();
-        if tospace_space < real_space  {
-            collect(self);
+        if let Some(limit) = self.limit {
+            if self.words_in_use() + real_space > limit {
+                collect(self);
+                if self.words_in_use() + real_space > limit {
+                    return Err(OutOfMemory)
+                }
+            }
+        }
+        let nursery_space = self.nursery.capacity() - self.nursery.len();
+        if nursery_space < real_space  {
+            try!(self.make_room(real_space));
         } else {
             self.check_must_collect()
         }
-        debug_assert!(((self.tospace.len()*size_of!(usize)) & 7) == 0);
+        debug_assert!(((self.nursery.len()*size_of!(usize)) & 7) == 0);
+        self.allocated += real_space as u64;
+        self.totals.allocations += 1;
+        let start = self.nursery.len();
         let alloced_ptr = unsafe {
-            self.tospace.as_ptr().offset(self.tospace.len() as isize)
+            self.nursery.as_ptr().offset(start as isize)
         };
-        self.tospace.push(Value::new(space | tag as usize));
+        self.nursery.push(Value::new(space | tag as usize));
         debug_assert!(alloced_ptr as usize & 7 == 0);
-        (alloced_ptr as *mut libc::c_void,
-         self.tospace.len() + real_space)
+        Ok((alloced_ptr as *mut libc::c_void,
+            start + real_space))
+    }
+
+    /// Completes an object allocated with `alloc_raw`, whose contents the
+    /// caller has written directly.  `len` is the length returned by
+    /// `alloc_raw`.
+    ///
+    /// Unsafe because the GC will scan the object, so its contents must be
+    /// valid before the next allocation.
+    pub unsafe fn finish_raw(&mut self, len: usize) {
+        debug_assert!(len <= self.nursery.capacity());
+        self.nursery.set_len(len)
     }
 
     /// Allocates a vector.  The `elements` array must be rooted for the GC.
-    pub fn alloc_vector(&mut self, start: usize, end: usize) {
+    pub fn alloc_vector(&mut self, start: usize, end: usize) -> Result<(), OutOfMemory> {
         assert!(end >= start);
-        let (value_ptr, final_len) = self.alloc_raw(end - start + 2,
-                                                    value::HeaderTag::Vector);
-        self.tospace.push(Value::new(0));
+        let (value_ptr, final_len) = try!(self.alloc_raw(end - start + 2,
+                                                         value::HeaderTag::Vector));
+        self.nursery.push(Value::new(0));
         let ptr = value_ptr as usize | value::VECTOR_TAG;
         {
             let stack = &self.stack[start..end];
-            self.tospace.extend_from_slice(stack);
+            self.nursery.extend_from_slice(stack);
         }
-        unsafe { self.tospace.set_len(final_len) };
+        unsafe { self.nursery.set_len(final_len) };
         self.stack.push(Value::new(ptr));
+        Ok(())
+    }
+
+    /// Allocates a vector of `len` elements, each `self.stack[fill]`, and
+    /// pushes it.  `len` must be at most `MAX_VECTOR_LEN`.
+    pub fn alloc_filled_vector(&mut self, len: usize, fill: usize) -> Result<(), OutOfMemory> {
+        assert!(len <= MAX_VECTOR_LEN);
+        let (value_ptr, final_len) = try!(self.alloc_raw(len + 2, value::HeaderTag::Vector));
+        self.nursery.push(Value::new(0));
+        // The allocation may have moved the fill, so it is read only now.
+        let fill = self.stack[fill].clone();
+        self.nursery.extend(::std::iter::repeat(fill).take(len));
+        unsafe { self.nursery.set_len(final_len) };
+        self.stack.push(Value::new(value_ptr as usize | value::VECTOR_TAG));
+        Ok(())
+    }
+
+    /// Allocates a weak box whose referent is `self.stack[referent]`, and
+    /// pushes it.
+    pub fn alloc_weak_box(&mut self, referent: usize) -> Result<(), OutOfMemory> {
+        let (value_ptr, final_len) = try!(self.alloc_raw(weak::SIZEOF_WEAK_BOX,
+                                                         value::HeaderTag::RustData));
+        self.nursery.push(Value::new(weak::WEAK_BOX));
+        let x = self.stack[referent].clone();
+        self.nursery.push(x);
+        unsafe { self.nursery.set_len(final_len) };
+        self.stack.push(Value::new(value_ptr as usize | value::RUST_DATA_TAG));
+        Ok(())
+    }
+
+    /// Allocates a flonum holding `x`, and pushes it.
+    pub fn alloc_flonum(&mut self, x: f64) -> Result<(), OutOfMemory> {
+        let (value_ptr, final_len) = try!(self.alloc_raw(flonum::SIZEOF_FLONUM,
+                                                         value::HeaderTag::RustData));
+        self.nursery.push(Value::new(flonum::FLONUM));
+        self.nursery.push(Value::new(x.to_bits() as usize));
+        unsafe { self.nursery.set_len(final_len) };
+        self.stack.push(Value::new(value_ptr as usize | value::RUST_DATA_TAG));
+        Ok(())
+    }
+
+    /// Allocates a ratio of `numerator` to `denominator`, which must be in
+    /// lowest terms (see `ratio`), and pushes it.
+    pub fn alloc_ratio(&mut self, numerator: isize, denominator: isize) -> Result<(), OutOfMemory> {
+        let (value_ptr, final_len) = try!(self.alloc_raw(ratio::SIZEOF_RATIO,
+                                                         value::HeaderTag::RustData));
+        self.nursery.push(Value::new(ratio::RATIO));
+        self.nursery.push(Value::new(numerator as usize));
+        self.nursery.push(Value::new(denominator as usize));
+        unsafe { self.nursery.set_len(final_len) };
+        self.stack.push(Value::new(value_ptr as usize | value::RUST_DATA_TAG));
+        Ok(())
     }
 
-    /// Allocates a closure. `src` and `src2` are as found in the opcode.
-    pub fn alloc_closure(&mut self, src: u8, src2: u8, upvalues: usize) {
-        let argcount = (src as u16) << 7 | src2 as u16;
-        let vararg = src & ::std::i8::MIN as u8 == 0;
-        let stack_len = self.stack.len();
-        let (value_ptr, final_len) = self.alloc_raw(upvalues + 2,
-                                                    value::HeaderTag::Vector);
-        let ptr = {
-            let elements = &self.stack[stack_len - upvalues..stack_len];
-            let ptr = value_ptr as usize | value::VECTOR_TAG;
-            self.tospace.push(Value::new((argcount as usize) << 2 |
-                                         (-(vararg as isize) as usize &
-                                          ::std::isize::MIN as usize)));
-            self.tospace.extend_from_slice(elements);
-            unsafe { self.tospace.set_len(final_len) };
-            ptr
+    /// Allocates a resource owning `data`, which is dropped when the
+    /// resource is collected, and pushes it.
+    pub fn alloc_resource<T: 'static>(&mut self, data: T) -> Result<(), OutOfMemory> {
+        let (value_ptr, final_len) = try!(self.alloc_raw(finalize::SIZEOF_RESOURCE,
+                                                         value::HeaderTag::Finalized));
+        unsafe {
+            self.finish_raw(final_len);
+            finalize::init(value_ptr as *mut finalize::Resource,
+                           &mut self.resources,
+                           finalize::drop_box::<T>,
+                           Box::into_raw(Box::new(data)) as *mut ());
+        }
+        self.stack.push(Value::new(value_ptr as usize | value::RUST_DATA_TAG));
+        Ok(())
+    }
+
+    /// Allocates a record (or record type) whose contents are
+    /// `self.stack[start..end]`, and pushes it.  See `record` for the layout.
+    pub fn alloc_record(&mut self, start: usize, end: usize) -> Result<(), OutOfMemory> {
+        assert!(end > start);
+        let (value_ptr, final_len) = try!(self.alloc_raw(end - start + 1,
+                                                         value::HeaderTag::Record));
+        {
+            let stack = &self.stack[start..end];
+            self.nursery.extend_from_slice(stack);
+        }
+        unsafe { self.nursery.set_len(final_len) };
+        self.stack.push(Value::new(value_ptr as usize | value::VECTOR_TAG));
+        Ok(())
+    }
+
+    /// Allocates a closure of the function `function` (see `closure` for the
+    /// layout), and pushes it.  Its constants vector is `self.stack[constants]`,
+    /// and its upvalues are the `upvalues` values just below that.
+    pub fn alloc_closure(&mut self, function: usize, constants: usize, upvalues: usize)
+                         -> Result<(), OutOfMemory> {
+        assert!(constants >= upvalues);
+        let (value_ptr, final_len) = try!(self.alloc_raw(upvalues + closure::UPVALUES_OFFSET,
+                                                         value::HeaderTag::Closure));
+        {
+            let stack = &self.stack[constants - upvalues..constants];
+            self.nursery.push(Value::new(function << 2));
+            self.nursery.push(self.stack[constants].clone());
+            self.nursery.extend_from_slice(stack);
+        }
+        unsafe { self.nursery.set_len(final_len) };
+        self.stack.push(Value::new(value_ptr as usize | value::FUNCTION_TAG));
+        Ok(())
+    }
+
+    /// The write barrier.
+    ///
+    /// Must be called whenever `new` is stored into a field of the heap
+    /// object `object`.  If `object` is tenured and `new` is in the nursery,
+    /// `object` is added to the remembered set, so that the next minor
+    /// collection treats it as a root.
+    pub fn write_barrier(&mut self, object: &Value, new: &Value) {
+        if object.immediatep() || new.immediatep() {
+            return
+        }
+        let (object, new) = unsafe { (object.as_ptr() as usize, new.as_ptr() as usize) };
+        let contains = |space: &[Value], ptr: usize| {
+            let start = space.as_ptr() as usize;
+            ptr >= start && ptr < start + space.len() * size_of!(Value)
         };
-        self.stack.push(Value::new(ptr));
+        if contains(&self.nursery, new) && contains(&self.tospace, object) {
+            self.remembered.insert(object);
+        }
     }
 
-    /// Create an instance of the garage collector
+    /// Create an instance of the garage collector, with room for `size`
+    /// words in each generation.
     pub fn new(size: usize) -> Self {
+        Self::with_capacity(size * size_of!(Value))
+    }
+
+    /// Create an instance of the garbage collector, with room for `bytes`
+    /// bytes in each generation.  The heap grows as needed, up to the limit
+    /// set by `set_limit`.
+    pub fn with_capacity(bytes: usize) -> Self {
+        let size = bytes / size_of!(Value);
         Heap {
+            nursery: Vec::with_capacity(size),
             fromspace: Vec::with_capacity(size),
             tospace: Vec::with_capacity(size),
+            remembered: HashSet::new(),
             symbol_table: symbol::SymbolTable::default(),
-            environment: ptr::null_mut(),
-            constants: ptr::null(),
             stack: Stack { innards: Vec::with_capacity(1 << 16) },
-            last_mem_use: 1<<16
+            roots: Rc::new(RootStack::default()),
+            persistent: Arc::new(PersistentRoots::default()),
+            last_mem_use: 1<<16,
+            limit: None,
+            record_type_count: 0,
+            resources: ptr::null_mut(),
+            gc_hooks: stats::GcHooks::default(),
+            shared: None,
+            gc_log: vec![],
+            allocated: 0,
+            totals: stats::Totals::default(),
         }
     }
 
     /// Interns a symbol.
     pub fn intern(&mut self, string: &str) {
-        use symbol::Symbol;
-        use std::rc::Rc;
-        {
-            let rc = Rc::new(string.to_owned());
-            let val = self.symbol_table.contents
-                                       .entry(rc.clone())
-                                       .or_insert_with(|| Box::new(Symbol::new(rc)));
-            self.stack.push(Value::new(&mut(**val) as *mut _ as usize |
-                                       value::SYMBOL_TAG))
-        }
+        let symbol = self.symbol_table.intern(string);
+        self.stack.push(Value::new(symbol as usize | value::SYMBOL_TAG));
         self.check_must_collect()
     }
 
@@ -512,6 +1046,9 @@ impl Heap {
         match self.stack.pop().map(|x| x.kind()) {
             Some(Kind::Symbol(ptr)) => {
                 let contents = unsafe { &*(*ptr).contents.get() };
+                if contents.get() == value::UNBOUND {
+                    return Err(format!("Unbound variable: {}", unsafe { (*ptr).name() }))
+                }
                 Ok(self.stack.push(contents.clone()))
             }
             _ => Err("Attempt to get the value of a non-symbol".to_owned()),
@@ -534,11 +1071,11 @@ mod tests {
                PAIR_HEADER,
                SIZEOF_PAIR);
         heap.stack.push(zero);
-        heap.alloc_pair(0, 0);
+        heap.alloc_pair(0, 0).unwrap();
         heap.stack[0] = heap.stack.pop().unwrap();
         // debug!("{:?}", heap);
         for i in 1..((1 << 11)) {
-            heap.alloc_pair(0, 0);
+            heap.alloc_pair(0, 0).unwrap();
             assert_eq!(heap.stack.len(), 2);
             assert_eq!(heap.stack[1].tag(), Tags::Pair);
             heap.stack[0] = heap.stack.pop().unwrap();
@@ -556,7 +1093,7 @@ mod tests {
             assert_valid(&heap);
             // super::collect(&mut heap);
             assert_valid(&heap);
-            assert!(heap.tospace.len() >= 3 * i)
+            assert!(heap.tospace.len() + heap.nursery.len() >= 3 * i)
     }
     heap.stack.pop();
     assert!(heap.stack.len() == 0);
@@ -565,4 +1102,235 @@ mod tests {
     super::collect(&mut heap);
     assert!(heap.tospace.len() == 0)
 }
+
+    #[test]
+    fn write_barrier_keeps_young_objects_alive() {
+        let mut heap = Heap::new(1 << 4);
+        heap.stack.push(Value::nil());
+        heap.alloc_pair(0, 0).unwrap();
+        // The pair at index 1 is now tenured.
+        super::collect(&mut heap);
+        heap.alloc_pair(0, 0).unwrap();
+        let young = heap.stack.pop().unwrap();
+        let old = heap.stack[1].clone();
+        old.set_car(young.clone()).unwrap();
+        heap.write_barrier(&old, &young);
+        assert_eq!(heap.remembered.len(), 1);
+        super::collect_nursery(&mut heap);
+        assert!(heap.remembered.is_empty());
+        assert_eq!(heap.nursery.len(), 0);
+        let car = heap.stack[1].car().unwrap();
+        assert_eq!(car.tag(), Tags::Pair);
+        let start = heap.tospace.as_ptr() as usize;
+        let ptr = unsafe { car.as_ptr() } as usize;
+        assert!(ptr >= start && ptr < start + heap.tospace.len() * size_of!(Value));
+        assert_eq!(car.car().unwrap().get(), NIL);
+    }
+
+    #[test]
+    fn property_lists_survive_collections() {
+        let mut heap = Heap::new(1 << 4);
+        heap.intern("x");
+        heap.stack.push(Value::new(4 << 2));
+        heap.alloc_pair(1, 1).unwrap();
+        let symbol = match heap.stack[0].kind() {
+            Kind::Symbol(symbol) => symbol,
+            _ => unreachable!(),
+        };
+        unsafe { *(*symbol).plist.get() = heap.stack.pop().unwrap() };
+        heap.stack.truncate(1);
+        super::collect_nursery(&mut heap);
+        super::collect(&mut heap);
+        let plist = unsafe { (*(*symbol).plist.get()).clone() };
+        assert_eq!(plist.car().unwrap().get(), 4 << 2);
+    }
+
+    #[test]
+    fn globals_survive_collections() {
+        let mut heap = Heap::new(1 << 4);
+        heap.stack.push(Value::new(4 << 2));
+        heap.alloc_pair(0, 0).unwrap();
+        heap.intern("x");
+        heap.store_global().unwrap();
+        heap.stack.clear();
+        super::collect(&mut heap);
+        heap.intern("x");
+        heap.load_global().unwrap();
+        assert_eq!(heap.stack.pop().unwrap().car().unwrap().get(), 4 << 2);
+    }
+
+    #[test]
+    fn children_are_visited_as_the_collector_sees_them() {
+        let children = |x: &Value| {
+            let mut children = vec![];
+            visit_children(x, |child| children.push(child.get()));
+            children
+        };
+        let mut heap = Heap::new(1 << 4);
+        heap.stack.push(Value::new(TRUE));
+        heap.stack.push(Value::new(4));
+        heap.alloc_pair(0, 1).unwrap();
+        heap.alloc_vector(0, 3).unwrap();
+        let (pair, vector) = (heap.stack[2].clone(), heap.stack[3].clone());
+        assert_eq!(children(&pair), vec![TRUE, 4]);
+        assert_eq!(children(&vector), vec![TRUE, 4, pair.get()]);
+        heap.alloc_weak_box(2).unwrap();
+        let weak = heap.stack.pop().unwrap();
+        assert!(children(&weak).is_empty());
+        heap.intern("x");
+        let symbol = heap.stack.pop().unwrap();
+        assert!(children(&symbol).is_empty());
+        assert!(children(&Value::nil()).is_empty());
+    }
+
+    #[test]
+    fn gc_hooks_see_every_collection() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut heap = Heap::new(1 << 4);
+        {
+            let seen = seen.clone();
+            heap.on_gc(move |stats| seen.borrow_mut().push(*stats));
+        }
+        heap.stack.push(Value::nil());
+        heap.alloc_pair(0, 0).unwrap();
+        heap.alloc_pair(0, 0).unwrap();
+        heap.stack.truncate(2);
+        super::collect_nursery(&mut heap);
+        super::collect(&mut heap);
+        let seen = seen.borrow();
+        assert_eq!(seen.iter().map(|stats| stats.kind).collect::<Vec<_>>(),
+                   vec![GcKind::Minor, GcKind::Major]);
+        assert_eq!(seen[0].words_before, 2 * SIZEOF_PAIR);
+        assert_eq!(seen[0].words_after, SIZEOF_PAIR);
+        assert_eq!(seen[1].words_after, SIZEOF_PAIR);
+    }
+
+    #[test]
+    fn heap_stats_count_objects_and_collections() {
+        use api::SchemeValue;
+        let mut heap = Heap::new(1 << 8);
+        heap.stack.push(Value::nil());
+        heap.alloc_pair(0, 0).unwrap();
+        let string = "a string longer than a word".to_owned().to_value(&mut heap).unwrap();
+        heap.stack.push(string);
+        heap.alloc_vector(0, 3).unwrap();
+        let stats = heap.stats();
+        assert_eq!(stats.allocations_since_gc, 3);
+        assert_eq!(stats.bytes_since_gc, stats.bytes_allocated);
+        assert_eq!(stats.bytes_in_use as u64, stats.bytes_allocated);
+        assert_eq!((stats.minor_collections, stats.major_collections), (0, 0));
+        assert_eq!(stats.last_pause, None);
+        assert_eq!(stats.objects.get("pair"), Some(&1));
+        assert_eq!(stats.objects.get("rust data"), Some(&1));
+        assert_eq!(stats.objects.get("vector"), Some(&1));
+        heap.stack.truncate(3);
+        super::collect(&mut heap);
+        let after = heap.stats();
+        assert_eq!(after.major_collections, 1);
+        assert_eq!(after.allocations_since_gc, 0);
+        assert_eq!(after.bytes_since_gc, 0);
+        assert_eq!(after.bytes_allocated, stats.bytes_allocated);
+        assert_eq!(after.live_bytes, after.bytes_in_use);
+        assert!(after.live_bytes < stats.bytes_in_use);
+        assert_eq!(after.objects.get("vector"), None);
+        assert_eq!(after.objects.get("rust data"), Some(&1));
+        assert_eq!(Some(after.max_pause), after.last_pause);
+    }
+
+    #[cfg(feature = "gc-trace")]
+    #[test]
+    fn identical_heaps_leave_identical_traces() {
+        let run = || {
+            let mut heap = Heap::new(1 << 4);
+            heap.stack.push(Value::nil());
+            heap.alloc_pair(0, 0).unwrap();
+            heap.alloc_pair(1, 1).unwrap();
+            heap.stack.truncate(2);
+            super::collect_nursery(&mut heap);
+            super::collect(&mut heap);
+            heap.take_gc_log()
+        };
+        let log = run();
+        assert_eq!(log, run());
+        assert_eq!(log.iter().map(|trace| trace.kind).collect::<Vec<_>>(),
+                   vec![GcKind::Minor, GcKind::Major]);
+        assert_eq!(log[0].copied.get("pair"), Some(&1));
+        assert_eq!(log[0].roots[0], ("stack", 2));
+        assert_eq!(log[1].copied.get("pair"), Some(&1));
+        assert_eq!(log[0].layout_hash, log[1].layout_hash);
+    }
+
+    #[test]
+    fn deeply_nested_objects_survive_collection() {
+        let mut heap = Heap::new(1 << 4);
+        heap.stack.push(Value::new(4));
+        heap.stack.push(Value::nil());
+        for _ in 0..10000 {
+            heap.alloc_pair(0, 1).unwrap();
+            heap.stack[0] = heap.stack.pop().unwrap();
+        }
+        super::collect(&mut heap);
+        let mut x = heap.stack[0].clone();
+        let mut depth = 0;
+        while x.tag() == Tags::Pair {
+            x = x.car().unwrap();
+            depth += 1
+        }
+        assert_eq!((depth, x.get()), (10000, 4));
+    }
+
+    #[test]
+    fn scratch_roots_follow_their_objects() {
+        let mut heap = Heap::new(1 << 4);
+        heap.stack.push(Value::nil());
+        heap.alloc_pair(0, 0).unwrap();
+        let pair = heap.stack.pop().unwrap();
+        heap.stack.clear();
+        {
+            let frame = heap.scratch_frame();
+            let slot = frame.root(pair);
+            let before = slot.get();
+            super::collect(&mut heap);
+            assert!(slot.get() != before);
+            assert_eq!(slot.car().unwrap().get(), NIL);
+            assert_eq!(heap.roots.len(), 1);
+        }
+        assert!(heap.roots.is_empty());
+        super::collect(&mut heap);
+        assert_eq!(heap.tospace.len(), 0);
+    }
+
+    #[test]
+    fn allocation_fails_past_the_limit() {
+        let mut heap = Heap::new(1 << 4);
+        heap.set_limit(Some(1 << 12));
+        assert_eq!(heap.limit(), Some(1 << 12));
+        heap.stack.push(Value::nil());
+        let mut pairs = 0;
+        while heap.alloc_pair(0, 0).is_ok() {
+            heap.stack[0] = heap.stack.pop().unwrap();
+            pairs += 1
+        }
+        assert!(pairs > 0 && pairs * SIZEOF_PAIR * size_of!(Value) <= 1 << 12);
+        assert_eq!(heap.stack.len(), 1);
+        // Dropping the list makes room again.
+        heap.stack[0] = Value::nil();
+        heap.alloc_pair(0, 0).unwrap();
+        heap.set_limit(None);
+        for _ in 0..pairs + 1 {
+            heap.alloc_pair(0, 0).unwrap();
+        }
+    }
+
+    #[test]
+    fn heap_grows_for_large_objects() {
+        let mut heap = Heap::new(1 << 4);
+        for _ in 0..(1 << 8) {
+            heap.stack.push(Value::nil())
+        }
+        heap.alloc_vector(0, 1 << 8).unwrap();
+        assert_eq!(heap.stack.pop().unwrap().size(), Some((1 << 8) + 2));
+    }
 }
@@ -0,0 +1,265 @@
+//! Storage for local GC roots.
+//!
+//! Local roots live in a `RootStack`, which is a stack of `Value` slots that
+//! the garbage collector scans (and relocates) along with the VM stack.  The
+//! slots are allocated in fixed-size chunks that are never moved or freed
+//! while the heap is alive, so a reference to a slot stays valid even when
+//! more roots are pushed.  Handles (see `api::handle`) are just references
+//! to these slots.
+//!
+//! Inside the VM, `ScratchFrame`s serve the same purpose: a `Value` held in
+//! a Rust local across an allocation must be rooted in a scratch frame, or
+//! the GC may move its object out from under it.
+//!
+//! Persistent roots live in `PersistentRoots` instead, which is not tied to
+//! a scope.  Its slots are reference counted, and may be released from any
+//! thread, so it is protected by a mutex.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::{self, ThreadId};
+use value::Value;
+
+/// The number of slots in each chunk of a `RootStack`.
+const CHUNK_SIZE: usize = 256;
+
+/// A stack of GC roots.
+///
+/// Roots are pushed and popped in LIFO order by handle scopes.  The
+/// `RootStack` also tracks how many scopes are open, so that a handle can
+/// only be created in the innermost scope – creating one in an outer scope
+/// would place it in a slot that the inner scope is about to release.
+#[derive(Debug)]
+pub struct RootStack {
+    /// The chunks of slots.  Chunks are only ever appended.
+    chunks: RefCell<Vec<Box<[Value]>>>,
+
+    /// The number of slots in use.
+    len: Cell<usize>,
+
+    /// The number of currently open scopes.
+    depth: Cell<usize>,
+}
+
+impl Default for RootStack {
+    fn default() -> Self {
+        RootStack {
+            chunks: RefCell::new(vec![]),
+            len: Cell::new(0),
+            depth: Cell::new(0),
+        }
+    }
+}
+
+impl RootStack {
+    /// The number of live roots.
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    /// Is this stack empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes `value` as a new root, returning a pointer to its slot.
+    ///
+    /// The pointer stays valid until the stack is truncated below its
+    /// position.
+    pub fn push(&self, value: Value) -> *const Value {
+        let len = self.len.get();
+        let mut chunks = self.chunks.borrow_mut();
+        if len == chunks.len() * CHUNK_SIZE {
+            chunks.push(vec![Value::new(0); CHUNK_SIZE].into_boxed_slice())
+        }
+        let slot: *const Value = &chunks[len / CHUNK_SIZE][len % CHUNK_SIZE];
+        unsafe { (*slot).set(value) };
+        self.len.set(len + 1);
+        slot
+    }
+
+    /// Releases all roots above `len`.
+    pub fn truncate(&self, len: usize) {
+        debug_assert!(len <= self.len.get());
+        let chunks = self.chunks.borrow();
+        for i in len..self.len.get() {
+            // Clear the slot, so that stale pointers are not kept around
+            // for the debug-mode heap checks to trip over.
+            chunks[i / CHUNK_SIZE][i % CHUNK_SIZE].set(Value::new(0))
+        }
+        self.len.set(len)
+    }
+
+    /// Opens a new scope, returning its depth.
+    pub fn enter_scope(&self) -> usize {
+        let depth = self.depth.get() + 1;
+        self.depth.set(depth);
+        depth
+    }
+
+    /// Closes the innermost scope, releasing all roots above `base`.
+    pub fn exit_scope(&self, depth: usize, base: usize) {
+        assert_eq!(depth, self.depth.get(),
+                   "handle scopes must be closed in LIFO order");
+        self.truncate(base);
+        self.depth.set(depth - 1)
+    }
+
+    /// The depth of the innermost open scope.
+    pub fn depth(&self) -> usize {
+        self.depth.get()
+    }
+
+    /// Opens a scratch frame on this stack.
+    pub fn scratch_frame(this: &Rc<RootStack>) -> ScratchFrame {
+        let base = this.len();
+        let depth = this.enter_scope();
+        ScratchFrame {
+            roots: this.clone(),
+            base: base,
+            depth: depth,
+        }
+    }
+
+    /// Calls `f` on a raw pointer to each live root.  Used by the GC.
+    pub fn for_each<F: FnMut(*mut Value)>(&self, mut f: F) {
+        let chunks = self.chunks.borrow();
+        for i in 0..self.len.get() {
+            f(&chunks[i / CHUNK_SIZE][i % CHUNK_SIZE] as *const Value as *mut Value)
+        }
+    }
+}
+
+/// An alloca-style frame of roots for `Value`s in Rust locals.
+///
+/// Scratch frames nest with handle scopes: both are scopes on the same
+/// `RootStack`, and only the innermost one can root values.  All roots of a
+/// frame are released when it is dropped.
+#[derive(Debug)]
+pub struct ScratchFrame {
+    roots: Rc<RootStack>,
+    base: usize,
+    depth: usize,
+}
+
+impl ScratchFrame {
+    /// Roots `value` until the frame is dropped.  The returned slot is
+    /// updated by the GC, so it stays valid across allocations.
+    ///
+    /// `value` must have been obtained since the last allocation.
+    pub fn root(&self, value: Value) -> &Value {
+        debug_assert_eq!(self.depth, self.roots.depth(),
+                         "scratch roots can only be created in the innermost scope");
+        unsafe { &*self.roots.push(value) }
+    }
+}
+
+impl Drop for ScratchFrame {
+    fn drop(&mut self) {
+        self.roots.exit_scope(self.depth, self.base)
+    }
+}
+
+/// Reference-counted GC roots, which may be held by other threads.
+///
+/// Only the thread that owns the heap may read or write the values, but
+/// any thread may change the reference counts.
+#[derive(Debug)]
+pub struct PersistentRoots {
+    slots: Mutex<Slots>,
+    owner: ThreadId,
+}
+
+#[derive(Debug, Default)]
+struct Slots {
+    values: Vec<Value>,
+
+    /// The reference count of each slot.  Zero for a free slot.
+    counts: Vec<usize>,
+
+    /// The indices of the free slots.
+    free: Vec<usize>,
+}
+
+impl Default for PersistentRoots {
+    fn default() -> Self {
+        PersistentRoots {
+            slots: Mutex::new(Slots::default()),
+            owner: thread::current().id(),
+        }
+    }
+}
+
+impl PersistentRoots {
+    /// A panic while the lock is held cannot leave the slots inconsistent,
+    /// so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<Slots> {
+        self.slots.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Is the current thread the one that owns the heap?
+    pub fn on_owning_thread(&self) -> bool {
+        thread::current().id() == self.owner
+    }
+
+    /// The number of live roots.
+    pub fn len(&self) -> usize {
+        let slots = self.lock();
+        slots.values.len() - slots.free.len()
+    }
+
+    /// Is this set empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Roots `value` with a reference count of 1, returning its slot.
+    pub fn add(&self, value: Value) -> usize {
+        debug_assert!(self.on_owning_thread());
+        let mut slots = self.lock();
+        match slots.free.pop() {
+            Some(index) => {
+                slots.values[index] = value;
+                slots.counts[index] = 1;
+                index
+            }
+            None => {
+                slots.values.push(value);
+                slots.counts.push(1);
+                slots.values.len() - 1
+            }
+        }
+    }
+
+    /// The value in slot `index`.
+    pub fn get(&self, index: usize) -> Value {
+        debug_assert!(self.on_owning_thread());
+        self.lock().values[index].clone()
+    }
+
+    /// Increments the reference count of slot `index`.
+    pub fn retain(&self, index: usize) {
+        self.lock().counts[index] += 1
+    }
+
+    /// Decrements the reference count of slot `index`, releasing the slot
+    /// if it drops to zero.
+    pub fn release(&self, index: usize) {
+        let mut slots = self.lock();
+        slots.counts[index] -= 1;
+        if slots.counts[index] == 0 {
+            // The GC still scans free slots, so they must hold a valid
+            // value.
+            slots.values[index] = Value::new(0);
+            slots.free.push(index)
+        }
+    }
+
+    /// Calls `f` on a raw pointer to each root.  Used by the GC.
+    pub fn for_each<F: FnMut(*mut Value)>(&self, mut f: F) {
+        for value in &self.lock().values {
+            f(value as *const Value as *mut Value)
+        }
+    }
+}
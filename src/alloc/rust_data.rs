@@ -1 +1,119 @@
-pub fn alloc_rustdata_tag() {
+//! Rust data: Rust values of any type stored on the Scheme heap.
+//!
+//! A Rust data object is a resource (see `finalize`) whose data is a
+//! `RustData`, so its value is dropped when the object dies.  The value is a
+//! `Box<Any>`, so taking it back out checks its type.
+//!
+//! Rust data is only `equal?` to itself, unless it was made with
+//! `RustData::comparable`: then it has hooks that compare it to other Rust
+//! data of the same type with `==`, and hash it with `Hash`, so that it can
+//! be used as a key of an `equal?` hash table.
+
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use value::Value;
+use super::finalize;
+
+/// The data of a Rust data object.
+pub struct RustData {
+    value: Box<Any>,
+
+    /// Compares two values, for `equal?`.
+    equal: Option<fn(&Any, &Any) -> bool>,
+
+    /// Hashes a value, consistently with `equal`.
+    hash: Option<fn(&Any) -> u64>,
+}
+
+fn equal_as<T: PartialEq + 'static>(x: &Any, y: &Any) -> bool {
+    match (x.downcast_ref::<T>(), y.downcast_ref::<T>()) {
+        (Some(x), Some(y)) => x == y,
+        _ => false,
+    }
+}
+
+fn hash_as<T: Hash + 'static>(x: &Any) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    x.downcast_ref::<T>().expect("hash hook of the wrong type").hash(&mut hasher);
+    hasher.finish()
+}
+
+impl RustData {
+    /// Rust data holding `value`, which is only `equal?` to itself.
+    pub fn new<T: Any>(value: T) -> Self {
+        RustData {
+            value: Box::new(value),
+            equal: None,
+            hash: None,
+        }
+    }
+
+    /// Rust data holding `value`, which is `equal?` to Rust data holding an
+    /// equal `T`.
+    pub fn comparable<T: Any + PartialEq + Hash>(value: T) -> Self {
+        RustData {
+            value: Box::new(value),
+            equal: Some(equal_as::<T>),
+            hash: Some(hash_as::<T>),
+        }
+    }
+}
+
+/// The data of `x`, if it is a Rust data object.
+fn data(x: &Value) -> Option<&RustData> {
+    finalize::downcast::<RustData>(x)
+}
+
+/// The value of `x`, if it is a Rust data object holding a `T`.
+pub fn downcast<T: Any>(x: &Value) -> Option<&T> {
+    data(x).and_then(|data| data.value.downcast_ref())
+}
+
+/// Are the Rust data objects `x` and `y` equal by their hooks?  Objects
+/// without hooks, and other objects, are not, so that equal objects are
+/// always hashed by the same hook.
+pub fn equal(x: &Value, y: &Value) -> bool {
+    match (data(x), data(y)) {
+        (Some(x), Some(y)) => {
+            match (x.equal, y.equal) {
+                (Some(equal), Some(_)) => equal(&*x.value, &*y.value),
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// The hash of the Rust data object `x` by its hook, if it has one.
+pub fn hash(x: &Value) -> Option<u64> {
+    data(x).and_then(|data| data.hash.map(|hash| hash(&*data.value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use alloc::{self, Heap};
+    use super::*;
+
+    #[test]
+    fn rust_data_is_checked_compared_and_dropped() {
+        let dropped = Rc::new(());
+        let mut heap = Heap::new(1 << 8);
+        heap.alloc_resource(RustData::new(dropped.clone())).unwrap();
+        heap.alloc_resource(RustData::comparable("a".to_owned())).unwrap();
+        heap.alloc_resource(RustData::comparable("a".to_owned())).unwrap();
+        alloc::collect(&mut heap);
+        assert!(downcast::<Rc<()>>(&heap.stack[0]).is_some());
+        assert!(downcast::<String>(&heap.stack[0]).is_none());
+        assert_eq!(downcast::<String>(&heap.stack[1]).map(|s| &s[..]), Some("a"));
+        assert!(!equal(&heap.stack[0], &heap.stack[0].clone()));
+        assert!(equal(&heap.stack[1], &heap.stack[2]));
+        assert_eq!(hash(&heap.stack[1]), hash(&heap.stack[2]));
+        assert_eq!(hash(&heap.stack[0]), None);
+        heap.stack.clear();
+        alloc::collect(&mut heap);
+        assert_eq!(Rc::strong_count(&dropped), 1);
+    }
+}
@@ -0,0 +1,149 @@
+//! Statistics about garbage collections, and hooks to observe them.
+//!
+//! Each collection is passed to the hooks as a `GcStats`, and added to the
+//! `Totals` of the heap, which `Heap::stats` reports, with the objects in
+//! the heap, as a `HeapStats`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+/// The kind of a collection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GcKind {
+    /// A collection of the nursery only.
+    Minor,
+
+    /// A collection of the whole heap.
+    Major,
+}
+
+/// Statistics about one collection, as passed to `Heap::on_gc` hooks.
+#[derive(Copy, Clone, Debug)]
+pub struct GcStats {
+    /// The kind of collection.
+    pub kind: GcKind,
+
+    /// The number of words in use before the collection.
+    pub words_before: usize,
+
+    /// The number of words in use after the collection.
+    pub words_after: usize,
+
+    /// The number of resources that were found dead, and finalized.
+    pub finalized: usize,
+
+    /// How long the collection took, including finalization.
+    pub duration: Duration,
+}
+
+/// Statistics about a heap, as returned by `Heap::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The number of bytes in use after the last collection.  After a
+    /// major collection, this is the bytes of the live objects.
+    pub live_bytes: usize,
+
+    /// The number of bytes in use, including garbage not yet collected.
+    pub bytes_in_use: usize,
+
+    /// The number of objects allocated since the last collection.
+    pub allocations_since_gc: usize,
+
+    /// The number of bytes allocated since the last collection.
+    pub bytes_since_gc: u64,
+
+    /// The number of bytes allocated since the heap was created.
+    pub bytes_allocated: u64,
+
+    /// The number of minor collections so far.
+    pub minor_collections: usize,
+
+    /// The number of major collections so far.
+    pub major_collections: usize,
+
+    /// How long all of the collections so far took.
+    pub total_pause: Duration,
+
+    /// How long the longest collection so far took.
+    pub max_pause: Duration,
+
+    /// How long the last collection took, if there was one.
+    pub last_pause: Option<Duration>,
+
+    /// The number of objects in the heap of each kind (see `visit::kind`),
+    /// including garbage not yet collected.
+    pub objects: BTreeMap<&'static str, usize>,
+}
+
+/// What the collections of a heap have done so far, and what has been
+/// allocated since the last one.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Totals {
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    pub last_pause: Option<Duration>,
+
+    /// The number of words in use after the last collection.
+    pub words_after: usize,
+
+    /// The number of words allocated before the last collection.
+    pub allocated_before: u64,
+
+    /// The number of objects allocated since the last collection.
+    pub allocations: usize,
+}
+
+impl Totals {
+    /// Adds the collection `stats`, before which `allocated` words had been
+    /// allocated.
+    pub fn record(&mut self, stats: &GcStats, allocated: u64) {
+        match stats.kind {
+            GcKind::Minor => self.minor_collections += 1,
+            GcKind::Major => self.major_collections += 1,
+        }
+        self.total_pause += stats.duration;
+        if stats.duration > self.max_pause {
+            self.max_pause = stats.duration
+        }
+        self.last_pause = Some(stats.duration);
+        self.words_after = stats.words_after;
+        self.allocated_before = allocated;
+        self.allocations = 0
+    }
+}
+
+/// A hook run after every collection.
+pub type GcHook = Box<FnMut(&GcStats)>;
+
+/// The hooks registered with `Heap::on_gc`.
+#[derive(Default)]
+pub struct GcHooks {
+    hooks: Vec<GcHook>,
+}
+
+impl fmt::Debug for GcHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GcHooks({} hooks)", self.hooks.len())
+    }
+}
+
+impl GcHooks {
+    pub fn push(&mut self, hook: GcHook) {
+        self.hooks.push(hook)
+    }
+
+    /// Runs all hooks.  Hooks registered while this runs are kept, but
+    /// not run until the next collection.
+    pub fn run(this: &mut GcHooks, stats: &GcStats) {
+        let mut hooks = mem::replace(&mut this.hooks, vec![]);
+        for hook in &mut hooks {
+            hook(stats)
+        }
+        hooks.extend(this.hooks.drain(..));
+        this.hooks = hooks
+    }
+}
@@ -0,0 +1,160 @@
+//! Traces of collections, for tests of the collector (with the `gc-trace`
+//! feature).
+//!
+//! A trace records what a collection did: how many roots of each kind it
+//! scanned, how many objects of each kind it copied, how many pointers it
+//! forwarded to objects that were already copied or left alone, and a hash
+//! of the layout of the tenured generation afterwards.  Traces do not
+//! depend on where the heap is in memory, so the same program always leaves
+//! the same traces, and tests can assert what the collector did rather than
+//! just that the program survived it.
+//!
+//! Without the feature, collections are not traced, and `Heap::gc_log` is
+//! always empty.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use builtins;
+use value::{Kind, Value, Tags, HEADER_TAG};
+use super::{kind, layout, GcKind, Heap, Layout};
+use super::FINALIZED;
+
+/// The trace of one collection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GcTrace {
+    pub kind: GcKind,
+
+    /// The number of roots of each kind, in the order they were scanned.
+    pub roots: Vec<(&'static str, usize)>,
+
+    /// The number of objects copied of each kind.
+    pub copied: BTreeMap<&'static str, usize>,
+
+    /// The number of pointers to objects that had already been copied, which
+    /// were updated to point to the copies.
+    pub forwarded: usize,
+
+    /// The number of pointers to objects outside of the evacuated spaces,
+    /// which were left alone.
+    pub untouched: usize,
+
+    /// A hash of the tenured generation after the collection, in which
+    /// pointers count as offsets into it (or, for symbols and native
+    /// procedures, as their names).
+    pub layout_hash: u64,
+}
+
+/// What a collection has done so far.
+#[derive(Debug, Default)]
+pub struct Counters {
+    copied: RefCell<BTreeMap<&'static str, usize>>,
+    forwarded: Cell<usize>,
+    untouched: Cell<usize>,
+}
+
+impl Counters {
+    pub fn copied(&self, header: usize) {
+        *self.copied.borrow_mut().entry(kind(header)).or_insert(0) += 1
+    }
+
+    pub fn forwarded(&self) {
+        self.forwarded.set(self.forwarded.get() + 1)
+    }
+
+    pub fn untouched(&self) {
+        self.untouched.set(self.untouched.get() + 1)
+    }
+}
+
+/// The roots that a collection of kind `kind` scans, before it starts.
+pub fn roots(heap: &Heap, kind: GcKind) -> Vec<(&'static str, usize)> {
+    let (mut handles, mut persistent) = (0, 0);
+    heap.roots.for_each(|_| handles += 1);
+    heap.persistent.for_each(|_| persistent += 1);
+    let mut roots = vec![("stack", heap.stack.len()),
+                         ("handles", handles),
+                         ("persistent handles", persistent)];
+    if kind == GcKind::Minor {
+        roots.push(("symbols", heap.symbol_table.contents.len()));
+        roots.push(("remembered", heap.remembered.len()))
+    }
+    roots
+}
+
+/// FNV-1a, which is simple and the same everywhere.
+fn hash(state: &mut u64, word: usize) {
+    for byte in 0..size_of!(usize) {
+        *state ^= (word >> (byte * 8)) as u8 as u64;
+        *state = state.wrapping_mul(0x100000001b3)
+    }
+}
+
+/// Hashes the tenured generation of `heap`.
+fn layout_hash(heap: &Heap) -> u64 {
+    let base = heap.tospace.as_ptr() as usize;
+    let end = base + heap.tospace.len() * size_of!(Value);
+    let mut state = 0xcbf29ce484222325;
+    let value = |state: &mut u64, x: &Value| {
+        let word = x.get();
+        let ptr = word & !0b111;
+        if x.immediatep() {
+            return hash(state, word)
+        }
+        hash(state, word & 0b111);
+        let name = match x.kind() {
+            Kind::Symbol(symbol) => unsafe { (*symbol).name() },
+            _ if x.tag() == Tags::RustFunc => builtins::descriptor(x).name,
+            _ if ptr >= base && ptr < end => return hash(state, (ptr - base) / size_of!(Value)),
+            _ => "",
+        };
+        for byte in name.bytes() {
+            hash(state, byte as usize)
+        }
+    };
+    let mut offset = 0;
+    while offset < heap.tospace.len() {
+        let header = heap.tospace[offset].get();
+        let size = header & !HEADER_TAG;
+        let words = &heap.tospace[offset..offset + size];
+        hash(&mut state, header);
+        match layout(header, words[1].get()) {
+            Some(Layout::Values) => {
+                for x in &words[1..] {
+                    value(&mut state, x)
+                }
+            }
+            Some(Layout::Weak) => {
+                hash(&mut state, words[1].get());
+                value(&mut state, &words[2])
+            }
+            // Resources hold pointers to each other, and to Rust data.
+            Some(Layout::Opaque) if header & HEADER_TAG == FINALIZED => {}
+            Some(Layout::Opaque) => {
+                for x in &words[1..] {
+                    hash(&mut state, x.get())
+                }
+            }
+            Some(Layout::Bytecode) | None => {}
+        }
+        offset += super::align_word_size(size)
+    }
+    state
+}
+
+/// Adds the trace of the collection of kind `kind` that scanned `roots`,
+/// and did what `counters` counted, to the log of `heap`.
+pub fn record(heap: &mut Heap,
+              kind: GcKind,
+              roots: Vec<(&'static str, usize)>,
+              counters: &Counters) {
+    let trace = GcTrace {
+        kind: kind,
+        roots: roots,
+        copied: counters.copied.borrow().clone(),
+        forwarded: counters.forwarded.get(),
+        untouched: counters.untouched.get(),
+        layout_hash: layout_hash(heap),
+    };
+    heap.gc_log.push(trace)
+}
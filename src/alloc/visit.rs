@@ -0,0 +1,86 @@
+//! Where the `Value`s in heap objects are.
+//!
+//! The collector, the debug checks, and heap images all find the values of
+//! an object with `layout`, and other code (printers, serializers,
+//! verifiers) visits them with `visit_children`, so that a new kind of heap
+//! object only has to be described here.
+
+use bytecode;
+use value::{Value, Tags, HEADER_TAG};
+use super::{weak, PAIR, VECTOR, RECORD, CLOSURE, BYTECODE, RUSTDATA, FINALIZED};
+
+/// Which words of a heap object are `Value`s.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// All words after the header: pairs, vectors, records, and closures.
+    Values,
+
+    /// None: strings and resources.
+    Opaque,
+
+    /// A weak box, whose referent (its third word) the collector does not
+    /// trace (see `weak`).
+    Weak,
+
+    /// A BCO, whose constants vector is its only value.
+    Bytecode,
+}
+
+/// The layout of the object whose first two words are `header` and
+/// `second`, or `None` if `header` is not the header of an object (such as
+/// a forwarding pointer).
+pub fn layout(header: usize, second: usize) -> Option<Layout> {
+    match header & HEADER_TAG {
+        PAIR | VECTOR | RECORD | CLOSURE => Some(Layout::Values),
+        RUSTDATA if second == weak::WEAK_BOX => Some(Layout::Weak),
+        RUSTDATA | FINALIZED => Some(Layout::Opaque),
+        BYTECODE => Some(Layout::Bytecode),
+        _ => None,
+    }
+}
+
+/// The kind of the object whose header is `header`, as traces and heap
+/// statistics count them.
+pub fn kind(header: usize) -> &'static str {
+    match header & HEADER_TAG {
+        PAIR => "pair",
+        VECTOR => "vector",
+        RECORD => "record",
+        CLOSURE => "closure",
+        RUSTDATA => "rust data",
+        FINALIZED => "resource",
+        BYTECODE => "bytecode",
+        _ => "unknown",
+    }
+}
+
+/// Calls `f` on each value in the heap object `x`, in order, that the
+/// collector traces: the referents of weak boxes are not visited.  Does
+/// nothing if `x` is not a heap object; in particular, the value of a
+/// symbol is a root, not a child of the symbol.
+///
+/// `f` must not allocate, since that may move `x`.
+pub fn visit_children<F: FnMut(&Value)>(x: &Value, mut f: F) {
+    if x.immediatep() || x.tag() == Tags::Symbol || x.tag() == Tags::RustFunc {
+        return
+    }
+    unsafe {
+        let object = x.as_ptr();
+        let header = (*object).get();
+        match layout(header, (*object.offset(1)).get()) {
+            Some(Layout::Values) => {
+                // The word after a vector's header is padding.
+                let first = if header & HEADER_TAG == VECTOR { 2 } else { 1 };
+                for i in first..header & !HEADER_TAG {
+                    f(&*object.offset(i as isize))
+                }
+            }
+            Some(Layout::Bytecode) => {
+                let bco = &*(object as *const bytecode::BCO);
+                f(&*bytecode::get_constants_vector(bco).get())
+            }
+            Some(Layout::Opaque) | Some(Layout::Weak) => {}
+            None => bug!("Strange header {:x}", header),
+        }
+    }
+}
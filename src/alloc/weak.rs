@@ -0,0 +1,128 @@
+//! Weak boxes.
+//!
+//! A weak box is a `RustData` object holding one `Value`, its referent.
+//! Since the GC does not scan `RustData`, the referent is not kept alive by
+//! the box.  Instead, every weak box that the GC copies is remembered, and
+//! once the collection is over its referent is either updated (if the
+//! referent survived) or replaced by `BROKEN_WEAK` (if it did not).
+//!
+//! Weak boxes are immutable, so a tenured weak box never points into the
+//! nursery: the write barrier does not need to know about them, and minor
+//! collections only need to fix up the boxes that they promote.
+
+use value::{self, Value, HEADER_TAG};
+use super::Evacuated;
+
+/// The type word of a weak box.  (Strings are 0, see `string`.)
+pub const WEAK_BOX: usize = 1;
+
+/// The layout of a weak box.
+#[repr(C)]
+pub struct WeakBox {
+    header: usize,
+    ty: usize,
+    pub referent: Value,
+}
+
+/// The size of a weak box in words.
+pub const SIZEOF_WEAK_BOX: usize = 3;
+
+/// Is the heap object at `object` a weak box?
+pub unsafe fn is_weak_box_object(object: *const Value) -> bool {
+    (*object).get() & HEADER_TAG == value::HeaderTag::RustData as usize &&
+    (*object.offset(1)).get() == WEAK_BOX
+}
+
+/// The weak box `x`, if it is one.
+pub fn as_weak_box(x: &Value) -> Option<&WeakBox> {
+    if x.tag() == value::Tags::RustData && unsafe { is_weak_box_object(x.as_ptr()) } {
+        Some(unsafe { &*(x.as_ptr() as *const WeakBox) })
+    } else {
+        None
+    }
+}
+
+/// Fixes up the referents of the weak boxes `boxes` after they have been
+/// copied by a collection that evacuated `from`.  `major` is whether the
+/// collection is a major one, which also collects symbols.
+///
+/// Must be called before the symbol table is fixed up, since a box whose
+/// referent is a dead symbol is broken too.
+pub unsafe fn fix_weak_boxes(boxes: &[*mut WeakBox], from: &Evacuated, major: bool) {
+    for &weak in boxes {
+        let referent = &(*weak).referent;
+        if referent.immediatep() || referent.tag() == value::Tags::RustFunc {
+            continue
+        }
+        let alive = if referent.tag() == value::Tags::Symbol {
+            if !major {
+                continue
+            }
+            let symbol = referent.as_ptr() as *const ::symbol::Symbol;
+            (*symbol).alive.get()
+        } else {
+            let pointer = referent.as_ptr();
+            if !from.contains(pointer as usize) {
+                continue
+            }
+            if (*pointer).get() == HEADER_TAG {
+                // Forwarded, so it survived.
+                referent.set((*pointer.offset(1)).clone());
+                true
+            } else {
+                false
+            }
+        };
+        if !alive {
+            referent.set(Value::new(value::BROKEN_WEAK))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{self, Heap};
+    use value::{Value, BROKEN_WEAK, TRUE};
+    use super::*;
+
+    fn referent(heap: &Heap, index: usize) -> usize {
+        as_weak_box(&heap.stack[index]).unwrap().referent.get()
+    }
+
+    #[test]
+    fn weak_boxes_do_not_keep_their_referents_alive() {
+        let mut heap = Heap::new(1 << 8);
+        heap.stack.push(Value::nil());
+        heap.alloc_pair(0, 0).unwrap();
+        heap.alloc_weak_box(1).unwrap();
+        heap.stack.remove(1);
+        heap.stack.push(Value::new(TRUE));
+        heap.alloc_weak_box(2).unwrap();
+        assert_eq!(heap.stack.len(), 4);
+        alloc::collect_nursery(&mut heap);
+        assert_eq!(referent(&heap, 1), BROKEN_WEAK);
+        assert_eq!(referent(&heap, 3), TRUE);
+        alloc::collect(&mut heap);
+        assert_eq!(referent(&heap, 1), BROKEN_WEAK);
+        assert!(as_weak_box(&heap.stack[0]).is_none());
+    }
+
+    #[test]
+    fn weak_boxes_follow_live_referents() {
+        let mut heap = Heap::new(1 << 8);
+        heap.stack.push(Value::nil());
+        heap.alloc_pair(0, 0).unwrap();
+        heap.alloc_weak_box(1).unwrap();
+        for &major in &[false, true, true] {
+            if major {
+                alloc::collect(&mut heap)
+            } else {
+                alloc::collect_nursery(&mut heap)
+            }
+            assert_eq!(referent(&heap, 2), heap.stack[1].get());
+        }
+        heap.stack[1] = Value::nil();
+        alloc::collect(&mut heap);
+        assert_eq!(referent(&heap, 2), BROKEN_WEAK);
+    }
+}
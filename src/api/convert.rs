@@ -0,0 +1,414 @@
+//! Typed conversions between Rust and Scheme values.
+//!
+//! `IntoScheme` makes a Scheme value of a Rust value, and `FromScheme` a
+//! Rust value of a Scheme value:
+//!
+//! | Rust                 | Scheme                                      |
+//! |----------------------|---------------------------------------------|
+//! | `usize`, `i64`       | a fixnum                                    |
+//! | `f64`                | a flonum (from any number)                  |
+//! | `bool`               | a boolean                                   |
+//! | `String`             | a string                                    |
+//! | `Vec<T>`             | a list                                      |
+//! | `Option<T>`          | `#f` for `None`, and the value of `T` otherwise |
+//! | `(A,)` to `(A, B, C, D)` | a vector                                |
+//! | `()`                 | the unspecified value (only into Scheme)    |
+//! | a `Record`           | a record                                    |
+//!
+//! Fixnums cannot be negative yet, so neither can the numbers converted.
+//! Since `#f` is `None`, an `Option<bool>` is never `Some(false)`.
+//!
+//! A struct becomes a record by implementing `Record`, which `scheme_record!`
+//! does:
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate rusty_scheme;
+//!
+//! struct Point {
+//!     x: usize,
+//!     y: usize,
+//! }
+//!
+//! scheme_record!(Point as "point" { x, y });
+//! # fn main() {}
+//! ```
+//!
+//! Its values are records of a record type named `point`, with the fields
+//! `x` and `y`, which is made once per interpreter.  Going the other way,
+//! its fields are read by name, so any record with `x` and `y` fields can be
+//! converted to a `Point`, like the generic accessors of Scheme code.
+//!
+//! The arguments of procedures registered with `Vm::register_typed` are
+//! converted with `FromArgs`: a tuple takes one argument per element, and a
+//! `Vec` takes any number of arguments.
+
+use std::i64;
+use std::usize;
+
+use alloc::Heap;
+use arith;
+use builtins::Arity;
+use equal;
+use interp::State;
+use record;
+use value::{self, Value};
+use super::SchemeValue;
+
+/// The largest fixnum.
+const MAX_FIXNUM: usize = usize::MAX >> 2;
+
+/// A Rust value that can be converted to a Scheme value.
+pub trait IntoScheme {
+    /// Pushes the Scheme value of `self` onto the stack.  Pushes nothing if
+    /// it fails.
+    fn into_scheme(self, s: &mut State) -> Result<(), String>;
+}
+
+/// A Rust value that can be converted from a Scheme value.
+pub trait FromScheme: Sized {
+    fn from_scheme(x: &Value) -> Result<Self, String>;
+}
+
+/// The arguments of a procedure.  See the module documentation.
+pub trait FromArgs: Sized {
+    /// The numbers of arguments accepted.
+    fn arity() -> Arity;
+
+    fn from_args(args: &[Value]) -> Result<Self, String>;
+}
+
+/// A Rust type whose values are records, such as a struct whose fields are
+/// `IntoScheme` and `FromScheme`.  See the module documentation.
+pub trait Record: Sized {
+    /// The name of the record type.
+    fn type_name() -> &'static str;
+
+    /// The names of the fields, in order.
+    fn field_names() -> &'static [&'static str];
+
+    /// Writes the fields of `self`, in the order of `field_names`.
+    fn write_fields(self, fields: &mut FieldWriter) -> Result<(), String>;
+
+    /// Reads a value from the fields of a record.
+    fn read_fields(fields: &FieldReader) -> Result<Self, String>;
+}
+
+/// The fields of a record being made.
+pub struct FieldWriter<'a> {
+    s: &'a mut State,
+}
+
+impl<'a> FieldWriter<'a> {
+    /// Writes the next field.
+    pub fn field<T: IntoScheme>(&mut self, x: T) -> Result<(), String> {
+        x.into_scheme(self.s)
+    }
+}
+
+/// The fields of a record being read.
+pub struct FieldReader<'a> {
+    record: &'a Value,
+}
+
+impl<'a> FieldReader<'a> {
+    /// Reads the field named `name`.
+    pub fn field<T: FromScheme>(&self, name: &str) -> Result<T, String> {
+        T::from_scheme(&try!(record::field(self.record, name)))
+    }
+}
+
+/// Implements `Record` for a struct, whose record type has the given name
+/// and the given fields.  See `api::convert`.
+#[macro_export]
+macro_rules! scheme_record {
+    ($name:ident as $scheme_name:tt { $($field:ident),* }) => {
+        impl $crate::Record for $name {
+            fn type_name() -> &'static str {
+                $scheme_name
+            }
+
+            fn field_names() -> &'static [&'static str] {
+                const FIELDS: &'static [&'static str] = &[$(stringify!($field)),*];
+                FIELDS
+            }
+
+            fn write_fields(self, fields: &mut $crate::FieldWriter) -> Result<(), String> {
+                $(try!(fields.field(self.$field));)*
+                Ok(())
+            }
+
+            fn read_fields(fields: &$crate::FieldReader) -> Result<Self, String> {
+                Ok($name { $($field: try!(fields.field(stringify!($field)))),* })
+            }
+        }
+    }
+}
+
+/// Runs `f`, which pushes some values, and replaces them with what
+/// `finish` pushes, given the stack indexes of the first of them and of
+/// the end.  If either fails, pops whatever they pushed.
+fn compound<F, G>(s: &mut State, f: F, finish: G) -> Result<(), String>
+    where F: FnOnce(&mut State) -> Result<(), String>,
+          G: FnOnce(&mut Heap, usize, usize) -> Result<(), String>
+{
+    let base = s.heap.stack.len();
+    let res = f(s).and_then(|()| {
+        let end = s.heap.stack.len();
+        finish(&mut s.heap, base, end)
+    });
+    let result = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    Ok(s.heap.stack.push(result.unwrap()))
+}
+
+/// Pushes a list of `heap.stack[start..end]`.
+fn list(heap: &mut Heap, start: usize, end: usize) -> Result<(), String> {
+    heap.stack.push(Value::nil());
+    for i in (start..end).rev() {
+        let tail = heap.stack.len() - 1;
+        try!(heap.alloc_pair(i, tail));
+        let pair = heap.stack.pop().unwrap();
+        *heap.stack.last_mut().unwrap() = pair
+    }
+    Ok(())
+}
+
+fn push_value<T: SchemeValue>(s: &mut State, x: &T) -> Result<(), String> {
+    let value = try!(x.to_value(&mut s.heap));
+    Ok(s.heap.stack.push(value))
+}
+
+impl IntoScheme for usize {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        if self > MAX_FIXNUM {
+            return Err(format!("{} is too large for a fixnum", self))
+        }
+        push_value(s, &self)
+    }
+}
+
+impl FromScheme for usize {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        Self::of_value(x)
+    }
+}
+
+impl IntoScheme for i64 {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        if self < 0 {
+            return Err(format!("{} is negative, and fixnums cannot be yet", self))
+        }
+        (self as u64 as usize).into_scheme(s)
+    }
+}
+
+impl FromScheme for i64 {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        let x = try!(usize::from_scheme(x));
+        if x as u64 > i64::MAX as u64 {
+            return Err(format!("{} is too large for an i64", x))
+        }
+        Ok(x as i64)
+    }
+}
+
+impl IntoScheme for f64 {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        Ok(try!(s.heap.alloc_flonum(self)))
+    }
+}
+
+impl FromScheme for f64 {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        arith::real(x).ok_or_else(|| "not a number".to_owned())
+    }
+}
+
+impl IntoScheme for bool {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        push_value(s, &self)
+    }
+}
+
+impl FromScheme for bool {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        Self::of_value(x)
+    }
+}
+
+impl IntoScheme for String {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        push_value(s, &self)
+    }
+}
+
+impl FromScheme for String {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        Self::of_value(x)
+    }
+}
+
+impl IntoScheme for () {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        Ok(s.heap.stack.push(Value::unspecified()))
+    }
+}
+
+impl<T: IntoScheme> IntoScheme for Vec<T> {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        compound(s,
+                 |s| {
+                     for x in self {
+                         try!(x.into_scheme(s))
+                     }
+                     Ok(())
+                 },
+                 list)
+    }
+}
+
+impl<T: FromScheme> FromScheme for Vec<T> {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        let (mut xs, mut x) = (vec![], x.clone());
+        while x.pairp() {
+            xs.push(try!(T::from_scheme(&x.car().unwrap())));
+            x = x.cdr().unwrap()
+        }
+        if !x.is_nil() {
+            return Err("not a list".to_owned())
+        }
+        Ok(xs)
+    }
+}
+
+impl<T: IntoScheme> IntoScheme for Option<T> {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        match self {
+            Some(x) => x.into_scheme(s),
+            None => false.into_scheme(s),
+        }
+    }
+}
+
+impl<T: FromScheme> FromScheme for Option<T> {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        if x.get() == value::FALSE {
+            Ok(None)
+        } else {
+            T::from_scheme(x).map(Some)
+        }
+    }
+}
+
+impl<T: Record> IntoScheme for T {
+    fn into_scheme(self, s: &mut State) -> Result<(), String> {
+        compound(s,
+                 |s| {
+                     try!(s.host_types.push(&mut s.heap, T::type_name(), T::field_names()));
+                     self.write_fields(&mut FieldWriter { s: s })
+                 },
+                 record::make_record)
+    }
+}
+
+impl<T: Record> FromScheme for T {
+    fn from_scheme(x: &Value) -> Result<Self, String> {
+        T::read_fields(&FieldReader { record: x })
+    }
+}
+
+impl<T: FromScheme> FromArgs for Vec<T> {
+    fn arity() -> Arity {
+        Arity::AtLeast(0)
+    }
+
+    fn from_args(args: &[Value]) -> Result<Self, String> {
+        args.iter().map(T::from_scheme).collect()
+    }
+}
+
+impl FromArgs for () {
+    fn arity() -> Arity {
+        Arity::Exactly(0)
+    }
+
+    fn from_args(_: &[Value]) -> Result<Self, String> {
+        Ok(())
+    }
+}
+
+macro_rules! tuple {
+    ($len:expr; $($t:ident $i:tt),*) => {
+        impl<$($t: IntoScheme),*> IntoScheme for ($($t,)*) {
+            fn into_scheme(self, s: &mut State) -> Result<(), String> {
+                compound(s,
+                         |s| {
+                             $(try!(self.$i.into_scheme(s));)*
+                             Ok(())
+                         },
+                         |heap, start, end| heap.alloc_vector(start, end).map_err(String::from))
+            }
+        }
+
+        impl<$($t: FromScheme),*> FromScheme for ($($t,)*) {
+            fn from_scheme(x: &Value) -> Result<Self, String> {
+                match equal::vector_elements(x) {
+                    Some(xs) if xs.len() == $len => Ok(($(try!($t::from_scheme(&xs[$i])),)*)),
+                    _ => Err(format!("not a vector of {} elements", $len)),
+                }
+            }
+        }
+
+        impl<$($t: FromScheme),*> FromArgs for ($($t,)*) {
+            fn arity() -> Arity {
+                Arity::Exactly($len)
+            }
+
+            fn from_args(args: &[Value]) -> Result<Self, String> {
+                Ok(($(try!($t::from_scheme(&args[$i])),)*))
+            }
+        }
+    }
+}
+
+tuple!(1; A 0);
+tuple!(2; A 0, B 1);
+tuple!(3; A 0, B 1, C 2);
+tuple!(4; A 0, B 1, C 2, D 3);
+
+#[cfg(test)]
+mod tests {
+    use api::Vm;
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: usize,
+        y: usize,
+    }
+
+    scheme_record!(Point as "point" { x, y });
+
+    #[test]
+    fn values_round_trip() {
+        let mut vm = Vm::new();
+        let xs = vm.value(vec![(1usize, "one".to_owned()), (2, "two".to_owned())]).unwrap();
+        vm.state().gc();
+        assert_eq!(vm.get::<Vec<(i64, String)>>(&xs),
+                   Ok(vec![(1, "one".to_owned()), (2, "two".to_owned())]));
+        let none = vm.value(None::<usize>).unwrap();
+        assert_eq!(vm.get::<Option<f64>>(&none), Ok(None));
+        let point = vm.value(Point { x: 1, y: 2 }).unwrap();
+        vm.define("p", &point).unwrap();
+        let x = vm.eval_str("(record-ref p 'x)").unwrap();
+        assert_eq!(vm.get::<usize>(&x), Ok(1));
+        assert_eq!(vm.get::<Point>(&point), Ok(Point { x: 1, y: 2 }));
+        assert!(vm.value(-1i64).is_err());
+        let half = vm.value(0.5).unwrap();
+        assert_eq!(vm.get::<f64>(&half), Ok(0.5));
+        assert!(vm.get::<(usize,)>(&point).is_err());
+        assert!(vm.state().is_empty());
+    }
+}
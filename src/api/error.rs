@@ -0,0 +1,196 @@
+//! `SchemeError`: the errors of the embedding API.
+//!
+//! Inside the interpreter, an error is a message.  At the API, it becomes a
+//! `SchemeError`, which also has
+//!
+//! - a kind, told by the message for errors of the interpreter, or by the
+//!   value raised for errors that Scheme code raises with `raise` or
+//!   `error` (see `builtins::errors`);
+//! - the backtrace of the Scheme frames that were running, innermost first.
+//!
+//! A `SchemeError` that a registered procedure returns is raised as a
+//! condition of its kind, so that it keeps its kind (and its backtrace) on
+//! the way back out to the host.
+
+use std::error;
+use std::fmt;
+
+use interp::Frame;
+use super::PersistentHandle;
+
+/// The kinds of errors.
+#[derive(Clone, Debug)]
+pub enum ErrorKind {
+    /// A value of the wrong type.
+    TypeError,
+
+    /// A procedure called with the wrong number of arguments.
+    ArityError,
+
+    /// A global variable, whose name this is, that has no value.
+    UnboundVariable(String),
+
+    /// Input or output failed.
+    IoError,
+
+    /// Code that could not be read or compiled.
+    SyntaxError,
+
+    /// A limit was exceeded: the recursion depth, the fuel, or the heap, or
+    /// the code was interrupted.
+    LimitExceeded,
+
+    /// A value raised by Scheme code, that is not a condition of another
+    /// kind.
+    UserRaised(PersistentHandle),
+
+    /// Any other error.
+    Other,
+}
+
+impl ErrorKind {
+    /// The kind of the error of the interpreter whose message is
+    /// `message`.
+    pub fn of_message(message: &str) -> Self {
+        const UNBOUND: &'static str = "Unbound variable: ";
+        if message.starts_with(UNBOUND) {
+            ErrorKind::UnboundVariable(message[UNBOUND.len()..].to_owned())
+        } else if message.contains("wrong number of arguments") {
+            ErrorKind::ArityError
+        } else if message.contains("os error") {
+            ErrorKind::IoError
+        } else if message.starts_with("out of memory") || message.starts_with("maximum") ||
+                  message == "out of fuel" || message == "interrupted" {
+            ErrorKind::LimitExceeded
+        } else if message.contains("not a ") || message.contains("non-") ||
+                  message.starts_with("can't") {
+            ErrorKind::TypeError
+        } else {
+            ErrorKind::Other
+        }
+    }
+
+    /// The kind of a condition (see `builtins::errors`) of this kind.  Is
+    /// `None` for `UserRaised`, which is raised as its value.
+    pub fn condition_kind(&self) -> Option<&str> {
+        Some(match *self {
+            ErrorKind::TypeError => "type-error",
+            ErrorKind::ArityError => "arity-error",
+            ErrorKind::UnboundVariable(_) => "unbound-variable",
+            ErrorKind::IoError => "io-error",
+            ErrorKind::SyntaxError => "syntax-error",
+            ErrorKind::LimitExceeded => "limit-exceeded",
+            ErrorKind::UserRaised(_) => return None,
+            ErrorKind::Other => "error",
+        })
+    }
+
+    /// The kind of the condition of kind `kind`, whose message is
+    /// `message`, or `None` if no `ErrorKind` raises conditions of that
+    /// kind.  The inverse of `condition_kind`, so that errors of every kind
+    /// but `UserRaised` keep their kind when raised through Scheme code.
+    pub fn of_condition(kind: &str, message: &str) -> Option<Self> {
+        Some(match kind {
+            "type-error" => ErrorKind::TypeError,
+            "arity-error" => ErrorKind::ArityError,
+            "unbound-variable" => ErrorKind::of_message(message),
+            "io-error" => ErrorKind::IoError,
+            "syntax-error" => ErrorKind::SyntaxError,
+            "limit-exceeded" => ErrorKind::LimitExceeded,
+            "error" => ErrorKind::Other,
+            _ => return None,
+        })
+    }
+}
+
+/// An error raised by Scheme code, or by the embedding API.
+#[derive(Clone, Debug)]
+pub struct SchemeError {
+    kind: ErrorKind,
+    message: String,
+    backtrace: Vec<Frame>,
+}
+
+impl SchemeError {
+    /// An error of kind `kind`, without a backtrace.
+    pub fn new(kind: ErrorKind, message: String) -> Self {
+        SchemeError {
+            kind: kind,
+            message: message,
+            backtrace: vec![],
+        }
+    }
+
+    /// The same error, with the backtrace `backtrace`.
+    pub fn with_backtrace(self, backtrace: Vec<Frame>) -> Self {
+        SchemeError { backtrace: backtrace, ..self }
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The frames that were running when the error was raised, innermost
+    /// first.
+    pub fn backtrace(&self) -> &[Frame] {
+        &self.backtrace
+    }
+}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for SchemeError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Errors are equal if they have the same kind and message, whatever their
+/// backtraces, and whatever values Scheme code raised.
+impl PartialEq for SchemeError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind.condition_kind() == other.kind.condition_kind() &&
+        self.message == other.message
+    }
+}
+
+impl From<String> for SchemeError {
+    fn from(message: String) -> Self {
+        SchemeError::new(ErrorKind::of_message(&message), message)
+    }
+}
+
+impl<'a> From<&'a str> for SchemeError {
+    fn from(message: &str) -> Self {
+        SchemeError::from(message.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpreter_errors_are_classified() {
+        let kind = |message: &str| {
+            ErrorKind::of_message(message).condition_kind().map(String::from)
+        };
+        assert_eq!(kind("car: wrong number of arguments: expected 1, got 2"),
+                   Some("arity-error".to_owned()));
+        assert_eq!(kind("Attempt to take the car of a non-pair"), Some("type-error".to_owned()));
+        assert_eq!(kind("interrupted"), Some("limit-exceeded".to_owned()));
+        assert_eq!(kind("oops"), Some("error".to_owned()));
+        match ErrorKind::of_message("Unbound variable: x") {
+            ErrorKind::UnboundVariable(ref name) if name == "x" => {}
+            kind => panic!("{:?}", kind),
+        }
+    }
+}
@@ -1,42 +1,334 @@
-use std::cell;
-use value;
-use libc;
-pub struct Handle<'a> {
+//! Handles: rooted references to Scheme values, for use by embedders.
+//!
+//! A `Handle` is a reference to a slot that the garbage collector knows
+//! about.  When the GC moves an object, it updates the slot, so a `Handle`
+//! always refers to the current location of its object.  This is what makes
+//! it safe to hold on to a Scheme value across an allocation.
+//!
+//! Handles are created in a `HandleScope`, much like SpiderMonkey's `Rooted`
+//! or V8's `HandleScope`.  All handles created in a scope are released when
+//! the scope is closed.  Scopes are opened with `State::with_scope`, which
+//! passes the scope to a closure:
+//!
+//! ```rust,ignore
+//! let mut interp = rusty_scheme::State::new();
+//! interp.with_scope(|interp, scope| {
+//!     interp.push_nil();
+//!     interp.push_nil();
+//!     interp.cons().unwrap();
+//!     let pair = interp.root(scope).unwrap();
+//!     interp.gc();
+//!     assert!(pair.pairp());
+//! })
+//! ```
+//!
+//! The closure must be valid for *every* scope lifetime, so the lifetime of
+//! a `HandleScope` is unique to it.  This means that handles cannot be
+//! returned from the closure, stored anywhere that outlives it, or used with
+//! a different scope – all of these are compile-time errors.
+//!
+//! A `PersistentHandle` is not tied to a scope, so it can be stored in host
+//! data structures.  Persistent handles are reference counted: cloning one
+//! is cheap, and the value is released when the last clone is dropped.
+//! They are `Send`, so they can be passed between host threads and dropped
+//! on any of them, but they can only be dereferenced (`push_constant`) by
+//! the `State` that created them, which is checked at runtime.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use alloc::{self, Heap, PersistentRoots, RootStack};
+use value::Value;
+use super::State;
+
+/// A scope in which handles can be created.
+///
+/// The lifetime `'s` is a brand: it is unique to this scope, and is
+/// invariant so that it cannot be shortened or lengthened to match another
+/// scope.
+pub struct HandleScope<'s> {
+    roots: Rc<RootStack>,
+    base: usize,
+    depth: usize,
+    _brand: PhantomData<Cell<&'s ()>>,
+}
+
+/// A rooted reference to a Scheme value.
+///
+/// A `Handle` dereferences to the `Value` it holds.  The `Value` obtained
+/// this way must not be kept after the next allocation – keep the `Handle`
+/// instead.
+#[derive(Copy, Clone)]
+pub struct Handle<'s> {
+    slot: &'s Value,
+    _brand: PhantomData<Cell<&'s ()>>,
+}
+
+impl<'s> HandleScope<'s> {
+    fn new(roots: Rc<RootStack>) -> Self {
+        let base = roots.len();
+        let depth = roots.enter_scope();
+        HandleScope {
+            roots: roots,
+            base: base,
+            depth: depth,
+            _brand: PhantomData,
+        }
+    }
+
+    /// Roots `value` in this scope.
+    ///
+    /// `value` must have been obtained since the last allocation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this is not the innermost open scope.
+    pub fn root(&self, value: Value) -> Handle<'s> {
+        assert_eq!(self.depth, self.roots.depth(),
+                   "handles can only be created in the innermost scope");
+        let slot = self.roots.push(value);
+        Handle {
+            slot: unsafe { &*slot },
+            _brand: PhantomData,
+        }
+    }
+}
+
+impl<'s> Drop for HandleScope<'s> {
+    fn drop(&mut self) {
+        self.roots.exit_scope(self.depth, self.base)
+    }
+}
+
+impl<'s> Deref for Handle<'s> {
+    type Target = Value;
+    fn deref(&self) -> &Value {
+        self.slot
+    }
+}
+
+impl<'s> fmt::Debug for Handle<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handle({:x})", self.slot.get())
+    }
+}
+
+impl<'s> Handle<'s> {
+    /// Replaces the referent of this handle.
+    pub fn set(&self, value: Value) {
+        self.slot.set(value)
+    }
+
+    /// The value of the Rust data that this handle refers to, if it holds a
+    /// `T` (see `Vm::wrap`).  It stays alive at least as long as the scope.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&'s T> {
+        alloc::rust_data(self.slot)
+    }
+}
+
+/// A reference-counted reference to a Scheme value, which is rooted until
+/// the handle and all of its clones are dropped.  See
+/// `State::intern_constant`.
+pub struct PersistentHandle {
+    roots: Arc<PersistentRoots>,
+
+    /// The index of the root in `roots`.
     index: usize,
-    reference: &'a cell::UnsafeCell<Table>,
 }
-enum InternalHandle<T> {
-    SchemeObject(T),
-    AnotherHandle(usize),
+
+impl PersistentHandle {
+    /// Is the current thread the one that owns the handle's `State`?  If
+    /// not, the handle can be cloned and dropped, but not dereferenced.
+    pub fn on_owning_thread(&self) -> bool {
+        self.roots.on_owning_thread()
+    }
+}
+
+impl Clone for PersistentHandle {
+    fn clone(&self) -> Self {
+        self.roots.retain(self.index);
+        PersistentHandle {
+            roots: self.roots.clone(),
+            index: self.index,
+        }
+    }
 }
 
-struct Table {
-    table: Vec<Handle>,
-    first_free: usize,
+impl Drop for PersistentHandle {
+    fn drop(&mut self) {
+        self.roots.release(self.index)
+    }
 }
 
-impl<'a> Drop for Handle<'a> {
-    fn Drop(&mut self) {
-        self.reference.
+impl fmt::Debug for PersistentHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PersistentHandle({})", self.index)
+    }
+}
 
-impl Index<Handle, Output=T> for Table<T> {
-    fn index(&self, index: &Handle) -> &T {
-        match self[index.0] {
-            SchemeObject(ref x) => x,
-            AnotherHandle(n) => panic!("Expected object, found link {:?}", n),
+/// Pops the top of the stack of `heap` and roots it in a new persistent
+/// handle.
+pub fn persist(heap: &mut Heap) -> Result<PersistentHandle, String> {
+    match heap.stack.pop() {
+        Some(v) => {
+            Ok(PersistentHandle {
+                index: heap.persistent.add(v),
+                roots: heap.persistent.clone(),
+            })
         }
+        None => Err("Attempt to pop from empty stack".to_owned()),
     }
 }
 
-impl IndexMut<Handle> for Table<T> {
-    fn index(&self, index: &Handle) -> &T {
-        match self[index.0] {
-            SchemeObject(ref mut x) => x,
-            AnotherHandle(n) => panic!("Expected object, found link {:?}", n),
+/// The value of `handle`, which must have been created by `heap`.
+pub fn persistent_value(heap: &Heap, handle: &PersistentHandle) -> Result<Value, String> {
+    if !Arc::ptr_eq(&handle.roots, &heap.persistent) {
+        return Err(if handle.on_owning_thread() {
+            "persistent handle from another State".to_owned()
+        } else {
+            "persistent handle dereferenced on the wrong thread".to_owned()
+        })
+    }
+    Ok(heap.persistent.get(handle.index))
+}
+
+impl State {
+    /// Opens a new handle scope, and calls `f` with it.  All handles created
+    /// in the scope are released when `f` returns.
+    pub fn with_scope<F, R>(&mut self, f: F) -> R
+        where F: for<'s> FnOnce(&mut State, &HandleScope<'s>) -> R
+    {
+        let scope = HandleScope::new(self.state.heap.roots.clone());
+        f(self, &scope)
+    }
+
+    /// Pops the top of the stack and roots it in `scope`.
+    pub fn root<'s>(&mut self, scope: &HandleScope<'s>) -> Result<Handle<'s>, String> {
+        match self.state.heap.stack.pop() {
+            Some(v) => Ok(scope.root(v)),
+            None => Err("Attempt to pop from empty stack".to_owned()),
         }
     }
+
+    /// Pushes the value referred to by `handle` onto the stack.
+    pub fn push_handle(&mut self, handle: &Handle) {
+        self.state.heap.stack.push((**handle).clone())
+    }
+
+    /// Pops the top of the stack and roots it in a new persistent handle.
+    /// Access through the handle is O(1), so embedders can keep values
+    /// that they pass to Scheme often, such as the symbols naming their
+    /// events, instead of recreating them on every call.
+    pub fn intern_constant(&mut self) -> Result<PersistentHandle, String> {
+        persist(&mut self.state.heap)
+    }
+
+    /// Pushes the value referred to by `handle` onto the stack.  Fails if
+    /// `handle` was created by a different `State`.
+    pub fn push_constant(&mut self, handle: &PersistentHandle) -> Result<(), String> {
+        let value = try!(persistent_value(&self.state.heap, handle));
+        Ok(self.state.heap.stack.push(value))
+    }
 }
 
-impl Drop for Handle
+#[cfg(test)]
+mod tests {
+    use api::State;
+    use value::Tags;
+
+    #[test]
+    fn handles_survive_collection() {
+        let mut interp = State::new();
+        interp.with_scope(|interp, scope| {
+            interp.push_nil();
+            interp.push_nil();
+            interp.cons().unwrap();
+            let pair = interp.root(scope).unwrap();
+            interp.drop().unwrap();
+            interp.drop().unwrap();
+            assert!(interp.is_empty());
+            interp.gc();
+            assert_eq!(pair.tag(), Tags::Pair);
+            interp.push_handle(&pair);
+            interp.cdr().unwrap();
+            assert!(interp.pop::<bool>().is_err());
+        });
+        assert!(interp.state.heap.roots.is_empty())
+    }
+
+    #[test]
+    fn nested_scopes_release_their_roots() {
+        let mut interp = State::new();
+        interp.with_scope(|interp, outer| {
+            interp.push_true();
+            let t = interp.root(outer).unwrap();
+            interp.with_scope(|interp, inner| {
+                interp.push_false();
+                let _ = interp.root(inner).unwrap();
+                assert_eq!(interp.state.heap.roots.len(), 2);
+            });
+            assert_eq!(interp.state.heap.roots.len(), 1);
+            interp.push_handle(&t);
+            assert_eq!(interp.pop::<bool>(), Ok(true));
+        })
+    }
 
-impl Iterator<Item=T> for Table<T> {
+    #[test]
+    fn constants_survive_collection() {
+        let mut interp = State::new();
+        interp.push("click".to_owned()).unwrap();
+        let click = interp.intern_constant().unwrap();
+        interp.push(5).unwrap();
+        let five = interp.intern_constant().unwrap();
+        assert!(interp.is_empty());
+        for _ in 0..2 {
+            interp.gc();
+            interp.push_constant(&click).unwrap();
+            assert_eq!(interp.pop::<String>(), Ok("click".to_owned()));
+        }
+        interp.push_constant(&five).unwrap();
+        assert_eq!(interp.pop::<usize>(), Ok(5));
+        assert!(State::new().push_constant(&five).is_err());
+    }
+
+    #[test]
+    fn persistent_handles_are_released_by_the_last_clone() {
+        use std::thread;
+        let mut interp = State::new();
+        interp.push(1).unwrap();
+        let one = interp.intern_constant().unwrap();
+        let clone = one.clone();
+        let roots = interp.state.heap.persistent.clone();
+        drop(one);
+        assert_eq!(roots.len(), 1);
+        // Handles can be dropped on other threads.
+        thread::spawn(move || {
+                assert!(!clone.on_owning_thread());
+                drop(clone)
+            })
+            .join()
+            .unwrap();
+        assert!(roots.is_empty());
+        interp.push(2).unwrap();
+        let two = interp.intern_constant().unwrap();
+        interp.push_constant(&two).unwrap();
+        assert_eq!(interp.pop::<usize>(), Ok(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn only_innermost_scope_can_root() {
+        let mut interp = State::new();
+        interp.with_scope(|interp, outer| {
+            interp.with_scope(|interp, _| {
+                interp.push_true();
+                let _ = interp.root(outer);
+            })
+        })
+    }
+}
@@ -1,5 +1,9 @@
 //! The public Rust embedding API of `RustyScheme`.  Very unstable.
 //!
+//! Most embedders want `Vm`, which hides the stack described below behind
+//! rooted values (see `vm`), converted to and from Rust values with the
+//! traits in `convert`.
+//!
 //! This API is similar to Lua's embedding API, in that an explicit stack is
 //! used.
 //!
@@ -10,7 +14,7 @@
 //! let mut interp = rusty_scheme::State::new();
 //!
 //! // Push onto the stack.  Always works, unless the interpreter
-//! // hits the memory limit set by the embedder (not yet implemented).
+//! // hits the memory limit set by the embedder.
 //! assert!(interp.push(23).is_ok());
 //! assert!(interp.push(175).is_ok());
 //!
@@ -29,9 +33,33 @@
 
 extern crate env_logger;
 
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
 mod pool;
+mod handle;
+#[macro_use]
+mod convert;
+mod error;
+mod vm;
+
+pub use self::convert::{FieldReader, FieldWriter, FromArgs, FromScheme, IntoScheme, Record};
+pub use self::handle::{Handle, HandleScope, PersistentHandle};
+pub use self::error::{ErrorKind, SchemeError};
+pub use self::vm::{Context, Vm};
+pub use builtins::Arity;
+pub use alloc::{GcKind, GcStats, GcTrace, HeapStats};
+pub use audit::Event;
+pub use interp::{Frame, Outcome, Suspended};
+pub use profiler::{Cost, Profile, ProfileEntry};
+pub use shared::SharedTable;
+pub use repl::{LineSource, ReplOptions};
 
 use interp;
+use print;
+use record;
+use repl;
 use value;
 use alloc;
 use arith;
@@ -43,16 +71,16 @@ pub struct State {
 
 // Unsafe because the return value is not rooted
 pub unsafe trait SchemeValue: Sized {
-    fn to_value(&self, heap: &mut alloc::Heap) -> value::Value;
+    fn to_value(&self, heap: &mut alloc::Heap) -> Result<value::Value, alloc::OutOfMemory>;
     fn of_value(val: &value::Value) -> Result<Self, String>;
 }
 
 unsafe impl SchemeValue for usize {
-    fn to_value(&self, _: &mut alloc::Heap) -> value::Value {
+    fn to_value(&self, _: &mut alloc::Heap) -> Result<value::Value, alloc::OutOfMemory> {
         if self & 3 << (size_of!(usize) * 8 - 2) != 0 {
             panic!("bignums not yet supported")
         } else {
-            value::Value::new(self << 2)
+            Ok(value::Value::new(self << 2))
         }
     }
     fn of_value(val: &value::Value) -> Result<Self, String> {
@@ -61,12 +89,12 @@ unsafe impl SchemeValue for usize {
 }
 
 unsafe impl SchemeValue for bool {
-    fn to_value(&self, _: &mut alloc::Heap) -> value::Value {
-        value::Value::new(if *self {
+    fn to_value(&self, _: &mut alloc::Heap) -> Result<value::Value, alloc::OutOfMemory> {
+        Ok(value::Value::new(if *self {
             value::TRUE
         } else {
             value::FALSE
-        })
+        }))
     }
     fn of_value(val: &value::Value) -> Result<Self, String> {
         match val.get() {
@@ -77,6 +105,15 @@ unsafe impl SchemeValue for bool {
     }
 }
 
+unsafe impl SchemeValue for char {
+    fn to_value(&self, _: &mut alloc::Heap) -> Result<value::Value, alloc::OutOfMemory> {
+        Ok(value::Value::character(*self))
+    }
+    fn of_value(val: &value::Value) -> Result<Self, String> {
+        val.as_char().ok_or_else(|| format!("Bad char {:x}", val.get()))
+    }
+}
+
 impl Default for State {
     fn default() -> Self {
         Self::new()
@@ -90,16 +127,50 @@ impl State {
         }
     }
 
+    /// Creates a state that uses the shared constants `table` (see
+    /// `SharedTable`), which any number of other states can use too.
+    pub fn with_shared(table: Arc<SharedTable>) -> Self {
+        let mut state = Self::new();
+        state.state.heap.share(table);
+        state
+    }
+
+    /// Adds an audit hook, which is called before code run in this state
+    /// does anything in `Event`, and can veto it by returning an error.
+    pub fn add_audit_hook<F>(&mut self, hook: F)
+        where F: FnMut(&Event) -> Result<(), String> + 'static
+    {
+        self.state.audit.push(Box::new(hook))
+    }
+
     pub fn execute_bytecode(&mut self) -> Result<(), String> {
         interp::interpret_bytecode(&mut self.state)
     }
 
+    /// Pushes `value` onto the stack.  Fails if the memory limit was hit.
     pub fn push<T: SchemeValue>(&mut self, value: T) -> Result<(), ()> {
         let state = &mut self.state;
-        let new_val = value.to_value(&mut state.heap);
+        let new_val = try!(value.to_value(&mut state.heap).map_err(|_| ()));
         Ok(state.heap.stack.push(new_val))
     }
 
+    /// Registers `hook` to be run after every garbage collection.  See
+    /// `alloc::Heap::on_gc`.
+    pub fn on_gc<F: FnMut(&alloc::GcStats) + 'static>(&mut self, hook: F) {
+        self.state.heap.on_gc(hook)
+    }
+
+    /// Statistics about the heap.  See `alloc::Heap::stats`.
+    pub fn heap_stats(&self) -> HeapStats {
+        self.state.heap.stats()
+    }
+
+    /// Sets the maximum size of the heap in bytes, or removes the limit.
+    /// See `alloc::Heap::set_limit`.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.state.heap.set_limit(limit)
+    }
+
     /// Pops the top of the stack and converts it to a Rust value.
     pub fn pop<T: SchemeValue>(&mut self) -> Result<T, String> {
         let x = self.state.heap.stack.pop();
@@ -121,7 +192,7 @@ impl State {
     pub fn cons(&mut self) -> Result<(), String> {
         let len = self.state.heap.stack.len();
         debug_assert!(len > 1);
-        self.state.heap.alloc_pair(len - 2, len - 1);
+        try!(self.state.heap.alloc_pair(len - 2, len - 1));
         Ok(())
     }
 
@@ -137,7 +208,7 @@ impl State {
         for _ in 0..(arg) {
             let q = self.len();
             try!(self.cons());
-            self.store(2, 0);
+            self.store(0, 2);
             self.state.heap.stack.pop();
             self.state.heap.stack.pop();
             debug_assert_eq!(q, self.len() + 1)
@@ -215,23 +286,45 @@ impl State {
         Ok(())
     }
 
-    pub fn exponential(&mut self, src: usize, src2: usize, _dst: usize) -> Result<(), String> {
+    pub fn exponential(&mut self, src: usize, src2: usize, dst: usize) -> Result<(), String> {
         // See above.
         let fp = self.fp;
         let heap = &mut self.state.heap;
         let (fst, snd) = (heap.stack[src - fp].clone(), heap.stack[src2 - fp].clone());
-        heap.stack[_dst - fp] = arith::exponential(fst, snd);
+        heap.stack[dst - fp] = try!(arith::exponential(heap, &fst, &snd));
+        Ok(())
+    }
+
+    /// Replaces the topmost `len` values, a record type name followed by
+    /// the fields, with a record of the readable record type of that name
+    /// (see `record`).
+    pub fn readable_record(&mut self, len: usize) -> Result<(), String> {
+        let heap = &mut self.state.heap;
+        if len == 0 || len > heap.stack.len() {
+            return Err("readable_record: no record type name".to_owned())
+        }
+        let start = heap.stack.len() - len;
+        heap.stack[start] = try!(self.state.readable.get(heap, &heap.stack[start]));
+        try!(record::make_record(heap, start, start + len));
+        let record = heap.stack.pop().unwrap();
+        heap.stack.truncate(start);
+        heap.stack.push(record);
+        Ok(())
     }
 
     pub fn vector(&mut self, src: usize, src2: usize) -> Result<(), String> {
         debug_assert!(src2 >= src);
-        Ok(alloc::Heap::alloc_vector(&mut self.state.heap, src, src2))
+        try!(alloc::Heap::alloc_vector(&mut self.state.heap, src, src2));
+        Ok(())
     }
 
     pub fn array_set(&mut self, index: usize, src: usize, dst: usize) -> Result<(), String> {
         let fp = self.fp;
         let heap = &mut self.state.heap;
-        heap.stack[dst - fp].array_set(index, &heap.stack[src])
+        try!(heap.stack[dst - fp].array_set(index, &heap.stack[src]));
+        let (object, new) = (heap.stack[dst - fp].clone(), heap.stack[src].clone());
+        heap.write_barrier(&object, &new);
+        Ok(())
     }
 
     pub fn array_get(&mut self, index: usize, src: usize, dst: usize) -> Result<(), String> {
@@ -254,13 +347,38 @@ impl State {
 
     pub fn push_nil(&mut self) {
         let heap = &mut self.state.heap;
-        heap.stack.push(value::Value::new(value::NIL))
+        heap.stack.push(value::Value::nil())
     }
 
     pub fn load_global(&mut self) -> Result<(), String> {
+        try!(self.state.builtins.resolve_global(&mut self.state.heap));
         self.state.heap.load_global()
     }
 
+    /// Registers the builtin library named `library`, such as
+    /// `&["rusty", "base"]`.
+    ///
+    /// This is never required – builtins are registered on first use – but
+    /// importing a library eagerly avoids the lookup later.
+    pub fn import(&mut self, library: &[&str]) -> Result<(), String> {
+        self.state.builtins.import(&mut self.state.heap, library)
+    }
+
+    /// Pops a procedure and the `argc` arguments above it, and queues a
+    /// call of the procedure with them, to be run by `poll_pending_work`.
+    /// Native procedures queue calls the same way, with `interp::enqueue`.
+    pub fn enqueue(&mut self, argc: usize) -> Result<(), String> {
+        interp::enqueue(&mut self.state, argc)
+    }
+
+    /// Runs the calls queued since the last poll, and returns how many ran.
+    /// Hosts with an event loop call this once per iteration, so that
+    /// Scheme callbacks run without blocking inside a native procedure.
+    /// See `interp::poll_pending_work`.
+    pub fn poll_pending_work(&mut self) -> Result<usize, String> {
+        interp::poll_pending_work(&mut self.state)
+    }
+
     pub fn load(&mut self, src: usize) {
         let stack = &mut self.state.heap.stack;
         let val = stack[stack.len() - src - 1].clone();
@@ -287,6 +405,95 @@ impl State {
     pub fn gc(&mut self) {
         alloc::collect(&mut self.state.heap)
     }
+
+    /// The external representation of the top of the stack, as Scheme's
+    /// `write` prints it.  This can run Scheme code: records are printed
+    /// by their printers.
+    pub fn write(&mut self) -> Result<String, String> {
+        print::print(&mut self.state, false)
+    }
+
+    /// The top of the stack, as Scheme's `display` prints it.
+    pub fn display(&mut self) -> Result<String, String> {
+        print::print(&mut self.state, true)
+    }
+
+    /// Evaluates a line of REPL input, and pushes its value, which is
+    /// remembered in the REPL history (see `repl`).
+    pub fn eval_interactive(&mut self, source: &str) -> Result<(), String> {
+        repl::eval(&mut self.state, source)
+    }
+
+    /// Runs an interactive REPL, reading lines from `lines` and writing
+    /// prompts, values, and errors to `out`, until `lines` runs out (see
+    /// `repl::run`).
+    pub fn run_repl<L, W>(&mut self, lines: L, out: W, options: &ReplOptions) -> io::Result<()>
+        where L: LineSource,
+              W: Write
+    {
+        repl::run(&mut self.state, lines, out, options)
+    }
+
+    /// Sets the number of values that the REPL history remembers.
+    pub fn set_history_length(&mut self, length: usize) {
+        self.state.history.set_length(&self.state.heap, length)
+    }
+
+    /// The interrupt flag.  Setting it, from a Ctrl-C handler or another
+    /// thread, stops the Scheme code that is running with an "interrupted"
+    /// error soon after, so that a runaway loop does not hang the host.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.state.interrupt.clone()
+    }
+
+    /// Calls the procedure below the topmost `argc` values on the stack,
+    /// replacing it and its arguments with the result, but fails with "out
+    /// of fuel" if it runs for more than about `fuel` instructions.
+    pub fn run_with_fuel(&mut self, argc: usize, fuel: usize) -> Result<(), String> {
+        interp::run_with_fuel(&mut self.state, argc, fuel)
+    }
+
+    /// Calls the procedure below the topmost `argc` values on the stack for
+    /// at most about `max_instructions` instructions, and pauses it if it
+    /// has not returned by then (see `interp::eval_with_budget`).
+    pub fn eval_with_budget(&mut self,
+                            argc: usize,
+                            max_instructions: usize)
+                            -> Result<Outcome, String> {
+        interp::eval_with_budget(&mut self.state, argc, max_instructions)
+    }
+
+    /// Continues a call paused by `eval_with_budget`.
+    pub fn resume(&mut self,
+                  suspended: Suspended,
+                  max_instructions: usize)
+                  -> Result<Outcome, String> {
+        interp::resume(&mut self.state, suspended, max_instructions)
+    }
+
+    /// Frees a paused call that will not be resumed.
+    pub fn cancel(&mut self, suspended: Suspended) {
+        interp::cancel(&mut self.state, suspended)
+    }
+
+    /// Sets how deep calls may recurse: how many frames may be waiting for
+    /// calls to return, after which calls fail with "maximum recursion
+    /// depth exceeded" (`interp::DEFAULT_MAX_FRAMES` by default).
+    pub fn set_max_frames(&mut self, frames: usize) {
+        self.state.max_frames = frames
+    }
+
+    /// Turns the profiler on or off.  While it is on, the instructions run
+    /// and the bytes allocated are counted per Scheme function, more slowly.
+    pub fn set_profiling(&mut self, on: bool) {
+        interp::set_profiling(&mut self.state, on)
+    }
+
+    /// Takes what the profiler has counted so far, most costly function
+    /// first, and starts counting from zero.
+    pub fn profile(&mut self) -> Profile {
+        interp::profile(&mut self.state)
+    }
 }
 
 #[cfg(test)]
@@ -340,6 +547,7 @@ mod tests {
         let x: Result<usize, _> = interp.pop();
         assert!(x.is_err());
         interp.gc();
-        assert_eq!(interp.state.heap.symbol_table.contents.len(), 0)
+        // Every symbol has a value, so none of them can be dropped.
+        assert_eq!(interp.state.heap.symbol_table.contents.len(), 100)
     }
 }
@@ -0,0 +1,602 @@
+//! `Vm`: the high-level embedding API.
+//!
+//! `State` is a stack machine, like Lua's API: values are pushed, operated
+//! on, and popped, and it is up to the embedder to keep them rooted.  `Vm`
+//! wraps a `State` for embedders who would rather not think about the
+//! stack.  Everything it returns is a `PersistentHandle`, which stays
+//! valid across collections until it is dropped, and every error is a
+//! `SchemeError`:
+//!
+//! ```rust
+//! let mut vm = rusty_scheme::Vm::new();
+//! let three = vm.eval_str("(+ 1 2)").unwrap();
+//! assert_eq!(vm.get::<usize>(&three), Ok(3));
+//! let four = vm.value(4usize).unwrap();
+//! vm.define("four", &four).unwrap();
+//! let seven = vm.eval_str("(+ four 3)").unwrap();
+//! assert_eq!(vm.get::<usize>(&seven), Ok(7));
+//! ```
+//!
+//! Rust closures become Scheme procedures with `register_fn`.  They get a
+//! `Context`, which has the same API as `Vm`, to make their results with.
+//! `register_typed` does the conversions itself, for closures of Rust
+//! values.
+//!
+//! Rust values of other types are stored on the heap with `wrap`, and
+//! borrowed back with `downcast_ref`.
+//!
+//! A `SchemeError` has a kind and a backtrace (see `error`).  Errors that
+//! registered procedures return are raised in Scheme as conditions, and
+//! `condition` and `error_of` convert between the two.
+//!
+//! The `State` underneath is always available, through `state`, for what
+//! the facade does not cover.
+
+use std::any::Any;
+use std::hash::Hash;
+use std::mem;
+
+use alloc::{self, RustData};
+use builtins::Arity;
+use builtins::errors;
+use compiler;
+use interp;
+use value::Value;
+use super::handle;
+use super::{ErrorKind, FromArgs, FromScheme, IntoScheme, PersistentHandle, SchemeError, State};
+
+fn syntax_error(message: String) -> SchemeError {
+    SchemeError::new(ErrorKind::SyntaxError, message)
+}
+
+/// Raises `e` in `s`, as a condition of its kind, or as the value that
+/// Scheme code raised.  Returns the message of the error that raises it.
+fn raise(s: &mut interp::State, e: SchemeError) -> String {
+    s.backtrace = e.backtrace().to_vec();
+    let base = s.heap.stack.len();
+    let pushed = match (e.kind(), e.kind().condition_kind()) {
+        (&ErrorKind::UserRaised(ref handle), _) => {
+            handle::persistent_value(&s.heap, handle).map(|value| s.heap.stack.push(value))
+        }
+        (_, kind) => {
+            e.message()
+             .to_owned()
+             .into_scheme(s)
+             .and_then(|()| errors::push_condition(s, kind.unwrap(), base, base + 1))
+        }
+    };
+    let value = if pushed.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    match value {
+        Some(value) => errors::raise_value(s, value, e.message().to_owned()),
+        None => e.message().to_owned(),
+    }
+}
+
+/// The value of the Rust data that `handle` refers to, if it holds a `T`.
+/// The handle keeps the data alive, so it can be borrowed for as long as
+/// the handle and the heap.
+fn downcast<'a, T: Any>(heap: &'a alloc::Heap, handle: &'a PersistentHandle) -> Option<&'a T> {
+    let value = match handle::persistent_value(heap, handle) {
+        Ok(value) => value,
+        Err(_) => return None,
+    };
+    alloc::rust_data(&value).map(|x| unsafe { &*(x as *const T) })
+}
+
+/// A Scheme interpreter, with an API of rooted values.
+#[derive(Default)]
+pub struct Vm {
+    state: State,
+}
+
+/// The API of `Vm`, over an interpreter that may be running: procedures
+/// registered with `Vm::register_fn` are passed one, so that they can make
+/// and inspect values.
+pub struct Context<'a> {
+    s: &'a mut interp::State,
+}
+
+impl<'a> Context<'a> {
+    /// Runs `f`, which leaves one value on the stack if it succeeds, and
+    /// returns that value.  If it fails, whatever it left on the stack is
+    /// popped.
+    fn rooted<F>(&mut self, f: F) -> Result<PersistentHandle, SchemeError>
+        where F: FnOnce(&mut interp::State) -> Result<(), String>
+    {
+        let base = self.s.heap.stack.len();
+        self.rooted_above(base, f)
+    }
+
+    /// Like `rooted`, but if `f` fails, the stack is cut back to `base`,
+    /// so that whatever was pushed for `f` is popped too.
+    fn rooted_above<F>(&mut self, base: usize, f: F) -> Result<PersistentHandle, SchemeError>
+        where F: FnOnce(&mut interp::State) -> Result<(), String>
+    {
+        match f(self.s).and_then(|()| handle::persist(&mut self.s.heap)) {
+            Ok(handle) => Ok(handle),
+            Err(e) => {
+                self.s.heap.stack.truncate(base);
+                Err(self.error(e))
+            }
+        }
+    }
+
+    /// The `SchemeError` of the error of the interpreter whose message is
+    /// `message`, which has just been raised.
+    fn error(&mut self, message: String) -> SchemeError {
+        let backtrace = mem::replace(&mut self.s.backtrace, vec![]);
+        let kind = match self.s.raised.take() {
+            Some((root, ref raised)) if *raised == message => {
+                let value = self.s.heap.persistent.get(root);
+                self.s.heap.persistent.release(root);
+                self.kind_of_raised(value)
+            }
+            Some((root, _)) => {
+                self.s.heap.persistent.release(root);
+                ErrorKind::of_message(&message)
+            }
+            None => ErrorKind::of_message(&message),
+        };
+        SchemeError::new(kind, message).with_backtrace(backtrace)
+    }
+
+    /// The kind of the error that raises `value`.
+    fn kind_of_raised(&mut self, value: Value) -> ErrorKind {
+        match errors::condition(&value).and_then(|(kind, message)| {
+            ErrorKind::of_condition(&kind, &message)
+        }) {
+            Some(kind) => kind,
+            None => {
+                self.s.heap.stack.push(value);
+                ErrorKind::UserRaised(handle::persist(&mut self.s.heap).unwrap())
+            }
+        }
+    }
+
+    /// A condition of the kind of `e` (see `builtins::errors`), with its
+    /// backtrace, or the value that Scheme code raised.
+    pub fn condition(&mut self, e: &SchemeError) -> Result<PersistentHandle, SchemeError> {
+        let message = e.message().to_owned();
+        match (e.kind(), e.kind().condition_kind()) {
+            (&ErrorKind::UserRaised(ref handle), _) => Ok(handle.clone()),
+            (_, kind) => {
+                self.rooted(|s| {
+                    let base = s.heap.stack.len();
+                    try!(message.into_scheme(s));
+                    let res = errors::push_condition(s, kind.unwrap(), base, base + 1);
+                    let condition = s.heap.stack.pop();
+                    s.heap.stack.truncate(base);
+                    try!(res);
+                    s.heap.stack.push(condition.unwrap());
+                    errors::set_backtrace(s, base, e.backtrace())
+                })
+            }
+        }
+    }
+
+    /// Describes the frames of the backtrace of `e`, innermost first, as
+    /// the functions running in them and where those came from.
+    pub fn backtrace(&self, e: &SchemeError) -> Vec<String> {
+        e.backtrace().iter().map(|frame| frame.describe(self.s)).collect()
+    }
+
+    /// The error that raising `handle` from Scheme code would be.
+    pub fn error_of(&mut self, handle: &PersistentHandle) -> Result<SchemeError, SchemeError> {
+        let value = try!(handle::persistent_value(&self.s.heap, handle));
+        let message = match errors::condition(&value) {
+            Some((_, message)) => message,
+            None => "uncaught exception".to_owned(),
+        };
+        Ok(SchemeError::new(self.kind_of_raised(value), message))
+    }
+
+    /// Pushes the value of `handle`.
+    fn push(&mut self, handle: &PersistentHandle) -> Result<(), SchemeError> {
+        let value = try!(handle::persistent_value(&self.s.heap, handle));
+        Ok(self.s.heap.stack.push(value))
+    }
+
+    /// Evaluates the forms in `source`, and returns the value of the last.
+    pub fn eval_str(&mut self, source: &str) -> Result<PersistentHandle, SchemeError> {
+        let (forms, lines) = try!(compiler::read_lines(source.as_bytes())
+                                      .map_err(|e| syntax_error(format!("read error: {:?}", e))));
+        let program = try!(compiler::compile_toplevel(&mut self.s.libraries,
+                                                      &mut self.s.macros,
+                                                      &forms,
+                                                      &lines)
+                               .map_err(syntax_error));
+        self.rooted(|s| {
+            try!(interp::load(s, &program));
+            interp::call(s, 0)
+        })
+    }
+
+    /// Converts `x` to a Scheme value.
+    pub fn value<T: IntoScheme>(&mut self, x: T) -> Result<PersistentHandle, SchemeError> {
+        self.rooted(|s| x.into_scheme(s))
+    }
+
+    /// Calls the procedure `procedure` with the arguments `args`, and
+    /// returns its result.  This may be done from a registered procedure,
+    /// which Scheme code is calling.
+    pub fn call(&mut self,
+                procedure: &PersistentHandle,
+                args: &[PersistentHandle])
+                -> Result<PersistentHandle, SchemeError> {
+        let base = self.s.heap.stack.len();
+        for x in Some(procedure).into_iter().chain(args) {
+            if let Err(e) = self.push(x) {
+                self.s.heap.stack.truncate(base);
+                return Err(e)
+            }
+        }
+        self.rooted_above(base, |s| interp::call(s, args.len()))
+    }
+
+    /// Stores `x` on the heap, as Rust data that is only `equal?` to
+    /// itself.  It is dropped once the value is collected.
+    pub fn wrap<T: Any>(&mut self, x: T) -> Result<PersistentHandle, SchemeError> {
+        self.rooted(|s| s.heap.alloc_resource(RustData::new(x)).map_err(String::from))
+    }
+
+    /// Stores `x` on the heap, as Rust data that is `equal?` to Rust data
+    /// holding an equal `T`.
+    pub fn wrap_comparable<T>(&mut self, x: T) -> Result<PersistentHandle, SchemeError>
+        where T: Any + PartialEq + Hash
+    {
+        self.rooted(|s| s.heap.alloc_resource(RustData::comparable(x)).map_err(String::from))
+    }
+
+    /// The value of the Rust data that `handle` refers to, if it holds a
+    /// `T`.
+    pub fn downcast_ref<'b, T: Any>(&'b self, handle: &'b PersistentHandle) -> Option<&'b T> {
+        downcast(&self.s.heap, handle)
+    }
+
+    /// Converts the value of `handle` to a `T`.
+    pub fn get<T: FromScheme>(&mut self, handle: &PersistentHandle) -> Result<T, SchemeError> {
+        let value = try!(handle::persistent_value(&self.s.heap, handle));
+        T::from_scheme(&value).map_err(SchemeError::from)
+    }
+
+    /// Binds the global variable `name` to the value of `handle`.
+    pub fn define(&mut self, name: &str, handle: &PersistentHandle) -> Result<(), SchemeError> {
+        try!(self.push(handle));
+        self.s.heap.intern(name);
+        self.s.heap.store_global().map_err(SchemeError::from)
+    }
+
+    /// The value of the global variable `name`.
+    pub fn lookup(&mut self, name: &str) -> Result<PersistentHandle, SchemeError> {
+        self.rooted(|s| {
+            s.heap.intern(name);
+            try!(s.builtins.resolve_global(&mut s.heap));
+            s.heap.load_global()
+        })
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm { state: State::new() }
+    }
+
+    /// The `State` underneath.
+    pub fn state(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    /// The API of the `Vm`, as passed to registered procedures.
+    pub fn context(&mut self) -> Context {
+        Context { s: &mut self.state.state }
+    }
+
+    /// Evaluates the forms in `source`, and returns the value of the last.
+    pub fn eval_str(&mut self, source: &str) -> Result<PersistentHandle, SchemeError> {
+        self.context().eval_str(source)
+    }
+
+    /// Converts `x` to a Scheme value.
+    pub fn value<T: IntoScheme>(&mut self, x: T) -> Result<PersistentHandle, SchemeError> {
+        self.context().value(x)
+    }
+
+    /// Calls the procedure `procedure` with the arguments `args`, and
+    /// returns its result.
+    pub fn call(&mut self,
+                procedure: &PersistentHandle,
+                args: &[PersistentHandle])
+                -> Result<PersistentHandle, SchemeError> {
+        self.context().call(procedure, args)
+    }
+
+    /// Converts the value of `handle` to a `T`.
+    pub fn get<T: FromScheme>(&mut self, handle: &PersistentHandle) -> Result<T, SchemeError> {
+        self.context().get(handle)
+    }
+
+    /// A condition of the kind of `e`, or the value that Scheme code raised.
+    pub fn condition(&mut self, e: &SchemeError) -> Result<PersistentHandle, SchemeError> {
+        self.context().condition(e)
+    }
+
+    /// The error that raising `handle` from Scheme code would be.
+    pub fn error_of(&mut self, handle: &PersistentHandle) -> Result<SchemeError, SchemeError> {
+        self.context().error_of(handle)
+    }
+
+    /// Describes the frames of the backtrace of `e`, innermost first.
+    pub fn backtrace(&mut self, e: &SchemeError) -> Vec<String> {
+        self.context().backtrace(e)
+    }
+
+    /// Stores `x` on the heap, as Rust data that is only `equal?` to
+    /// itself.  It is dropped once the value is collected.
+    pub fn wrap<T: Any>(&mut self, x: T) -> Result<PersistentHandle, SchemeError> {
+        self.context().wrap(x)
+    }
+
+    /// Stores `x` on the heap, as Rust data that is `equal?` to Rust data
+    /// holding an equal `T`.
+    pub fn wrap_comparable<T>(&mut self, x: T) -> Result<PersistentHandle, SchemeError>
+        where T: Any + PartialEq + Hash
+    {
+        self.context().wrap_comparable(x)
+    }
+
+    /// The value of the Rust data that `handle` refers to, if it holds a
+    /// `T`.  It lives at least as long as the handle.
+    pub fn downcast_ref<'a, T: Any>(&'a self, handle: &'a PersistentHandle) -> Option<&'a T> {
+        downcast(&self.state.state.heap, handle)
+    }
+
+    /// Binds the global variable `name` to the value of `handle`.
+    pub fn define(&mut self, name: &str, handle: &PersistentHandle) -> Result<(), SchemeError> {
+        self.context().define(name, handle)
+    }
+
+    /// The value of the global variable `name`.
+    pub fn lookup(&mut self, name: &str) -> Result<PersistentHandle, SchemeError> {
+        self.context().lookup(name)
+    }
+
+    /// Defines the global procedure `name`, which accepts `arity` arguments,
+    /// as a call of `f`.  Calling it with the wrong number of arguments is
+    /// an error, without calling `f`.  `f` gets the arguments as handles,
+    /// which stay rooted for as long as it keeps them, and its error
+    /// becomes the error of the call.
+    pub fn register_fn<F>(&mut self, name: &str, arity: Arity, f: F)
+        where F: Fn(&mut Context, &[PersistentHandle]) -> Result<PersistentHandle, SchemeError> +
+                 'static
+    {
+        let function = move |s: &mut interp::State, argc: usize| {
+            let len = s.heap.stack.len();
+            let mut args = Vec::with_capacity(argc);
+            for i in len - argc..len {
+                let arg = s.heap.stack[i].clone();
+                s.heap.stack.push(arg);
+                args.push(try!(handle::persist(&mut s.heap)))
+            }
+            let result = match f(&mut Context { s: s }, &args) {
+                Ok(result) => result,
+                Err(e) => return Err(raise(s, e)),
+            };
+            // Dropping the handles does not allocate, so the value stays
+            // valid.
+            handle::persistent_value(&s.heap, &result)
+        };
+        let s = &mut self.state.state;
+        s.builtins.register_host(&mut s.heap, name, arity, Box::new(function))
+    }
+
+    /// Defines the global procedure `name` as a call of `f`, with its
+    /// arguments converted to `A`, and its result converted from `R` (see
+    /// `convert`).  Arguments that cannot be converted are an error.
+    pub fn register_typed<A, R, F>(&mut self, name: &str, f: F)
+        where A: FromArgs,
+              R: IntoScheme,
+              F: Fn(A) -> Result<R, SchemeError> + 'static
+    {
+        let owned_name = name.to_owned();
+        let function = move |s: &mut interp::State, argc: usize| {
+            let args = {
+                let len = s.heap.stack.len();
+                try!(A::from_args(&s.heap.stack[len - argc..])
+                         .map_err(|e| format!("{}: {}", owned_name, e)))
+            };
+            match f(args) {
+                Ok(result) => try!(result.into_scheme(s)),
+                Err(e) => return Err(raise(s, e)),
+            }
+            Ok(s.heap.stack.pop().unwrap())
+        };
+        let s = &mut self.state.state;
+        s.builtins.register_host(&mut s.heap, name, A::arity(), Box::new(function))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_stay_rooted_between_calls() {
+        let mut vm = Vm::new();
+        let list = vm.eval_str("(define (f n) (cons n '())) (f 6)").unwrap();
+        vm.define("xs", &list).unwrap();
+        vm.state().gc();
+        let n = vm.eval_str("(* (car xs) 7)").unwrap();
+        assert_eq!(vm.get::<usize>(&n), Ok(42));
+        assert!(vm.get::<bool>(&n).is_err());
+        let yes = vm.value(true).unwrap();
+        vm.define("yes", &yes).unwrap();
+        let f = vm.lookup("yes").unwrap();
+        assert_eq!(vm.get::<bool>(&f), Ok(true));
+        assert!(vm.state().is_empty());
+    }
+
+    #[test]
+    fn registered_functions_are_procedures() {
+        let mut vm = Vm::new();
+        vm.register_fn("add-and-collect", Arity::Exactly(2), |cx, args| {
+            let (x, y) = (try!(cx.get::<usize>(&args[0])), try!(cx.get::<usize>(&args[1])));
+            // The arguments are rooted.
+            try!(cx.eval_str("(define garbage (cons 1 2))"));
+            let sum = try!(cx.value(x + y));
+            assert_eq!(cx.get::<usize>(&args[1]), Ok(y));
+            Ok(sum)
+        });
+        vm.register_fn("fail", Arity::AtLeast(0), |_, _| Err(SchemeError::from("failed")));
+        let sum = vm.eval_str("(define (f x) (add-and-collect x 2)) (f (f 1))").unwrap();
+        assert_eq!(vm.get::<usize>(&sum), Ok(5));
+        assert!(vm.eval_str("(add-and-collect 1)").unwrap_err().message().contains("arguments"));
+        assert_eq!(vm.eval_str("(fail 1 2 3)").map(|_| ()),
+                   Err(SchemeError::from("failed")));
+        assert!(vm.state().is_empty());
+    }
+
+    #[test]
+    fn calls_can_nest_across_the_boundary() {
+        let mut vm = Vm::new();
+        vm.register_fn("twice", Arity::Exactly(2), |cx, args| {
+            let once = try!(cx.call(&args[0], &args[1..]));
+            // Collect, while the procedure and the first result are not on
+            // the stack.
+            try!(cx.eval_str("(let loop ((n 100))
+                                (if (= n 0) 0 (begin (cons 1 2) (loop (- n 1)))))"));
+            cx.call(&args[0], &[once])
+        });
+        let f = vm.eval_str("(lambda (x) (twice (lambda (y) (* y 3)) (+ x 1)))").unwrap();
+        let two = vm.value(2usize).unwrap();
+        let result = vm.call(&f, &[two.clone()]).unwrap();
+        assert_eq!(vm.get::<usize>(&result), Ok(27));
+        let car = vm.lookup("car").unwrap();
+        assert!(vm.call(&car, &[two]).is_err());
+        assert!(vm.call(&f, &[]).is_err());
+        assert!(vm.state().is_empty());
+    }
+
+    #[test]
+    fn typed_functions_convert_their_arguments() {
+        let mut vm = Vm::new();
+        vm.register_typed("sum", |xs: Vec<usize>| Ok(xs.iter().fold(0, |x, y| x + y)));
+        vm.register_typed("swap", |(x, y): (String, bool)| Ok((y, x)));
+        let sum = vm.eval_str("(sum 1 2 3)").unwrap();
+        assert_eq!(vm.get::<usize>(&sum), Ok(6));
+        let swapped = vm.eval_str("(swap \"a\" #t)").unwrap();
+        assert_eq!(vm.get::<(bool, String)>(&swapped), Ok((true, "a".to_owned())));
+        assert_eq!(vm.eval_str("(swap 1 #t)").map(|_| ()),
+                   Err(SchemeError::from("swap: Value is not a string")));
+    }
+
+    #[test]
+    fn rust_data_can_be_wrapped_and_downcast() {
+        let mut vm = Vm::new();
+        let x = vm.wrap_comparable(vec![1u8, 2]).unwrap();
+        let y = vm.wrap_comparable(vec![1u8, 2]).unwrap();
+        let z = vm.wrap(vec![1u8, 2]).unwrap();
+        vm.define("x", &x).unwrap();
+        vm.define("y", &y).unwrap();
+        vm.define("z", &z).unwrap();
+        vm.state().gc();
+        assert_eq!(vm.downcast_ref::<Vec<u8>>(&x), Some(&vec![1, 2]));
+        assert_eq!(vm.downcast_ref::<String>(&x), None);
+        let equal = vm.eval_str("(list (equal? x y) (equal? x z) (eq? z z))").unwrap();
+        assert_eq!(vm.get::<Vec<bool>>(&equal), Ok(vec![true, false, true]));
+    }
+
+    #[test]
+    fn errors_have_kinds_and_backtraces() {
+        let mut vm = Vm::new();
+        let e = vm.eval_str("(define (f x) (+ 1 (car x))) (define (g x) (+ 1 (f x))) (g 1)")
+                  .unwrap_err();
+        match *e.kind() {
+            ErrorKind::TypeError => {}
+            ref kind => panic!("{:?}", kind),
+        }
+        assert_eq!(&vm.backtrace(&e)[..2], &["f at line 1", "g at line 1"]);
+        match *vm.eval_str("(+ 1").unwrap_err().kind() {
+            ErrorKind::SyntaxError => {}
+            ref kind => panic!("{:?}", kind),
+        }
+        let e = vm.eval_str("(raise (list 1 2))").unwrap_err();
+        match *e.kind() {
+            ErrorKind::UserRaised(ref x) => assert_eq!(vm.get::<Vec<usize>>(x), Ok(vec![1, 2])),
+            ref kind => panic!("{:?}", kind),
+        }
+        let e = vm.eval_str("(error \"bad thing:\" 1 \"two\")").unwrap_err();
+        assert_eq!(e.message(), "bad thing: 1 \"two\"");
+        assert!(vm.state().is_empty());
+    }
+
+    #[test]
+    fn errors_and_conditions_convert_across_the_boundary() {
+        let mut vm = Vm::new();
+        vm.register_fn("check", Arity::Exactly(0), |_, _| {
+            Err(SchemeError::new(ErrorKind::TypeError, "check: not a widget".to_owned()))
+        });
+        let e = vm.eval_str("(define (h) (check)) (h)").unwrap_err();
+        assert_eq!(e, SchemeError::new(ErrorKind::TypeError, "check: not a widget".to_owned()));
+        let c = vm.condition(&e).unwrap();
+        vm.define("c", &c).unwrap();
+        let kind = vm.eval_str("(eq? (record-ref c 'kind) 'type-error)").unwrap();
+        assert_eq!(vm.get::<bool>(&kind), Ok(true));
+        assert_eq!(vm.error_of(&c), Ok(e));
+        let raised = vm.eval_str("(raise c)").unwrap_err();
+        assert_eq!(raised.message(), "check: not a widget");
+        match *raised.kind() {
+            ErrorKind::TypeError => {}
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn raised_conditions_have_backtraces() {
+        let mut vm = Vm::new();
+        let e = vm.eval_str("(define (f)\n  (error \"no\")\n  1)\n\n(define (g) (f) 1)\n(g)")
+                  .unwrap_err();
+        let c = match *e.kind() {
+            ErrorKind::Other => vm.condition(&e).unwrap(),
+            ref kind => panic!("{:?}", kind),
+        };
+        vm.define("c", &c).unwrap();
+        let backtrace = vm.eval_str("(error-backtrace c)").unwrap();
+        let backtrace = vm.get::<Vec<String>>(&backtrace).unwrap();
+        assert_eq!(&backtrace[..2], &["f at line 1", "g at line 5"]);
+        let c = vm.condition(&SchemeError::from("car: not a pair")).unwrap();
+        vm.define("c", &c).unwrap();
+        let backtrace = vm.eval_str("(error-backtrace c)").unwrap();
+        assert_eq!(vm.get::<Vec<String>>(&backtrace), Ok(vec![]));
+    }
+
+    #[test]
+    fn running_out_of_memory_is_an_error() {
+        let mut vm = Vm::new();
+        assert!(vm.eval_str("(length (list 1 2 3))").is_ok());
+        let limit = vm.state().heap_stats().bytes_in_use + (1 << 18);
+        vm.state().set_memory_limit(Some(limit));
+        let e = vm.eval_str("(define (grow xs) (grow (cons 1 xs))) (grow '())").unwrap_err();
+        match *e.kind() {
+            ErrorKind::LimitExceeded => {}
+            ref kind => panic!("{:?}", kind),
+        }
+        assert!(e.message().starts_with("out of memory"));
+        // The list is garbage once the call has failed.
+        assert!(vm.eval_str("(length (list 1 2 3))").is_ok());
+        vm.state().set_memory_limit(None);
+        assert!(vm.state().is_empty());
+    }
+
+    #[test]
+    fn errors_leave_the_stack_as_it_was() {
+        let mut vm = Vm::new();
+        assert!(vm.eval_str("(car 1)").is_err());
+        assert!(vm.eval_str("(+ 1").is_err());
+        assert_eq!(vm.lookup("no-such-variable").map(|_| ()),
+                   Err(SchemeError::from("Unbound variable: no-such-variable")));
+        assert!(vm.state().is_empty());
+        let two = vm.eval_str("2").unwrap();
+        assert_eq!(vm.get::<usize>(&two), Ok(2));
+    }
+}
@@ -1,7 +1,146 @@
+//! Arithmetic on Scheme numbers.
+//!
+//! Only fixnums, ratios and flonums exist so far.  A fixnum `n` is
+//! represented as `n << 2`, as a signed word, so sums and differences of
+//! representations are representations of sums and differences, and only
+//! one operand of a product needs to be shifted back.  A result that does
+//! not fit in a fixnum is an error until bignums exist, rather than
+//! wrapping around, and so is a ratio whose numerator or denominator would
+//! not (see `ratio`).
+//!
+//! Fixnums and ratios are exact, and operations on exact numbers are exact:
+//! the quotient of fixnums that do not divide is a ratio, and a result with
+//! a denominator of 1 is a fixnum (see `rational`).  They are computed on
+//! `i128`s, which hold any product of two parts, so only the result can
+//! overflow.
+//!
+//! Flonums are inexact, and inexactness is contagious: an operation on a
+//! flonum and an exact number converts the exact one, and its result is a
+//! flonum (see `flonums`).  Flonum arithmetic follows IEEE 754, so dividing
+//! a flonum by zero gives an infinity or a NaN rather than an error.
+//!
+//! `quotient` and `remainder` truncate, so the remainder has the sign of the
+//! dividend, while `modulo` floors, so it has the sign of the divisor, and
+//! goes with `floor_quotient`.  They take flonums too, as long as they are
+//! integers.
+//! `exponential`, behind `expt` and the `Power` opcode, squares repeatedly,
+//! so it needs a number of multiplications logarithmic in the exponent.  An
+//! exact number to an integer power is exact, even a negative one.
+//!
+//! `parse` reads the syntax of numbers, for the reader and `string->number`,
+//! and `format` writes fixnums in a radix, for `number->string`.
+//!
+//! The tests are a matrix of every operation over every pair of operand
+//! types.  Rows for bignums go in it with that type.
+
+use std::char;
+use std::isize;
+
 use alloc;
+use flonum;
+use ratio;
 use value::Value;
-pub fn exponential(_: Value, _: Value) -> ! {
-    unimplemented!()
+
+/// The largest fixnum.
+const MAX_FIXNUM: usize = isize::MAX as usize >> 2;
+
+/// The smallest fixnum.
+const MIN_FIXNUM: isize = isize::MIN >> 2;
+
+/// The error for a result that is not a fixnum.
+fn overflow() -> String {
+    "fixnum overflow (bignums are not supported yet)".to_owned()
+}
+
+/// The error for an operand that is not a number.
+fn not_a_number(operation: &str) -> String {
+    format!("{}: not a number", operation)
+}
+
+/// The value of the number `x`, as an `f64`.  That of a ratio is the
+/// quotient of its parts, which may be off by a rounding.
+pub fn real(x: &Value) -> Option<f64> {
+    if x.fixnump() {
+        Some((x.get() as isize >> 2) as f64)
+    } else if let Some((numerator, denominator)) = ratio::as_ratio(x) {
+        Some(numerator as f64 / denominator as f64)
+    } else {
+        flonum::as_f64(x)
+    }
+}
+
+/// `first` and `other`, as `f64`s, if both are numbers and at least one is
+/// a flonum, so that the operation on them is inexact.
+fn flonums(first: &Value, other: &Value) -> Option<(f64, f64)> {
+    if first.both_fixnums(other) || !(first.flonump() || other.flonump()) {
+        return None
+    }
+    match (real(first), real(other)) {
+        (Some(x), Some(y)) => Some((x, y)),
+        _ => None,
+    }
+}
+
+/// The new flonum `x`.
+pub fn flonum(alloc: &mut alloc::Heap, x: f64) -> Result<Value, String> {
+    try!(alloc.alloc_flonum(x));
+    Ok(alloc.stack.pop().unwrap())
+}
+
+/// The numerator and denominator of `x`, if it is an exact number.
+pub fn exact_parts(x: &Value) -> Option<(i128, i128)> {
+    if x.fixnump() {
+        Some(((x.get() as isize >> 2) as i128, 1))
+    } else {
+        ratio::as_ratio(x).map(|(numerator, denominator)| (numerator as i128, denominator as i128))
+    }
+}
+
+/// The numerators and denominators of `first` and `other`, if both are
+/// exact numbers.
+fn exacts(first: &Value, other: &Value) -> Option<((i128, i128), (i128, i128))> {
+    match (exact_parts(first), exact_parts(other)) {
+        (Some(x), Some(y)) => Some((x, y)),
+        _ => None,
+    }
+}
+
+/// Is `x` in the range of fixnums?
+fn fits_fixnum(x: i128) -> bool {
+    x >= MIN_FIXNUM as i128 && x <= MAX_FIXNUM as i128
+}
+
+/// The greatest common divisor of `x` and `y`, which is never negative.
+fn gcd(x: i128, y: i128) -> i128 {
+    let (mut x, mut y) = (x.abs(), y.abs());
+    while y != 0 {
+        let r = x % y;
+        x = y;
+        y = r
+    }
+    x
+}
+
+/// The exact number `numerator/denominator`: a fixnum if it is an integer,
+/// and otherwise a new ratio, in lowest terms.  The parts must be less than
+/// 2^126 in magnitude, as the product of two parts of exact numbers is.
+pub fn rational(alloc: &mut alloc::Heap,
+                numerator: i128,
+                denominator: i128)
+                -> Result<Value, String> {
+    if denominator == 0 {
+        return Err("division by zero".to_owned())
+    }
+    let divisor = gcd(numerator, denominator) * denominator.signum();
+    let (numerator, denominator) = (numerator / divisor, denominator / divisor);
+    if !fits_fixnum(numerator) || !fits_fixnum(denominator) {
+        return Err(overflow())
+    }
+    if denominator == 1 {
+        return Ok(Value::new((numerator as isize as usize) << 2))
+    }
+    try!(alloc.alloc_ratio(numerator as isize, denominator as isize));
+    Ok(alloc.stack.pop().unwrap())
 }
 pub fn slow_add(_alloc: alloc::Heap, _first: &mut Value, _other: &mut Value) -> ! {
     unimplemented!()
@@ -12,11 +151,10 @@ pub fn slow_add(_alloc: alloc::Heap, _first: &mut Value, _other: &mut Value) ->
 /// as a fast path function, which is inlined into the interpreter.  The general case is much slower and put in a seperate function, which is not inlined.
 /// function
 // #[inline(always)]
-pub fn add(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn add(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
     if first.both_fixnums(other) {
-        let res = (first.get() & !1).checked_add(other.get());
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
+        let res = (first.get() as isize).checked_add(other.get() as isize);
+        res.ok_or_else(overflow).map(|x| Value::new(x as usize))
         /*
         if res.contents > first.contents {
             // Overflow!
@@ -24,54 +162,686 @@ pub fn add(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Val
         } else {
             Ok(res)
         }*/
-    } else if first.flonump() && other.flonump() {
-        // Multiply the `f64` values pointed to by the arguments
-        //Ok(alloc.alloc_float(unsafe { float_val(first) * float_val(other) }))
-        //unimplemented!()
-        Err("flonums not yet implemented".to_owned())
+    } else if let Some((x, y)) = flonums(first, other) {
+        flonum(alloc, x + y)
+    } else if let Some(((a, b), (c, d))) = exacts(first, other) {
+        rational(alloc, a * d + c * b, b * d)
     } else {
         // Slow path.
-        Err("non-fixnum addition not yet implemented".to_owned())
+        Err(not_a_number("+"))
         //
         //self::slow_add(alloc, first, other)
     }
 }
 //#[inline(always)]
-pub fn subtract(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn subtract(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
     if first.both_fixnums(other) {
-        let res = (first.get() & !1).checked_sub(other.get());
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
-    } else if first.flonump() && other.flonump() {
-        Err("flonums not yet implemented".to_owned())
+        let res = (first.get() as isize).checked_sub(other.get() as isize);
+        res.ok_or_else(overflow).map(|x| Value::new(x as usize))
+    } else if let Some((x, y)) = flonums(first, other) {
+        flonum(alloc, x - y)
+    } else if let Some(((a, b), (c, d))) = exacts(first, other) {
+        rational(alloc, a * d - c * b, b * d)
     } else {
-        Err("non-fixnum addition not yet implemented".to_owned())
+        Err(not_a_number("-"))
     }
 }
 
 //#[inline(always)]
-pub fn multiply(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn less(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<bool, String> {
     if first.both_fixnums(other) {
-        let res = (first.get() & !1).checked_mul(other.get());
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
-    } else if first.flonump() && other.flonump() {
-        Err("flonums not yet implemented".to_owned())
+        Ok((first.get() as isize) < other.get() as isize)
+    } else if let Some((x, y)) = flonums(first, other) {
+        Ok(x < y)
+    } else if let Some(((a, b), (c, d))) = exacts(first, other) {
+        // The denominators are positive.
+        Ok(a * d < c * b)
     } else {
-        Err("non-fixnum addition not yet implemented".to_owned())
+        Err(not_a_number("<"))
     }
 }
 
 //#[inline(always)]
-pub fn divide(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn num_equal(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<bool, String> {
     if first.both_fixnums(other) {
-        let (first, other) = (first.get() & !3, other.get() & !3);
-        let res = first.checked_div(other);
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
-    } else if first.flonump() && other.flonump() {
-        Err("flonums not yet implemented".to_owned())
+        Ok(first.get() == other.get())
+    } else if let Some((x, y)) = flonums(first, other) {
+        Ok(x == y)
+    } else if let Some(((a, b), (c, d))) = exacts(first, other) {
+        Ok(a * d == c * b)
     } else {
-        Err("non-fixnum addition not yet implemented".to_owned())
+        Err(not_a_number("="))
+    }
+}
+
+//#[inline(always)]
+pub fn multiply(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+    if first.both_fixnums(other) {
+        let res = (first.get() as isize >> 2).checked_mul(other.get() as isize);
+        res.ok_or_else(overflow).map(|x| Value::new(x as usize))
+    } else if let Some((x, y)) = flonums(first, other) {
+        flonum(alloc, x * y)
+    } else if let Some(((a, b), (c, d))) = exacts(first, other) {
+        rational(alloc, a * c, b * d)
+    } else {
+        Err(not_a_number("*"))
+    }
+}
+
+//#[inline(always)]
+pub fn divide(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+    if first.both_fixnums(other) {
+        let (first, other) = (first.get() as isize, other.get() as isize);
+        if other == 0 {
+            return Err("division by zero".to_owned())
+        }
+        if first % other != 0 {
+            return rational(alloc, (first >> 2) as i128, (other >> 2) as i128)
+        }
+        // Only the most negative fixnum divided by -1 overflows.
+        let res = (first / other).checked_mul(4);
+        res.ok_or_else(overflow).map(|x| Value::new(x as usize))
+    } else if let Some((x, y)) = flonums(first, other) {
+        flonum(alloc, x / y)
+    } else if let Some(((a, b), (c, d))) = exacts(first, other) {
+        rational(alloc, a * d, b * c)
+    } else {
+        Err(not_a_number("/"))
+    }
+}
+
+/// The integers that `first` and `other` represent, if both are fixnums.
+fn fixnums(first: &Value, other: &Value) -> Option<(isize, isize)> {
+    if first.both_fixnums(other) {
+        Some((first.get() as isize >> 2, other.get() as isize >> 2))
+    } else {
+        None
+    }
+}
+
+/// The fixnum `n`, or an overflow error if there is no `n` or it is not a
+/// fixnum.
+fn fixnum(n: Option<isize>) -> Result<Value, String> {
+    n.and_then(|n| n.checked_mul(4)).ok_or_else(overflow).map(|x| Value::new(x as usize))
+}
+
+/// The operands of an integer division.
+enum Integers {
+    Fixnums(isize, isize),
+
+    /// Operands of which at least one is a flonum, and both are integers.
+    Flonums(f64, f64),
+}
+
+/// `first` and `other`, as integers, for the division `name`.
+fn division(name: &str, first: &Value, other: &Value) -> Result<Integers, String> {
+    match (fixnums(first, other), flonums(first, other)) {
+        (Some((_, 0)), _) => Err("division by zero".to_owned()),
+        (Some((x, y)), _) => Ok(Integers::Fixnums(x, y)),
+        (_, Some((_, y))) if y == 0.0 => Err("division by zero".to_owned()),
+        // Infinities and NaNs have no integer part, so they are excluded.
+        (_, Some((x, y))) if x.fract() == 0.0 && y.fract() == 0.0 => Ok(Integers::Flonums(x, y)),
+        _ => Err(format!("{}: not an integer", name)),
+    }
+}
+
+pub fn quotient(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+    match try!(division("quotient", first, other)) {
+        // Only the most negative fixnum divided by -1 overflows.
+        Integers::Fixnums(x, y) => fixnum(x.checked_div(y)),
+        Integers::Flonums(x, y) => flonum(alloc, (x / y).trunc()),
+    }
+}
+
+pub fn remainder(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+    match try!(division("remainder", first, other)) {
+        Integers::Fixnums(x, y) => fixnum(Some(x % y)),
+        Integers::Flonums(x, y) => flonum(alloc, x % y),
+    }
+}
+
+pub fn modulo(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+    match try!(division("modulo", first, other)) {
+        Integers::Fixnums(x, y) => {
+            let r = x % y;
+            fixnum(Some(if r != 0 && (r < 0) != (y < 0) { r + y } else { r }))
+        }
+        Integers::Flonums(x, y) => {
+            let r = x % y;
+            flonum(alloc, if r != 0.0 && (r < 0.0) != (y < 0.0) { r + y } else { r })
+        }
+    }
+}
+
+/// The quotient of `first` and `other`, rounded down, so that it goes with
+/// `modulo` as `quotient` goes with `remainder`.
+pub fn floor_quotient(alloc: &mut alloc::Heap,
+                      first: &Value,
+                      other: &Value)
+                      -> Result<Value, String> {
+    match try!(division("floor-quotient", first, other)) {
+        Integers::Fixnums(x, y) => {
+            let round_down = x % y != 0 && (x < 0) != (y < 0);
+            fixnum(x.checked_div(y).map(|q| if round_down { q - 1 } else { q }))
+        }
+        Integers::Flonums(x, y) => flonum(alloc, (x / y).floor()),
+    }
+}
+
+/// `base` to the power `exponent`, which must not be negative, if it is
+/// in the range of fixnums.
+fn power(base: i128, exponent: i128) -> Option<i128> {
+    let (mut base, mut exponent, mut result) = (Some(base), exponent, Some(1i128));
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.and_then(|r| base.and_then(|b| r.checked_mul(b)))
+                           .and_then(|r| if fits_fixnum(r) { Some(r) } else { None })
+        }
+        exponent >>= 1;
+        // The last square would not be used, and might overflow.
+        if exponent > 0 {
+            base = base.and_then(|b| b.checked_mul(b))
+        }
+    }
+    result
+}
+
+/// Raises `first` to the power `other`.  An exact number to an integer
+/// power is exact; to a ratio, it is a flonum, since it is irrational as a
+/// rule.
+pub fn exponential(alloc: &mut alloc::Heap,
+                   first: &Value,
+                   other: &Value)
+                   -> Result<Value, String> {
+    if let Some((x, y)) = flonums(first, other) {
+        return flonum(alloc, x.powf(y))
+    }
+    let ((numerator, denominator), exponent) = match exacts(first, other) {
+        Some((base, (exponent, 1))) => (base, exponent),
+        Some(_) => return flonum(alloc, real(first).unwrap().powf(real(other).unwrap())),
+        None => return Err(not_a_number("expt")),
+    };
+    if exponent < 0 && numerator == 0 {
+        return Err("division by zero".to_owned())
+    }
+    // The parts are coprime, so their powers are too, and they cannot be
+    // reduced.
+    match (power(numerator, exponent.abs()), power(denominator, exponent.abs())) {
+        (Some(numerator), Some(denominator)) if exponent < 0 => {
+            rational(alloc, denominator, numerator)
+        }
+        (Some(numerator), Some(denominator)) => rational(alloc, numerator, denominator),
+        _ => Err(overflow()),
+    }
+}
+
+/// A number, as `parse` reads it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Parsed {
+    Fixnum(isize),
+    Flonum(f64),
+
+    /// An exact number that is not an integer: its numerator and
+    /// denominator, in lowest terms.
+    Ratio(isize, isize),
+
+    /// An exact integer too large for a fixnum, or a ratio whose parts are.
+    Bignum,
+
+    /// Not the syntax of a number.
+    Invalid,
+}
+
+/// Is `text` a decimal with a fraction or an exponent, such as `1.5`,
+/// `.5` or `1e3`, without a sign?
+fn is_decimal(text: &str) -> bool {
+    let digits = |text: &str| text.chars().all(|c| c.is_digit(10));
+    let (mantissa, exponent) = match text.find(|c| c == 'e' || c == 'E') {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    };
+    let (whole, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+    let exponent_ok = exponent.map_or(true, |exponent| {
+        let exponent = if exponent.starts_with('+') || exponent.starts_with('-') {
+            &exponent[1..]
+        } else {
+            exponent
+        };
+        !exponent.is_empty() && digits(exponent)
+    });
+    (fraction.is_some() || exponent.is_some()) && digits(whole) &&
+    fraction.map_or(true, |fraction| digits(fraction)) &&
+    whole.len() + fraction.map_or(0, str::len) > 0 && exponent_ok
+}
+
+/// The value of `digits` in radix `radix`, if they are digits, and `None`
+/// inside if it does not fit in a word.
+fn natural(digits: &str, radix: u32) -> Option<Option<usize>> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None
+    }
+    Some(digits.chars().fold(Some(0usize), |n, c| {
+        n.and_then(|n| n.checked_mul(radix as usize))
+         .and_then(|n| n.checked_add(c.to_digit(radix).unwrap() as usize))
+    }))
+}
+
+/// The exact number `numerator/denominator`, where `denominator` is
+/// positive, as `parse` reads it.
+fn exact(numerator: i128, denominator: i128) -> Parsed {
+    let divisor = gcd(numerator, denominator);
+    let (numerator, denominator) = (numerator / divisor, denominator / divisor);
+    if !fits_fixnum(numerator) || !fits_fixnum(denominator) {
+        Parsed::Bignum
+    } else if denominator == 1 {
+        Parsed::Fixnum(numerator as isize)
+    } else {
+        Parsed::Ratio(numerator as isize, denominator as isize)
+    }
+}
+
+/// The exact value of the decimal `text` (see `is_decimal`), such as 3/2
+/// for `1.5`.
+fn exact_decimal(negative: bool, text: &str) -> Parsed {
+    let (mantissa, exponent) = match text.find(|c| c == 'e' || c == 'E') {
+        Some(i) => (&text[..i], text[i + 1..].trim_left_matches('+').parse::<i32>().ok()),
+        None => (text, Some(0)),
+    };
+    let (whole, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, ""),
+    };
+    let digits = whole.chars().chain(fraction.chars()).fold(Some(0i128), |n, c| {
+        n.and_then(|n| n.checked_mul(10)).map(|n| n + c.to_digit(10).unwrap() as i128)
+    });
+    let scale = exponent.and_then(|exponent| exponent.checked_sub(fraction.len() as i32));
+    // 10^38 is the largest power of ten in an `i128`.
+    let parts = match (digits, scale) {
+        (Some(0), _) => Some((0, 1)),
+        (Some(n), Some(scale)) if scale >= 0 && scale <= 38 => {
+            n.checked_mul(10i128.pow(scale as u32)).map(|n| (n, 1))
+        }
+        (Some(n), Some(scale)) if scale < 0 && scale >= -38 => Some((n, 10i128.pow(-scale as u32))),
+        _ => None,
+    };
+    match parts {
+        Some((n, d)) => exact(if negative { -n } else { n }, d),
+        None => Parsed::Bignum,
+    }
+}
+
+/// Reads `text` as a number, in radix `radix` unless it has a radix prefix
+/// (`#x`, `#o`, `#b` or `#d`).  An exactness prefix (`#e` or `#i`), before
+/// or after that, makes it exact or inexact.  Only decimals can have a
+/// fraction or an exponent, and a ratio such as `-3/4` is read in lowest
+/// terms.
+pub fn parse(text: &str, radix: u32) -> Parsed {
+    let (mut text, mut radix, mut exact, mut radix_given) = (text, radix, None, false);
+    while text.len() >= 2 && text.as_bytes()[0] == b'#' {
+        match text.as_bytes()[1] {
+            b'e' | b'E' if exact.is_none() => exact = Some(true),
+            b'i' | b'I' if exact.is_none() => exact = Some(false),
+            prefix if !radix_given => {
+                radix = match prefix {
+                    b'x' | b'X' => 16,
+                    b'o' | b'O' => 8,
+                    b'b' | b'B' => 2,
+                    b'd' | b'D' => 10,
+                    _ => return Parsed::Invalid,
+                };
+                radix_given = true
+            }
+            _ => return Parsed::Invalid,
+        }
+        text = &text[2..]
+    }
+    let (negative, digits) = match text.as_bytes().first() {
+        Some(&b'-') => (true, &text[1..]),
+        Some(&b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let sign = |n: usize| if negative { -(n as i128) } else { n as i128 };
+    let number = if let Some(magnitude) = natural(digits, radix) {
+        match magnitude {
+            Some(n) => self::exact(sign(n), 1),
+            None => Parsed::Bignum,
+        }
+    } else if let Some(slash) = digits.find('/') {
+        match (natural(&digits[..slash], radix), natural(&digits[slash + 1..], radix)) {
+            (Some(_), Some(Some(0))) => return Parsed::Invalid,
+            (Some(Some(n)), Some(Some(d))) => self::exact(sign(n), d as i128),
+            (Some(_), Some(_)) => Parsed::Bignum,
+            _ => return Parsed::Invalid,
+        }
+    } else if radix == 10 && is_decimal(digits) {
+        if exact == Some(true) {
+            return exact_decimal(negative, digits)
+        }
+        match digits.parse::<f64>() {
+            Ok(x) => Parsed::Flonum(if negative { -x } else { x }),
+            Err(_) => return Parsed::Invalid,
+        }
+    } else {
+        return Parsed::Invalid
+    };
+    match (number, exact) {
+        (Parsed::Fixnum(n), Some(false)) => Parsed::Flonum(n as f64),
+        (Parsed::Ratio(n, d), Some(false)) => Parsed::Flonum(n as f64 / d as f64),
+        (number, _) => number,
+    }
+}
+
+/// The digits of the fixnum `n` in radix `radix`, which is at most 36,
+/// after a `-` if it is negative.
+pub fn format(n: isize, radix: u32) -> String {
+    let mut magnitude = if n < 0 { (n as usize).wrapping_neg() } else { n as usize };
+    let mut digits = vec![];
+    loop {
+        digits.push(char::from_digit((magnitude % radix as usize) as u32, radix).unwrap());
+        magnitude /= radix as usize;
+        if magnitude == 0 {
+            break
+        }
+    }
+    if n < 0 {
+        digits.push('-')
+    }
+    digits.iter().rev().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::isize;
+
+    use alloc::Heap;
+    use value::Value;
+    use super::*;
+
+    /// The largest and smallest fixnums.
+    const MAX: isize = isize::MAX >> 2;
+    const MIN: isize = isize::MIN >> 2;
+
+    fn fixnum(n: isize) -> Value {
+        Value::new((n << 2) as usize)
+    }
+
+    /// The numerator and denominator of `x`, which must be exact.
+    fn value_of(x: Result<Value, String>) -> Result<(isize, isize), String> {
+        x.map(|x| {
+            let (n, d) = exact_parts(&x).expect("result is not exact");
+            (n as isize, d as isize)
+        })
+    }
+
+    /// The expected result `n`.
+    fn int(n: isize) -> Option<(isize, isize)> {
+        Some((n, 1))
+    }
+
+    /// The expected result `n/d`.
+    fn frac(n: isize, d: isize) -> Option<(isize, isize)> {
+        Some((n, d))
+    }
+
+    type Op = fn(&mut Heap, &Value, &Value) -> Result<Value, String>;
+
+    #[test]
+    fn fixnum_arithmetic_is_exact_or_fails() {
+        let ops: &[(&str, Op)] = &[("+", add), ("-", subtract), ("*", multiply), ("/", divide)];
+        // Each row is the operands, then the results of the operations, or
+        // `None` for an error.
+        let rows: &[(isize, isize, [Option<(isize, isize)>; 4])] = &[
+            (3, 4, [int(7), int(-1), int(12), frac(3, 4)]),
+            (-3, 4, [int(1), int(-7), int(-12), frac(-3, 4)]),
+            (-8, -2, [int(-10), int(-6), int(16), int(4)]),
+            (6, -4, [int(2), int(10), int(-24), frac(-3, 2)]),
+            (0, -5, [int(-5), int(5), int(0), int(0)]),
+            (7, 0, [int(7), int(7), int(0), None]),
+            (MAX, 1, [None, int(MAX - 1), int(MAX), int(MAX)]),
+            (MIN, 1, [int(MIN + 1), None, int(MIN), int(MIN)]),
+            (MIN, -1, [None, int(MIN + 1), None, None]),
+            (MAX, 2, [None, int(MAX - 2), None, frac(MAX, 2)]),
+        ];
+        let mut heap = Heap::new(1 << 4);
+        for &(x, y, ref expected) in rows {
+            for (&(name, op), &expected) in ops.iter().zip(expected.iter()) {
+                let res = value_of(op(&mut heap, &fixnum(x), &fixnum(y)));
+                assert_eq!(res.ok(), expected, "({} {} {})", name, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn integer_division_and_powers_have_the_right_signs() {
+        let ops: &[(&str, Op)] = &[("quotient", quotient),
+                                   ("remainder", remainder),
+                                   ("modulo", modulo),
+                                   ("floor-quotient", floor_quotient),
+                                   ("expt", exponential)];
+        let rows: &[(isize, isize, [Option<(isize, isize)>; 5])] = &[
+            (7, 2, [int(3), int(1), int(1), int(3), int(49)]),
+            (-7, 2, [int(-3), int(-1), int(1), int(-4), int(49)]),
+            (7, -2, [int(-3), int(1), int(-1), int(-4), frac(1, 49)]),
+            (-7, -2, [int(3), int(-1), int(-1), int(3), frac(1, 49)]),
+            (-2, -3, [int(0), int(-2), int(-2), int(0), frac(-1, 8)]),
+            (6, 3, [int(2), int(0), int(0), int(2), int(216)]),
+            (-2, 3, [int(0), int(-2), int(1), int(-1), int(-8)]),
+            (5, 0, [None, None, None, None, int(1)]),
+            (0, -1, [int(0), int(0), int(0), int(0), None]),
+            (-1, -3, [int(0), int(-1), int(-1), int(0), int(-1)]),
+            (2, 61, [int(0), int(2), int(2), int(0), None]),
+            (2, 60, [int(0), int(2), int(2), int(0), int(1 << 60)]),
+            (2, -61, [int(0), int(2), int(-59), int(-1), None]),
+            (MIN, -1, [None, int(0), int(0), None, None]),
+        ];
+        let mut heap = Heap::new(1 << 4);
+        for &(x, y, ref expected) in rows {
+            for (&(name, op), &expected) in ops.iter().zip(expected.iter()) {
+                let res = value_of(op(&mut heap, &fixnum(x), &fixnum(y)));
+                assert_eq!(res.ok(), expected, "({} {} {})", name, x, y);
+            }
+        }
+    }
+
+    /// Pushes `x`, as a fixnum if it is `exact`, and as a flonum otherwise.
+    fn push(heap: &mut Heap, x: f64, exact: bool) {
+        if exact {
+            heap.stack.push(fixnum(x as isize))
+        } else {
+            heap.alloc_flonum(x).unwrap()
+        }
+    }
+
+    #[test]
+    fn operations_on_flonums_are_inexact() {
+        let ops: &[(&str, Op)] = &[("+", add),
+                                   ("-", subtract),
+                                   ("*", multiply),
+                                   ("/", divide),
+                                   ("quotient", quotient),
+                                   ("modulo", modulo),
+                                   ("expt", exponential)];
+        // Each row is the operands, each with whether it is exact, then the
+        // (inexact) results of the operations, or `None` for an error.
+        let rows: &[(f64, bool, f64, bool, [Option<f64>; 7])] = &[
+            (1.5, false, 2.0, true, [Some(3.5), Some(-0.5), Some(3.0), Some(0.75), None, None,
+                                     Some(2.25)]),
+            (-7.0, true, 2.0, false, [Some(-5.0), Some(-9.0), Some(-14.0), Some(-3.5),
+                                      Some(-3.0), Some(1.0), Some(49.0)]),
+            (8.0, false, -2.0, false, [Some(6.0), Some(10.0), Some(-16.0), Some(-4.0),
+                                       Some(-4.0), Some(0.0), Some(0.015625)]),
+            (3.0, true, 0.0, false, [Some(3.0), Some(3.0), Some(0.0),
+                                     Some(::std::f64::INFINITY), None, None, Some(1.0)]),
+        ];
+        let mut heap = Heap::new(1 << 4);
+        for &(x, x_exact, y, y_exact, ref expected) in rows {
+            push(&mut heap, x, x_exact);
+            push(&mut heap, y, y_exact);
+            for (&(name, op), &expected) in ops.iter().zip(expected.iter()) {
+                let (first, other) = (heap.stack[0].clone(), heap.stack[1].clone());
+                let res = op(&mut heap, &first, &other).map(|x| {
+                    assert!(x.flonump(), "result is not a flonum");
+                    real(&x).unwrap()
+                });
+                assert_eq!(res.ok(), expected, "({} {} {})", name, x, y);
+            }
+            heap.stack.clear()
+        }
+        push(&mut heap, 2.0, false);
+        push(&mut heap, ::std::f64::NAN, false);
+        let (two, nan) = (heap.stack[0].clone(), heap.stack[1].clone());
+        assert_eq!(num_equal(&mut heap, &fixnum(2), &two), Ok(true));
+        assert_eq!(less(&mut heap, &fixnum(1), &two), Ok(true));
+        assert_eq!(num_equal(&mut heap, &nan, &nan), Ok(false));
+        assert_eq!(less(&mut heap, &nan, &fixnum(1)), Ok(false));
+    }
+
+    #[test]
+    fn operations_on_ratios_are_exact_and_in_lowest_terms() {
+        let ops: &[(&str, Op)] = &[("+", add), ("-", subtract), ("*", multiply), ("/", divide)];
+        // Each row is the operands, as numerators and denominators, then the
+        // results of the operations, or `None` for an error.
+        let rows: &[((i128, i128), (i128, i128), [Option<(i128, i128)>; 4])] = &[
+            ((1, 2), (1, 3), [Some((5, 6)), Some((1, 6)), Some((1, 6)), Some((3, 2))]),
+            ((1, 2), (1, 2), [Some((1, 1)), Some((0, 1)), Some((1, 4)), Some((1, 1))]),
+            ((3, 1), (1, 2), [Some((7, 2)), Some((5, 2)), Some((3, 2)), Some((6, 1))]),
+            ((-3, 4), (2, 1), [Some((5, 4)), Some((-11, 4)), Some((-3, 2)), Some((-3, 8))]),
+            ((2, 3), (-2, 1), [Some((-4, 3)), Some((8, 3)), Some((-4, 3)), Some((-1, 3))]),
+            ((1, 2), (0, 1), [Some((1, 2)), Some((1, 2)), Some((0, 1)), None]),
+            ((MAX as i128, 2), (1, 3), [None, None, Some((MAX as i128, 6)), None]),
+            ((1, MAX as i128), (2, 1), [None, None, Some((2, MAX as i128)), None]),
+        ];
+        let mut heap = Heap::new(1 << 4);
+        for &((a, b), (c, d), ref expected) in rows {
+            // The operands stay on the stack, since the results may move them.
+            let x = rational(&mut heap, a, b).unwrap();
+            heap.stack.push(x);
+            let y = rational(&mut heap, c, d).unwrap();
+            heap.stack.push(y);
+            for (&(name, op), &expected) in ops.iter().zip(expected.iter()) {
+                let (first, other) = (heap.stack[0].clone(), heap.stack[1].clone());
+                let res = op(&mut heap, &first, &other).map(|x| {
+                    exact_parts(&x).expect("result is not exact")
+                });
+                assert_eq!(res.ok(), expected, "({} {}/{} {}/{})", name, a, b, c, d);
+            }
+            heap.stack.clear()
+        }
+        let half = rational(&mut heap, 1, 2).unwrap();
+        heap.stack.push(half);
+        let third = rational(&mut heap, -1, 3).unwrap();
+        heap.stack.push(third);
+        heap.alloc_flonum(0.25).unwrap();
+        {
+            let (half, third, quarter) = (heap.stack[0].clone(), heap.stack[1].clone(),
+                                          heap.stack[2].clone());
+            assert_eq!(less(&mut heap, &third, &half), Ok(true));
+            assert_eq!(less(&mut heap, &half, &fixnum(1)), Ok(true));
+            assert_eq!(less(&mut heap, &quarter, &half), Ok(true));
+            assert_eq!(num_equal(&mut heap, &half, &half.clone()), Ok(true));
+            assert_eq!(num_equal(&mut heap, &half, &third), Ok(false));
+            assert!(quotient(&mut heap, &half, &fixnum(1)).is_err());
+            assert_eq!(real(&add(&mut heap, &half, &quarter).unwrap()), Some(0.75));
+        }
+        // Each result is read before the next allocation, which may move
+        // the operands on the stack.
+        let power = |heap: &mut Heap, base: usize, n: isize| {
+            let x = heap.stack[base].clone();
+            exponential(heap, &x, &fixnum(n)).map(|x| exact_parts(&x).unwrap())
+        };
+        assert_eq!(power(&mut heap, 0, 3), Ok((1, 8)));
+        assert_eq!(power(&mut heap, 1, -2), Ok((9, 1)));
+        assert_eq!(power(&mut heap, 1, -3), Ok((-27, 1)));
+        assert_eq!(power(&mut heap, 0, 0), Ok((1, 1)));
+        assert!(power(&mut heap, 0, 61).is_err());
+        heap.stack.push(fixnum(2));
+        heap.stack.push(fixnum(0));
+        assert_eq!(power(&mut heap, 3, -1), Ok((1, 2)));
+        assert!(power(&mut heap, 4, -1).is_err());
+        let half = heap.stack[0].clone();
+        let root = exponential(&mut heap, &fixnum(4), &half).unwrap();
+        assert!(root.flonump());
+        assert_eq!(real(&root), Some(2.0));
+    }
+
+    #[test]
+    fn fixnum_comparisons_are_signed() {
+        let mut heap = Heap::new(1 << 4);
+        for &(x, y) in &[(-1, 0), (MIN, MAX), (3, 4), (MIN, -1)] {
+            assert_eq!(less(&mut heap, &fixnum(x), &fixnum(y)), Ok(true));
+            assert_eq!(less(&mut heap, &fixnum(y), &fixnum(x)), Ok(false));
+            assert_eq!(num_equal(&mut heap, &fixnum(x), &fixnum(y)), Ok(false));
+            assert_eq!(num_equal(&mut heap, &fixnum(x), &fixnum(x)), Ok(true));
+        }
+    }
+
+    #[test]
+    fn non_numbers_are_errors() {
+        let mut heap = Heap::new(1 << 4);
+        let nil = Value::nil();
+        for &(x, y) in &[(&fixnum(1), &nil), (&nil, &fixnum(1)), (&nil, &nil)] {
+            assert!(add(&mut heap, x, y).is_err());
+            assert!(multiply(&mut heap, x, y).is_err());
+            assert!(less(&mut heap, x, y).is_err());
+        }
+    }
+
+    #[test]
+    fn numbers_are_parsed_and_formatted_in_every_radix() {
+        let rows: &[(&str, u32, Parsed)] = &[
+            ("42", 10, Parsed::Fixnum(42)),
+            ("-42", 10, Parsed::Fixnum(-42)),
+            ("+101", 2, Parsed::Fixnum(5)),
+            ("#xff", 10, Parsed::Fixnum(255)),
+            ("#X-Ff", 2, Parsed::Fixnum(-255)),
+            ("#o17", 10, Parsed::Fixnum(15)),
+            ("#b-101", 16, Parsed::Fixnum(-5)),
+            ("#d10", 16, Parsed::Fixnum(10)),
+            ("#e#x10", 10, Parsed::Fixnum(16)),
+            ("#x#e10", 10, Parsed::Fixnum(16)),
+            ("#e1.5e1", 10, Parsed::Fixnum(15)),
+            ("#e1.5", 10, Parsed::Ratio(3, 2)),
+            ("#e-0.125", 10, Parsed::Ratio(-1, 8)),
+            ("3/4", 10, Parsed::Ratio(3, 4)),
+            ("-6/8", 10, Parsed::Ratio(-3, 4)),
+            ("#x1/10", 10, Parsed::Ratio(1, 16)),
+            ("8/4", 10, Parsed::Fixnum(2)),
+            ("#i1/2", 10, Parsed::Flonum(0.5)),
+            ("#i5", 10, Parsed::Flonum(5.0)),
+            ("-.5", 10, Parsed::Flonum(-0.5)),
+            ("1e3", 10, Parsed::Flonum(1000.0)),
+            ("1e3", 16, Parsed::Fixnum(0x1e3)),
+            ("99999999999999999999999", 10, Parsed::Bignum),
+            ("", 10, Parsed::Invalid),
+            ("-", 10, Parsed::Invalid),
+            (".", 10, Parsed::Invalid),
+            ("1.5", 16, Parsed::Invalid),
+            ("12", 2, Parsed::Invalid),
+            ("#x#x1", 10, Parsed::Invalid),
+            ("#e#i1", 10, Parsed::Invalid),
+            ("#q1", 10, Parsed::Invalid),
+            ("1e", 10, Parsed::Invalid),
+            ("inf", 10, Parsed::Invalid),
+            ("1/0", 10, Parsed::Invalid),
+            ("1/", 10, Parsed::Invalid),
+            ("3/-4", 10, Parsed::Invalid),
+            ("1.5/2", 10, Parsed::Invalid),
+        ];
+        for &(text, radix, expected) in rows {
+            assert_eq!(parse(text, radix), expected, "{} in radix {}", text, radix);
+        }
+        assert_eq!(parse(&MAX.to_string(), 10), Parsed::Fixnum(MAX));
+        assert_eq!(parse(&MIN.to_string(), 10), Parsed::Fixnum(MIN));
+        assert_eq!(parse(&(MAX as usize + 1).to_string(), 10), Parsed::Bignum);
+        for &n in &[0, 1, -1, 255, -4096, MAX, MIN] {
+            for &radix in &[2, 8, 10, 16] {
+                assert_eq!(parse(&format(n, radix), radix), Parsed::Fixnum(n));
+            }
+        }
+        assert_eq!(format(-255, 16), "-ff");
+        assert_eq!(format(5, 2), "101");
     }
 }
@@ -0,0 +1,102 @@
+//! Audit hooks: callbacks that see what code is about to do, and can veto
+//! it, so that embedders can log or forbid actions.
+//!
+//! Hooks are added with `State::add_audit_hook` (in `api`), and cannot be removed.
+//! Each is called with an `Event` before the action, in the order they were
+//! added; the first that returns an error vetoes the action, which then
+//! fails with that error.  Hooks cannot access the `State`.
+//!
+//! Files read by `include` are part of the program being compiled, and do
+//! not raise events of their own: the `load` or `eval` that compiles them
+//! does.
+
+use std::fmt;
+use std::mem;
+use std::path::Path;
+
+/// Something that code is about to do.
+#[derive(Copy, Clone, Debug)]
+pub enum Event<'a> {
+    /// `load` is about to run a file (source or compiled object).
+    Load(&'a Path),
+
+    /// `compile-file` is about to compile a file into a compiled object
+    /// file.
+    CompileFile(&'a Path, &'a Path),
+
+    /// `mmap-file` is about to map a file.
+    MapFile(&'a Path),
+
+    /// The REPL is about to evaluate source code.
+    Eval(&'a str),
+
+    /// A native procedure, by name, is about to be called.
+    NativeCall(&'static str),
+}
+
+/// An audit hook.
+pub type Hook = Box<FnMut(&Event) -> Result<(), String>>;
+
+/// The audit hooks of a `State`.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Hook>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hooks({} hooks)", self.hooks.len())
+    }
+}
+
+impl Hooks {
+    pub fn push(&mut self, hook: Hook) {
+        self.hooks.push(hook)
+    }
+
+    /// Are there any hooks?  Checked before building events that are
+    /// expensive to build, or happen often.
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Runs the hooks on `event`, stopping at the first veto.  Hooks added
+    /// while this runs are kept, but only see later events.
+    pub fn run(&mut self, event: &Event) -> Result<(), String> {
+        let mut hooks = mem::replace(&mut self.hooks, vec![]);
+        let res = hooks.iter_mut().map(|hook| hook(event)).collect();
+        hooks.extend(self.hooks.drain(..));
+        self.hooks = hooks;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use interp;
+    use repl;
+    use super::*;
+
+    #[test]
+    fn hooks_see_and_can_veto_actions() {
+        let mut s = interp::new();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        s.audit.push(Box::new(move |event: &Event| {
+            log.borrow_mut().push(format!("{:?}", event));
+            match *event {
+                Event::Load(path) => Err(format!("may not load {}", path.display())),
+                _ => Ok(()),
+            }
+        }));
+        repl::eval(&mut s, "(string-contains \"ab\" \"b\")").unwrap();
+        assert_eq!(repl::eval(&mut s, "(load \"x.scm\")"), Err("may not load x.scm".to_owned()));
+        let seen = seen.borrow();
+        assert_eq!(seen[0], "Eval(\"(string-contains \\\"ab\\\" \\\"b\\\")\")");
+        assert!(seen.contains(&"NativeCall(\"string-contains\")".to_owned()));
+        assert_eq!(seen.last().unwrap(), "Load(\"x.scm\")");
+    }
+}
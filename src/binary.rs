@@ -0,0 +1,66 @@
+//! The binary encoding of compiled objects and snapshots.
+//!
+//! Integers are little-endian `u64`s, whatever the size of the integer in
+//! Rust, and strings are a length followed by UTF-8.
+
+use std::io::{self, Read, Write};
+
+pub fn write_u64<W: Write>(w: &mut W, x: u64) -> io::Result<()> {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (x >> (8 * i)) as u8
+    }
+    w.write_all(&bytes)
+}
+
+pub fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    try!(write_u64(w, s.len() as u64));
+    w.write_all(s.as_bytes())
+}
+
+/// Reads the encoding of a kind of file, `what`, which is named in errors.
+pub struct Reader<R> {
+    r: R,
+    what: &'static str,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(r: R, what: &'static str) -> Self {
+        Reader { r: r, what: what }
+    }
+
+    /// An error saying that the file is bad.
+    pub fn error(&self, why: &str) -> String {
+        format!("bad {}: {}", self.what, why)
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<Vec<u8>, String> {
+        let mut buf = vec![];
+        try!((&mut self.r).take(n as u64).read_to_end(&mut buf).map_err(|e| e.to_string()));
+        if buf.len() < n {
+            return Err(self.error("unexpected end of file"))
+        }
+        Ok(buf)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(try!(self.bytes(8)).iter().rev().fold(0, |x, &byte| x << 8 | byte as u64))
+    }
+
+    /// Reads a count or index, which must be at most `max`.
+    pub fn usize(&mut self, max: usize) -> Result<usize, String> {
+        let x = try!(self.u64());
+        if x > max as u64 {
+            return Err(self.error("number out of range"))
+        }
+        Ok(x as usize)
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        // A string cannot be longer than the file, so there is no need to
+        // trust the length before reading.
+        let len = try!(self.usize(::std::usize::MAX));
+        let bytes = try!(self.bytes(len));
+        String::from_utf8(bytes).map_err(|_| self.error("string is not UTF-8"))
+    }
+}
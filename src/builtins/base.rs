@@ -0,0 +1,121 @@
+//! The `(rusty base)` library: basic procedures on pairs, booleans, the
+//! EOF object, and equality, and `apply` and `procedure?`.
+
+use closure;
+use equal;
+use interp::State;
+use value::{self, Value};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 16] = [
+    Native { name: "car", arity: Arity::Exactly(1), function: car },
+    Native { name: "cdr", arity: Arity::Exactly(1), function: cdr },
+    Native { name: "cons", arity: Arity::Exactly(2), function: cons },
+    Native { name: "set-car!", arity: Arity::Exactly(2), function: set_car },
+    Native { name: "set-cdr!", arity: Arity::Exactly(2), function: set_cdr },
+    Native { name: "pair?", arity: Arity::Exactly(1), function: is_pair },
+    Native { name: "null?", arity: Arity::Exactly(1), function: is_null },
+    Native { name: "not", arity: Arity::Exactly(1), function: not },
+    Native { name: "eof-object", arity: Arity::Exactly(0), function: eof_object },
+    Native { name: "eof-object?", arity: Arity::Exactly(1), function: is_eof_object },
+    Native { name: "eq?", arity: Arity::Exactly(2), function: is_eq },
+    Native { name: "eqv?", arity: Arity::Exactly(2), function: is_eqv },
+    Native { name: "equal?", arity: Arity::Exactly(2), function: is_equal },
+    Native { name: "equal-hash", arity: Arity::Between(1, 2), function: equal_hash },
+    Native { name: "apply", arity: Arity::AtLeast(2), function: apply },
+    Native { name: "procedure?", arity: Arity::Exactly(1), function: is_procedure },
+];
+
+fn car(s: &mut State, argc: usize) -> Result<Value, String> {
+    args(s, argc)[0].car().map_err(|()| "Attempt to take the car of a non-pair".to_owned())
+}
+
+fn cdr(s: &mut State, argc: usize) -> Result<Value, String> {
+    args(s, argc)[0].cdr().map_err(|()| "Attempt to take the cdr of a non-pair".to_owned())
+}
+
+fn cons(s: &mut State, _: usize) -> Result<Value, String> {
+    let len = s.heap.stack.len();
+    try!(s.heap.alloc_pair(len - 2, len - 1));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn set_car(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (pair, new) = {
+        let args = args(s, argc);
+        (args[0].clone(), args[1].clone())
+    };
+    try!(pair.set_car(new.clone()).map_err(|()| "set-car!: not a pair".to_owned()));
+    s.heap.write_barrier(&pair, &new);
+    Ok(Value::unspecified())
+}
+
+fn set_cdr(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (pair, new) = {
+        let args = args(s, argc);
+        (args[0].clone(), args[1].clone())
+    };
+    try!(pair.set_cdr(new.clone()).map_err(|()| "set-cdr!: not a pair".to_owned()));
+    s.heap.write_barrier(&pair, &new);
+    Ok(Value::unspecified())
+}
+
+fn is_pair(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(args(s, argc)[0].pairp()))
+}
+
+fn is_null(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(args(s, argc)[0].is_nil()))
+}
+
+fn not(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(!args(s, argc)[0].is_true()))
+}
+
+fn eof_object(_: &mut State, _: usize) -> Result<Value, String> {
+    Ok(Value::eof())
+}
+
+fn is_eof_object(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(args(s, argc)[0].is_eof()))
+}
+
+fn is_eq(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    Ok(Value::boolean(equal::eq(&args[0], &args[1])))
+}
+
+fn is_eqv(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    Ok(Value::boolean(equal::eqv(&args[0], &args[1])))
+}
+
+fn is_equal(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    Ok(Value::boolean(equal::equal(&args[0], &args[1])))
+}
+
+/// `(equal-hash x [bound])` is a hash of `x` that agrees with `equal?`,
+/// less than `bound` if it is given.
+fn equal_hash(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    // Drop the top bits, so that the hash is a non-negative fixnum.
+    let hash = equal::equal_hash(&args[0]) as usize >> 3;
+    match args.get(1).map(|bound| bound.as_fixnum()) {
+        None => Ok(Value::new(hash << 2)),
+        Some(Ok(bound)) if (bound << 2) as isize > 0 => Ok(Value::new(hash % bound << 2)),
+        Some(_) => Err("equal-hash: bound is not a positive fixnum".to_owned()),
+    }
+}
+
+/// `(procedure? x)` is whether `x` is a closure or a native procedure.
+fn is_procedure(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = &args(s, argc)[0];
+    Ok(Value::boolean(closure::is_closure(x) || x.tag() == value::Tags::RustFunc))
+}
+
+/// `apply` is called by the interpreter itself (see `interp`), and never
+/// through its descriptor.
+pub fn apply(_: &mut State, _: usize) -> Result<Value, String> {
+    bug!("apply called as a native procedure")
+}
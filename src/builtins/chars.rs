@@ -0,0 +1,127 @@
+//! The `(rusty chars)` library: classifying characters, changing their
+//! case, and converting them to and from code points.
+//!
+//! Characters are immediates (see `value`), written `#\a`, `#\space`
+//! or `#\x3bb`, so equal characters are `eqv?`.
+//!
+//! Without the `unicode-chars` feature, only ASCII characters are
+//! alphabetic, numeric, whitespace or cased, so these procedures agree
+//! with the C locale.  With it, they use Unicode's categories: alphabetic
+//! characters are those with the `Alphabetic` property, numeric ones are
+//! those in the `Nd`, `Nl` and `No` categories, and whitespace is
+//! `White_Space`.  A character whose upper or lower case is more than one
+//! character (as `ß` is `SS`) is left as it is.
+
+use std::char;
+
+use interp::State;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 8] = [
+    Native { name: "char?", arity: Arity::Exactly(1), function: is_char },
+    Native { name: "char-alphabetic?", arity: Arity::Exactly(1), function: is_alphabetic },
+    Native { name: "char-numeric?", arity: Arity::Exactly(1), function: is_numeric },
+    Native { name: "char-whitespace?", arity: Arity::Exactly(1), function: is_whitespace },
+    Native { name: "char-upcase", arity: Arity::Exactly(1), function: upcase },
+    Native { name: "char-downcase", arity: Arity::Exactly(1), function: downcase },
+    Native { name: "char->integer", arity: Arity::Exactly(1), function: char_to_integer },
+    Native { name: "integer->char", arity: Arity::Exactly(1), function: integer_to_char },
+];
+
+/// The character that is the argument of `name`.
+fn char_arg(s: &State, argc: usize, name: &str) -> Result<char, String> {
+    args(s, argc)[0].as_char().ok_or_else(|| format!("{}: not a character", name))
+}
+
+#[cfg(not(feature = "unicode-chars"))]
+mod classes {
+    pub fn is_alphabetic(c: char) -> bool {
+        c.is_ascii_alphabetic()
+    }
+
+    pub fn is_numeric(c: char) -> bool {
+        c.is_ascii_digit()
+    }
+
+    pub fn is_whitespace(c: char) -> bool {
+        c.is_ascii_whitespace()
+    }
+
+    pub fn upcase(c: char) -> char {
+        c.to_ascii_uppercase()
+    }
+
+    pub fn downcase(c: char) -> char {
+        c.to_ascii_lowercase()
+    }
+}
+
+#[cfg(feature = "unicode-chars")]
+mod classes {
+    /// The only character of `chars`, or `c` if there is not just one.
+    fn single<I: Iterator<Item = char>>(c: char, mut chars: I) -> char {
+        match (chars.next(), chars.next()) {
+            (Some(single), None) => single,
+            _ => c,
+        }
+    }
+
+    pub fn is_alphabetic(c: char) -> bool {
+        c.is_alphabetic()
+    }
+
+    pub fn is_numeric(c: char) -> bool {
+        c.is_numeric()
+    }
+
+    pub fn is_whitespace(c: char) -> bool {
+        c.is_whitespace()
+    }
+
+    pub fn upcase(c: char) -> char {
+        single(c, c.to_uppercase())
+    }
+
+    pub fn downcase(c: char) -> char {
+        single(c, c.to_lowercase())
+    }
+}
+
+fn is_char(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(char_arg(s, argc, "char?").is_ok()))
+}
+
+fn is_alphabetic(s: &mut State, argc: usize) -> Result<Value, String> {
+    char_arg(s, argc, "char-alphabetic?").map(|c| Value::boolean(classes::is_alphabetic(c)))
+}
+
+fn is_numeric(s: &mut State, argc: usize) -> Result<Value, String> {
+    char_arg(s, argc, "char-numeric?").map(|c| Value::boolean(classes::is_numeric(c)))
+}
+
+fn is_whitespace(s: &mut State, argc: usize) -> Result<Value, String> {
+    char_arg(s, argc, "char-whitespace?").map(|c| Value::boolean(classes::is_whitespace(c)))
+}
+
+fn upcase(s: &mut State, argc: usize) -> Result<Value, String> {
+    char_arg(s, argc, "char-upcase").map(|c| Value::character(classes::upcase(c)))
+}
+
+fn downcase(s: &mut State, argc: usize) -> Result<Value, String> {
+    char_arg(s, argc, "char-downcase").map(|c| Value::character(classes::downcase(c)))
+}
+
+fn char_to_integer(s: &mut State, argc: usize) -> Result<Value, String> {
+    char_arg(s, argc, "char->integer").map(|c| Value::new((c as usize) << 2))
+}
+
+/// `(integer->char n)` is the character whose code point is `n`, which
+/// must not be a surrogate.
+fn integer_to_char(s: &mut State, argc: usize) -> Result<Value, String> {
+    let c = match args(s, argc)[0].as_fixnum() {
+        Ok(n) if n <= 0x10FFFF => char::from_u32(n as u32),
+        _ => None,
+    };
+    c.map(Value::character).ok_or_else(|| "integer->char: not a Unicode scalar value".to_owned())
+}
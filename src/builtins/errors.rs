@@ -0,0 +1,184 @@
+//! The `(rusty errors)` library: raising errors from Scheme code.
+//!
+//! `(raise obj)` raises an error carrying `obj`, which the embedding API
+//! recovers (see `api::SchemeError`).  `(error message irritant ...)`
+//! raises a condition: a record of type `condition`, whose fields are its
+//! `kind` (a symbol, `error` for these), its `message`, a list of its
+//! `irritants`, and its `backtrace`.  The embedding API turns its errors
+//! into conditions of other kinds, and back.
+//!
+//! The backtrace of a raised condition is filled in once the error has
+//! unwound all of the Scheme code, as a list of strings, one for each
+//! frame, innermost first (see `interp::Frame::describe`).
+//! `(error-backtrace condition)` returns it.
+
+use api::SchemeValue;
+use interp::{Frame, State};
+use print;
+use record;
+use string;
+use value::{self, Value};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 3] = [
+    Native { name: "raise", arity: Arity::Exactly(1), function: raise },
+    Native { name: "error", arity: Arity::AtLeast(1), function: error },
+    Native { name: "error-backtrace", arity: Arity::Exactly(1), function: error_backtrace },
+];
+
+/// The name of the record type of conditions.
+const CONDITION: &'static str = "condition";
+
+/// The fields of conditions.
+const FIELDS: [&'static str; 4] = ["kind", "message", "irritants", "backtrace"];
+
+/// Makes `x` the value raised in `s`, and returns the error that raises it,
+/// whose message is `message`.
+pub fn raise_value(s: &mut State, x: Value, message: String) -> String {
+    if let Some((root, _)) = s.raised.take() {
+        s.heap.persistent.release(root)
+    }
+    s.raised = Some((s.heap.persistent.add(x), message.clone()));
+    message
+}
+
+/// Pushes a condition of kind `kind`, whose message is `s.heap.stack[start]`
+/// and whose irritants are the values above it, up to `end`.
+pub fn push_condition(s: &mut State, kind: &str, start: usize, end: usize) -> Result<(), String> {
+    let base = s.heap.stack.len();
+    let res = make_condition(s, kind, start, end);
+    let condition = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    Ok(s.heap.stack.push(condition.unwrap()))
+}
+
+fn make_condition(s: &mut State, kind: &str, start: usize, end: usize) -> Result<(), String> {
+    let base = s.heap.stack.len();
+    try!(s.host_types.push(&mut s.heap, CONDITION, &FIELDS));
+    s.heap.intern(kind);
+    let message = s.heap.stack[start].clone();
+    s.heap.stack.push(message);
+    s.heap.stack.push(Value::nil());
+    for i in (start + 1..end).rev() {
+        let tail = s.heap.stack.len() - 1;
+        try!(s.heap.alloc_pair(i, tail));
+        let pair = s.heap.stack.pop().unwrap();
+        *s.heap.stack.last_mut().unwrap() = pair
+    }
+    s.heap.stack.push(Value::nil());
+    record::make_record(&mut s.heap, base, base + 1 + FIELDS.len())
+}
+
+/// Sets the backtrace of the condition `s.heap.stack[index]` to `frames`.
+pub fn set_backtrace(s: &mut State, index: usize, frames: &[Frame]) -> Result<(), String> {
+    let base = s.heap.stack.len();
+    let res = push_backtrace(s, frames);
+    let backtrace = s.heap.stack.pop();
+    s.heap.stack.truncate(base);
+    try!(res);
+    let (condition, backtrace) = (s.heap.stack[index].clone(), backtrace.unwrap());
+    try!(record::set_field(&condition, "backtrace", backtrace.clone()));
+    Ok(s.heap.write_barrier(&condition, &backtrace))
+}
+
+/// Pushes a list of the descriptions of `frames`.
+fn push_backtrace(s: &mut State, frames: &[Frame]) -> Result<(), String> {
+    let oom = |_| "out of memory".to_owned();
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    for frame in frames.iter().rev() {
+        let description = try!(frame.describe(s).to_value(&mut s.heap).map_err(oom));
+        s.heap.stack.push(description);
+        try!(s.heap.alloc_pair(base + 1, base));
+        let pair = s.heap.stack.pop().unwrap();
+        s.heap.stack.truncate(base);
+        s.heap.stack.push(pair)
+    }
+    Ok(())
+}
+
+/// Gives the condition raised by the error that has just unwound all of
+/// the Scheme code, if one was, the backtrace of the error.
+pub fn attach_backtrace(s: &mut State) {
+    let root = match s.raised {
+        Some((root, _)) => root,
+        None => return,
+    };
+    let raised = s.heap.persistent.get(root);
+    if condition(&raised).is_none() {
+        return
+    }
+    let base = s.heap.stack.len();
+    s.heap.stack.push(raised);
+    let frames = s.backtrace.clone();
+    // Without the memory for the backtrace, the condition has none.
+    let _ = set_backtrace(s, base, &frames);
+    s.heap.stack.truncate(base)
+}
+
+/// The kind and message of `x`, if it is a condition.
+pub fn condition(x: &Value) -> Option<(String, String)> {
+    let name = match record::record_type(x).and_then(|rtd| record::record_type_name(&rtd)) {
+        Ok(name) => name,
+        Err(_) => return None,
+    };
+    match name.kind() {
+        value::Kind::Symbol(name) if unsafe { (*name).name() } == CONDITION => {}
+        _ => return None,
+    }
+    match (record::field(x, "kind").map(|x| x.kind()), record::field(x, "message")) {
+        (Ok(value::Kind::Symbol(kind)), Ok(message)) => {
+            let message = match string::as_str(&message) {
+                Some(message) => message.to_owned(),
+                None => return None,
+            };
+            Some((unsafe { (*kind).name() }.to_owned(), message))
+        }
+        _ => None,
+    }
+}
+
+/// `s.heap.stack[index]`, as `write` prints it.
+fn written(s: &mut State, index: usize) -> Result<String, String> {
+    let x = s.heap.stack[index].clone();
+    s.heap.stack.push(x);
+    let res = print::print(s, false);
+    s.heap.stack.pop();
+    res
+}
+
+fn raise(s: &mut State, argc: usize) -> Result<Value, String> {
+    let message = match condition(&args(s, argc)[0]) {
+        Some((_, message)) => message,
+        None => {
+            let len = s.heap.stack.len();
+            format!("uncaught exception: {}", try!(written(s, len - 1)))
+        }
+    };
+    let x = args(s, argc)[0].clone();
+    Err(raise_value(s, x, message))
+}
+
+fn error_backtrace(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = &args(s, argc)[0];
+    match condition(x) {
+        Some(_) => record::field(x, "backtrace"),
+        None => Err("error-backtrace: not a condition".to_owned()),
+    }
+}
+
+fn error(s: &mut State, argc: usize) -> Result<Value, String> {
+    let start = s.heap.stack.len() - argc;
+    let mut message = match string::as_str(&s.heap.stack[start]) {
+        Some(message) => message.to_owned(),
+        None => try!(written(s, start)),
+    };
+    for i in start + 1..start + argc {
+        message.push(' ');
+        message.push_str(&try!(written(s, i)))
+    }
+    try!(push_condition(s, "error", start, start + argc));
+    let condition = s.heap.stack.pop().unwrap();
+    Err(raise_value(s, condition, message))
+}
@@ -0,0 +1,192 @@
+//! The `(rusty eval)` library: `eval`, and the environments it evaluates
+//! in.
+//!
+//! An environment is a record of type `environment`, with three fields:
+//!
+//! - `imports`: the list of its import sets, or `#f` for the interaction
+//!   environment, the top level of the REPL and of the files it loads,
+//!   whose imports are `State::libraries`.  Evaluating a form in the
+//!   interaction environment compiles it as if it were typed at the REPL,
+//!   so its imports last, and it can use the imports of earlier forms.  An
+//!   environment made by `environment` starts from the libraries defined
+//!   so far, imports its import sets, and forgets whatever the form
+//!   imports.
+//! - `sandbox` and `bindings`: `#f`, unless the environment is a sandbox
+//!   made by `make-environment`, when they are its number and the list of
+//!   its identifiers, each paired with the global it refers to.
+//!
+//! There is still only one global namespace (see `compiler::libraries`),
+//! so what an environment changes is only what its identifiers refer to.
+//! A definition evaluated in any but a sandbox defines the global of the
+//! same name.  A sandbox's code is closed (see `compiler::compile_closed`):
+//! its identifiers only refer to the globals it was given and those it
+//! defines, which are named after the sandbox, like `(environment 0) x`,
+//! so untrusted code can neither reach nor change anything else.
+
+use compiler::{self, Datum, Exports};
+use interp::{self, State};
+use record;
+use value::{self, Value};
+use super::{args, push_list, Arity, Native};
+
+pub static PROCEDURES: [Native; 4] = [
+    Native { name: "environment", arity: Arity::AtLeast(0), function: environment },
+    Native { name: "eval", arity: Arity::Exactly(2), function: eval },
+    Native {
+        name: "interaction-environment",
+        arity: Arity::Exactly(0),
+        function: interaction_environment,
+    },
+    Native { name: "make-environment", arity: Arity::Exactly(1), function: make_environment },
+];
+
+/// The name of the record type of environments.
+const ENVIRONMENT: &'static str = "environment";
+
+/// The fields of environments.
+const FIELDS: [&'static str; 3] = ["imports", "sandbox", "bindings"];
+
+/// Makes an environment whose fields are the values at the top of the
+/// stack, which are popped.
+fn push_environment(s: &mut State) -> Result<Value, String> {
+    let base = s.heap.stack.len() - FIELDS.len();
+    let res = s.host_types.push(&mut s.heap, ENVIRONMENT, &FIELDS).and_then(|()| {
+        for i in 0..FIELDS.len() {
+            let x = s.heap.stack[base + i].clone();
+            s.heap.stack.push(x)
+        }
+        let start = base + FIELDS.len();
+        record::make_record(&mut s.heap, start, start + 1 + FIELDS.len())
+    });
+    let env = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    Ok(env.unwrap())
+}
+
+/// The `import` declaration of the import sets `imports`.
+fn import(imports: Vec<Datum>) -> Datum {
+    Datum::list(Some(Datum::symbol("import")).into_iter().chain(imports).collect())
+}
+
+/// Pushes the list of the bindings `globals`, in order.
+fn push_bindings(s: &mut State, globals: &Exports) -> Result<(), String> {
+    let mut ids: Vec<_> = globals.keys().collect();
+    ids.sort();
+    let start = s.heap.stack.len();
+    for id in ids {
+        s.heap.intern(id);
+        s.heap.intern(&globals[id]);
+        let len = s.heap.stack.len();
+        try!(s.heap.alloc_pair(len - 2, len - 1));
+        let binding = s.heap.stack.pop().unwrap();
+        s.heap.stack.truncate(len - 2);
+        s.heap.stack.push(binding)
+    }
+    push_list(s, start)
+}
+
+/// The bindings that the list `x` of bindings pairs identifiers with.
+fn bindings(x: &Value) -> Result<Exports, String> {
+    let x = try!(compiler::datum(x));
+    Ok(x.as_list()
+        .unwrap()
+        .into_iter()
+        .map(|binding| {
+            match *binding {
+                Datum::Pair(ref pair) => {
+                    (pair.0.as_symbol().unwrap().to_owned(), pair.1.as_symbol().unwrap().to_owned())
+                }
+                _ => unreachable!(),
+            }
+        })
+        .collect())
+}
+
+/// `(environment import-set ...)` is the environment that imports the
+/// import sets.
+fn environment(s: &mut State, argc: usize) -> Result<Value, String> {
+    let imports = try!(args(s, argc).iter()
+                                    .map(|x| compiler::datum(x))
+                                    .collect::<Result<Vec<_>, _>>()
+                                    .map_err(|e| format!("environment: {}", e)));
+    // The import sets are checked now, rather than by every `eval`.
+    let mut libraries = s.libraries.clone();
+    libraries.imports.clear();
+    try!(compiler::compile_in(&mut libraries, &[import(imports)]));
+    let base = s.heap.stack.len();
+    for i in base - argc..base {
+        let x = s.heap.stack[i].clone();
+        s.heap.stack.push(x)
+    }
+    let res = push_list(s, base).and_then(|()| {
+        s.heap.stack.push(Value::new(value::FALSE));
+        s.heap.stack.push(Value::new(value::FALSE));
+        push_environment(s)
+    });
+    s.heap.stack.truncate(base);
+    res
+}
+
+/// `(interaction-environment)` is the environment of the REPL.
+fn interaction_environment(s: &mut State, _: usize) -> Result<Value, String> {
+    for _ in 0..FIELDS.len() {
+        s.heap.stack.push(Value::new(value::FALSE))
+    }
+    push_environment(s)
+}
+
+/// `(make-environment ids)` is a new sandbox, whose identifiers are those
+/// in the list `ids`, which refer to the globals of the same name.
+fn make_environment(s: &mut State, argc: usize) -> Result<Value, String> {
+    let ids = try!(compiler::datum(&args(s, argc)[0]).ok().and_then(|ids| {
+        ids.as_list().and_then(|ids| {
+            ids.into_iter().map(|id| id.as_symbol().map(str::to_owned)).collect::<Option<Vec<_>>>()
+        })
+    }).ok_or_else(|| "make-environment: not a list of identifiers".to_owned()));
+    let globals = ids.into_iter().map(|id| (id.clone(), id)).collect();
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    s.heap.stack.push(Value::new(s.sandboxes << 2));
+    let res = push_bindings(s, &globals).and_then(|()| push_environment(s));
+    s.heap.stack.truncate(base);
+    s.sandboxes += 1;
+    res
+}
+
+/// `(eval expr env)` compiles the datum `expr` in the environment `env`,
+/// and runs it.
+fn eval(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (form, env) = {
+        let args = args(s, argc);
+        (try!(compiler::datum(&args[0]).map_err(|e| format!("eval: {}", e))), args[1].clone())
+    };
+    if !record::is_named(&env, ENVIRONMENT) {
+        return Err("eval: not an environment".to_owned())
+    }
+    let imports = try!(record::field(&env, "imports"));
+    let sandbox = try!(record::field(&env, "sandbox"));
+    let program = if let Ok(n) = sandbox.as_fixnum() {
+        let mut globals = try!(bindings(&try!(record::field(&env, "bindings"))));
+        let name = vec!["environment".to_owned(), n.to_string()];
+        let program = try!(compiler::compile_closed(&s.libraries, name, &mut globals, &[form]));
+        // The sandbox keeps its definitions, even if running them fails.
+        try!(push_bindings(s, &globals));
+        let bindings = s.heap.stack.pop().unwrap();
+        let env = s.heap.stack.last().unwrap().clone();
+        try!(record::set_field(&env, "bindings", bindings.clone()));
+        s.heap.write_barrier(&env, &bindings);
+        program
+    } else if imports.is_true() {
+        let imports = try!(compiler::datum(&imports).map_err(|e| format!("eval: {}", e)));
+        let imports = imports.as_list().unwrap().into_iter().cloned().collect();
+        let mut libraries = s.libraries.clone();
+        libraries.imports.clear();
+        try!(compiler::compile_in(&mut libraries, &[import(imports), form]))
+    } else {
+        try!(compiler::compile_in(&mut s.libraries, &[form]))
+    };
+    try!(interp::load(s, &program));
+    try!(interp::call(s, 0));
+    Ok(s.heap.stack.pop().unwrap())
+}
@@ -0,0 +1,144 @@
+//! The `(rusty files)` library: the file system, for scripts.
+//!
+//! File names are strings, relative to the current directory (unlike
+//! `load`'s, which are relative to the file being loaded).  A procedure
+//! that fails raises a condition of kind `io-error` (see
+//! `builtins::errors`), whose message says what failed and why, and whose
+//! irritant is the file name, so that the embedding API sees an
+//! `ErrorKind::IoError`.  `directory-files` lists the names of the files
+//! in a directory, without the directory, in order.
+
+use std::fs;
+use std::io;
+
+use api::SchemeValue;
+use interp::State;
+use string;
+use value::Value;
+use super::{args, errors, Arity, Native};
+
+pub static PROCEDURES: [Native; 6] = [
+    Native { name: "create-directory", arity: Arity::Exactly(1), function: create_directory },
+    Native { name: "delete-file", arity: Arity::Exactly(1), function: delete_file },
+    Native { name: "directory-files", arity: Arity::Exactly(1), function: directory_files },
+    Native { name: "file-exists?", arity: Arity::Exactly(1), function: file_exists },
+    Native { name: "file-size", arity: Arity::Exactly(1), function: file_size },
+    Native { name: "rename-file", arity: Arity::Exactly(2), function: rename_file },
+];
+
+fn new_string(s: &mut State, string: String) -> Result<Value, String> {
+    string.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned())
+}
+
+/// The `i`th of the `argc` arguments, which must be a file name.
+fn file_name(s: &State, name: &str, argc: usize, i: usize) -> Result<String, String> {
+    match string::as_str(&args(s, argc)[i]) {
+        Some(file) => Ok(file.to_owned()),
+        None => Err(format!("{}: file name is not a string", name)),
+    }
+}
+
+/// Raises the `io-error` condition of `name` failing with `e` on the file
+/// named by its `i`th of `argc` arguments, and returns the error that
+/// raises it.
+fn io_error(s: &mut State, name: &str, argc: usize, i: usize, e: io::Error) -> String {
+    let index = s.heap.stack.len() - argc + i;
+    let message = format!("{}: {}: {}", name, string::as_str(&s.heap.stack[index]).unwrap(), e);
+    let base = s.heap.stack.len();
+    let res = new_string(s, message.clone()).and_then(|text| {
+        s.heap.stack.push(text);
+        let file = s.heap.stack[index].clone();
+        s.heap.stack.push(file);
+        errors::push_condition(s, "io-error", base, base + 2)
+    });
+    let condition = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    match condition {
+        Some(condition) => errors::raise_value(s, condition, message),
+        None => message,
+    }
+}
+
+/// `(file-exists? name)` is whether there is a file (or directory) named
+/// `name`.
+fn file_exists(s: &mut State, argc: usize) -> Result<Value, String> {
+    let file = try!(file_name(s, "file-exists?", argc, 0));
+    Ok(Value::boolean(fs::metadata(file).is_ok()))
+}
+
+/// `(file-size name)` is the size of the file `name`, in bytes.
+fn file_size(s: &mut State, argc: usize) -> Result<Value, String> {
+    let file = try!(file_name(s, "file-size", argc, 0));
+    match fs::metadata(file) {
+        Ok(metadata) => Ok(Value::new((metadata.len() as usize) << 2)),
+        Err(e) => Err(io_error(s, "file-size", argc, 0, e)),
+    }
+}
+
+/// `(delete-file name)` deletes the file `name`, which must not be a
+/// directory.
+fn delete_file(s: &mut State, argc: usize) -> Result<Value, String> {
+    let file = try!(file_name(s, "delete-file", argc, 0));
+    match fs::remove_file(file) {
+        Ok(()) => Ok(Value::unspecified()),
+        Err(e) => Err(io_error(s, "delete-file", argc, 0, e)),
+    }
+}
+
+/// `(rename-file from to)` renames the file `from` to `to`, replacing any
+/// file named `to`.
+fn rename_file(s: &mut State, argc: usize) -> Result<Value, String> {
+    let from = try!(file_name(s, "rename-file", argc, 0));
+    let to = try!(file_name(s, "rename-file", argc, 1));
+    match fs::rename(from, to) {
+        Ok(()) => Ok(Value::unspecified()),
+        Err(e) => Err(io_error(s, "rename-file", argc, 0, e)),
+    }
+}
+
+/// `(create-directory name)` makes the directory `name`, whose parent must
+/// exist.
+fn create_directory(s: &mut State, argc: usize) -> Result<Value, String> {
+    let file = try!(file_name(s, "create-directory", argc, 0));
+    match fs::create_dir(file) {
+        Ok(()) => Ok(Value::unspecified()),
+        Err(e) => Err(io_error(s, "create-directory", argc, 0, e)),
+    }
+}
+
+/// The sorted names of the files in the directory `dir`.
+fn files_in(dir: &str) -> io::Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in try!(fs::read_dir(dir)) {
+        let name = try!(entry).file_name();
+        names.push(name.to_string_lossy().into_owned())
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// `(directory-files name)` is the list of the names of the files in the
+/// directory `name`.
+fn directory_files(s: &mut State, argc: usize) -> Result<Value, String> {
+    let dir = try!(file_name(s, "directory-files", argc, 0));
+    let names = match files_in(&dir) {
+        Ok(names) => names,
+        Err(e) => return Err(io_error(s, "directory-files", argc, 0, e)),
+    };
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    for name in names.into_iter().rev() {
+        let res = new_string(s, name).and_then(|name| {
+            s.heap.stack.push(name);
+            Ok(try!(s.heap.alloc_pair(base + 1, base)))
+        });
+        if let Err(e) = res {
+            s.heap.stack.truncate(base);
+            return Err(e)
+        }
+        let list = s.heap.stack.pop().unwrap();
+        s.heap.stack[base] = list;
+        s.heap.stack.pop();
+    }
+    Ok(s.heap.stack.pop().unwrap())
+}
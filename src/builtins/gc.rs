@@ -0,0 +1,85 @@
+//! The `(rusty gc)` library: statistics about the heap.
+//!
+//! `(gc-stats)` returns the statistics of `alloc::Heap::stats` as an
+//! association list from symbols, such as `live-bytes` and
+//! `major-collections`, to numbers.  Pauses are in microseconds.  The value
+//! of `objects` is an association list from the kinds of objects, such as
+//! `pair` and `rust-data`, to the number of them in the heap.
+
+use std::time::Duration;
+
+use interp::State;
+use value::Value;
+use super::{Arity, Native};
+
+pub static PROCEDURES: [Native; 1] = [
+    Native { name: "gc-stats", arity: Arity::Exactly(0), function: gc_stats },
+];
+
+fn microseconds(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1_000_000 + duration.subsec_nanos() as usize / 1000
+}
+
+/// Pushes the pair of the symbol `name` and the fixnum `n`.
+fn push_entry(s: &mut State, name: &str, n: usize) -> Result<(), String> {
+    let base = s.heap.stack.len();
+    s.heap.intern(name);
+    s.heap.stack.push(Value::new(n << 2));
+    try!(s.heap.alloc_pair(base, base + 1));
+    let pair = s.heap.stack.pop().unwrap();
+    s.heap.stack.truncate(base);
+    Ok(s.heap.stack.push(pair))
+}
+
+/// Replaces the values on the stack from `start` up with a list of them.
+fn make_list(s: &mut State, start: usize) -> Result<(), String> {
+    s.heap.stack.push(Value::nil());
+    while s.heap.stack.len() > start + 1 {
+        let len = s.heap.stack.len();
+        try!(s.heap.alloc_pair(len - 2, len - 1));
+        let pair = s.heap.stack.pop().unwrap();
+        s.heap.stack.truncate(len - 2);
+        s.heap.stack.push(pair)
+    }
+    Ok(())
+}
+
+fn push_stats(s: &mut State) -> Result<(), String> {
+    let stats = s.heap.stats();
+    let numbers = [("live-bytes", stats.live_bytes),
+                   ("bytes-in-use", stats.bytes_in_use),
+                   ("allocations-since-gc", stats.allocations_since_gc),
+                   ("bytes-since-gc", stats.bytes_since_gc as usize),
+                   ("bytes-allocated", stats.bytes_allocated as usize),
+                   ("minor-collections", stats.minor_collections),
+                   ("major-collections", stats.major_collections),
+                   ("total-pause", microseconds(stats.total_pause)),
+                   ("max-pause", microseconds(stats.max_pause)),
+                   ("last-pause", stats.last_pause.map_or(0, microseconds))];
+    let start = s.heap.stack.len();
+    for &(name, n) in &numbers {
+        try!(push_entry(s, name, n))
+    }
+    let objects = s.heap.stack.len();
+    for (kind, &n) in &stats.objects {
+        try!(push_entry(s, &kind.replace(' ', "-"), n))
+    }
+    try!(make_list(s, objects));
+    s.heap.intern("objects");
+    try!(s.heap.alloc_pair(objects + 1, objects));
+    let pair = s.heap.stack.pop().unwrap();
+    s.heap.stack.truncate(objects);
+    s.heap.stack.push(pair);
+    make_list(s, start)
+}
+
+/// `(gc-stats)` returns the statistics of the heap, as an association
+/// list.
+fn gc_stats(s: &mut State, _: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    let res = push_stats(s);
+    let stats = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    Ok(stats.unwrap())
+}
@@ -0,0 +1,70 @@
+//! Native procedures defined by the host at run time, rather than in a
+//! library (see `api::Vm::register_fn`).
+//!
+//! A host procedure is a `Native` like any other, so the interpreter calls
+//! it the same way, but its descriptor is allocated when it is registered,
+//! and kept by the `Registry` for the lifetime of the interpreter.  The
+//! descriptor is the first field of a `HostProcedure`, and its function is
+//! `trampoline`, which finds the rest of the `HostProcedure` from the
+//! procedure being called: the name, and the boxed Rust closure.
+
+use std::fmt;
+
+use interp::State;
+use value::Value;
+use super::{Arity, Native};
+
+/// The closure of a host procedure.  It is called like a `NativeFn`.
+pub type HostFn = Box<Fn(&mut State, usize) -> Result<Value, String>>;
+
+/// A host procedure.
+#[repr(C)]
+pub struct HostProcedure {
+    /// Must be first, so that a pointer to the descriptor is a pointer to
+    /// the `HostProcedure`.
+    native: Native,
+
+    /// The storage of `native.name`.
+    name: String,
+
+    function: HostFn,
+}
+
+impl fmt::Debug for HostProcedure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostProcedure({})", self.name)
+    }
+}
+
+impl HostProcedure {
+    pub fn new(name: &str, arity: Arity, function: HostFn) -> Box<HostProcedure> {
+        let mut procedure = Box::new(HostProcedure {
+            native: Native {
+                name: "",
+                arity: arity,
+                function: trampoline,
+            },
+            name: name.to_owned(),
+            function: function,
+        });
+        // The string's buffer does not move when the box does, and lives as
+        // long as the descriptor.
+        procedure.native.name = unsafe { &*(procedure.name.as_str() as *const str) };
+        procedure
+    }
+
+    /// The descriptor.  It lives as long as the `Registry` that holds the
+    /// procedure, which is as long as the interpreter.
+    pub fn native(&self) -> &'static Native {
+        unsafe { &*(&self.native as *const Native) }
+    }
+}
+
+fn trampoline(s: &mut State, argc: usize) -> Result<Value, String> {
+    let procedure = {
+        let len = s.heap.stack.len();
+        // The `Registry` keeps the procedure, and never removes it.
+        unsafe { &*(s.heap.stack[len - argc - 1].as_ptr() as *const HostProcedure) }
+    };
+    (procedure.function)(s, argc)
+}
@@ -0,0 +1,81 @@
+//! The `(rusty keywords)` library: keywords, and the keyword arguments of
+//! procedures whose lambda lists have `#!key` parameters.
+//!
+//! A keyword is a symbol whose name ends with a colon, like `size:`, and
+//! evaluates to itself (see `compiler::syntax`).  A procedure's keyword
+//! arguments follow its positional ones, alternating keywords with their
+//! values, so `(f 1 size: 3)` passes `size` as 3.  The prologue of such a
+//! procedure calls `%check-keywords` on the list of them, then
+//! `%keyword-argument` for each of its keyword parameters.
+
+use interp::State;
+use value::{self, Value};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 3] = [
+    Native { name: "keyword?", arity: Arity::Exactly(1), function: is_keyword },
+    Native { name: "%check-keywords", arity: Arity::Exactly(2), function: check_keywords },
+    Native { name: "%keyword-argument", arity: Arity::Exactly(2), function: keyword_argument },
+];
+
+/// The name of the keyword `x`, if it is one.
+fn keyword_name(x: &Value) -> Option<String> {
+    match x.kind() {
+        value::Kind::Symbol(ptr) => {
+            let name = unsafe { (*ptr).name() };
+            if name.len() > 1 && name.ends_with(':') {
+                Some(name.to_owned())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_keyword(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(keyword_name(&args(s, argc)[0]).is_some()))
+}
+
+/// `(%check-keywords arguments keywords)` checks that the list of keyword
+/// arguments `arguments` alternates keywords in the list `keywords` with
+/// values.
+fn check_keywords(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (mut list, keywords) = {
+        let args = args(s, argc);
+        (args[0].clone(), args[1].clone())
+    };
+    while let Ok(key) = list.car() {
+        let name = try!(keyword_name(&key).ok_or("keyword arguments: not a keyword"));
+        let mut known = keywords.clone();
+        while known.car().ok().map_or(false, |x| x.get() != key.get()) {
+            known = known.cdr().unwrap()
+        }
+        if !known.pairp() {
+            return Err(format!("keyword arguments: unknown keyword {}", name))
+        }
+        list = match list.cdr().unwrap().cdr() {
+            Ok(rest) => rest,
+            Err(_) => return Err(format!("keyword arguments: {} has no value", name)),
+        }
+    }
+    Ok(Value::unspecified())
+}
+
+/// `(%keyword-argument arguments keyword)` is the list that starts with the
+/// value of `keyword` in the checked list of keyword arguments
+/// `arguments`, or `#f` if it is not there.  The first value of a keyword
+/// that is passed more than once is its value.
+fn keyword_argument(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (mut list, key) = {
+        let args = args(s, argc);
+        (args[0].clone(), args[1].clone())
+    };
+    while let (Ok(x), Ok(rest)) = (list.car(), list.cdr()) {
+        if x.get() == key.get() {
+            return Ok(rest)
+        }
+        list = rest.cdr().unwrap()
+    }
+    Ok(Value::new(value::FALSE))
+}
@@ -0,0 +1,62 @@
+//! The `(rusty load)` library: running Scheme files, and compiling them
+//! ahead of time.
+
+use std::path::PathBuf;
+
+use audit;
+use compiler;
+use interp::{self, State};
+use string;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 2] = [
+    Native { name: "load", arity: Arity::Exactly(1), function: load },
+    Native { name: "compile-file", arity: Arity::Exactly(2), function: compile_file },
+];
+
+/// The file named by `x`, relative to the directory of the file being
+/// loaded, if any.
+fn path(s: &State, name: &str, x: &Value) -> Result<PathBuf, String> {
+    match string::as_str(x) {
+        Some(x) => Ok(compiler::resolve(s.loading.last().map(|x| x.as_path()), x)),
+        None => Err(format!("{}: file name is not a string", name)),
+    }
+}
+
+/// `(load name)` compiles and runs the file `name`, or just runs it if it
+/// is a compiled object (`.rsbc`) file.
+fn load(s: &mut State, argc: usize) -> Result<Value, String> {
+    let path = try!(path(s, "load", &args(s, argc)[0]));
+    try!(s.audit.run(&audit::Event::Load(&path)));
+    let program = if path.extension().map_or(false, |x| x == "rsbc") {
+        try!(compiler::load_object(&path))
+    } else {
+        try!(compiler::compile_file(&mut s.libraries, &path))
+    };
+    s.loading.push(path);
+    let res = run(s, &program);
+    s.loading.pop();
+    try!(res);
+    Ok(Value::unspecified())
+}
+
+/// `(compile-file source object)` compiles the file `source`, and saves
+/// the program in the compiled object file `object` without running it.
+fn compile_file(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (source, object) = {
+        let args = args(s, argc);
+        (try!(path(s, "compile-file", &args[0])), try!(path(s, "compile-file", &args[1])))
+    };
+    try!(s.audit.run(&audit::Event::CompileFile(&source, &object)));
+    let program = try!(compiler::compile_file(&mut s.libraries, &source));
+    try!(compiler::save_object(&program, &object));
+    Ok(Value::unspecified())
+}
+
+fn run(s: &mut State, program: &compiler::Program) -> Result<(), String> {
+    try!(interp::load(s, program));
+    try!(interp::call(s, 0));
+    s.heap.stack.pop();
+    Ok(())
+}
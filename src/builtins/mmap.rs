@@ -0,0 +1,139 @@
+//! The `(rusty mmap)` library: files mapped into memory, as bytevectors.
+//!
+//! A mapped file is a resource (see `alloc::finalize`), so its bytes are
+//! never copied into the GC heap, and the mapping is removed once the
+//! bytevector is collected.  Mappings are private: a copy-on-write mapping
+//! can be modified, but the modifications are never written to the file.
+//!
+//! There are no other bytevectors yet, so the `bytevector` procedures here
+//! only work on mapped files.
+
+extern crate libc;
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use alloc;
+use audit;
+use interp::State;
+use string;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 5] = [
+    Native { name: "mmap-file", arity: Arity::Between(1, 2), function: mmap_file },
+    Native { name: "bytevector?", arity: Arity::Exactly(1), function: is_bytevector },
+    Native { name: "bytevector-length", arity: Arity::Exactly(1), function: bytevector_length },
+    Native { name: "bytevector-u8-ref", arity: Arity::Exactly(2), function: bytevector_u8_ref },
+    Native { name: "bytevector-u8-set!", arity: Arity::Exactly(3), function: bytevector_u8_set },
+];
+
+/// A file mapped into memory.
+struct Mapping {
+    /// The start of the mapping, or null if the file is empty (empty
+    /// mappings are not allowed).
+    ptr: *mut u8,
+    len: usize,
+    writable: bool,
+}
+
+impl Mapping {
+    fn new(file: &File, writable: bool) -> Result<Mapping, String> {
+        let len = try!(file.metadata().map_err(|e| e.to_string())).len();
+        if len > ::std::usize::MAX as u64 {
+            return Err("file too large to map".to_owned())
+        }
+        let len = len as usize;
+        if len == 0 {
+            return Ok(Mapping { ptr: ptr::null_mut(), len: 0, writable: writable })
+        }
+        let protection = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       len,
+                       protection,
+                       libc::MAP_PRIVATE,
+                       file.as_raw_fd(),
+                       0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(::std::io::Error::last_os_error().to_string())
+        }
+        Ok(Mapping { ptr: ptr as *mut u8, len: len, writable: writable })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+fn mapping<'a>(name: &str, x: &'a Value) -> Result<&'a Mapping, String> {
+    alloc::resource::<Mapping>(x).ok_or_else(|| format!("{}: not a bytevector", name))
+}
+
+fn index(name: &str, mapping: &Mapping, x: &Value) -> Result<usize, String> {
+    match x.as_fixnum() {
+        Ok(i) if i < mapping.len => Ok(i),
+        Ok(_) => Err(format!("{}: index out of bounds", name)),
+        Err(_) => Err(format!("{}: index is not an exact integer", name)),
+    }
+}
+
+/// `(mmap-file name [copy-on-write])` maps the file `name` into memory.
+/// The bytevector is read-only unless `copy-on-write` is true.
+fn mmap_file(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (name, writable) = {
+        let args = args(s, argc);
+        let name = try!(string::as_str(&args[0])
+                            .ok_or_else(|| "mmap-file: file name is not a string".to_owned()));
+        (name.to_owned(), args.get(1).map_or(false, Value::is_true))
+    };
+    try!(s.audit.run(&audit::Event::MapFile(Path::new(&name))));
+    let file = try!(File::open(&name).map_err(|e| format!("mmap-file: {}: {}", name, e)));
+    let mapping = try!(Mapping::new(&file, writable)
+                           .map_err(|e| format!("mmap-file: {}: {}", name, e)));
+    try!(s.heap.alloc_resource(mapping));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn is_bytevector(s: &mut State, argc: usize) -> Result<Value, String> {
+    let mapped = alloc::resource::<Mapping>(&args(s, argc)[0]).is_some();
+    Ok(Value::boolean(mapped))
+}
+
+fn bytevector_length(s: &mut State, argc: usize) -> Result<Value, String> {
+    let len = try!(mapping("bytevector-length", &args(s, argc)[0])).len;
+    Ok(Value::new(len << 2))
+}
+
+fn bytevector_u8_ref(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let mapping = try!(mapping("bytevector-u8-ref", &args[0]));
+    let i = try!(index("bytevector-u8-ref", mapping, &args[1]));
+    Ok(Value::new((unsafe { *mapping.ptr.offset(i as isize) } as usize) << 2))
+}
+
+fn bytevector_u8_set(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let mapping = try!(mapping("bytevector-u8-set!", &args[0]));
+    let i = try!(index("bytevector-u8-set!", mapping, &args[1]));
+    let byte = match args[2].as_fixnum() {
+        Ok(byte) if byte < 256 => byte as u8,
+        _ => return Err("bytevector-u8-set!: not a byte".to_owned()),
+    };
+    if !mapping.writable {
+        return Err("bytevector-u8-set!: bytevector is read-only".to_owned())
+    }
+    unsafe { *mapping.ptr.offset(i as isize) = byte };
+    Ok(Value::unspecified())
+}
@@ -0,0 +1,1154 @@
+//! Native procedures ("builtins"), and their lazy registration.
+//!
+//! Builtins are grouped into libraries, each of which is a static table of
+//! `Native` descriptors.  Nothing is registered when a `State` is created:
+//! a library's procedures are bound to their global names the first time
+//! the library is imported (`Registry::import`), or the first time one of
+//! its names is looked up as an unbound global (`Registry::resolve_global`).
+//! Embedders that only use a few builtins therefore only pay for the
+//! libraries they actually touch.
+//!
+//! A native procedure is represented as a `Value` with tag `RUST_FUNC_TAG`,
+//! pointing to its (static) descriptor.  Descriptors are not on the GC heap,
+//! so the GC leaves these values alone.
+//!
+//! ### Calling convention
+//!
+//! A native procedure is called with the interpreter state and the number
+//! of arguments.  The arguments are the topmost values on the stack – use
+//! `args` to get at them.  The native procedure must not pop them; the
+//! interpreter pops them (and the procedure) after the call, and pushes the
+//! returned value.  The returned value is unrooted, so the native procedure
+//! must not allocate after computing it.
+
+use std::collections::HashMap;
+
+use audit;
+use interp;
+use alloc;
+use value::{self, Value};
+
+mod base;
+mod chars;
+pub mod errors;
+mod eval;
+mod files;
+mod gc;
+mod host;
+mod keywords;
+mod load;
+mod mmap;
+mod numbers;
+mod output;
+mod parameters;
+mod processes;
+mod promises;
+mod random;
+mod records;
+mod repl;
+mod sort;
+mod strings;
+mod symbols;
+mod system;
+mod threads;
+mod time;
+mod vectors;
+mod weak;
+
+pub use self::host::HostFn;
+
+/// The signature of a native procedure.
+pub type NativeFn = fn(&mut interp::State, usize) -> Result<Value, String>;
+
+/// The number of arguments a native procedure accepts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Arity {
+    /// Exactly this many arguments.
+    Exactly(usize),
+
+    /// At least this many arguments.
+    AtLeast(usize),
+
+    /// Between the first and second number of arguments, inclusive.
+    Between(usize, usize),
+}
+
+impl Arity {
+    /// Checks that `argc` arguments are acceptable.
+    pub fn check(&self, name: &str, argc: usize) -> Result<(), String> {
+        let ok = match *self {
+            Arity::Exactly(n) => argc == n,
+            Arity::AtLeast(n) => argc >= n,
+            Arity::Between(min, max) => argc >= min && argc <= max,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(format!("{}: wrong number of arguments: expected {}, got {}",
+                        name, self, argc))
+        }
+    }
+}
+
+impl ::std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::Between(min, max) => write!(f, "between {} and {}", min, max),
+        }
+    }
+}
+
+/// The descriptor of a native procedure.
+pub struct Native {
+    /// The global name of the procedure.
+    pub name: &'static str,
+
+    /// The number of arguments the procedure accepts.
+    pub arity: Arity,
+
+    /// The implementation.
+    pub function: NativeFn,
+}
+
+impl Native {
+    /// The Scheme value representing this procedure.
+    pub fn to_value(&'static self) -> Value {
+        let ptr = self as *const Native as usize;
+        debug_assert!(ptr & 0b111 == 0, "misaligned native descriptor");
+        Value::new(ptr | value::RUST_FUNC_TAG)
+    }
+}
+
+/// A library of native procedures.
+pub struct Library {
+    /// The name of the library, as in `(import (rusty base))`.
+    pub name: &'static [&'static str],
+
+    /// The procedures exported by the library.
+    pub procedures: &'static [Native],
+}
+
+/// All libraries of native procedures.
+pub static LIBRARIES: &'static [Library] = &[
+    Library { name: &["rusty", "base"], procedures: &base::PROCEDURES },
+    Library { name: &["rusty", "chars"], procedures: &chars::PROCEDURES },
+    Library { name: &["rusty", "errors"], procedures: &errors::PROCEDURES },
+    Library { name: &["rusty", "eval"], procedures: &eval::PROCEDURES },
+    Library { name: &["rusty", "files"], procedures: &files::PROCEDURES },
+    Library { name: &["rusty", "gc"], procedures: &gc::PROCEDURES },
+    Library { name: &["rusty", "keywords"], procedures: &keywords::PROCEDURES },
+    Library { name: &["rusty", "load"], procedures: &load::PROCEDURES },
+    Library { name: &["rusty", "mmap"], procedures: &mmap::PROCEDURES },
+    Library { name: &["rusty", "numbers"], procedures: &numbers::PROCEDURES },
+    Library { name: &["rusty", "output"], procedures: &output::PROCEDURES },
+    Library { name: &["rusty", "parameters"], procedures: &parameters::PROCEDURES },
+    Library { name: &["rusty", "processes"], procedures: &processes::PROCEDURES },
+    Library { name: &["rusty", "promises"], procedures: &promises::PROCEDURES },
+    Library { name: &["rusty", "random"], procedures: &random::PROCEDURES },
+    Library { name: &["rusty", "records"], procedures: &records::PROCEDURES },
+    Library { name: &["rusty", "repl"], procedures: &repl::PROCEDURES },
+    Library { name: &["rusty", "sort"], procedures: &sort::PROCEDURES },
+    Library { name: &["rusty", "strings"], procedures: &strings::PROCEDURES },
+    Library { name: &["rusty", "symbols"], procedures: &symbols::PROCEDURES },
+    Library { name: &["rusty", "system"], procedures: &system::PROCEDURES },
+    Library { name: &["rusty", "threads"], procedures: &threads::PROCEDURES },
+    Library { name: &["rusty", "time"], procedures: &time::PROCEDURES },
+    Library { name: &["rusty", "vectors"], procedures: &vectors::PROCEDURES },
+    Library { name: &["rusty", "weak"], procedures: &weak::PROCEDURES },
+];
+
+/// Tracks which libraries have been registered.
+#[derive(Debug, Default)]
+pub struct Registry {
+    /// Indexed like `LIBRARIES`.
+    loaded: Vec<bool>,
+
+    /// Maps procedure names to the index of their library.  Built the first
+    /// time an unbound global has to be resolved.
+    index: Option<HashMap<&'static str, usize>>,
+
+    /// The procedures registered by the host, which are kept for as long as
+    /// the interpreter.
+    host: Vec<Box<host::HostProcedure>>,
+}
+
+impl Registry {
+    /// Has the library named `name` been registered?
+    pub fn is_loaded(&self, name: &[&str]) -> bool {
+        LIBRARIES.iter()
+                 .position(|lib| lib.name == name)
+                 .and_then(|i| self.loaded.get(i).cloned())
+                 .unwrap_or(false)
+    }
+
+    /// The names of the libraries that have been registered.
+    pub fn loaded(&self) -> Vec<&'static [&'static str]> {
+        LIBRARIES.iter()
+                 .zip(&self.loaded)
+                 .filter(|&(_, &loaded)| loaded)
+                 .map(|(lib, _)| lib.name)
+                 .collect()
+    }
+
+    /// Marks the library named `name` as registered, without binding its
+    /// procedures.  Used when its bindings are restored from a snapshot.
+    pub fn set_loaded(&mut self, name: &[&str]) -> Result<(), String> {
+        match LIBRARIES.iter().position(|lib| lib.name == name) {
+            Some(i) => {
+                if self.loaded.len() < LIBRARIES.len() {
+                    self.loaded.resize(LIBRARIES.len(), false)
+                }
+                Ok(self.loaded[i] = true)
+            }
+            None => Err(format!("no such library: ({})", name.join(" "))),
+        }
+    }
+
+    /// Imports the library named `name`, binding all of its procedures to
+    /// their global names.  Does nothing if it is already registered.
+    pub fn import(&mut self, heap: &mut alloc::Heap, name: &[&str]) -> Result<(), String> {
+        match LIBRARIES.iter().position(|lib| lib.name == name) {
+            Some(i) => Ok(self.register(heap, i)),
+            None => Err(format!("no such library: ({})", name.join(" "))),
+        }
+    }
+
+    fn register(&mut self, heap: &mut alloc::Heap, library: usize) {
+        if self.loaded.len() < LIBRARIES.len() {
+            self.loaded.resize(LIBRARIES.len(), false)
+        }
+        if self.loaded[library] {
+            return
+        }
+        debug!("Registering library ({})", LIBRARIES[library].name.join(" "));
+        for native in LIBRARIES[library].procedures {
+            bind(heap, native)
+        }
+        self.loaded[library] = true
+    }
+
+    /// Resolves the symbol on top of the stack, if it is unbound and names
+    /// a native procedure.  Leaves the stack unchanged.
+    ///
+    /// If the procedure's library has not been registered yet, the whole
+    /// library is registered.  Otherwise, only the procedure is rebound – this
+    /// happens when the GC has collected the symbol it was bound to.
+    pub fn resolve_global(&mut self, heap: &mut alloc::Heap) -> Result<(), String> {
+        let name = match heap.stack.last().map(|x| x.kind()) {
+            Some(value::Kind::Symbol(ptr)) => unsafe {
+                if (*(*ptr).contents.get()).get() != value::UNBOUND {
+                    return Ok(())
+                }
+                (*ptr).name()
+            },
+            _ => return Ok(()),
+        };
+        if let Some(procedure) = self.host.iter().rev().find(|x| x.native().name == name) {
+            return Ok(bind(heap, procedure.native()))
+        }
+        let library = match self.index().get(name) {
+            Some(&library) => library,
+            None => return Ok(()),
+        };
+        if self.loaded.get(library).cloned().unwrap_or(false) {
+            let native = LIBRARIES[library]
+                             .procedures
+                             .iter()
+                             .find(|native| native.name == name)
+                             .expect("native procedure disappeared from its library");
+            bind(heap, native)
+        } else {
+            self.register(heap, library)
+        }
+        Ok(())
+    }
+
+    /// Registers `function` as the host procedure `name`, which accepts
+    /// `arity` arguments, and binds it to its name.
+    pub fn register_host(&mut self,
+                         heap: &mut alloc::Heap,
+                         name: &str,
+                         arity: Arity,
+                         function: HostFn) {
+        let procedure = host::HostProcedure::new(name, arity, function);
+        bind(heap, procedure.native());
+        self.host.push(procedure)
+    }
+
+    fn index(&mut self) -> &HashMap<&'static str, usize> {
+        if self.index.is_none() {
+            let mut index = HashMap::new();
+            for (i, library) in LIBRARIES.iter().enumerate() {
+                for native in library.procedures {
+                    index.insert(native.name, i);
+                }
+            }
+            self.index = Some(index)
+        }
+        self.index.as_ref().unwrap()
+    }
+}
+
+/// Binds `native` to its global name.
+fn bind(heap: &mut alloc::Heap, native: &'static Native) {
+    heap.stack.push(native.to_value());
+    heap.intern(native.name);
+    heap.store_global().expect("interned a non-symbol?")
+}
+
+/// The descriptor of the native procedure `x`, which must have tag
+/// `RUST_FUNC_TAG`.
+pub fn descriptor(x: &Value) -> &'static Native {
+    debug_assert_eq!(x.tag(), value::Tags::RustFunc);
+    unsafe { &*(x.as_ptr() as *const Native) }
+}
+
+/// The native procedure named `name`, in any library.
+pub fn find(name: &str) -> Option<&'static Native> {
+    LIBRARIES.iter().flat_map(|lib| lib.procedures).find(|native| native.name == name)
+}
+
+/// Is `x` the procedure `apply`?
+pub fn is_apply(x: &Value) -> bool {
+    x.tag() == value::Tags::RustFunc &&
+    unsafe { (*(x.as_ptr() as *const Native)).function as usize == base::apply as usize }
+}
+
+/// The arguments of a native procedure that was passed `argc` arguments.
+pub fn args(s: &interp::State, argc: usize) -> &[Value] {
+    let len = s.heap.stack.len();
+    &s.heap.stack[len - argc..]
+}
+
+/// Pushes the list of the values above `s.heap.stack[start]`, which are
+/// replaced by it.  If there is no memory for it, they are just popped.
+pub fn push_list(s: &mut interp::State, start: usize) -> Result<(), String> {
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    for i in (start..base).rev() {
+        if let Err(e) = s.heap.alloc_pair(i, base) {
+            s.heap.stack.truncate(start);
+            return Err(e.into())
+        }
+        let list = s.heap.stack.pop().unwrap();
+        s.heap.stack[base] = list
+    }
+    let list = s.heap.stack.pop().unwrap();
+    s.heap.stack.truncate(start);
+    s.heap.stack.push(list);
+    Ok(())
+}
+
+/// Calls the native procedure below the topmost `argc` values on the stack,
+/// replacing it and its arguments with the result.
+pub fn call_native(s: &mut interp::State, argc: usize) -> Result<(), String> {
+    let native = {
+        let len = s.heap.stack.len();
+        let procedure = &s.heap.stack[len - argc - 1];
+        debug_assert_eq!(procedure.tag(), value::Tags::RustFunc);
+        unsafe { &*(procedure.as_ptr() as *const Native) }
+    };
+    try!(native.arity.check(native.name, argc));
+    if !s.audit.is_empty() {
+        try!(s.audit.run(&audit::Event::NativeCall(native.name)))
+    }
+    let result = try!((native.function)(s, argc));
+    let len = s.heap.stack.len();
+    s.heap.stack.truncate(len - argc - 1);
+    s.heap.stack.push(result);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    use compiler;
+    use interp;
+    use value::{self, Tags, Value};
+
+    fn run(source: &str) -> Result<Value, String> {
+        interp::run(&mut interp::new(), source)
+    }
+
+    /// What a program whose value is `b` runs to.
+    fn boolean(b: bool) -> Result<Value, String> {
+        Ok(Value::boolean(b))
+    }
+
+    /// Whether `source` runs, to `#t`.
+    fn truth(source: &str) -> bool {
+        run(source) == Ok(Value::new(value::TRUE))
+    }
+
+    /// An output that keeps what is written to it.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// What running `source` prints.
+    fn output(source: &str) -> Result<String, String> {
+        let mut s = interp::new();
+        let out = Output::default();
+        s.output = Box::new(out.clone());
+        try!(interp::run(&mut s, source));
+        let text = out.0.borrow().clone();
+        Ok(String::from_utf8(text).unwrap())
+    }
+
+    #[test]
+    fn nothing_is_registered_at_startup() {
+        let s = interp::new();
+        assert!(!s.builtins.is_loaded(&["rusty", "base"]));
+        assert_eq!(s.heap.symbol_table.contents.len(), 0);
+    }
+
+    #[test]
+    fn import_binds_the_whole_library() {
+        let mut s = interp::new();
+        s.builtins.import(&mut s.heap, &["rusty", "base"]).unwrap();
+        assert!(s.builtins.is_loaded(&["rusty", "base"]));
+        s.heap.intern("cons");
+        s.heap.load_global().unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().tag(), Tags::RustFunc);
+        assert!(s.builtins.import(&mut s.heap, &["rusty", "nonexistent"]).is_err());
+    }
+
+    #[test]
+    fn unbound_builtins_are_resolved_on_first_use() {
+        let mut s = interp::new();
+        s.heap.intern("car");
+        assert!(s.heap.load_global().is_err());
+        s.heap.intern("car");
+        s.builtins.resolve_global(&mut s.heap).unwrap();
+        assert!(s.builtins.is_loaded(&["rusty", "base"]));
+        s.heap.load_global().unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().tag(), Tags::RustFunc);
+    }
+
+    #[test]
+    fn natives_check_their_arity() {
+        let mut s = interp::new();
+        s.heap.intern("car");
+        s.builtins.resolve_global(&mut s.heap).unwrap();
+        s.heap.load_global().unwrap();
+        assert!(super::call_native(&mut s, 0).is_err());
+        s.heap.stack.push(value::Value::nil());
+        s.heap.stack.push(value::Value::nil());
+        s.heap.alloc_pair(1, 2).unwrap();
+        let pair = s.heap.stack.pop().unwrap();
+        s.heap.stack.truncate(1);
+        s.heap.stack.push(pair);
+        super::call_native(&mut s, 1).unwrap();
+        assert_eq!(s.heap.stack.len(), 1);
+        assert_eq!(s.heap.stack[0].get(), value::NIL);
+    }
+
+    #[test]
+    fn vector_bulk_operations_work_in_place() {
+        let source = "(define v (make-vector 5 0))
+                      (vector-fill! v 1 1 3)
+                      (vector-copy! v 2 v 0 3)
+                      (vector-map! (lambda (x) (+ x 10)) v)
+                      (vector-map! car (vector (cons 1 2)))
+                      v";
+        let v = run(source).unwrap();
+        let elements: Vec<_> = ::equal::vector_elements(&v)
+                                   .unwrap()
+                                   .iter()
+                                   .map(|x| x.as_fixnum().unwrap())
+                                   .collect();
+        assert_eq!(elements, [10, 11, 10, 11, 11]);
+        assert_eq!(run("(let ((v (vector 1 2 3))) (vector-copy! v 0 v 1) (vector-ref v 1))"),
+                   Ok(Value::new(3 << 2)));
+        assert!(run("(vector-fill! (vector 1) 0 0 2)").is_err());
+        assert!(run("(vector-copy! (vector 1) 0 (vector 1 2))").is_err());
+        assert!(run("(vector-map! cons (vector 1))").is_err());
+    }
+
+    #[test]
+    fn make_vector_checks_its_length() {
+        assert_eq!(run("(vector-length (make-vector 3))"), Ok(Value::new(3 << 2)));
+        assert!(truth("(eq? (vector-ref (make-vector 2 'a) 1) 'a)"));
+        assert!(run("(make-vector -1)").is_err());
+        assert!(run("(make-vector (expt 2 60) 0)").is_err());
+        assert!(run("(make-vector 1/2)").is_err());
+        // Past the heap limit, the vector fails before it is filled.
+        let forms = compiler::read_all(&mut "(make-vector 100000000 0)".as_bytes()
+                                                                        .bytes()
+                                                                        .peekable())
+                        .unwrap();
+        let program = compiler::compile(&forms).unwrap();
+        let mut s = interp::new();
+        interp::load(&mut s, &program).unwrap();
+        let limit = s.heap.stats().bytes_in_use + (1 << 18);
+        s.heap.set_limit(Some(limit));
+        assert!(interp::call(&mut s, 0).unwrap_err().contains("out of memory"));
+    }
+
+    #[test]
+    fn vectors_convert_copy_and_map() {
+        let fixnum = |n: usize| Ok(Value::new(n << 2));
+        assert_eq!(run("(apply + (vector->list (vector 1 2 3) 1))"), fixnum(5));
+        assert_eq!(run("(vector->list (vector 1 2) 2)"), Ok(Value::nil()));
+        assert_eq!(run("(vector-ref (list->vector '(1 2 3)) 2)"), fixnum(3));
+        assert_eq!(run("(vector-length (list->vector '()))"), fixnum(0));
+        assert_eq!(run("(let ((v (vector 1 2 3)))
+                          (let ((w (vector-copy v 1)))
+                            (vector-set! w 0 9)
+                            (+ (vector-ref v 1) (vector-length w))))"),
+                   fixnum(4));
+        assert_eq!(run("(apply + (vector->list (vector-map + (vector 1 2 3) (vector 10 20))))"),
+                   fixnum(33));
+        assert_eq!(run("(vector-ref (vector-map car (vector (cons 1 2))) 0)"), fixnum(1));
+        assert_eq!(run("(let ((n 0))
+                          (vector-for-each (lambda (x y) (set! n (+ n (* x y))))
+                                           (vector 1 2) (vector 3 4))
+                          n)"),
+                   fixnum(11));
+        assert!(run("(list->vector '(1 . 2))").is_err());
+        assert!(run("(vector-copy (vector 1) 2)").is_err());
+        assert!(run("(vector-map cons (vector 1))").is_err());
+        assert!(run("(vector-for-each 1 (vector 1))").is_err());
+    }
+
+    #[test]
+    fn vectors_are_folded_searched_appended_and_sorted() {
+        let fixnum = |n: usize| Ok(Value::new(n << 2));
+        assert_eq!(run("(vector-fold (lambda (n x y) (+ n (* x y))) 1 #(1 2) #(3 4 5))"),
+                   fixnum(12));
+        assert_eq!(run("(vector-fold + 0 #())"), fixnum(0));
+        assert_eq!(run("(vector-index (lambda (x) (< 2 x)) #(1 3 5))"), fixnum(1));
+        assert_eq!(run("(vector-index < #(3 2) #(1 1))"), Ok(Value::new(value::FALSE)));
+        let search = "(vector-binary-search #(1 3 5 7) 7 (lambda (x y) (- x y)))";
+        assert_eq!(run(search), fixnum(3));
+        assert_eq!(run("(vector-binary-search #(1 3) 2 -)"), Ok(Value::new(value::FALSE)));
+        assert_eq!(run("(vector-length (vector-append #(1) #() #(2 3)))"), fixnum(3));
+        assert_eq!(run("(vector-ref (subvector #(1 2 3) 1 3) 1)"), fixnum(3));
+        // The sort is stable, and survives comparisons that allocate.
+        let sorted = "(let ((v (vector '(3 . a) '(1 . b) '(2 . c) '(1 . d) '(0 . e))))
+                        (vector-sort! v (lambda (x y)
+                                          (make-vector 100)
+                                          (< (car x) (car y))))
+                        (equal? (map cdr (vector->list v)) '(e b d c a)))";
+        assert_eq!(run(sorted), Ok(Value::new(value::TRUE)));
+        assert_eq!(run("(let ((v (vector 2 1))) (vector-sort! v <) (vector-ref v 0))"),
+                   fixnum(1));
+        assert!(run("(subvector #(1 2) 1 3)").is_err());
+        assert!(run("(vector-append #(1) '(2))").is_err());
+        assert!(run("(vector-sort! (vector 2 1) car)").is_err());
+        assert!(run("(vector-binary-search #(1) 1 (lambda (x y) #t))").is_err());
+    }
+
+    #[test]
+    fn lists_are_sorted_stably() {
+        let sorted = "(let ((xs (list '(3 . a) '(1 . b) '(2 . c) '(1 . d))))
+                        (and (equal? (map cdr (sort xs (lambda (x y) (< (car x) (car y)))))
+                                     '(b d c a))
+                             (equal? (map cdr xs) '(a b c d))
+                             (eq? (sort! xs (lambda (x y) (< (car y) (car x)))) xs)
+                             (equal? (map cdr xs) '(a c b d))))";
+        assert_eq!(run(sorted), Ok(Value::new(value::TRUE)));
+        // Longer lists take several merge passes.
+        let long = "(let loop ((i 0) (xs '()))
+                      (if (< i 500)
+                          (loop (+ i 1) (cons i xs))
+                          (car (sort xs <))))";
+        assert_eq!(run(long), Ok(Value::new(0)));
+        assert_eq!(run("(sort '() <)"), Ok(Value::nil()));
+        assert!(run("(sort '(1 . 2) <)").is_err());
+        assert!(run("(sort '(2 1) car)").is_err());
+        for circular in &["(let ((xs (cons 1 (cons 2 '())))) (set-cdr! (cdr xs) xs) (sort xs <))",
+                          "(let ((xs (cons 1 '()))) (set-cdr! xs xs) (sort! xs <))"] {
+            assert_eq!(run(circular).map_err(|e| e.contains("not a list")), Err(true));
+        }
+    }
+
+    #[test]
+    fn strings_are_searched_split_and_joined() {
+        let string = |source: &str| ::string::as_str(&run(source).unwrap()).unwrap().to_owned();
+        assert_eq!(run("(string-contains \"héllo world\" \"o\" 5)"), Ok(Value::new(7 << 2)));
+        assert_eq!(run("(string-contains \"abc\" \"d\")"), Ok(Value::new(value::FALSE)));
+        assert_eq!(run("(string-index \"a-b_c\" \"_-\")"), Ok(Value::new(1 << 2)));
+        assert_eq!(run("(string-index \"abc\" (lambda (c) (eqv? c #\\c)))"),
+                   Ok(Value::new(2 << 2)));
+        assert_eq!(run("(string-index \"abc\" #\\b)"), Ok(Value::new(1 << 2)));
+        assert_eq!(string("(string-join (string-split \"a,,b\" \",\") \"+\")"), "a++b");
+        assert_eq!(string("(car (cdr (string-split \"a b\" \" \")))"), "b");
+        assert_eq!(string("(string-join '())"), "");
+        assert!(run("(string-contains \"abc\" \"a\" 4)").is_err());
+        assert!(run("(string-split \"abc\" \"\")").is_err());
+    }
+
+    #[test]
+    fn strings_are_sliced_compared_and_converted() {
+        let string = |source: &str| ::string::as_str(&run(source).unwrap()).unwrap().to_owned();
+        assert_eq!(string("(substring \"héllo\" 1 3)"), "él");
+        assert_eq!(string("(substring \"héllo\" 4)"), "o");
+        assert_eq!(string("(string-append \"a\" \"\" \"bc\")"), "abc");
+        assert_eq!(string("(string-append)"), "");
+        assert_eq!(run("(string=? \"a\" \"a\" \"a\")"), boolean(true));
+        assert_eq!(run("(string<? \"a\" \"b\" \"b\")"), boolean(false));
+        assert_eq!(run("(string<=? \"a\" \"b\" \"b\")"), boolean(true));
+        assert_eq!(run("(string>? \"é\" \"z\")"), boolean(true));
+        assert_eq!(run("(string>=? \"a\" \"b\")"), boolean(false));
+        assert_eq!(run("(car (cdr (string->list \"aéb\")))"), Ok(Value::character('é')));
+        assert_eq!(run("(length (string->list \"aéb\" 1 2))"), Ok(Value::new(1 << 2)));
+        assert_eq!(string("(list->string (string->list \"aéb\"))"), "aéb");
+        assert_eq!(string("(string-upcase \"straße\")"), "STRASSE");
+        assert_eq!(string("(string-downcase \"ÉA\")"), "éa");
+        assert_eq!(string("(symbol->string (string->symbol \"a b\"))"), "a b");
+        assert_eq!(run("(eq? (string->symbol \"a b\") '|a b|)"), boolean(true));
+        assert_eq!(string("(let ((s (string-append \"abcde\")))
+                             (string-copy! s 1 s 0 3)
+                             s)"),
+                   "aabce");
+        assert_eq!(string("(let ((s (string-append \"héllo\")))
+                             (string-copy! s 1 \"ÿ\")
+                             s)"),
+                   "hÿllo");
+        assert!(run("(substring \"abc\" 2 1)").is_err());
+        assert!(run("(substring \"abc\" 0 4)").is_err());
+        assert!(run("(string=? \"a\" 1)").is_err());
+        assert!(run("(list->string '(#\\a . #\\b))").is_err());
+        assert!(run("(list->string '(\"a\"))").is_err());
+        assert!(run("(symbol->string \"a\")").is_err());
+        assert!(run("(string-copy! (string-append \"abc\") 2 \"xy\")").is_err());
+        assert!(run("(string-copy! (string-append \"abc\") 0 \"é\")").is_err());
+    }
+
+    #[test]
+    fn type_predicates_tell_types_apart() {
+        assert!(truth("(string? \"abc\")"));
+        assert!(truth("(not (string? 'abc))"));
+        assert!(truth("(symbol? 'abc)"));
+        assert!(truth("(not (symbol? \"abc\"))"));
+        assert!(truth("(procedure? car)"));
+        assert!(truth("(procedure? (lambda (x) x))"));
+        assert!(truth("(not (procedure? '(lambda (x) x)))"));
+        assert!(truth("(char? #\\a)"));
+        assert!(truth("(not (char? \"a\"))"));
+        assert!(truth("(not (string? #\\a))"));
+        assert!(truth("(not (char? 1))"));
+    }
+
+    #[test]
+    fn only_false_is_false() {
+        for &falsy in &["#f", "(not 1)", "(= 1 2)", "(string? 1)"] {
+            assert_eq!(run(falsy), boolean(false), "{}", falsy);
+            assert_eq!(run(&format!("(if {} 1 2)", falsy)), Ok(Value::new(2 << 2)), "{}", falsy);
+        }
+        for &truthy in &["0", "'()", "\"\"", "(vector)", "'a", "car"] {
+            assert_eq!(run(&format!("(not {})", truthy)), boolean(false), "{}", truthy);
+            assert_eq!(run(&format!("(if {} 1 2)", truthy)), Ok(Value::new(1 << 2)), "{}", truthy);
+            assert_eq!(run(&format!("(let ((x {})) (if x 1 2))", truthy)),
+                       Ok(Value::new(1 << 2)),
+                       "{}",
+                       truthy);
+        }
+        assert_eq!(run("(not #f)"), boolean(true));
+        assert_eq!(run("(equal? #f '())"), boolean(false));
+        assert_eq!(run("(vector? #t)"), boolean(false));
+        assert!(run("(vector-ref #f 0)").is_err());
+    }
+
+    #[test]
+    fn special_objects_are_recognized() {
+        assert_eq!(run("(eof-object)"), Ok(Value::eof()));
+        assert_eq!(run("(eof-object? (eof-object))"), boolean(true));
+        assert_eq!(run("(eof-object? '())"), boolean(false));
+        assert_eq!(run("(null? '())"), boolean(true));
+        assert_eq!(run("(null? (eof-object))"), boolean(false));
+        assert_eq!(run("(define x 1) (set! x 2)"), Ok(Value::unspecified()));
+        assert!(run("(cdr '(1))").unwrap().is_nil());
+    }
+
+    #[test]
+    fn symbols_have_property_lists() {
+        let fixnum = |n: usize| Ok(Value::new(n << 2));
+        assert_eq!(run("(put! 'x 'size 3) (put! 'x 'color 'red) (put! 'x 'size 4) (get 'x 'size)"),
+                   fixnum(4));
+        assert_eq!(run("(put! 'x 'a 1) (put! 'x 'b 2) (put! 'x 'c 3)
+                        (remove-property! 'x 'b)
+                        (remove-property! 'x 'c)
+                        (+ (* 10 (get 'x 'a)) (length (symbol-plist 'x)))"),
+                   fixnum(12));
+        assert_eq!(run("(get 'y 'size)"), Ok(Value::new(value::FALSE)));
+        assert_eq!(run("(get 'y 'size 0)"), fixnum(0));
+        assert_eq!(run("(put! 'y 'p 1) (get 'z 'p 2)"), fixnum(2));
+        assert!(run("(symbol-plist 'y)").unwrap().is_nil());
+        assert!(run("(put! \"y\" 'p 1)").is_err());
+    }
+
+    #[test]
+    fn parameters_are_bound_dynamically() {
+        let fixnum = |n: usize| Ok(Value::new(n << 2));
+        let source = "(define p (make-parameter 1))
+                      (define (f) (p))
+                      (+ (* 100 (parameterize ((p 2)) (+ (* 10 (f)) (parameterize ((p 3)) (f)))))
+                         (p))";
+        assert_eq!(run(source), fixnum(2301));
+        assert_eq!(run("(define p (make-parameter 1 (lambda (x) (* x 2))))
+                        (+ (* 10 (p)) (parameterize ((p 3)) (p)))"),
+                   fixnum(26));
+        // The bindings are undone when the body fails.
+        let mut s = interp::new();
+        let source = "(define p (make-parameter 1)) (parameterize ((p 2)) (error \"fails\"))";
+        assert!(interp::run(&mut s, source).is_err());
+        assert!(s.parameters.is_empty());
+        assert!(run("(parameterize ((car 1)) 2)").is_err());
+    }
+
+    #[test]
+    fn promises_are_forced_once() {
+        let fixnum = |n: usize| Ok(Value::new(n << 2));
+        assert_eq!(run("(define n 0)
+                        (define p (delay (begin (set! n (+ n 1)) n)))
+                        (+ (force p) (force p) (* 10 n))"),
+                   fixnum(12));
+        assert_eq!(run("(force (make-promise 3))"), fixnum(3));
+        assert_eq!(run("(force (delay (delay 3)))").map(|x| ::record::is_record(&x)),
+                   Ok(true));
+        assert_eq!(run("(force 4)"), fixnum(4));
+        assert_eq!(run("(define p (delay 1)) (if (eq? (make-promise p) p) (promise? p) #f)"),
+                   Ok(Value::new(value::TRUE)));
+        // A chain of `delay-force`s runs in constant space.
+        assert_eq!(run("(define (loop n) (delay-force (if (= n 0) (delay 5) (loop (- n 1)))))
+                        (force (loop 100000))"),
+                   fixnum(5));
+        // The reentrant example of R7RS: the first value computed wins.
+        assert_eq!(run("(define x 5)
+                        (define count 0)
+                        (define p (delay (begin (set! count (+ count 1))
+                                                (if (< x count) count (force p)))))
+                        (+ (* 10 (force p)) (begin (set! x 10) (force p)))"),
+                   fixnum(66));
+    }
+
+    #[test]
+    fn eval_compiles_data_in_an_environment() {
+        let fixnum = |n: usize| Ok(Value::new(n << 2));
+        assert_eq!(run("(eval (list '+ 1 2) (interaction-environment))"), fixnum(3));
+        assert_eq!(run("(eval '(define x 5) (interaction-environment)) (+ x 1)"), fixnum(6));
+        // Imports in the interaction environment last.
+        assert_eq!(run("(eval '(import (prefix (rusty base) b:)) (interaction-environment))
+                        (eval '(b:car '(7)) (interaction-environment))"),
+                   fixnum(7));
+        assert_eq!(run("(eval '(r:car '(1 2)) (environment '(prefix (rusty base) r:)))"),
+                   fixnum(1));
+        assert!(run("(environment '(no such library))").is_err());
+        assert!(run("(eval 1 2)").is_err());
+        assert!(run("(eval (list car) (interaction-environment))").is_err());
+    }
+
+    #[test]
+    fn sandboxes_only_reach_their_bindings() {
+        let fixnum = |n: usize| Ok(Value::new(n << 2));
+        let sandbox = |source: &str| {
+            run(&format!("(define env (make-environment '(car cons +))) {}", source))
+        };
+        assert_eq!(sandbox("(eval '(+ 1 (car (cons 2 '()))) env)"), fixnum(3));
+        assert_eq!(sandbox("(eval '(define (f x) (+ x 1)) env) (eval '(f 2) env)"), fixnum(3));
+        // The sandbox's definitions do not replace the globals it was given.
+        assert_eq!(sandbox("(eval '(define car 5) env) (+ (car '(4)) (eval 'car env))"),
+                   fixnum(9));
+        for source in &["(load \"x.scm\")",
+                        "(cdr '(1))",
+                        "(set! car 1)",
+                        "(include \"x.scm\")",
+                        "(import (rusty load))",
+                        "(define-macro (m) 1)",
+                        "(lambda () %parameterize)"] {
+            assert!(sandbox(&format!("(eval '{} env)", source)).is_err(), "{}", source);
+        }
+        assert!(run("(make-environment '(1))").is_err());
+    }
+
+    #[test]
+    fn characters_are_classified_and_converted() {
+        assert_eq!(run("(char-alphabetic? #\\a)"), boolean(true));
+        assert_eq!(run("(char-alphabetic? #\\1)"), boolean(false));
+        assert_eq!(run("(char-numeric? #\\7)"), boolean(true));
+        assert_eq!(run("(char-whitespace? #\\tab)"), boolean(true));
+        assert_eq!(run("(char-whitespace? #\\x)"), boolean(false));
+        assert_eq!(run("(char-upcase #\\a)"), Ok(Value::character('A')));
+        assert_eq!(run("(char-downcase #\\Q)"), Ok(Value::character('q')));
+        assert_eq!(run("(char-upcase #\\ß)"), Ok(Value::character('ß')));
+        assert_eq!(run("(char->integer #\\é)"), Ok(Value::new(0xe9 << 2)));
+        assert_eq!(run("(integer->char 955)"), Ok(Value::character('λ')));
+        assert_eq!(run("(char-alphabetic? #\\λ)"), boolean(cfg!(feature = "unicode-chars")));
+        assert!(truth("(eqv? (integer->char 97) #\\a)"));
+        assert!(truth("(eq? (char-upcase #\\a) #\\x41)"));
+        assert!(run("(integer->char #xD800)").is_err());
+        assert!(run("(integer->char #x110000)").is_err());
+        assert!(run("(char-upcase \"a\")").is_err());
+        assert!(run("(char->integer 1)").is_err());
+    }
+
+    #[test]
+    fn numbers_are_converted_to_and_from_strings() {
+        let string = |source: &str| ::string::as_str(&run(source).unwrap()).unwrap().to_owned();
+        let fixnum = |n: isize| Ok(Value::new((n << 2) as usize));
+        assert_eq!(string("(number->string 255 16)"), "ff");
+        assert_eq!(string("(number->string -5 2)"), "-101");
+        assert_eq!(string("(number->string #o777)"), "511");
+        assert_eq!(run("(string->number \"-ff\" 16)"), fixnum(-255));
+        assert_eq!(run("(string->number \"#b101\" 10)"), fixnum(5));
+        assert_eq!(run("(string->number \"#e1e2\")"), fixnum(100));
+        assert_eq!(run("(+ #xA #d1 #b1 #e-3)"), fixnum(9));
+        assert_eq!(run("(string->number \"12a\")"), Ok(Value::new(value::FALSE)));
+        assert_eq!(string("(number->string -1.5)"), "-1.5");
+        assert_eq!(string("(number->string -3/4)"), "-3/4");
+        assert_eq!(string("(number->string 255/16 16)"), "ff/10");
+        assert!(truth("(eqv? (string->number \"-6/8\") -3/4)"));
+        assert!(truth("(eqv? (string->number \"#e1.25\") 5/4)"));
+        assert_eq!(run("(eqv? (string->number \"1e3\") 1000.0)"), Ok(Value::new(value::TRUE)));
+        assert!(run("(number->string 1.5 2)").is_err());
+        assert!(run("(string->number \"99999999999999999999999\")").is_err());
+        assert!(run("(number->string 1 3)").is_err());
+        assert!(run("(number->string \"1\")").is_err());
+    }
+
+    #[test]
+    fn arithmetic_and_comparisons_are_variadic_procedures() {
+        let fixnum = |n: isize| Ok(Value::new((n << 2) as usize));
+        assert_eq!(run("(+ 1 2 3)"), fixnum(6));
+        assert_eq!(run("(+)"), fixnum(0));
+        assert_eq!(run("(*)"), fixnum(1));
+        assert_eq!(run("(apply * '(2 3 4))"), fixnum(24));
+        assert_eq!(run("(- 7)"), fixnum(-7));
+        assert_eq!(run("(- 10 1 2)"), fixnum(7));
+        assert_eq!(run("(/ 12 2 3)"), fixnum(2));
+        assert!(truth("(and (= (/ 2.0) 0.5) (eqv? (+ 1 2 0.5) 3.5))"));
+        assert!(truth("(equal? (map + '(1 2) '(10 20)) '(11 22))"));
+        assert!(truth("(and (< 1 2 3) (not (< 1 3 2)) (> 3 2 1) (= 1 1.0 1))"));
+        assert!(truth("(and (<= 1 1 2) (>= 2 2 1) (not (<= 2 1)))"));
+        assert!(truth("(let ((nan (- (/ 1 0.0) (/ 1 0.0)))) (not (or (<= nan 1) (>= nan 1))))"));
+        assert!(truth("(let ((f <)) (and (f 1) (f 1 2)))"));
+        assert!(run("(+ 1 2 'a)").is_err());
+        assert!(run("(< 1 0 'a)").is_err());
+        assert!(run("(> 'a)").is_err());
+        assert!(run("(/ 1 0)").is_err());
+        assert!(run("(-)").is_err());
+    }
+
+    #[test]
+    fn integer_arithmetic_has_the_right_signs() {
+        let fixnum = |n: isize| Ok(Value::new((n << 2) as usize));
+        assert_eq!(run("(abs -7)"), fixnum(7));
+        assert_eq!(run("(min 3 -1 2)"), fixnum(-1));
+        assert_eq!(run("(max 3 -1 2)"), fixnum(3));
+        assert_eq!(run("(quotient -7 2)"), fixnum(-3));
+        assert_eq!(run("(remainder -7 2)"), fixnum(-1));
+        assert_eq!(run("(modulo -7 2)"), fixnum(1));
+        assert_eq!(run("(modulo 7 -2)"), fixnum(-1));
+        assert_eq!(run("(gcd 12 -18 8)"), fixnum(2));
+        assert_eq!(run("(gcd)"), fixnum(0));
+        assert_eq!(run("(lcm 4 -6)"), fixnum(12));
+        assert_eq!(run("(lcm 4 0)"), fixnum(0));
+        assert_eq!(run("(lcm)"), fixnum(1));
+        assert_eq!(run("(expt -3 3)"), fixnum(-27));
+        assert!(truth("(and (eqv? (expt 2 -2) 1/4) (eqv? (expt -2/3 3) -8/27))"));
+        assert!(truth("(and (eqv? (/ 6 4) 3/2) (eqv? (/ 3) 1/3) (eqv? (- 1/2 3/2) -1))"));
+        assert!(truth("(equal? (list (abs -1/2) (min 1/2 1/3) (max 1/3 1)) '(1/2 1/3 1))"));
+        assert!(truth("(and (< 1/3 1/2 1) (= 2/4 1/2) (eqv? (max 1/2 0.25) 0.5))"));
+        assert!(run("(quotient 1/2 1)").is_err());
+        // Through the `Power` opcode, and as an argument.
+        assert_eq!(run("(let ((e expt)) (+ (expt 2 10) (e 1 -5)))"), fixnum(1025));
+        assert!(run("(abs (- (- 0 (expt 2 60)) (expt 2 60)))").is_err());
+        assert!(run("(quotient 1 0)").is_err());
+        assert!(run("(expt 0 -1)").is_err());
+        assert!(run("(expt 2 100)").is_err());
+        assert!(run("(min 1 'a)").is_err());
+    }
+
+    #[test]
+    fn inexact_numbers_are_rounded_and_have_transcendental_functions() {
+        let fixnum = |n: isize| Ok(Value::new((n << 2) as usize));
+        assert!(truth("(eqv? (+ 1 0.5) 1.5)"));
+        assert!(truth("(and (inexact? (* 2 1.0)) (exact? (* 2 1)) (integer? 2.0))"));
+        assert!(truth("(not (or (integer? 2.5) (exact-integer? 2.0) (eqv? 0.0 -0.0)))"));
+        assert!(truth("(and (= 0.0 -0.0) (equal? '(1.5 #(2.0)) (list 1.5 (vector 2.0))))"));
+        assert!(truth("(equal? (list (max 1 2.0) (min 1 2.0) (abs -0.5)) '(2.0 1.0 0.5))"));
+        assert!(truth("(and (eqv? (inexact 3) 3.0) (eqv? (exact->inexact 1.5) 1.5))"));
+        assert!(truth("(and (real? 1.5) (complex? 1) (rational? 0.5) (rational? 2))"));
+        assert!(truth("(not (or (rational? (/ 1 0.0)) (real? 'a) (number? \"1\")))"));
+        assert!(truth("(equal? (list (exact 2.0) (inexact->exact -3.0)) (list 2 -3))"));
+        assert!(truth("(and (eqv? (exact 1.5) 3/2) (eqv? (inexact->exact -0.5) (/ -1 2)))"));
+        assert!(truth("(= (exact 0.1) 3602879701896397/36028797018963968)"));
+        assert!(truth("(and (exact? 1/2) (not (inexact? 1/2)) (rational? 1/2))"));
+        assert!(truth("(eqv? (inexact 1/4) 0.25)"));
+        // The value of the second as an `f64` is an integer.
+        assert!(truth("(not (or (integer? 1/2) (integer? 2305843009213693951/2)))"));
+        assert!(truth("(equal? (list (numerator -6/4) (denominator -6/4)) '(-3 2))"));
+        assert!(run("(exact 1e-300)").is_err());
+        assert!(truth("(equal? (list (numerator 0.5) (denominator 0.5)) '(1.0 2.0))"));
+        assert!(truth("(equal? (list (numerator -0.1) (denominator -0.1))
+                               (list -3602879701896397.0 36028797018963968.0))"));
+        assert!(truth("(equal? (list (numerator 6) (denominator 6) (numerator 3.0)) '(6 1 3.0))"));
+        assert!(truth("(= (/ (numerator 1e-280) (denominator 1e-280)) 1e-280)"));
+        assert!(run("(denominator 5e-324)").is_err());
+        assert!(run("(numerator (/ 1 0.0))").is_err());
+        for &source in &["(car 1.5)",
+                         "(vector-ref (vector 1) 0.0)",
+                         "(vector-ref (vector 1) (lambda () 0))",
+                         "(substring \"abc\" 1.0)",
+                         "(make-vector 1.0)",
+                         "(symbol->string 1.5)"] {
+            assert!(run(source).is_err(), "{}", source);
+        }
+        assert!(truth("(equal? (map floor '(-1.5 1.5 2)) '(-2.0 1.0 2))"));
+        assert!(truth("(equal? (map ceiling '(-1.5 1.2)) '(-1.0 2.0))"));
+        assert!(truth("(equal? (map truncate '(-1.7 1.7)) '(-1.0 1.0))"));
+        assert!(truth("(equal? (map round '(0.5 1.5 2.5 -2.5 2.6 7)) '(0.0 2.0 2.0 -2.0 3.0 7))"));
+        assert!(truth("(equal? (map round '(1/2 3/2 5/2 -5/2 -7/3 8/3)) '(0 2 2 -2 -2 3))"));
+        assert!(truth("(equal? (list (floor -7/2) (ceiling -7/2) (truncate -7/2)) '(-4 -3 -3))"));
+        assert!(truth("(equal? (list (floor 7/2) (ceiling 7/2) (truncate 7/2)) '(3 4 3))"));
+        assert_eq!(run("(sqrt 16)"), fixnum(4));
+        assert!(truth("(and (eqv? (sqrt 9/4) 3/2) (inexact? (sqrt 1/2)) (nan? (sqrt -1/4)))"));
+        assert!(truth("(and (= (sqrt 2.25) 1.5) (inexact? (sqrt 15)) (nan? (sqrt -1)))"));
+        assert!(truth("(and (= (exp 0) 1) (< (abs (- (log 8 2) 3)) 1e-12) (= (log 1) 0))"));
+        assert!(truth("(and (= (sin 0) 0) (= (cos 0) 1) (= (atan 1 0) (/ (acos -1) 2)))"));
+        assert!(truth("(and (< (abs (- (tan (atan 2)) 2)) 1e-12) (= (asin 1) (atan 1 0)))"));
+        assert!(truth("(and (infinite? (/ 1 0.0)) (finite? 1) (nan? (- (/ 1 0.0) (/ 1 0.0))))"));
+    }
+
+    #[test]
+    fn floor_and_truncate_division_return_two_values() {
+        assert!(truth("(equal? (call-with-values (lambda () (floor/ -7 2)) list) '(-4 1))"));
+        assert!(truth("(equal? (call-with-values (lambda () (truncate/ -7 2)) list) '(-3 -1))"));
+        assert!(truth("(equal? (call-with-values (lambda () (floor/ 7.0 -2)) list)
+                                '(-4.0 -1.0))"));
+        assert!(truth("(equal? (list (floor-quotient 7 -2) (truncate-remainder 7 -2)) '(-4 1))"));
+        assert!(truth("(null? (call-with-values values list))"));
+        assert!(truth("(equal? (call-with-values (lambda () (values 1 2 3)) list) '(1 2 3))"));
+        assert!(truth("(= (call-with-values (lambda () 5) (lambda (x) (* x 2))) (values 10))"));
+        assert!(run("(floor/ 1 0)").is_err());
+        assert!(run("(truncate/ 1.5 1)").is_err());
+    }
+
+    #[test]
+    fn random_sources_are_seedable_and_uniform() {
+        // The first outputs of xoshiro256** seeded by splitmix64 from 0.
+        assert!(truth("(let ((a (make-random-source)))
+                         (equal? (list (random-source-integer a 1000000)
+                                       (random-source-integer a 1000000)
+                                       (random-source-integer a 1000000))
+                                 '(66420 335082 508768)))"));
+        assert!(truth("(= (random-integer 1000000) 66420)"));
+        assert!(truth("(let* ((a (make-random-source -7)) (x (random-source-integer a 100)))
+                         (random-source-seed! a -7)
+                         (= x (random-source-integer a 100)))"));
+        assert!(truth("(let loop ((i 0))
+                         (or (= i 1000)
+                             (let ((k (random-integer 3)) (x (random-real)))
+                               (and (< -1 k) (< k 3) (< 0 x) (< x 1) (loop (+ i 1))))))"));
+        assert!(truth("(let ((a (make-random-source)))
+                         (random-source-randomize! a)
+                         (and (random-source? a) (not (random-source? 5))
+                              (exact-integer? (random-entropy))
+                              (not (negative? (random-entropy)))))"));
+        assert!(run("(random-integer 0)").is_err());
+        assert!(run("(random-integer 1.5)").is_err());
+        assert!(run("(random-source-real 5)").is_err());
+        assert!(run("(make-random-source 'a)").is_err());
+    }
+
+    #[test]
+    fn clocks_tick_and_time_returns_the_values_it_times() {
+        assert!(truth("(= (jiffies-per-second) 1000000)"));
+        assert!(truth("(let* ((a (current-jiffy)) (b (current-jiffy)))
+                         (and (exact-integer? a) (not (< b a))))"));
+        // After 2017, and before 2500.
+        assert!(truth("(let ((now (current-second))) (and (< 1.5e9 now) (< now 1.6e10)))"));
+        assert!(truth("(equal? (time (list 1 2)) '(1 2))"));
+        assert!(truth("(equal? (call-with-values (lambda () (time (values 1 2))) list) '(1 2))"));
+        assert!(truth("(let ((time (lambda (x) (* x 2)))) (= (time 3) 6))"));
+        assert!(run("(time (car '()))").is_err());
+    }
+
+    #[test]
+    fn the_command_line_and_environment_variables_are_strings() {
+        ::std::env::set_var("RUSTY_SCHEME_TEST_VARIABLE", "a value");
+        assert!(truth("(string? (car (command-line)))"));
+        assert!(truth(&format!("(= (length (command-line)) {})", ::std::env::args().count())));
+        assert!(truth("(equal? (get-environment-variable \"RUSTY_SCHEME_TEST_VARIABLE\")
+                               \"a value\")"));
+        assert!(truth("(not (get-environment-variable \"RUSTY_SCHEME_NO_SUCH_VARIABLE\"))"));
+        assert!(truth("(equal? (assoc \"RUSTY_SCHEME_TEST_VARIABLE\" (get-environment-variables))
+                               '(\"RUSTY_SCHEME_TEST_VARIABLE\" . \"a value\"))"));
+        assert!(run("(get-environment-variable 'path)").is_err());
+        assert!(run("(exit 'later)").is_err());
+        assert!(run("(exit 4294967296)").is_err());
+    }
+
+    #[test]
+    fn mapped_files_are_read_without_copying_them() {
+        use std::fs::{self, File};
+        use std::io::Write;
+
+        let name = format!("rusty-scheme-mmap-{}", ::std::process::id());
+        let path = ::std::env::temp_dir().join(name);
+        File::create(&path).unwrap().write_all(&[1, 2, 255]).unwrap();
+        let map = |copy_on_write: &str, body: &str| {
+            run(&format!("(define b (mmap-file \"{}\" {})) {}",
+                         path.display(), copy_on_write, body))
+        };
+        assert_eq!(map("#f", "(bytevector-length b)"), Ok(Value::new(3 << 2)));
+        assert_eq!(map("#f", "(bytevector-u8-ref b 2)"), Ok(Value::new(255 << 2)));
+        assert!(map("#f", "(bytevector-u8-set! b 0 7)").is_err());
+        assert!(map("#f", "(bytevector-u8-ref b 3)").is_err());
+        assert_eq!(map("#t", "(bytevector-u8-set! b 0 7) (bytevector-u8-ref b 0)"),
+                   Ok(Value::new(7 << 2)));
+        assert_eq!(fs::read(&path).unwrap(), [1, 2, 255]);
+        assert_eq!(run("(bytevector? (vector))"), Ok(Value::new(value::FALSE)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn processes_are_talked_to_through_pipes() {
+        assert!(truth("(define p (run-process \"cat\" '()))
+                       (process-write p \"hello\\nworld\\n\")
+                       (process-close-input p)
+                       (and (process? p)
+                            (equal? (process-read-line p) \"hello\")
+                            (equal? (process-read p) \"world\\n\")
+                            (eof-object? (process-read-line p))
+                            (eqv? (process-wait p) 0)
+                            (eqv? (process-status p) 0))"));
+        assert!(truth("(eqv? (process-wait (run-process \"sh\" '(\"-c\" \"exit 3\"))) 3)"));
+        assert!(truth("(eq? (process-wait (run-process \"sh\" '(\"-c\" \"kill $$\"))) 'killed)"));
+        assert!(truth("(not (process-status (run-process \"cat\" '())))"));
+        assert!(run("(run-process \"rusty-scheme-no-such-program\" '())").is_err());
+        assert!(run("(run-process \"cat\" '(1))").is_err());
+        assert!(run("(process-wait 'p)").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn ports_are_polled_for_readiness() {
+        assert!(truth("(define p (run-process \"cat\" '()))
+                       (define q (run-process \"cat\" '()))
+                       (and (not (port-ready? p))
+                            (null? (wait-for-ports (list p q) 0))
+                            (begin (process-write q \"x\\n\") #t)
+                            (equal? (wait-for-ports (list p q) 10) (list q))
+                            (port-ready? q)
+                            (equal? (process-read-line q) \"x\")
+                            (not (port-ready? q))
+                            (begin (process-close-input p) #t)
+                            (equal? (wait-for-ports (list p) 10) (list p))
+                            (eof-object? (process-read p)))"));
+        assert!(run("(wait-for-ports '(1) 0)").is_err());
+        assert!(run("(wait-for-ports '() 'soon)").is_err());
+    }
+
+    #[test]
+    fn scripts_can_list_rename_and_delete_files() {
+        use std::fs::{self, File};
+        use std::io::Write;
+
+        let name = format!("rusty-scheme-files-{}", ::std::process::id());
+        let dir = ::std::env::temp_dir().join(name);
+        let in_dir = |body: &str| truth(&format!("(define dir \"{}\") {}", dir.display(), body));
+        assert!(in_dir("(not (file-exists? dir))"));
+        assert!(in_dir("(create-directory dir) (file-exists? dir)"));
+        assert!(run(&format!("(create-directory \"{}\")", dir.display())).is_err());
+        File::create(dir.join("b")).unwrap().write_all(b"four").unwrap();
+        File::create(dir.join("a")).unwrap();
+        assert!(in_dir("(equal? (directory-files dir) '(\"a\" \"b\"))"));
+        assert!(in_dir("(= (file-size (string-append dir \"/b\")) 4)"));
+        assert!(in_dir("(rename-file (string-append dir \"/b\") (string-append dir \"/c\"))
+                       (equal? (directory-files dir) '(\"a\" \"c\"))"));
+        assert!(in_dir("(delete-file (string-append dir \"/a\"))
+                       (equal? (directory-files dir) '(\"c\"))"));
+        let missing = run(&format!("(delete-file \"{}/a\")", dir.display())).unwrap_err();
+        assert!(missing.starts_with("delete-file: ") && missing.contains("os error"));
+        assert!(run("(file-size 'a)").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn green_threads_take_turns() {
+        assert!(truth("(define log '())
+                       (define (worker name)
+                         (lambda ()
+                           (let loop ((i 0))
+                             (if (< i 2)
+                                 (begin (set! log (cons name log)) (yield) (loop (+ i 1)))))))
+                       (spawn (worker 'a))
+                       (spawn (worker 'b))
+                       (run-threads)
+                       (equal? log '(b a b a))"));
+        // A thread that never yields is still paused when its slice runs out.
+        assert!(truth("(define count 0)
+                       (define seen #f)
+                       (spawn (lambda ()
+                                (let loop () (if (< count 50000) (begin (set! count (+ count 1))
+                                                                        (loop))))))
+                       (spawn (lambda () (set! seen count)))
+                       (run-threads)
+                       (and (< seen 50000) (= count 50000))"));
+        // Inside `parameterize`, it runs on until it can be paused.
+        assert!(truth("(define p (make-parameter 1))
+                       (define seen '())
+                       (parameterize ((p 2)) (spawn (lambda () (set! seen (cons (p) seen)))))
+                       (spawn (lambda ()
+                                (parameterize ((p 3))
+                                  (let loop ((i 0)) (if (< i 50000) (loop (+ i 1))))
+                                  (set! seen (cons (p) seen)))
+                                (set! seen (cons (p) seen))))
+                       (run-threads)
+                       (yield)
+                       (and (= (p) 1) (equal? seen '(1 3 2)))"));
+        assert!(run("(spawn (lambda () (car '()))) (run-threads)").is_err());
+        assert!(run("(spawn (lambda () (run-threads))) (run-threads)").is_err());
+        assert!(run("(spawn 1)").is_err());
+    }
+
+    #[test]
+    fn write_and_display_print_through_the_printer() {
+        assert_eq!(output("(write \"a\\nb\") (display \"a\") (newline) (display '(1 \"b\" #(c)))"),
+                   Ok("\"a\\nb\"a\n(1 b #(c))".to_owned()));
+        assert_eq!(output("(define point (make-record-type 'point 'x 'y))
+                           (define-record-printer point (lambda (p) (cons 'pt (record-ref p 'x))))
+                           (write (make-record point 1 2))"),
+                   Ok("(pt . 1)".to_owned()));
+        assert!(run("(write 1 2)").is_err());
+    }
+
+    #[test]
+    fn print_length_and_depth_limit_what_the_output_procedures_print() {
+        assert_eq!(output("(set! *print-length* 2) (set! *print-depth* 2)
+                           (define x '(1 (2 (3)) \"a\" 4))
+                           (write x) (display x) (pretty-print x)"),
+                   Ok("(1 (2 ...) ...)(1 (2 ...) ...)(1 (2 ...) ...)\n".to_owned()));
+        // Depths past what the printer used to allow are honored.
+        assert_eq!(output("(set! *print-depth* 70)
+                           (write (let loop ((i 0) (x 1))
+                                    (if (< i 69) (loop (+ i 1) (cons x '())) x)))"),
+                   Ok(format!("{}1{}", "(".repeat(69), ")".repeat(69))));
+    }
+
+    #[test]
+    fn write_puts_symbols_that_would_not_read_back_between_bars() {
+        assert_eq!(output("(write '|foo bar|) (display '|foo bar|)
+                           (write (string->symbol \"a(b\"))"),
+                   Ok("|foo bar|foo bar|a(b|".to_owned()));
+        assert!(truth("(eq? (string->symbol \"foo bar\") '|foo bar|)"));
+    }
+}
@@ -0,0 +1,669 @@
+//! The `(rusty numbers)` library: the arithmetic and comparisons as
+//! procedures, numeric predicates, exactness conversions, integer
+//! arithmetic beyond the primitives, rounding, transcendental functions,
+//! and conversions between numbers and strings.
+//!
+//! A call of `+`, `-`, `*`, `<` or `=` with two arguments compiles to an
+//! instruction (see `compiler::syntax`).  The procedures here are for the
+//! other calls, and for passing the operations as values, as in
+//! `(apply + xs)` or `(sort xs <)`.  They take any number of arguments, as
+//! R7RS says: `(- z)` is the negation of `z`, and `(/ z)` its reciprocal.
+//!
+//! Numbers are fixnums and ratios, which are exact, and flonums, which are
+//! inexact (see `arith`).  Every number is real, since there are no complex
+//! numbers, and every finite one is rational.  `exact` converts a flonum to
+//! the ratio it is exactly equal to, so `(exact 0.1)` is
+//! `3602879701896397/36028797018963968`.  `numerator` and `denominator`
+//! take that ratio apart, in lowest terms, but as flonums, so
+//! `(numerator 0.75)` is `3.0` and `(denominator 0.75)` is `4.0`.  `min`
+//! and `max` return an inexact number if any argument is inexact, as R7RS
+//! requires.  A result whose parts are not fixnums, such as `(abs n)` of
+//! the most negative fixnum, or `(exact 1e-300)`, is an error.
+//!
+//! `round` rounds to even, so `(round 2.5)` is `2.0` and `(round 5/2)` is
+//! `2`.  `sqrt` is exact for an exact number whose numerator and
+//! denominator are perfect squares; otherwise it, like the other
+//! transcendental functions, returns a flonum.  There are no complex
+//! numbers, so outside their real domains, as in `(sqrt -1)` or
+//! `(asin 2)`, they return a NaN, as IEEE 754 does.  `floor/` and
+//! `truncate/` return two values, so they are in the prelude, on top of
+//! `floor-quotient` and the others here.
+//!
+//! `string->number` reads numbers as the reader does (see `arith::parse`),
+//! with the same prefixes, but fails on a number that is too large for the
+//! parts of a fixnum or ratio rather than returning `#f` for it, which is
+//! for strings that are not numbers at all.
+
+use std::f64;
+
+use alloc::Heap;
+use api::SchemeValue;
+use arith::{self, Parsed};
+use flonum;
+use interp::State;
+use ratio;
+use string;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 54] = [
+    Native { name: "+", arity: Arity::AtLeast(0), function: add },
+    Native { name: "-", arity: Arity::AtLeast(1), function: subtract },
+    Native { name: "*", arity: Arity::AtLeast(0), function: multiply },
+    Native { name: "/", arity: Arity::AtLeast(1), function: divide },
+    Native { name: "=", arity: Arity::AtLeast(1), function: num_equal },
+    Native { name: "<", arity: Arity::AtLeast(1), function: less },
+    Native { name: ">", arity: Arity::AtLeast(1), function: greater },
+    Native { name: "<=", arity: Arity::AtLeast(1), function: less_or_equal },
+    Native { name: ">=", arity: Arity::AtLeast(1), function: greater_or_equal },
+    Native { name: "number?", arity: Arity::Exactly(1), function: is_number },
+    Native { name: "complex?", arity: Arity::Exactly(1), function: is_number },
+    Native { name: "real?", arity: Arity::Exactly(1), function: is_number },
+    Native { name: "rational?", arity: Arity::Exactly(1), function: is_rational },
+    Native { name: "integer?", arity: Arity::Exactly(1), function: is_integer },
+    Native { name: "exact-integer?", arity: Arity::Exactly(1), function: is_exact_integer },
+    Native { name: "exact?", arity: Arity::Exactly(1), function: is_exact },
+    Native { name: "inexact?", arity: Arity::Exactly(1), function: is_inexact },
+    Native { name: "nan?", arity: Arity::Exactly(1), function: is_nan },
+    Native { name: "infinite?", arity: Arity::Exactly(1), function: is_infinite },
+    Native { name: "finite?", arity: Arity::Exactly(1), function: is_finite },
+    Native { name: "exact", arity: Arity::Exactly(1), function: exact },
+    Native { name: "inexact->exact", arity: Arity::Exactly(1), function: exact },
+    Native { name: "inexact", arity: Arity::Exactly(1), function: inexact },
+    Native { name: "exact->inexact", arity: Arity::Exactly(1), function: inexact },
+    Native { name: "numerator", arity: Arity::Exactly(1), function: numerator },
+    Native { name: "denominator", arity: Arity::Exactly(1), function: denominator },
+    Native { name: "abs", arity: Arity::Exactly(1), function: abs },
+    Native { name: "min", arity: Arity::AtLeast(1), function: min },
+    Native { name: "max", arity: Arity::AtLeast(1), function: max },
+    Native { name: "quotient", arity: Arity::Exactly(2), function: quotient },
+    Native { name: "remainder", arity: Arity::Exactly(2), function: remainder },
+    Native { name: "modulo", arity: Arity::Exactly(2), function: modulo },
+    Native { name: "floor-quotient", arity: Arity::Exactly(2), function: floor_quotient },
+    Native { name: "floor-remainder", arity: Arity::Exactly(2), function: modulo },
+    Native { name: "truncate-quotient", arity: Arity::Exactly(2), function: quotient },
+    Native { name: "truncate-remainder", arity: Arity::Exactly(2), function: remainder },
+    Native { name: "gcd", arity: Arity::AtLeast(0), function: gcd },
+    Native { name: "lcm", arity: Arity::AtLeast(0), function: lcm },
+    Native { name: "floor", arity: Arity::Exactly(1), function: floor },
+    Native { name: "ceiling", arity: Arity::Exactly(1), function: ceiling },
+    Native { name: "round", arity: Arity::Exactly(1), function: round },
+    Native { name: "truncate", arity: Arity::Exactly(1), function: truncate },
+    Native { name: "sqrt", arity: Arity::Exactly(1), function: sqrt },
+    Native { name: "exp", arity: Arity::Exactly(1), function: exp },
+    Native { name: "log", arity: Arity::Between(1, 2), function: log },
+    Native { name: "sin", arity: Arity::Exactly(1), function: sin },
+    Native { name: "cos", arity: Arity::Exactly(1), function: cos },
+    Native { name: "tan", arity: Arity::Exactly(1), function: tan },
+    Native { name: "asin", arity: Arity::Exactly(1), function: asin },
+    Native { name: "acos", arity: Arity::Exactly(1), function: acos },
+    Native { name: "atan", arity: Arity::Between(1, 2), function: atan },
+    Native { name: "expt", arity: Arity::Exactly(2), function: expt },
+    Native { name: "number->string", arity: Arity::Between(1, 2), function: number_to_string },
+    Native { name: "string->number", arity: Arity::Between(1, 2), function: string_to_number },
+];
+
+/// The argument, if it is a number.
+fn number(s: &State, argc: usize, name: &str) -> Result<Value, String> {
+    let x = args(s, argc)[0].clone();
+    if arith::real(&x).is_some() {
+        Ok(x)
+    } else {
+        Err(format!("{}: not a number", name))
+    }
+}
+
+/// The values of the arguments of `name`, which must be numbers.
+fn reals(s: &State, argc: usize, name: &str) -> Result<Vec<f64>, String> {
+    args(s, argc)
+        .iter()
+        .map(|x| arith::real(x).ok_or_else(|| format!("{}: not a number", name)))
+        .collect()
+}
+
+/// The arithmetic operation of type `op`.
+type Operation = fn(&mut Heap, &Value, &Value) -> Result<Value, String>;
+
+/// Applies `op` to `first` and each argument from the `i`th on, in turn.
+/// Each result is only kept in a local, which is safe since `op` reads its
+/// operands before it allocates.
+fn fold(s: &mut State, argc: usize, i: usize, first: Value, op: Operation)
+        -> Result<Value, String> {
+    let start = s.heap.stack.len() - argc;
+    let mut x = first;
+    for j in start + i..start + argc {
+        let y = s.heap.stack[j].clone();
+        x = try!(op(&mut s.heap, &x, &y))
+    }
+    Ok(x)
+}
+
+/// Applies `op` to the arguments, from the left: or to `identity` and the
+/// argument, if there is only one.
+fn fold_from_first(s: &mut State, argc: usize, identity: Value, op: Operation)
+                   -> Result<Value, String> {
+    if argc == 1 {
+        fold(s, argc, 0, identity, op)
+    } else {
+        let first = args(s, argc)[0].clone();
+        fold(s, argc, 1, first, op)
+    }
+}
+
+fn add(s: &mut State, argc: usize) -> Result<Value, String> {
+    fold(s, argc, 0, Value::new(0), arith::add)
+}
+
+fn multiply(s: &mut State, argc: usize) -> Result<Value, String> {
+    fold(s, argc, 0, Value::new(1 << 2), arith::multiply)
+}
+
+/// `(- z)` is the negation of `z`.
+fn subtract(s: &mut State, argc: usize) -> Result<Value, String> {
+    fold_from_first(s, argc, Value::new(0), arith::subtract)
+}
+
+/// `(/ z)` is the reciprocal of `z`.
+fn divide(s: &mut State, argc: usize) -> Result<Value, String> {
+    fold_from_first(s, argc, Value::new(1 << 2), arith::divide)
+}
+
+/// Is `test` true of each argument of `name` and the next?  Every argument
+/// must be a number, even once the answer is known.
+fn compare(s: &mut State,
+           argc: usize,
+           name: &str,
+           test: fn(&mut Heap, &Value, &Value) -> Result<bool, String>)
+           -> Result<Value, String> {
+    try!(number(s, argc, name));
+    let start = s.heap.stack.len() - argc;
+    let mut truth = true;
+    for i in start + 1..start + argc {
+        let (x, y) = (s.heap.stack[i - 1].clone(), s.heap.stack[i].clone());
+        truth = try!(test(&mut s.heap, &x, &y)) && truth
+    }
+    Ok(Value::boolean(truth))
+}
+
+/// Is `x` greater than `y`?
+fn is_greater(heap: &mut Heap, x: &Value, y: &Value) -> Result<bool, String> {
+    arith::less(heap, y, x)
+}
+
+/// Is `x` less than or equal to `y`?  This is `<` or `=`, rather than not
+/// `>`, so that it is false of a NaN.
+fn is_less_or_equal(heap: &mut Heap, x: &Value, y: &Value) -> Result<bool, String> {
+    Ok(try!(arith::less(heap, x, y)) || try!(arith::num_equal(heap, x, y)))
+}
+
+fn is_greater_or_equal(heap: &mut Heap, x: &Value, y: &Value) -> Result<bool, String> {
+    is_less_or_equal(heap, y, x)
+}
+
+fn num_equal(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare(s, argc, "=", arith::num_equal)
+}
+
+fn less(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare(s, argc, "<", arith::less)
+}
+
+fn greater(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare(s, argc, ">", is_greater)
+}
+
+fn less_or_equal(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare(s, argc, "<=", is_less_or_equal)
+}
+
+fn greater_or_equal(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare(s, argc, ">=", is_greater_or_equal)
+}
+
+fn is_number(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(arith::real(&args(s, argc)[0]).is_some()))
+}
+
+/// Every real number but the infinities and NaNs is rational, even if it is
+/// inexact.
+fn is_rational(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(arith::real(&args(s, argc)[0]).map_or(false, f64::is_finite)))
+}
+
+/// A ratio is never an integer, though its value as an `f64` may be.
+fn is_integer(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = &args(s, argc)[0];
+    Ok(Value::boolean(ratio::as_ratio(x).is_none() &&
+                      arith::real(x).map_or(false, |x| x.fract() == 0.0)))
+}
+
+fn is_exact_integer(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(args(s, argc)[0].fixnump()))
+}
+
+fn is_exact(s: &mut State, argc: usize) -> Result<Value, String> {
+    number(s, argc, "exact?").map(|x| Value::boolean(!x.flonump()))
+}
+
+fn is_inexact(s: &mut State, argc: usize) -> Result<Value, String> {
+    number(s, argc, "inexact?").map(|x| Value::boolean(x.flonump()))
+}
+
+fn is_nan(s: &mut State, argc: usize) -> Result<Value, String> {
+    reals(s, argc, "nan?").map(|x| Value::boolean(x[0].is_nan()))
+}
+
+fn is_infinite(s: &mut State, argc: usize) -> Result<Value, String> {
+    reals(s, argc, "infinite?").map(|x| Value::boolean(x[0].is_infinite()))
+}
+
+fn is_finite(s: &mut State, argc: usize) -> Result<Value, String> {
+    reals(s, argc, "finite?").map(|x| Value::boolean(x[0].is_finite()))
+}
+
+/// `(exact z)` is the exact number equal to `z`: for a flonum that is not
+/// an integer, the ratio of its mantissa to a power of two.
+fn exact(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = try!(number(s, argc, "exact"));
+    let f = match flonum::as_f64(&x) {
+        None => return Ok(x),
+        Some(f) if !f.is_finite() => {
+            return Err(format!("exact: {} has no exact equivalent", flonum::format(f)))
+        }
+        Some(f) => f,
+    };
+    // Parts beyond 2^126 do not fit in an `i128`, let alone a fixnum.
+    let limit = 2f64.powi(126);
+    match ratio(f) {
+        Some((n, d)) if n.abs() < limit && d < limit => {
+            arith::rational(&mut s.heap, n as i128, d as i128).map_err(|e| format!("exact: {}", e))
+        }
+        _ => Err("exact: fixnum overflow".to_owned()),
+    }
+}
+
+fn inexact(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = try!(number(s, argc, "inexact"));
+    if x.flonump() {
+        Ok(x)
+    } else {
+        arith::flonum(&mut s.heap, arith::real(&x).unwrap())
+    }
+}
+
+/// The numerator and denominator, in lowest terms, of the ratio that the
+/// finite flonum `x` is exactly equal to, if the denominator is a flonum.
+fn ratio(x: f64) -> Option<(f64, f64)> {
+    if x.fract() == 0.0 {
+        return Some((x, 1.0))
+    }
+    // `x` is the mantissa, with its hidden bit, over a power of two.
+    let bits = x.to_bits();
+    let exponent = (bits >> 52 & 0x7FF) as i32;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let mut scale = 1074;
+    if exponent != 0 {
+        mantissa |= 1 << 52;
+        scale = 1075 - exponent
+    }
+    let shift = mantissa.trailing_zeros() as i32;
+    mantissa >>= shift;
+    scale -= shift;
+    if scale > 1023 {
+        return None
+    }
+    let numerator = if x < 0.0 { -(mantissa as f64) } else { mantissa as f64 };
+    Some((numerator, f64::from_bits(((1023 + scale) as u64) << 52)))
+}
+
+/// The numerator (if `which` is 0) or denominator (if it is 1) of the
+/// argument of `name`.
+fn ratio_part(s: &mut State, argc: usize, name: &str, which: usize) -> Result<Value, String> {
+    let x = try!(number(s, argc, name));
+    if let Some(parts) = ratio::as_ratio(&x) {
+        return fixnum(name, Some(if which == 0 { parts.0 } else { parts.1 }))
+    }
+    let f = match flonum::as_f64(&x) {
+        None if which == 0 => return Ok(x),
+        None => return Ok(Value::new(1 << 2)),
+        Some(f) => f,
+    };
+    if !f.is_finite() {
+        return Err(format!("{}: {} is not rational", name, flonum::format(f)))
+    }
+    match ratio(f) {
+        Some(parts) => arith::flonum(&mut s.heap, if which == 0 { parts.0 } else { parts.1 }),
+        None => Err(format!("{}: the denominator of {} is too large", name, flonum::format(f))),
+    }
+}
+
+fn numerator(s: &mut State, argc: usize) -> Result<Value, String> {
+    ratio_part(s, argc, "numerator", 0)
+}
+
+fn denominator(s: &mut State, argc: usize) -> Result<Value, String> {
+    ratio_part(s, argc, "denominator", 1)
+}
+
+/// The integers that the arguments of `name` are.
+fn integers(s: &State, argc: usize, name: &str) -> Result<Vec<isize>, String> {
+    args(s, argc)
+        .iter()
+        .map(|x| {
+            if x.fixnump() {
+                Ok(x.get() as isize >> 2)
+            } else {
+                Err(format!("{}: not an exact integer", name))
+            }
+        })
+        .collect()
+}
+
+/// The fixnum `n`, if there is one and it is a fixnum.
+fn fixnum(name: &str, n: Option<isize>) -> Result<Value, String> {
+    match n.and_then(|n| n.checked_mul(4)) {
+        Some(n) => Ok(Value::new(n as usize)),
+        None => Err(format!("{}: fixnum overflow", name)),
+    }
+}
+
+fn abs(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = try!(number(s, argc, "abs"));
+    match arith::exact_parts(&x) {
+        Some((n, d)) => arith::rational(&mut s.heap, n.abs(), d).map_err(|e| format!("abs: {}", e)),
+        None => arith::flonum(&mut s.heap, arith::real(&x).unwrap().abs()),
+    }
+}
+
+/// The least argument of `name`, or the greatest if `greatest`.  It is
+/// inexact if any argument is, and a NaN if any argument is.
+fn extremum(s: &mut State, argc: usize, name: &str, greatest: bool) -> Result<Value, String> {
+    if args(s, argc).iter().all(|x| arith::exact_parts(x).is_some()) {
+        // Comparing exact numbers does not allocate, so the arguments
+        // stay where they are.
+        let xs = args(s, argc).to_vec();
+        let mut m = xs[0].clone();
+        for x in &xs[1..] {
+            let (lesser, greater) = if greatest { (&m, x) } else { (x, &m) };
+            if try!(arith::less(&mut s.heap, lesser, greater)) {
+                m = x.clone()
+            }
+        }
+        return Ok(m)
+    }
+    let xs = try!(reals(s, argc, name));
+    let x = xs.into_iter().fold(if greatest { f64::NEG_INFINITY } else { f64::INFINITY },
+                                |m, x| {
+                                    if m.is_nan() || x.is_nan() {
+                                        f64::NAN
+                                    } else if greatest {
+                                        m.max(x)
+                                    } else {
+                                        m.min(x)
+                                    }
+                                });
+    arith::flonum(&mut s.heap, x)
+}
+
+fn min(s: &mut State, argc: usize) -> Result<Value, String> {
+    extremum(s, argc, "min", false)
+}
+
+fn max(s: &mut State, argc: usize) -> Result<Value, String> {
+    extremum(s, argc, "max", true)
+}
+
+/// Calls the arithmetic operation `op`, named `name`, on the two arguments.
+fn binary(s: &mut State, argc: usize, name: &str, op: Operation) -> Result<Value, String> {
+    let (x, y) = {
+        let args = args(s, argc);
+        (args[0].clone(), args[1].clone())
+    };
+    op(&mut s.heap, &x, &y).map_err(|e| format!("{}: {}", name, e))
+}
+
+fn quotient(s: &mut State, argc: usize) -> Result<Value, String> {
+    binary(s, argc, "quotient", arith::quotient)
+}
+
+fn remainder(s: &mut State, argc: usize) -> Result<Value, String> {
+    binary(s, argc, "remainder", arith::remainder)
+}
+
+fn modulo(s: &mut State, argc: usize) -> Result<Value, String> {
+    binary(s, argc, "modulo", arith::modulo)
+}
+
+fn floor_quotient(s: &mut State, argc: usize) -> Result<Value, String> {
+    binary(s, argc, "floor-quotient", arith::floor_quotient)
+}
+
+fn expt(s: &mut State, argc: usize) -> Result<Value, String> {
+    binary(s, argc, "expt", arith::exponential)
+}
+
+/// The greatest common divisor of `a` and `b`, which is negative if
+/// Euclid's algorithm leaves it so, or `None` if it is the negation of the
+/// most negative integer.
+fn euclid(mut a: isize, mut b: isize) -> Option<isize> {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r
+    }
+    if a < 0 { a.checked_neg() } else { Some(a) }
+}
+
+/// `(gcd n ...)` is the greatest common divisor of the `n`s, which is 0
+/// if there are none, or all are 0.
+fn gcd(s: &mut State, argc: usize) -> Result<Value, String> {
+    let mut d = Some(0);
+    for n in try!(integers(s, argc, "gcd")) {
+        d = d.and_then(|d| euclid(d, n))
+    }
+    fixnum("gcd", d)
+}
+
+/// `(lcm n ...)` is the least common multiple of the `n`s, which is 1 if
+/// there are none, and 0 if any is 0.
+fn lcm(s: &mut State, argc: usize) -> Result<Value, String> {
+    let mut m = Some(1);
+    for n in try!(integers(s, argc, "lcm")) {
+        m = m.and_then(|m| {
+            if m == 0 || n == 0 {
+                return Some(0)
+            }
+            euclid(m, n).and_then(|d| (m / d).checked_mul(n)).and_then(|m| {
+                if m < 0 { m.checked_neg() } else { Some(m) }
+            })
+        })
+    }
+    fixnum("lcm", m)
+}
+
+/// Rounds the argument of `name` to an integer: with `op` if it is a
+/// flonum, and with `exact_op`, on its numerator and denominator, if it is
+/// exact.
+fn rounding(s: &mut State,
+            argc: usize,
+            name: &str,
+            op: fn(f64) -> f64,
+            exact_op: fn(i128, i128) -> i128)
+            -> Result<Value, String> {
+    let x = try!(number(s, argc, name));
+    match arith::exact_parts(&x) {
+        // A fixnum is already an integer.
+        Some((_, 1)) => Ok(x),
+        Some((n, d)) => arith::rational(&mut s.heap, exact_op(n, d), 1),
+        None => arith::flonum(&mut s.heap, op(arith::real(&x).unwrap())),
+    }
+}
+
+/// `n/d` rounded down, for a positive `d`.
+fn floor_ratio(n: i128, d: i128) -> i128 {
+    if n % d != 0 && n < 0 { n / d - 1 } else { n / d }
+}
+
+fn ceiling_ratio(n: i128, d: i128) -> i128 {
+    if n % d != 0 && n > 0 { n / d + 1 } else { n / d }
+}
+
+fn truncate_ratio(n: i128, d: i128) -> i128 {
+    n / d
+}
+
+/// `n/d` rounded to the nearest integer, or to the even one of the two
+/// nearest, for a positive `d`.
+fn round_ratio(n: i128, d: i128) -> i128 {
+    let q = floor_ratio(n, d);
+    let twice_rest = 2 * (n - q * d);
+    if twice_rest > d || twice_rest == d && q % 2 != 0 { q + 1 } else { q }
+}
+
+/// `x` rounded to the nearest integer, or to the even one of the two
+/// nearest.
+fn round_to_even(x: f64) -> f64 {
+    let r = x.round();
+    if (r - x).abs() == 0.5 {
+        // Halving a number that is halfway between two integers is exact,
+        // and leaves one that is not.
+        2.0 * (x / 2.0).round()
+    } else {
+        r
+    }
+}
+
+fn floor(s: &mut State, argc: usize) -> Result<Value, String> {
+    rounding(s, argc, "floor", f64::floor, floor_ratio)
+}
+
+fn ceiling(s: &mut State, argc: usize) -> Result<Value, String> {
+    rounding(s, argc, "ceiling", f64::ceil, ceiling_ratio)
+}
+
+fn round(s: &mut State, argc: usize) -> Result<Value, String> {
+    rounding(s, argc, "round", round_to_even, round_ratio)
+}
+
+fn truncate(s: &mut State, argc: usize) -> Result<Value, String> {
+    rounding(s, argc, "truncate", f64::trunc, truncate_ratio)
+}
+
+/// The root of `n`, if it is the square of an integer.
+fn exact_root(n: i128) -> Option<i128> {
+    // The root of a negative number is a NaN, which is not positive.
+    let root = (n as f64).sqrt();
+    if root >= 0.0 && (root.round() as i128) * (root.round() as i128) == n {
+        Some(root.round() as i128)
+    } else {
+        None
+    }
+}
+
+/// `(sqrt z)` is exact if `z` is the square of an exact number.
+fn sqrt(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = try!(number(s, argc, "sqrt"));
+    if let Some((n, d)) = arith::exact_parts(&x) {
+        if let (Some(n), Some(d)) = (exact_root(n), exact_root(d)) {
+            return arith::rational(&mut s.heap, n, d)
+        }
+    }
+    let root = arith::real(&x).unwrap().sqrt();
+    arith::flonum(&mut s.heap, root)
+}
+
+/// Applies `op` to the argument of `name`, inexactly.
+fn transcendental(s: &mut State,
+                  argc: usize,
+                  name: &str,
+                  op: fn(f64) -> f64)
+                  -> Result<Value, String> {
+    let x = try!(reals(s, argc, name))[0];
+    arith::flonum(&mut s.heap, op(x))
+}
+
+fn exp(s: &mut State, argc: usize) -> Result<Value, String> {
+    transcendental(s, argc, "exp", f64::exp)
+}
+
+/// `(log z)` is the natural logarithm of `z`, and `(log z b)` its logarithm
+/// to the base `b`.
+fn log(s: &mut State, argc: usize) -> Result<Value, String> {
+    let xs = try!(reals(s, argc, "log"));
+    let x = if xs.len() == 2 { xs[0].ln() / xs[1].ln() } else { xs[0].ln() };
+    arith::flonum(&mut s.heap, x)
+}
+
+fn sin(s: &mut State, argc: usize) -> Result<Value, String> {
+    transcendental(s, argc, "sin", f64::sin)
+}
+
+fn cos(s: &mut State, argc: usize) -> Result<Value, String> {
+    transcendental(s, argc, "cos", f64::cos)
+}
+
+fn tan(s: &mut State, argc: usize) -> Result<Value, String> {
+    transcendental(s, argc, "tan", f64::tan)
+}
+
+fn asin(s: &mut State, argc: usize) -> Result<Value, String> {
+    transcendental(s, argc, "asin", f64::asin)
+}
+
+fn acos(s: &mut State, argc: usize) -> Result<Value, String> {
+    transcendental(s, argc, "acos", f64::acos)
+}
+
+/// `(atan y)` is the arctangent of `y`, and `(atan y x)` the angle of the
+/// point `(x, y)`, between -pi and pi.
+fn atan(s: &mut State, argc: usize) -> Result<Value, String> {
+    let xs = try!(reals(s, argc, "atan"));
+    let x = if xs.len() == 2 { xs[0].atan2(xs[1]) } else { xs[0].atan() };
+    arith::flonum(&mut s.heap, x)
+}
+
+/// The radix argument of `name`, or 10 without one.
+fn radix(s: &State, argc: usize, name: &str) -> Result<u32, String> {
+    if argc < 2 {
+        return Ok(10)
+    }
+    match args(s, argc)[1].as_fixnum() {
+        Ok(radix @ 2) | Ok(radix @ 8) | Ok(radix @ 10) | Ok(radix @ 16) => Ok(radix as u32),
+        _ => Err(format!("{}: the radix must be 2, 8, 10 or 16", name)),
+    }
+}
+
+/// `(number->string z radix)` writes `z` in `radix`, which must be 10 if `z`
+/// is inexact.
+fn number_to_string(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = try!(number(s, argc, "number->string"));
+    let radix = try!(radix(s, argc, "number->string"));
+    let digits = match flonum::as_f64(&x) {
+        Some(x) if radix == 10 => flonum::format(x),
+        Some(_) => {
+            return Err("number->string: inexact numbers are only written in radix 10".to_owned())
+        }
+        None => {
+            match ratio::as_ratio(&x) {
+                Some((n, d)) => ratio::format(n, d, radix),
+                None => arith::format(x.get() as isize >> 2, radix),
+            }
+        }
+    };
+    digits.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned())
+}
+
+fn string_to_number(s: &mut State, argc: usize) -> Result<Value, String> {
+    let radix = try!(radix(s, argc, "string->number"));
+    let parsed = match string::as_str(&args(s, argc)[0]) {
+        Some(text) => arith::parse(text, radix),
+        None => return Err("string->number: not a string".to_owned()),
+    };
+    match parsed {
+        Parsed::Fixnum(n) => Ok(Value::new((n << 2) as usize)),
+        Parsed::Flonum(x) => arith::flonum(&mut s.heap, x),
+        Parsed::Bignum => Err("string->number: bignums are not supported yet".to_owned()),
+        Parsed::Ratio(n, d) => arith::rational(&mut s.heap, n as i128, d as i128),
+        Parsed::Invalid => Ok(Value::boolean(false)),
+    }
+}
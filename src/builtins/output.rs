@@ -0,0 +1,57 @@
+//! The `(rusty output)` library: `write`, `display`, `pretty-print`, and
+//! `newline`.
+//!
+//! They print to the state's output, `State::output`, which is standard
+//! output unless the host replaces it.  `write`, `display`, and
+//! `pretty-print` go through the printer (see `print`), so records print
+//! with their printers, and `*print-length*` and `*print-depth*` limit what
+//! is printed.
+
+use std::io::Write;
+
+use interp::State;
+use print;
+use value::Value;
+use super::{Arity, Native};
+
+pub static PROCEDURES: [Native; 4] = [
+    Native { name: "display", arity: Arity::Exactly(1), function: display },
+    Native { name: "newline", arity: Arity::Exactly(0), function: newline },
+    Native { name: "pretty-print", arity: Arity::Exactly(1), function: pretty_print },
+    Native { name: "write", arity: Arity::Exactly(1), function: write },
+];
+
+/// Writes `text` to the output, for the procedure `name`.
+fn output(s: &mut State, name: &str, text: &str) -> Result<Value, String> {
+    try!(s.output.write_all(text.as_bytes()).map_err(|e| format!("{}: {}", name, e)));
+    Ok(Value::unspecified())
+}
+
+/// `(write obj)` prints `obj` as the REPL does, so that it reads back, if
+/// it can.
+fn write(s: &mut State, _: usize) -> Result<Value, String> {
+    let text = try!(print::print(s, false));
+    output(s, "write", &text)
+}
+
+/// `(display obj)` prints `obj` for people to read: strings without quotes
+/// or escapes, and symbols without bars.
+fn display(s: &mut State, _: usize) -> Result<Value, String> {
+    let text = try!(print::print(s, true));
+    output(s, "display", &text)
+}
+
+/// `(pretty-print obj)` writes `obj` on a line of its own: as `write` does,
+/// followed by a newline.
+fn pretty_print(s: &mut State, _: usize) -> Result<Value, String> {
+    let text = try!(print::print(s, false));
+    try!(output(s, "pretty-print", &text));
+    newline(s, 0)
+}
+
+/// `(newline)` ends the line, and flushes the output.
+fn newline(s: &mut State, _: usize) -> Result<Value, String> {
+    try!(output(s, "newline", "\n"));
+    try!(s.output.flush().map_err(|e| format!("newline: {}", e)));
+    Ok(Value::unspecified())
+}
@@ -0,0 +1,115 @@
+//! The `(rusty parameters)` library: the procedures under parameter
+//! objects.
+//!
+//! `make-parameter`, in the prelude, makes a parameter: a procedure of no
+//! arguments whose only free variable is its cell, a record of type
+//! `parameter` holding its global value and its converter (or `#f`).
+//! `(parameterize ((param value) ...) body...)` is expanded into
+//! `(%parameterize (lambda () body...) param value ...)`, which converts the
+//! values, binds the parameters to them on `State::parameters`, the stack
+//! of dynamic bindings, while the thunk runs, and takes them off again when
+//! it returns or fails.  A parameter's value is its innermost binding
+//! there, or its global value if it has none.
+//!
+//! The stack is the only dynamic state, so anything that leaves or reenters
+//! a dynamic extent other than by returning or failing (continuations, and
+//! `dynamic-wind` with them) must save and restore it along with the
+//! control stack.  Green threads do: each has a stack of its own, which is
+//! swapped in while it runs (see `interp`).
+
+use closure;
+use interp::{self, State};
+use record;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 3] = [
+    Native { name: "%parameter-cell", arity: Arity::Exactly(2), function: parameter_cell },
+    Native { name: "%parameter-value", arity: Arity::Exactly(1), function: parameter_value },
+    Native { name: "%parameterize", arity: Arity::AtLeast(1), function: parameterize },
+];
+
+/// The name of the record type of parameter cells.
+const PARAMETER: &'static str = "parameter";
+
+/// The fields of parameter cells.
+const FIELDS: [&'static str; 2] = ["value", "converter"];
+
+/// `(%parameter-cell value converter)` makes the cell of a parameter.
+fn parameter_cell(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    try!(s.host_types.push(&mut s.heap, PARAMETER, &FIELDS));
+    for i in 0..argc {
+        let x = s.heap.stack[base - argc + i].clone();
+        s.heap.stack.push(x)
+    }
+    let res = record::make_record(&mut s.heap, base, base + 1 + FIELDS.len());
+    let cell = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    Ok(cell.unwrap())
+}
+
+/// The cell of the parameter `x`.
+fn cell_of(x: &Value) -> Result<Value, String> {
+    if closure::is_closure(x) {
+        if let Ok(cell) = closure::upvalue(x, 0) {
+            if record::is_named(&cell, PARAMETER) {
+                return Ok(cell)
+            }
+        }
+    }
+    Err("parameterize: not a parameter".to_owned())
+}
+
+/// `(%parameter-value cell)` is the value of the parameter whose cell is
+/// `cell`.
+fn parameter_value(s: &mut State, argc: usize) -> Result<Value, String> {
+    let cell = args(s, argc)[0].clone();
+    for &(root, value) in s.parameters.iter().rev() {
+        if s.heap.persistent.get(root).get() == cell.get() {
+            return Ok(s.heap.persistent.get(value))
+        }
+    }
+    record::field(&cell, "value")
+}
+
+/// `(%parameterize thunk param value ...)` calls `thunk` with each `param`
+/// bound to its `value`, converted.
+fn parameterize(s: &mut State, argc: usize) -> Result<Value, String> {
+    if argc % 2 == 0 {
+        return Err("parameterize: a parameter has no value".to_owned())
+    }
+    let base = s.heap.stack.len() - argc;
+    // Every value is converted before any parameter is bound.
+    for i in 0..argc / 2 {
+        let i = base + 1 + 2 * i;
+        let cell = try!(cell_of(&s.heap.stack[i]));
+        let converter = try!(record::field(&cell, "converter"));
+        s.heap.stack[i] = cell;
+        if converter.is_true() {
+            let x = s.heap.stack[i + 1].clone();
+            s.heap.stack.push(converter);
+            s.heap.stack.push(x);
+            try!(interp::call(s, 1));
+            let x = s.heap.stack.pop().unwrap();
+            s.heap.stack[i + 1] = x
+        }
+    }
+    let depth = s.parameters.len();
+    for i in 0..argc / 2 {
+        let i = base + 1 + 2 * i;
+        let (cell, x) = (s.heap.stack[i].clone(), s.heap.stack[i + 1].clone());
+        let binding = (s.heap.persistent.add(cell), s.heap.persistent.add(x));
+        s.parameters.push(binding)
+    }
+    let thunk = s.heap.stack[base].clone();
+    s.heap.stack.push(thunk);
+    let res = interp::call(s, 0);
+    for (cell, x) in s.parameters.split_off(depth) {
+        s.heap.persistent.release(cell);
+        s.heap.persistent.release(x)
+    }
+    try!(res);
+    Ok(s.heap.stack.pop().unwrap())
+}
@@ -0,0 +1,312 @@
+//! The `(rusty processes)` library: running other programs, and talking to
+//! them through pipes.
+//!
+//! A process is a resource (see `alloc::finalize`) owning a child process,
+//! whose standard input and output are pipes to the interpreter, and whose
+//! standard error is the interpreter's.  There are no ports yet (`IOPort`
+//! is only a placeholder), so the pipes are reached through the process
+//! itself: `process-write` writes a string to the child's input,
+//! `process-read-line` and `process-read` read its output, and
+//! `process-close-input` closes its input, so that it sees the end of it.
+//!
+//! `process-wait` closes the input too, so that a child reading it to the
+//! end cannot keep waiting for it, then waits for the child to exit.  Its
+//! status, which `process-status` also gives once the child has exited,
+//! is its exit code, or the symbol `killed` if a signal ended it.  A
+//! process that is collected before it is waited for is left running.
+//!
+//! The outputs of processes are the only ports there are, so they are what
+//! the readiness procedures poll (with `poll(2)`), so that code serving
+//! several children can wait for whichever has something to say rather
+//! than block the whole VM reading one of them.  An output is ready when
+//! reading it would not block: when it has buffered or pending data, or
+//! has been closed by the child.  `(wait-for-ports processes timeout)`
+//! waits until any of the list `processes` is ready, or `timeout` seconds
+//! have passed (forever if it is `#f`), and is the list of those that are
+//! ready, which is empty if the time ran out.
+
+extern crate libc;
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+
+use alloc;
+use api::SchemeValue;
+use arith;
+use compiler::{self, Datum};
+use interp::State;
+use string;
+use value::{self, Value};
+use super::{args, push_list, Arity, Native};
+
+pub static PROCEDURES: [Native; 10] = [
+    Native { name: "run-process", arity: Arity::Exactly(2), function: run_process },
+    Native { name: "process?", arity: Arity::Exactly(1), function: is_process },
+    Native { name: "process-write", arity: Arity::Exactly(2), function: process_write },
+    Native {
+        name: "process-close-input",
+        arity: Arity::Exactly(1),
+        function: process_close_input,
+    },
+    Native { name: "process-read-line", arity: Arity::Exactly(1), function: process_read_line },
+    Native { name: "process-read", arity: Arity::Exactly(1), function: process_read },
+    Native { name: "process-wait", arity: Arity::Exactly(1), function: process_wait },
+    Native { name: "process-status", arity: Arity::Exactly(1), function: process_status },
+    Native { name: "port-ready?", arity: Arity::Exactly(1), function: is_port_ready },
+    Native { name: "wait-for-ports", arity: Arity::Exactly(2), function: wait_for_ports },
+];
+
+/// A child process, and the ends of its pipes that are still open.
+struct Process {
+    child: RefCell<Child>,
+    input: RefCell<Option<ChildStdin>>,
+    output: RefCell<Option<BufReader<ChildStdout>>>,
+    status: RefCell<Option<ExitStatus>>,
+}
+
+fn process<'a>(name: &str, x: &'a Value) -> Result<&'a Process, String> {
+    alloc::resource::<Process>(x).ok_or_else(|| format!("{}: not a process", name))
+}
+
+fn new_string(s: &mut State, string: String) -> Result<Value, String> {
+    string.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned())
+}
+
+/// The exit status `status`, as Scheme sees it.
+fn status_value(s: &mut State, status: ExitStatus) -> Value {
+    match status.code() {
+        Some(code) => Value::new((code as isize as usize) << 2),
+        None => {
+            s.heap.intern("killed");
+            s.heap.stack.pop().unwrap()
+        }
+    }
+}
+
+/// `(run-process command arguments)` starts the program `command` with the
+/// list of strings `arguments`, and is its process.  Like a shell, it
+/// looks for `command` on the `PATH` if it has no directory.
+fn run_process(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (command, arguments) = {
+        let args = args(s, argc);
+        let command = try!(string::as_str(&args[0])
+                               .map(str::to_owned)
+                               .ok_or("run-process: the command is not a string"));
+        let arguments = try!(compiler::datum(&args[1]).ok().and_then(|x| {
+            x.as_list().and_then(|xs| {
+                xs.into_iter()
+                  .map(|x| match *x {
+                      Datum::Str(ref x) => Some(x.clone()),
+                      _ => None,
+                  })
+                  .collect::<Option<Vec<_>>>()
+            })
+        }).ok_or_else(|| "run-process: the arguments are not a list of strings".to_owned()));
+        (command, arguments)
+    };
+    let mut child = try!(Command::new(&command)
+                             .args(&arguments)
+                             .stdin(Stdio::piped())
+                             .stdout(Stdio::piped())
+                             .spawn()
+                             .map_err(|e| format!("run-process: {}: {}", command, e)));
+    let (input, output) = (child.stdin.take(), child.stdout.take().map(BufReader::new));
+    try!(s.heap.alloc_resource(Process {
+        child: RefCell::new(child),
+        input: RefCell::new(input),
+        output: RefCell::new(output),
+        status: RefCell::new(None),
+    }));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn is_process(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(alloc::resource::<Process>(&args(s, argc)[0]).is_some()))
+}
+
+/// `(process-write process string)` writes `string` to the input of
+/// `process`.
+fn process_write(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let process = try!(process("process-write", &args[0]));
+    let text = try!(string::as_str(&args[1]).ok_or("process-write: not a string"));
+    match *process.input.borrow_mut() {
+        Some(ref mut input) => {
+            try!(input.write_all(text.as_bytes())
+                      .and_then(|()| input.flush())
+                      .map_err(|e| format!("process-write: {}", e)))
+        }
+        None => return Err("process-write: the input is closed".to_owned()),
+    }
+    Ok(Value::unspecified())
+}
+
+fn process_close_input(s: &mut State, argc: usize) -> Result<Value, String> {
+    try!(process("process-close-input", &args(s, argc)[0])).input.borrow_mut().take();
+    Ok(Value::unspecified())
+}
+
+/// Reads from the output of the process that is the first of the `argc`
+/// arguments with `read`, which returns how much it read, and returns what
+/// it read, or the EOF object at the end of the output.
+fn read_output<F>(s: &mut State, name: &str, argc: usize, read: F) -> Result<Value, String>
+    where F: FnOnce(&mut BufReader<ChildStdout>, &mut String) -> ::std::io::Result<usize>
+{
+    let mut text = String::new();
+    {
+        let process = try!(process(name, &args(s, argc)[0]));
+        let mut output = process.output.borrow_mut();
+        let output = try!(output.as_mut().ok_or_else(|| format!("{}: the output is closed", name)));
+        if try!(read(output, &mut text).map_err(|e| format!("{}: {}", name, e))) == 0 {
+            return Ok(Value::eof())
+        }
+    }
+    new_string(s, text)
+}
+
+/// `(process-read-line process)` is the next line of the output of
+/// `process`, without its line ending, or the EOF object at its end.
+fn process_read_line(s: &mut State, argc: usize) -> Result<Value, String> {
+    let line = try!(read_output(s, "process-read-line", argc, |output, text| {
+        output.read_line(text)
+    }));
+    if line.is_eof() {
+        return Ok(line)
+    }
+    let text = string::as_str(&line).unwrap().to_owned();
+    if !text.ends_with('\n') {
+        return Ok(line)
+    }
+    let end = if text.ends_with("\r\n") { text.len() - 2 } else { text.len() - 1 };
+    new_string(s, text[..end].to_owned())
+}
+
+/// `(process-read process)` is the rest of the output of `process`, or
+/// the EOF object if there is none.  It waits until the child closes its
+/// output, usually by exiting.
+fn process_read(s: &mut State, argc: usize) -> Result<Value, String> {
+    read_output(s, "process-read", argc, |output, text| output.read_to_string(text))
+}
+
+/// `(process-wait process)` closes the input of `process`, waits for it to
+/// exit, and is its status.
+fn process_wait(s: &mut State, argc: usize) -> Result<Value, String> {
+    let status = {
+        let process = try!(process("process-wait", &args(s, argc)[0]));
+        process.input.borrow_mut().take();
+        let status = try!(process.child
+                                 .borrow_mut()
+                                 .wait()
+                                 .map_err(|e| format!("process-wait: {}", e)));
+        *process.status.borrow_mut() = Some(status);
+        status
+    };
+    Ok(status_value(s, status))
+}
+
+/// `(process-status process)` is the status of `process` if it has
+/// exited, or `#f` if it is still running.
+fn process_status(s: &mut State, argc: usize) -> Result<Value, String> {
+    let status = {
+        let process = try!(process("process-status", &args(s, argc)[0]));
+        let known = *process.status.borrow();
+        match known {
+            Some(status) => Some(status),
+            None => {
+                let status = try!(process.child
+                                         .borrow_mut()
+                                         .try_wait()
+                                         .map_err(|e| format!("process-status: {}", e)));
+                *process.status.borrow_mut() = status;
+                status
+            }
+        }
+    };
+    Ok(match status {
+        Some(status) => status_value(s, status),
+        None => Value::new(value::FALSE),
+    })
+}
+
+/// Which of the outputs of `processes` are ready, waiting for at most
+/// `timeout` milliseconds (forever if it is negative) for any to be.
+fn ready(name: &str, processes: &[&Process], timeout: libc::c_int) -> Result<Vec<bool>, String> {
+    let mut ready = vec![];
+    let mut fds = vec![];
+    for process in processes {
+        match *process.output.borrow() {
+            Some(ref output) => {
+                ready.push(!output.buffer().is_empty());
+                fds.push(libc::pollfd {
+                    fd: output.get_ref().as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+            }
+            None => ready.push(true),
+        }
+    }
+    // Unless one is ready, every process has an output, so `fds` is in
+    // step with `ready`.
+    if ready.iter().any(|&ready| ready) {
+        return Ok(ready)
+    }
+    let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    if n < 0 {
+        return Err(format!("{}: {}", name, io::Error::last_os_error()))
+    }
+    for (ready, fd) in ready.iter_mut().zip(&fds) {
+        *ready = fd.revents != 0
+    }
+    Ok(ready)
+}
+
+/// `(port-ready? process)` is whether reading the output of `process`
+/// would not block.
+fn is_port_ready(s: &mut State, argc: usize) -> Result<Value, String> {
+    let process = try!(process("port-ready?", &args(s, argc)[0]));
+    Ok(Value::boolean(try!(ready("port-ready?", &[process], 0))[0]))
+}
+
+/// `(wait-for-ports processes timeout)` is the list of the processes in
+/// `processes` whose outputs are ready, once any is or `timeout` has
+/// passed.
+fn wait_for_ports(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    let found = {
+        let args = args(s, argc);
+        let timeout = match (args[1].is_true(), arith::real(&args[1])) {
+            (false, _) => -1,
+            (true, Some(seconds)) if seconds >= 0.0 => {
+                (seconds * 1000.0).ceil().min(libc::c_int::max_value() as f64) as libc::c_int
+            }
+            _ => return Err("wait-for-ports: the timeout is not a number of seconds".to_owned()),
+        };
+        let mut list = args[0].clone();
+        let mut values = vec![];
+        while let (Ok(x), Ok(rest)) = (list.car(), list.cdr()) {
+            values.push(x);
+            list = rest
+        }
+        if !list.is_nil() {
+            return Err("wait-for-ports: not a list of processes".to_owned())
+        }
+        let is_ready = {
+            let processes = try!(values.iter()
+                                       .map(|x| process("wait-for-ports", x))
+                                       .collect::<Result<Vec<_>, _>>());
+            try!(ready("wait-for-ports", &processes, timeout))
+        };
+        values.into_iter()
+              .zip(is_ready)
+              .filter_map(|(x, ready)| if ready { Some(x) } else { None })
+              .collect::<Vec<_>>()
+    };
+    // Nothing has been allocated since the processes were found, so they
+    // have not moved.
+    s.heap.stack.extend(found);
+    try!(push_list(s, base));
+    Ok(s.heap.stack.pop().unwrap())
+}
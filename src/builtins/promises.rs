@@ -0,0 +1,124 @@
+//! The `(rusty promises)` library: `force`, `make-promise` and
+//! `promise?`.
+//!
+//! A promise is a record of type `promise`, whose only field is its box: a
+//! pair of whether it is done and, if it is, its value, or else the thunk
+//! that computes it.  `(delay expr)` and `(delay-force expr)` are expanded
+//! into calls of `%make-promise` (see `compiler::syntax`), as in the
+//! reference implementation of R7RS: `delay-force`'s thunk returns another
+//! promise, and forcing the outer one forces that one in its place, so a
+//! chain of them runs in constant space.  When the outer promise takes the
+//! inner one's place, the two share a box, so that whichever is forced,
+//! the other is done too.
+//!
+//! If forcing a promise forces it again from inside its thunk, the first
+//! value it gets is its value: when the outer force returns, it finds it
+//! done, and ignores what its thunk returned.
+
+use interp::{self, State};
+use record;
+use value::{self, Value};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 4] = [
+    Native { name: "force", arity: Arity::Exactly(1), function: force },
+    Native { name: "make-promise", arity: Arity::Exactly(1), function: make_promise },
+    Native { name: "promise?", arity: Arity::Exactly(1), function: is_promise },
+    Native { name: "%make-promise", arity: Arity::Exactly(2), function: new_promise },
+];
+
+/// The name of the record type of promises.
+const PROMISE: &'static str = "promise";
+
+/// The fields of promises.
+const FIELDS: [&'static str; 1] = ["box"];
+
+/// The box of `x`, if it is a promise.
+fn promise_box(x: &Value) -> Option<Value> {
+    if record::is_named(x, PROMISE) {
+        record::field(x, "box").ok()
+    } else {
+        None
+    }
+}
+
+/// `(%make-promise done value)` makes a promise, which is done if `done`
+/// is true, and whose value (or else thunk) is `value`.
+fn new_promise(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    let res = push_promise(s, base - argc, base - argc + 1);
+    let promise = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    Ok(promise.unwrap())
+}
+
+/// Pushes a promise whose box holds `s.heap.stack[done]` and
+/// `s.heap.stack[value]`.
+fn push_promise(s: &mut State, done: usize, value: usize) -> Result<(), String> {
+    let base = s.heap.stack.len();
+    try!(s.host_types.push(&mut s.heap, PROMISE, &FIELDS));
+    let done = Value::boolean(s.heap.stack[done].is_true());
+    s.heap.stack.push(done);
+    try!(s.heap.alloc_pair(base + 1, value));
+    let pair = s.heap.stack.pop().unwrap();
+    s.heap.stack[base + 1] = pair;
+    record::make_record(&mut s.heap, base, base + 2)
+}
+
+/// `(make-promise obj)` is `obj` if it is a promise, and a promise whose
+/// value is `obj` if not.
+fn make_promise(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = args(s, argc)[0].clone();
+    if promise_box(&x).is_some() {
+        return Ok(x)
+    }
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::new(value::TRUE));
+    let res = push_promise(s, base, base - 1);
+    let promise = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    Ok(promise.unwrap())
+}
+
+fn is_promise(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(promise_box(&args(s, argc)[0]).is_some()))
+}
+
+/// `(force obj)` is the value of the promise `obj`, which is computed the
+/// first time it is forced.  Anything else is its own value.
+fn force(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    loop {
+        let promise = s.heap.stack[base].clone();
+        let thunk = match promise_box(&promise) {
+            None => return Ok(promise),
+            Some(ref b) if b.car().unwrap().is_true() => return Ok(b.cdr().unwrap()),
+            Some(b) => b.cdr().unwrap(),
+        };
+        s.heap.stack.push(thunk);
+        try!(interp::call(s, 0));
+        let result = s.heap.stack.pop().unwrap();
+        // The thunk may have moved the promise, or forced it.
+        let b = promise_box(&s.heap.stack[base]).unwrap();
+        if b.car().unwrap().is_true() {
+            continue
+        }
+        match promise_box(&result) {
+            Some(inner) => {
+                let (done, x) = (inner.car().unwrap(), inner.cdr().unwrap());
+                b.set_car(done).unwrap();
+                b.set_cdr(x.clone()).unwrap();
+                s.heap.write_barrier(&b, &x);
+                record::set_field(&result, "box", b.clone()).unwrap();
+                s.heap.write_barrier(&result, &b)
+            }
+            None => {
+                b.set_car(Value::new(value::TRUE)).unwrap();
+                b.set_cdr(result.clone()).unwrap();
+                s.heap.write_barrier(&b, &result)
+            }
+        }
+    }
+}
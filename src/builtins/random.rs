@@ -0,0 +1,191 @@
+//! The `(rusty random)` library: pseudo-random numbers, as in SRFI 27.
+//!
+//! A random source is a resource (see `alloc::finalize`) holding the state
+//! of a xoshiro256** generator, which is seeded by running splitmix64 on a
+//! 64-bit seed, as its authors recommend.  It is fast and statistically
+//! good, but not cryptographically secure: anything secret should come from
+//! `random-entropy`, which reads the operating system's entropy.
+//!
+//! A new source starts from the same seed, unless it is given one, and so
+//! does the default source, which `random-integer` and `random-real` use
+//! (see the prelude).  A program that does not seed them therefore sees
+//! the same numbers every time it runs, as benchmarks want;
+//! `random-source-randomize!` seeds a source from the operating system
+//! instead.  The default source is only made when they are first called,
+//! since a source is a resource, which cannot be saved in a snapshot (see
+//! `snapshot`).
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::Read;
+use std::u64;
+
+use alloc;
+use arith;
+use interp::State;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 7] = [
+    Native { name: "make-random-source", arity: Arity::Between(0, 1), function: make_source },
+    Native { name: "random-source?", arity: Arity::Exactly(1), function: is_source },
+    Native { name: "random-source-seed!", arity: Arity::Exactly(2), function: seed_source },
+    Native {
+        name: "random-source-randomize!",
+        arity: Arity::Exactly(1),
+        function: randomize_source,
+    },
+    Native { name: "random-source-integer", arity: Arity::Exactly(2), function: source_integer },
+    Native { name: "random-source-real", arity: Arity::Exactly(1), function: source_real },
+    Native { name: "random-entropy", arity: Arity::Exactly(0), function: random_entropy },
+];
+
+/// The seed of a source that is not given one.
+const DEFAULT_SEED: u64 = 0;
+
+/// The next output of splitmix64, whose state is `x`.
+fn splitmix64(x: &mut u64) -> u64 {
+    *x = x.wrapping_add(0x9e3779b97f4a7c15);
+    let z = *x;
+    let z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A random source: the state of a xoshiro256** generator.
+struct Source {
+    state: Cell<[u64; 4]>,
+}
+
+impl Source {
+    fn new(seed: u64) -> Source {
+        let source = Source { state: Cell::new([0; 4]) };
+        source.seed(seed);
+        source
+    }
+
+    fn seed(&self, seed: u64) {
+        // splitmix64 never gives four zeros, the one state that xoshiro
+        // never leaves.
+        let mut x = seed;
+        let a = splitmix64(&mut x);
+        let b = splitmix64(&mut x);
+        let c = splitmix64(&mut x);
+        self.state.set([a, b, c, splitmix64(&mut x)])
+    }
+
+    fn next(&self) -> u64 {
+        let mut s = self.state.get();
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        self.state.set(s);
+        result
+    }
+
+    /// A uniformly distributed integer in `[0, n)`, for a positive `n`.
+    fn below(&self, n: u64) -> u64 {
+        // Outputs past the last whole multiple of `n` are rejected, so that
+        // every remainder is as likely.
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next();
+            if x < zone {
+                return x % n
+            }
+        }
+    }
+
+    /// A uniformly distributed real in `(0, 1)`.
+    fn real(&self) -> f64 {
+        loop {
+            let x = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+            if x != 0.0 {
+                return x
+            }
+        }
+    }
+}
+
+fn source<'a>(name: &str, x: &'a Value) -> Result<&'a Source, String> {
+    alloc::resource::<Source>(x).ok_or_else(|| format!("{}: not a random source", name))
+}
+
+/// The seed `x`, which can be any exact integer.
+fn seed(name: &str, x: &Value) -> Result<u64, String> {
+    if x.fixnump() {
+        Ok((x.get() as isize >> 2) as u64)
+    } else {
+        Err(format!("{}: the seed is not an exact integer", name))
+    }
+}
+
+/// 64 bits of entropy from the operating system.
+fn entropy(name: &str) -> Result<u64, String> {
+    let mut bytes = [0; 8];
+    try!(File::open("/dev/urandom")
+             .and_then(|mut file| file.read_exact(&mut bytes))
+             .map_err(|e| format!("{}: {}", name, e)));
+    Ok(bytes.iter().fold(0, |x, &byte| x << 8 | byte as u64))
+}
+
+/// `(make-random-source [seed])` is a new random source, seeded with
+/// `seed` if it is given.
+fn make_source(s: &mut State, argc: usize) -> Result<Value, String> {
+    let seed = match args(s, argc).first() {
+        Some(x) => try!(seed("make-random-source", x)),
+        None => DEFAULT_SEED,
+    };
+    try!(s.heap.alloc_resource(Source::new(seed)));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn is_source(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(alloc::resource::<Source>(&args(s, argc)[0]).is_some()))
+}
+
+/// `(random-source-seed! source seed)` restarts `source` from `seed`, so
+/// that it gives the numbers that every source seeded with it gives.
+fn seed_source(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let seed = try!(seed("random-source-seed!", &args[1]));
+    try!(source("random-source-seed!", &args[0])).seed(seed);
+    Ok(Value::unspecified())
+}
+
+fn randomize_source(s: &mut State, argc: usize) -> Result<Value, String> {
+    let seed = try!(entropy("random-source-randomize!"));
+    try!(source("random-source-randomize!", &args(s, argc)[0])).seed(seed);
+    Ok(Value::unspecified())
+}
+
+/// `(random-source-integer source n)` is a random exact integer in
+/// `[0, n)`.
+fn source_integer(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let n = args[1].get() as isize >> 2;
+    if !args[1].fixnump() || n <= 0 {
+        return Err("random-source-integer: the range is not a positive exact integer".to_owned())
+    }
+    let k = try!(source("random-source-integer", &args[0])).below(n as u64);
+    Ok(Value::new((k as usize) << 2))
+}
+
+/// `(random-source-real source)` is a random flonum strictly between 0
+/// and 1.
+fn source_real(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = try!(source("random-source-real", &args(s, argc)[0])).real();
+    arith::flonum(&mut s.heap, x)
+}
+
+/// `(random-entropy)` is a non-negative fixnum of 61 bits from the
+/// operating system's entropy.
+fn random_entropy(_: &mut State, _: usize) -> Result<Value, String> {
+    let bits = try!(entropy("random-entropy")) >> 3;
+    Ok(Value::new((bits as usize) << 2))
+}
@@ -0,0 +1,95 @@
+//! The `(rusty records)` library: record types, and field access by name.
+
+use interp::State;
+use record;
+use value::{self, Value, Tags};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 9] = [
+    Native { name: "make-record-type", arity: Arity::AtLeast(1), function: make_record_type },
+    Native { name: "make-record", arity: Arity::AtLeast(1), function: make_record },
+    Native { name: "record?", arity: Arity::Exactly(1), function: is_record },
+    Native { name: "record-type", arity: Arity::Exactly(1), function: record_type },
+    Native { name: "record-type-name", arity: Arity::Exactly(1), function: record_type_name },
+    Native { name: "record-ref", arity: Arity::Exactly(2), function: record_ref },
+    Native { name: "record-set!", arity: Arity::Exactly(3), function: record_set },
+    Native {
+        name: "define-record-printer",
+        arity: Arity::Exactly(2),
+        function: define_record_printer,
+    },
+    Native {
+        name: "register-record-type",
+        arity: Arity::Exactly(1),
+        function: register_record_type,
+    },
+];
+
+fn make_record_type(s: &mut State, argc: usize) -> Result<Value, String> {
+    let len = s.heap.stack.len();
+    try!(record::make_record_type(&mut s.heap, len - argc, len));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn make_record(s: &mut State, argc: usize) -> Result<Value, String> {
+    let len = s.heap.stack.len();
+    try!(record::make_record(&mut s.heap, len - argc, len));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn is_record(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::new(if record::is_record(&args(s, argc)[0]) {
+        value::TRUE
+    } else {
+        value::FALSE
+    }))
+}
+
+fn record_type(s: &mut State, argc: usize) -> Result<Value, String> {
+    record::record_type(&args(s, argc)[0])
+}
+
+fn record_type_name(s: &mut State, argc: usize) -> Result<Value, String> {
+    record::record_type_name(&args(s, argc)[0])
+}
+
+fn record_ref(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let offset = try!(record::lookup(&args[0], &args[1]));
+    Ok(record::get(&args[0], offset))
+}
+
+fn record_set(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (object, new) = {
+        let args = args(s, argc);
+        let offset = try!(record::lookup(&args[0], &args[1]));
+        record::set(&args[0], offset, args[2].clone());
+        (args[0].clone(), args[2].clone())
+    };
+    s.heap.write_barrier(&object, &new);
+    Ok(Value::unspecified())
+}
+
+/// `(define-record-printer rtd printer)` makes `printer` print records of
+/// type `rtd` (see `print`).  A `printer` of `#f` removes the printer.
+fn define_record_printer(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (rtd, printer) = {
+        let args = args(s, argc);
+        (args[0].clone(), args[1].clone())
+    };
+    let printer = match printer.tag() {
+        _ if printer.get() == value::FALSE => None,
+        Tags::Function | Tags::RustFunc => Some(printer),
+        _ => return Err("define-record-printer: not a procedure".to_owned()),
+    };
+    try!(s.printers.set(&s.heap, &rtd, printer));
+    Ok(Value::unspecified())
+}
+
+/// `(register-record-type rtd)` gives records of type `rtd` an external
+/// syntax, `#[name field ...]` (see `record`).
+fn register_record_type(s: &mut State, argc: usize) -> Result<Value, String> {
+    let rtd = args(s, argc)[0].clone();
+    try!(s.readable.register(&s.heap, &rtd));
+    Ok(Value::unspecified())
+}
@@ -0,0 +1,49 @@
+//! The `(rusty repl)` library: access to the REPL's history, and to the
+//! opcode reference.
+
+use api::SchemeValue;
+use bytecode::Opcode;
+use interp::State;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 2] = [
+    Native { name: "history", arity: Arity::Exactly(1), function: history },
+    Native { name: "opcode-table", arity: Arity::Exactly(0), function: opcode_table },
+];
+
+/// `(history n)` returns the `n`th most recent REPL value, counting from 1.
+fn history(s: &mut State, argc: usize) -> Result<Value, String> {
+    let n = try!(args(s, argc)[0].as_fixnum().map_err(|e| format!("history: {}", e)));
+    s.history
+     .get(&s.heap, n)
+     .ok_or_else(|| format!("history: only {} values are remembered", s.history.len()))
+}
+
+/// `(opcode-table)` returns the opcode reference (see
+/// `bytecode::opcode_table`) as a list of vectors, one per opcode, of its
+/// number, its name, the kinds of its three operands, and what it changes
+/// (all symbols), followed by its summary (a string).
+fn opcode_table(s: &mut State, _: usize) -> Result<Value, String> {
+    let opcodes: Vec<_> = (0..).map(Opcode::from_u8).take_while(Option::is_some).collect();
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    for (i, opcode) in opcodes.into_iter().enumerate().rev() {
+        let info = opcode.unwrap().info();
+        s.heap.stack.push(Value::new(i << 2));
+        s.heap.intern(&format!("{:?}", info.opcode));
+        for operand in &info.operands {
+            s.heap.intern(operand.name())
+        }
+        s.heap.intern(info.effect.name());
+        let summary = try!(info.summary.to_owned().to_value(&mut s.heap));
+        s.heap.stack.push(summary);
+        let len = s.heap.stack.len();
+        try!(s.heap.alloc_vector(base + 1, len));
+        try!(s.heap.alloc_pair(len, base));
+        let list = s.heap.stack.pop().unwrap();
+        s.heap.stack.truncate(base);
+        s.heap.stack.push(list)
+    }
+    Ok(s.heap.stack.pop().unwrap())
+}
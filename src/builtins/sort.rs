@@ -0,0 +1,105 @@
+//! The `(rusty sort)` library: sorting lists.
+//!
+//! `(sort list less?)` is a new list of the elements of `list`, sorted so
+//! that none is `less?` than the one before it, and `(sort! list less?)`
+//! sorts `list` itself, by replacing the cars of its pairs, and returns
+//! it.  Both are stable: elements that are not `less?` than each other
+//! stay in the same order.
+//!
+//! The sort is the bottom-up merge sort of `vector-sort!` (see `vectors`),
+//! which needs no recursion, so even a very long list cannot overflow the
+//! Rust stack, and which calls `less?` from Rust in a loop.  The elements
+//! are sorted on the stack, where the GC can find them if `less?`
+//! allocates.
+
+use interp::State;
+use value::Value;
+use super::{args, Arity, Native};
+use super::vectors::{merge_sort, procedure};
+
+pub static PROCEDURES: [Native; 2] = [
+    Native { name: "sort", arity: Arity::Exactly(2), function: sort },
+    Native { name: "sort!", arity: Arity::Exactly(2), function: sort_in_place },
+];
+
+/// The elements of the list `list`, which must be proper: neither
+/// improper nor circular.
+fn elements(name: &str, list: &Value) -> Result<Vec<Value>, String> {
+    let mut elements = vec![];
+    // The slow pointer catches up with a circular list.
+    let (mut x, mut slow) = (list.clone(), list.clone());
+    while !x.is_nil() {
+        match (x.car(), x.cdr()) {
+            (Ok(car), Ok(cdr)) => {
+                elements.push(car);
+                x = cdr
+            }
+            _ => return Err(format!("{}: not a list", name)),
+        }
+        if elements.len() % 2 == 0 {
+            slow = slow.cdr().unwrap();
+            if slow.get() == x.get() {
+                return Err(format!("{}: not a list", name))
+            }
+        }
+    }
+    Ok(elements)
+}
+
+/// Sorts the list argument of `name` by its `less?` argument, pushing the
+/// sorted elements, and returns where they start and how many there are.
+fn sort_elements(name: &str, s: &mut State, argc: usize) -> Result<(usize, usize), String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, elements) = {
+        let args = args(s, argc);
+        (try!(procedure(name, &args[1], 2)), try!(elements(name, &args[0])))
+    };
+    let len = elements.len();
+    // The elements, and as many slots to merge them into.
+    let work = s.heap.stack.len();
+    s.heap.stack.extend(elements.clone());
+    s.heap.stack.extend(elements);
+    match merge_sort(s, native, base + 1, work, work + len, len) {
+        Ok(sorted) => Ok((sorted, len)),
+        Err(e) => {
+            s.heap.stack.truncate(work);
+            Err(e)
+        }
+    }
+}
+
+fn sort(s: &mut State, argc: usize) -> Result<Value, String> {
+    let work = s.heap.stack.len();
+    let (sorted, len) = try!(sort_elements("sort", s, argc));
+    let list = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    for i in (sorted..sorted + len).rev() {
+        if let Err(e) = s.heap.alloc_pair(i, list) {
+            s.heap.stack.truncate(work);
+            return Err(e.into())
+        }
+        let pair = s.heap.stack.pop().unwrap();
+        s.heap.stack[list] = pair
+    }
+    let list = s.heap.stack.pop().unwrap();
+    s.heap.stack.truncate(work);
+    Ok(list)
+}
+
+fn sort_in_place(s: &mut State, argc: usize) -> Result<Value, String> {
+    let work = s.heap.stack.len();
+    let (sorted, len) = try!(sort_elements("sort!", s, argc));
+    let head = s.heap.stack[work - argc].clone();
+    let mut list = head.clone();
+    for i in sorted..sorted + len {
+        let x = s.heap.stack[i].clone();
+        // `less?` may have cut the list short.
+        if list.set_car(x.clone()).is_err() {
+            break
+        }
+        s.heap.write_barrier(&list, &x);
+        list = list.cdr().unwrap()
+    }
+    s.heap.stack.truncate(work);
+    Ok(head)
+}
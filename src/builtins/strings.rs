@@ -0,0 +1,341 @@
+//! The `(rusty strings)` library: measuring, searching, splitting,
+//! joining, comparing, and converting strings.
+//!
+//! Strings are UTF-8, and indices count characters, not bytes, so indexing
+//! is linear in the index.  Substrings are found with the standard
+//! library's searcher (the two-way algorithm), so searching is linear in
+//! the length of the string.  Strings compare by their characters' code
+//! points, and change case by Unicode's rules, which may change their
+//! length.
+//!
+//! `string-copy!` overwrites characters in place, so it can only replace
+//! them with characters of the same length in UTF-8 (as any ASCII
+//! characters are), and not change string literals shared between states
+//! (see `shared`).
+
+use std::cmp::Ordering;
+
+use api::SchemeValue;
+use interp::{self, State};
+use string;
+use value::{self, Value, Tags};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 20] = [
+    Native { name: "string?", arity: Arity::Exactly(1), function: is_string },
+    Native { name: "string-length", arity: Arity::Exactly(1), function: string_length },
+    Native { name: "string-contains", arity: Arity::Between(2, 3), function: string_contains },
+    Native { name: "string-index", arity: Arity::Between(2, 3), function: string_index },
+    Native { name: "string-split", arity: Arity::Exactly(2), function: string_split },
+    Native { name: "string-join", arity: Arity::Between(1, 2), function: string_join },
+    Native { name: "substring", arity: Arity::Between(2, 3), function: substring },
+    Native { name: "string-append", arity: Arity::AtLeast(0), function: string_append },
+    Native { name: "string=?", arity: Arity::AtLeast(1), function: string_equal },
+    Native { name: "string<?", arity: Arity::AtLeast(1), function: string_less },
+    Native { name: "string>?", arity: Arity::AtLeast(1), function: string_greater },
+    Native { name: "string<=?", arity: Arity::AtLeast(1), function: string_less_equal },
+    Native { name: "string>=?", arity: Arity::AtLeast(1), function: string_greater_equal },
+    Native { name: "string->list", arity: Arity::Between(1, 3), function: string_to_list },
+    Native { name: "list->string", arity: Arity::Exactly(1), function: list_to_string },
+    Native { name: "string-upcase", arity: Arity::Exactly(1), function: string_upcase },
+    Native { name: "string-downcase", arity: Arity::Exactly(1), function: string_downcase },
+    Native { name: "string-copy!", arity: Arity::Between(3, 5), function: string_copy },
+    Native { name: "symbol->string", arity: Arity::Exactly(1), function: symbol_to_string },
+    Native { name: "string->symbol", arity: Arity::Exactly(1), function: string_to_symbol },
+];
+
+fn string_arg<'a>(name: &str, x: &'a Value) -> Result<&'a str, String> {
+    string::as_str(x).ok_or_else(|| format!("{}: not a string", name))
+}
+
+/// The byte offset of the character at index `start` of `string`.
+fn offset(name: &str, string: &str, start: Option<&Value>) -> Result<usize, String> {
+    let start = match start {
+        Some(x) => try!(x.as_fixnum().map_err(|_| format!("{}: index is not a fixnum", name))),
+        None => return Ok(0),
+    };
+    match string.char_indices().map(|(i, _)| i).chain(Some(string.len())).nth(start) {
+        Some(offset) => Ok(offset),
+        None => Err(format!("{}: index out of bounds", name)),
+    }
+}
+
+/// The index of the character at byte offset `offset` of `string`.
+fn index(string: &str, offset: usize) -> Value {
+    Value::new(string[..offset].chars().count() << 2)
+}
+
+/// The byte offsets of the characters at the optional `start` and `end`
+/// indices in `args`, which default to the whole of `string`.
+fn range(name: &str, string: &str, args: &[Value]) -> Result<(usize, usize), String> {
+    let start = try!(offset(name, string, args.get(0)));
+    let end = match args.get(1) {
+        Some(_) => try!(offset(name, string, args.get(1))),
+        None => string.len(),
+    };
+    if start > end {
+        return Err(format!("{}: range out of bounds", name))
+    }
+    Ok((start, end))
+}
+
+fn new_string(s: &mut State, string: String) -> Result<Value, String> {
+    string.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned())
+}
+
+fn not_found() -> Value {
+    Value::new(value::FALSE)
+}
+
+fn is_string(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(string::as_str(&args(s, argc)[0]).is_some()))
+}
+
+/// `(string-length string)` is the number of characters in `string`.
+fn string_length(s: &mut State, argc: usize) -> Result<Value, String> {
+    let string = try!(string_arg("string-length", &args(s, argc)[0]));
+    Ok(Value::new(string.chars().count() << 2))
+}
+
+/// `(string-contains string pattern [start])` is the index of the first
+/// occurrence of `pattern` in `string` at or after `start`, or `#f`.
+fn string_contains(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let string = try!(string_arg("string-contains", &args[0]));
+    let pattern = try!(string_arg("string-contains", &args[1]));
+    let start = try!(offset("string-contains", string, args.get(2)));
+    Ok(match string[start..].find(pattern) {
+        Some(i) => index(string, start + i),
+        None => not_found(),
+    })
+}
+
+/// `(string-index string test [start])` is the index of the first character
+/// at or after `start` that satisfies `test`, or `#f`.  `test` is either a
+/// character, a string, which is satisfied by the characters in it, or a
+/// procedure, which is called with each character in turn.
+fn string_index(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (string, test, start) = {
+        let args = args(s, argc);
+        let string = try!(string_arg("string-index", &args[0]));
+        let start = try!(offset("string-index", string, args.get(2)));
+        (string.to_owned(), args[1].clone(), start)
+    };
+    let set = match (test.as_char(), string::as_str(&test)) {
+        (Some(c), _) => Some(c.to_string()),
+        (None, Some(set)) => Some(set.to_owned()),
+        (None, None) => None,
+    };
+    if let Some(set) = set {
+        return Ok(match string[start..].find(|c| set.contains(c)) {
+            Some(i) => index(&string, start + i),
+            None => not_found(),
+        })
+    }
+    if test.tag() != Tags::Function && test.tag() != Tags::RustFunc {
+        return Err("string-index: test is not a character, a string, or a procedure".to_owned())
+    }
+    for (i, c) in string[start..].char_indices() {
+        // Calling the test may move it.
+        let test = s.heap.stack[base + 1].clone();
+        s.heap.stack.push(test);
+        s.heap.stack.push(Value::character(c));
+        try!(interp::call(s, 1));
+        if s.heap.stack.pop().unwrap().is_true() {
+            return Ok(index(&string, start + i))
+        }
+    }
+    Ok(not_found())
+}
+
+/// Pushes `items` as a list of strings, then pops it and returns it.
+fn list_of_strings(s: &mut State, items: &[&str]) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    for item in items.iter().rev() {
+        let item = try!(new_string(s, item.to_string()));
+        s.heap.stack.push(item);
+        try!(s.heap.alloc_pair(base + 1, base));
+        s.heap.stack[base] = s.heap.stack.pop().unwrap();
+        s.heap.stack.pop();
+    }
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+/// `(string-split string delimiter)` is the list of the parts of `string`
+/// between occurrences of `delimiter`, which must not be empty.
+fn string_split(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (string, delimiter) = {
+        let args = args(s, argc);
+        (try!(string_arg("string-split", &args[0])).to_owned(),
+         try!(string_arg("string-split", &args[1])).to_owned())
+    };
+    if delimiter.is_empty() {
+        return Err("string-split: empty delimiter".to_owned())
+    }
+    let parts: Vec<_> = string.split(&*delimiter).collect();
+    list_of_strings(s, &parts)
+}
+
+/// `(string-join strings [delimiter])` concatenates the list `strings`,
+/// with `delimiter` (by default a space) between each two of them.
+fn string_join(s: &mut State, argc: usize) -> Result<Value, String> {
+    let joined = {
+        let args = args(s, argc);
+        let delimiter = match args.get(1) {
+            Some(x) => try!(string_arg("string-join", x)),
+            None => " ",
+        };
+        let mut joined = String::new();
+        let mut list = args[0].clone();
+        while !list.is_nil() {
+            let item = try!(list.car().map_err(|()| "string-join: not a list".to_owned()));
+            if list.get() != args[0].get() {
+                joined.push_str(delimiter)
+            }
+            joined.push_str(try!(string_arg("string-join", &item)));
+            list = list.cdr().unwrap()
+        }
+        joined
+    };
+    joined.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned())
+}
+
+/// `(substring string start [end])`
+fn substring(s: &mut State, argc: usize) -> Result<Value, String> {
+    let substring = {
+        let args = args(s, argc);
+        let string = try!(string_arg("substring", &args[0]));
+        let (start, end) = try!(range("substring", string, &args[1..]));
+        string[start..end].to_owned()
+    };
+    new_string(s, substring)
+}
+
+fn string_append(s: &mut State, argc: usize) -> Result<Value, String> {
+    let mut appended = String::new();
+    for x in args(s, argc) {
+        appended.push_str(try!(string_arg("string-append", x)))
+    }
+    new_string(s, appended)
+}
+
+/// Do the strings `args` compare, each to the next, as one of `orderings`?
+fn compare(name: &str, args: &[Value], orderings: &[Ordering]) -> Result<Value, String> {
+    let mut strings = Vec::with_capacity(args.len());
+    for x in args {
+        strings.push(try!(string_arg(name, x)))
+    }
+    let ordered = strings.windows(2).all(|pair| orderings.contains(&pair[0].cmp(pair[1])));
+    Ok(Value::boolean(ordered))
+}
+
+fn string_equal(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare("string=?", args(s, argc), &[Ordering::Equal])
+}
+
+fn string_less(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare("string<?", args(s, argc), &[Ordering::Less])
+}
+
+fn string_greater(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare("string>?", args(s, argc), &[Ordering::Greater])
+}
+
+fn string_less_equal(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare("string<=?", args(s, argc), &[Ordering::Less, Ordering::Equal])
+}
+
+fn string_greater_equal(s: &mut State, argc: usize) -> Result<Value, String> {
+    compare("string>=?", args(s, argc), &[Ordering::Greater, Ordering::Equal])
+}
+
+/// `(string->list string [start [end]])` is the list of the characters of
+/// `string`.
+fn string_to_list(s: &mut State, argc: usize) -> Result<Value, String> {
+    let chars: Vec<_> = {
+        let args = args(s, argc);
+        let string = try!(string_arg("string->list", &args[0]));
+        let (start, end) = try!(range("string->list", string, &args[1..]));
+        string[start..end].chars().collect()
+    };
+    let base = s.heap.stack.len();
+    s.heap.stack.push(Value::nil());
+    for &c in chars.iter().rev() {
+        s.heap.stack.push(Value::character(c));
+        try!(s.heap.alloc_pair(base + 1, base));
+        s.heap.stack[base] = s.heap.stack.pop().unwrap();
+        s.heap.stack.pop();
+    }
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+/// `(list->string list)` is the string of the characters in `list`.
+fn list_to_string(s: &mut State, argc: usize) -> Result<Value, String> {
+    let mut string = String::new();
+    let mut list = args(s, argc)[0].clone();
+    while !list.is_nil() {
+        let item = try!(list.car().map_err(|()| "list->string: not a list".to_owned()));
+        string.push(try!(item.as_char().ok_or("list->string: not a character")));
+        list = list.cdr().unwrap()
+    }
+    new_string(s, string)
+}
+
+/// `(symbol->string symbol)` is the name of `symbol`, a new string.
+fn symbol_to_string(s: &mut State, argc: usize) -> Result<Value, String> {
+    let name = match args(s, argc)[0].kind() {
+        value::Kind::Symbol(ptr) => unsafe { (*ptr).name() }.to_owned(),
+        _ => return Err("symbol->string: not a symbol".to_owned()),
+    };
+    new_string(s, name)
+}
+
+/// `(string->symbol string)` is the symbol named `string`, which `write`
+/// prints between bars if it has to (see `print`).
+fn string_to_symbol(s: &mut State, argc: usize) -> Result<Value, String> {
+    let name = try!(string_arg("string->symbol", &args(s, argc)[0])).to_owned();
+    s.heap.intern(&name);
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn string_upcase(s: &mut State, argc: usize) -> Result<Value, String> {
+    let string = try!(string_arg("string-upcase", &args(s, argc)[0])).to_uppercase();
+    new_string(s, string)
+}
+
+fn string_downcase(s: &mut State, argc: usize) -> Result<Value, String> {
+    let string = try!(string_arg("string-downcase", &args(s, argc)[0])).to_lowercase();
+    new_string(s, string)
+}
+
+/// `(string-copy! to at from [start [end]])` replaces the characters of
+/// `to` from index `at` with those of `from` from `start` to `end`.  The
+/// source and destination may overlap.
+fn string_copy(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    if s.heap.is_shared(&args[0]) {
+        return Err("string-copy!: string is immutable".to_owned())
+    }
+    let to = try!(string_arg("string-copy!", &args[0]));
+    let from = try!(string_arg("string-copy!", &args[2]));
+    let (start, end) = try!(range("string-copy!", from, &args[3..]));
+    let at = try!(offset("string-copy!", to, Some(&args[1])));
+    // Copied first, since it may be part of `to`.
+    let source = from[start..end].to_owned();
+    // The characters replaced are as many as those copied.
+    let replaced = to[at..].char_indices()
+                           .map(|(i, _)| i)
+                           .chain(Some(to.len() - at))
+                           .nth(source.chars().count());
+    let replaced = match replaced {
+        Some(replaced) => replaced,
+        None => return Err("string-copy!: destination out of bounds".to_owned()),
+    };
+    if replaced != source.len() {
+        return Err("string-copy!: characters of different lengths in UTF-8".to_owned())
+    }
+    unsafe { string::overwrite(&args[0], at, source.as_bytes()) }
+    Ok(Value::unspecified())
+}
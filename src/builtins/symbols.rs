@@ -0,0 +1,111 @@
+//! The `(rusty symbols)` library: `symbol?`, and property lists.
+//!
+//! Every symbol has a property list, as in Lisp: a list of properties
+//! (compared with `eq?`, so usually symbols) alternating with their values,
+//! such as `(color red size 3)`.  `(put! symbol property value)` sets a
+//! property, `(get symbol property [default])` looks one up, returning
+//! `default` (or `#f`) if the symbol does not have it, and
+//! `(remove-property! symbol property)` removes one.  `(symbol-plist
+//! symbol)` is the whole list, which must not be mutated.
+//!
+//! The list is kept in the symbol (see `symbol::Symbol`), not in a table,
+//! so a symbol that nothing refers to is reclaimed with its properties.
+
+use interp::State;
+use symbol::Symbol;
+use value::{self, Value};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 5] = [
+    Native { name: "symbol?", arity: Arity::Exactly(1), function: is_symbol },
+    Native { name: "put!", arity: Arity::Exactly(3), function: put },
+    Native { name: "get", arity: Arity::Between(2, 3), function: get },
+    Native { name: "remove-property!", arity: Arity::Exactly(2), function: remove_property },
+    Native { name: "symbol-plist", arity: Arity::Exactly(1), function: symbol_plist },
+];
+
+/// The symbol that is the first argument of `name`.
+fn symbol_arg(s: &State, argc: usize, name: &str) -> Result<*mut Symbol, String> {
+    match args(s, argc)[0].kind() {
+        value::Kind::Symbol(symbol) => Ok(symbol),
+        _ => Err(format!("{}: not a symbol", name)),
+    }
+}
+
+fn is_symbol(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::boolean(args(s, argc)[0].tag() == value::Tags::Symbol))
+}
+
+fn plist(symbol: *mut Symbol) -> Value {
+    unsafe { (*(*symbol).plist.get()).clone() }
+}
+
+/// The pair of the property list `list` whose car is `property`, if there
+/// is one, and the pair holding the value of the property before it, if it
+/// is not the first.
+fn find(list: Value, property: &Value) -> (Option<Value>, Option<Value>) {
+    let (mut before, mut list) = (None, list);
+    while let (Ok(key), Ok(cell)) = (list.car(), list.cdr()) {
+        if !cell.pairp() {
+            break
+        }
+        if key.get() == property.get() {
+            return (Some(list), before)
+        }
+        list = cell.cdr().unwrap();
+        before = Some(cell)
+    }
+    (None, None)
+}
+
+fn put(s: &mut State, argc: usize) -> Result<Value, String> {
+    let symbol = try!(symbol_arg(s, argc, "put!"));
+    let (property, new) = {
+        let args = args(s, argc);
+        (args[1].clone(), args[2].clone())
+    };
+    if let (Some(pair), _) = find(plist(symbol), &property) {
+        let cell = pair.cdr().unwrap();
+        cell.set_car(new.clone()).unwrap();
+        s.heap.write_barrier(&cell, &new);
+        return Ok(Value::unspecified())
+    }
+    let base = s.heap.stack.len();
+    s.heap.stack.push(plist(symbol));
+    try!(s.heap.alloc_pair(base - 1, base));
+    try!(s.heap.alloc_pair(base - 2, base + 1));
+    let list = s.heap.stack.pop().unwrap();
+    s.heap.stack.truncate(base);
+    unsafe { *(*symbol).plist.get() = list };
+    Ok(Value::unspecified())
+}
+
+fn get(s: &mut State, argc: usize) -> Result<Value, String> {
+    let symbol = try!(symbol_arg(s, argc, "get"));
+    let args = args(s, argc);
+    match find(plist(symbol), &args[1]) {
+        (Some(pair), _) => Ok(pair.cdr().unwrap().car().unwrap()),
+        (None, _) => Ok(args.get(2).cloned().unwrap_or(Value::new(value::FALSE))),
+    }
+}
+
+fn remove_property(s: &mut State, argc: usize) -> Result<Value, String> {
+    let symbol = try!(symbol_arg(s, argc, "remove-property!"));
+    let property = args(s, argc)[1].clone();
+    match find(plist(symbol), &property) {
+        (Some(pair), None) => unsafe {
+            *(*symbol).plist.get() = pair.cdr().unwrap().cdr().unwrap()
+        },
+        (Some(pair), Some(before)) => {
+            let rest = pair.cdr().unwrap().cdr().unwrap();
+            before.set_cdr(rest.clone()).unwrap();
+            s.heap.write_barrier(&before, &rest)
+        }
+        (None, _) => {}
+    }
+    Ok(Value::unspecified())
+}
+
+fn symbol_plist(s: &mut State, argc: usize) -> Result<Value, String> {
+    symbol_arg(s, argc, "symbol-plist").map(plist)
+}
@@ -0,0 +1,130 @@
+//! The `(rusty system)` library: the process's command line and
+//! environment variables, and `exit`.
+//!
+//! The command line is that of the whole process, program name first, and
+//! environment variables whose names or values are not Unicode are left
+//! out.  `(exit obj)` first takes off every parameter binding that
+//! `parameterize` made (see `builtins::parameters`), as leaving their
+//! extents by failing would, then ends the process: with status 0 if `obj`
+//! is `#t` or missing, 1 if it is `#f`, and `obj` itself if it is an exact
+//! integer that fits in 32 bits.  Those bindings are the only dynamic
+//! state, so there are no other winders to run.  Embedders that must not
+//! be ended by code they run can veto the call with an audit hook (see
+//! `audit`).
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use api::SchemeValue;
+use interp::State;
+use value::{self, Value};
+use super::{args, push_list, Arity, Native};
+
+pub static PROCEDURES: [Native; 4] = [
+    Native { name: "command-line", arity: Arity::Exactly(0), function: command_line },
+    Native { name: "exit", arity: Arity::Between(0, 1), function: exit },
+    Native {
+        name: "get-environment-variable",
+        arity: Arity::Exactly(1),
+        function: get_environment_variable,
+    },
+    Native {
+        name: "get-environment-variables",
+        arity: Arity::Exactly(0),
+        function: get_environment_variables,
+    },
+];
+
+fn new_string(s: &mut State, string: String) -> Result<Value, String> {
+    string.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned())
+}
+
+/// `(command-line)` is the list of the process's arguments, as strings.
+fn command_line(s: &mut State, _: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    for arg in env::args_os().filter_map(|arg| arg.into_string().ok()) {
+        match new_string(s, arg) {
+            Ok(arg) => s.heap.stack.push(arg),
+            Err(e) => {
+                s.heap.stack.truncate(base);
+                return Err(e)
+            }
+        }
+    }
+    try!(push_list(s, base));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+/// `(get-environment-variable name)` is the value of the environment
+/// variable `name`, or `#f` if it is not set.
+fn get_environment_variable(s: &mut State, argc: usize) -> Result<Value, String> {
+    let name = try!(::string::as_str(&args(s, argc)[0])
+                        .map(str::to_owned)
+                        .ok_or("get-environment-variable: not a string"));
+    match env::var(name) {
+        Ok(value) => new_string(s, value),
+        Err(_) => Ok(Value::new(value::FALSE)),
+    }
+}
+
+/// Pushes the pair of the strings `name` and `value`.
+fn push_binding(s: &mut State, name: String, value: String) -> Result<(), String> {
+    let base = s.heap.stack.len();
+    let res = new_string(s, name).and_then(|name| {
+        s.heap.stack.push(name);
+        let value = try!(new_string(s, value));
+        s.heap.stack.push(value);
+        Ok(try!(s.heap.alloc_pair(base, base + 1)))
+    });
+    let binding = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(base);
+    try!(res);
+    s.heap.stack.push(binding.unwrap());
+    Ok(())
+}
+
+/// `(get-environment-variables)` is the association list of every
+/// environment variable's name with its value.
+fn get_environment_variables(s: &mut State, _: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    for (name, value) in env::vars_os() {
+        if let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) {
+            if let Err(e) = push_binding(s, name, value) {
+                s.heap.stack.truncate(base);
+                return Err(e)
+            }
+        }
+    }
+    try!(push_list(s, base));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+/// The exit status that `obj` stands for.
+fn status(obj: Option<&Value>) -> Result<i32, String> {
+    match obj {
+        None => Ok(0),
+        Some(x) if x.get() == value::TRUE => Ok(0),
+        Some(x) if x.get() == value::FALSE => Ok(1),
+        Some(x) if x.fixnump() => {
+            let n = x.get() as isize >> 2;
+            if n as i32 as isize == n {
+                Ok(n as i32)
+            } else {
+                Err(format!("exit: status {} is out of range", n))
+            }
+        }
+        Some(_) => Err("exit: not a status".to_owned()),
+    }
+}
+
+/// `(exit [obj])` unwinds the parameter bindings and ends the process.
+fn exit(s: &mut State, argc: usize) -> Result<Value, String> {
+    let code = try!(status(args(s, argc).first()));
+    for (cell, x) in s.parameters.drain(..).rev() {
+        s.heap.persistent.release(cell);
+        s.heap.persistent.release(x)
+    }
+    let _ = io::stdout().flush();
+    process::exit(code)
+}
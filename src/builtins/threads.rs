@@ -0,0 +1,38 @@
+//! The `(rusty threads)` library: green threads, which take turns running
+//! on the one VM (see `interp`).
+//!
+//! `(spawn thunk)` queues a thread that calls `thunk`, with the parameter
+//! bindings of the code that spawned it, and `(run-threads)` runs the
+//! queued threads until all have returned.  A thread is paused when its
+//! slice of instructions runs out, or when it calls `(yield)`, which does
+//! nothing outside a thread.  Threads cannot run threads of their own.
+
+use interp::{self, State};
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 3] = [
+    Native { name: "spawn", arity: Arity::Exactly(1), function: spawn },
+    Native { name: "yield", arity: Arity::Exactly(0), function: yield_thread },
+    Native { name: "run-threads", arity: Arity::Exactly(0), function: run_threads },
+];
+
+fn spawn(s: &mut State, argc: usize) -> Result<Value, String> {
+    let thunk = args(s, argc)[0].clone();
+    s.heap.stack.push(thunk);
+    if let Err(e) = interp::spawn(s) {
+        s.heap.stack.pop();
+        return Err(e)
+    }
+    Ok(Value::unspecified())
+}
+
+fn yield_thread(s: &mut State, _: usize) -> Result<Value, String> {
+    interp::yield_thread(s);
+    Ok(Value::unspecified())
+}
+
+fn run_threads(s: &mut State, _: usize) -> Result<Value, String> {
+    try!(interp::run_threads(s));
+    Ok(Value::unspecified())
+}
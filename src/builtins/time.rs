@@ -0,0 +1,74 @@
+//! The `(rusty time)` library: the clocks of R7RS, and what the prelude's
+//! `time` measures with.
+//!
+//! `current-second` is the wall clock, in seconds since the Unix epoch, as
+//! a flonum.  `current-jiffy` is a monotonic clock, in microseconds since
+//! the `State` was made, so it is a fixnum, and it is what to time code
+//! with: the wall clock can jump.
+//!
+//! `(time expr)` evaluates `expr`, writes how long it took and how many
+//! bytes it allocated to standard error, and returns its values.  It is a
+//! special form (see `compiler::syntax`), so that it can report `expr`
+//! itself, on top of the prelude's `%time`.
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arith;
+use compiler;
+use interp::State;
+use value::Value;
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 5] = [
+    Native { name: "current-second", arity: Arity::Exactly(0), function: current_second },
+    Native { name: "current-jiffy", arity: Arity::Exactly(0), function: current_jiffy },
+    Native { name: "jiffies-per-second", arity: Arity::Exactly(0), function: jiffies_per_second },
+    Native { name: "%bytes-allocated", arity: Arity::Exactly(0), function: bytes_allocated },
+    Native { name: "%report-time", arity: Arity::Exactly(3), function: report_time },
+];
+
+/// The number of jiffies in a second.
+const JIFFIES_PER_SECOND: u64 = 1_000_000;
+
+fn current_second(s: &mut State, _: usize) -> Result<Value, String> {
+    let since_epoch = try!(SystemTime::now()
+                               .duration_since(UNIX_EPOCH)
+                               .map_err(|_| "current-second: the clock is before 1970".to_owned()));
+    let seconds = since_epoch.as_secs() as f64 + since_epoch.subsec_nanos() as f64 * 1e-9;
+    arith::flonum(&mut s.heap, seconds)
+}
+
+fn current_jiffy(s: &mut State, _: usize) -> Result<Value, String> {
+    let elapsed = s.started.elapsed();
+    let jiffies = elapsed.as_secs() * JIFFIES_PER_SECOND + elapsed.subsec_nanos() as u64 / 1000;
+    Ok(Value::new((jiffies as usize) << 2))
+}
+
+fn jiffies_per_second(_: &mut State, _: usize) -> Result<Value, String> {
+    Ok(Value::new((JIFFIES_PER_SECOND as usize) << 2))
+}
+
+/// `(%bytes-allocated)` is the number of bytes allocated so far, which,
+/// unlike `gc-stats`, it does not add to.
+fn bytes_allocated(s: &mut State, _: usize) -> Result<Value, String> {
+    Ok(Value::new((s.heap.bytes_allocated() as usize) << 2))
+}
+
+/// `(%report-time expr jiffies bytes)` writes that evaluating `expr` took
+/// `jiffies` and allocated `bytes`.
+fn report_time(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (expr, jiffies, bytes) = {
+        let args = args(s, argc);
+        (try!(compiler::datum(&args[0]).map_err(|e| format!("time: {}", e))),
+         try!(args[1].as_fixnum().map_err(|_| "time: jiffies are not a fixnum".to_owned())),
+         try!(args[2].as_fixnum().map_err(|_| "time: bytes are not a fixnum".to_owned())))
+    };
+    let milliseconds = jiffies as f64 * 1000.0 / JIFFIES_PER_SECOND as f64;
+    let _ = writeln!(io::stderr(),
+                     "time: {}: {:.3} ms, {} bytes allocated",
+                     expr,
+                     milliseconds,
+                     bytes);
+    Ok(Value::unspecified())
+}
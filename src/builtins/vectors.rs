@@ -0,0 +1,610 @@
+//! The `(rusty vectors)` library: vectors, and bulk operations on them.
+//!
+//! The bulk operations, and the conversions between vectors and lists,
+//! loop in Rust, so that they cost much less per element than the
+//! equivalent Scheme loop.  `vector-map`, `vector-map!`, and
+//! `vector-for-each` call a native procedure directly, without going
+//! through the interpreter at all.  With several vectors, `vector-map` and
+//! `vector-for-each` stop at the end of the shortest, as `map` does.
+//!
+//! `vector-fold`, `vector-index` and `vector-binary-search` are as in SRFI
+//! 133, which revises SRFI 43 to agree with R7RS: the procedures they call
+//! are not passed indices.  `vector-sort!` is a stable merge sort, which
+//! sorts a copy of the elements on the stack, where the GC can find and
+//! move them while the comparison allocates, and then copies them back.
+//!
+//! A vector literal, such as `#(1 2)` (which need not be quoted), is one
+//! vector, built when its program is loaded, so every evaluation of it
+//! returns the same vector.  It is not immutable, but modifying it is an
+//! error, as in R7RS: `vector-set!` on a literal is not detected, and
+//! changes what the literal evaluates to from then on.
+
+use std::cmp;
+use std::mem;
+
+use alloc;
+use audit;
+use equal;
+use interp::{self, State};
+use value::{self, Value, Tags};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 20] = [
+    Native { name: "vector?", arity: Arity::Exactly(1), function: is_vector },
+    Native { name: "make-vector", arity: Arity::Between(1, 2), function: make_vector },
+    Native { name: "vector", arity: Arity::AtLeast(0), function: vector },
+    Native { name: "vector-length", arity: Arity::Exactly(1), function: vector_length },
+    Native { name: "vector-ref", arity: Arity::Exactly(2), function: vector_ref },
+    Native { name: "vector-set!", arity: Arity::Exactly(3), function: vector_set },
+    Native { name: "vector-fill!", arity: Arity::Between(2, 4), function: vector_fill },
+    Native { name: "vector-copy!", arity: Arity::Between(3, 5), function: vector_copy },
+    Native { name: "vector-map!", arity: Arity::Exactly(2), function: vector_map_in_place },
+    Native { name: "vector-copy", arity: Arity::Between(1, 3), function: vector_copy_new },
+    Native { name: "subvector", arity: Arity::Exactly(3), function: subvector },
+    Native { name: "vector-append", arity: Arity::AtLeast(0), function: vector_append },
+    Native { name: "vector->list", arity: Arity::Between(1, 3), function: vector_to_list },
+    Native { name: "list->vector", arity: Arity::Exactly(1), function: list_to_vector },
+    Native { name: "vector-map", arity: Arity::AtLeast(2), function: vector_map },
+    Native { name: "vector-for-each", arity: Arity::AtLeast(2), function: vector_for_each },
+    Native { name: "vector-fold", arity: Arity::AtLeast(3), function: vector_fold },
+    Native { name: "vector-index", arity: Arity::AtLeast(2), function: vector_index },
+    Native {
+        name: "vector-binary-search",
+        arity: Arity::Exactly(3),
+        function: vector_binary_search,
+    },
+    Native { name: "vector-sort!", arity: Arity::Exactly(2), function: vector_sort },
+];
+
+fn elements<'a>(name: &str, x: &'a Value) -> Result<&'a [Value], String> {
+    if x.immediatep() {
+        return Err(format!("{}: not a vector", name))
+    }
+    equal::vector_elements(x).ok_or_else(|| format!("{}: not a vector", name))
+}
+
+fn index(name: &str, x: &Value) -> Result<usize, String> {
+    x.as_fixnum().map_err(|_| format!("{}: index is not an exact integer", name))
+}
+
+/// The range of the elements from the optional `start` and `end`
+/// arguments, which default to all of them.
+fn range(name: &str, args: &[Value], len: usize) -> Result<(usize, usize), String> {
+    let start = match args.get(0) {
+        Some(x) => try!(index(name, x)),
+        None => 0,
+    };
+    let end = match args.get(1) {
+        Some(x) => try!(index(name, x)),
+        None => len,
+    };
+    if start > end || end > len {
+        return Err(format!("{}: range out of bounds", name))
+    }
+    Ok((start, end))
+}
+
+fn unspecified() -> Value {
+    Value::unspecified()
+}
+
+fn is_vector(s: &mut State, argc: usize) -> Result<Value, String> {
+    let x = &args(s, argc)[0];
+    let vector = !x.immediatep() && equal::vector_elements(x).is_some();
+    Ok(Value::boolean(vector))
+}
+
+/// `(make-vector k fill)` is a new vector of `k` elements, each `fill`.
+/// It is allocated directly, so a length past the heap limit fails before
+/// anything is filled.
+fn make_vector(s: &mut State, argc: usize) -> Result<Value, String> {
+    let len = {
+        let k = &args(s, argc)[0];
+        if !k.fixnump() {
+            return Err("make-vector: length is not an exact integer".to_owned())
+        }
+        k.get() as isize >> 2
+    };
+    if len < 0 {
+        return Err("make-vector: negative length".to_owned())
+    }
+    if len as usize > alloc::MAX_VECTOR_LEN {
+        return Err("make-vector: length too large".to_owned())
+    }
+    // The fill is the second argument, or the unspecified value pushed in
+    // its place.
+    if argc == 1 {
+        s.heap.stack.push(unspecified())
+    }
+    let fill = s.heap.stack.len() - 1;
+    let vector = s.heap.alloc_filled_vector(len as usize, fill);
+    if argc == 1 {
+        s.heap.stack.remove(fill);
+    }
+    try!(vector);
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn vector(s: &mut State, argc: usize) -> Result<Value, String> {
+    let len = s.heap.stack.len();
+    try!(s.heap.alloc_vector(len - argc, len));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn vector_length(s: &mut State, argc: usize) -> Result<Value, String> {
+    let len = try!(elements("vector-length", &args(s, argc)[0])).len();
+    Ok(Value::new(len << 2))
+}
+
+fn vector_ref(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    let i = try!(index("vector-ref", &args[1]));
+    try!(elements("vector-ref", &args[0]))
+        .get(i)
+        .cloned()
+        .ok_or_else(|| "vector-ref: index out of bounds".to_owned())
+}
+
+fn vector_set(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (object, new) = {
+        let args = args(s, argc);
+        let i = try!(index("vector-set!", &args[1]));
+        match try!(elements("vector-set!", &args[0])).get(i) {
+            Some(element) => element.set(args[2].clone()),
+            None => return Err("vector-set!: index out of bounds".to_owned()),
+        }
+        (args[0].clone(), args[2].clone())
+    };
+    s.heap.write_barrier(&object, &new);
+    Ok(unspecified())
+}
+
+/// `(vector-fill! vector fill [start [end]])`
+fn vector_fill(s: &mut State, argc: usize) -> Result<Value, String> {
+    let (object, new) = {
+        let args = args(s, argc);
+        let elements = try!(elements("vector-fill!", &args[0]));
+        let (start, end) = try!(range("vector-fill!", &args[2..], elements.len()));
+        for element in &elements[start..end] {
+            element.set(args[1].clone())
+        }
+        (args[0].clone(), args[1].clone())
+    };
+    s.heap.write_barrier(&object, &new);
+    Ok(unspecified())
+}
+
+/// `(vector-copy! to at from [start [end]])`.  The source and destination
+/// may overlap.
+fn vector_copy(s: &mut State, argc: usize) -> Result<Value, String> {
+    let object = {
+        let args = args(s, argc);
+        let to = try!(elements("vector-copy!", &args[0]));
+        let at = try!(index("vector-copy!", &args[1]));
+        let from = try!(elements("vector-copy!", &args[2]));
+        let (start, end) = try!(range("vector-copy!", &args[3..], from.len()));
+        if at > to.len() || end - start > to.len() - at {
+            return Err("vector-copy!: destination out of bounds".to_owned())
+        }
+        // Copying backwards is only needed if the source starts before, and
+        // overlaps, the destination.
+        let pairs = to[at..at + end - start].iter().zip(&from[start..end]);
+        if args[0].get() == args[2].get() && at > start {
+            for (dst, src) in pairs.rev() {
+                dst.set(src.clone())
+            }
+        } else {
+            for (dst, src) in pairs {
+                dst.set(src.clone())
+            }
+        }
+        args[0].clone()
+    };
+    let len = s.heap.stack.len();
+    let from = s.heap.stack[len - argc + 2].clone();
+    for element in try!(elements("vector-copy!", &from)) {
+        s.heap.write_barrier(&object, element)
+    }
+    Ok(unspecified())
+}
+
+/// `(vector-copy vector [start [end]])` is a new vector of the elements
+/// of `vector` from `start` to `end`.
+fn vector_copy_new(s: &mut State, argc: usize) -> Result<Value, String> {
+    copy("vector-copy", s, argc)
+}
+
+/// `(subvector vector start end)` is `vector-copy`, with a range.
+fn subvector(s: &mut State, argc: usize) -> Result<Value, String> {
+    copy("subvector", s, argc)
+}
+
+fn copy(name: &str, s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    let copied = {
+        let args = args(s, argc);
+        let elements = try!(elements(name, &args[0]));
+        let (start, end) = try!(range(name, &args[1..], elements.len()));
+        elements[start..end].to_vec()
+    };
+    new_vector(s, base, copied)
+}
+
+/// `(vector-append vector ...)` is a new vector of the elements of the
+/// `vector`s, in order.
+fn vector_append(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    let mut appended = vec![];
+    for x in args(s, argc) {
+        appended.extend_from_slice(try!(elements("vector-append", x)))
+    }
+    new_vector(s, base, appended)
+}
+
+/// A new vector of `elements`, which are pushed above `base`, the top of
+/// the stack.
+fn new_vector(s: &mut State, base: usize, elements: Vec<Value>) -> Result<Value, String> {
+    s.heap.stack.extend(elements);
+    let len = s.heap.stack.len();
+    try!(s.heap.alloc_vector(base, len));
+    let vector = s.heap.stack.pop().unwrap();
+    s.heap.stack.truncate(base);
+    Ok(vector)
+}
+
+/// `(vector->list vector [start [end]])`
+fn vector_to_list(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    let (start, end) = {
+        let args = args(s, argc);
+        let len = try!(elements("vector->list", &args[0])).len();
+        try!(range("vector->list", &args[1..], len))
+    };
+    s.heap.stack.push(Value::nil());
+    for i in (start..end).rev() {
+        // Allocating moves the vector.
+        let element = equal::vector_elements(&s.heap.stack[base - argc]).unwrap()[i].clone();
+        s.heap.stack.push(element);
+        try!(s.heap.alloc_pair(base + 1, base));
+        s.heap.stack[base] = s.heap.stack.pop().unwrap();
+        s.heap.stack.pop();
+    }
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn list_to_vector(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len();
+    let mut list = args(s, argc)[0].clone();
+    while !list.is_nil() {
+        match (list.car(), list.cdr()) {
+            (Ok(car), Ok(cdr)) => {
+                s.heap.stack.push(car);
+                list = cdr
+            }
+            _ => {
+                s.heap.stack.truncate(base);
+                return Err("list->vector: not a list".to_owned())
+            }
+        }
+    }
+    let len = s.heap.stack.len();
+    try!(s.heap.alloc_vector(base, len));
+    let vector = s.heap.stack.pop().unwrap();
+    s.heap.stack.truncate(base);
+    Ok(vector)
+}
+
+/// The descriptor of `procedure` if it is a native procedure, which must
+/// accept `argc` arguments, or `None` if it is a Scheme procedure.
+pub fn procedure(name: &str,
+                 procedure: &Value,
+                 argc: usize)
+                 -> Result<Option<&'static Native>, String> {
+    // Natives are not on the heap, so the descriptor stays valid.
+    match procedure.tag() {
+        Tags::RustFunc => {
+            let native = unsafe { &*(procedure.as_ptr() as *const Native) };
+            try!(native.arity.check(native.name, argc));
+            Ok(Some(native))
+        }
+        Tags::Function => Ok(None),
+        _ => Err(format!("{}: not a procedure", name)),
+    }
+}
+
+/// Calls the procedure `s.heap.stack[base]`, whose descriptor is `native`
+/// if it is native, on the `i`th elements of the vectors above it, up to
+/// `end`, and returns its result.  If `state`, the value just above the
+/// procedure is not a vector, but its first argument.
+fn call_on_elements(s: &mut State,
+                    native: Option<&'static Native>,
+                    base: usize,
+                    state: bool,
+                    end: usize,
+                    i: usize)
+                    -> Result<Value, String> {
+    let start = s.heap.stack.len();
+    if native.is_none() {
+        let procedure = s.heap.stack[base].clone();
+        s.heap.stack.push(procedure);
+    }
+    let mut vectors = base + 1;
+    if state {
+        let state = s.heap.stack[base + 1].clone();
+        s.heap.stack.push(state);
+        vectors += 1
+    }
+    for j in vectors..end {
+        // The procedure may allocate, which moves the vectors.
+        let element = equal::vector_elements(&s.heap.stack[j]).unwrap()[i].clone();
+        s.heap.stack.push(element)
+    }
+    finish_call(s, native, start, end - base - 1)
+}
+
+/// Calls the procedure `s.heap.stack[procedure]`, whose descriptor is
+/// `native` if it is native, on the values `args`, and returns its result.
+fn call_on(s: &mut State,
+           native: Option<&'static Native>,
+           procedure: usize,
+           args: &[usize])
+           -> Result<Value, String> {
+    let start = s.heap.stack.len();
+    if native.is_none() {
+        let procedure = s.heap.stack[procedure].clone();
+        s.heap.stack.push(procedure);
+    }
+    for &i in args {
+        let arg = s.heap.stack[i].clone();
+        s.heap.stack.push(arg)
+    }
+    finish_call(s, native, start, args.len())
+}
+
+/// Finishes a call whose `argc` arguments have been pushed from `start`,
+/// after the procedure itself unless it is native, and returns its result.
+fn finish_call(s: &mut State,
+               native: Option<&'static Native>,
+               start: usize,
+               argc: usize)
+               -> Result<Value, String> {
+    match native {
+        Some(native) => {
+            if !s.audit.is_empty() {
+                if let Err(e) = s.audit.run(&audit::Event::NativeCall(native.name)) {
+                    s.heap.stack.truncate(start);
+                    return Err(e)
+                }
+            }
+            let result = (native.function)(s, argc);
+            s.heap.stack.truncate(start);
+            result
+        }
+        None => {
+            try!(interp::call(s, argc));
+            Ok(s.heap.stack.pop().unwrap())
+        }
+    }
+}
+
+/// The length of the shortest of the vectors `args`.
+fn shortest(name: &str, args: &[Value]) -> Result<usize, String> {
+    let mut len = None;
+    for x in args {
+        let n = try!(elements(name, x)).len();
+        len = Some(len.map_or(n, |len| cmp::min(len, n)))
+    }
+    Ok(len.unwrap_or(0))
+}
+
+/// `(vector-map! procedure vector)` replaces each element of `vector` with
+/// the result of calling `procedure` on it, in order.
+fn vector_map_in_place(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, len) = {
+        let args = args(s, argc);
+        (try!(procedure("vector-map!", &args[0], 1)),
+         try!(elements("vector-map!", &args[1])).len())
+    };
+    for i in 0..len {
+        let result = try!(call_on_elements(s, native, base, false, base + 2, i));
+        let vector = s.heap.stack[base + 1].clone();
+        equal::vector_elements(&vector).unwrap()[i].set(result.clone());
+        s.heap.write_barrier(&vector, &result);
+    }
+    Ok(unspecified())
+}
+
+/// `(vector-map procedure vector ...)` is a new vector of the results of
+/// calling `procedure` on the elements of the `vector`s, in order.
+fn vector_map(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, len) = {
+        let args = args(s, argc);
+        (try!(procedure("vector-map", &args[0], argc - 1)),
+         try!(shortest("vector-map", &args[1..])))
+    };
+    let end = s.heap.stack.len();
+    s.heap.stack.extend(::std::iter::repeat(unspecified()).take(len));
+    let res = s.heap.alloc_vector(end, end + len);
+    let vector = if res.is_ok() { s.heap.stack.pop() } else { None };
+    s.heap.stack.truncate(end);
+    try!(res);
+    s.heap.stack.push(vector.unwrap());
+    for i in 0..len {
+        let result = match call_on_elements(s, native, base, false, end, i) {
+            Ok(result) => result,
+            Err(e) => {
+                s.heap.stack.truncate(end);
+                return Err(e)
+            }
+        };
+        let vector = s.heap.stack[end].clone();
+        equal::vector_elements(&vector).unwrap()[i].set(result.clone());
+        s.heap.write_barrier(&vector, &result);
+    }
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+/// `(vector-for-each procedure vector ...)` calls `procedure` on the
+/// elements of the `vector`s, in order.
+fn vector_for_each(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, len) = {
+        let args = args(s, argc);
+        (try!(procedure("vector-for-each", &args[0], argc - 1)),
+         try!(shortest("vector-for-each", &args[1..])))
+    };
+    for i in 0..len {
+        try!(call_on_elements(s, native, base, false, base + argc, i));
+    }
+    Ok(unspecified())
+}
+
+/// `(vector-fold kons knil vector ...)` calls `kons` on its state, which
+/// starts as `knil`, and the elements of the `vector`s, in order, each
+/// result being the next state, and returns the last.  The state is kept
+/// in the argument `knil`.
+fn vector_fold(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, len) = {
+        let args = args(s, argc);
+        (try!(procedure("vector-fold", &args[0], argc - 1)),
+         try!(shortest("vector-fold", &args[2..])))
+    };
+    for i in 0..len {
+        let state = try!(call_on_elements(s, native, base, true, base + argc, i));
+        s.heap.stack[base + 1] = state
+    }
+    Ok(s.heap.stack[base + 1].clone())
+}
+
+/// `(vector-index pred vector ...)` is the first index at which the
+/// elements of the `vector`s satisfy `pred`, or `#f`.
+fn vector_index(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, len) = {
+        let args = args(s, argc);
+        (try!(procedure("vector-index", &args[0], argc - 1)),
+         try!(shortest("vector-index", &args[1..])))
+    };
+    for i in 0..len {
+        if try!(call_on_elements(s, native, base, false, base + argc, i)).is_true() {
+            return Ok(Value::new(i << 2))
+        }
+    }
+    Ok(Value::new(value::FALSE))
+}
+
+/// `(vector-binary-search vector value cmp)` is the index of an element
+/// of the sorted `vector` for which `(cmp element value)` is zero, or `#f`
+/// if there is none.  `cmp` is negative if the element comes before
+/// `value`, and positive if it comes after.
+fn vector_binary_search(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, len) = {
+        let args = args(s, argc);
+        (try!(procedure("vector-binary-search", &args[2], 2)),
+         try!(elements("vector-binary-search", &args[0])).len())
+    };
+    let element = s.heap.stack.len();
+    s.heap.stack.push(unspecified());
+    let (mut lo, mut hi) = (0, len);
+    let mut found = Ok(Value::new(value::FALSE));
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let x = equal::vector_elements(&s.heap.stack[base]).unwrap()[mid].clone();
+        s.heap.stack[element] = x;
+        let order = match call_on(s, native, base + 2, &[element, base + 1]) {
+            Ok(ref order) if order.fixnump() => order.get() as isize >> 2,
+            Ok(_) => {
+                found = Err("vector-binary-search: comparison is not a fixnum".to_owned());
+                break
+            }
+            Err(e) => {
+                found = Err(e);
+                break
+            }
+        };
+        if order < 0 {
+            lo = mid + 1
+        } else if order > 0 {
+            hi = mid
+        } else {
+            found = Ok(Value::new(mid << 2));
+            break
+        }
+    }
+    s.heap.stack.truncate(element);
+    found
+}
+
+/// `(vector-sort! vector less?)` sorts `vector` in place, so that no
+/// element is `less?` than the one before it.  Elements that are not
+/// `less?` than each other stay in the same order.
+fn vector_sort(s: &mut State, argc: usize) -> Result<Value, String> {
+    let base = s.heap.stack.len() - argc;
+    let (native, elements) = {
+        let args = args(s, argc);
+        (try!(procedure("vector-sort!", &args[1], 2)),
+         try!(elements("vector-sort!", &args[0])).to_vec())
+    };
+    let len = elements.len();
+    // The elements, and as many slots to merge them into.
+    let work = s.heap.stack.len();
+    s.heap.stack.extend(elements.clone());
+    s.heap.stack.extend(elements);
+    let res = merge_sort(s, native, base + 1, work, work + len, len).map(|sorted| {
+        let vector = s.heap.stack[base].clone();
+        for (i, element) in equal::vector_elements(&vector).unwrap().iter().enumerate() {
+            let x = s.heap.stack[sorted + i].clone();
+            element.set(x.clone());
+            s.heap.write_barrier(&vector, &x)
+        }
+        unspecified()
+    });
+    s.heap.stack.truncate(work);
+    res
+}
+
+/// Sorts the `len` values on the stack from `from`, by the procedure
+/// `s.heap.stack[less]`, merging runs of them into the `len` slots from
+/// `to` and back, and returns where the sorted values ended up.  The sort
+/// is stable.  `native` is the descriptor of `less?`, as `procedure` finds
+/// it.
+pub fn merge_sort(s: &mut State,
+                  native: Option<&'static Native>,
+                  less: usize,
+                  mut from: usize,
+                  mut to: usize,
+                  len: usize)
+                  -> Result<usize, String> {
+    let mut width = 1;
+    while width < len {
+        let mut lo = 0;
+        while lo < len {
+            let mid = cmp::min(lo + width, len);
+            let hi = cmp::min(lo + 2 * width, len);
+            let (mut i, mut j) = (from + lo, from + mid);
+            for k in to + lo..to + hi {
+                // Taking from the right run only if it is less keeps equal
+                // elements in order.
+                let right = j < from + hi &&
+                            (i == from + mid ||
+                             try!(call_on(s, native, less, &[j, i])).is_true());
+                let x = if right {
+                    j += 1;
+                    s.heap.stack[j - 1].clone()
+                } else {
+                    i += 1;
+                    s.heap.stack[i - 1].clone()
+                };
+                s.heap.stack[k] = x
+            }
+            lo = hi
+        }
+        mem::swap(&mut from, &mut to);
+        width *= 2
+    }
+    Ok(from)
+}
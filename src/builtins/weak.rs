@@ -0,0 +1,39 @@
+//! The `(rusty weak)` library: weak boxes.
+
+use alloc;
+use interp::State;
+use value::{self, Value};
+use super::{args, Arity, Native};
+
+pub static PROCEDURES: [Native; 3] = [
+    Native { name: "make-weak-box", arity: Arity::Exactly(1), function: make_weak_box },
+    Native { name: "weak-box?", arity: Arity::Exactly(1), function: is_weak_box },
+    Native { name: "weak-box-value", arity: Arity::Between(1, 2), function: weak_box_value },
+];
+
+fn make_weak_box(s: &mut State, _: usize) -> Result<Value, String> {
+    let len = s.heap.stack.len();
+    try!(s.heap.alloc_weak_box(len - 1));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+fn is_weak_box(s: &mut State, argc: usize) -> Result<Value, String> {
+    Ok(Value::new(if alloc::as_weak_box(&args(s, argc)[0]).is_some() {
+        value::TRUE
+    } else {
+        value::FALSE
+    }))
+}
+
+/// `(weak-box-value box [default])` returns `default` (or `#f`) if the
+/// referent has been collected.
+fn weak_box_value(s: &mut State, argc: usize) -> Result<Value, String> {
+    let args = args(s, argc);
+    match alloc::as_weak_box(&args[0]) {
+        Some(weak) if weak.referent.get() == value::BROKEN_WEAK => {
+            Ok(args.get(1).cloned().unwrap_or(Value::new(value::FALSE)))
+        }
+        Some(weak) => Ok(weak.referent.clone()),
+        None => Err("weak-box-value: not a weak box".to_owned()),
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::ptr;
+use std::u8;
 use value;
 use alloc;
+use api::SchemeValue;
 use std::cell;
 
 /// A bytecode object.  Consists of a header, the length of the bytecodes,
 /// the actual bytecodes, and finally the constants vector (not actually part
 /// of the BCO, but always allocated after it).
+///
+/// The BCOs of a library share one constants vector (see `ConstantPool`).
+/// Each BCO only uses the part of it given by its `ConstantRange`.
 pub struct BCO {
     /// The standard header object
     header: usize,
@@ -15,30 +22,253 @@ pub struct BCO {
 
     /// Pointer to the constants vector
     constants_vector: cell::UnsafeCell<value::Value>,
+
+    /// The part of the constants vector used by this BCO
+    constants: ConstantRange,
 }
 
 pub fn get_constants_vector(bco: &BCO) -> &cell::UnsafeCell<value::Value> {
     &bco.constants_vector
 }
 
-/// The opcodes
+/// Gets constant `index` of `bco`.
+pub fn get_constant(bco: &BCO, index: usize) -> Result<value::Value, String> {
+    let index = try!(bco.constants.get(index));
+    match unsafe { (*bco.constants_vector.get()).kind() } {
+        value::Kind::Vector(vec) => unsafe {
+            value::Value::raw_array_get(vec, index).map(|ptr| (*ptr).clone())
+        },
+        _ => bug!("constants vector of a BCO is not a vector"),
+    }
+}
+
+/// The part of a constants vector that one BCO uses.  Constant `i` of the
+/// BCO is element `start + i` of the vector.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ConstantRange {
+    pub start: usize,
+    pub len: usize,
+}
+
+impl ConstantRange {
+    /// All of a constants vector.
+    pub fn all() -> Self {
+        ConstantRange {
+            start: 0,
+            len: ::std::usize::MAX,
+        }
+    }
+
+    /// The index into the constants vector of constant `index`.
+    pub fn get(&self, index: usize) -> Result<usize, String> {
+        if index < self.len {
+            Ok(self.start + index)
+        } else {
+            Err(format!("constant {} out of range (function has {} constants)",
+                        index,
+                        self.len))
+        }
+    }
+}
+
+/// A constant, as known to the compiler.
+///
+/// Vector literals are constants, built once with the constants vector
+/// rather than every time they are evaluated, so every evaluation of a
+/// literal gives the same vector.  Like string literals, they must not be
+/// modified.  Booleans, the empty list, and pairs are only constants as
+/// parts of vectors.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Constant {
+    Fixnum(usize),
+
+    /// The bits of a flonum.
+    Flonum(u64),
+
+    /// The numerator and denominator of a ratio (see `ratio`).
+    Ratio(isize, isize),
+    Char(char),
+    Symbol(String),
+    Str(String),
+    Bool(bool),
+    Nil,
+    Pair(Box<(Constant, Constant)>),
+    Vector(Vec<Constant>),
+}
+
+impl Constant {
+    /// Builds `self`, and pushes it.
+    fn push(&self, heap: &mut alloc::Heap) -> Result<(), alloc::OutOfMemory> {
+        let base = heap.stack.len();
+        match *self {
+            Constant::Fixnum(x) => heap.stack.push(value::Value::new(x << 2)),
+            Constant::Flonum(x) => try!(heap.alloc_flonum(f64::from_bits(x))),
+            Constant::Ratio(n, d) => try!(heap.alloc_ratio(n, d)),
+            Constant::Char(c) => heap.stack.push(value::Value::character(c)),
+            Constant::Symbol(ref name) => heap.intern(name),
+            Constant::Str(ref string) => {
+                let x = match heap.shared_string(string) {
+                    Some(x) => x,
+                    None => try!(string.to_value(heap)),
+                };
+                heap.stack.push(x)
+            }
+            Constant::Bool(x) => heap.stack.push(value::Value::new(if x {
+                value::TRUE
+            } else {
+                value::FALSE
+            })),
+            Constant::Nil => heap.stack.push(value::Value::nil()),
+            Constant::Pair(ref pair) => {
+                try!(pair.0.push(heap));
+                try!(pair.1.push(heap));
+                try!(heap.alloc_pair(base, base + 1));
+                let pair = heap.stack.pop().unwrap();
+                heap.stack.truncate(base);
+                heap.stack.push(pair)
+            }
+            Constant::Vector(ref items) => {
+                for x in items {
+                    try!(x.push(heap))
+                }
+                let len = heap.stack.len();
+                try!(heap.alloc_vector(base, len));
+                let vector = heap.stack.pop().unwrap();
+                heap.stack.truncate(base);
+                heap.stack.push(vector)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The constants of a library, shared by all of its BCOs.
+///
+/// Each function compiled into the pool gets a `ConstantRange` of the pool.
+/// A constant that is already in the pool is reused if it is within reach
+/// of the function's range – constant operands are a byte, so a range can
+/// be at most 256 long.  Otherwise it is added again.  Ranges may therefore
+/// overlap, and include constants that their function does not use.
+#[derive(Debug, Default)]
+pub struct ConstantPool {
+    constants: Vec<Constant>,
+
+    /// The index of the most recent copy of each constant.
+    index: HashMap<Constant, usize>,
+}
+
+/// The constants of one function being compiled into a `ConstantPool`.
+#[derive(Debug, Default)]
+pub struct FunctionConstants {
+    start: Option<usize>,
+    end: usize,
+}
+
+impl FunctionConstants {
+    /// The part of the pool used by the function.
+    pub fn range(&self) -> ConstantRange {
+        match self.start {
+            Some(start) => ConstantRange { start: start, len: self.end - start },
+            None => ConstantRange { start: 0, len: 0 },
+        }
+    }
+}
+
+impl ConstantPool {
+    /// A pool of `constants`, in order.
+    pub fn from_constants(constants: Vec<Constant>) -> Self {
+        let index = constants.iter().cloned().enumerate().map(|(i, x)| (x, i)).collect();
+        ConstantPool {
+            constants: constants,
+            index: index,
+        }
+    }
+
+    /// The constants in the pool.
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    /// The number of constants in the pool.
+    pub fn len(&self) -> usize {
+        self.constants.len()
+    }
+
+    /// Is the pool empty?
+    pub fn is_empty(&self) -> bool {
+        self.constants.is_empty()
+    }
+
+    /// Adds `constant` to `function`, returning its index relative to the
+    /// start of the function's range.
+    pub fn add(&mut self,
+               function: &mut FunctionConstants,
+               constant: Constant)
+               -> Result<u8, String> {
+        if let Some(&i) = self.index.get(&constant) {
+            match function.start {
+                None => {
+                    function.start = Some(i);
+                    function.end = i + 1;
+                    return Ok(0)
+                }
+                Some(start) if i >= start && i - start <= u8::MAX as usize => {
+                    function.end = ::std::cmp::max(function.end, i + 1);
+                    return Ok((i - start) as u8)
+                }
+                Some(_) => {}
+            }
+        }
+        let i = self.constants.len();
+        let start = *function.start.get_or_insert(i);
+        if i - start > u8::MAX as usize {
+            return Err("too many constants in one function".to_owned())
+        }
+        self.constants.push(constant.clone());
+        self.index.insert(constant, i);
+        function.end = i + 1;
+        Ok((i - start) as u8)
+    }
+
+    /// Allocates the constants vector, and pushes it.
+    pub fn materialize(&self, heap: &mut alloc::Heap) -> Result<(), alloc::OutOfMemory> {
+        let base = heap.stack.len();
+        for constant in &self.constants {
+            try!(constant.push(heap))
+        }
+        let len = heap.stack.len();
+        let res = heap.alloc_vector(base, len);
+        let vector = heap.stack.pop();
+        heap.stack.truncate(base);
+        try!(res);
+        heap.stack.push(vector.unwrap());
+        Ok(())
+    }
+}
+
+/// The opcodes.
+///
+/// Operands are slots of the current frame, relative to the frame pointer,
+/// unless said otherwise.  Each frame is a fixed window of
+/// `Function::frame_size` slots, allocated when the function is entered:
+/// instructions read their operands from slots, and write their results to
+/// slot `dst`, so nothing is ever pushed or popped.  Calls take the
+/// procedure and its arguments from consecutive slots, and the callee's
+/// window starts at the slot of the procedure, above which the caller has
+/// nothing live.
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 pub enum Opcode {
-    /// Implements `cons`.  `src` is the stack index of the source,
-    /// `src2` is the stack index of the destination.  `dst` must be 0, 1, or 2
-    /// and refers to the number of words to pop off of the stack.
-    /// Pushes the new pair onto the stack.
+    /// `cons`: stores a new pair of `src` and `src2` in `dst`.
     Cons,
 
-    /// Implements `car`.  `src` is the stack depth of the pair to take the `car`
-    /// of.
+    /// `car`: stores the car of the pair `src` in `dst`.
     Car,
 
     /// `cdr`
     Cdr,
 
-    /// `set-car!`
+    /// `set-car!`: sets the car of `dst` to `src`.
     SetCar,
 
     /// `set-cdr!`
@@ -47,7 +277,7 @@ pub enum Opcode {
     /// `pair?`
     IsPair,
 
-    /// Addition
+    /// Addition: stores `src` + `src2` in `dst`.
     Add,
 
     /// Subtraction
@@ -62,13 +292,14 @@ pub enum Opcode {
     /// Exponentiation
     Power,
 
-    /// Create an array
+    /// Create an array of the slots from `src` up to (but not including)
+    /// `src2`, in `dst`.
     MakeArray,
 
-    /// Store to an array
+    /// Store `src2` to element `src` of the array `dst`.
     SetArray,
 
-    /// Load from an array
+    /// Load element `src` of the array `src2` into `dst`.
     GetArray,
 
     /// Check for vector
@@ -77,52 +308,430 @@ pub enum Opcode {
     /// Length of vector
     ArrayLen,
 
-    /// Function call
+    /// Function call: calls the procedure in `src` with the `src2` slots
+    /// above it as arguments, and stores the result in `src`.
     Call,
 
-    /// Tail call
+    /// Tail call: like `Call`, but the callee replaces the current frame.
     TailCall,
 
-    /// Return from a function
+    /// Return `src` from the function.
     Return,
 
-    /// Create a closure
+    /// Create a closure in `dst`.  `src` and `src2` are the low and high
+    /// bytes of the index of the function, relative to the first function
+    /// of the program.  Its upvalues (`Function::upvalues` of them) are the
+    /// slots starting at `dst`.
     Closure,
 
-    /// Mutation of stack slots
+    /// Mutation of stack slots: copies `src` to `dst`.
     Set,
 
-    /// Load from constant vector
+    /// Load constant `src` into `dst`.
     LoadConstant,
 
-    /// Load from environment
+    /// Load upvalue `src` into `dst`.
     LoadEnvironment,
 
-    /// Load from argument
+    /// Load the local variable in `src` into `dst` (the same as `Set`).
     LoadArgument,
 
-    /// Load from global
+    /// Load the global named by the symbol in `src` into `dst`.
     LoadGlobal,
 
-    /// Load `#f`
+    /// Load `#f` into `dst`.
     LoadFalse,
 
-    /// Load `#t`
+    /// Load `#t` into `dst`.
     LoadTrue,
 
-    /// Load the empty list
+    /// Load the empty list into `dst`.
     LoadNil,
 
-    /// Store to environment.  `src` is the stack index of the source.
-    /// `dst` is the stack index of the destination.
+    /// Store `src` to upvalue `dst`.
     StoreEnvironment,
 
-    /// Store to argument.  `src` is the index of the argument.
+    /// Store `src` to the local variable in `dst` (the same as `Set`).
     StoreArgument,
 
-    /// Store to global.  `src` is the index of the global in the constants
-    /// vector.
+    /// Store `src` to the global named by constant `src2`.
     StoreGlobal,
+
+    /// Numeric `<`.  Stores whether `src` is less than `src2` in `dst`.
+    Less,
+
+    /// Numeric `=`.  Stores whether `src` is equal to `src2` in `dst`.
+    NumEqual,
+
+    /// Unconditional jump.  The target is `Bytecode::jump_target`.
+    Jump,
+
+    /// Jump if `dst` is `#f`.
+    JumpIfFalse,
+
+    /// Jump if `dst` is not `#f`.
+    JumpIfTrue,
+
+    /// Load a record field.  `src` is the record, `src2` is the symbol
+    /// naming the field, and `dst` is the destination.  The field offset is
+    /// cached per instruction (see `record`).
+    RecordRef,
+
+    /// Store to a record field.  `dst` is the record, `src2` is the symbol
+    /// naming the field, and `src` is the new value.
+    RecordSet,
+
+    /// Does nothing.  Frames used to grow and shrink, and this popped `src`
+    /// values; it keeps its number so that the opcodes after it keep
+    /// theirs.
+    Pop,
+
+    /// Load the unspecified value into `dst`.
+    LoadUnspecified,
+
+    /// `apply`: calls the procedure in `src`, with all but the last of the
+    /// `src2` slots above it as the first arguments, and the elements of the
+    /// last, which must be a list, as the rest.  A tail call if `dst` is 1.
+    Apply,
+
+    /// Load the global named by constant `src` into `dst`: a
+    /// `LoadConstant` and a `LoadGlobal` in one instruction (see
+    /// `compiler::peephole`).
+    LoadConstantGlobal,
+
+    /// Stores `src` plus `src2`, a signed byte, in `dst`: an `Add` or
+    /// `Subtract` of a small constant.
+    AddConst,
+
+    /// Jump unless `dst` is less than `dst + 1`: a `Less` and a
+    /// `JumpIfFalse` of its result in one instruction.  The target is
+    /// `Bytecode::jump_target`.
+    LessJumpIfFalse,
+
+    /// Jump unless `dst` is numerically equal to `dst + 1`.
+    NumEqualJumpIfFalse,
+}
+
+/// What each opcode does, in order, so that `OPCODES[op as usize]` describes
+/// `op`.  New opcodes must be added at the end of both, since compiled-object
+/// files refer to opcodes by number.
+///
+/// This is the one description of the instruction set that tools use: the
+/// opcode reference (`opcode_table`, `(opcode-table)`, the REPL's
+/// `,opcodes`, and `rusty_scheme opcodes`) is generated from it, and a new
+/// opcode cannot be decoded until it is here.
+static OPCODES: [OpcodeInfo; 45] = {
+    use self::Effect::*;
+    use self::Operand::*;
+    [
+        OpcodeInfo { opcode: Opcode::Cons, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (cons src src2)" },
+        OpcodeInfo { opcode: Opcode::Car, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = (car src)" },
+        OpcodeInfo { opcode: Opcode::Cdr, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = (cdr src)" },
+        OpcodeInfo { opcode: Opcode::SetCar, operands: [Slot, Unused, Slot], effect: Object,
+                     summary: "(set-car! dst src)" },
+        OpcodeInfo { opcode: Opcode::SetCdr, operands: [Slot, Unused, Slot], effect: Object,
+                     summary: "(set-cdr! dst src)" },
+        OpcodeInfo { opcode: Opcode::IsPair, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = (pair? src)" },
+        OpcodeInfo { opcode: Opcode::Add, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (+ src src2)" },
+        OpcodeInfo { opcode: Opcode::Subtract, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (- src src2)" },
+        OpcodeInfo { opcode: Opcode::Multiply, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (* src src2)" },
+        OpcodeInfo { opcode: Opcode::Divide, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (/ src src2)" },
+        OpcodeInfo { opcode: Opcode::Power, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (expt src src2)" },
+        OpcodeInfo { opcode: Opcode::MakeArray, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = a vector of the slots from src up to src2" },
+        OpcodeInfo { opcode: Opcode::SetArray, operands: [Slot, Slot, Slot], effect: Object,
+                     summary: "(vector-set! dst src src2)" },
+        OpcodeInfo { opcode: Opcode::GetArray, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (vector-ref src2 src)" },
+        OpcodeInfo { opcode: Opcode::IsArray, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = (vector? src)" },
+        OpcodeInfo { opcode: Opcode::ArrayLen, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = (vector-length src)" },
+        OpcodeInfo { opcode: Opcode::Call, operands: [Slot, Count, Unused], effect: Src,
+                     summary: "src = (src arguments...), the src2 slots after src" },
+        OpcodeInfo { opcode: Opcode::TailCall, operands: [Slot, Count, Unused], effect: Frame,
+                     summary: "tail call (src arguments...), the src2 slots after src" },
+        OpcodeInfo { opcode: Opcode::Return, operands: [Slot, Unused, Unused], effect: Frame,
+                     summary: "return src" },
+        OpcodeInfo { opcode: Opcode::Closure, operands: [Function, Function, Slot], effect: Dst,
+                     summary: "dst = a closure of a function, over the slots from dst" },
+        OpcodeInfo { opcode: Opcode::Set, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = src" },
+        OpcodeInfo { opcode: Opcode::LoadConstant, operands: [Constant, Unused, Slot], effect: Dst,
+                     summary: "dst = constant src" },
+        OpcodeInfo { opcode: Opcode::LoadEnvironment, operands: [Upvalue, Unused, Slot],
+                     effect: Dst, summary: "dst = upvalue src" },
+        OpcodeInfo { opcode: Opcode::LoadArgument, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = src" },
+        OpcodeInfo { opcode: Opcode::LoadGlobal, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = the global named by the symbol src" },
+        OpcodeInfo { opcode: Opcode::LoadFalse, operands: [Unused, Unused, Slot], effect: Dst,
+                     summary: "dst = #f" },
+        OpcodeInfo { opcode: Opcode::LoadTrue, operands: [Unused, Unused, Slot], effect: Dst,
+                     summary: "dst = #t" },
+        OpcodeInfo { opcode: Opcode::LoadNil, operands: [Unused, Unused, Slot], effect: Dst,
+                     summary: "dst = '()" },
+        OpcodeInfo { opcode: Opcode::StoreEnvironment, operands: [Slot, Unused, Upvalue],
+                     effect: Environment, summary: "upvalue dst = src" },
+        OpcodeInfo { opcode: Opcode::StoreArgument, operands: [Slot, Unused, Slot], effect: Dst,
+                     summary: "dst = src" },
+        OpcodeInfo { opcode: Opcode::StoreGlobal, operands: [Slot, Constant, Unused],
+                     effect: Global, summary: "the global named by constant src2 = src" },
+        OpcodeInfo { opcode: Opcode::Less, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (< src src2)" },
+        OpcodeInfo { opcode: Opcode::NumEqual, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = (= src src2)" },
+        OpcodeInfo { opcode: Opcode::Jump, operands: [Target, Target, Unused], effect: Nothing,
+                     summary: "jump" },
+        OpcodeInfo { opcode: Opcode::JumpIfFalse, operands: [Target, Target, Slot],
+                     effect: Nothing, summary: "jump if dst is #f" },
+        OpcodeInfo { opcode: Opcode::JumpIfTrue, operands: [Target, Target, Slot],
+                     effect: Nothing, summary: "jump unless dst is #f" },
+        OpcodeInfo { opcode: Opcode::RecordRef, operands: [Slot, Slot, Slot], effect: Dst,
+                     summary: "dst = the field of src named by src2" },
+        OpcodeInfo { opcode: Opcode::RecordSet, operands: [Slot, Slot, Slot], effect: Object,
+                     summary: "the field of dst named by src2 = src" },
+        OpcodeInfo { opcode: Opcode::Pop, operands: [Unused, Unused, Unused], effect: Nothing,
+                     summary: "nothing" },
+        OpcodeInfo { opcode: Opcode::LoadUnspecified, operands: [Unused, Unused, Slot],
+                     effect: Dst, summary: "dst = the unspecified value" },
+        OpcodeInfo { opcode: Opcode::Apply, operands: [Slot, Count, Flag], effect: Src,
+                     summary: "src = (apply src arguments...), a tail call if dst is 1" },
+        OpcodeInfo { opcode: Opcode::LoadConstantGlobal, operands: [Constant, Unused, Slot],
+                     effect: Dst, summary: "dst = the global named by constant src" },
+        OpcodeInfo { opcode: Opcode::AddConst, operands: [Slot, Immediate, Slot], effect: Dst,
+                     summary: "dst = (+ src src2)" },
+        OpcodeInfo { opcode: Opcode::LessJumpIfFalse, operands: [Target, Target, Slot],
+                     effect: Nothing, summary: "jump unless (< dst dst+1)" },
+        OpcodeInfo { opcode: Opcode::NumEqualJumpIfFalse, operands: [Target, Target, Slot],
+                     effect: Nothing, summary: "jump unless (= dst dst+1)" },
+    ]
+};
+
+/// What an operand of an instruction is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    /// Ignored.
+    Unused,
+
+    /// A slot of the frame.
+    Slot,
+
+    /// A constant of the function.
+    Constant,
+
+    /// An upvalue of the closure.
+    Upvalue,
+
+    /// A number of slots (the arguments of a call).
+    Count,
+
+    /// A signed byte.
+    Immediate,
+
+    /// 0 or 1.
+    Flag,
+
+    /// A byte of a jump target (see `Bytecode::jump_target`).
+    Target,
+
+    /// A byte of the index of a function (see `Opcode::Closure`).
+    Function,
+}
+
+/// What an instruction changes, besides which instruction runs next.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Effect {
+    /// Nothing: jumps, and `Pop`.
+    Nothing,
+
+    /// Slot `dst`.
+    Dst,
+
+    /// Slot `src`, which holds the procedure of a call until it returns.
+    Src,
+
+    /// The object in slot `dst`.
+    Object,
+
+    /// Upvalue `dst`.
+    Environment,
+
+    /// A global variable.
+    Global,
+
+    /// The frame, which the instruction leaves.
+    Frame,
+}
+
+impl Operand {
+    /// The name of the kind of operand, in the opcode reference.
+    pub fn name(self) -> &'static str {
+        match self {
+            Operand::Unused => "-",
+            Operand::Slot => "slot",
+            Operand::Constant => "constant",
+            Operand::Upvalue => "upvalue",
+            Operand::Count => "count",
+            Operand::Immediate => "immediate",
+            Operand::Flag => "flag",
+            Operand::Target => "target",
+            Operand::Function => "function",
+        }
+    }
+}
+
+impl Effect {
+    /// The name of the effect, in the opcode reference.
+    pub fn name(self) -> &'static str {
+        match self {
+            Effect::Nothing => "nothing",
+            Effect::Dst => "dst",
+            Effect::Src => "src",
+            Effect::Object => "object",
+            Effect::Environment => "upvalue",
+            Effect::Global => "global",
+            Effect::Frame => "frame",
+        }
+    }
+}
+
+/// The description of an opcode, in `OPCODES`.
+#[derive(Copy, Clone, Debug)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+
+    /// What `src`, `src2`, and `dst` are.
+    pub operands: [Operand; 3],
+
+    /// What the instruction changes.
+    pub effect: Effect,
+
+    /// What the instruction does, in terms of its operands.
+    pub summary: &'static str,
+}
+
+/// The opcode reference: a Markdown table of the number, name, operands,
+/// effect, and summary of every opcode.
+pub fn opcode_table() -> String {
+    use std::fmt::Write;
+
+    let mut out = "| # | opcode | src | src2 | dst | changes | does |\n".to_owned();
+    out.push_str("|---|---|---|---|---|---|---|\n");
+    for (i, info) in OPCODES.iter().enumerate() {
+        let operands = info.operands;
+        let _ = writeln!(out, "| {} | {:?} | {} | {} | {} | {} | {} |",
+                         i, info.opcode, operands[0].name(), operands[1].name(),
+                         operands[2].name(),
+                         info.effect.name(), info.summary);
+    }
+    out
+}
+
+impl Opcode {
+    /// The opcode numbered `x`, if there is one.
+    pub fn from_u8(x: u8) -> Option<Opcode> {
+        OPCODES.get(x as usize).map(|info| info.opcode)
+    }
+
+    /// The description of the opcode.
+    pub fn info(self) -> &'static OpcodeInfo {
+        &OPCODES[self as usize]
+    }
+
+    /// Does the opcode jump (to `Bytecode::jump_target`)?
+    pub fn is_jump(self) -> bool {
+        match self {
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::LessJumpIfFalse |
+            Opcode::NumEqualJumpIfFalse => true,
+            _ => false,
+        }
+    }
+}
+
+/// Describes the constant `x`, as it would be written in source code.
+/// Constants are atoms (see `Constant`), so no printer is needed.
+pub fn describe_constant(x: &value::Value) -> String {
+    if x.fixnump() {
+        return (x.get() as isize >> 2).to_string()
+    }
+    if let Some(s) = ::string::as_str(x) {
+        return format!("{:?}", s)
+    }
+    match x.kind() {
+        value::Kind::Symbol(ptr) => format!("'{}", unsafe { (*ptr).name() }),
+        _ => "#<object>".to_owned(),
+    }
+}
+
+/// Disassembles `code`, one instruction per line: its index, opcode, and
+/// operands, followed by what the operands refer to.  Jump and closure
+/// operands are relative (to the entry, and the first function, of the
+/// code), like the operands themselves; `constant(i)` describes constant
+/// `i` of the code.  Instructions that are jumped to are marked with `>`.
+pub fn disassemble_code(code: &[Bytecode], constant: &Fn(usize) -> Option<String>) -> String {
+    use std::fmt::Write;
+
+    let targets: Vec<_> =
+        code.iter().filter(|op| op.opcode.is_jump()).map(Bytecode::jump_target).collect();
+    let mut out = String::new();
+    for (i, op) in code.iter().enumerate() {
+        let marker = if targets.contains(&i) { '>' } else { ' ' };
+        let opcode = format!("{:?}", op.opcode);
+        let _ = write!(out, "{} {:4}  {:<16}{:3} {:3} {:3}",
+                       marker, i, opcode, op.src, op.src2, op.dst);
+        match op.opcode {
+            _ if op.opcode.is_jump() => {
+                let _ = write!(out, "  ; -> {}", op.jump_target());
+            }
+            Opcode::LoadConstant | Opcode::LoadConstantGlobal => {
+                let x = constant(op.src as usize).unwrap_or_else(|| "out of range".to_owned());
+                let _ = write!(out, "  ; {}", x);
+            }
+            Opcode::StoreGlobal => {
+                let x = constant(op.src2 as usize).unwrap_or_else(|| "out of range".to_owned());
+                let _ = write!(out, "  ; {}", x);
+            }
+            Opcode::Closure => {
+                let _ = write!(out, "  ; function {}", op.src as usize | (op.src2 as usize) << 8);
+            }
+            _ => {}
+        }
+        out.push('\n')
+    }
+    out
+}
+
+/// Disassembles the BCO `bco` (see `disassemble_code`).
+pub fn disassemble(bco: &BCO) -> String {
+    let bytes = unsafe {
+        let start = (bco as *const BCO as *const u8).offset(size_of!(BCO) as isize);
+        ::std::slice::from_raw_parts(start, bco.bytecode_length)
+    };
+    let mut code = vec![];
+    for op in bytes.chunks(4) {
+        match (Opcode::from_u8(op[0]), op.len()) {
+            (Some(opcode), 4) => {
+                code.push(Bytecode { opcode: opcode, src: op[1], src2: op[2], dst: op[3] })
+            }
+            _ => break,
+        }
+    }
+    let constant = |i| get_constant(bco, i).ok().map(|x| describe_constant(&x));
+    let mut out = disassemble_code(&code, &constant);
+    if code.len() * 4 < bytes.len() {
+        out.push_str("; bad instruction\n")
+    }
+    out
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -133,121 +742,667 @@ pub struct Bytecode {
     pub dst: u8,
 }
 
+impl Bytecode {
+    /// Creates a jump to the instruction index `target`.  `cond` is
+    /// the stack index of the condition, and is ignored by `Jump`.
+    pub fn jump(opcode: Opcode, target: u16, cond: u8) -> Self {
+        Bytecode {
+            opcode: opcode,
+            src: target as u8,
+            src2: (target >> 8) as u8,
+            dst: cond,
+        }
+    }
+
+    /// The target of a jump: `src` holds the low byte and `src2` the high
+    /// byte of an instruction index, relative to the start of the function.
+    pub fn jump_target(&self) -> usize {
+        self.src as usize | (self.src2 as usize) << 8
+    }
+
+    /// Does this leave the function by calling another in its place: a
+    /// `TailCall`, or an `Apply` that is a tail call?
+    pub fn is_tail_call(&self) -> bool {
+        match self.opcode {
+            Opcode::TailCall => true,
+            Opcode::Apply => self.dst == 1,
+            _ => false,
+        }
+    }
+
+    /// Can the next instruction run after this one?  Not after a `Jump`, a
+    /// `Return`, or a tail call.
+    pub fn falls_through(&self) -> bool {
+        match self.opcode {
+            Opcode::Jump | Opcode::Return => false,
+            _ => !self.is_tail_call(),
+        }
+    }
+}
+
+/// A basic block of the code of a function: the instructions from `start`
+/// up to `end`.  Only the first can be jumped to, and only the last can
+/// jump, return or make a tail call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+
+    /// The indices of the blocks that can run next.
+    pub successors: Vec<usize>,
+}
+
+/// The control flow graph of `code`, the code of a function: its basic
+/// blocks, in order.  Jumps out of the code are left out, since `verify`
+/// rejects them.
+pub fn blocks(code: &[Bytecode]) -> Vec<Block> {
+    let mut starts = vec![false; code.len() + 1];
+    starts[0] = true;
+    for (i, op) in code.iter().enumerate() {
+        if op.opcode.is_jump() || !op.falls_through() {
+            starts[i + 1] = true
+        }
+        if op.opcode.is_jump() && op.jump_target() < code.len() {
+            starts[op.jump_target()] = true
+        }
+    }
+    let starts: Vec<_> = (0..code.len()).filter(|&i| starts[i]).collect();
+    let mut block_of = vec![0; code.len()];
+    for (b, &start) in starts.iter().enumerate() {
+        let end = starts.get(b + 1).cloned().unwrap_or(code.len());
+        for i in start..end {
+            block_of[i] = b
+        }
+    }
+    starts.iter()
+          .enumerate()
+          .map(|(b, &start)| {
+              let end = starts.get(b + 1).cloned().unwrap_or(code.len());
+              let last = code[end - 1];
+              let mut successors = vec![];
+              if last.falls_through() && end < code.len() {
+                  successors.push(b + 1)
+              }
+              if last.opcode.is_jump() && last.jump_target() < code.len() &&
+                 !successors.contains(&block_of[last.jump_target()]) {
+                  successors.push(block_of[last.jump_target()])
+              }
+              Block { start: start, end: end, successors: successors }
+          })
+          .collect()
+}
+
+/// Which instructions of `code`, the code of a function, can run: those
+/// in the blocks that can be reached from the entry.
+pub fn reachable(code: &[Bytecode]) -> Vec<bool> {
+    let blocks = blocks(code);
+    let mut reached = vec![false; blocks.len()];
+    let mut todo = if blocks.is_empty() { vec![] } else { vec![0] };
+    while let Some(b) = todo.pop() {
+        if !reached[b] {
+            reached[b] = true;
+            todo.extend(&blocks[b].successors)
+        }
+    }
+    let mut instructions = vec![false; code.len()];
+    for (block, _) in blocks.iter().zip(reached).filter(|&(_, reached)| reached) {
+        for i in block.start..block.end {
+            instructions[i] = true
+        }
+    }
+    instructions
+}
+
+/// Optimizes the control flow of `code`, the code of a function, until
+/// nothing changes: jumps to unconditional jumps jump to their targets
+/// instead, jumps to the next instruction are removed, and so are the
+/// blocks that can never run.  Returns whether anything changed.
+///
+/// Only jumps that test a slot are removed, not those that compare two,
+/// which fail if the slots are not numbers.
+pub fn optimize(code: &mut Vec<Bytecode>) -> bool {
+    let mut changed = false;
+    while thread_jumps(code) | remove_jumps_to_next(code) | remove_unreachable(code) {
+        changed = true
+    }
+    changed
+}
+
+/// Makes each jump to an unconditional jump jump to where that one does,
+/// as many times as it takes.  Returns whether anything changed.
+fn thread_jumps(code: &mut [Bytecode]) -> bool {
+    let mut changed = false;
+    for i in 0..code.len() {
+        if !code[i].opcode.is_jump() {
+            continue
+        }
+        let mut target = code[i].jump_target();
+        // A loop of jumps that jump to each other runs forever either way.
+        for _ in 0..code.len() {
+            match code.get(target) {
+                Some(next) if next.opcode as u8 == Opcode::Jump as u8 &&
+                              next.jump_target() != target => target = next.jump_target(),
+                _ => break,
+            }
+        }
+        if target != code[i].jump_target() {
+            let Bytecode { opcode, dst, .. } = code[i];
+            code[i] = Bytecode::jump(opcode, target as u16, dst);
+            changed = true
+        }
+    }
+    changed
+}
+
+/// Removes the jumps to the next instruction, which do nothing.  Returns
+/// whether anything changed.
+fn remove_jumps_to_next(code: &mut Vec<Bytecode>) -> bool {
+    let keep: Vec<_> = code.iter()
+                           .enumerate()
+                           .map(|(i, op)| {
+                               match op.opcode {
+                                   Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue => {
+                                       op.jump_target() != i + 1
+                                   }
+                                   _ => true,
+                               }
+                           })
+                           .collect();
+    retain(code, &keep)
+}
+
+/// Removes the instructions of `code`, the code of a function, that can
+/// never run.  Returns whether there were any.
+pub fn remove_unreachable(code: &mut Vec<Bytecode>) -> bool {
+    let keep = reachable(code);
+    retain(code, &keep)
+}
+
+/// Keeps only the instructions of `code` that `keep` says to.  A jump to
+/// one that is removed jumps to the next one kept instead, so only those
+/// that do nothing, or never run, can be removed.  Returns whether any
+/// were.
+fn retain(code: &mut Vec<Bytecode>, keep: &[bool]) -> bool {
+    if keep.iter().all(|&keep| keep) {
+        return false
+    }
+    // Where each instruction ends up.
+    let mut moved = vec![0; code.len() + 1];
+    let mut out = Vec::with_capacity(code.len());
+    for (i, &op) in code.iter().enumerate() {
+        moved[i] = out.len();
+        if keep[i] {
+            out.push(op)
+        }
+    }
+    moved[code.len()] = out.len();
+    for op in &mut out {
+        if op.opcode.is_jump() {
+            *op = Bytecode::jump(op.opcode, moved[op.jump_target()] as u16, op.dst)
+        }
+    }
+    *code = out;
+    true
+}
+
+/// A compiled function.  Its code starts at `entry`, and runs until the
+/// entry of the next function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    /// The index of the first instruction.  Jump targets are relative to it.
+    pub entry: usize,
+
+    /// The number of required arguments.
+    pub nargs: usize,
+
+    /// Are extra arguments collected into a list?
+    pub rest: bool,
+
+    /// The part of the constants vector used by the function.
+    pub constants: ConstantRange,
+
+    /// The index of the first function of the same program.  `Closure`
+    /// operands are relative to it.
+    pub first: usize,
+
+    /// The number of slots in the frame of the function: the closure, the
+    /// arguments, the local variables, and the temporaries.
+    pub frame_size: usize,
+
+    /// The number of upvalues of the closures of the function.
+    pub upvalues: usize,
+}
+
+/// Where a function came from, for backtraces.  The reader only knows the
+/// line that each top-level form starts on, so that is the line of every
+/// function in it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Source {
+    /// The name the function was defined with, if any.
+    pub name: Option<String>,
+
+    /// The file that it was read from, if any.
+    pub file: Option<String>,
+
+    /// The line of the top-level form it is part of, starting at 1, or 0
+    /// if that is not known.
+    pub line: u32,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(f.write_str(self.name.as_ref().map_or("<lambda>", |name| name)));
+        match (&self.file, self.line) {
+            (_, 0) => Ok(()),
+            (&Some(ref file), line) => write!(f, " at {}:{}", file, line),
+            (&None, line) => write!(f, " at line {}", line),
+        }
+    }
+}
+
+/// Why `verify` rejected the code of a function.  `index` is the index of
+/// the instruction, relative to the entry of the function.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum BadByteCode {
-    StackUnderflow {
+    SlotOutOfRange {
         index: usize,
-        depth: usize,
-        min: usize,
+        slot: usize,
+        frame_size: usize,
     },
     EnvOutOfRange {
         index: usize,
         required_length: usize,
         actual_length: usize,
     },
+    ConstantOutOfRange {
+        index: usize,
+        constant: usize,
+    },
+    JumpOutOfRange {
+        index: usize,
+        target: usize,
+    },
+    FunctionOutOfRange {
+        index: usize,
+        function: usize,
+    },
+    FrameTooSmall {
+        frame_size: usize,
+    },
+    RunsOffEnd {
+        index: usize,
+    },
+    Unreachable {
+        index: usize,
+    },
+    TailCallNotInTailPosition {
+        index: usize,
+    },
+}
+
+impl fmt::Display for BadByteCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BadByteCode::SlotOutOfRange { index, slot, frame_size } => {
+                write!(f, "instruction {}: slot {} out of range (frame has {} slots)",
+                       index, slot, frame_size)
+            }
+            BadByteCode::EnvOutOfRange { index, required_length, actual_length } => {
+                write!(f, "instruction {}: needs {} upvalues, but closures have {}",
+                       index, required_length, actual_length)
+            }
+            BadByteCode::ConstantOutOfRange { index, constant } => {
+                write!(f, "instruction {}: constant {} out of range", index, constant)
+            }
+            BadByteCode::JumpOutOfRange { index, target } => {
+                write!(f, "instruction {}: jump to {} out of range", index, target)
+            }
+            BadByteCode::FunctionOutOfRange { index, function } => {
+                write!(f, "instruction {}: function {} out of range", index, function)
+            }
+            BadByteCode::FrameTooSmall { frame_size } => {
+                write!(f, "frame of {} slots too small for the arguments", frame_size)
+            }
+            BadByteCode::RunsOffEnd { index } => {
+                write!(f, "instruction {}: runs off the end of the code", index)
+            }
+            BadByteCode::Unreachable { index } => {
+                write!(f, "instruction {}: unreachable", index)
+            }
+            BadByteCode::TailCallNotInTailPosition { index } => {
+                write!(f, "instruction {}: tail call followed by code that only it leads to",
+                       index)
+            }
+        }
+    }
 }
 
-pub fn allocate_bytecode(obj: &[u8], heap: &mut alloc::Heap) {
+/// Allocates a BCO containing the bytecodes `obj`.  The constants vector
+/// is popped from the stack, and the BCO uses its `constants` range.
+pub fn allocate_bytecode(obj: &[u8],
+                         constants: ConstantRange,
+                         heap: &mut alloc::Heap)
+                         -> Result<(), alloc::OutOfMemory> {
     use value::HeaderTag;
-    let (val, _) = heap.alloc_raw((size_of!(BCO) + obj.len() + (size_of!(usize) - 1)) /
-                                  size_of!(value::Value),
-                                  HeaderTag::Bytecode);
+    let (val, final_len) = try!(heap.alloc_raw((size_of!(BCO) + obj.len() +
+                                                (size_of!(usize) - 1)) /
+                                               size_of!(value::Value),
+                                               HeaderTag::Bytecode));
     let bco_obj = val as *mut BCO;
     let consts_vector = heap.stack.pop().unwrap();
     heap.stack.push(value::Value::new(val as usize | value::RUST_DATA_TAG));
     unsafe {
+        heap.finish_raw(final_len);
         (*bco_obj).bytecode_length = obj.len();
+        (*bco_obj).constants = constants;
         (*(*bco_obj).constants_vector.get()) = consts_vector;
         ptr::copy_nonoverlapping(obj.as_ptr(),
                                  (val as *mut u8).offset(size_of!(BCO) as isize),
                                  obj.len())
     }
+    Ok(())
 }
 
 pub enum SchemeResult {
     BadBytecode(BadByteCode),
 }
-#[cfg(none)]
-pub fn verify_bytecodes(b: &[Bytecode],
-                        argcount: u16,
-                        is_vararg: bool,
-                        environment_length: usize)
-                        -> Result<(), BadByteCode> {
-    let argcount: usize = argcount.into();
-    let mut i = 0;
-    let mut max_stack = 0;
-    let mut current_depth: usize = 0;
-    let mut current_stack: Vec<usize> = vec![];
-    let iter = b.iter();
-
-    macro_rules! check_stack {
-        ($min: expr) => (if current_depth <= $exp {
-            return Err(BadByteCode::StackUnderflow { index: i - 1,
-                                                     depth: current_depth,
-                                                     min: $min, })
-        } else {})
-    }
-    macro_rules! check_argument {
-        ($min: expr) => (if argcount <= $expr {
-            return Err(BadByteCode::StackUnderflow { index: i - 1,
-                                                     depth: current_depth,
-                                                     min: $min, })
-        } else {})
-    }
-    macro_rules! check_env {
-        ($min: expr) => (if environment_length <= $expr {
-            return Err(BadByteCode::EnvOutOfRange { index: i - 1,
-                                                    required_length: $expr + 1,
-                                                    actual_length:
-                                                    environment_length,
-            })
-        })
+
+/// Checks that `code`, the code of function `id` of `functions`, stays
+/// within its frame: that every slot operand is below its frame size, and
+/// every constant, upvalue, jump target, and function it refers to exists.
+/// Since frames do not grow or shrink, this needs no analysis of the flow
+/// of the code – each instruction is checked on its own.
+///
+/// Then it checks that control stays within the code, and that a tail
+/// call is only ever in tail position: every instruction can be reached
+/// from the entry, none runs off the end of the code, and so none comes
+/// after a tail call unless a jump lands on it.  A tail call replaces the
+/// frame, so code that is only run after one would never run at all; a
+/// compiler that emits it is using `TailCall` for a call whose value it
+/// needs, which would return to the wrong frame.
+///
+/// Whether operands have the right types is checked when the code runs.
+pub fn verify(functions: &[Function], id: usize, code: &[Bytecode]) -> Result<(), BadByteCode> {
+    let function = functions[id];
+    let size = function.frame_size;
+    if size < 1 + function.nargs + function.rest as usize {
+        return Err(BadByteCode::FrameTooSmall { frame_size: size })
     }
-    while let Some(opcode) = iter.next() {
-        i += 1;
-        match try!(byte_to_opcode(opcode)) {
-            Opcode::Cons => {
-                check_stack!(2);
-                current_depth -= 1;
+    for (i, op) in code.iter().enumerate() {
+        let (src, src2, dst) = (op.src as usize, op.src2 as usize, op.dst as usize);
+        let slots = |slots: &[usize]| match slots.iter().find(|&&slot| slot >= size) {
+            Some(&slot) => {
+                Err(BadByteCode::SlotOutOfRange { index: i, slot: slot, frame_size: size })
             }
-            Opcode::Car | Opcode::Cdr => {
-                check_stack!(1);
-            }
-            Opcode::SetCar | Opcode::SetCdr => {
-                check_stack!(2);
-            }
-            Opcode::IsPair => {
-                check_stack!(1);
-            }
-            Opcode::PushTrue | Opcode::PushFalse | Opcode::PushNil => {
-                current_depth += 1;
+            None => Ok(()),
+        };
+        let constant = |constant: usize| if constant < function.constants.len {
+            Ok(())
+        } else {
+            Err(BadByteCode::ConstantOutOfRange { index: i, constant: constant })
+        };
+        let upvalue = |upvalue: usize| if upvalue < function.upvalues {
+            Ok(())
+        } else {
+            Err(BadByteCode::EnvOutOfRange {
+                index: i,
+                required_length: upvalue + 1,
+                actual_length: function.upvalues,
+            })
+        };
+        match op.opcode {
+            Opcode::Cons | Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide |
+            Opcode::Power | Opcode::SetArray | Opcode::GetArray | Opcode::Less |
+            Opcode::NumEqual | Opcode::RecordRef | Opcode::RecordSet => {
+                try!(slots(&[src, src2, dst]))
             }
-            Opcode::LoadGlobal | Opcode::LoadConstant => {
-                check_env!(src);
-                current_depth += 1;
+            Opcode::Car | Opcode::Cdr | Opcode::SetCar | Opcode::SetCdr | Opcode::IsPair |
+            Opcode::IsArray | Opcode::ArrayLen | Opcode::Set | Opcode::LoadArgument |
+            Opcode::StoreArgument | Opcode::LoadGlobal | Opcode::AddConst => {
+                try!(slots(&[src, dst]))
             }
-            Opcode::LoadArgument => {
-                check_argument!(src);
-                current_depth += 1
+            Opcode::MakeArray => try!(slots(&[src, src2.saturating_sub(1), dst])),
+            Opcode::Call | Opcode::TailCall | Opcode::Apply => try!(slots(&[src + src2])),
+            Opcode::Return => try!(slots(&[src])),
+            Opcode::Closure => {
+                let nested = function.first + (src | src2 << 8);
+                match functions.get(nested) {
+                    Some(nested) => try!(slots(&[dst + nested.upvalues.saturating_sub(1)])),
+                    None => {
+                        return Err(BadByteCode::FunctionOutOfRange { index: i, function: nested })
+                    }
+                }
             }
-            Opcode::StoreArgument => {
-                check_argument!(src);
-                current_depth += 1
+            Opcode::LoadConstant | Opcode::LoadConstantGlobal => {
+                try!(constant(src));
+                try!(slots(&[dst]))
             }
             Opcode::LoadEnvironment => {
-                check_stack!(src);
-                current_depth += 1;
+                try!(upvalue(src));
+                try!(slots(&[dst]))
             }
-            Opcode::ArraySet => {
-                check_stack!(2);
-                current_depth -= 1;
+            Opcode::StoreEnvironment => {
+                try!(upvalue(dst));
+                try!(slots(&[src]))
             }
-            Opcode::ArrayGet => {
-                check_stack!(2);
+            Opcode::StoreGlobal => {
+                try!(constant(src2));
+                try!(slots(&[src]))
             }
-            Opcode::IsArray => {
-                check_stack!(1);
+            Opcode::LoadFalse | Opcode::LoadTrue | Opcode::LoadNil | Opcode::LoadUnspecified => {
+                try!(slots(&[dst]))
             }
-            Opcode::Vector => try!(iter.next().ok_or(BadByteCode::EOF)).into(),
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::LessJumpIfFalse |
+            Opcode::NumEqualJumpIfFalse => {
+                if op.jump_target() >= code.len() {
+                    return Err(BadByteCode::JumpOutOfRange { index: i, target: op.jump_target() })
+                }
+                match op.opcode {
+                    Opcode::LessJumpIfFalse | Opcode::NumEqualJumpIfFalse => {
+                        try!(slots(&[dst, dst + 1]))
+                    }
+                    _ => try!(slots(&[dst])),
+                }
+            }
+            Opcode::Pop => {}
+        }
+    }
+    let last = match code.len() {
+        0 => return Err(BadByteCode::RunsOffEnd { index: 0 }),
+        len => len - 1,
+    };
+    let reached = reachable(code);
+    if let Some(i) = reached.iter().position(|&reached| !reached) {
+        if i > 0 && code[i - 1].is_tail_call() {
+            return Err(BadByteCode::TailCallNotInTailPosition { index: i - 1 })
+        }
+        return Err(BadByteCode::Unreachable { index: i })
+    }
+    if code[last].falls_through() {
+        return Err(BadByteCode::RunsOffEnd { index: last })
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::Heap;
+    use super::*;
+
+    #[test]
+    fn functions_share_constants_within_reach() {
+        let mut pool = ConstantPool::default();
+        let mut f = FunctionConstants::default();
+        assert_eq!(pool.add(&mut f, Constant::Symbol("car".to_owned())), Ok(0));
+        assert_eq!(pool.add(&mut f, Constant::Fixnum(1)), Ok(1));
+        let mut g = FunctionConstants::default();
+        assert_eq!(pool.add(&mut g, Constant::Fixnum(1)), Ok(0));
+        assert_eq!(pool.add(&mut g, Constant::Str("x".to_owned())), Ok(1));
+        // `car` is before `g`'s range, so it is added again.
+        assert_eq!(pool.add(&mut g, Constant::Symbol("car".to_owned())), Ok(2));
+        assert_eq!(pool.len(), 4);
+        assert_eq!(f.range(), ConstantRange { start: 0, len: 2 });
+        assert_eq!(g.range(), ConstantRange { start: 1, len: 3 });
+        assert!(g.range().get(3).is_err());
+    }
+
+    #[test]
+    fn opcodes_are_numbered_in_order() {
+        for (i, info) in OPCODES.iter().enumerate() {
+            assert_eq!(info.opcode as usize, i);
+        }
+        assert!(Opcode::from_u8(OPCODES.len() as u8).is_none());
+    }
+
+    #[test]
+    fn functions_have_at_most_256_constants() {
+        let mut pool = ConstantPool::default();
+        let mut f = FunctionConstants::default();
+        for i in 0..256 {
+            assert_eq!(pool.add(&mut f, Constant::Fixnum(i)), Ok(i as u8));
+        }
+        assert!(pool.add(&mut f, Constant::Fixnum(256)).is_err());
+    }
+
+    #[test]
+    fn verify_keeps_code_within_its_frame() {
+        let op = |opcode, src, src2, dst| {
+            Bytecode { opcode: opcode, src: src, src2: src2, dst: dst }
+        };
+        let function = Function {
+            entry: 0,
+            nargs: 1,
+            rest: false,
+            constants: ConstantRange { start: 0, len: 1 },
+            first: 0,
+            frame_size: 3,
+            upvalues: 1,
+        };
+        let functions = [function];
+        let code = [op(Opcode::LoadConstant, 0, 0, 2),
+                    op(Opcode::Add, 1, 2, 1),
+                    op(Opcode::StoreEnvironment, 1, 0, 0),
+                    Bytecode::jump(Opcode::JumpIfFalse, 4, 1),
+                    op(Opcode::Closure, 0, 0, 2),
+                    op(Opcode::Return, 1, 0, 0)];
+        assert_eq!(verify(&functions, 0, &code), Ok(()));
+        let bad = |i: usize, x: Bytecode| {
+            let mut code = code.to_vec();
+            code[i] = x;
+            verify(&functions, 0, &code)
+        };
+        assert_eq!(bad(1, op(Opcode::Add, 1, 3, 1)),
+                   Err(BadByteCode::SlotOutOfRange { index: 1, slot: 3, frame_size: 3 }));
+        assert_eq!(bad(0, op(Opcode::LoadConstant, 1, 0, 2)),
+                   Err(BadByteCode::ConstantOutOfRange { index: 0, constant: 1 }));
+        assert!(bad(2, op(Opcode::StoreEnvironment, 1, 0, 1)).is_err());
+        assert!(bad(3, Bytecode::jump(Opcode::JumpIfFalse, 6, 1)).is_err());
+        assert!(bad(4, op(Opcode::Closure, 1, 0, 2)).is_err());
+        assert!(bad(5, op(Opcode::Call, 1, 2, 0)).is_err());
+        let small = [Function { frame_size: 1, ..function }];
+        assert!(verify(&small, 0, &[]).is_err());
+    }
+
+    #[test]
+    fn verify_keeps_control_within_the_code() {
+        let op = |opcode, src, src2, dst| {
+            Bytecode { opcode: opcode, src: src, src2: src2, dst: dst }
+        };
+        let function = Function {
+            entry: 0,
+            nargs: 1,
+            rest: false,
+            constants: ConstantRange { start: 0, len: 0 },
+            first: 0,
+            frame_size: 3,
+            upvalues: 0,
+        };
+        let functions = [function];
+        let code = [Bytecode::jump(Opcode::JumpIfFalse, 2, 1),
+                    op(Opcode::TailCall, 1, 0, 0),
+                    op(Opcode::LoadTrue, 0, 0, 2),
+                    op(Opcode::Return, 2, 0, 0)];
+        assert_eq!(verify(&functions, 0, &code), Ok(()));
+        let bad = |i: usize, x: Bytecode| {
+            let mut code = code.to_vec();
+            code[i] = x;
+            verify(&functions, 0, &code)
+        };
+        assert_eq!(bad(3, op(Opcode::Set, 2, 0, 1)),
+                   Err(BadByteCode::RunsOffEnd { index: 3 }));
+        assert_eq!(bad(0, Bytecode::jump(Opcode::Jump, 3, 0)),
+                   Err(BadByteCode::Unreachable { index: 1 }));
+        // Code after a tail call that no jump lands on.
+        assert_eq!(bad(0, op(Opcode::LoadFalse, 0, 0, 1)),
+                   Err(BadByteCode::TailCallNotInTailPosition { index: 1 }));
+        assert_eq!(bad(0, op(Opcode::Apply, 1, 0, 1)),
+                   Err(BadByteCode::TailCallNotInTailPosition { index: 0 }));
+        assert_eq!(verify(&functions, 0, &[]), Err(BadByteCode::RunsOffEnd { index: 0 }));
+    }
+
+    #[test]
+    fn optimize_threads_jumps_and_removes_dead_blocks() {
+        let op = |opcode, dst| Bytecode { opcode: opcode, src: 0, src2: 0, dst: dst };
+        let disassembly = |code: &[Bytecode]| disassemble_code(code, &|_| None);
+        let lines = |lines: &[&str]| {
+            lines.iter().map(|line| format!("{}\n", line)).collect::<String>()
+        };
+        let mut code = vec![Bytecode::jump(Opcode::JumpIfFalse, 3, 1),
+                            op(Opcode::LoadTrue, 2),
+                            Bytecode::jump(Opcode::Jump, 5, 0),
+                            op(Opcode::LoadFalse, 2),
+                            Bytecode::jump(Opcode::Jump, 5, 0),
+                            Bytecode::jump(Opcode::Jump, 6, 0),
+                            Bytecode { src: 2, ..op(Opcode::Return, 0) },
+                            op(Opcode::LoadNil, 2),
+                            Bytecode::jump(Opcode::Jump, 7, 0)];
+        assert_eq!(disassembly(&code),
+                   lines(&["     0  JumpIfFalse       3   0   1  ; -> 3",
+                           "     1  LoadTrue          0   0   2",
+                           "     2  Jump              5   0   0  ; -> 5",
+                           ">    3  LoadFalse         0   0   2",
+                           "     4  Jump              5   0   0  ; -> 5",
+                           ">    5  Jump              6   0   0  ; -> 6",
+                           ">    6  Return            2   0   0",
+                           ">    7  LoadNil           0   0   2",
+                           "     8  Jump              7   0   0  ; -> 7"]));
+        let successors: Vec<_> = blocks(&code).into_iter().map(|b| b.successors).collect();
+        assert_eq!(successors, [vec![1, 2], vec![3], vec![3], vec![4], vec![], vec![5]]);
+        assert!(optimize(&mut code));
+        assert_eq!(disassembly(&code),
+                   lines(&["     0  JumpIfFalse       3   0   1  ; -> 3",
+                           "     1  LoadTrue          0   0   2",
+                           "     2  Jump              4   0   0  ; -> 4",
+                           ">    3  LoadFalse         0   0   2",
+                           ">    4  Return            2   0   0"]));
+        assert!(!optimize(&mut code));
+        // Comparisons can fail, so they stay, and so does a jump to itself.
+        let mut code = vec![Bytecode::jump(Opcode::JumpIfTrue, 1, 1),
+                            Bytecode::jump(Opcode::LessJumpIfFalse, 2, 1),
+                            Bytecode::jump(Opcode::Jump, 2, 0)];
+        assert!(optimize(&mut code));
+        assert_eq!(disassembly(&code),
+                   lines(&["     0  LessJumpIfFalse   1   0   1  ; -> 1",
+                           ">    1  Jump              1   0   0  ; -> 1"]));
+    }
+
+    #[test]
+    fn bcos_see_their_own_constants() {
+        let mut heap = Heap::new(1 << 8);
+        let mut pool = ConstantPool::default();
+        let (mut f, mut g) = (FunctionConstants::default(), FunctionConstants::default());
+        pool.add(&mut f, Constant::Fixnum(5)).unwrap();
+        pool.add(&mut g, Constant::Fixnum(6)).unwrap();
+        pool.materialize(&mut heap).unwrap();
+        let constants = heap.stack[0].clone();
+        allocate_bytecode(&[0; 8], f.range(), &mut heap).unwrap();
+        heap.stack.push(constants);
+        allocate_bytecode(&[0; 8], g.range(), &mut heap).unwrap();
+        for (i, &start) in [0, 1].iter().enumerate() {
+            let bco = unsafe { &*(heap.stack[i].as_ptr() as *const BCO) };
+            assert_eq!(bco.constants, ConstantRange { start: start, len: 1 });
+            assert_eq!(get_constant(bco, 0).unwrap().as_fixnum(), Ok(5 + i));
+            assert!(get_constant(bco, 1).is_err());
         }
     }
 }
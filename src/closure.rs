@@ -1,5 +1,92 @@
-use value;
-use alloc;
-use arith;
+//! Closures.
+//!
+//! A closure is a heap object with tag `FUNCTION_TAG`, whose header has the
+//! `HeaderTag::Closure` tag:
+//!
+//! | header | function | constants vector | upvalue 0 | upvalue 1 | ... |
+//!
+//! The function is a fixnum: the index of the closure's code in the
+//! interpreter's function table (see `interp`).  Code is never freed, so
+//! the index stays valid for the lifetime of the interpreter.  The
+//! constants vector is the one shared by all functions of the program the
+//! code belongs to.  The upvalues are the values of the free variables of
+//! the function, in the order chosen by the compiler.
 
-pub fn 
+use std::slice;
+
+use value::{self, Value, Tags, HEADER_TAG};
+
+const CLOSURE: usize = value::HeaderTag::Closure as usize;
+
+/// The offset of the function index in a closure, in words.
+const FUNCTION_OFFSET: usize = 1;
+
+/// The offset of the constants vector in a closure, in words.
+const CONSTANTS_OFFSET: usize = 2;
+
+/// The offset of the first upvalue in a closure, in words.
+pub const UPVALUES_OFFSET: usize = 3;
+
+/// The words of the closure `x`, including the header, if it is one.
+fn words(x: &Value) -> Option<&[Value]> {
+    if x.tag() != Tags::Function {
+        return None
+    }
+    unsafe {
+        let ptr = x.as_ptr();
+        let header = (*ptr).get();
+        debug_assert_eq!(header & HEADER_TAG, CLOSURE);
+        Some(slice::from_raw_parts(ptr, header & !HEADER_TAG))
+    }
+}
+
+/// Is `x` a closure?
+pub fn is_closure(x: &Value) -> bool {
+    words(x).is_some()
+}
+
+/// The index of the function of the closure `x`.
+pub fn function(x: &Value) -> Result<usize, String> {
+    words(x)
+        .map(|words| words[FUNCTION_OFFSET].get() >> 2)
+        .ok_or_else(|| "Attempt to call a non-procedure".to_owned())
+}
+
+/// The constants vector of the closure `x`.
+pub fn constants(x: &Value) -> Value {
+    words(x).expect("not a closure")[CONSTANTS_OFFSET].clone()
+}
+
+/// Element `index` of the constants vector of the closure `x`.
+pub fn constant(x: &Value, index: usize) -> Result<Value, String> {
+    let constants = constants(x);
+    unsafe {
+        let ptr = constants.as_ptr();
+        let len = (*ptr).get() & !HEADER_TAG;
+        // Skip the header and the word after it.
+        if index + 2 < len {
+            Ok((*ptr.offset(index as isize + 2)).clone())
+        } else {
+            Err(format!("constant {} out of range", index))
+        }
+    }
+}
+
+/// Upvalue `index` of the closure `x`.
+pub fn upvalue(x: &Value, index: usize) -> Result<Value, String> {
+    words(x)
+        .expect("not a closure")
+        .get(UPVALUES_OFFSET + index)
+        .cloned()
+        .ok_or_else(|| format!("upvalue {} out of range", index))
+}
+
+/// Sets upvalue `index` of the closure `x` to `new`.  The caller must call
+/// the write barrier.
+pub fn set_upvalue(x: &Value, index: usize, new: Value) -> Result<(), String> {
+    words(x)
+        .expect("not a closure")
+        .get(UPVALUES_OFFSET + index)
+        .map(|slot| slot.set(new))
+        .ok_or_else(|| format!("upvalue {} out of range", index))
+}
@@ -0,0 +1,612 @@
+//! Code generation: turns expressions into bytecode.
+//!
+//! The generated code is for a register machine whose registers are the
+//! slots of a frame.  The slots are used like a stack: every expression
+//! stores its value in the slot above those in use, and the compiler tracks
+//! how many are in use, so that it always knows which slot each local
+//! variable and temporary is in.  The most that are ever in use is the size
+//! of the frame.  Register operands are bytes, so a function uses at most
+//! 256 slots.
+//!
+//! Each expression is compiled knowing what is done with its value (see
+//! `Cont`).  The body of a function returns it, and so does every
+//! expression in tail position in the body: the branches of an `if`, the
+//! last expression of a sequence, and the body of a `let`.  A call is a
+//! `TailCall` exactly when its value is returned, and any other call is a
+//! `Call`.  Code that can never run, such as the jump to the end of an
+//! `if` after a branch that loops back, is removed, since `bytecode::verify`
+//! rejects it.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::u16;
+
+use bytecode::{self, Bytecode, Opcode, Constant, ConstantPool, FunctionConstants, Function,
+               Source};
+use super::{Program, peephole};
+use super::datum::Datum;
+use super::syntax::{self, Expr, Lambda, Primitive, Var, VarInfo};
+
+/// What to do with the value of an expression.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Cont {
+    /// Leave it in the slot above those in use.
+    Push,
+
+    /// Return it from the function.
+    Return,
+}
+
+/// Where a variable lives.
+#[derive(Copy, Clone, Debug)]
+enum Location {
+    /// A frame slot.
+    Slot(usize),
+
+    /// An upvalue of the closure being run.
+    Upvalue(usize),
+}
+
+/// A named `let` compiled as a loop.
+#[derive(Copy, Clone, Debug)]
+struct Loop {
+    /// The first instruction of the body.
+    head: usize,
+
+    /// The first slot of the loop variables.
+    first: usize,
+
+    /// The number of loop variables.
+    count: usize,
+}
+
+/// A function being compiled.
+#[derive(Default)]
+struct FunctionBuilder {
+    code: Vec<Bytecode>,
+    constants: FunctionConstants,
+
+    /// The number of frame slots in use, including slot 0 (the closure).
+    depth: usize,
+
+    /// The most slots ever in use: the size of the frame.
+    size: usize,
+    locations: HashMap<Var, Location>,
+    loops: HashMap<Var, Loop>,
+}
+
+impl FunctionBuilder {
+    fn emit(&mut self, opcode: Opcode, src: usize, src2: usize, dst: usize) -> Result<(), String> {
+        if src > 0xFF || src2 > 0xFF || dst > 0xFF {
+            return Err("function too large: too many local variables or temporaries".to_owned())
+        }
+        self.size = cmp::max(self.size, self.depth);
+        self.code.push(Bytecode {
+            opcode: opcode,
+            src: src as u8,
+            src2: src2 as u8,
+            dst: dst as u8,
+        });
+        Ok(())
+    }
+
+    /// Emits an instruction that stores a value in a new slot.
+    fn push(&mut self, opcode: Opcode) -> Result<(), String> {
+        self.depth += 1;
+        let top = self.depth - 1;
+        self.emit(opcode, 0, 0, top)
+    }
+
+    /// Frees the topmost `count` slots.  No code is needed, since the frame
+    /// does not shrink.
+    fn pop(&mut self, count: usize) {
+        self.depth -= count
+    }
+
+    /// Emits a jump to `target`.  `cond` is the slot of the condition.
+    fn jump_to(&mut self, opcode: Opcode, target: usize, cond: usize) -> Result<(), String> {
+        if target > u16::MAX as usize || self.code.len() > u16::MAX as usize {
+            return Err("function too large: too many instructions".to_owned())
+        }
+        try!(self.emit(opcode, 0, 0, cond));
+        *self.code.last_mut().unwrap() = Bytecode::jump(opcode, target as u16, cond as u8);
+        Ok(())
+    }
+
+    /// Emits a forward jump, to be given its target by `patch`.
+    fn jump(&mut self, opcode: Opcode, cond: usize) -> Result<usize, String> {
+        try!(self.jump_to(opcode, 0, cond));
+        Ok(self.code.len() - 1)
+    }
+
+    /// Makes the jump at `at` jump to the next instruction.
+    fn patch(&mut self, at: usize) -> Result<(), String> {
+        let target = self.code.len();
+        if target > u16::MAX as usize {
+            return Err("function too large: too many instructions".to_owned())
+        }
+        let Bytecode { opcode, dst, .. } = self.code[at];
+        self.code[at] = Bytecode::jump(opcode, target as u16, dst);
+        Ok(())
+    }
+}
+
+/// The state of code generation.
+struct Codegen<'a> {
+    vars: &'a [VarInfo],
+    pool: ConstantPool,
+
+    /// Is the code of each function optimized (see `peephole`)?
+    optimize: bool,
+
+    /// The compiled functions, by index.  `None` while being compiled.
+    functions: Vec<Option<(Vec<Bytecode>, Function)>>,
+
+    /// Where the functions came from, by index.
+    sources: Vec<Source>,
+}
+
+/// Generates the code of a program, whose variables are `vars`, and
+/// optimizes it unless `optimize` is false.  `main` is the source of the
+/// main function.
+pub fn generate(vars: &[VarInfo],
+                body: &Expr,
+                optimize: bool,
+                main: Source)
+                -> Result<Program, String> {
+    let mut codegen = Codegen {
+        vars: vars,
+        pool: ConstantPool::default(),
+        optimize: optimize,
+        functions: vec![],
+        sources: vec![],
+    };
+    try!(codegen.function(&[], None, body, &[], &main));
+    let mut code = vec![];
+    let mut functions = vec![];
+    for function in codegen.functions {
+        let (function_code, mut function) = function.unwrap();
+        function.entry = code.len();
+        code.extend(function_code);
+        functions.push(function)
+    }
+    Ok(Program {
+        code: code,
+        functions: functions,
+        sources: codegen.sources,
+        constants: codegen.pool,
+    })
+}
+
+/// `datum` as a constant (see `bytecode::Constant`).
+fn frozen(datum: &Datum) -> Constant {
+    match *datum {
+        Datum::Fixnum(x) => Constant::Fixnum(x),
+        Datum::Flonum(x) => Constant::Flonum(x),
+        Datum::Ratio(n, d) => Constant::Ratio(n, d),
+        Datum::Char(c) => Constant::Char(c),
+        Datum::Bool(x) => Constant::Bool(x),
+        Datum::Nil => Constant::Nil,
+        Datum::Symbol(ref name) => Constant::Symbol(name.clone()),
+        Datum::Str(ref s) => Constant::Str(s.clone()),
+        Datum::Pair(ref pair) => Constant::Pair(Box::new((frozen(&pair.0), frozen(&pair.1)))),
+        Datum::Vector(ref items) => Constant::Vector(items.iter().map(frozen).collect()),
+    }
+}
+
+impl<'a> Codegen<'a> {
+    /// Compiles a function, whose upvalues are the variables `free`, and
+    /// returns its index.
+    fn function(&mut self,
+                params: &[Var],
+                rest: Option<Var>,
+                body: &Expr,
+                free: &[Var],
+                source: &Source)
+                -> Result<usize, String> {
+        let index = self.functions.len();
+        if index > u16::MAX as usize {
+            return Err("too many functions".to_owned())
+        }
+        self.functions.push(None);
+        self.sources.push(source.clone());
+        let mut f = FunctionBuilder::default();
+        f.depth = 1;
+        for &var in params.iter().chain(rest.iter()) {
+            f.locations.insert(var, Location::Slot(f.depth));
+            f.depth += 1
+        }
+        f.size = f.depth;
+        for (i, &var) in free.iter().enumerate() {
+            f.locations.insert(var, Location::Upvalue(i));
+        }
+        for (i, &var) in params.iter().chain(rest.iter()).enumerate() {
+            if self.vars[var].boxed {
+                try!(self.box_slot(&mut f, 1 + i))
+            }
+        }
+        try!(self.expr(&mut f, body, Cont::Return));
+        if self.optimize {
+            peephole::optimize(&mut f.code, &mut self.pool, &mut f.constants)
+        } else {
+            bytecode::remove_unreachable(&mut f.code);
+        }
+        let function = Function {
+            entry: 0,
+            nargs: params.len(),
+            rest: rest.is_some(),
+            constants: f.constants.range(),
+            first: 0,
+            frame_size: f.size,
+            upvalues: free.len(),
+        };
+        self.functions[index] = Some((f.code, function));
+        Ok(index)
+    }
+
+    fn finish(&mut self, f: &mut FunctionBuilder, cont: Cont) -> Result<(), String> {
+        match cont {
+            Cont::Push => Ok(()),
+            Cont::Return => {
+                let top = f.depth - 1;
+                f.emit(Opcode::Return, top, 0, 0)
+            }
+        }
+    }
+
+    /// Compiles `expr`.  Afterwards, one more slot is in use than before,
+    /// holding its value (if `cont` is `Return`, as far as the following
+    /// code is concerned).
+    fn expr(&mut self, f: &mut FunctionBuilder, expr: &Expr, cont: Cont) -> Result<(), String> {
+        let start = f.depth;
+        try!(self.expr_inner(f, expr, cont));
+        f.depth = start + 1;
+        Ok(())
+    }
+
+    fn expr_inner(&mut self, f: &mut FunctionBuilder, expr: &Expr, cont: Cont) -> Result<(), String> {
+        match *expr {
+            Expr::Constant(ref datum) => try!(self.constant(f, datum)),
+            Expr::Local(var) => try!(self.load(f, var)),
+            Expr::Global(ref name) => {
+                try!(self.load_constant(f, Constant::Symbol(name.clone())));
+                let top = f.depth - 1;
+                try!(f.emit(Opcode::LoadGlobal, top, 0, top))
+            }
+            Expr::SetLocal(var, ref value) => {
+                try!(self.expr(f, value, Cont::Push));
+                try!(self.store(f, var));
+                try!(f.push(Opcode::LoadUnspecified))
+            }
+            Expr::SetGlobal(ref name, ref value) => {
+                try!(self.expr(f, value, Cont::Push));
+                let index = try!(self.pool.add(&mut f.constants, Constant::Symbol(name.clone())));
+                let value = f.depth - 1;
+                try!(f.emit(Opcode::StoreGlobal, value, index as usize, 0));
+                f.pop(1);
+                try!(f.push(Opcode::LoadUnspecified))
+            }
+            Expr::If(ref test, ref then, ref otherwise) => {
+                return self.if_(f, test, then, otherwise.as_ref().map(|x| &**x), cont)
+            }
+            Expr::Lambda(ref lambda) => try!(self.closure(f, lambda)),
+            Expr::Sequence(ref exprs) => {
+                match exprs.split_last() {
+                    Some((last, init)) => {
+                        for x in init {
+                            try!(self.expr(f, x, Cont::Push));
+                            f.pop(1)
+                        }
+                        return self.expr(f, last, cont)
+                    }
+                    None => try!(f.push(Opcode::LoadUnspecified)),
+                }
+            }
+            Expr::Call(ref function, ref args) => return self.call(f, function, args, cont),
+            Expr::Or(ref exprs) => return self.or(f, exprs, cont),
+            Expr::Apply(ref function, ref args) => {
+                let base = f.depth;
+                try!(self.expr(f, function, Cont::Push));
+                for x in args {
+                    try!(self.expr(f, x, Cont::Push))
+                }
+                return f.emit(Opcode::Apply, base, args.len(), (cont == Cont::Return) as usize)
+            }
+            Expr::Primitive(primitive, ref args) => try!(self.primitive(f, primitive, args)),
+            Expr::Let(ref bindings, ref body) => {
+                let base = f.depth;
+                for &(_, ref init) in bindings {
+                    try!(self.expr(f, init, Cont::Push))
+                }
+                try!(self.bind_slots(f, base, bindings));
+                return self.scope(f, base, body, cont)
+            }
+            Expr::Letrec(ref bindings, ref body) => {
+                let base = f.depth;
+                for (i, &(var, _)) in bindings.iter().enumerate() {
+                    f.locations.insert(var, Location::Slot(base + i));
+                    try!(f.push(Opcode::LoadUnspecified));
+                    if self.vars[var].boxed {
+                        try!(self.box_slot(f, base + i))
+                    }
+                }
+                for &(var, ref init) in bindings {
+                    try!(self.expr(f, init, Cont::Push));
+                    try!(self.store(f, var))
+                }
+                return self.scope(f, base, body, cont)
+            }
+            Expr::Loop(name, ref bindings, ref body) => {
+                let base = f.depth;
+                for &(_, ref init) in bindings {
+                    try!(self.expr(f, init, Cont::Push))
+                }
+                // Loops jump back to where the boxed variables are boxed, so
+                // that each iteration has new boxes.
+                f.loops.insert(name,
+                               Loop {
+                                   head: f.code.len(),
+                                   first: base,
+                                   count: bindings.len(),
+                               });
+                try!(self.bind_slots(f, base, bindings));
+                return self.scope(f, base, body, cont)
+            }
+        }
+        self.finish(f, cont)
+    }
+
+    /// Compiles the body of a scope whose locals start at slot `base`, and
+    /// then (unless returning) moves its value into `base`, freeing the
+    /// slots of the locals.
+    fn scope(&mut self,
+             f: &mut FunctionBuilder,
+             base: usize,
+             body: &Expr,
+             cont: Cont)
+             -> Result<(), String> {
+        let locals = f.depth - base;
+        try!(self.expr(f, body, cont));
+        if cont == Cont::Push && locals > 0 {
+            let top = f.depth - 1;
+            try!(f.emit(Opcode::Set, top, 0, base));
+            f.pop(locals)
+        }
+        Ok(())
+    }
+
+    fn if_(&mut self,
+           f: &mut FunctionBuilder,
+           test: &Expr,
+           then: &Expr,
+           otherwise: Option<&Expr>,
+           cont: Cont)
+           -> Result<(), String> {
+        try!(self.expr(f, test, Cont::Push));
+        let cond = f.depth - 1;
+        let to_else = try!(f.jump(Opcode::JumpIfFalse, cond));
+        f.pop(1);
+        try!(self.expr(f, then, cont));
+        let to_end = match cont {
+            Cont::Push => Some(try!(f.jump(Opcode::Jump, 0))),
+            Cont::Return => None,
+        };
+        try!(f.patch(to_else));
+        f.depth = cond;
+        match otherwise {
+            Some(otherwise) => try!(self.expr(f, otherwise, cont)),
+            None => {
+                try!(f.push(Opcode::LoadUnspecified));
+                try!(self.finish(f, cont))
+            }
+        }
+        match to_end {
+            Some(at) => f.patch(at),
+            None => Ok(()),
+        }
+    }
+
+    /// Compiles `(or test...)`: each test but the last jumps to the end if
+    /// it is true, leaving its value in the slot of the result.
+    fn or(&mut self, f: &mut FunctionBuilder, exprs: &[Expr], cont: Cont) -> Result<(), String> {
+        let (last, init) = exprs.split_last().expect("or without tests");
+        let slot = f.depth;
+        let mut to_end = vec![];
+        for x in init {
+            try!(self.expr(f, x, Cont::Push));
+            to_end.push(try!(f.jump(Opcode::JumpIfTrue, slot)));
+            f.pop(1)
+        }
+        try!(self.expr(f, last, cont));
+        for at in to_end {
+            try!(f.patch(at))
+        }
+        match cont {
+            Cont::Push => Ok(()),
+            Cont::Return => f.emit(Opcode::Return, slot, 0, 0),
+        }
+    }
+
+    fn call(&mut self,
+            f: &mut FunctionBuilder,
+            function: &Expr,
+            args: &[Expr],
+            cont: Cont)
+            -> Result<(), String> {
+        if let Expr::Local(var) = *function {
+            if let Some(&target) = f.loops.get(&var) {
+                return self.loop_back(f, target, args)
+            }
+        }
+        let base = f.depth;
+        try!(self.expr(f, function, Cont::Push));
+        for x in args {
+            try!(self.expr(f, x, Cont::Push))
+        }
+        let opcode = match cont {
+            Cont::Push => Opcode::Call,
+            Cont::Return => Opcode::TailCall,
+        };
+        f.emit(opcode, base, args.len(), 0)
+    }
+
+    /// Compiles a call to the named `let` `target`: stores the new values of
+    /// the loop variables, and jumps back to the start of the body.
+    fn loop_back(&mut self, f: &mut FunctionBuilder, target: Loop, args: &[Expr]) -> Result<(), String> {
+        for x in args {
+            try!(self.expr(f, x, Cont::Push))
+        }
+        let values = f.depth - args.len();
+        for i in 0..target.count {
+            try!(f.emit(Opcode::StoreArgument, values + i, 0, target.first + i))
+        }
+        f.depth = target.first + target.count;
+        f.jump_to(Opcode::Jump, target.head, 0)
+    }
+
+    fn primitive(&mut self,
+                 f: &mut FunctionBuilder,
+                 primitive: Primitive,
+                 args: &[Expr])
+                 -> Result<(), String> {
+        for x in args {
+            try!(self.expr(f, x, Cont::Push))
+        }
+        let top = f.depth - 1;
+        let opcode = match primitive {
+            Primitive::Car => return f.emit(Opcode::Car, top, 0, top),
+            Primitive::Cdr => return f.emit(Opcode::Cdr, top, 0, top),
+            Primitive::Cons => Opcode::Cons,
+            Primitive::Add => Opcode::Add,
+            Primitive::Subtract => Opcode::Subtract,
+            Primitive::Multiply => Opcode::Multiply,
+            Primitive::Less => Opcode::Less,
+            Primitive::NumEqual => Opcode::NumEqual,
+            Primitive::Power => Opcode::Power,
+        };
+        try!(f.emit(opcode, top - 1, top, top - 1));
+        f.pop(1);
+        Ok(())
+    }
+
+    fn closure(&mut self, f: &mut FunctionBuilder, lambda: &Lambda) -> Result<(), String> {
+        let free = syntax::free_variables(lambda);
+        let index = try!(self.function(&lambda.params,
+                                       lambda.rest,
+                                       &lambda.body,
+                                       &free,
+                                       &lambda.source));
+        // Boxes are shared, not copied.
+        let base = f.depth;
+        for &var in &free {
+            try!(self.load_location(f, var))
+        }
+        f.depth = base + 1;
+        f.emit(Opcode::Closure, index & 0xFF, index >> 8, base)
+    }
+
+    fn load_constant(&mut self, f: &mut FunctionBuilder, constant: Constant) -> Result<(), String> {
+        let index = try!(self.pool.add(&mut f.constants, constant));
+        f.depth += 1;
+        let top = f.depth - 1;
+        f.emit(Opcode::LoadConstant, index as usize, 0, top)
+    }
+
+    /// Compiles code that loads `datum` into a new slot.  Lists are built
+    /// every time, but vectors are constants.
+    fn constant(&mut self, f: &mut FunctionBuilder, datum: &Datum) -> Result<(), String> {
+        match *datum {
+            Datum::Fixnum(x) => self.load_constant(f, Constant::Fixnum(x)),
+            Datum::Flonum(x) => self.load_constant(f, Constant::Flonum(x)),
+            Datum::Ratio(n, d) => self.load_constant(f, Constant::Ratio(n, d)),
+            Datum::Char(c) => self.load_constant(f, Constant::Char(c)),
+            Datum::Bool(true) => f.push(Opcode::LoadTrue),
+            Datum::Bool(false) => f.push(Opcode::LoadFalse),
+            Datum::Nil => f.push(Opcode::LoadNil),
+            Datum::Symbol(ref name) => self.load_constant(f, Constant::Symbol(name.clone())),
+            Datum::Str(ref s) => self.load_constant(f, Constant::Str(s.clone())),
+            Datum::Pair(_) => {
+                // Built from the end, so that long lists need only two slots.
+                let (items, tail) = datum.elements();
+                try!(self.constant(f, tail));
+                for x in items.into_iter().rev() {
+                    try!(self.constant(f, x));
+                    let top = f.depth - 1;
+                    try!(f.emit(Opcode::Cons, top, top - 1, top - 1));
+                    f.pop(1)
+                }
+                Ok(())
+            }
+            Datum::Vector(_) => self.load_constant(f, frozen(datum)),
+        }
+    }
+
+    fn location(&self, f: &FunctionBuilder, var: Var) -> Location {
+        match f.locations.get(&var) {
+            Some(&location) => location,
+            None => bug!("variable {} has no location", self.vars[var].name),
+        }
+    }
+
+    /// Puts the variables of `bindings` in the slots from `base` up, which
+    /// hold their values, boxing those that are boxed.
+    fn bind_slots(&mut self,
+                  f: &mut FunctionBuilder,
+                  base: usize,
+                  bindings: &[(Var, Expr)])
+                  -> Result<(), String> {
+        for (i, &(var, _)) in bindings.iter().enumerate() {
+            f.locations.insert(var, Location::Slot(base + i));
+            if self.vars[var].boxed {
+                try!(self.box_slot(f, base + i))
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the value in `slot` with a box holding it.
+    fn box_slot(&mut self, f: &mut FunctionBuilder, slot: usize) -> Result<(), String> {
+        try!(f.push(Opcode::LoadNil));
+        let top = f.depth - 1;
+        try!(f.emit(Opcode::Cons, slot, top, slot));
+        f.pop(1);
+        Ok(())
+    }
+
+    /// Loads the contents of the location of `var` – its box, if it is
+    /// boxed – into a new slot.
+    fn load_location(&mut self, f: &mut FunctionBuilder, var: Var) -> Result<(), String> {
+        f.depth += 1;
+        let top = f.depth - 1;
+        match self.location(f, var) {
+            Location::Slot(slot) => f.emit(Opcode::LoadArgument, slot, 0, top),
+            Location::Upvalue(index) => f.emit(Opcode::LoadEnvironment, index, 0, top),
+        }
+    }
+
+    /// Loads the value of `var` into a new slot.
+    fn load(&mut self, f: &mut FunctionBuilder, var: Var) -> Result<(), String> {
+        try!(self.load_location(f, var));
+        if self.vars[var].boxed {
+            let top = f.depth - 1;
+            try!(f.emit(Opcode::Car, top, 0, top))
+        }
+        Ok(())
+    }
+
+    /// Stores the value in the topmost slot into `var`, and frees the slot.
+    fn store(&mut self, f: &mut FunctionBuilder, var: Var) -> Result<(), String> {
+        let value = f.depth - 1;
+        if self.vars[var].boxed {
+            try!(self.load_location(f, var));
+            try!(f.emit(Opcode::SetCar, value, 0, value + 1));
+            f.pop(2);
+            return Ok(())
+        }
+        f.pop(1);
+        match self.location(f, var) {
+            Location::Slot(slot) => f.emit(Opcode::StoreArgument, value, 0, slot),
+            Location::Upvalue(index) => f.emit(Opcode::StoreEnvironment, value, 0, index),
+        }
+    }
+}
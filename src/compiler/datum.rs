@@ -0,0 +1,271 @@
+//! Source code, as seen by the compiler.
+//!
+//! The compiler works on Rust data rather than on Scheme objects, so that it
+//! does not need to worry about the garbage collector moving its input.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, BufRead, Bytes, Read};
+use std::iter::Peekable;
+use std::usize;
+
+use flonum;
+use print;
+use ratio;
+use read::{Event, EventSource, ReadError};
+
+/// A Scheme datum.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Datum {
+    /// A fixnum, as `value::Kind::Fixnum` has it: a negative one is in two's
+    /// complement in the low bits.
+    Fixnum(usize),
+
+    /// The bits of a flonum, so that data can be compared and hashed.
+    Flonum(u64),
+
+    /// A ratio, as its numerator and denominator (see `ratio`).
+    Ratio(isize, isize),
+    Char(char),
+    Bool(bool),
+    Nil,
+    Symbol(String),
+    Str(String),
+    Pair(Box<(Datum, Datum)>),
+    Vector(Vec<Datum>),
+}
+
+impl Datum {
+    pub fn cons(car: Datum, cdr: Datum) -> Datum {
+        Datum::Pair(Box::new((car, cdr)))
+    }
+
+    /// A proper list of `items`.
+    pub fn list(items: Vec<Datum>) -> Datum {
+        items.into_iter().rev().fold(Datum::Nil, |tail, x| Datum::cons(x, tail))
+    }
+
+    pub fn symbol(name: &str) -> Datum {
+        Datum::Symbol(name.to_owned())
+    }
+
+    pub fn as_symbol(&self) -> Option<&str> {
+        match *self {
+            Datum::Symbol(ref name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The elements of `self`, and whatever ends it (`Nil` for a proper
+    /// list).
+    pub fn elements(&self) -> (Vec<&Datum>, &Datum) {
+        let mut items = vec![];
+        let mut current = self;
+        while let Datum::Pair(ref pair) = *current {
+            items.push(&pair.0);
+            current = &pair.1
+        }
+        (items, current)
+    }
+
+    /// The elements of `self`, if it is a proper list.
+    pub fn as_list(&self) -> Option<Vec<&Datum>> {
+        match self.elements() {
+            (items, &Datum::Nil) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Datum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Datum::Fixnum(x) => write!(f, "{}", (x << 2) as isize >> 2),
+            Datum::Flonum(x) => f.write_str(&flonum::format(f64::from_bits(x))),
+            Datum::Ratio(n, d) => f.write_str(&ratio::format(n, d, 10)),
+            Datum::Char(c) => print::write_char(f, c),
+            Datum::Bool(x) => f.write_str(if x { "#t" } else { "#f" }),
+            Datum::Nil => f.write_str("()"),
+            Datum::Symbol(ref name) => print::write_symbol(f, name),
+            Datum::Str(ref s) => write!(f, "{:?}", s),
+            Datum::Pair(_) => {
+                let (items, tail) = self.elements();
+                try!(f.write_str("("));
+                for (i, item) in items.iter().enumerate() {
+                    try!(write!(f, "{}{}", if i == 0 { "" } else { " " }, item))
+                }
+                if *tail != Datum::Nil {
+                    try!(write!(f, " . {}", tail))
+                }
+                f.write_str(")")
+            }
+            Datum::Vector(ref items) => {
+                try!(f.write_str("#("));
+                for (i, item) in items.iter().enumerate() {
+                    try!(write!(f, "{}{}", if i == 0 { "" } else { " " }, item))
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+/// A datum being read.
+enum Frame {
+    /// A list opened by `[` (true) or `(` (false).
+    List(bool, Vec<Datum>),
+
+    /// A list after its dot, and the datum after the dot.
+    Dotted(bool, Vec<Datum>, Option<Datum>),
+
+    Vector(Vec<Datum>),
+
+    /// An abbreviation, such as `'` for `quote`.
+    Abbreviation(&'static str),
+}
+
+/// Adds the complete datum `x` to the innermost frame of `stack`, or to
+/// `data` if there is none.
+fn complete(stack: &mut Vec<Frame>, data: &mut Vec<Datum>, x: Datum) -> Result<(), ReadError> {
+    let mut x = x;
+    loop {
+        match stack.last_mut() {
+            None => return Ok(data.push(x)),
+            Some(&mut Frame::List(_, ref mut items)) |
+            Some(&mut Frame::Vector(ref mut items)) => return Ok(items.push(x)),
+            Some(&mut Frame::Dotted(_, _, ref mut tail @ None)) => return Ok(*tail = Some(x)),
+            Some(&mut Frame::Dotted(_, _, Some(_))) => return Err(ReadError::BadDot),
+            Some(&mut Frame::Abbreviation(_)) => {}
+        }
+        if let Some(Frame::Abbreviation(name)) = stack.pop() {
+            x = Datum::list(vec![Datum::symbol(name), x])
+        }
+    }
+}
+
+/// A reader that counts the lines it reads, so that `read_lines` can tell
+/// where each datum starts.
+struct Lines<'a, R> {
+    inner: R,
+    line: &'a Cell<u32>,
+}
+
+/// The number of newlines in `bytes`.
+fn newlines(bytes: &[u8]) -> u32 {
+    bytes.iter().filter(|&&byte| byte == b'\n').count() as u32
+}
+
+impl<'a, R: BufRead> Read for Lines<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.line.set(self.line.get() + newlines(&buf[..n]));
+        Ok(n)
+    }
+}
+
+impl<'a, R: BufRead> BufRead for Lines<'a, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.line.set(self.line.get() + newlines(&buf[..amt]))
+        }
+        self.inner.consume(amt)
+    }
+}
+
+/// Reads all of the data in `r`.
+pub fn read_all<R: BufRead>(r: &mut Peekable<Bytes<R>>) -> Result<Vec<Datum>, ReadError> {
+    read_data(r, None).map(|(data, _)| data)
+}
+
+/// Reads all of the data in `r`, and the line that each starts on,
+/// counting from 1.
+pub fn read_lines<R: BufRead>(r: R) -> Result<(Vec<Datum>, Vec<u32>), ReadError> {
+    let line = Cell::new(1);
+    let lines = Lines { inner: r, line: &line };
+    read_data(&mut lines.bytes().peekable(), Some(&line))
+}
+
+/// Reads all of the data in `r`.  If `line` counts the lines read, also
+/// returns the line that each datum starts on.
+fn read_data<R: BufRead>(r: &mut Peekable<Bytes<R>>,
+                         line: Option<&Cell<u32>>)
+                         -> Result<(Vec<Datum>, Vec<u32>), ReadError> {
+    let mut data = vec![];
+    let mut lines = vec![];
+    let mut stack = vec![];
+    for event in EventSource::new(r) {
+        let event = try!(event);
+        match (line, &event) {
+            (_, &Event::EOF) => {}
+            (Some(line), _) if stack.is_empty() && lines.len() == data.len() => {
+                lines.push(line.get())
+            }
+            _ => {}
+        }
+        let atom = match event {
+            Event::Int(x) => Datum::Fixnum(x as usize & usize::MAX >> 2),
+            Event::Float(x) => Datum::Flonum(x.to_bits()),
+            Event::Ratio(n, d) => Datum::Ratio(n, d),
+            Event::Str(s) => Datum::Str(s),
+            Event::Symbol(name) => Datum::Symbol(name),
+            Event::True => Datum::Bool(true),
+            Event::False => Datum::Bool(false),
+            Event::Char(c) => Datum::Char(c),
+            Event::ReadEval => return Err(ReadError::ReadEvalDisabled),
+            Event::StartRecord => return Err(ReadError::RecordsDisabled),
+            Event::EOF => break,
+            Event::StartList(square) => {
+                stack.push(Frame::List(square, vec![]));
+                continue
+            }
+            Event::StartVec => {
+                stack.push(Frame::Vector(vec![]));
+                continue
+            }
+            Event::Dot => {
+                match stack.pop() {
+                    Some(Frame::List(square, items)) if !items.is_empty() => {
+                        stack.push(Frame::Dotted(square, items, None))
+                    }
+                    _ => return Err(ReadError::BadDot),
+                }
+                continue
+            }
+            Event::EndList(square) => {
+                match stack.pop() {
+                    Some(Frame::List(opened, items)) if opened == square => Datum::list(items),
+                    Some(Frame::Dotted(opened, items, Some(tail))) if opened == square => {
+                        items.into_iter().rev().fold(tail, |tail, x| Datum::cons(x, tail))
+                    }
+                    Some(Frame::Vector(items)) if !square => Datum::Vector(items),
+                    Some(Frame::Dotted(_, _, None)) => return Err(ReadError::BadDot),
+                    Some(_) => return Err(ReadError::BadCloseParen),
+                    None => return Err(ReadError::UnexpectedCloseParen),
+                }
+            }
+            abbreviation => {
+                stack.push(Frame::Abbreviation(match abbreviation {
+                    Event::Quote => "quote",
+                    Event::Quasiquote => "quasiquote",
+                    Event::Unquote => "unquote",
+                    Event::UnquoteSplicing => "unquote-splicing",
+                    Event::Syntax => "syntax",
+                    Event::Quasisyntax => "quasisyntax",
+                    Event::Unsyntax => "unsyntax",
+                    _ => "unsyntax-splicing",
+                }));
+                continue
+            }
+        };
+        try!(complete(&mut stack, &mut data, atom))
+    }
+    match stack.last() {
+        None => Ok((data, lines)),
+        Some(&Frame::Vector(_)) => Err(ReadError::EOFInVector),
+        Some(_) => Err(ReadError::EOFInList),
+    }
+}
@@ -0,0 +1,143 @@
+//! Procedural macros: running Scheme code while compiling.
+//!
+//! The transformer of a `define-macro` is an ordinary procedure.  It is
+//! called with the unevaluated arguments of each use of the macro, and
+//! returns the form to expand the use into.
+//!
+//! Transformers run in an interpreter of their own, so that compiling a
+//! program cannot change the state it will run in.  Each one is the global
+//! variable of its macro's name there.  Transformers can therefore call the
+//! builtins and each other, but not procedures defined by the program being
+//! compiled, which do not exist yet.  The REPL keeps its expander from one
+//! input to the next, along with its macros (see `syntax::Macros`).
+
+use std::fmt;
+
+use bytecode::Source;
+use equal;
+use flonum;
+use interp;
+use ratio;
+use string;
+use value::{self, Value, Tags};
+use super::codegen;
+use super::datum::Datum;
+use super::syntax::{Expr, VarInfo};
+
+/// The interpreter that transformers run in.
+pub struct Expander {
+    state: interp::State,
+}
+
+impl Default for Expander {
+    fn default() -> Self {
+        Expander { state: interp::new() }
+    }
+}
+
+impl fmt::Debug for Expander {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Expander")
+    }
+}
+
+impl Expander {
+    /// Makes the value of `transformer`, with variables `vars`, the
+    /// transformer of the macro `name`.
+    pub fn define(&mut self, name: &str, vars: &[VarInfo], transformer: Expr) -> Result<(), String> {
+        let define = Expr::SetGlobal(name.to_owned(), Box::new(transformer));
+        let expr = Expr::Sequence(vec![define, Expr::Global(name.to_owned())]);
+        self.eval(vars, &expr, |x| {
+            if x.tag() == Tags::Function || x.tag() == Tags::RustFunc {
+                Ok(())
+            } else {
+                Err(format!("the transformer of macro {} is not a procedure", name))
+            }
+        })
+    }
+
+    /// Calls the transformer of the macro `name` on the arguments of the
+    /// macro use `form`.
+    pub fn expand(&mut self, name: &str, form: &Datum) -> Result<Datum, String> {
+        let args = match form.as_list() {
+            Some(items) => items[1..].iter().map(|&x| Expr::Constant(x.clone())).collect(),
+            None => return Err(format!("improper list in macro use: {}", form)),
+        };
+        let call = Expr::Call(Box::new(Expr::Global(name.to_owned())), args);
+        self.eval(&[], &call, |x| {
+            datum(x).map_err(|e| format!("macro expansion contains an object that is {}", e))
+        })
+    }
+
+    /// Runs `expr`, and passes its value to `then`.  The value is only
+    /// valid until `then` returns.
+    fn eval<T, F>(&mut self, vars: &[VarInfo], expr: &Expr, then: F) -> Result<T, String>
+        where F: FnOnce(&Value) -> Result<T, String>
+    {
+        let program = try!(codegen::generate(vars, expr, true, Source::default()));
+        let s = &mut self.state;
+        let base = s.heap.stack.len();
+        let res = interp::load(s, &program)
+                      .and_then(|()| interp::call(s, 0))
+                      .and_then(|()| then(&s.heap.stack[base]));
+        s.heap.stack.truncate(base);
+        res
+    }
+}
+
+fn not_a_datum() -> String {
+    "not a datum".to_owned()
+}
+
+/// The datum that `x` represents.  Fails if `x` is not a datum, such as if
+/// it is a procedure.
+pub fn datum(x: &Value) -> Result<Datum, String> {
+    match x.get() {
+        value::FALSE => return Ok(Datum::Bool(false)),
+        value::TRUE => return Ok(Datum::Bool(true)),
+        value::NIL => return Ok(Datum::Nil),
+        _ => {}
+    }
+    if x.fixnump() {
+        return Ok(Datum::Fixnum(x.get() >> 2))
+    }
+    if let Some(x) = flonum::as_f64(x) {
+        return Ok(Datum::Flonum(x.to_bits()))
+    }
+    if let Some((n, d)) = ratio::as_ratio(x) {
+        return Ok(Datum::Ratio(n, d))
+    }
+    if let Some(s) = string::as_str(x) {
+        return Ok(Datum::Str(s.to_owned()))
+    }
+    if let Some(c) = x.as_char() {
+        return Ok(Datum::Char(c))
+    }
+    match x.tag() {
+        Tags::Symbol => {
+            match x.kind() {
+                value::Kind::Symbol(ptr) => Ok(Datum::symbol(unsafe { (*ptr).name() })),
+                _ => unreachable!(),
+            }
+        }
+        Tags::Pair => {
+            // Lists are walked iteratively, so that long ones do not
+            // overflow the Rust stack.
+            let mut items = vec![];
+            let mut x = x.clone();
+            while x.tag() == Tags::Pair {
+                items.push(try!(datum(&x.car().unwrap())));
+                x = x.cdr().unwrap()
+            }
+            let tail = try!(datum(&x));
+            Ok(items.into_iter().rev().fold(tail, |tail, x| Datum::cons(x, tail)))
+        }
+        Tags::Vector if !x.immediatep() => {
+            match equal::vector_elements(x) {
+                Some(items) => Ok(Datum::Vector(try!(items.iter().map(datum).collect()))),
+                None => Err(not_a_datum()),
+            }
+        }
+        _ => Err(not_a_datum()),
+    }
+}
@@ -0,0 +1,191 @@
+//! Constant folding and propagation: evaluates pure operations on constant
+//! operands at compile time, so that they compile to a constant.
+//!
+//! What is folded:
+//!
+//! - fixnum `+`, `-`, `*`, `<`, and `=` (unless the result is not a
+//!   non-negative fixnum, which has no literal);
+//! - `car` and `cdr` of quoted pairs;
+//! - `string-length` of string literals;
+//! - `if` with a constant test, and `or` with constant tests.
+//!
+//! A `let` variable that is bound to an atom and never assigned is replaced
+//! by the atom, so that its uses can be folded too.  (Lists are not
+//! propagated, since each use would build a copy.)  Folding works from
+//! the inside out, so `(let ((x 2)) (+ x (* 3 4)))` folds to `14`.
+//!
+//! Like the primitives (see `syntax`), `string-length` is folded if it
+//! refers to the global of that name, even if a program redefines it.
+
+use std::collections::HashMap;
+use std::isize;
+
+use super::datum::Datum;
+use super::syntax::{Expr, Lambda, Primitive, Var, VarInfo};
+
+/// The largest fixnum.
+const MAX_FIXNUM: usize = isize::MAX as usize >> 2;
+
+/// Folds `expr`, whose variables are `vars`.
+pub fn fold(vars: &[VarInfo], expr: Expr) -> Expr {
+    Folder { vars: vars, constants: HashMap::new() }.expr(expr)
+}
+
+struct Folder<'a> {
+    vars: &'a [VarInfo],
+
+    /// The variables replaced by constants.
+    constants: HashMap<Var, Datum>,
+}
+
+/// Can `datum` be propagated to the uses of a variable bound to it?
+fn is_atom(datum: &Datum) -> bool {
+    match *datum {
+        Datum::Pair(_) => false,
+        _ => true,
+    }
+}
+
+/// The value of `primitive` on `args`, if they are constants it can be
+/// evaluated on.
+fn primitive(primitive: Primitive, args: &[Expr]) -> Option<Datum> {
+    let constants: Vec<_> = args.iter()
+                                .filter_map(|x| match *x {
+                                    Expr::Constant(ref datum) => Some(datum),
+                                    _ => None,
+                                })
+                                .collect();
+    if constants.len() != args.len() {
+        return None
+    }
+    let fixnum = |x: Option<usize>| x.and_then(|x| if x <= MAX_FIXNUM { Some(x) } else { None });
+    match (primitive, constants[0], constants.get(1)) {
+        (Primitive::Car, &Datum::Pair(ref pair), None) => Some(pair.0.clone()),
+        (Primitive::Cdr, &Datum::Pair(ref pair), None) => Some(pair.1.clone()),
+        // Negative fixnums are not folded.
+        (_, &Datum::Fixnum(x), Some(&&Datum::Fixnum(y))) if x <= MAX_FIXNUM && y <= MAX_FIXNUM => {
+            match primitive {
+                Primitive::Add => fixnum(x.checked_add(y)).map(Datum::Fixnum),
+                Primitive::Subtract => fixnum(x.checked_sub(y)).map(Datum::Fixnum),
+                Primitive::Multiply => fixnum(x.checked_mul(y)).map(Datum::Fixnum),
+                Primitive::Less => Some(Datum::Bool(x < y)),
+                Primitive::NumEqual => Some(Datum::Bool(x == y)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+impl<'a> Folder<'a> {
+    fn boxed(&mut self, expr: Box<Expr>) -> Box<Expr> {
+        Box::new(self.expr(*expr))
+    }
+
+    fn exprs(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs.into_iter().map(|x| self.expr(x)).collect()
+    }
+
+    fn bindings(&mut self, bindings: Vec<(Var, Expr)>) -> Vec<(Var, Expr)> {
+        bindings.into_iter().map(|(var, init)| (var, self.expr(init))).collect()
+    }
+
+    fn expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Local(var) => {
+                match self.constants.get(&var) {
+                    Some(datum) => Expr::Constant(datum.clone()),
+                    None => Expr::Local(var),
+                }
+            }
+            Expr::SetLocal(var, value) => Expr::SetLocal(var, self.boxed(value)),
+            Expr::SetGlobal(name, value) => Expr::SetGlobal(name, self.boxed(value)),
+            Expr::If(test, then, otherwise) => {
+                match (self.expr(*test), otherwise) {
+                    (Expr::Constant(Datum::Bool(false)), Some(otherwise)) => self.expr(*otherwise),
+                    (Expr::Constant(Datum::Bool(false)), None) => {
+                        Expr::If(Box::new(Expr::Constant(Datum::Bool(false))), then, None)
+                    }
+                    (Expr::Constant(_), _) => self.expr(*then),
+                    (test, otherwise) => {
+                        Expr::If(Box::new(test),
+                                 self.boxed(then),
+                                 otherwise.map(|x| self.boxed(x)))
+                    }
+                }
+            }
+            Expr::Lambda(Lambda { params, rest, body, source }) => {
+                Expr::Lambda(Lambda {
+                    params: params,
+                    rest: rest,
+                    body: self.boxed(body),
+                    source: source,
+                })
+            }
+            Expr::Sequence(exprs) => Expr::Sequence(self.exprs(exprs)),
+            Expr::Call(function, args) => {
+                let (function, args) = (self.boxed(function), self.exprs(args));
+                match (&*function, args.first(), args.len()) {
+                    (&Expr::Global(ref name), Some(&Expr::Constant(Datum::Str(ref s))), 1)
+                        if name == "string-length" => {
+                        return Expr::Constant(Datum::Fixnum(s.chars().count()))
+                    }
+                    _ => {}
+                }
+                Expr::Call(function, args)
+            }
+            Expr::Apply(function, args) => Expr::Apply(self.boxed(function), self.exprs(args)),
+            Expr::Or(exprs) => {
+                // A false test can be left out, since if the tests after it
+                // are false too, the last one's value is `#f` as well.
+                let mut kept = vec![];
+                for x in self.exprs(exprs) {
+                    match x {
+                        Expr::Constant(Datum::Bool(false)) => {}
+                        x @ Expr::Constant(_) => {
+                            kept.push(x);
+                            break
+                        }
+                        x => kept.push(x),
+                    }
+                }
+                match kept.len() {
+                    0 => Expr::Constant(Datum::Bool(false)),
+                    1 => kept.pop().unwrap(),
+                    _ => Expr::Or(kept),
+                }
+            }
+            Expr::Primitive(op, args) => {
+                let args = self.exprs(args);
+                match primitive(op, &args) {
+                    Some(datum) => Expr::Constant(datum),
+                    None => Expr::Primitive(op, args),
+                }
+            }
+            Expr::Let(bindings, body) => {
+                let mut kept = vec![];
+                for (var, init) in self.bindings(bindings) {
+                    match init {
+                        Expr::Constant(ref datum) if is_atom(datum) && !self.vars[var].assigned => {
+                            self.constants.insert(var, datum.clone());
+                        }
+                        init => kept.push((var, init)),
+                    }
+                }
+                let body = self.boxed(body);
+                if kept.is_empty() {
+                    *body
+                } else {
+                    Expr::Let(kept, body)
+                }
+            }
+            Expr::Letrec(bindings, body) => {
+                Expr::Letrec(self.bindings(bindings), self.boxed(body))
+            }
+            Expr::Loop(name, bindings, body) => {
+                Expr::Loop(name, self.bindings(bindings), self.boxed(body))
+            }
+            expr @ Expr::Constant(_) | expr @ Expr::Global(_) => expr,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Libraries defined by `define-library`, and import sets.
+//!
+//! There is still only one global namespace.  A library's definitions are
+//! globals whose names are prefixed with the name of the library, like
+//! `(foo bar) x` for `x` in `(foo bar)`, which no identifier can be.  An
+//! import makes identifiers refer to the globals that the library exports
+//! them as.  Identifiers that are neither imported nor defined by a library
+//! refer to the global of the same name, so the builtins and the prelude
+//! are available everywhere.
+//!
+//! The libraries of native procedures (`builtins::LIBRARIES`) can be
+//! imported too, so that their names can be prefixed or renamed.
+
+use std::collections::HashMap;
+
+use builtins;
+use library::Name;
+use super::datum::Datum;
+
+/// What a library exports: maps each exported identifier to the global it
+/// refers to.
+pub type Exports = HashMap<String, String>;
+
+/// The libraries defined so far, and what the top level has imported from
+/// them.
+#[derive(Clone, Debug, Default)]
+pub struct Libraries {
+    defined: HashMap<Name, Exports>,
+
+    /// The identifiers imported at top level, mapped to their globals.
+    pub imports: Exports,
+
+    /// Are constant folding and the peephole optimizer (see `compiler::fold`
+    /// and `compiler::peephole`) turned off?  Unoptimized code is easier to
+    /// relate to the source, for debugging.
+    pub unoptimized: bool,
+}
+
+impl Libraries {
+    /// No libraries, with the optimizations turned off.
+    pub fn unoptimized() -> Self {
+        Libraries { unoptimized: true, ..Libraries::default() }
+    }
+
+    /// Defines, or redefines, the library `name`.
+    pub fn define(&mut self, name: Name, exports: Exports) {
+        self.defined.insert(name, exports);
+    }
+
+    /// The libraries defined so far, and their exports.
+    pub fn defined(&self) -> &HashMap<Name, Exports> {
+        &self.defined
+    }
+
+    /// The exports of the library `name`, if it is defined.
+    pub fn get(&self, name: &[String]) -> Option<Exports> {
+        if let Some(exports) = self.defined.get(name) {
+            return Some(exports.clone())
+        }
+        builtins::LIBRARIES.iter().find(|lib| lib.name == name).map(|lib| {
+            lib.procedures.iter().map(|p| (p.name.to_owned(), p.name.to_owned())).collect()
+        })
+    }
+}
+
+/// The name of the library that `name` names, like `(foo bar)`.
+pub fn library_name(name: &Datum) -> Result<Name, String> {
+    let parts = try!(name.as_list().ok_or_else(|| format!("bad library name: {}", name)));
+    parts.iter()
+         .map(|part| {
+             match **part {
+                 Datum::Symbol(ref part) => Ok(part.clone()),
+                 Datum::Fixnum(n) => Ok(n.to_string()),
+                 _ => Err(format!("bad library name: {}", name)),
+             }
+         })
+         .collect()
+}
+
+/// The global that `id` in the library `library` is.
+pub fn global_name(library: &[String], id: &str) -> String {
+    format!("({}) {}", library.join(" "), id)
+}
+
+/// The identifiers of a part of an `only`, `except`, or `rename` import
+/// set that are exported by `set`.
+fn identifiers<'a>(set: &Exports, ids: &[&'a Datum]) -> Result<Vec<&'a str>, String> {
+    ids.iter()
+       .map(|id| {
+           match id.as_symbol() {
+               Some(id) if set.contains_key(id) => Ok(id),
+               Some(id) => Err(format!("{} is not in the import set", id)),
+               None => Err(format!("not an identifier: {}", id)),
+           }
+       })
+       .collect()
+}
+
+/// The identifiers that the import set `spec` imports, mapped to their
+/// globals.
+pub fn import_set(libraries: &Libraries, spec: &Datum) -> Result<Exports, String> {
+    let items = try!(spec.as_list().ok_or_else(|| format!("bad import set: {}", spec)));
+    let modifier = match items.first().and_then(|x| x.as_symbol()) {
+        Some(modifier @ "only") | Some(modifier @ "except") | Some(modifier @ "prefix") |
+        Some(modifier @ "rename") if items.len() >= 2 && items[1].as_list().is_some() => {
+            modifier
+        }
+        _ => {
+            let name = try!(library_name(spec));
+            return libraries.get(&name).ok_or_else(|| format!("no such library: {}", spec))
+        }
+    };
+    let mut set = try!(import_set(libraries, items[1]));
+    match modifier {
+        "only" => {
+            let ids = try!(identifiers(&set, &items[2..]));
+            set = ids.into_iter().map(|id| (id.to_owned(), set[id].clone())).collect();
+        }
+        "except" => {
+            for id in try!(identifiers(&set, &items[2..])) {
+                set.remove(id);
+            }
+        }
+        "prefix" => {
+            let prefix = match (items.len(), items.get(2).and_then(|x| x.as_symbol())) {
+                (3, Some(prefix)) => prefix,
+                _ => return Err(format!("bad import set: {}", spec)),
+            };
+            set = set.into_iter()
+                     .map(|(id, global)| (format!("{}{}", prefix, id), global))
+                     .collect();
+        }
+        _ => {
+            let mut renamed = Exports::new();
+            for rename in &items[2..] {
+                let (from, to) = match rename.as_list() {
+                    Some(ref pair) if pair.len() == 2 => (pair[0], pair[1]),
+                    _ => return Err(format!("bad import set: {}", spec)),
+                };
+                let from = try!(identifiers(&set, &[from]))[0];
+                let to = try!(to.as_symbol().ok_or_else(|| format!("not an identifier: {}", to)));
+                renamed.insert(to.to_owned(), set.remove(from).unwrap());
+            }
+            set.extend(renamed);
+        }
+    }
+    Ok(set)
+}
@@ -0,0 +1,408 @@
+//! `syntax-rules` macros.
+//!
+//! A macro is a list of rules, each a pattern and a template.  A use of the
+//! macro is matched against each pattern in turn, and the first that
+//! matches binds its pattern variables to parts of the use.  The template
+//! of that rule is then instantiated with the bindings, and the result
+//! replaces the use.
+//!
+//! ### Hygiene
+//!
+//! Every identifier that a template introduces (that is, every symbol in it
+//! that is not a pattern variable) is renamed to a fresh identifier, of the
+//! form `name\0n`.  The readers cannot produce such a symbol, so
+//!
+//! - if the expansion binds a renamed identifier, references to it from the
+//!   expansion refer to that binding, and identifiers of the same name in
+//!   the macro use do not;
+//! - a renamed identifier that the expansion does not bind refers to the
+//!   binding of `name` where the macro was defined.  Macros can only be
+//!   defined at top level for now, so that is always the special form,
+//!   macro, or global variable named `name`, even if the macro use is in the
+//!   scope of a local variable named `name`.
+//!
+//! Quoting a renamed identifier gives the symbol `name`.
+
+use std::collections::HashMap;
+
+use super::datum::Datum;
+
+/// The name that the (possibly renamed) identifier `name` was renamed
+/// from.
+pub fn base_name(name: &str) -> &str {
+    match name.find('\0') {
+        Some(end) => &name[..end],
+        None => name,
+    }
+}
+
+/// `x`, with every renamed identifier in it replaced by its base name.
+pub fn strip(x: &Datum) -> Datum {
+    match *x {
+        Datum::Symbol(ref name) => Datum::symbol(base_name(name)),
+        Datum::Pair(ref pair) => Datum::cons(strip(&pair.0), strip(&pair.1)),
+        Datum::Vector(ref items) => Datum::Vector(items.iter().map(strip).collect()),
+        ref x => x.clone(),
+    }
+}
+
+/// A `syntax-rules` macro.
+#[derive(Debug)]
+pub struct Macro {
+    /// The identifier that marks repetition, normally `...`.
+    ellipsis: String,
+    literals: Vec<String>,
+    rules: Vec<(Datum, Datum)>,
+}
+
+/// What a pattern variable is bound to.
+#[derive(Clone, Debug)]
+enum Binding {
+    One(Datum),
+
+    /// The bindings of a pattern variable under an ellipsis, one for each
+    /// repetition.
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+/// Is `x` the identifier `name`?
+fn is(x: &Datum, name: &str) -> bool {
+    x.as_symbol().map_or(false, |x| base_name(x) == name)
+}
+
+impl Macro {
+    /// Parses the `(syntax-rules ...)` form `spec`.
+    pub fn new(spec: &Datum) -> Result<Macro, String> {
+        let bad = || format!("bad syntax-rules: {}", spec);
+        let items = try!(spec.as_list().ok_or_else(&bad));
+        if items.len() < 2 || !is(items[0], "syntax-rules") {
+            return Err(bad())
+        }
+        // R7RS allows a custom ellipsis before the literals.
+        let (ellipsis, rest) = match items[1].as_symbol() {
+            Some(ellipsis) => (base_name(ellipsis).to_owned(), &items[2..]),
+            None => ("...".to_owned(), &items[1..]),
+        };
+        let (literals, rules) = try!(rest.split_first().ok_or_else(&bad));
+        let literals = try!(literals.as_list().ok_or_else(&bad));
+        let literals = try!(literals.iter()
+                                    .map(|x| x.as_symbol().map(|x| base_name(x).to_owned()))
+                                    .collect::<Option<Vec<String>>>()
+                                    .ok_or_else(&bad));
+        let mut parsed = vec![];
+        for rule in rules {
+            match rule.as_list() {
+                Some(ref rule) if rule.len() == 2 => {
+                    match *rule[0] {
+                        Datum::Pair(_) => parsed.push((rule[0].clone(), rule[1].clone())),
+                        _ => return Err(bad()),
+                    }
+                }
+                _ => return Err(bad()),
+            }
+        }
+        Ok(Macro {
+            ellipsis: ellipsis,
+            literals: literals,
+            rules: parsed,
+        })
+    }
+
+    /// Expands the macro use `form`.  `rename` gives the fresh name of an
+    /// identifier introduced by the expansion.
+    pub fn expand(&self, form: &Datum, rename: &mut FnMut(&str) -> String) -> Result<Datum, String> {
+        // The keyword is not matched.
+        let args = match *form {
+            Datum::Pair(ref pair) => &pair.1,
+            _ => return Err(format!("bad macro use: {}", form)),
+        };
+        for &(ref pattern, ref template) in &self.rules {
+            let pattern = match *pattern {
+                Datum::Pair(ref pair) => &pair.1,
+                _ => unreachable!(),
+            };
+            let mut bindings = Bindings::new();
+            if self.matches(pattern, args, &mut bindings) {
+                let mut renames = HashMap::new();
+                let mut instantiate = Instantiate {
+                    ellipsis: Some(&self.ellipsis),
+                    bindings: &bindings,
+                    renames: &mut renames,
+                    rename: rename,
+                };
+                return instantiate.template(template)
+            }
+        }
+        Err(format!("no syntax-rules pattern matches {}", strip(form)))
+    }
+
+    fn is_ellipsis(&self, x: &Datum) -> bool {
+        is(x, &self.ellipsis)
+    }
+
+    /// Matches `form` against `pattern`, adding to `bindings`.
+    fn matches(&self, pattern: &Datum, form: &Datum, bindings: &mut Bindings) -> bool {
+        match *pattern {
+            Datum::Symbol(ref name) => {
+                let name = base_name(name);
+                if self.literals.iter().any(|x| x == name) {
+                    is(form, name)
+                } else {
+                    if name != "_" {
+                        bindings.insert(name.to_owned(), Binding::One(form.clone()));
+                    }
+                    true
+                }
+            }
+            Datum::Pair(_) => {
+                let (items, tail) = pattern.elements();
+                match items.iter().position(|x| self.is_ellipsis(x)) {
+                    Some(i) if i > 0 => {
+                        let (form_items, form_tail) = form.elements();
+                        self.matches_repeated(&items, i - 1, tail, &form_items, form_tail, bindings)
+                    }
+                    _ => {
+                        // No ellipsis: match one pair at a time, since the
+                        // tail matches the rest of the list.
+                        let mut form = form;
+                        for item in items {
+                            match *form {
+                                Datum::Pair(ref pair) => {
+                                    if !self.matches(item, &pair.0, bindings) {
+                                        return false
+                                    }
+                                    form = &pair.1
+                                }
+                                _ => return false,
+                            }
+                        }
+                        self.matches(tail, form, bindings)
+                    }
+                }
+            }
+            Datum::Vector(ref items) => {
+                match *form {
+                    Datum::Vector(ref form_items) => {
+                        let items: Vec<&Datum> = items.iter().collect();
+                        let form_items: Vec<&Datum> = form_items.iter().collect();
+                        match items.iter().position(|x| self.is_ellipsis(x)) {
+                            Some(i) if i > 0 => {
+                                self.matches_repeated(&items,
+                                                      i - 1,
+                                                      &Datum::Nil,
+                                                      &form_items,
+                                                      &Datum::Nil,
+                                                      bindings)
+                            }
+                            _ => {
+                                items.len() == form_items.len() &&
+                                items.iter()
+                                     .zip(form_items)
+                                     .all(|(x, y)| self.matches(x, y, bindings))
+                            }
+                        }
+                    }
+                    _ => false,
+                }
+            }
+            ref pattern => pattern == form,
+        }
+    }
+
+    /// Matches the elements `form` of a list or vector (ending in
+    /// `form_tail`) against the elements `items` of a pattern, in which
+    /// `items[repeated]` is followed by an ellipsis.
+    fn matches_repeated(&self,
+                        items: &[&Datum],
+                        repeated: usize,
+                        tail: &Datum,
+                        form: &[&Datum],
+                        form_tail: &Datum,
+                        bindings: &mut Bindings)
+                        -> bool {
+        let (before, after) = (&items[..repeated], &items[repeated + 2..]);
+        if form.len() < before.len() + after.len() {
+            return false
+        }
+        let after_start = form.len() - after.len();
+        let all = |patterns: &[&Datum], forms: &[&Datum], bindings: &mut Bindings| {
+            patterns.iter().zip(forms).all(|(x, y)| self.matches(x, y, bindings))
+        };
+        if !all(before, &form[..before.len()], bindings) ||
+           !all(after, &form[after_start..], bindings) ||
+           !self.matches(tail, form_tail, bindings) {
+            return false
+        }
+        let pattern = items[repeated];
+        let mut repetitions = vec![];
+        for x in &form[before.len()..after_start] {
+            let mut inner = Bindings::new();
+            if !self.matches(pattern, x, &mut inner) {
+                return false
+            }
+            repetitions.push(inner)
+        }
+        for var in self.pattern_variables(pattern) {
+            let many = repetitions.iter_mut().map(|x| x.remove(&var).unwrap()).collect();
+            bindings.insert(var, Binding::Many(many));
+        }
+        true
+    }
+
+    /// The pattern variables of `pattern`.
+    fn pattern_variables(&self, pattern: &Datum) -> Vec<String> {
+        match *pattern {
+            Datum::Symbol(ref name) => {
+                let name = base_name(name);
+                if name == "_" || self.literals.iter().any(|x| x == name) ||
+                   name == self.ellipsis {
+                    vec![]
+                } else {
+                    vec![name.to_owned()]
+                }
+            }
+            Datum::Pair(ref pair) => {
+                let mut vars = self.pattern_variables(&pair.0);
+                vars.extend(self.pattern_variables(&pair.1));
+                vars
+            }
+            Datum::Vector(ref items) => {
+                items.iter().flat_map(|x| self.pattern_variables(x)).collect()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// The instantiation of a template.
+struct Instantiate<'a> {
+    /// The ellipsis, unless escaped by `(... template)`.
+    ellipsis: Option<&'a str>,
+    bindings: &'a Bindings,
+
+    /// The fresh names of the identifiers introduced so far.
+    renames: &'a mut HashMap<String, String>,
+    rename: &'a mut FnMut(&str) -> String,
+}
+
+impl<'a> Instantiate<'a> {
+    fn is_ellipsis(&self, x: &Datum) -> bool {
+        self.ellipsis.map_or(false, |ellipsis| is(x, ellipsis))
+    }
+
+    fn template(&mut self, template: &Datum) -> Result<Datum, String> {
+        match *template {
+            Datum::Symbol(ref name) => {
+                match self.bindings.get(base_name(name)) {
+                    Some(&Binding::One(ref x)) => Ok(x.clone()),
+                    Some(&Binding::Many(_)) => {
+                        Err(format!("pattern variable {} used without an ellipsis",
+                                    base_name(name)))
+                    }
+                    None => {
+                        if let Some(renamed) = self.renames.get(name) {
+                            return Ok(Datum::Symbol(renamed.clone()))
+                        }
+                        let renamed = (self.rename)(name);
+                        self.renames.insert(name.clone(), renamed.clone());
+                        Ok(Datum::Symbol(renamed))
+                    }
+                }
+            }
+            Datum::Pair(_) => {
+                let (items, tail) = template.elements();
+                if items.len() == 2 && *tail == Datum::Nil && self.is_ellipsis(items[0]) {
+                    // `(... template)`: the ellipsis is an ordinary
+                    // identifier in `template`.
+                    let ellipsis = self.ellipsis.take();
+                    let result = self.template(items[1]);
+                    self.ellipsis = ellipsis;
+                    return result
+                }
+                let items = try!(self.elements(&items));
+                let tail = try!(self.template(tail));
+                Ok(items.into_iter().rev().fold(tail, |tail, x| Datum::cons(x, tail)))
+            }
+            Datum::Vector(ref items) => {
+                let items: Vec<&Datum> = items.iter().collect();
+                Ok(Datum::Vector(try!(self.elements(&items))))
+            }
+            ref x => Ok(x.clone()),
+        }
+    }
+
+    /// Instantiates the elements of a list or vector template, splicing in
+    /// the repetitions of elements followed by ellipses.
+    fn elements(&mut self, items: &[&Datum]) -> Result<Vec<Datum>, String> {
+        let mut result = vec![];
+        let mut i = 0;
+        while i < items.len() {
+            let mut depth = 0;
+            while i + depth + 1 < items.len() && self.is_ellipsis(items[i + depth + 1]) {
+                depth += 1
+            }
+            if depth == 0 {
+                result.push(try!(self.template(items[i])))
+            } else {
+                try!(self.repeat(items[i], depth, &mut result))
+            }
+            i += depth + 1
+        }
+        Ok(result)
+    }
+
+    /// Instantiates `template`, followed by `depth` ellipses, once for each
+    /// repetition of its pattern variables, and adds the results to `out`.
+    fn repeat(&mut self, template: &Datum, depth: usize, out: &mut Vec<Datum>) -> Result<(), String> {
+        let vars: Vec<(String, Vec<Binding>)> = template_symbols(template)
+            .into_iter()
+            .filter_map(|name| {
+                match self.bindings.get(&name) {
+                    Some(&Binding::Many(ref xs)) => Some((name, xs.clone())),
+                    _ => None,
+                }
+            })
+            .collect();
+        let count = match vars.first() {
+            Some(&(_, ref xs)) => xs.len(),
+            None => return Err(format!("no pattern variables before ellipsis in {}", template)),
+        };
+        if vars.iter().any(|&(_, ref xs)| xs.len() != count) {
+            return Err(format!("pattern variables of different lengths in {}", template))
+        }
+        for i in 0..count {
+            let mut bindings = self.bindings.clone();
+            for &(ref name, ref xs) in &vars {
+                bindings.insert(name.clone(), xs[i].clone());
+            }
+            let mut inner = Instantiate {
+                ellipsis: self.ellipsis,
+                bindings: &bindings,
+                renames: self.renames,
+                rename: self.rename,
+            };
+            if depth > 1 {
+                try!(inner.repeat(template, depth - 1, out))
+            } else {
+                out.push(try!(inner.template(template)))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The base names of the symbols in `template`.
+fn template_symbols(template: &Datum) -> Vec<String> {
+    match *template {
+        Datum::Symbol(ref name) => vec![base_name(name).to_owned()],
+        Datum::Pair(ref pair) => {
+            let mut names = template_symbols(&pair.0);
+            names.extend(template_symbols(&pair.1));
+            names
+        }
+        Datum::Vector(ref items) => items.iter().flat_map(template_symbols).collect(),
+        _ => vec![],
+    }
+}
@@ -1,33 +1,755 @@
-use api;
-
-pub fn compile_list(vec: &mut Vec<Opcode>, s: &mut api::State)
-                    -> Result<usize, &'static str> {
-    try!(s.dup());
-    let mut length = 1usize;
-    while let Ok(x) = s.car() {
-        try!(compile_form(vec, s));
-        length += 1;
-        if (s.marked()) {
-            return Err("circular list not allowed")
-        }
-        s.mark();
-        try!(s.cdr());
-        try!(s.dup());
-    }
-    let retval = if s.null() {
-        Ok(length)
-    } else {
-        Err("dotted list not allowed")
+//! The compiler, from Scheme source to bytecode.
+//!
+//! Compilation has three passes: reading the source into `Datum`s
+//! (`datum`), expanding them into a core language with resolved variables
+//! (`syntax`, which expands `syntax-rules` macros with `macros` and
+//! `define-macro` macros with `expander`), and generating code
+//! (`codegen`).  Unless `Libraries::unoptimized` is set, the core language
+//! is simplified by `fold` before generating code, and the code is then
+//! optimized by `peephole`.
+//!
+//! A compiled `Program` is a list of functions, sharing one constants
+//! vector.  The first function is the program itself: it takes no
+//! arguments, runs the top-level forms in order, and returns the value of
+//! the last one.  `interp::load` turns a program into a closure.  Each
+//! function has a `Source`, saying where it came from, for backtraces.
+//!
+//! ### Variables
+//!
+//! Local variables live in frame slots.  A closure gets a copy of the value
+//! of each of its free variables when it is created, as an upvalue.  That is
+//! not enough for a variable that is assigned, whose closures must see the
+//! new value, or for `letrec`, whose closures are created before the
+//! variables they refer to are initialized, so captured variables that are
+//! assigned, and captured `letrec` variables, are boxed: the slot holds a
+//! pair, whose car is the value, and closures copy the pair.  Global
+//! variables are the values of their symbols.
+
+mod codegen;
+mod datum;
+mod expander;
+mod fold;
+mod libraries;
+mod macros;
+mod object;
+mod peephole;
+mod syntax;
+
+pub use self::datum::{Datum, read_all, read_lines};
+pub use self::expander::datum;
+pub use self::libraries::{Exports, Libraries};
+pub use self::object::{load_object, read_code, save_object, write_code};
+pub use self::syntax::Macros;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use bytecode::{Bytecode, ConstantPool, Function, Source};
+use library::Name;
+
+/// A compiled program.
+#[derive(Debug)]
+pub struct Program {
+    /// The code of all of the functions.
+    pub code: Vec<Bytecode>,
+
+    /// The functions, starting with the main function.  Their entries are
+    /// indices into `code`.
+    pub functions: Vec<Function>,
+
+    /// Where the functions came from, indexed like `functions`.
+    pub sources: Vec<Source>,
+
+    pub constants: ConstantPool,
+}
+
+/// Compiles the top-level forms `forms` into a program, with no libraries
+/// but the builtins.
+pub fn compile(forms: &[Datum]) -> Result<Program, String> {
+    compile_in(&mut Libraries::default(), forms)
+}
+
+/// Compiles the top-level forms `forms` into a program, which can import
+/// the libraries in `libraries`.  If compiling succeeds, `libraries` gains
+/// the libraries that the program defines, and its top-level imports.
+pub fn compile_in(libraries: &mut Libraries, forms: &[Datum]) -> Result<Program, String> {
+    compile_with(&mut syntax::Syntax::default(), libraries, forms, &[])
+}
+
+/// Like `compile_in`, but the forms start on the lines `lines`, as
+/// returned by `read_lines`, which the program's sources say.
+pub fn compile_lines(libraries: &mut Libraries,
+                     forms: &[Datum],
+                     lines: &[u32])
+                     -> Result<Program, String> {
+    compile_with(&mut syntax::Syntax::default(), libraries, forms, lines)
+}
+
+/// Like `compile_lines`, but the program can use the top-level macros in
+/// `macros`.  If compiling succeeds, `macros` gains the macros that the
+/// program defines, and loses those it redefines as variables; the REPL
+/// compiles each input this way, so that its macros outlive it.
+pub fn compile_toplevel(libraries: &mut Libraries,
+                        macros: &mut Macros,
+                        forms: &[Datum],
+                        lines: &[u32])
+                        -> Result<Program, String> {
+    let mut syntax = syntax::Syntax::with_macros(macros);
+    let res = compile_with(&mut syntax, libraries, forms, lines);
+    syntax.return_macros(macros, res.is_ok());
+    res
+}
+
+/// Compiles the file `path` into a program, like `compile_in`.  The files
+/// it includes are found relative to it.
+pub fn compile_file(libraries: &mut Libraries, path: &Path) -> Result<Program, String> {
+    let (forms, lines) = try!(read_file(path));
+    let mut syntax = syntax::Syntax::default();
+    syntax.files.push(path.to_owned());
+    compile_with(&mut syntax, libraries, &forms, &lines)
+}
+
+/// Compiles the top-level forms `forms` of closed code, such as a
+/// sandbox's, into a program.  Its identifiers can only refer to the
+/// globals in `globals`, which gains its definitions: they are named like
+/// those of the library `name`, so that they do not replace any others.
+pub fn compile_closed(libraries: &Libraries,
+                      name: Name,
+                      globals: &mut Exports,
+                      forms: &[Datum])
+                      -> Result<Program, String> {
+    let mut syntax = syntax::Syntax::closed(name, globals.clone());
+    syntax.libraries = libraries.clone();
+    let program = try!(generate(&mut syntax, forms, &[], !libraries.unoptimized));
+    *globals = syntax.globals;
+    Ok(program)
+}
+
+fn compile_with(syntax: &mut syntax::Syntax,
+                libraries: &mut Libraries,
+                forms: &[Datum],
+                lines: &[u32])
+                -> Result<Program, String> {
+    syntax.libraries = libraries.clone();
+    syntax.globals = libraries.imports.clone();
+    let program = try!(generate(syntax, forms, lines, !libraries.unoptimized));
+    *libraries = mem::replace(&mut syntax.libraries, Libraries::default());
+    libraries.imports = mem::replace(&mut syntax.globals, Exports::new());
+    Ok(program)
+}
+
+/// Generates the program of the top-level forms `forms`, which start on
+/// the lines `lines`, and are expanded with `syntax`.
+fn generate(syntax: &mut syntax::Syntax,
+            forms: &[Datum],
+            lines: &[u32],
+            optimize: bool)
+            -> Result<Program, String> {
+    let mut exprs = vec![];
+    for (i, form) in forms.iter().enumerate() {
+        syntax.line = lines.get(i).cloned().unwrap_or(0);
+        exprs.push(try!(syntax.toplevel(form)))
+    }
+    let mut body = syntax::Expr::Sequence(exprs);
+    if optimize {
+        body = fold::fold(&syntax.vars, body)
+    }
+    let main = Source {
+        name: Some("<top level>".to_owned()),
+        file: syntax.file(),
+        line: 0,
     };
-    s.drop();
-    retval
-}
-
-pub fn compile_form(vec: &mut Vec<Opcode>, s: &mut api::State)
-                    -> Result<usize, &'static str> {
-    match s.typeof() {
-        Typeof::Pair => {
-            try!(s.dup());
-            try!(s.car());
-            try!(s.cdr());
-            match 
+    codegen::generate(&syntax.vars, &body, optimize, main)
+}
+
+/// Reads all of the data in the file `path`, and the lines they start on.
+pub fn read_file(path: &Path) -> Result<(Vec<Datum>, Vec<u32>), String> {
+    let file = try!(File::open(path).map_err(|e| format!("can't read {}: {}", path.display(), e)));
+    read_lines(BufReader::new(file)).map_err(|e| format!("{}: read error: {:?}", path.display(), e))
+}
+
+/// The file that the file name `name` refers to, in the file `current`, or
+/// outside of any file if it is `None`.  Relative names are relative to the
+/// directory of `current`.
+pub fn resolve(current: Option<&Path>, name: &str) -> PathBuf {
+    match current.and_then(Path::parent) {
+        Some(directory) => directory.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use alloc;
+    use bytecode::{Bytecode, Constant, Opcode};
+    use interp;
+    use value::{self, Value};
+    use super::*;
+    use super::object::{read_object, write_object};
+
+    fn compile_str(source: &str) -> Result<Program, String> {
+        let forms = read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+        compile(&forms)
+    }
+
+    /// Compiles and runs `source` in a new state, and returns its value.
+    fn run(source: &str) -> Value {
+        let mut s = interp::new();
+        let value = interp::run(&mut s, source).unwrap();
+        assert!(s.heap.stack.is_empty());
+        value
+    }
+
+    fn has_closures(source: &str) -> bool {
+        compile_str(source).unwrap().code.iter().any(|op| match op.opcode {
+            Opcode::Closure => true,
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn let_and_let_star_bind_in_order() {
+        assert_eq!(run("(let ((x 1) (y 2)) (+ x y))").as_fixnum(), Ok(3));
+        assert_eq!(run("(let ((x 1)) (let ((x 2) (y x)) (- x y)))").as_fixnum(), Ok(1));
+        assert_eq!(run("(let* ((x 2) (y (* x 3))) (- y x))").as_fixnum(), Ok(4));
+        assert_eq!(run("(let ((f (lambda (x) (* x x)))) (+ (f 3) 1))").as_fixnum(), Ok(10));
+        assert_eq!(run("(let ((if (lambda (a b c) c))) (if #t 1 2))").as_fixnum(), Ok(2));
+        assert!(compile_str("(let ((x 1) (x 2)) x)").is_err());
+        assert!(compile_str("(let ((x)) x)").is_err());
+    }
+
+    #[test]
+    fn letrec_allows_mutual_recursion() {
+        let even = run("(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1)))))
+                                 (odd? (lambda (n) (if (= n 0) #f (even? (- n 1))))))
+                          (even? 1001))");
+        assert_eq!(even.get(), value::FALSE);
+        let count = run("(letrec ((f (lambda (n acc) (if (< n 1) acc (f (- n 1) (cons n acc))))))
+                           (car (cdr (f 3 '()))))");
+        assert_eq!(count.as_fixnum(), Ok(2));
+    }
+
+    #[test]
+    fn internal_definitions_are_letrec_star() {
+        let source = "(define (f n)
+                        (define (even? n) (if (= n 0) #t (odd? (- n 1))))
+                        (define (odd? n) (if (= n 0) #f (even? (- n 1))))
+                        (define m (* n 10))
+                        (if (even? n) m (+ m 1)))
+                      (+ (f 4) (f 3))";
+        assert_eq!(run(source).as_fixnum(), Ok(71));
+        assert_eq!(run("(let ((x 1)) (define y (+ x 1)) (define z (* y 3)) z)").as_fixnum(),
+                   Ok(6));
+        let source = "(define-syntax define-two
+                        (syntax-rules () ((_ a b v) (begin (define a v) (define b v)))))
+                      ((lambda () (define-two p q 5) (begin (define r 6)) (+ p q r)))";
+        assert_eq!(run(source).as_fixnum(), Ok(16));
+        assert_eq!(run("(define x 1) (let () (define x 2) x)").as_fixnum(), Ok(2));
+        assert!(compile_str("(lambda () 1 (define x 2) x)").is_err());
+        assert!(compile_str("(lambda () (define x 2))").is_err());
+        assert!(compile_str("(lambda () (define x 1) (define x 2) x)").is_err());
+    }
+
+    #[test]
+    fn assigned_variables_are_shared_with_closures() {
+        let source = "(define (make-counter) (let ((n 0)) (lambda () (set! n (+ n 1)) n)))
+                      (define c (make-counter))
+                      (c)
+                      (c)
+                      (+ (c) (* 10 ((make-counter))))";
+        assert_eq!(run(source).as_fixnum(), Ok(13));
+        let source = "(define (f x) (let ((get (lambda () x))) (set! x 5) (get))) (f 1)";
+        assert_eq!(run(source).as_fixnum(), Ok(5));
+        assert_eq!(run("(let ((x 1)) ((lambda () (set! x 2))) x)").as_fixnum(), Ok(2));
+        // Each iteration of a loop has its own variables.
+        let source = "(define fs '())
+                      (do ((i 0 (+ i 1))) ((= i 3))
+                        (set! fs (cons (lambda () i) fs))
+                        (set! i (+ i 0)))
+                      (+ ((car fs)) (* 10 ((car (cdr fs)))))";
+        assert_eq!(run(source).as_fixnum(), Ok(12));
+    }
+
+    #[test]
+    fn derived_conditionals_choose_a_branch() {
+        assert_eq!(run("(+ (when (< 1 2) 1 2) (unless (< 2 1) 3))").as_fixnum(), Ok(5));
+        assert!(run("(when #f 1)").is_unspecified());
+        let cond = "(define (f x)
+                      (cond ((< x 0) 1)
+                            ((assv x '((0 . 20) (1 . 300))) => cdr)
+                            ((= x 2))
+                            (else 4000)))
+                    (+ (f -1) (f 0) (f 1) (if (eq? (f 2) #t) 50000 0) (f 3))";
+        assert_eq!(run(cond).as_fixnum(), Ok(54321));
+        assert!(run("(cond (#f 1))").is_unspecified());
+        let case = "(define (f x)
+                      (case x
+                        ((1 2 3) 1)
+                        ((a b) => (lambda (x) (if (eq? x 'b) 20 0)))
+                        (else 300)))
+                    (+ (f 2) (f 'b) (f 9))";
+        assert_eq!(run(case).as_fixnum(), Ok(321));
+        assert_eq!(run("(case 5 ((1) 1) (else => (lambda (x) (* x 2))))").as_fixnum(), Ok(10));
+        assert_eq!(run("(let ((else #f)) (cond (else 1) (#t 2)))").as_fixnum(), Ok(2));
+        assert!(compile_str("(cond (else 1) (#t 2))").is_err());
+        assert!(compile_str("(case 1 (2 3))").is_err());
+    }
+
+    #[test]
+    fn named_let_loops_without_closures() {
+        let source = "(let loop ((i 0) (acc 0)) (if (< i 10) (loop (+ i 1) (+ acc i)) acc))";
+        assert_eq!(run(source).as_fixnum(), Ok(45));
+        assert!(!has_closures(source));
+        let nested = "(let outer ((i 0) (n 0))
+                        (if (< i 3)
+                            (outer (+ i 1) (let inner ((j i) (n n)) (if (< j 3) (inner (+ j 1) (+ n 1)) n)))
+                            n))";
+        assert_eq!(run(nested).as_fixnum(), Ok(6));
+        assert!(!has_closures(nested));
+    }
+
+    #[test]
+    fn and_and_or_short_circuit() {
+        assert_eq!(run("(and)").get(), value::TRUE);
+        assert_eq!(run("(or)").get(), value::FALSE);
+        assert_eq!(run("(and 5)").as_fixnum(), Ok(5));
+        assert_eq!(run("(or 5)").as_fixnum(), Ok(5));
+        assert_eq!(run("(and 1 #f 3)").get(), value::FALSE);
+        let source = "(define (f x) (or (< x 0) (and (< x 10) (* x 2)) #f))
+                      (+ (f 3) (if (eq? (f -1) #t) 100 0) (if (f 20) 1000 0))";
+        assert_eq!(run(source).as_fixnum(), Ok(106));
+        let source = "(define (t) 1)
+                      (define n 0)
+                      (or (t) (set! n 1))
+                      (and (not (t)) (set! n 2))
+                      n";
+        assert_eq!(run(source).as_fixnum(), Ok(0));
+        // The last test is a tail call.
+        let source = "(define (f n) (or (= n 0) (f (- n 1)))) (f 100000)";
+        assert_eq!(run(source).get(), value::TRUE);
+        let code = compile_str("(lambda (a b) (or a (and b a) b))").unwrap().code;
+        assert!(!code.iter().any(|op| match op.opcode {
+            Opcode::Call | Opcode::TailCall => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn do_loops_without_closures() {
+        let source = "(do ((i 0 (+ i 1)) (acc 0 (+ acc i))) ((= i 10) acc))";
+        assert_eq!(run(source).as_fixnum(), Ok(45));
+        assert!(!has_closures(source));
+        let source = "(let ((v (make-vector 3 0)))
+                        (do ((i 0 (+ i 1)) (v v)) ((= i 3) (vector-ref v 2))
+                          (vector-set! v i (* i i))))";
+        assert_eq!(run(source).as_fixnum(), Ok(4));
+        assert!(run("(do ((i 0 (+ i 1))) ((= i 2)))").is_unspecified());
+        assert!(compile_str("(do ((i 0 1 2)) (#t))").is_err());
+        assert!(compile_str("(do ((i 0)) ())").is_err());
+    }
+
+    #[test]
+    fn loops_allocate_nothing_per_iteration() {
+        let allocated = |source: String| {
+            let mut s = interp::new();
+            interp::run(&mut s, &source).unwrap();
+            s.heap.stats().bytes_allocated
+        };
+        let named_let = |n| format!("(let loop ((i 0)) (if (< i {}) (loop (+ i 1)) i))", n);
+        assert_eq!(allocated(named_let(10)), allocated(named_let(10000)));
+        let do_loop = |n| format!("(do ((i 0 (+ i 1)) (acc 0 (+ acc i))) ((= i {}) acc))", n);
+        assert_eq!(allocated(do_loop(10)), allocated(do_loop(10000)));
+    }
+
+    #[test]
+    fn named_let_falls_back_to_a_procedure() {
+        // Not a tail call.
+        let source = "(let f ((n 3)) (if (= n 0) 0 (+ 1 (f (- n 1)))))";
+        assert_eq!(run(source).as_fixnum(), Ok(3));
+        assert!(has_closures(source));
+        // Captured by a lambda.
+        let source = "(let f ((n 3)) (if (= n 0) 7 ((lambda () (f (- n 1))))))";
+        assert_eq!(run(source).as_fixnum(), Ok(7));
+        assert!(has_closures(source));
+    }
+
+    #[test]
+    fn syntax_rules_macros_are_hygienic() {
+        let or = "(define-syntax my-or
+                    (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))";
+        assert_eq!(run(&format!("{} (let ((t 5)) (my-or #f t))", or)).as_fixnum(), Ok(5));
+        assert_eq!(run(&format!("{} (my-or)", or)).get(), value::FALSE);
+        // `if` in the template is the special form, not the local variable.
+        let source = "(define-syntax my-if (syntax-rules () ((_ c a b) (if c a b))))
+                      (let ((if (lambda (a b c) c))) (my-if #t 1 2))";
+        assert_eq!(run(source).as_fixnum(), Ok(1));
+        let source = "(define-syntax my-let*
+                        (syntax-rules ()
+                          ((_ () body ...) (let () body ...))
+                          ((_ ((x v) rest ...) body ...) (let ((x v)) (my-let* (rest ...) body ...)))))
+                      (my-let* ((a 1) (b (+ a 1))) b)";
+        assert_eq!(run(source).as_fixnum(), Ok(2));
+        assert!(compile_str("(define-syntax m (syntax-rules () ((_ x) x))) (m)").is_err());
+        assert!(compile_str("(define-syntax m (syntax-rules () ((_ x) (m x)))) (m 1)").is_err());
+    }
+
+    #[test]
+    fn syntax_rules_handles_nested_ellipses() {
+        let q = "(define-syntax q (syntax-rules () ((_ (a b ...) ...) '((b ... a) ...))))";
+        // ((2 3 1) (5 4))
+        assert_eq!(run(&format!("{} (car (car (q (1 2 3) (4 5))))", q)).as_fixnum(), Ok(2));
+        assert_eq!(run(&format!("{} (car (cdr (car (cdr (q (1 2 3) (4 5))))))", q)).as_fixnum(),
+                   Ok(4));
+        let escaped = "(define-syntax e (syntax-rules () ((_ x) '(x (... ...)))))
+                       (eq? (car (cdr (e 1))) '...)";
+        assert_eq!(run(escaped).get(), value::TRUE);
+    }
+
+    #[test]
+    fn define_macro_transforms_unevaluated_forms() {
+        let unless = "(define-macro (my-unless c e) (cons 'if (cons c (cons #f (cons e '())))))";
+        assert_eq!(run(&format!("{} (my-unless (= 1 2) 7)", unless)).as_fixnum(), Ok(7));
+        let source = "(define-macro (head x) (cons 'quote (cons (car x) '())))
+                      (eq? (head (abc def)) 'abc)";
+        assert_eq!(run(source).get(), value::TRUE);
+        assert!(compile_str("(define-macro m 5)").is_err());
+        assert!(compile_str("(define-macro (forever) '(forever)) (forever)").is_err());
+        assert!(compile_str("(define-macro (f) (lambda () 1)) (f)").is_err());
+    }
+
+    #[test]
+    fn apply_spreads_its_last_argument() {
+        assert_eq!(run("(car (apply cons 1 '(2)))").as_fixnum(), Ok(1));
+        assert_eq!(run("(car (cdr (cdr (apply (lambda args args) 1 2 '(3)))))").as_fixnum(),
+                   Ok(3));
+        // `apply` as a value, and applied to itself.
+        assert_eq!(run("(let ((ap apply)) (cdr (ap cons '(1 2))))").as_fixnum(), Ok(2));
+        assert_eq!(run("(car (apply apply cons '((3 4))))").as_fixnum(), Ok(3));
+        // Calls through `apply` in tail position are tail calls.
+        let source = "(define (count n) (if (= n 0) 'done (apply count (- n 1) '())))
+                      (eq? (count 100000) 'done)";
+        assert_eq!(run(source).get(), value::TRUE);
+        assert!(interp::run(&mut interp::new(), "(apply cons 1 2)").is_err());
+    }
+
+    #[test]
+    fn optional_and_keyword_parameters_have_defaults() {
+        let source = "(define (f a (b (+ a 1)) #!key (c 3) d) (+ a (* 10 b) (* 100 c)))";
+        for &(call, result) in &[("(f 1)", 321), ("(f 1 4)", 341), ("(f 1 4 c: 5)", 541),
+                                 ("(f 1 4 d: 0 c: 2 c: 7)", 241)] {
+            assert_eq!(run(&format!("{} {}", source, call)).as_fixnum(), Ok(result));
+        }
+        assert_eq!(run("((lambda (a #!optional b) (if b 1 a)) 2)").as_fixnum(), Ok(2));
+        assert_eq!(run("((lambda ((a 1) #!rest r) (car r)) 2 3)").as_fixnum(), Ok(3));
+        assert_eq!(run("((lambda ((a 1) . r) r) 2)").get(), value::NIL);
+        assert_eq!(run("(keyword? size:)").get(), value::TRUE);
+        for call in &["(f)", "(f 1 2 e: 3)", "(f 1 2 c:)", "(f 1 2 3)"] {
+            assert!(interp::run(&mut interp::new(), &format!("{} {}", source, call)).is_err());
+        }
+        assert!(compile_str("((lambda (a (b 1) 2) a) 1)").is_err());
+        assert!(compile_str("(lambda (#!key a #!rest r) a)").is_err());
+        assert!(compile_str("(lambda (a #!optional (a 1)) a)").is_err());
+    }
+
+    #[test]
+    fn libraries_export_only_what_they_name() {
+        let stack = "(define-library (data stack)
+                       (export make push (rename top peek))
+                       (import (rusty base))
+                       (begin
+                         (define (make) '())
+                         (define (push s x) (cons x s))
+                         (define (top s) (car (checked s)))
+                         (define (checked s) (if (null? s) (car 'empty) s))))";
+        let source = format!("{} (import (prefix (data stack) s:) (rename (only (rusty base) car) \
+                              (car first)))
+                              (first (cons (s:peek (s:push (s:make) 5)) 6))",
+                             stack);
+        assert_eq!(run(&source).as_fixnum(), Ok(5));
+        // Definitions that shadow primitives are not inlined.
+        let source = "(define-library (m) (export car) (begin (define (car x) 'mine)))
+                      (define (f) (car '(1)))
+                      (import (m))
+                      (eq? (car (f)) 'mine)";
+        assert_eq!(run(source).get(), value::TRUE);
+        assert!(interp::run(&mut interp::new(), &format!("{} push", stack)).is_err());
+        assert!(compile_str("(define-library (a) (export x) (begin (define y 1)))").is_err());
+        assert!(compile_str("(import (no such library))").is_err());
+        assert!(compile_str("(import (only (rusty base) nothing))").is_err());
+        // Libraries and imports outlive the program that defines them.
+        let mut libraries = Libraries::default();
+        let forms = read_all(&mut stack.as_bytes().bytes().peekable()).unwrap();
+        compile_in(&mut libraries, &forms).unwrap();
+        let forms = read_all(&mut "(import (data stack))".as_bytes().bytes().peekable()).unwrap();
+        compile_in(&mut libraries, &forms).unwrap();
+        assert_eq!(libraries.imports["push"], "(data stack) push");
+    }
+
+    #[test]
+    fn include_and_load_find_files_relative_to_the_including_file() {
+        use std::fs::{self, File};
+        use std::io::Write;
+
+        let name = format!("rusty-scheme-include-{}", ::std::process::id());
+        let dir = ::std::env::temp_dir().join(name);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let write = |name: &str, source: &str| {
+            File::create(dir.join(name)).unwrap().write_all(source.as_bytes()).unwrap()
+        };
+        write("main.scm", "(include \"sub/a.scm\") (define z (+ (f 1) y))");
+        write("sub/a.scm", "(define (f x) (+ x 1)) (include \"b.scm\")");
+        write("sub/b.scm", "(define y 10)");
+        write("loop.scm", "(include \"loop.scm\")");
+        write("run.scm", "(load \"sub/b.scm\") (define w (+ y 1))");
+        let program = compile_file(&mut Libraries::default(), &dir.join("main.scm")).unwrap();
+        let mut s = interp::new();
+        interp::load(&mut s, &program).unwrap();
+        interp::call(&mut s, 0).unwrap();
+        s.heap.stack.pop();
+        s.heap.intern("z");
+        s.heap.load_global().unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(12));
+        assert!(compile_file(&mut Libraries::default(), &dir.join("loop.scm")).is_err());
+        assert!(compile_file(&mut Libraries::default(), &dir.join("missing.scm")).is_err());
+        let source = format!("(load \"{}\") w", dir.join("run.scm").display());
+        assert_eq!(run(&source).as_fixnum(), Ok(11));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn peephole_optimization_keeps_the_meaning_of_programs() {
+        let source = "(define (f a b) (car (cons (if a (if b (* 6 7) 2) 3) '())))
+                      (define x (begin (set! f f) (+ (- 10 4) 1)))
+                      (cons (f #t #t) (cons (f #t #f) (cons (f #f #t) x)))";
+        let forms = read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+        let optimized = compile(&forms).unwrap();
+        let unoptimized = compile_in(&mut Libraries::unoptimized(), &forms).unwrap();
+        assert!(optimized.code.len() < unoptimized.code.len());
+        let count = |program: &Program, f: &Fn(Opcode) -> bool| {
+            program.code.iter().filter(|op| f(op.opcode)).count()
+        };
+        let arith = |op| match op {
+            Opcode::Add | Opcode::Subtract | Opcode::Multiply => true,
+            _ => false,
+        };
+        assert_eq!(count(&optimized, &arith), 0);
+        assert_eq!(count(&unoptimized, &arith), 3);
+        assert!(count(&optimized, &|op| match op {
+                          Opcode::LoadConstantGlobal => true,
+                          _ => false,
+                      }) > 0);
+        // No jump lands on an unconditional jump.
+        for (i, function) in optimized.functions.iter().enumerate() {
+            let end = optimized.functions.get(i + 1).map_or(optimized.code.len(), |f| f.entry);
+            let code = &optimized.code[function.entry..end];
+            for op in code.iter().filter(|op| op.opcode.is_jump()) {
+                if let Some(&Bytecode { opcode: Opcode::Jump, .. }) = code.get(op.jump_target()) {
+                    panic!("jump to a jump")
+                }
+            }
+        }
+        let printed = |program: &Program| {
+            let mut s = interp::new();
+            interp::load(&mut s, program).unwrap();
+            interp::call(&mut s, 0).unwrap();
+            ::print::print(&mut s, false).unwrap()
+        };
+        assert_eq!(printed(&optimized), "(42 2 3 . 7)");
+        assert_eq!(printed(&unoptimized), printed(&optimized));
+    }
+
+    #[test]
+    fn common_sequences_use_superinstructions() {
+        let source = "(define (count-down n acc)
+                        (if (< 0 n) (count-down (- n 1) (cons (car acc) acc)) acc))
+                      (define (len xs n) (if (null? xs) n (len (cdr xs) (+ n 1))))
+                      (define (id x) x)
+                      (cons (count-down 3 '(a)) (len (count-down 200 '(b)) (id 0)))";
+        let code = compile_str(source).unwrap().code;
+        let has = |f: &Fn(Opcode) -> bool| code.iter().any(|op| f(op.opcode));
+        assert!(has(&|op| match op {
+            Opcode::AddConst => true,
+            _ => false,
+        }));
+        assert!(has(&|op| match op {
+            Opcode::LessJumpIfFalse => true,
+            _ => false,
+        }));
+        assert!(!has(&|op| match op {
+            Opcode::Less | Opcode::Add | Opcode::Subtract => true,
+            _ => false,
+        }));
+        let pair = run(source);
+        let list = pair.car().unwrap();
+        assert_eq!(list.car().unwrap().get(), list.cdr().unwrap().car().unwrap().get());
+        assert_eq!(pair.cdr().unwrap().as_fixnum(), Ok(201));
+    }
+
+    #[test]
+    fn constant_expressions_compile_to_constants() {
+        for &(source, value) in &[("(+ 1 (* 2 3))", 7),
+                                  ("(let ((x 2) (y 3)) (if (< x y) (- y x) 0))", 1),
+                                  ("(car (cdr '(1 2 3)))", 2),
+                                  ("(string-length \"h\u{e9}llo\")", 5)] {
+            let program = compile_str(source).unwrap();
+            assert_eq!(program.code.len(), 2, "{}", source);
+            assert_eq!(run(source).as_fixnum(), Ok(value), "{}", source);
+            let forms = read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+            let program = compile_in(&mut Libraries::unoptimized(), &forms).unwrap();
+            assert!(program.code.len() > 2, "{}", source);
+        }
+        // Assigned variables are not propagated, and operations that would
+        // fail are left alone.
+        let source = "(let ((x 1)) (set! x 2) (+ x 1))";
+        assert!(compile_str(source).unwrap().code.len() > 2);
+        assert_eq!(run(source).as_fixnum(), Ok(3));
+        assert!(compile_str("(car 1)").unwrap().code.len() > 2);
+        assert!(compile_str("(string-length (quote a))").unwrap().code.len() > 2);
+    }
+
+    #[test]
+    fn vector_literals_are_built_once() {
+        let source = "(define (f) #(1 \"a\" (b #f) #())) (cons (f) (f))";
+        let program = compile_str(source).unwrap();
+        assert!(!program.code.iter().any(|op| match op.opcode {
+            Opcode::MakeArray => true,
+            _ => false,
+        }));
+        let pair = run(source);
+        assert_eq!(pair.car().unwrap().get(), pair.cdr().unwrap().get());
+        let vectors = program.constants
+                             .constants()
+                             .iter()
+                             .filter(|x| match **x {
+                                 Constant::Vector(_) => true,
+                                 _ => false,
+                             })
+                             .count();
+        assert_eq!(vectors, 1);
+        // Vectors evaluate to themselves, whether quoted or not.
+        assert_eq!(run("(equal? #(1 #(2) (a)) '#(1 #(2) (a)))").get(), value::TRUE);
+        assert_eq!(run("(vector-ref #(1 2) 1)").as_fixnum(), Ok(2));
+    }
+
+    #[test]
+    fn compiled_code_stays_within_its_frames() {
+        let source = "(define (f . xs) (let loop ((xs xs) (n 0))
+                                         (if (null? xs) n (loop (cdr xs) (+ n (car xs))))))
+                      (define (g x) (letrec ((h (lambda () (set! x (+ x 1)) x))) (h) (h)))
+                      (cons (apply f 1 2 '(3)) (cons (g 5) (f)))";
+        let forms = read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+        let mut libraries = Libraries::unoptimized();
+        for program in &[compile(&forms).unwrap(), compile_in(&mut libraries, &forms).unwrap()] {
+            for (i, function) in program.functions.iter().enumerate() {
+                let end = program.functions.get(i + 1).map_or(program.code.len(), |f| f.entry);
+                let code = &program.code[function.entry..end];
+                assert_eq!(::bytecode::verify(&program.functions, i, code), Ok(()));
+                assert!(!code.iter().any(|op| match op.opcode {
+                    Opcode::Pop => true,
+                    _ => false,
+                }));
+            }
+        }
+        let list = run(source);
+        assert_eq!(list.car().unwrap().as_fixnum(), Ok(6));
+        assert_eq!(list.cdr().unwrap().car().unwrap().as_fixnum(), Ok(7));
+        assert_eq!(list.cdr().unwrap().cdr().unwrap().as_fixnum(), Ok(0));
+    }
+
+    #[test]
+    fn tail_calls_are_only_in_tail_position() {
+        // The loop's `if` jumps to its end after a branch that loops back.
+        let source = "(define (g) 1)
+                      (define (f x) (g) (if x (g) (+ 1 (g))))
+                      (define (h n) (+ 1 (let loop ((i 0)) (if (< i n) (loop (+ i 1)) i))))
+                      (define (k) (apply + 1 '(2)))
+                      (+ (f #t) (f #f) (h 3) (k))";
+        let forms = read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+        let mut libraries = Libraries::unoptimized();
+        for program in &[compile(&forms).unwrap(), compile_in(&mut libraries, &forms).unwrap()] {
+            for (i, function) in program.functions.iter().enumerate() {
+                let end = program.functions.get(i + 1).map_or(program.code.len(), |f| f.entry);
+                let code = &program.code[function.entry..end];
+                assert_eq!(::bytecode::verify(&program.functions, i, code), Ok(()));
+            }
+            let (mut calls, mut tail_calls) = (0, 0);
+            for op in &program.code {
+                match op.opcode {
+                    Opcode::Call => calls += 1,
+                    Opcode::TailCall | Opcode::Apply if op.is_tail_call() => tail_calls += 1,
+                    _ => {}
+                }
+            }
+            // The first `(g)` in `f`, the one in `(+ 1 (g))`, and the
+            // arguments of the last `+`.
+            assert_eq!(calls, 6);
+            // The other `(g)`, the `apply`, and the last `+`.
+            assert_eq!(tail_calls, 3);
+        }
+        assert_eq!(run(source).as_fixnum(), Ok(10));
+    }
+
+    #[test]
+    fn closures_keep_only_the_variables_they_refer_to() {
+        let source = "(define box #f)
+                      (define (make a b)
+                        (let ((big (cons a b)))
+                          (set! box (make-weak-box big))
+                          (lambda () (lambda () b))))
+                      (define keep (make 1 2))
+                      ((keep))";
+        let program = compile_str(source).unwrap();
+        let upvalues: Vec<_> = program.functions.iter().map(|f| f.upvalues).collect();
+        assert_eq!(upvalues, [0, 0, 1, 1]);
+        let mut s = interp::new();
+        interp::load(&mut s, &program).unwrap();
+        interp::call(&mut s, 0).unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(2));
+        // Neither closure refers to `big`, or `a`, so they do not keep it.
+        alloc::collect(&mut s.heap);
+        let pair = interp::run(&mut s, "(cons (weak-box-value box) ((keep)))").unwrap();
+        assert_eq!(pair.car().unwrap().get(), value::FALSE);
+        assert_eq!(pair.cdr().unwrap().as_fixnum(), Ok(2));
+    }
+
+    #[test]
+    fn compiled_objects_run_like_the_programs_they_were_saved_from() {
+        let source = "(define (f x) (cons x '#(\"s\" #t (())))) (define xs (f 'sym)) (cons 7 xs)";
+        let mut object = vec![];
+        write_object(&compile_str(source).unwrap(), &mut object).unwrap();
+        let program = read_object(&object[..]).unwrap();
+        let mut s = interp::new();
+        interp::load(&mut s, &program).unwrap();
+        interp::call(&mut s, 0).unwrap();
+        let pair = s.heap.stack.pop().unwrap();
+        assert_eq!(pair.car().unwrap().as_fixnum(), Ok(7));
+        s.heap.intern("sym");
+        let sym = s.heap.stack.pop().unwrap();
+        assert_eq!(pair.cdr().unwrap().car().unwrap().get(), sym.get());
+        assert_eq!(program.constants.constants(),
+                   compile_str(source).unwrap().constants.constants());
+        assert!(read_object(&object[..object.len() - 1]).is_err());
+        assert!(read_object(&b"RSBX"[..]).is_err());
+        let last = object.len() - 4;
+        object[last] = 0xff;
+        assert!(read_object(&object[..]).is_err());
+    }
+
+    #[test]
+    fn functions_know_where_they_came_from() {
+        let source = "(define (f) 1)\n\n  (define g\n (lambda () (let l () (+ 1 (l)))))\n\
+                      (lambda () 2)";
+        let (forms, lines) = read_lines(source.as_bytes()).unwrap();
+        assert_eq!(lines, vec![1, 3, 5]);
+        let program = compile_lines(&mut Libraries::default(), &forms, &lines).unwrap();
+        let sources: Vec<_> = program.sources.iter().map(|source| source.to_string()).collect();
+        assert_eq!(sources,
+                   vec!["<top level>", "f at line 1", "g at line 3", "l at line 3",
+                        "<lambda> at line 5"]);
+        assert_eq!(program.sources.len(), program.functions.len());
+    }
+}
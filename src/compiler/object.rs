@@ -0,0 +1,254 @@
+//! Compiled-object files (`.rsbc`): programs saved after compiling, so that
+//! they can be run without reading or compiling them again.
+//!
+//! A file is the magic number `RSBC`, a format version, and then the
+//! constants, the functions, and the code of the program, encoded as in
+//! `binary`.  Symbols are saved by name, and interned when the program is
+//! loaded, like those of a freshly compiled program.
+//!
+//! Loading checks that what the file describes is consistent – opcodes
+//! exist, functions and constant ranges are within the program, and the
+//! code of each function stays within its frame (see `bytecode::verify`) –
+//! but not that the code is safe to run.  Only load files you trust.
+//!
+//! Libraries defined by the program are not saved: a saved program can
+//! define globals, but not libraries that later programs can import.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use arith;
+use binary::{Reader, write_str, write_u64};
+use bytecode::{self, Bytecode, Constant, ConstantPool, ConstantRange, Function, Opcode, Source};
+use super::Program;
+
+const MAGIC: &'static [u8; 4] = b"RSBC";
+const VERSION: u64 = 2;
+
+const FIXNUM: u8 = 0;
+const SYMBOL: u8 = 1;
+const STRING: u8 = 2;
+const TRUE: u8 = 3;
+const FALSE: u8 = 4;
+const NIL: u8 = 5;
+const PAIR: u8 = 6;
+const VECTOR: u8 = 7;
+const FLONUM: u8 = 8;
+const RATIO: u8 = 9;
+const CHAR: u8 = 10;
+
+/// Writes `program` to `w`.
+pub fn write_object<W: Write>(program: &Program, w: &mut W) -> io::Result<()> {
+    try!(w.write_all(MAGIC));
+    try!(write_u64(w, VERSION));
+    let constants = program.constants.constants();
+    try!(write_u64(w, constants.len() as u64));
+    for constant in constants {
+        try!(write_constant(w, constant))
+    }
+    write_code(w, &program.functions, &program.code)
+}
+
+/// Writes `constant`, and then its parts, if it has any.
+fn write_constant<W: Write>(w: &mut W, constant: &Constant) -> io::Result<()> {
+    match *constant {
+        Constant::Fixnum(x) => {
+            try!(w.write_all(&[FIXNUM]));
+            write_u64(w, x as u64)
+        }
+        Constant::Flonum(x) => {
+            try!(w.write_all(&[FLONUM]));
+            write_u64(w, x)
+        }
+        Constant::Ratio(n, d) => {
+            try!(w.write_all(&[RATIO]));
+            try!(write_u64(w, n as u64));
+            write_u64(w, d as u64)
+        }
+        Constant::Char(c) => {
+            try!(w.write_all(&[CHAR]));
+            write_u64(w, c as u64)
+        }
+        Constant::Symbol(ref name) => {
+            try!(w.write_all(&[SYMBOL]));
+            write_str(w, name)
+        }
+        Constant::Str(ref s) => {
+            try!(w.write_all(&[STRING]));
+            write_str(w, s)
+        }
+        Constant::Bool(x) => w.write_all(&[if x { TRUE } else { FALSE }]),
+        Constant::Nil => w.write_all(&[NIL]),
+        Constant::Pair(ref pair) => {
+            try!(w.write_all(&[PAIR]));
+            try!(write_constant(w, &pair.0));
+            write_constant(w, &pair.1)
+        }
+        Constant::Vector(ref items) => {
+            try!(w.write_all(&[VECTOR]));
+            try!(write_u64(w, items.len() as u64));
+            for x in items {
+                try!(write_constant(w, x))
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Reads what `write_constant` wrote.
+fn read_constant<R: Read>(r: &mut Reader<R>) -> Result<Constant, String> {
+    Ok(match try!(r.bytes(1))[0] {
+        FIXNUM => Constant::Fixnum(try!(r.usize(::std::usize::MAX >> 2))),
+        FLONUM => Constant::Flonum(try!(r.u64())),
+        RATIO => {
+            let (n, d) = (try!(r.u64()) as i64, try!(r.u64()) as i64);
+            // Only a ratio in lowest terms reads back as itself.
+            match arith::parse(&format!("{}/{}", n, d), 10) {
+                arith::Parsed::Ratio(x, y) if (x as i64, y as i64) == (n, d) => {
+                    Constant::Ratio(x, y)
+                }
+                _ => return Err(r.error("bad ratio")),
+            }
+        }
+        CHAR => {
+            let code = try!(r.u64());
+            match ::std::char::from_u32(code as u32) {
+                Some(c) if c as u64 == code => Constant::Char(c),
+                _ => return Err(r.error("bad character")),
+            }
+        }
+        SYMBOL => Constant::Symbol(try!(r.string())),
+        STRING => Constant::Str(try!(r.string())),
+        TRUE => Constant::Bool(true),
+        FALSE => Constant::Bool(false),
+        NIL => Constant::Nil,
+        PAIR => {
+            let car = try!(read_constant(r));
+            Constant::Pair(Box::new((car, try!(read_constant(r)))))
+        }
+        VECTOR => {
+            let mut items = vec![];
+            for _ in 0..try!(r.usize(::std::usize::MAX)) {
+                items.push(try!(read_constant(r)))
+            }
+            Constant::Vector(items)
+        }
+        _ => return Err(r.error("unknown kind of constant")),
+    })
+}
+
+/// Writes the functions `functions`, and their code `code`.  Snapshots
+/// save the code of an interpreter this way too.
+pub fn write_code<W: Write>(w: &mut W,
+                            functions: &[Function],
+                            code: &[Bytecode])
+                            -> io::Result<()> {
+    try!(write_u64(w, functions.len() as u64));
+    for function in functions {
+        for &x in &[function.entry, function.nargs, function.rest as usize,
+                    function.constants.start, function.constants.len, function.first,
+                    function.frame_size, function.upvalues] {
+            try!(write_u64(w, x as u64))
+        }
+    }
+    try!(write_u64(w, code.len() as u64));
+    for op in code {
+        try!(w.write_all(&[op.opcode as u8, op.src, op.src2, op.dst]))
+    }
+    Ok(())
+}
+
+/// Saves `program` to the file `path`.
+pub fn save_object(program: &Program, path: &Path) -> Result<(), String> {
+    let error = |e: io::Error| format!("can't write {}: {}", path.display(), e);
+    let mut w = BufWriter::new(try!(File::create(path).map_err(&error)));
+    write_object(program, &mut w).and_then(|()| w.flush()).map_err(&error)
+}
+
+/// Reads what `write_code` wrote.  Only checks that the functions are in
+/// the code, and verifies their code: what their constants ranges and first
+/// functions refer to is up to the caller.
+pub fn read_code<R: Read>(r: &mut Reader<R>) -> Result<(Vec<Function>, Vec<Bytecode>), String> {
+    let max = ::std::usize::MAX;
+    let mut functions = vec![];
+    for _ in 0..try!(r.usize(max)) {
+        let entry = try!(r.usize(max));
+        let nargs = try!(r.usize(max));
+        let rest = try!(r.usize(1)) == 1;
+        let start = try!(r.usize(max));
+        let len = try!(r.usize(max));
+        let first = try!(r.usize(max));
+        let frame_size = try!(r.usize(max));
+        let upvalues = try!(r.usize(max));
+        functions.push(Function {
+            entry: entry,
+            nargs: nargs,
+            rest: rest,
+            constants: ConstantRange { start: start, len: len },
+            first: first,
+            frame_size: frame_size,
+            upvalues: upvalues,
+        })
+    }
+    let mut code = vec![];
+    for _ in 0..try!(r.usize(max)) {
+        let op = try!(r.bytes(4));
+        code.push(Bytecode {
+            opcode: try!(Opcode::from_u8(op[0]).ok_or_else(|| r.error("unknown opcode"))),
+            src: op[1],
+            src2: op[2],
+            dst: op[3],
+        })
+    }
+    if functions.iter().any(|f| f.entry >= code.len() || f.first >= functions.len()) {
+        return Err(r.error("function outside of the code"))
+    }
+    for (i, function) in functions.iter().enumerate() {
+        let end = functions.get(i + 1).map_or(code.len(), |next| next.entry);
+        if end < function.entry {
+            return Err(r.error("functions out of order"))
+        }
+        try!(bytecode::verify(&functions, i, &code[function.entry..end])
+                 .map_err(|e| r.error(&format!("function {}: {}", i, e))))
+    }
+    Ok((functions, code))
+}
+
+/// Reads a program written by `write_object` from `r`.
+pub fn read_object<R: Read>(r: R) -> Result<Program, String> {
+    let mut r = Reader::new(r, "compiled object");
+    if &try!(r.bytes(4))[..] != MAGIC {
+        return Err(r.error("not a compiled object"))
+    }
+    let version = try!(r.u64());
+    if version != VERSION {
+        return Err(r.error(&format!("unsupported version {}", version)))
+    }
+    let mut constants = vec![];
+    for _ in 0..try!(r.usize(::std::usize::MAX)) {
+        constants.push(try!(read_constant(&mut r)))
+    }
+    let (functions, code) = try!(read_code(&mut r));
+    let in_pool = |f: &Function| {
+        f.constants.start <= constants.len() &&
+        f.constants.len <= constants.len() - f.constants.start
+    };
+    if functions.is_empty() || !functions.iter().all(|f| f.first == 0 && in_pool(f)) {
+        return Err(r.error("function outside of the program"))
+    }
+    // Sources are not saved, so backtraces cannot tell where the functions
+    // came from.
+    Ok(Program {
+        code: code,
+        sources: vec![Source::default(); functions.len()],
+        functions: functions,
+        constants: ConstantPool::from_constants(constants),
+    })
+}
+
+/// Loads the program saved in the file `path`.
+pub fn load_object(path: &Path) -> Result<Program, String> {
+    let file = try!(File::open(path).map_err(|e| format!("can't read {}: {}", path.display(), e)));
+    read_object(BufReader::new(file)).map_err(|e| format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,213 @@
+//! The peephole optimizer: rewrites short sequences of instructions in the
+//! code of a function into shorter or cheaper ones.
+//!
+//! - Jumps are threaded, and jumps to the next instruction and code that
+//!   can never run are removed, by `bytecode::optimize`.
+//! - A `LoadConstant` of a symbol followed by a `LoadGlobal` (the start of
+//!   every call of a global) is fused into a `LoadConstantGlobal`.
+//! - A load into a temporary that is then stored to a variable loads into
+//!   the variable instead, and a load into a slot that the next load
+//!   overwrites is removed, as is a copy of a slot to itself.
+//! - Fixnum arithmetic on two constants is replaced by a constant, unless
+//!   the result would not be a fixnum.
+//!
+//! Then come the superinstructions, which save dispatches in the commonest
+//! sequences:
+//!
+//! - adding or subtracting a small constant is an `AddConst`;
+//! - a `Less` or `NumEqual` whose result is only tested by a `JumpIfFalse`
+//!   is a `LessJumpIfFalse` or `NumEqualJumpIfFalse`;
+//! - a `Car`, `Cdr`, `AddConst`, or `Return` of a local variable that was
+//!   just loaded into a temporary reads the variable's slot instead.  Since
+//!   operands are slots, these need no opcodes of their own (such as a
+//!   `CarLocal` or `ReturnLocal`).
+//!
+//! The rewrites rely on how `codegen` uses slots: the slot that a store
+//! copies from, the second operand of arithmetic on consecutive slots, and
+//! the result of a comparison that is tested, are temporaries that nothing
+//! reads afterwards.
+//!
+//! A sequence is only rewritten if no jump lands inside of it, so that
+//! rewriting it cannot change what any jump does.  The code is rewritten
+//! until no more rewrites apply, since each may make others possible.
+
+use std::i8;
+
+use bytecode::{self, Bytecode, Constant, ConstantPool, FunctionConstants, Opcode};
+
+/// Does `op` store a value in `dst`, without doing anything else?
+fn is_load(op: &Bytecode) -> bool {
+    match op.opcode {
+        Opcode::LoadConstant | Opcode::LoadArgument | Opcode::LoadEnvironment | Opcode::LoadFalse |
+        Opcode::LoadTrue | Opcode::LoadNil | Opcode::LoadUnspecified => true,
+        _ => false,
+    }
+}
+
+/// Does `op` read only `src`, and write only `dst`?
+fn is_unary(op: &Bytecode) -> bool {
+    match op.opcode {
+        Opcode::Car | Opcode::Cdr | Opcode::AddConst => true,
+        _ => false,
+    }
+}
+
+/// Does `op` copy `src` to `dst`?
+fn is_copy(op: &Bytecode) -> bool {
+    match op.opcode {
+        Opcode::Set | Opcode::LoadArgument | Opcode::StoreArgument => true,
+        _ => false,
+    }
+}
+
+fn op(opcode: Opcode, src: u8, dst: u8) -> Bytecode {
+    Bytecode { opcode: opcode, src: src, src2: 0, dst: dst }
+}
+
+/// Optimizes `code`, the code of a function whose constants are
+/// `constants` in `pool`.
+pub fn optimize(code: &mut Vec<Bytecode>,
+                pool: &mut ConstantPool,
+                constants: &mut FunctionConstants) {
+    while bytecode::optimize(code) | rewrite(code, pool, constants) {}
+}
+
+/// The value of the constant that `op` loads, if it loads a fixnum.
+fn fixnum(pool: &ConstantPool, constants: &FunctionConstants, op: &Bytecode) -> Option<isize> {
+    if let Opcode::LoadConstant = op.opcode {
+        let index = constants.range().start + op.src as usize;
+        if let Some(&Constant::Fixnum(x)) = pool.constants().get(index) {
+            return Some(x as isize)
+        }
+    }
+    None
+}
+
+/// The rewrite of the instructions at the start of `code`, as the number
+/// of instructions it replaces and what it replaces them with.  `free(n)`
+/// is whether no jump lands inside the first `n` instructions.
+fn rewrite_at(code: &[Bytecode],
+              free: &Fn(usize) -> bool,
+              pool: &mut ConstantPool,
+              constants: &mut FunctionConstants)
+              -> Option<(usize, Vec<Bytecode>)> {
+    let a = code[0];
+    if is_copy(&a) && a.src == a.dst {
+        return Some((1, vec![]))
+    }
+    if code.len() >= 3 && free(3) {
+        let (b, arith) = (code[1], code[2]);
+        let folded = match (fixnum(pool, constants, &a), fixnum(pool, constants, &b)) {
+            (Some(x), Some(y)) if a.dst == arith.src && b.dst == arith.src2 &&
+                                  arith.src2 == arith.src + 1 &&
+                                  arith.dst == arith.src => {
+                match arith.opcode {
+                    Opcode::Add => x.checked_add(y),
+                    Opcode::Subtract => x.checked_sub(y),
+                    Opcode::Multiply => x.checked_mul(y),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        // Fixnums have two bits fewer than words.
+        if let Some(x) = folded.and_then(|x| x.checked_mul(4).map(|_| x)) {
+            if let Ok(index) = pool.add(constants, Constant::Fixnum(x as usize)) {
+                return Some((3, vec![op(Opcode::LoadConstant, index, a.dst)]))
+            }
+        }
+    }
+    if code.len() < 2 || !free(2) {
+        return None
+    }
+    let b = code[1];
+    match (a.opcode, b.opcode) {
+        _ if is_load(&a) && is_load(&b) && a.dst == b.dst && !(is_copy(&b) && b.src == a.dst) => {
+            Some((1, vec![]))
+        }
+        (_, Opcode::Set) | (_, Opcode::StoreArgument) if is_load(&a) && a.dst == b.src => {
+            Some((2, vec![Bytecode { dst: b.dst, ..a }]))
+        }
+        (Opcode::LoadConstant, Opcode::LoadGlobal) if b.src == a.dst && b.dst == a.dst => {
+            Some((2, vec![op(Opcode::LoadConstantGlobal, a.src, a.dst)]))
+        }
+        (Opcode::LoadConstant, Opcode::Add) |
+        (Opcode::LoadConstant, Opcode::Subtract) if b.src2 == a.dst && b.src2 == b.src + 1 &&
+                                                     b.dst == b.src => {
+            let x = match (fixnum(pool, constants, &a), b.opcode) {
+                (Some(x), Opcode::Add) => x,
+                (Some(x), _) => -x,
+                (None, _) => return None,
+            };
+            if x < i8::MIN as isize || x > i8::MAX as isize {
+                return None
+            }
+            Some((2, vec![Bytecode { opcode: Opcode::AddConst, src2: x as i8 as u8, ..b }]))
+        }
+        (Opcode::Less, Opcode::JumpIfFalse) |
+        (Opcode::NumEqual, Opcode::JumpIfFalse) if a.src2 == a.src + 1 && a.dst == a.src &&
+                                                  b.dst == a.dst => {
+            let opcode = match a.opcode {
+                Opcode::Less => Opcode::LessJumpIfFalse,
+                _ => Opcode::NumEqualJumpIfFalse,
+            };
+            Some((2, vec![Bytecode::jump(opcode, b.jump_target() as u16, a.dst)]))
+        }
+        (Opcode::LoadArgument, Opcode::Return) if b.src == a.dst => {
+            Some((2, vec![Bytecode { src: a.src, ..b }]))
+        }
+        (Opcode::LoadArgument, _) if is_unary(&b) && b.src == a.dst && b.dst == a.dst => {
+            Some((2, vec![Bytecode { src: a.src, ..b }]))
+        }
+        _ => None,
+    }
+}
+
+/// Applies the rewrites that apply, from the start of `code` to its end.
+/// Returns whether anything changed.
+fn rewrite(code: &mut Vec<Bytecode>,
+           pool: &mut ConstantPool,
+           constants: &mut FunctionConstants)
+           -> bool {
+    let mut targeted = vec![false; code.len() + 1];
+    for op in code.iter().filter(|op| op.opcode.is_jump()) {
+        targeted[op.jump_target()] = true
+    }
+    // Where each instruction ends up.  The instructions of a rewritten
+    // sequence end up where its replacement starts.
+    let mut moved = vec![0; code.len() + 1];
+    let mut out = Vec::with_capacity(code.len());
+    let mut i = 0;
+    while i < code.len() {
+        let rewritten = {
+            let free = |n: usize| (i + 1..i + n).all(|j| !targeted[j]);
+            rewrite_at(&code[i..], &free, pool, constants)
+        };
+        match rewritten {
+            Some((n, replacement)) => {
+                for j in i..i + n {
+                    moved[j] = out.len()
+                }
+                out.extend(replacement);
+                i += n
+            }
+            None => {
+                moved[i] = out.len();
+                out.push(code[i]);
+                i += 1
+            }
+        }
+    }
+    moved[code.len()] = out.len();
+    // Every rewrite makes the code shorter.
+    if out.len() == code.len() {
+        return false
+    }
+    for op in &mut out {
+        if op.opcode.is_jump() {
+            *op = Bytecode::jump(op.opcode, moved[op.jump_target()] as u16, op.dst)
+        }
+    }
+    *code = out;
+    true
+}
@@ -0,0 +1,1510 @@
+//! The syntax pass: turns data into expressions.
+//!
+//! This expands the binding forms into a small core language, and resolves
+//! every variable reference to the binding it refers to.  Each binding gets
+//! a `Var`, an index into `Syntax::vars`, so that later passes never need
+//! to think about scope or shadowing.
+//!
+//! Special forms, macros and inlined primitives are only recognized if their
+//! names are not lexically bound, so `(let ((if f)) (if 1 2 3))` calls `f`.
+//! Macros are expanded as they are found; see `macros` for how identifiers
+//! introduced by a `syntax-rules` expansion are resolved, and `expander`
+//! for `define-macro`, and `libraries` for how `define-library` and
+//! `import` decide which global an identifier refers to.
+//!
+//! ### Named `let`
+//!
+//! A named `let` whose name is only ever called, with the right number of
+//! arguments, in tail position of its body – and never referenced from a
+//! nested `lambda` or assigned – is a loop, and becomes an `Expr::Loop`.
+//! Anything else is compiled as R7RS specifies, as a call to a procedure
+//! bound by `letrec`.
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use bytecode::Source;
+use library::Name;
+use super::datum::Datum;
+use super::expander::Expander;
+use super::libraries::{self, Exports, Libraries};
+use super::macros::{self, Macro, base_name};
+
+/// How many macro expansions may be nested inside each other, so that a
+/// macro that expands into itself is an error rather than a stack overflow.
+const MAX_EXPANSION_DEPTH: usize = 256;
+
+/// A variable: an index into `Syntax::vars`.
+pub type Var = usize;
+
+/// What is known about a variable.
+#[derive(Clone, Debug)]
+pub struct VarInfo {
+    pub name: String,
+
+    /// The number of `lambda`s the variable is bound inside of.
+    depth: usize,
+
+    /// Is the variable referenced from inside a nested `lambda`?
+    pub captured: bool,
+
+    /// Is the variable the target of a `set!`?
+    pub assigned: bool,
+
+    /// Is the variable kept in a box (a pair whose car is the value)?
+    /// Variables that are both captured and assigned are boxed, so that
+    /// closures see the assignments, and `letrec` variables are boxed if
+    /// captured, since closures that refer to them are created before they
+    /// are initialized.
+    pub boxed: bool,
+}
+
+/// A primitive operation that is compiled inline.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Primitive {
+    Add,
+    Subtract,
+    Multiply,
+    Less,
+    NumEqual,
+    Power,
+    Cons,
+    Car,
+    Cdr,
+}
+
+/// The names and argument counts of the primitives.
+static PRIMITIVES: &'static [(&'static str, usize, Primitive)] = &[
+    ("+", 2, Primitive::Add),
+    ("-", 2, Primitive::Subtract),
+    ("*", 2, Primitive::Multiply),
+    ("<", 2, Primitive::Less),
+    ("=", 2, Primitive::NumEqual),
+    ("expt", 2, Primitive::Power),
+    ("cons", 2, Primitive::Cons),
+    ("car", 1, Primitive::Car),
+    ("cdr", 1, Primitive::Cdr),
+];
+
+/// How a macro is expanded.
+#[derive(Clone, Debug)]
+enum Transformer {
+    Rules(Rc<Macro>),
+
+    /// A `define-macro`, whose transformer is in `Syntax::expander`.
+    Procedure,
+}
+
+/// A `lambda` expression.
+#[derive(Clone, Debug)]
+pub struct Lambda {
+    pub params: Vec<Var>,
+    pub rest: Option<Var>,
+    pub body: Box<Expr>,
+    pub source: Source,
+}
+
+/// An expression of the core language.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    /// A quoted or self-evaluating datum.
+    Constant(Datum),
+    Local(Var),
+    Global(String),
+    SetLocal(Var, Box<Expr>),
+
+    /// `set!` of a global variable, or a top-level `define`.
+    SetGlobal(String, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+    Lambda(Lambda),
+
+    /// A sequence of expressions.  Only a program, a macro definition, a
+    /// branch that `unless` or `case` leaves out, or the result of a `do`
+    /// can be empty, and then its value is unspecified.
+    Sequence(Vec<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+
+    /// `(or test...)`, with at least two tests: the value of the first that
+    /// is true, or else of the last, which is in tail position.
+    Or(Vec<Expr>),
+
+    /// `(apply function arg ... list)`.  There is at least one argument.
+    Apply(Box<Expr>, Vec<Expr>),
+    Primitive(Primitive, Vec<Expr>),
+    Let(Vec<(Var, Expr)>, Box<Expr>),
+    Letrec(Vec<(Var, Expr)>, Box<Expr>),
+
+    /// A named `let` that is a loop.  Calls to the name in the body jump
+    /// back to its start.
+    Loop(Var, Vec<(Var, Expr)>, Box<Expr>),
+}
+
+/// The state of the syntax pass.  After an error, the state is meaningless.
+#[derive(Debug, Default)]
+pub struct Syntax {
+    pub vars: Vec<VarInfo>,
+
+    /// The bindings in scope, innermost last.
+    scope: Vec<(String, Var)>,
+
+    /// The number of `lambda`s being expanded.
+    depth: usize,
+
+    /// The macros defined by `define-syntax` and `define-macro`.
+    macros: HashMap<String, Transformer>,
+
+    /// Where `define-macro` transformers run, created on first use.
+    expander: Option<Box<Expander>>,
+
+    /// The number of identifiers renamed so far.
+    renamed: usize,
+
+    /// The number of macro expansions being expanded.
+    expansions: usize,
+
+    /// The file being compiled, then the files being included, innermost
+    /// last.
+    pub files: Vec<PathBuf>,
+
+    /// The line of the top-level form being expanded, or 0 if it is not
+    /// known.
+    pub line: u32,
+
+    pub libraries: Libraries,
+
+    /// The globals that identifiers which are not lexically bound refer to,
+    /// where those are not the globals of the same name: the imports of the
+    /// top level, or of the library being defined, and that library's
+    /// definitions.
+    pub globals: Exports,
+
+    /// The library being defined, if any.
+    library: Option<Name>,
+
+    /// Are identifiers that are neither lexically bound nor in `globals`
+    /// unbound, rather than the globals of the same name?  See `closed`.
+    closed: bool,
+}
+
+/// The macros defined at top level, kept from one program to the next by
+/// the REPL, which compiles each input as a program of its own (see
+/// `compiler::compile_toplevel`).
+#[derive(Debug, Default)]
+pub struct Macros {
+    transformers: HashMap<String, Transformer>,
+    expander: Option<Box<Expander>>,
+}
+
+/// The forms that closed code cannot use, because they reach beyond its
+/// globals: into files, other libraries, or the whole interpreter, where
+/// `define-macro` transformers run.
+const UNSAFE_FORMS: [&'static str; 4] = ["define-library", "define-macro", "import", "include"];
+
+/// `form` is not valid syntax.
+fn bad_syntax<T>(form: &Datum) -> Result<T, String> {
+    Err(format!("bad syntax: {}", form))
+}
+
+impl Syntax {
+    /// The state of the syntax pass for closed code, such as a sandbox's,
+    /// whose identifiers only refer to the globals in `globals`.  Its
+    /// definitions are like those of the library `name`, and can neither
+    /// replace globals it did not define nor assign them.
+    pub fn closed(name: Name, globals: Exports) -> Syntax {
+        Syntax { globals: globals, library: Some(name), closed: true, ..Syntax::default() }
+    }
+
+    /// The state of the syntax pass for a program that starts out with the
+    /// top-level macros `macros`, whose expander it borrows until
+    /// `return_macros`.
+    pub fn with_macros(macros: &mut Macros) -> Syntax {
+        Syntax {
+            macros: macros.transformers.clone(),
+            expander: macros.expander.take(),
+            ..Syntax::default()
+        }
+    }
+
+    /// Gives back the expander borrowed by `with_macros`, and, if the
+    /// program expanded without an error, replaces `macros` with the
+    /// macros as they are now.  After an error, transformers that were
+    /// defined before it linger in the expander, unused.
+    pub fn return_macros(&mut self, macros: &mut Macros, expanded: bool) {
+        macros.expander = self.expander.take();
+        if expanded {
+            macros.transformers = mem::replace(&mut self.macros, HashMap::new())
+        }
+    }
+
+    /// The name of the file being expanded, if any.
+    pub fn file(&self) -> Option<String> {
+        self.files.last().map(|path| path.display().to_string())
+    }
+
+    /// Expands the top-level form `form`.
+    pub fn toplevel(&mut self, form: &Datum) -> Result<Expr, String> {
+        if let Some(items) = form.as_list() {
+            match items.first().and_then(|x| self.keyword(x)) {
+                Some("define") => return self.define(form, &items[1..]),
+                Some("define-syntax") => return self.define_syntax(form, &items[1..]),
+                Some("define-macro") => return self.define_macro(form, &items[1..]),
+                Some("begin") if items.len() > 1 => {
+                    let body = try!(items[1..].iter().map(|x| self.toplevel(x)).collect());
+                    return Ok(Expr::Sequence(body))
+                }
+                Some("include") => {
+                    return self.include(form, &items[1..], Syntax::toplevel).map(Expr::Sequence)
+                }
+                Some("define-library") => return self.define_library(form, &items[1..]),
+                Some("import") => {
+                    for spec in &items[1..] {
+                        for (id, global) in try!(libraries::import_set(&self.libraries, spec)) {
+                            self.macros.remove(&id);
+                            self.globals.insert(id, global);
+                        }
+                    }
+                    return Ok(Expr::Sequence(vec![]))
+                }
+                // The expansion may be a definition.
+                Some(name) if self.macros.contains_key(name) => {
+                    return self.expand(name, form, Syntax::toplevel)
+                }
+                _ => {}
+            }
+        }
+        self.expr(form)
+    }
+
+    /// The binding of `name`, if it is lexically bound.
+    fn lookup(&self, name: &str) -> Option<Var> {
+        self.scope.iter().rev().find(|binding| binding.0 == name).map(|binding| binding.1)
+    }
+
+    /// The name of the special form, macro or primitive that `x` could be:
+    /// its base name, if it is an identifier that is not lexically bound,
+    /// and does not refer to a global of another name.
+    fn keyword<'a>(&self, x: &'a Datum) -> Option<&'a str> {
+        match x.as_symbol() {
+            Some(name) if self.lookup(name).is_none() => {
+                let name = base_name(name);
+                match self.globals.get(name) {
+                    Some(global) if global != name => None,
+                    None if self.closed && !self.macros.contains_key(name) &&
+                            (UNSAFE_FORMS.contains(&name) ||
+                             PRIMITIVES.iter().any(|p| p.0 == name)) => None,
+                    _ => Some(name),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The global that `name`, which is not lexically bound, refers to.
+    fn global(&self, name: &str) -> Result<String, String> {
+        let name = base_name(name);
+        match self.globals.get(name) {
+            Some(global) => Ok(global.clone()),
+            None if self.closed => Err(format!("{} is not bound in this environment", name)),
+            None => Ok(name.to_owned()),
+        }
+    }
+
+    /// The global that `(set! name value)`, where `name` is not lexically
+    /// bound, assigns.
+    fn assigned_global(&self, name: &str) -> Result<String, String> {
+        let global = try!(self.global(name));
+        if self.closed && global == base_name(name) {
+            return Err(format!("{} cannot be assigned in this environment", base_name(name)))
+        }
+        Ok(global)
+    }
+
+    /// Expands the use `form` of the macro `name`, then the expansion,
+    /// with `then`.
+    fn expand<F>(&mut self, name: &str, form: &Datum, then: F) -> Result<Expr, String>
+        where F: FnOnce(&mut Syntax, &Datum) -> Result<Expr, String>
+    {
+        if self.expansions == MAX_EXPANSION_DEPTH {
+            return Err(format!("macro expansion too deep: {}", macros::strip(form)))
+        }
+        let expansion = try!(self.expansion(name, form));
+        self.expansions += 1;
+        let result = then(self, &expansion);
+        self.expansions -= 1;
+        result
+    }
+
+    /// The expansion of the use `form` of the macro `name`, which is not
+    /// expanded any further.
+    fn expansion(&mut self, name: &str, form: &Datum) -> Result<Datum, String> {
+        match self.macros[name].clone() {
+            Transformer::Rules(mac) => {
+                let renamed = &mut self.renamed;
+                mac.expand(form, &mut |name| {
+                    *renamed += 1;
+                    format!("{}\0{}", base_name(name), renamed)
+                })
+            }
+            Transformer::Procedure => {
+                self.expander.get_or_insert_with(Box::default).expand(name, form)
+            }
+        }
+    }
+
+    /// Expands `(include name ...)`: reads the files named, in order, and
+    /// expands each of their forms with `then`.
+    fn include<F>(&mut self, form: &Datum, args: &[&Datum], then: F) -> Result<Vec<Expr>, String>
+        where F: Fn(&mut Syntax, &Datum) -> Result<Expr, String>
+    {
+        if args.is_empty() {
+            return bad_syntax(form)
+        }
+        let mut exprs = vec![];
+        for name in args {
+            let name = match **name {
+                Datum::Str(ref name) => name,
+                _ => return bad_syntax(form),
+            };
+            let path = super::resolve(self.files.last().map(|x| x.as_path()), name);
+            if self.files.contains(&path) {
+                return Err(format!("{} includes itself", path.display()))
+            }
+            let (forms, lines) = try!(super::read_file(&path));
+            let line = self.line;
+            self.files.push(path);
+            let res: Result<Vec<_>, _> = forms.iter()
+                                              .zip(lines)
+                                              .map(|(x, line)| {
+                                                  self.line = line;
+                                                  then(self, x)
+                                              })
+                                              .collect();
+            self.files.pop();
+            self.line = line;
+            exprs.extend(try!(res))
+        }
+        Ok(exprs)
+    }
+
+    /// Creates a variable named `name`, without bringing it into scope.
+    fn var(&mut self, name: &Datum) -> Result<Var, String> {
+        match name.as_symbol() {
+            Some(name) => {
+                self.vars.push(VarInfo {
+                    name: name.to_owned(),
+                    depth: self.depth,
+                    captured: false,
+                    assigned: false,
+                    boxed: false,
+                });
+                Ok(self.vars.len() - 1)
+            }
+            None => Err(format!("not an identifier: {}", name)),
+        }
+    }
+
+    /// Brings `vars` into scope.  They must have distinct names.
+    fn bind(&mut self, vars: &[Var]) -> Result<(), String> {
+        for (i, &var) in vars.iter().enumerate() {
+            let name = self.vars[var].name.clone();
+            if vars[..i].iter().any(|&other| self.vars[other].name == name) {
+                return Err(format!("duplicate binding of {}", name))
+            }
+            self.scope.push((name, var))
+        }
+        Ok(())
+    }
+
+    /// Takes the last `count` bindings out of scope, boxing those that are
+    /// captured and assigned, now that all of their references are known.
+    fn unbind(&mut self, count: usize) {
+        let len = self.scope.len();
+        for &(_, var) in &self.scope[len - count..] {
+            let info = &mut self.vars[var];
+            info.boxed = info.boxed || info.captured && info.assigned
+        }
+        self.scope.truncate(len - count)
+    }
+
+    /// A reference to the variable `var`.
+    fn reference(&mut self, var: Var) -> Var {
+        if self.vars[var].depth < self.depth {
+            self.vars[var].captured = true
+        }
+        var
+    }
+
+    /// Expands the expression `form`.
+    pub fn expr(&mut self, form: &Datum) -> Result<Expr, String> {
+        let (items, head) = match *form {
+            Datum::Symbol(ref name) => {
+                return Ok(match self.lookup(name) {
+                    Some(var) => Expr::Local(self.reference(var)),
+                    None if is_keyword(base_name(name)) => {
+                        Expr::Constant(Datum::symbol(base_name(name)))
+                    }
+                    None => Expr::Global(try!(self.global(name))),
+                })
+            }
+            Datum::Pair(ref pair) => {
+                match form.as_list() {
+                    Some(items) => (items, &pair.0),
+                    None => return Err(format!("improper list in call: {}", form)),
+                }
+            }
+            Datum::Nil => return Err("empty combination ()".to_owned()),
+            // Vectors evaluate to themselves, as if quoted.
+            Datum::Vector(_) => return Ok(Expr::Constant(macros::strip(form))),
+            _ => return Ok(Expr::Constant(form.clone())),
+        };
+        let args = &items[1..];
+        if let Some(name) = self.keyword(head) {
+            if self.macros.contains_key(name) {
+                return self.expand(name, form, Syntax::expr)
+            }
+            match name {
+                "quote" if args.len() == 1 => {
+                    return Ok(Expr::Constant(macros::strip(args[0])))
+                }
+                "if" if args.len() == 2 || args.len() == 3 => {
+                    let test = try!(self.expr(args[0]));
+                    let then = try!(self.expr(args[1]));
+                    let otherwise = match args.get(2) {
+                        Some(x) => Some(Box::new(try!(self.expr(x)))),
+                        None => None,
+                    };
+                    return Ok(Expr::If(Box::new(test), Box::new(then), otherwise))
+                }
+                "set!" if args.len() == 2 => {
+                    let value = Box::new(try!(self.expr(args[1])));
+                    return match args[0].as_symbol() {
+                        Some(target) => {
+                            Ok(match self.lookup(target) {
+                                Some(var) => {
+                                    self.vars[var].assigned = true;
+                                    Expr::SetLocal(self.reference(var), value)
+                                }
+                                None => Expr::SetGlobal(try!(self.assigned_global(target)), value),
+                            })
+                        }
+                        None => bad_syntax(form),
+                    }
+                }
+                "lambda" if args.len() >= 2 => {
+                    return self.lambda(args[0], &args[1..]).map(Expr::Lambda)
+                }
+                "begin" if !args.is_empty() => return self.sequence(args),
+                "include" => {
+                    let mut exprs = try!(self.include(form, args, Syntax::expr));
+                    return Ok(if exprs.len() == 1 {
+                        exprs.pop().unwrap()
+                    } else {
+                        Expr::Sequence(exprs)
+                    })
+                }
+                "let" => return self.let_(form, args),
+                "let*" => return self.let_star(form, args),
+                "letrec" => return self.letrec(form, args),
+                "do" => return self.do_(form, args),
+                "parameterize" => return self.parameterize(form, args),
+                "when" => return self.when(form, args, false),
+                "unless" => return self.when(form, args, true),
+                "and" => return self.and(args),
+                "or" => return self.or(args),
+                "cond" => return self.cond(form, args),
+                "case" => return self.case(form, args),
+                "time" if args.len() == 1 => return self.time(args[0]),
+                "delay" if args.len() == 1 => return self.delay(args[0], false),
+                "delay-force" if args.len() == 1 => return self.delay(args[0], true),
+                "define" => {
+                    return Err(format!("definitions must come before the expressions of a \
+                                        body: {}",
+                                       form))
+                }
+                "apply" if args.len() >= 2 => {
+                    let function = try!(self.expr(args[0]));
+                    let args = try!(args[1..].iter().map(|x| self.expr(x)).collect());
+                    return Ok(Expr::Apply(Box::new(function), args))
+                }
+                "define-syntax" | "define-macro" => {
+                    return Err(format!("{} is only supported at top level: {}", name, form))
+                }
+                "quote" | "if" | "set!" | "lambda" | "begin" | "delay" | "delay-force" => {
+                    return bad_syntax(form)
+                }
+                _ => {}
+            }
+            if let Some(&(_, _, primitive)) = PRIMITIVES.iter()
+                                                        .find(|p| p.0 == name &&
+                                                                  p.1 == args.len()) {
+                let args = try!(args.iter().map(|x| self.expr(x)).collect());
+                return Ok(Expr::Primitive(primitive, args))
+            }
+        }
+        let function = try!(self.expr(head));
+        let args = try!(args.iter().map(|x| self.expr(x)).collect());
+        Ok(Expr::Call(Box::new(function), args))
+    }
+
+    /// Expands a body: definitions, then a non-empty sequence of
+    /// expressions.  The definitions are found as R7RS says, splicing in
+    /// `begin`s and expanding macros until the first expression, and bind
+    /// their names as `letrec*` does.
+    fn body(&mut self, forms: &[&Datum]) -> Result<Expr, String> {
+        // The forms not scanned yet, last first, each with the number of
+        // macro expansions it came out of.
+        let mut forms: Vec<(Datum, usize)> = forms.iter().rev().map(|&x| (x.clone(), 0)).collect();
+        let mut definitions = vec![];
+        while let Some((form, depth)) = forms.pop() {
+            let keyword = form.as_list()
+                              .and_then(|items| items.first().and_then(|&x| self.keyword(x)))
+                              .map(str::to_owned);
+            match keyword.as_ref().map(String::as_str) {
+                Some("define") => definitions.push(form),
+                Some("begin") => {
+                    let items = form.as_list().unwrap();
+                    forms.extend(items[1..].iter().rev().map(|&x| (x.clone(), depth)))
+                }
+                Some(name) if self.macros.contains_key(name) => {
+                    if depth == MAX_EXPANSION_DEPTH {
+                        return Err(format!("macro expansion too deep: {}", macros::strip(&form)))
+                    }
+                    forms.push((try!(self.expansion(name, &form)), depth + 1))
+                }
+                _ => {
+                    forms.push((form, depth));
+                    break
+                }
+            }
+        }
+        let rest: Vec<Datum> = forms.into_iter().rev().map(|x| x.0).collect();
+        let exprs: Vec<&Datum> = rest.iter().collect();
+        if definitions.is_empty() {
+            return self.sequence(&exprs)
+        }
+        if exprs.is_empty() {
+            return Err(format!("no expressions after the definitions of a body: {}",
+                               definitions.last().unwrap()))
+        }
+        let mut parsed = vec![];
+        for form in &definitions {
+            parsed.push(try!(internal_definition(form)))
+        }
+        let vars: Vec<Var> = try!(parsed.iter().map(|x| self.var(x.0)).collect());
+        try!(self.bind(&vars));
+        let mut inits = vec![];
+        for (&var, (name, params, body)) in vars.iter().zip(parsed) {
+            let init = match params {
+                Some(params) => {
+                    let mut lambda = try!(self.lambda(params, &body));
+                    lambda.source.name = name.as_symbol().map(|x| base_name(x).to_owned());
+                    Expr::Lambda(lambda)
+                }
+                None => try!(self.expr(body[0])),
+            };
+            inits.push((var, init))
+        }
+        let body = try!(self.sequence(&exprs));
+        self.unbind(vars.len());
+        for &var in &vars {
+            self.vars[var].boxed = self.vars[var].captured
+        }
+        Ok(Expr::Letrec(inits, Box::new(body)))
+    }
+
+    /// Expands a non-empty sequence of expressions.
+    fn sequence(&mut self, forms: &[&Datum]) -> Result<Expr, String> {
+        let mut exprs: Vec<Expr> = try!(forms.iter().map(|x| self.expr(x)).collect());
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            Expr::Sequence(exprs)
+        })
+    }
+
+    /// Expands `(define ...)` at top level.
+    fn define(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        let (name, value) = try!(self.definition(form, args));
+        Ok(Expr::SetGlobal(try!(self.global(&name)), Box::new(value)))
+    }
+
+    /// The name and value of the top-level definition `form`.  The name
+    /// stops being a macro, or an import.
+    fn definition(&mut self, form: &Datum, args: &[&Datum]) -> Result<(String, Expr), String> {
+        let name = match args.first() {
+            Some(&&Datum::Symbol(ref name)) if args.len() == 2 => name,
+            // `(define (name . params) body...)`
+            Some(&&Datum::Pair(ref pair)) if args.len() >= 2 => {
+                match pair.0 {
+                    Datum::Symbol(ref name) => name,
+                    _ => return bad_syntax(form),
+                }
+            }
+            _ => return bad_syntax(form),
+        };
+        let name = base_name(name).to_owned();
+        self.macros.remove(&name);
+        match self.library {
+            // Names defined by macros are only found now.  Closed code
+            // defines its own globals in place of those it was given.
+            Some(ref library) if self.closed || !self.globals.contains_key(&name) => {
+                self.globals.insert(name.clone(), libraries::global_name(library, &name));
+            }
+            Some(_) => {}
+            None => {
+                self.globals.remove(&name);
+            }
+        }
+        let mut value = match *args[0] {
+            Datum::Pair(ref pair) => Expr::Lambda(try!(self.lambda(&pair.1, &args[1..]))),
+            _ => try!(self.expr(args[1])),
+        };
+        if let Expr::Lambda(ref mut lambda) = value {
+            lambda.source.name = Some(name.clone())
+        }
+        Ok((name, value))
+    }
+
+    /// Expands `(define-syntax name (syntax-rules ...))` at top level.  The
+    /// value of the definition is unspecified.
+    fn define_syntax(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        match (args.len(), args.first().and_then(|x| x.as_symbol())) {
+            (2, Some(name)) => {
+                let mac = try!(Macro::new(args[1]));
+                self.macros.insert(base_name(name).to_owned(), Transformer::Rules(Rc::new(mac)));
+                Ok(Expr::Sequence(vec![]))
+            }
+            _ => bad_syntax(form),
+        }
+    }
+
+    /// Expands `(define-macro (name . params) body...)` or `(define-macro
+    /// name transformer)` at top level, like `define`, but evaluates the
+    /// transformer now.
+    fn define_macro(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        let (name, transformer) = try!(self.definition(form, args));
+        try!(self.expander
+                 .get_or_insert_with(Box::default)
+                 .define(&name, &self.vars, transformer));
+        self.macros.insert(name, Transformer::Procedure);
+        Ok(Expr::Sequence(vec![]))
+    }
+
+    /// Expands `(define-library name declaration...)` at top level.  The
+    /// body of the library becomes part of the program, where it is defined.
+    fn define_library(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        if self.library.is_some() {
+            return Err(format!("define-library inside a library: {}", form))
+        }
+        let name = match args.first() {
+            Some(name) => try!(libraries::library_name(name)),
+            None => return bad_syntax(form),
+        };
+        // The forms of the body, each with the file it is from, if it is
+        // included, and the line it starts on.
+        let mut body: Vec<(Option<PathBuf>, u32, Datum)> = vec![];
+        // Each export's identifier in the library, and outside of it.
+        let mut exports: Vec<(String, String)> = vec![];
+        let mut globals = Exports::new();
+        for declaration in &args[1..] {
+            let items = declaration.as_list().unwrap_or_default();
+            match items.first().and_then(|x| x.as_symbol()) {
+                Some("export") => {
+                    for spec in &items[1..] {
+                        exports.push(try!(export(spec)))
+                    }
+                }
+                Some("import") => {
+                    for spec in &items[1..] {
+                        globals.extend(try!(libraries::import_set(&self.libraries, spec)))
+                    }
+                }
+                Some("begin") => {
+                    body.extend(items[1..].iter().map(|&x| (None, self.line, x.clone())))
+                }
+                Some("include") if items.len() > 1 => {
+                    for file in &items[1..] {
+                        let file = match **file {
+                            Datum::Str(ref file) => file,
+                            _ => return bad_syntax(declaration),
+                        };
+                        let path = super::resolve(self.files.last().map(|x| x.as_path()), file);
+                        let (forms, lines) = try!(super::read_file(&path));
+                        body.extend(forms.into_iter()
+                                         .zip(lines)
+                                         .map(|(x, line)| (Some(path.clone()), line, x)))
+                    }
+                }
+                _ => return Err(format!("bad library declaration: {}", declaration)),
+            }
+        }
+        let mut defined = vec![];
+        for &(_, _, ref form) in &body {
+            defined_names(form, &mut defined)
+        }
+        for id in defined {
+            let global = libraries::global_name(&name, &id);
+            globals.insert(id, global);
+        }
+        // Expand the body in the library's environment.  Its macros are its
+        // own.
+        let saved = (mem::replace(&mut self.globals, globals), self.macros.clone());
+        self.library = Some(name.clone());
+        let line = self.line;
+        let res: Result<Vec<_>, _> = body.iter()
+                                         .map(|&(ref file, line, ref form)| {
+                                             self.files.extend(file.clone());
+                                             self.line = line;
+                                             let res = self.toplevel(form);
+                                             if file.is_some() {
+                                                 self.files.pop();
+                                             }
+                                             res
+                                         })
+                                         .collect();
+        self.line = line;
+        self.library = None;
+        let globals = mem::replace(&mut self.globals, saved.0);
+        self.macros = saved.1;
+        let mut body = try!(res);
+        let mut table = Exports::new();
+        for (id, external) in exports {
+            match globals.get(&id) {
+                Some(global) => table.insert(external, global.clone()),
+                None => {
+                    return Err(format!("library ({}) exports {}, which it neither defines nor \
+                                        imports",
+                                       name.join(" "),
+                                       id))
+                }
+            };
+        }
+        self.libraries.define(name, table);
+        body.push(Expr::Sequence(vec![]));
+        Ok(Expr::Sequence(body))
+    }
+
+    /// Expands `(lambda params body...)`.
+    fn lambda(&mut self, params: &Datum, body: &[&Datum]) -> Result<Lambda, String> {
+        let list = try!(lambda_list(params));
+        self.depth += 1;
+        let params: Vec<Var> = try!(list.required.iter().map(|&x| self.var(x)).collect());
+        let (rest, body) = if list.optional.is_empty() && list.keys.is_empty() {
+            let rest = match list.rest {
+                Some(rest) => Some(try!(self.var(rest))),
+                None => None,
+            };
+            let mut vars = params.clone();
+            vars.extend(rest);
+            (rest, try!(self.scoped_body(&vars, body)))
+        } else {
+            try!(self.bind(&params));
+            let arguments = self.temporary();
+            let body = try!(self.prologue(&list, arguments, body));
+            self.unbind(params.len());
+            (Some(arguments), body)
+        };
+        self.depth -= 1;
+        Ok(Lambda {
+            params: params,
+            rest: rest,
+            body: Box::new(body),
+            source: Source {
+                name: None,
+                file: self.file(),
+                line: self.line,
+            },
+        })
+    }
+
+    /// Expands `body`, after the prologue of a procedure with the optional
+    /// or keyword parameters of `list`, whose required parameters are in
+    /// scope.  `arguments` is the list of its other arguments.  Each
+    /// parameter is bound in turn, to an argument or its default, so that
+    /// the defaults of later ones can refer to it.
+    fn prologue(&mut self,
+                list: &LambdaList,
+                mut arguments: Var,
+                body: &[&Datum])
+                -> Result<Expr, String> {
+        let global = |name: &str| Box::new(Expr::Global(name.to_owned()));
+        let default = |this: &mut Syntax, default: Option<&Datum>| {
+            default.map_or(Ok(Expr::Constant(Datum::Bool(false))), |x| this.expr(x))
+        };
+        // Each binds a parameter or a temporary, in the scope of those
+        // before it.
+        let mut bindings = vec![];
+        let mut vars = vec![];
+        for &(name, init) in &list.optional {
+            let var = try!(self.var(name));
+            let is_pair = Expr::Call(global("pair?"), vec![Expr::Local(arguments)]);
+            let value = Expr::If(Box::new(is_pair.clone()),
+                                 Box::new(Expr::Primitive(Primitive::Car,
+                                                          vec![Expr::Local(arguments)])),
+                                 Some(Box::new(try!(default(self, init)))));
+            let next = self.temporary();
+            let rest = Expr::If(Box::new(is_pair),
+                                Box::new(Expr::Primitive(Primitive::Cdr,
+                                                         vec![Expr::Local(arguments)])),
+                                Some(Box::new(Expr::Constant(Datum::Nil))));
+            bindings.push(vec![(var, value), (next, rest)]);
+            try!(self.bind(&[var]));
+            vars.push(var);
+            arguments = next
+        }
+        if let Some(rest) = list.rest {
+            let var = try!(self.var(rest));
+            bindings.push(vec![(var, Expr::Local(arguments))]);
+            try!(self.bind(&[var]));
+            vars.push(var)
+        } else if list.keys.is_empty() {
+            let message = Expr::Constant(Datum::Str("too many arguments:".to_owned()));
+            let error = Expr::Call(global("error"), vec![message, Expr::Local(arguments)]);
+            let check = Expr::If(Box::new(Expr::Call(global("pair?"),
+                                                     vec![Expr::Local(arguments)])),
+                                 Box::new(error),
+                                 None);
+            bindings.push(vec![(self.temporary(), check)])
+        }
+        if !list.keys.is_empty() {
+            let keywords = list.keys.iter().map(|&(name, _)| keyword(name)).collect();
+            let check = Expr::Call(global("%check-keywords"),
+                                   vec![Expr::Local(arguments),
+                                        Expr::Constant(Datum::list(keywords))]);
+            bindings.push(vec![(self.temporary(), check)])
+        }
+        for &(name, init) in &list.keys {
+            let var = try!(self.var(name));
+            let found = Expr::Call(global("%keyword-argument"),
+                                   vec![Expr::Local(arguments), Expr::Constant(keyword(name))]);
+            let init = try!(default(self, init));
+            let value = self.if_value(found,
+                                      |x| Expr::Primitive(Primitive::Car, vec![x]),
+                                      Some(init));
+            bindings.push(vec![(var, value)]);
+            try!(self.bind(&[var]));
+            vars.push(var)
+        }
+        let body = try!(self.body(body));
+        self.unbind(vars.len());
+        Ok(bindings.into_iter().rev().fold(body, |body, bindings| {
+            Expr::Let(bindings, Box::new(body))
+        }))
+    }
+
+    /// Parses the bindings `((name init) ...)` of a `let`-like form.
+    fn bindings<'a>(&mut self,
+                    form: &Datum,
+                    bindings: &'a Datum)
+                    -> Result<Vec<(Var, &'a Datum)>, String> {
+        let bindings = try!(bindings.as_list().ok_or_else(|| format!("bad syntax: {}", form)));
+        let mut result = vec![];
+        for binding in bindings {
+            match binding.as_list() {
+                Some(ref binding) if binding.len() == 2 => {
+                    result.push((try!(self.var(binding[0])), binding[1]))
+                }
+                _ => return bad_syntax(form),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Expands the inits of `bindings` in the current scope.
+    fn inits(&mut self, bindings: &[(Var, &Datum)]) -> Result<Vec<(Var, Expr)>, String> {
+        bindings.iter().map(|&(var, init)| Ok((var, try!(self.expr(init))))).collect()
+    }
+
+    /// Expands `body` with `vars` in scope.
+    fn scoped_body(&mut self, vars: &[Var], body: &[&Datum]) -> Result<Expr, String> {
+        try!(self.bind(vars));
+        let body = try!(self.body(body));
+        self.unbind(vars.len());
+        Ok(body)
+    }
+
+    /// Expands `(let ...)`, including named `let`.
+    fn let_(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        if let Some(name) = args.first().and_then(|x| x.as_symbol()) {
+            if args.len() < 3 {
+                return bad_syntax(form)
+            }
+            return self.named_let(form, name, args[1], &args[2..])
+        }
+        if args.len() < 2 {
+            return bad_syntax(form)
+        }
+        let bindings = try!(self.bindings(form, args[0]));
+        let inits = try!(self.inits(&bindings));
+        let vars: Vec<Var> = bindings.iter().map(|x| x.0).collect();
+        let body = try!(self.scoped_body(&vars, &args[1..]));
+        Ok(Expr::Let(inits, Box::new(body)))
+    }
+
+    /// Expands `(let* ...)` into nested `let`s.
+    fn let_star(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        if args.len() < 2 {
+            return bad_syntax(form)
+        }
+        let bindings = try!(self.bindings(form, args[0]));
+        let mut inits = vec![];
+        // Each init sees the bindings before it.
+        for &(var, init) in &bindings {
+            inits.push((var, try!(self.expr(init))));
+            try!(self.bind(&[var]))
+        }
+        let body = try!(self.body(&args[1..]));
+        self.unbind(inits.len());
+        Ok(inits.into_iter().rev().fold(body, |body, binding| {
+            Expr::Let(vec![binding], Box::new(body))
+        }))
+    }
+
+    /// Expands `(letrec ...)`.
+    fn letrec(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        if args.len() < 2 {
+            return bad_syntax(form)
+        }
+        let bindings = try!(self.bindings(form, args[0]));
+        let vars: Vec<Var> = bindings.iter().map(|x| x.0).collect();
+        try!(self.bind(&vars));
+        let inits = try!(self.inits(&bindings));
+        let body = try!(self.body(&args[1..]));
+        self.unbind(vars.len());
+        for &var in &vars {
+            self.vars[var].boxed = self.vars[var].captured
+        }
+        Ok(Expr::Letrec(inits, Box::new(body)))
+    }
+
+    /// A variable for an expansion to keep a value in, which no identifier
+    /// refers to.
+    fn temporary(&mut self) -> Var {
+        self.var(&Datum::symbol("temp")).unwrap()
+    }
+
+    /// `(let ((temp value)) (if temp then otherwise))`, where `then` makes
+    /// the consequent from a reference to `temp`.
+    fn if_value<F>(&mut self, value: Expr, then: F, otherwise: Option<Expr>) -> Expr
+        where F: FnOnce(Expr) -> Expr
+    {
+        let temp = self.temporary();
+        let test = Expr::If(Box::new(Expr::Local(temp)),
+                            Box::new(then(Expr::Local(temp))),
+                            otherwise.map(Box::new));
+        Expr::Let(vec![(temp, value)], Box::new(test))
+    }
+
+    /// Expands `(when test body...)`, or `(unless test body...)` if
+    /// `unless`.
+    fn when(&mut self, form: &Datum, args: &[&Datum], unless: bool) -> Result<Expr, String> {
+        if args.len() < 2 {
+            return bad_syntax(form)
+        }
+        let test = Box::new(try!(self.expr(args[0])));
+        let body = Box::new(try!(self.sequence(&args[1..])));
+        Ok(if unless {
+            Expr::If(test, Box::new(Expr::Sequence(vec![])), Some(body))
+        } else {
+            Expr::If(test, body, None)
+        })
+    }
+
+    /// Expands `(and test...)`.
+    fn and(&mut self, args: &[&Datum]) -> Result<Expr, String> {
+        match args.len() {
+            0 => Ok(Expr::Constant(Datum::Bool(true))),
+            1 => self.expr(args[0]),
+            _ => {
+                let test = try!(self.expr(args[0]));
+                let rest = try!(self.and(&args[1..]));
+                let otherwise = Expr::Constant(Datum::Bool(false));
+                Ok(Expr::If(Box::new(test), Box::new(rest), Some(Box::new(otherwise))))
+            }
+        }
+    }
+
+    /// Expands `(or test...)`.
+    fn or(&mut self, args: &[&Datum]) -> Result<Expr, String> {
+        match args.len() {
+            0 => Ok(Expr::Constant(Datum::Bool(false))),
+            1 => self.expr(args[0]),
+            _ => Ok(Expr::Or(try!(args.iter().map(|x| self.expr(x)).collect()))),
+        }
+    }
+
+    /// Expands `(cond clause...)`, whose clauses are `(test body...)`,
+    /// `(test => receiver)`, `(test)`, or last, `(else body...)`.  If no
+    /// clause is taken, the value is unspecified.
+    fn cond(&mut self, form: &Datum, clauses: &[&Datum]) -> Result<Expr, String> {
+        let clause = clauses.first().and_then(|x| x.as_list()).unwrap_or_default();
+        if clause.is_empty() {
+            return bad_syntax(form)
+        }
+        let rest = &clauses[1..];
+        if self.keyword(clause[0]) == Some("else") {
+            return if rest.is_empty() && clause.len() > 1 {
+                self.sequence(&clause[1..])
+            } else {
+                bad_syntax(form)
+            }
+        }
+        let test = try!(self.expr(clause[0]));
+        let otherwise = if rest.is_empty() {
+            None
+        } else {
+            Some(try!(self.cond(form, rest)))
+        };
+        if clause.len() == 3 && self.keyword(clause[1]) == Some("=>") {
+            let receiver = Box::new(try!(self.expr(clause[2])));
+            return Ok(self.if_value(test, |x| Expr::Call(receiver, vec![x]), otherwise))
+        }
+        if clause.len() == 1 {
+            return Ok(match otherwise {
+                Some(otherwise) => Expr::Or(vec![test, otherwise]),
+                None => test,
+            })
+        }
+        let body = try!(self.sequence(&clause[1..]));
+        Ok(Expr::If(Box::new(test), Box::new(body), otherwise.map(Box::new)))
+    }
+
+    /// Expands `(case key clause...)`, whose clauses are `((datum...)
+    /// body...)`, or last, `(else body...)`, where either body can instead
+    /// be `=> receiver`.  The key is compared with each datum by `eqv?`.
+    fn case(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        if args.len() < 2 {
+            return bad_syntax(form)
+        }
+        let key = try!(self.expr(args[0]));
+        let temp = self.temporary();
+        let clauses = try!(self.case_clauses(form, temp, &args[1..]));
+        Ok(Expr::Let(vec![(temp, key)], Box::new(clauses)))
+    }
+
+    /// Expands the clauses of `(case ...)`, whose key is in `key`.
+    fn case_clauses(&mut self, form: &Datum, key: Var, clauses: &[&Datum]) -> Result<Expr, String> {
+        if clauses.is_empty() {
+            return Ok(Expr::Sequence(vec![]))
+        }
+        let clause = clauses[0].as_list().unwrap_or_default();
+        if clause.len() < 2 {
+            return bad_syntax(form)
+        }
+        let rest = &clauses[1..];
+        let body = if clause.len() == 3 && self.keyword(clause[1]) == Some("=>") {
+            let receiver = try!(self.expr(clause[2]));
+            Expr::Call(Box::new(receiver), vec![Expr::Local(key)])
+        } else {
+            try!(self.sequence(&clause[1..]))
+        };
+        if self.keyword(clause[0]) == Some("else") {
+            return if rest.is_empty() { Ok(body) } else { bad_syntax(form) }
+        }
+        let datums = try!(clause[0].as_list().ok_or_else(|| format!("bad syntax: {}", form)));
+        let test = datums.iter().rev().fold(Expr::Constant(Datum::Bool(false)), |rest, datum| {
+            let eqv = Box::new(Expr::Global("eqv?".to_owned()));
+            let datum = Expr::Constant(macros::strip(datum));
+            Expr::If(Box::new(Expr::Call(eqv, vec![Expr::Local(key), datum])),
+                     Box::new(Expr::Constant(Datum::Bool(true))),
+                     Some(Box::new(rest)))
+        });
+        let otherwise = try!(self.case_clauses(form, key, rest));
+        Ok(Expr::If(Box::new(test), Box::new(body), Some(Box::new(otherwise))))
+    }
+
+    /// Expands `(parameterize ((param value) ...) body...)` into a call of
+    /// `%parameterize` (see `builtins::parameters`), with the body as a
+    /// thunk, and then each parameter and its value.
+    fn parameterize(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        let bindings = match args.first().and_then(|x| x.as_list()) {
+            Some(ref bindings) if args.len() >= 2 => bindings.clone(),
+            _ => return bad_syntax(form),
+        };
+        let mut exprs = vec![Expr::Lambda(try!(self.lambda(&Datum::Nil, &args[1..])))];
+        for binding in bindings {
+            match binding.as_list() {
+                Some(ref items) if items.len() == 2 => {
+                    exprs.push(try!(self.expr(items[0])));
+                    exprs.push(try!(self.expr(items[1])))
+                }
+                _ => return bad_syntax(form),
+            }
+        }
+        let parameterize = Expr::Global("%parameterize".to_owned());
+        Ok(Expr::Call(Box::new(parameterize), exprs))
+    }
+
+    /// Expands `(delay expr)`, or `(delay-force expr)` if `force`, into a
+    /// call of `%make-promise` (see `builtins::promises`) with a thunk.  The
+    /// thunk of `delay` wraps the value of `expr` in a promise that is done.
+    fn delay(&mut self, expr: &Datum, force: bool) -> Result<Expr, String> {
+        let make_promise = || Box::new(Expr::Global("%make-promise".to_owned()));
+        let mut thunk = try!(self.lambda(&Datum::Nil, &[expr]));
+        if !force {
+            let done = Expr::Constant(Datum::Bool(true));
+            let body = mem::replace(&mut thunk.body, Box::new(Expr::Sequence(vec![])));
+            thunk.body = Box::new(Expr::Call(make_promise(), vec![done, *body]))
+        }
+        let pending = Expr::Constant(Datum::Bool(false));
+        Ok(Expr::Call(make_promise(), vec![pending, Expr::Lambda(thunk)]))
+    }
+
+    /// Expands `(time expr)` into a call of `%time` (see the prelude) with a
+    /// thunk of `expr`, and `expr` itself, to report.
+    fn time(&mut self, expr: &Datum) -> Result<Expr, String> {
+        let thunk = try!(self.lambda(&Datum::Nil, &[expr]));
+        let time = Box::new(Expr::Global("%time".to_owned()));
+        Ok(Expr::Call(time, vec![Expr::Lambda(thunk), Expr::Constant(macros::strip(expr))]))
+    }
+
+    /// Expands `(do ((var init step) ...) (test expr...) command...)`,
+    /// where each `step` is optional, into a loop, as a named `let` that is
+    /// one is.
+    fn do_(&mut self, form: &Datum, args: &[&Datum]) -> Result<Expr, String> {
+        let (specs, exit) = match (args.first().and_then(|x| x.as_list()),
+                                   args.get(1).and_then(|x| x.as_list())) {
+            (Some(specs), Some(exit)) => (specs, exit),
+            _ => return bad_syntax(form),
+        };
+        if exit.is_empty() {
+            return bad_syntax(form)
+        }
+        let mut bindings = vec![];
+        let mut steps = vec![];
+        for spec in specs {
+            let spec = spec.as_list().unwrap_or_default();
+            if spec.len() != 2 && spec.len() != 3 {
+                return bad_syntax(form)
+            }
+            bindings.push((try!(self.var(spec[0])), spec[1]));
+            steps.push(spec.get(2).cloned())
+        }
+        let inits = try!(self.inits(&bindings));
+        let vars: Vec<Var> = bindings.iter().map(|x| x.0).collect();
+        let name = self.temporary();
+        try!(self.bind(&vars));
+        let test = try!(self.expr(exit[0]));
+        let result: Vec<Expr> = try!(exit[1..].iter().map(|x| self.expr(x)).collect());
+        let mut commands: Vec<Expr> = try!(args[2..].iter().map(|x| self.expr(x)).collect());
+        let mut next = vec![];
+        for (&var, step) in vars.iter().zip(steps) {
+            next.push(match step {
+                Some(step) => try!(self.expr(step)),
+                None => Expr::Local(var),
+            })
+        }
+        self.unbind(vars.len());
+        commands.push(Expr::Call(Box::new(Expr::Local(name)), next));
+        let body = Expr::If(Box::new(test),
+                            Box::new(Expr::Sequence(result)),
+                            Some(Box::new(Expr::Sequence(commands))));
+        Ok(Expr::Loop(name, inits, Box::new(body)))
+    }
+
+    /// Expands `(let name bindings body...)`.
+    fn named_let(&mut self,
+                 form: &Datum,
+                 name: &str,
+                 bindings: &Datum,
+                 body: &[&Datum])
+                 -> Result<Expr, String> {
+        let bindings = try!(self.bindings(form, bindings));
+        let inits = try!(self.inits(&bindings));
+        let name = try!(self.var(&Datum::symbol(name)));
+        try!(self.bind(&[name]));
+        // The body is expanded as the body of a `lambda`.  If it turns out
+        // to be a loop, variables it refers to are marked as captured when
+        // they are not, which costs some speed but is still correct.
+        self.depth += 1;
+        let params: Vec<Var> = bindings.iter()
+                                       .map(|&(var, _)| {
+                                           self.vars[var].depth += 1;
+                                           var
+                                       })
+                                       .collect();
+        let body = try!(self.scoped_body(&params, body));
+        self.depth -= 1;
+        self.unbind(1);
+        if !self.vars[name].assigned && only_tail_calls(&body, name, params.len(), true) {
+            return Ok(Expr::Loop(name, inits, Box::new(body)))
+        }
+        self.vars[name].boxed = self.vars[name].captured;
+        let lambda = Expr::Lambda(Lambda {
+            params: params,
+            rest: None,
+            body: Box::new(body),
+            source: Source {
+                name: Some(base_name(&self.vars[name].name).to_owned()),
+                file: self.file(),
+                line: self.line,
+            },
+        });
+        let procedure = Expr::Letrec(vec![(name, lambda)], Box::new(Expr::Local(name)));
+        Ok(Expr::Call(Box::new(procedure), inits.into_iter().map(|x| x.1).collect()))
+    }
+}
+
+/// The identifiers inside and outside of a library of the export spec
+/// `spec`, `id` or `(rename id external)`.
+fn export(spec: &Datum) -> Result<(String, String), String> {
+    if let Some(id) = spec.as_symbol() {
+        return Ok((id.to_owned(), id.to_owned()))
+    }
+    let items = spec.as_list().unwrap_or_default();
+    match (items.len(), items.first().and_then(|x| x.as_symbol())) {
+        (3, Some("rename")) => {
+            match (items[1].as_symbol(), items[2].as_symbol()) {
+                (Some(id), Some(external)) => Ok((id.to_owned(), external.to_owned())),
+                _ => Err(format!("bad export: {}", spec)),
+            }
+        }
+        _ => Err(format!("bad export: {}", spec)),
+    }
+}
+
+/// Adds the names that the top-level form `form` defines to `names`, as
+/// far as that can be told without expanding macros.
+fn defined_names(form: &Datum, names: &mut Vec<String>) {
+    let items = match form.as_list() {
+        Some(items) => items,
+        None => return,
+    };
+    match items.first().and_then(|x| x.as_symbol()) {
+        Some("define") if items.len() > 1 => {
+            let name = match *items[1] {
+                Datum::Pair(ref pair) => pair.0.as_symbol(),
+                ref name => name.as_symbol(),
+            };
+            names.extend(name.map(|x| x.to_owned()))
+        }
+        Some("begin") => {
+            for form in &items[1..] {
+                defined_names(form, names)
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The name of the internal definition `form`, and if it defines a
+/// procedure, its parameters, and then the body of the procedure or else
+/// the expression whose value the name is bound to.
+fn internal_definition(form: &Datum) -> Result<(&Datum, Option<&Datum>, Vec<&Datum>), String> {
+    let items = form.as_list().unwrap_or_default();
+    match items.get(1).cloned() {
+        Some(&Datum::Pair(ref pair)) if items.len() >= 3 => {
+            Ok((&pair.0, Some(&pair.1), items[2..].to_vec()))
+        }
+        Some(name) if items.len() == 3 => Ok((name, None, vec![items[2]])),
+        _ => bad_syntax(form),
+    }
+}
+
+/// A parsed lambda list: required parameters, optional and keyword ones
+/// with their default expressions, if any, and the rest parameter.
+struct LambdaList<'a> {
+    required: Vec<&'a Datum>,
+    optional: Vec<(&'a Datum, Option<&'a Datum>)>,
+    keys: Vec<(&'a Datum, Option<&'a Datum>)>,
+    rest: Option<&'a Datum>,
+}
+
+/// Parses the lambda list `params`, as in SRFI 89 or DSSSL: required
+/// parameters, then optional ones, then keyword ones after `#!key`, then a
+/// rest parameter, after `#!rest` or a dot.  An optional parameter is
+/// either after `#!optional`, or like `(name default)`, and a keyword one
+/// is either like that or just its name; without a default, it is `#f`.
+fn lambda_list(params: &Datum) -> Result<LambdaList, String> {
+    let bad = || Err(format!("bad lambda list: {}", params));
+    let (items, tail) = params.elements();
+    let mut list = LambdaList { required: vec![], optional: vec![], keys: vec![], rest: None };
+    // Which part of the list comes next: required, optional, or keyword
+    // parameters, or the rest parameter.
+    let mut part = 0;
+    let mut items = items.into_iter();
+    while let Some(item) = items.next() {
+        let parameter = match *item {
+            Datum::Symbol(ref name) => {
+                match (base_name(name), part) {
+                    ("#!optional", 0) => part = 1,
+                    ("#!key", 0) | ("#!key", 1) => part = 2,
+                    ("#!rest", _) if part < 3 => {
+                        list.rest = items.next();
+                        part = 3
+                    }
+                    (name, _) if name.starts_with("#!") => return bad(),
+                    _ => {}
+                }
+                if base_name(name).starts_with("#!") {
+                    continue
+                }
+                (item, None)
+            }
+            _ => {
+                match item.as_list() {
+                    Some(ref pair) if pair.len() == 2 && pair[0].as_symbol().is_some() => {
+                        (pair[0], Some(pair[1]))
+                    }
+                    _ => return bad(),
+                }
+            }
+        };
+        match (part, parameter.1) {
+            (0, None) => list.required.push(parameter.0),
+            (0, Some(_)) | (1, _) => list.optional.push(parameter),
+            (2, _) => list.keys.push(parameter),
+            _ => return bad(),
+        }
+        if part == 0 && parameter.1.is_some() {
+            part = 1
+        }
+    }
+    match *tail {
+        Datum::Nil if part < 3 || list.rest.is_some() => {}
+        ref rest if part < 3 => list.rest = Some(rest),
+        _ => return bad(),
+    }
+    if list.rest.is_some() && !list.keys.is_empty() {
+        return Err(format!("a lambda list cannot have both keyword and rest parameters: {}",
+                           params))
+    }
+    let mut names: Vec<_> = list.required
+                                .iter()
+                                .chain(list.optional.iter().chain(&list.keys).map(|x| &x.0))
+                                .chain(&list.rest)
+                                .filter_map(|x| x.as_symbol())
+                                .collect();
+    names.sort();
+    if let Some(pair) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(format!("duplicate binding of {}", pair[0]))
+    }
+    Ok(list)
+}
+
+/// The keyword of the keyword parameter `name`, such as `size:` for
+/// `size`.
+fn keyword(name: &Datum) -> Datum {
+    Datum::symbol(&format!("{}:", base_name(name.as_symbol().unwrap())))
+}
+
+/// Is `name` a keyword, which evaluates to itself?
+fn is_keyword(name: &str) -> bool {
+    name.len() > 1 && name.ends_with(':')
+}
+
+/// Is every reference to `var` in `expr` a call with `argc` arguments in
+/// tail position?  `tail` is whether `expr` itself is in tail position.
+fn only_tail_calls(expr: &Expr, var: Var, argc: usize, tail: bool) -> bool {
+    let all = |exprs: &[Expr]| exprs.iter().all(|x| only_tail_calls(x, var, argc, false));
+    let bindings = |bindings: &[(Var, Expr)]| {
+        bindings.iter().all(|x| only_tail_calls(&x.1, var, argc, false))
+    };
+    match *expr {
+        Expr::Constant(_) | Expr::Global(_) => true,
+        Expr::Local(other) => other != var,
+        Expr::SetLocal(other, ref value) => {
+            other != var && only_tail_calls(value, var, argc, false)
+        }
+        Expr::SetGlobal(_, ref value) => only_tail_calls(value, var, argc, false),
+        Expr::If(ref test, ref then, ref otherwise) => {
+            only_tail_calls(test, var, argc, false) && only_tail_calls(then, var, argc, tail) &&
+            otherwise.as_ref().map_or(true, |x| only_tail_calls(x, var, argc, tail))
+        }
+        Expr::Lambda(ref lambda) => only_tail_calls(&lambda.body, var, argc, false),
+        Expr::Sequence(ref exprs) |
+        Expr::Or(ref exprs) => {
+            match exprs.split_last() {
+                Some((last, init)) => all(init) && only_tail_calls(last, var, argc, tail),
+                None => true,
+            }
+        }
+        Expr::Call(ref function, ref args) => {
+            let ok = match **function {
+                Expr::Local(other) if other == var => tail && args.len() == argc,
+                ref function => only_tail_calls(function, var, argc, false),
+            };
+            ok && all(args)
+        }
+        Expr::Apply(ref function, ref args) => {
+            only_tail_calls(function, var, argc, false) && all(args)
+        }
+        Expr::Primitive(_, ref args) => all(args),
+        Expr::Let(ref inits, ref body) |
+        Expr::Letrec(ref inits, ref body) |
+        Expr::Loop(_, ref inits, ref body) => {
+            bindings(inits) && only_tail_calls(body, var, argc, tail)
+        }
+    }
+}
+
+/// The variables that are free in `lambda`, in order of first reference.
+/// These are the upvalues of its closures, so a closure keeps alive only
+/// the variables that it, or a `lambda` nested in it, refers to – not the
+/// rest of the frame it was created in.
+pub fn free_variables(lambda: &Lambda) -> Vec<Var> {
+    let mut free = vec![];
+    let mut bound: HashSet<Var> = lambda.params.iter().cloned().chain(lambda.rest).collect();
+    collect_free(&lambda.body, &mut bound, &mut free);
+    free
+}
+
+/// Adds the variables referenced in `expr` that are not in `bound` to
+/// `free`.  Variables bound inside `expr` are added to `bound`.  Since each
+/// binding has its own `Var`, the order does not matter.
+fn collect_free(expr: &Expr, bound: &mut HashSet<Var>, free: &mut Vec<Var>) {
+    match *expr {
+        Expr::Constant(_) | Expr::Global(_) => {}
+        Expr::Local(var) => reference(var, bound, free),
+        Expr::SetLocal(var, ref value) => {
+            reference(var, bound, free);
+            collect_free(value, bound, free)
+        }
+        Expr::SetGlobal(_, ref value) => collect_free(value, bound, free),
+        Expr::If(ref test, ref then, ref otherwise) => {
+            collect_free(test, bound, free);
+            collect_free(then, bound, free);
+            if let Some(ref otherwise) = *otherwise {
+                collect_free(otherwise, bound, free)
+            }
+        }
+        Expr::Lambda(ref lambda) => {
+            bound.extend(lambda.params.iter().cloned().chain(lambda.rest));
+            collect_free(&lambda.body, bound, free)
+        }
+        Expr::Sequence(ref exprs) |
+        Expr::Or(ref exprs) |
+        Expr::Primitive(_, ref exprs) => {
+            for x in exprs {
+                collect_free(x, bound, free)
+            }
+        }
+        Expr::Call(ref function, ref args) |
+        Expr::Apply(ref function, ref args) => {
+            collect_free(function, bound, free);
+            for x in args {
+                collect_free(x, bound, free)
+            }
+        }
+        Expr::Let(ref bindings, ref body) |
+        Expr::Letrec(ref bindings, ref body) |
+        Expr::Loop(_, ref bindings, ref body) => {
+            if let Expr::Loop(name, _, _) = *expr {
+                bound.insert(name);
+            }
+            bound.extend(bindings.iter().map(|x| x.0));
+            for x in bindings {
+                collect_free(&x.1, bound, free)
+            }
+            collect_free(body, bound, free)
+        }
+    }
+}
+
+fn reference(var: Var, bound: &HashSet<Var>, free: &mut Vec<Var>) {
+    if !bound.contains(&var) && !free.contains(&var) {
+        free.push(var)
+    }
+}
@@ -0,0 +1,146 @@
+//! Debugger hooks: breakpoints, single-stepping, and tracing.
+//!
+//! A `Debugger` stops the code before an instruction that has a
+//! breakpoint, or before every instruction in single-step mode, and calls
+//! its handler.  A breakpoint is on a function (a BCO, as an index into
+//! `State::functions`; see `closure::function`) and the index of an
+//! instruction, relative to the entry of the function, as `Frame`s count
+//! them.  The handler can inspect the frames of the code with
+//! `interp::frames`, `interp::locals`, `interp::environment` and
+//! `interp::constants`, and says how to go on with a `Resume`.  The trace
+//! hook, if there is one, is called before every instruction, whether the
+//! code stops there or not.
+//!
+//! The dispatch loop only looks at the debugger while it `is_active`, when
+//! it checks the interrupt flag (see `interp`), which it then does before
+//! every instruction.  Fused loops are not fused then, so that every
+//! instruction is seen.  While the handler or the trace hook runs, it is
+//! not called again, so the Scheme code that it runs (to evaluate an
+//! expression in a REPL, say) does not stop.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use bytecode::Bytecode;
+use interp::{Frame, State};
+
+/// How the code goes on after the handler has stopped it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until the next breakpoint, leaving single-step mode.
+    Continue,
+
+    /// Stop again before the next instruction, in single-step mode.
+    Step,
+
+    /// Fail with "aborted by the debugger".
+    Abort,
+}
+
+/// Called when the code stops, with the frame it stopped in.
+pub type Handler = Box<FnMut(&mut State, &Frame) -> Resume>;
+
+/// Called before every instruction, with the frame it is in.
+pub type Trace = Box<FnMut(&State, &Frame, &Bytecode)>;
+
+/// The debugger of a `State`.
+#[derive(Default)]
+pub struct Debugger {
+    /// The functions and instruction indices of the breakpoints.
+    breakpoints: HashSet<(usize, usize)>,
+
+    /// Is the code stopped before every instruction?
+    stepping: bool,
+    handler: Option<Handler>,
+    trace: Option<Trace>,
+}
+
+impl fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "Debugger({} breakpoints, stepping: {})",
+               self.breakpoints.len(),
+               self.stepping)
+    }
+}
+
+impl Debugger {
+    /// Sets a breakpoint before instruction `pc` of function `function`.
+    /// Returns whether there was none there.
+    pub fn set_breakpoint(&mut self, function: usize, pc: usize) -> bool {
+        self.breakpoints.insert((function, pc))
+    }
+
+    /// Removes the breakpoint before instruction `pc` of function
+    /// `function`.  Returns whether there was one there.
+    pub fn clear_breakpoint(&mut self, function: usize, pc: usize) -> bool {
+        self.breakpoints.remove(&(function, pc))
+    }
+
+    /// The breakpoints, as functions and instruction indices, in order.
+    pub fn breakpoints(&self) -> Vec<(usize, usize)> {
+        let mut breakpoints: Vec<_> = self.breakpoints.iter().cloned().collect();
+        breakpoints.sort();
+        breakpoints
+    }
+
+    /// Turns single-step mode on or off.
+    pub fn set_stepping(&mut self, stepping: bool) {
+        self.stepping = stepping
+    }
+
+    pub fn is_stepping(&self) -> bool {
+        self.stepping
+    }
+
+    /// Sets the handler, which is called when the code stops.  Without a
+    /// handler, it never stops.
+    pub fn set_handler(&mut self, handler: Option<Handler>) {
+        self.handler = handler
+    }
+
+    /// Sets the trace hook.
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace
+    }
+
+    /// Does the dispatch loop need to call `before_instruction`?
+    pub fn is_active(&self) -> bool {
+        self.trace.is_some() ||
+        self.handler.is_some() && (self.stepping || !self.breakpoints.is_empty())
+    }
+
+    /// Does the code stop before instruction `pc` of `function`?
+    fn stops_at(&self, function: Option<usize>, pc: usize) -> bool {
+        self.stepping ||
+        function.map_or(false, |function| self.breakpoints.contains(&(function, pc)))
+    }
+}
+
+/// Runs the trace hook before the instruction `op` in `frame`, and stops
+/// if there is a breakpoint there, or the debugger is single-stepping.
+pub fn before_instruction(s: &mut State, frame: &Frame, op: &Bytecode) -> Result<(), String> {
+    if let Some(mut trace) = s.debugger.trace.take() {
+        trace(s, frame, op);
+        if s.debugger.trace.is_none() {
+            s.debugger.trace = Some(trace)
+        }
+    }
+    if !s.debugger.stops_at(frame.function, frame.pc) {
+        return Ok(())
+    }
+    let mut handler = match s.debugger.handler.take() {
+        Some(handler) => handler,
+        None => return Ok(()),
+    };
+    let resume = handler(s, frame);
+    // The handler may have replaced itself.
+    if s.debugger.handler.is_none() {
+        s.debugger.handler = Some(handler)
+    }
+    match resume {
+        Resume::Continue => Ok(s.debugger.stepping = false),
+        Resume::Step => Ok(s.debugger.stepping = true),
+        Resume::Abort => Err("aborted by the debugger".to_owned()),
+    }
+}
@@ -0,0 +1,318 @@
+//! The equality predicates `eq?`, `eqv?`, and `equal?`.
+//!
+//! `equal?` compares pairs and vectors structurally, strings by their
+//! contents, and Rust data by its hooks (see `alloc::RustData`).  It must
+//! terminate on circular structure.  It uses the union-find algorithm of
+//! Adams and Dybvig ("Efficient Nondestructive Equality Checking for Trees
+//! and Graphs"): when two objects are compared, they are first merged into one
+//! equivalence class, and any later comparison of two objects in the same
+//! class is assumed to succeed.  If the assumption was wrong, the mismatch
+//! is found elsewhere, so the overall result is still correct.  This makes
+//! every pair of objects be compared at most once.
+//!
+//! The comparison is iterative, with an explicit work list, so deeply nested
+//! lists and vectors – in their cars as well as their cdrs – do not
+//! overflow the Rust stack.  It does not allocate on the Scheme heap, so
+//! objects cannot move while it runs.
+//!
+//! ### Hashing
+//!
+//! `equal_hash` is consistent with `equal?`: equal objects hash equally.  It
+//! only looks at a bounded part of an object – at most `HASH_BUDGET` parts,
+//! nested at most `HASH_DEPTH` deep – so it terminates on circular structure
+//! and takes constant time on large structure.  Since the part it looks at
+//! depends only on the structure, and not on the identity, of the objects
+//! in it, two equal circular lists hash equally even if one is "unrolled".
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::slice;
+
+use alloc;
+use flonum;
+use ratio;
+use string;
+use value::{self, Value, Tags, HEADER_TAG};
+
+/// Are `x` and `y` the same object?
+pub fn eq(x: &Value, y: &Value) -> bool {
+    x.get() == y.get()
+}
+
+/// Are `x` and `y` the same object, or equal numbers or characters?
+///
+/// Fixnums are immediates, so they are equal numbers only if they are the
+/// same object, but flonums and ratios are boxed.  Ratios are in lowest
+/// terms, so they are compared by their parts, and flonums by their bits:
+/// `0.0` and `-0.0` are not `eqv?`, but a NaN is `eqv?` to itself.
+pub fn eqv(x: &Value, y: &Value) -> bool {
+    eq(x, y) ||
+    match (flonum::as_f64(x), flonum::as_f64(y)) {
+        (Some(x), Some(y)) => x.to_bits() == y.to_bits(),
+        _ => ratio::as_ratio(x).map_or(false, |x| ratio::as_ratio(y) == Some(x)),
+    }
+}
+
+/// The elements of `x`, if it is a vector (and not a record).
+pub fn vector_elements(x: &Value) -> Option<&[Value]> {
+    if x.tag() != Tags::Vector || x.immediatep() {
+        return None
+    }
+    unsafe {
+        let ptr = x.as_ptr();
+        let header = (*ptr).get();
+        if header & HEADER_TAG != value::HeaderTag::Vector as usize {
+            return None
+        }
+        // Skip the header and the word after it.
+        Some(slice::from_raw_parts(ptr.offset(2), (header & !HEADER_TAG) - 2))
+    }
+}
+
+/// Disjoint sets of objects, keyed by address.
+#[derive(Default)]
+struct UnionFind {
+    parents: HashMap<usize, usize>,
+}
+
+impl UnionFind {
+    fn find(&mut self, x: usize) -> usize {
+        let mut root = x;
+        while let Some(&parent) = self.parents.get(&root) {
+            root = parent
+        }
+        // Path compression.
+        let mut x = x;
+        while x != root {
+            let parent = self.parents.insert(x, root).unwrap();
+            x = parent
+        }
+        root
+    }
+
+    /// Merges the sets of `x` and `y`.  Returns `false` if they were
+    /// already the same set.
+    fn union(&mut self, x: usize, y: usize) -> bool {
+        let (x, y) = (self.find(x), self.find(y));
+        if x == y {
+            return false
+        }
+        self.parents.insert(x, y);
+        true
+    }
+}
+
+/// Are `x` and `y` structurally equal?
+pub fn equal(x: &Value, y: &Value) -> bool {
+    let mut sets = UnionFind::default();
+    let mut work = vec![(x.clone(), y.clone())];
+    while let Some((x, y)) = work.pop() {
+        if eqv(&x, &y) {
+            continue
+        }
+        match (x.tag(), y.tag()) {
+            (Tags::Pair, Tags::Pair) => {
+                if sets.union(x.get(), y.get()) {
+                    work.push((x.cdr().unwrap(), y.cdr().unwrap()));
+                    work.push((x.car().unwrap(), y.car().unwrap()))
+                }
+            }
+            (Tags::Vector, Tags::Vector) => {
+                match (vector_elements(&x), vector_elements(&y)) {
+                    (Some(xs), Some(ys)) if xs.len() == ys.len() => {
+                        if sets.union(x.get(), y.get()) {
+                            work.extend(xs.iter().cloned().zip(ys.iter().cloned()).rev())
+                        }
+                    }
+                    _ => return false,
+                }
+            }
+            (Tags::RustData, Tags::RustData) => {
+                match (string::as_str(&x), string::as_str(&y)) {
+                    (Some(x), Some(y)) if x == y => {}
+                    (None, None) if alloc::rust_data_equal(&x, &y) => {}
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// The maximum number of parts of an object that `equal_hash` looks at.
+const HASH_BUDGET: usize = 64;
+
+/// The maximum nesting depth that `equal_hash` looks at.  The cdr of a pair
+/// is not nested in it, so that the elements of a list count as being at
+/// the same depth.
+const HASH_DEPTH: usize = 8;
+
+/// A hash of `x` that is consistent with `equal`.
+pub fn equal_hash(x: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut work = vec![(x.clone(), 0)];
+    let mut budget = HASH_BUDGET;
+    while let Some((x, depth)) = work.pop() {
+        if budget == 0 {
+            break
+        }
+        budget -= 1;
+        hasher.write_usize(x.raw_tag());
+        if depth == HASH_DEPTH {
+            continue
+        }
+        match x.tag() {
+            Tags::Pair => {
+                work.push((x.cdr().unwrap(), depth));
+                work.push((x.car().unwrap(), depth + 1))
+            }
+            Tags::Vector => {
+                if let Some(xs) = vector_elements(&x) {
+                    hasher.write_usize(xs.len());
+                    work.extend(xs.iter().rev().map(|x| (x.clone(), depth + 1)))
+                }
+                // Other vector-like objects (records) are compared by
+                // identity, but move during GC, so they are hashed by type
+                // alone.
+            }
+            Tags::RustData => {
+                if let Some(s) = string::as_str(&x) {
+                    hasher.write(s.as_bytes())
+                } else if let Some(x) = flonum::as_f64(&x) {
+                    hasher.write_u64(x.to_bits())
+                } else if let Some((numerator, denominator)) = ratio::as_ratio(&x) {
+                    hasher.write_isize(numerator);
+                    hasher.write_isize(denominator)
+                } else if let Some(hash) = alloc::rust_data_hash(&x) {
+                    hasher.write_u64(hash)
+                }
+            }
+            // Immediates, symbols, and native procedures do not move.
+            Tags::Num | Tags::Num2 | Tags::Symbol | Tags::RustFunc => hasher.write_usize(x.get()),
+            Tags::Function => {}
+        }
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::Heap;
+    use api::SchemeValue;
+    use value::{Value, NIL};
+    use super::*;
+
+    /// Pushes a list of the fixnums `xs`, ending in `tail`.
+    fn list(heap: &mut Heap, xs: &[usize], tail: Value) {
+        heap.stack.push(tail);
+        for &x in xs.iter().rev() {
+            heap.stack.push(Value::new(x << 2));
+            let len = heap.stack.len();
+            heap.alloc_pair(len - 1, len - 2).unwrap();
+            let pair = heap.stack.pop().unwrap();
+            heap.stack.truncate(len - 2);
+            heap.stack.push(pair)
+        }
+    }
+
+    fn string(heap: &mut Heap, s: &str) {
+        let x = s.to_owned().to_value(heap).unwrap();
+        heap.stack.push(x)
+    }
+
+    /// Pushes a circular list that repeats `xs`.
+    fn circular(heap: &mut Heap, xs: &[usize]) {
+        list(heap, xs, Value::nil());
+        let head = heap.stack.last().unwrap().clone();
+        let mut last = head.clone();
+        while last.cdr().unwrap().get() != NIL {
+            last = last.cdr().unwrap()
+        }
+        last.set_cdr(head).unwrap()
+    }
+
+    #[test]
+    fn equal_compares_structure() {
+        let mut heap = Heap::new(1 << 10);
+        list(&mut heap, &[1, 2, 3], Value::nil());
+        string(&mut heap, "abc");
+        list(&mut heap, &[1, 2, 3], Value::nil());
+        string(&mut heap, "abc");
+        list(&mut heap, &[1, 2], Value::nil());
+        heap.alloc_vector(0, 2).unwrap();
+        heap.alloc_vector(2, 4).unwrap();
+        let s = &heap.stack;
+        assert!(!eq(&s[0], &s[2]) && !eqv(&s[0], &s[2]) && equal(&s[0], &s[2]));
+        assert!(!equal(&s[2], &s[4]));
+        assert!(!eq(&s[1], &s[3]) && equal(&s[1], &s[3]));
+        assert!(equal(&s[5], &s[6]));
+        assert!(equal(&s[0], &s[0]) && eq(&s[4], &s[4]));
+        assert!(!equal(&s[0], &s[1]) && !equal(&s[5], &s[0]));
+        for &(x, y) in &[(0, 2), (1, 3), (5, 6)] {
+            assert_eq!(equal_hash(&s[x]), equal_hash(&s[y]))
+        }
+        assert!(equal_hash(&s[0]) != equal_hash(&s[4]));
+    }
+
+    /// Pushes `(((... (x) ...)))`, nested `depth` deep.
+    fn nested(heap: &mut Heap, depth: usize, x: usize) {
+        heap.stack.push(Value::new(x << 2));
+        for _ in 0..depth {
+            list(heap, &[], Value::nil());
+            let len = heap.stack.len();
+            heap.alloc_pair(len - 2, len - 1).unwrap();
+            let pair = heap.stack.pop().unwrap();
+            heap.stack.truncate(len - 2);
+            heap.stack.push(pair)
+        }
+    }
+
+    #[test]
+    fn equal_handles_deep_nesting() {
+        let mut heap = Heap::new(1 << 10);
+        nested(&mut heap, 10000, 1);
+        nested(&mut heap, 10000, 1);
+        nested(&mut heap, 10000, 2);
+        let s = &heap.stack;
+        assert!(equal(&s[0], &s[1]) && !equal(&s[0], &s[2]));
+        assert_eq!(equal_hash(&s[0]), equal_hash(&s[2]));
+    }
+
+    #[test]
+    fn equal_terminates_on_circular_lists() {
+        let mut heap = Heap::new(1 << 10);
+        circular(&mut heap, &[1]);
+        circular(&mut heap, &[1, 1]);
+        circular(&mut heap, &[1, 2]);
+        circular(&mut heap, &[1, 2, 1]);
+        let s = &heap.stack;
+        assert!(equal(&s[0], &s[1]) && equal(&s[1], &s[0]));
+        assert!(!equal(&s[0], &s[2]) && !equal(&s[2], &s[3]));
+        assert_eq!(equal_hash(&s[0]), equal_hash(&s[1]));
+    }
+
+    #[test]
+    fn equal_hash_looks_at_a_bounded_part() {
+        let mut heap = Heap::new(1 << 10);
+        circular(&mut heap, &[1, 2]);
+        circular(&mut heap, &[1, 2, 1, 2]);
+        nested(&mut heap, HASH_DEPTH + 1, 1);
+        nested(&mut heap, HASH_DEPTH + 1, 1);
+        nested(&mut heap, HASH_DEPTH + 1, 2);
+        let mut xs: Vec<_> = (0..2 * HASH_BUDGET).collect();
+        list(&mut heap, &xs, Value::nil());
+        *xs.last_mut().unwrap() = 0;
+        list(&mut heap, &xs, Value::nil());
+        let s = &heap.stack;
+        assert!(equal(&s[0], &s[1]));
+        assert_eq!(equal_hash(&s[0]), equal_hash(&s[1]));
+        assert!(equal(&s[2], &s[3]));
+        assert_eq!(equal_hash(&s[2]), equal_hash(&s[3]));
+        // What differs is out of the hash's reach, which they share.
+        assert!(!equal(&s[2], &s[4]) && !equal(&s[5], &s[6]));
+        assert_eq!(equal_hash(&s[2]), equal_hash(&s[4]));
+        assert_eq!(equal_hash(&s[5]), equal_hash(&s[6]));
+    }
+}
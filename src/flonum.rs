@@ -0,0 +1,72 @@
+//! Flonums: inexact real numbers, as `f64`s.
+//!
+//! A flonum is a `RustData` object of three words: its header, its type
+//! word, `FLONUM`, and the bits of the `f64`.  Since the GC does not scan
+//! `RustData`, the bits are never mistaken for a pointer.  Flonums are
+//! immutable, so they may be shared, but `eqv?` compares them by their
+//! bits, not their identity (see `equal`).
+//!
+//! `format` writes a flonum so that the reader reads it back: always with a
+//! decimal point or an exponent, so that it is not read as a fixnum, and
+//! with the R7RS syntax for infinities and NaN.
+
+use value::{self, Value, HEADER_TAG};
+
+/// The type word of a flonum.  (Strings are 0, see `string`, and weak
+/// boxes 1, see `alloc::weak`.)
+pub const FLONUM: usize = 2;
+
+/// The size of a flonum in words.
+pub const SIZEOF_FLONUM: usize = 3;
+
+/// The value of `x`, if it is a flonum.
+pub fn as_f64(x: &Value) -> Option<f64> {
+    if x.tag() != value::Tags::RustData {
+        return None
+    }
+    unsafe {
+        let object = x.as_ptr();
+        if (*object).get() & HEADER_TAG == value::HeaderTag::RustData as usize &&
+           (*object.offset(1)).get() == FLONUM {
+            Some(f64::from_bits((*object.offset(2)).get() as u64))
+        } else {
+            None
+        }
+    }
+}
+
+/// `x` in the external syntax of flonums.
+pub fn format(x: f64) -> String {
+    if x.is_nan() {
+        "+nan.0".to_owned()
+    } else if x.is_infinite() {
+        if x > 0.0 { "+inf.0" } else { "-inf.0" }.to_owned()
+    } else {
+        // `Debug` writes the shortest digits that read back exactly, with
+        // a `.0` on integers.
+        format!("{:?}", x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::Heap;
+    use super::*;
+
+    #[test]
+    fn flonums_hold_their_bits_across_collections() {
+        let mut heap = Heap::new(1 << 4);
+        for &x in &[0.5, -0.0, 1e300, ::std::f64::NAN] {
+            heap.alloc_flonum(x).unwrap();
+            ::alloc::collect(&mut heap);
+            let y = as_f64(&heap.stack.pop().unwrap()).unwrap();
+            assert_eq!(y.to_bits(), x.to_bits());
+        }
+        assert_eq!(as_f64(&Value::nil()), None);
+        let formatted: Vec<_> = [2.0, 0.1, -1.5e-7, 1e21, ::std::f64::INFINITY]
+                                    .iter()
+                                    .map(|&x| format(x))
+                                    .collect();
+        assert_eq!(formatted, ["2.0", "0.1", "-1.5e-7", "1e21", "+inf.0"]);
+    }
+}
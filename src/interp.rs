@@ -1,76 +1,298 @@
 //! The interpreter for `RustyScheme`.
 //!
 //! This is the part of `RustyScheme` that actually executes `RustyScheme`
-//! bytecode.  It is a simple `match`-based interpreter.  Future optimizations
-//! include using tail calls to implement the equivalent of computed gotos.
+//! bytecode.  Each instruction is a function in `ops`, and the dispatch loop
+//! calls them through a table indexed by opcode, so that dispatch is one
+//! indirect call rather than a chain of comparisons.  The benchmarks in
+//! `benches` measure it.
 //!
-//! The entry point is in `self::interpret_bytecode`.  Upon entering this
-//! function (ex. from a Rust API call), the called function must be at the
-//! bottom of the stack, followed by the arguments.
+//! Compiled programs are added with `load`, and closures are called with
+//! `call`.  The lower-level entry point is `self::interpret_bytecode`,
+//! which runs from the current program counter until the current frame
+//! returns.
 //!
 //! Upon a Scheme->Scheme function call, the data stack layout is:
 //!
 //! |--------------------|
+//! | temporaries        | <- frame pointer + frame size
+//! |--------------------|
+//! | local variables    |
+//! |--------------------|
 //! | arguments          |
 //! |--------------------|
-//! | caller function    |
+//! | called closure     | <- frame pointer
 //! |--------------------|
 //!
-//! and the control stack layout is:
+//! and the control stack holds the caller's program counter, frame pointer,
+//! function, and the end of its frame, in a single Rust struct.  The frame
+//! is a fixed window of `Function::frame_size` slots, allocated on entry,
+//! and every operand is a slot relative to the frame pointer (see
+//! `bytecode::Opcode`), so instructions never push or pop.  Slot 0 is the
+//! closure being run, which holds its upvalues and constants (see
+//! `closure`).  A call starts the callee's frame at the slot of the
+//! procedure, and `Return` replaces the whole frame with the returned
+//! value, in that slot, and restores the window of the caller.
 //!
-//! |--------------------|
-//! | return address     |
-//! |--------------------|
-//! | old frame pointer  |
-//! |--------------------|
-//! | captured?          |
-//! |--------------------|
+//! Native procedures and `apply` find their arguments at the top of the
+//! stack, so a call first cuts the stack off after the arguments.
+//!
+//! Calls between Scheme procedures do not recurse in Rust, so recursion is
+//! limited only by `State::max_frames`, past which a call is an error
+//! rather than a stack that grows until memory runs out.  Native procedures
+//! that call Scheme procedures do recurse, through `call`, which is limited
+//! to `MAX_NESTED_CALLS` deep so that the Rust stack cannot overflow.
+//!
+//! ### Interrupts and fuel
+//!
+//! Every `CHECK_INTERVAL` instructions, the dispatch loop checks whether
+//! the host has set the interrupt flag (`State::interrupt`), from a Ctrl-C
+//! handler or another thread, and whether the fuel of `run_with_fuel` has
+//! run out.  Either stops the code with an error, so that a runaway loop
+//! can be stopped without stopping the host.
+//!
+//! `eval_with_budget` runs a call for a given number of instructions, and
+//! pauses it, rather than failing, if it has not returned by then: its
+//! frames move off the stack into a `Suspended`, which `resume` continues,
+//! so that the host can time-slice untrusted code.  Only the code of the
+//! call itself can be paused – fuel that runs out inside a Scheme
+//! procedure called by a native procedure is an error, since the native
+//! procedure's Rust frames cannot be saved.
+//!
+//! The code of all functions is kept in one vector, and never freed.  Jump
+//! targets are relative to the entry of the current function.
+//!
+//! ### Pending work
 //!
-//! but these three objects are all held in a single Rust struct.
+//! Native procedures must not run Scheme code that may take arbitrarily
+//! long, since the host is blocked until they return.  Instead, they can
+//! `enqueue` a call, which runs the next time the host calls
+//! `poll_pending_work` – typically once per frame of a GUI or game loop.
 //!
-//! `const STACK_OFFSET: usize` holds the difference between the old stack
-//! pointer and the new frame pointer. `captured?` holds whether the Scheme
-//! environment has been captured.
+//! ### Green threads
+//!
+//! `spawn` queues a thread: a call of a thunk, which `run_threads` runs
+//! with the others, round-robin, each for a slice of `THREAD_SLICE`
+//! instructions at a time, pausing it as `eval_with_budget` does, until all
+//! have returned.  A thread can also give up the rest of its slice with
+//! `yield_thread`.  A thread inside a Scheme procedure called by a native
+//! procedure cannot be paused, so it runs on until it has returned from
+//! the native procedure, and is paused then: threads are cooperative, and
+//! one that loops forever inside, say, `sort` stops the others.
+//!
+//! Each thread has its own stack of parameter bindings (see
+//! `builtins::parameters`), which starts as a copy of the bindings of the
+//! code that spawned it, and is swapped in while it runs.  There are no
+//! continuations, and so no `dynamic-wind` winders, to keep per thread.
+//!
+//! ### Fused loops
+//!
+//! Tight numeric loops end in a back edge of the form
+//!
+//! ```text
+//! Add/Subtract  i, step -> i
+//! Less/NumEqual i, n    -> t
+//! JumpIfTrue/JumpIfFalse t -> loop head
+//! ```
+//!
+//! When the dispatch loop reaches such a sequence and the operands are
+//! fixnums, `fused_back_edge` executes all three instructions at once.  Any
+//! other operands (or an overflow) take the ordinary path, one instruction
+//! at a time.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
-use std::ptr;
 use value;
 use alloc;
-use arith;
+use audit;
+use builtins;
+use closure;
+use compiler;
+use debugger;
+use prelude;
+use profiler;
+use record;
+use repl;
+use symbol;
+
+use bytecode::{self, Bytecode, Opcode};
+
+/// The default of `State::max_frames`.
+pub const DEFAULT_MAX_FRAMES: usize = 100_000;
 
-use bytecode::{Bytecode, Opcode};
+/// How many calls of `call` (by native procedures, or the host) may be
+/// running inside each other.
+const MAX_NESTED_CALLS: usize = 256;
 
-const STACK_OFFSET: usize = 1;
+/// How many instructions run between checks of the interrupt flag and the
+/// fuel.
+const CHECK_INTERVAL: usize = 1024;
 
+/// How many instructions a green thread runs before the next has a turn.
+const THREAD_SLICE: usize = 10_000;
+
+/// The caller's state, saved by `Call`.
 pub struct ActivationRecord {
     return_address: usize,
     frame_pointer: usize,
-    captured: bool,
+    function: Option<usize>,
+
+    /// The length of the stack when the caller was running: the end of its
+    /// frame.
+    window_end: usize,
+}
+
+/// A frame of a backtrace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The function running in the frame, as an index into
+    /// `State::functions`, if it is in one.
+    pub function: Option<usize>,
+
+    /// The instruction it was running, relative to the entry of the
+    /// function.
+    pub pc: usize,
+}
+
+impl Frame {
+    /// The function running in the frame, and where it came from.
+    pub fn describe(&self, s: &State) -> String {
+        describe(s, self.function)
+    }
+}
+
+/// The function `function`, and where it came from.
+fn describe(s: &State, function: Option<usize>) -> String {
+    match function.and_then(|function| s.sources.get(function)) {
+        Some(source) => source.to_string(),
+        None => "<unknown>".to_owned(),
+    }
 }
 
 /// The Scheme state.  It has several parts:
 ///
 /// - the program counter (`program_counter`), which stores the current
 ///   bytecode instruction position.
-/// - the stack pointer `sp`, which stores the current stack position.
+/// - the frame pointer `frame_pointer`, which stores the stack index of the
+///   current frame.
+/// - the current function `function`, an index into `functions`.  It is
+///   `None` when running bytecode that is not part of a function, in which
+///   case there is no closure in slot 0.
+/// - the base `base`, the entry of the current function, which jump targets
+///   are relative to.
 /// - the control stack `control_stack`, which stores control flow
 ///   information.
-/// - The environment pointer `env`, which (if non-NULL) points to the current
-///   environment.
-/// - the bytecode `bytecode`, which stores the bytecode currently being
-///   executed.
+/// - the bytecode `bytecode`, which stores the code of every function.
+/// - the function table `functions`.
+/// - the builtin registry `builtins`, which tracks which libraries of
+///   native procedures have been registered.
+/// - the field cache `field_cache`, which caches record field offsets for
+///   each record access instruction in `bytecode`.
+/// - the global cache `global_cache`, which caches the symbol of the global
+///   variable of each `LoadConstantGlobal` in `bytecode` (see
+///   `symbol::GlobalCache`).
+/// - whether the prelude has run, `prelude_loaded`.
+/// - the files being run by `load`, innermost last, `loading`.
+/// - the libraries defined by the programs compiled for the state, and
+///   their top-level imports, `libraries`.  The REPL and `load` compile
+///   with them.
+/// - the macros defined at top level by the REPL's inputs, `macros`, which
+///   later inputs can use.
+/// - where `write`, `display`, and `newline` print, `output`: standard
+///   output, unless the host replaces it.
+/// - the audit hooks `audit`, which see (and can veto) what the code run
+///   in the state does.
+/// - the queue of pending calls `pending`.  Each call is the procedure and
+///   its arguments, held as persistent roots.
+/// - the most frames that the control stack may hold, `max_frames`, and
+///   how many calls of `call` are running, `nested_calls`.
+/// - the interrupt flag `interrupt`, the instructions left of the fuel
+///   given to `run_with_fuel` or `eval_with_budget`, `fuel`, and the
+///   instructions left until they are next checked, `countdown`.
+/// - the value of `nested_calls` at which running out of fuel pauses the
+///   code, `suspendable`, and whether it is pausing, `suspending`.
+/// - the queue of green threads waiting for their turn, `threads`, and
+///   whether one is running, `in_thread`.
+/// - the `debugger` and the `profiler`, and whether either was active when
+///   the dispatch loop last looked, `watching`.
 pub struct State {
     program_counter: usize,
-    sp: usize,
+    frame_pointer: usize,
+    function: Option<usize>,
+    base: usize,
     control_stack: Vec<ActivationRecord>,
-    bytecode: Vec<Bytecode>,
+    pub bytecode: Vec<Bytecode>,
+    pub functions: Vec<bytecode::Function>,
+
+    /// Where the functions came from, indexed like `functions`.
+    pub sources: Vec<bytecode::Source>,
     pub heap: alloc::Heap,
+    pub builtins: builtins::Registry,
+    field_cache: record::FieldCache,
+    global_cache: symbol::GlobalCache,
+    pub printers: record::Printers,
+    pub readable: record::Readable,
+    pub host_types: record::HostTypes,
+    pub history: repl::History,
+    pub prelude_loaded: bool,
+    pub loading: Vec<PathBuf>,
+    pub libraries: compiler::Libraries,
+    pub macros: compiler::Macros,
+    pub output: Box<Write>,
+    pub audit: audit::Hooks,
+    pending: VecDeque<Vec<usize>>,
+    pub max_frames: usize,
+    nested_calls: usize,
+    pub interrupt: Arc<AtomicBool>,
+    fuel: Option<usize>,
+    countdown: usize,
+    suspendable: Option<usize>,
+    suspending: bool,
+    threads: VecDeque<Thread>,
+    in_thread: bool,
+
+    /// Changed through `debugger`, so that changes take effect at once.
+    pub debugger: debugger::Debugger,
+
+    /// Turned on and off with `set_profiling`.
+    pub profiler: profiler::Profiler,
+    watching: bool,
+
+    /// The frames that were running when the last error was raised,
+    /// innermost first.
+    pub backtrace: Vec<Frame>,
+
+    /// The value raised by `raise` or `error`, as a persistent root, and
+    /// the message of the error that raised it.
+    pub raised: Option<(usize, String)>,
+
+    /// The bindings of parameters made by `parameterize`, innermost last,
+    /// as persistent roots of their cells and values (see
+    /// `builtins::parameters`).
+    pub parameters: Vec<(usize, usize)>,
+
+    /// The number of sandboxes made by `make-environment`, which name
+    /// their definitions apart (see `builtins::eval`).
+    pub sandboxes: usize,
+
+    /// When the `State` was made, which is the epoch of `current-jiffy`
+    /// (see `builtins::time`).
+    pub started: Instant,
 }
 
 /// Create a new Scheme interpreter
 pub fn new() -> self::State {
     State {
         program_counter: 0,
-        sp: 0,
+        frame_pointer: 0,
+        function: None,
+        base: 0,
         control_stack: vec![],
         heap: alloc::Heap::new(1 <<
                                if cfg!(debug_assertions) {
@@ -79,228 +301,1328 @@ pub fn new() -> self::State {
             16
         }),
         bytecode: vec![],
+        functions: vec![],
+        sources: vec![],
+        builtins: builtins::Registry::default(),
+        field_cache: record::FieldCache::default(),
+        global_cache: symbol::GlobalCache::default(),
+        printers: record::Printers::default(),
+        readable: record::Readable::default(),
+        host_types: record::HostTypes::default(),
+        history: repl::History::default(),
+        prelude_loaded: false,
+        loading: vec![],
+        libraries: compiler::Libraries::default(),
+        macros: compiler::Macros::default(),
+        output: Box::new(io::stdout()),
+        audit: audit::Hooks::default(),
+        pending: VecDeque::new(),
+        max_frames: DEFAULT_MAX_FRAMES,
+        nested_calls: 0,
+        interrupt: Arc::new(AtomicBool::new(false)),
+        fuel: None,
+        countdown: CHECK_INTERVAL,
+        suspendable: None,
+        suspending: false,
+        threads: VecDeque::new(),
+        in_thread: false,
+        debugger: debugger::Debugger::default(),
+        profiler: profiler::Profiler::default(),
+        watching: false,
+        backtrace: vec![],
+        raised: None,
+        parameters: vec![],
+        sandboxes: 0,
+        started: Instant::now(),
     }
 }
 
 
-/// This function interprets the Scheme bytecode.
-pub fn interpret_bytecode(s: &mut State) -> Result<(), String> {
-    let pc = &mut s.program_counter;
-    let heap = &mut s.heap;
-    heap.environment = ptr::null_mut();
-    let sp = &mut s.sp;
-    let mut fp = 0;
-    loop {
-        let Bytecode { opcode, src, src2, dst } = s.bytecode[*pc];
-        let (src, src2, dst): (usize, usize, usize) = (src.into(), src2.into(), dst.into());
-        // let len = heap.stack.len();
-        match opcode {
-            Opcode::Cons => {
-                heap.alloc_pair(src, src2);
-                heap.stack[dst] = heap.stack.pop().unwrap();
-                *pc += 1;
-            }
-            Opcode::Car => {
-                heap.stack[dst] = try!(heap.stack[src]
-                                           .car()
-                                           .map_err(|()| {
-                                               "Attempt to take the \
-                                                car of a non-pair"
-                                                   .to_owned()
-                                           }));
-                *pc += 1;
-            }
-            Opcode::Cdr => {
-                heap.stack[dst] = try!(heap.stack[src]
-                                           .cdr()
-                                           .map_err(|()| {
-                                               "Attempt to take the \
-                                                cdr of a non-pair"
-                                                   .to_owned()
-                                           }));
-                *pc += 1;
-            }
-            Opcode::SetCar => {
-                try!(heap.stack[dst]
-                         .set_car(heap.stack[src].clone())
-                         .map_err(|()| "Attempt to set the car of a non-pair".to_owned()));
-                *pc += 1;
+/// Executes the arithmetic, compare, and branch at the program counter as
+/// a single step, if they form a fixnum loop back edge (see the module
+/// documentation).  Returns `false`, having done nothing, if they do not.
+///
+/// The dispatch loop has already charged the arithmetic to `countdown`, and
+/// the compare and branch are charged here, so that fuel and budgets count
+/// all three.  With fewer than two instructions left before the limits are
+/// next checked, the three run one at a time instead.
+fn fused_back_edge(s: &mut State) -> bool {
+    let pc = s.program_counter;
+    if pc + 2 >= s.bytecode.len() || s.watching || s.countdown < 2 {
+        return false
+    }
+    let (arith, compare, branch) = (s.bytecode[pc], s.bytecode[pc + 1], s.bytecode[pc + 2]);
+    let target = s.base + branch.jump_target();
+    match (compare.opcode, branch.opcode) {
+        (Opcode::Less, Opcode::JumpIfTrue) |
+        (Opcode::Less, Opcode::JumpIfFalse) |
+        (Opcode::NumEqual, Opcode::JumpIfTrue) |
+        (Opcode::NumEqual, Opcode::JumpIfFalse) if target <= pc &&
+                                                   branch.dst == compare.dst => {}
+        _ => return false,
+    }
+    let fp = s.frame_pointer;
+    let (fst, snd) = (s.heap.stack[fp + arith.src as usize].get(),
+                      s.heap.stack[fp + arith.src2 as usize].get());
+    if (fst | snd) & 3 != 0 {
+        return false
+    }
+    let res = match arith.opcode {
+        Opcode::Add => (fst as isize).checked_add(snd as isize),
+        Opcode::Subtract => (fst as isize).checked_sub(snd as isize),
+        _ => None,
+    };
+    let res = match res {
+        Some(res) => res,
+        None => return false,
+    };
+    s.heap.stack[fp + arith.dst as usize] = value::Value::new(res as usize);
+    let (fst, snd) = (s.heap.stack[fp + compare.src as usize].get(),
+                      s.heap.stack[fp + compare.src2 as usize].get());
+    if (fst | snd) & 3 != 0 {
+        // The arithmetic is done; let the ordinary path handle the rest.
+        s.program_counter = pc + 1;
+        return true
+    }
+    let truth = match compare.opcode {
+        Opcode::Less => (fst as isize) < snd as isize,
+        _ => fst == snd,
+    };
+    s.heap.stack[fp + compare.dst as usize] = value::Value::boolean(truth);
+    let taken = match branch.opcode {
+        Opcode::JumpIfTrue => truth,
+        _ => !truth,
+    };
+    s.countdown -= 2;
+    s.program_counter = if taken { target } else { pc + 3 };
+    true
+}
+
+/// Adds the compiled `program`, and pushes a closure of its main function.
+/// Calling the closure (with no arguments) runs the program.  The first
+/// program loaded is preceded by the prelude, which runs right away.
+pub fn load(s: &mut State, program: &compiler::Program) -> Result<(), String> {
+    if !s.prelude_loaded {
+        s.prelude_loaded = true;
+        try!(prelude::load(s))
+    }
+    let (entry, first) = (s.bytecode.len(), s.functions.len());
+    s.bytecode.extend_from_slice(&program.code);
+    s.functions.extend(program.functions.iter().map(|function| {
+        bytecode::Function {
+            entry: entry + function.entry,
+            first: first,
+            ..*function
+        }
+    }));
+    s.sources.extend(program.sources.iter().cloned());
+    try!(program.constants.materialize(&mut s.heap));
+    let len = s.heap.stack.len();
+    let closure = s.heap.alloc_closure(first, len - 1, 0).map(|()| s.heap.stack.pop().unwrap());
+    // Pop the constants vector.
+    s.heap.stack.pop();
+    s.heap.stack.push(try!(closure));
+    Ok(())
+}
+
+/// Calls the procedure below the topmost `argc` values on the stack, which
+/// are its arguments, replacing it and its arguments with the result.
+pub fn call(s: &mut State, argc: usize) -> Result<(), String> {
+    if s.nested_calls == 0 {
+        clear_error(s)
+    }
+    let argc = try!(resolve_apply(s, argc));
+    let len = s.heap.stack.len();
+    if s.heap.stack[len - argc - 1].tag() == value::Tags::RustFunc {
+        return builtins::call_native(s, argc)
+    }
+    if s.nested_calls == MAX_NESTED_CALLS {
+        return Err("maximum recursion depth exceeded".to_owned())
+    }
+    let (program_counter, frame_pointer, function, base) =
+        (s.program_counter, s.frame_pointer, s.function, s.base);
+    let depth = s.control_stack.len();
+    s.nested_calls += 1;
+    let res = enter(s, argc).and_then(|()| interpret_bytecode(s));
+    s.nested_calls -= 1;
+    if res.is_err() && s.nested_calls == 0 {
+        builtins::errors::attach_backtrace(s)
+    }
+    s.control_stack.truncate(depth);
+    s.program_counter = program_counter;
+    s.frame_pointer = frame_pointer;
+    s.function = function;
+    s.base = base;
+    res
+}
+
+/// Compiles `source` with the libraries of `s`, loads it, and runs it, as
+/// the REPL does, and returns its value.  The tests of every module run
+/// their Scheme code through this.
+#[cfg(test)]
+pub fn run(s: &mut State, source: &str) -> Result<value::Value, String> {
+    use std::io::Read;
+    let forms = compiler::read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+    let program = try!(compiler::compile_in(&mut s.libraries, &forms));
+    try!(load(s, &program));
+    try!(call(s, 0));
+    Ok(s.heap.stack.pop().unwrap())
+}
+
+/// Like `call`, but fails with "out of fuel" if the call has not returned
+/// after about `fuel` instructions.
+pub fn run_with_fuel(s: &mut State, argc: usize, fuel: usize) -> Result<(), String> {
+    let (saved, in_thread) = (s.fuel, s.in_thread);
+    s.fuel = Some(fuel);
+    s.countdown = 0;
+    // Fuel that runs out fails the call, even inside a green thread.
+    s.in_thread = false;
+    let res = call(s, argc);
+    s.fuel = saved;
+    s.in_thread = in_thread;
+    s.countdown = 0;
+    res
+}
+
+/// How a call run by `eval_with_budget` or `resume` ended.
+pub enum Outcome {
+    /// It returned, and its value replaced the procedure and its arguments.
+    Done,
+
+    /// It ran out of fuel, and was paused.
+    Suspended(Suspended),
+}
+
+/// A call paused by `eval_with_budget`.  It holds the frames of the call,
+/// as persistent roots, and where in its code it stopped, so the host can
+/// do anything else before it is resumed.  A call that will not be resumed
+/// must be given to `cancel`, or its frames are never freed.
+pub struct Suspended {
+    program_counter: usize,
+    function: Option<usize>,
+    base: usize,
+
+    /// The frame pointer, and those of the frames on the control stack, are
+    /// relative to the first slot of the call.
+    frame_pointer: usize,
+    control_stack: Vec<ActivationRecord>,
+    stack: Vec<usize>,
+}
+
+/// Calls the procedure below the topmost `argc` values on the stack, like
+/// `call`, but for at most about `max_instructions` instructions.  If it
+/// has not returned by then, it is paused, and taken off the stack.
+pub fn eval_with_budget(s: &mut State,
+                        argc: usize,
+                        max_instructions: usize)
+                        -> Result<Outcome, String> {
+    let argc = try!(resolve_apply(s, argc));
+    let start = s.heap.stack.len() - argc - 1;
+    if s.heap.stack[start].tag() == value::Tags::RustFunc {
+        return builtins::call_native(s, argc).map(|()| Outcome::Done)
+    }
+    run_sliced(s, start, max_instructions, |s| enter(s, argc))
+}
+
+/// Continues the paused call `suspended`, for at most about
+/// `max_instructions` more instructions.  Its value (or its frames again,
+/// if it is paused again) goes on top of the stack.
+pub fn resume(s: &mut State,
+              suspended: Suspended,
+              max_instructions: usize)
+              -> Result<Outcome, String> {
+    let start = s.heap.stack.len();
+    run_sliced(s, start, max_instructions, move |s| {
+        for index in suspended.stack {
+            let x = s.heap.persistent.get(index);
+            s.heap.stack.push(x);
+            s.heap.persistent.release(index)
+        }
+        for record in suspended.control_stack {
+            s.control_stack.push(ActivationRecord {
+                frame_pointer: start + record.frame_pointer,
+                window_end: start + record.window_end,
+                ..record
+            })
+        }
+        s.program_counter = suspended.program_counter;
+        s.frame_pointer = start + suspended.frame_pointer;
+        s.function = suspended.function;
+        s.base = suspended.base;
+        Ok(())
+    })
+}
+
+/// Frees the frames of the paused call `suspended`, which is not resumed.
+pub fn cancel(s: &mut State, suspended: Suspended) {
+    for index in suspended.stack {
+        s.heap.persistent.release(index)
+    }
+}
+
+/// Runs a call whose frames start at slot `start` of the stack, once
+/// `setup` has entered it, with `fuel` instructions of fuel.  Pauses it if
+/// the fuel runs out.
+fn run_sliced<F>(s: &mut State, start: usize, fuel: usize, setup: F) -> Result<Outcome, String>
+    where F: FnOnce(&mut State) -> Result<(), String>
+{
+    if s.nested_calls == 0 {
+        clear_error(s)
+    }
+    if s.nested_calls == MAX_NESTED_CALLS {
+        return Err("maximum recursion depth exceeded".to_owned())
+    }
+    let (program_counter, frame_pointer, function, base) =
+        (s.program_counter, s.frame_pointer, s.function, s.base);
+    let (saved_fuel, saved_suspendable) = (s.fuel, s.suspendable);
+    let depth = s.control_stack.len();
+    s.nested_calls += 1;
+    s.fuel = Some(fuel);
+    s.countdown = 0;
+    s.suspendable = Some(s.nested_calls);
+    let res = match setup(s).and_then(|()| run_until(s, depth)) {
+        Err(_) if s.suspending => {
+            s.suspending = false;
+            let control_stack = s.control_stack
+                                 .drain(depth..)
+                                 .map(|record| {
+                                     ActivationRecord {
+                                         frame_pointer: record.frame_pointer - start,
+                                         window_end: record.window_end - start,
+                                         ..record
+                                     }
+                                 })
+                                 .collect();
+            let values: Vec<value::Value> = s.heap.stack.drain(start..).collect();
+            let stack = values.into_iter().map(|x| s.heap.persistent.add(x)).collect();
+            Ok(Outcome::Suspended(Suspended {
+                program_counter: s.program_counter,
+                function: s.function,
+                base: s.base,
+                frame_pointer: s.frame_pointer - start,
+                control_stack: control_stack,
+                stack: stack,
+            }))
+        }
+        Err(e) => {
+            record_backtrace(s, depth);
+            Err(e)
+        }
+        Ok(()) => Ok(Outcome::Done),
+    };
+    s.nested_calls -= 1;
+    if res.is_err() && s.nested_calls == 0 {
+        builtins::errors::attach_backtrace(s)
+    }
+    s.control_stack.truncate(depth);
+    s.program_counter = program_counter;
+    s.frame_pointer = frame_pointer;
+    s.function = function;
+    s.base = base;
+    s.fuel = saved_fuel;
+    s.suspendable = saved_suspendable;
+    s.countdown = 0;
+    res
+}
+
+/// Checks the interrupt flag, which is cleared once it has interrupted
+/// the code, and the fuel, then runs the debugger if it is active, counts
+/// the instruction if the profiler is on, and starts counting down to the
+/// next check.  The next check is before the next instruction while either
+/// is.
+fn check_limits(s: &mut State) -> Result<(), String> {
+    if s.interrupt.swap(false, Ordering::SeqCst) {
+        return Err("interrupted".to_owned())
+    }
+    if s.fuel == Some(0) {
+        s.suspending = s.suspendable == Some(s.nested_calls);
+        // A green thread is paused once it is back where it can be.
+        if s.suspending || !s.in_thread {
+            return Err("out of fuel".to_owned())
+        }
+    }
+    if s.debugger.is_active() {
+        // The code that the debugger runs has no fuel of its own.
+        let (fuel, suspendable) = (s.fuel.take(), s.suspendable.take());
+        let frame = frame_at(s, s.function, s.program_counter);
+        let op = s.bytecode[s.program_counter];
+        let res = debugger::before_instruction(s, &frame, &op);
+        s.fuel = fuel;
+        s.suspendable = suspendable;
+        try!(res)
+    }
+    if s.profiler.is_enabled() {
+        s.profiler.count(s.function, s.heap.bytes_allocated())
+    }
+    s.watching = s.debugger.is_active() || s.profiler.is_enabled();
+    let interval = if s.watching { 1 } else { CHECK_INTERVAL };
+    s.countdown = match s.fuel {
+        Some(fuel) if fuel > 0 => {
+            let countdown = cmp::min(fuel, interval);
+            s.fuel = Some(fuel - countdown);
+            countdown
+        }
+        _ => interval,
+    };
+    Ok(())
+}
+
+/// Makes the dispatch loop check the limits, the debugger and the profiler
+/// before the next instruction, so that changes to them take effect at once.
+fn check_now(s: &mut State) {
+    if let Some(fuel) = s.fuel {
+        s.fuel = Some(fuel + s.countdown)
+    }
+    s.countdown = 0
+}
+
+/// The debugger of `s`.  The dispatch loop looks at it again before the
+/// next instruction, so that changes to it take effect at once.
+pub fn debugger(s: &mut State) -> &mut debugger::Debugger {
+    check_now(s);
+    &mut s.debugger
+}
+
+/// Turns the profiler on or off, from the next instruction on (see
+/// `profiler`).
+pub fn set_profiling(s: &mut State, on: bool) {
+    let allocated = s.heap.bytes_allocated();
+    s.profiler.set_enabled(on, allocated);
+    check_now(s)
+}
+
+/// Takes what the profiler has counted so far, and starts counting from
+/// zero.
+pub fn profile(s: &mut State) -> profiler::Profile {
+    let costs = s.profiler.take(s.heap.bytes_allocated());
+    profiler::Profile::new(costs, |function| describe(s, function))
+}
+
+/// Pops a procedure and the `argc` arguments above it, and queues a call
+/// of the procedure with them, to be run by `poll_pending_work`.
+pub fn enqueue(s: &mut State, argc: usize) -> Result<(), String> {
+    let len = s.heap.stack.len();
+    if argc >= len {
+        return Err("Attempt to pop from empty stack".to_owned())
+    }
+    {
+        let procedure = &s.heap.stack[len - argc - 1];
+        if !closure::is_closure(procedure) && procedure.tag() != value::Tags::RustFunc {
+            return Err("Attempt to enqueue a non-procedure".to_owned())
+        }
+    }
+    let values: Vec<value::Value> = s.heap.stack.drain(len - argc - 1..).collect();
+    let call = values.into_iter().map(|x| s.heap.persistent.add(x)).collect();
+    s.pending.push_back(call);
+    Ok(())
+}
+
+/// The number of calls that are queued.
+pub fn pending_work(s: &State) -> usize {
+    s.pending.len()
+}
+
+/// Runs the calls queued by `enqueue`, in order, discarding their results.
+/// Returns the number of calls that were run.
+///
+/// Calls queued while polling are left for the next poll, so that a
+/// callback that re-queues itself cannot keep the host from regaining
+/// control.  If a call fails, polling stops, and the calls after it stay
+/// queued.
+pub fn poll_pending_work(s: &mut State) -> Result<usize, String> {
+    let count = s.pending.len();
+    for _ in 0..count {
+        let call = s.pending.pop_front().unwrap();
+        let base = s.heap.stack.len();
+        for &index in &call {
+            let x = s.heap.persistent.get(index);
+            s.heap.stack.push(x);
+            s.heap.persistent.release(index)
+        }
+        if let Err(e) = self::call(s, call.len() - 1) {
+            s.heap.stack.truncate(base);
+            return Err(e)
+        }
+        s.heap.stack.pop();
+    }
+    Ok(count)
+}
+
+/// A green thread waiting for its turn: the thunk it calls, or the call of
+/// it that was paused, and its parameter bindings, all as persistent roots.
+struct Thread {
+    call: ThreadCall,
+    parameters: Vec<(usize, usize)>,
+}
+
+enum ThreadCall {
+    Start(usize),
+    Paused(Suspended),
+}
+
+/// Pops a thunk, and queues a green thread that calls it, to be run by
+/// `run_threads`.
+pub fn spawn(s: &mut State) -> Result<(), String> {
+    {
+        let thunk = try!(s.heap.stack.last().ok_or("Attempt to pop from empty stack"));
+        if !closure::is_closure(thunk) && thunk.tag() != value::Tags::RustFunc {
+            return Err("spawn: not a procedure".to_owned())
+        }
+    }
+    let thunk = s.heap.stack.pop().unwrap();
+    let mut parameters = vec![];
+    for &(cell, x) in &s.parameters {
+        let (cell, x) = (s.heap.persistent.get(cell), s.heap.persistent.get(x));
+        parameters.push((s.heap.persistent.add(cell), s.heap.persistent.add(x)))
+    }
+    let thread = Thread {
+        call: ThreadCall::Start(s.heap.persistent.add(thunk)),
+        parameters: parameters,
+    };
+    s.threads.push_back(thread);
+    Ok(())
+}
+
+/// The number of green threads that are waiting for their turn.
+pub fn threads(s: &State) -> usize {
+    s.threads.len()
+}
+
+/// Ends the slice of the green thread that is running, if one is, so that
+/// the next has its turn.
+pub fn yield_thread(s: &mut State) {
+    if s.in_thread {
+        s.fuel = Some(0);
+        s.countdown = 0
+    }
+}
+
+/// Frees the parameter bindings `parameters`.
+fn release_parameters(s: &mut State, parameters: Vec<(usize, usize)>) {
+    for (cell, x) in parameters {
+        s.heap.persistent.release(cell);
+        s.heap.persistent.release(x)
+    }
+}
+
+/// Runs the green threads queued by `spawn`, and those that they spawn, in
+/// turn until all have returned, discarding their results.  If a thread
+/// fails, the others stay queued.
+pub fn run_threads(s: &mut State) -> Result<(), String> {
+    if s.in_thread {
+        return Err("run-threads: called from a thread".to_owned())
+    }
+    while let Some(thread) = s.threads.pop_front() {
+        let outer = mem::replace(&mut s.parameters, thread.parameters);
+        let base = s.heap.stack.len();
+        s.in_thread = true;
+        let res = match thread.call {
+            ThreadCall::Start(root) => {
+                let thunk = s.heap.persistent.get(root);
+                s.heap.persistent.release(root);
+                s.heap.stack.push(thunk);
+                eval_with_budget(s, 0, THREAD_SLICE)
             }
-            Opcode::SetCdr => {
-                try!(heap.stack[dst]
-                         .set_cdr(heap.stack[src].clone())
-                         .map_err(|()| "Attempt to set the cdr of a non-pair".to_owned()));
-                *pc += 1;
+            ThreadCall::Paused(suspended) => resume(s, suspended, THREAD_SLICE),
+        };
+        s.in_thread = false;
+        let parameters = mem::replace(&mut s.parameters, outer);
+        match res {
+            Ok(Outcome::Suspended(suspended)) => {
+                s.threads.push_back(Thread {
+                    call: ThreadCall::Paused(suspended),
+                    parameters: parameters,
+                })
             }
-            Opcode::Set => {
-                heap.stack[dst] = heap.stack[src].clone();
-                *pc += 1;
+            Ok(Outcome::Done) => {
+                s.heap.stack.truncate(base);
+                release_parameters(s, parameters)
             }
-            Opcode::Add => {
-                // The hot paths are fixnums and flonums.  They are inlined.
-                // Most scripts probably do not heavily use complex numbers.
-                // Bignums or rationals will always be slow.
-                let (fst, snd) = (heap.stack[src].get(), heap.stack[src2].get());
-                heap.stack.push(if fst & snd & 3 == 0 {
-                    value::Value::new(fst.wrapping_add(snd)) // TODO: bignumx
-                } else {
-                    return Err("wrong type to add".to_owned());
-                });
-                *pc += 1;
+            Err(e) => {
+                s.heap.stack.truncate(base);
+                release_parameters(s, parameters);
+                return Err(e)
             }
+        }
+    }
+    Ok(())
+}
 
-            Opcode::Subtract => {
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                // See above.
-                heap.stack[dst] = try!(arith::subtract(heap, &fst, &snd));
-                *pc += 1;
+/// Replaces the topmost of the `argc` values on the stack, which must be a
+/// list, with its elements.  Returns the new number of values.
+fn spread(s: &mut State, argc: usize) -> Result<usize, String> {
+    if argc == 0 {
+        return Err("apply: no argument list".to_owned())
+    }
+    let mut elements = vec![];
+    {
+        // The slow pointer catches up with a circular list.
+        let list = s.heap.stack.last().unwrap();
+        let (mut x, mut slow) = (list.clone(), list.clone());
+        while x.tag() == value::Tags::Pair {
+            elements.push(x.car().unwrap());
+            x = x.cdr().unwrap();
+            if elements.len() % 2 == 0 {
+                slow = slow.cdr().unwrap();
+                if slow.get() == x.get() {
+                    return Err("apply: circular argument list".to_owned())
+                }
             }
+        }
+        if !x.is_nil() {
+            return Err("apply: last argument is not a list".to_owned())
+        }
+    }
+    s.heap.stack.pop();
+    let len = elements.len();
+    s.heap.stack.extend(elements);
+    Ok(argc - 1 + len)
+}
 
-            Opcode::Multiply => {
-                // See above.
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                heap.stack[dst] = try!(arith::multiply(heap, &fst, &snd));
-                *pc += 1;
-            }
+/// While the procedure below the topmost `argc` values on the stack is
+/// `apply`, replaces it with a call of its first argument.  The interpreter
+/// calls `apply` itself, so that it does not grow the Rust stack and can
+/// make tail calls.  Returns the new number of arguments.
+fn resolve_apply(s: &mut State, argc: usize) -> Result<usize, String> {
+    let mut argc = argc;
+    loop {
+        let start = s.heap.stack.len() - argc - 1;
+        if !builtins::is_apply(&s.heap.stack[start]) {
+            return Ok(argc)
+        }
+        if argc < 2 {
+            return Err(format!("apply: wrong number of arguments: expected at least 2, got {}",
+                               argc))
+        }
+        s.heap.stack.remove(start);
+        argc = try!(spread(s, argc - 1))
+    }
+}
 
-            Opcode::Divide => {
-                // See above.
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                heap.stack[dst] = try!(arith::divide(heap, &fst, &snd));
-                *pc += 1;
-            }
+/// Enters the closure below the topmost `argc` values on the stack, which
+/// are its arguments, and allocates the rest of its frame.  Extra
+/// arguments are collected into a list if the closure takes a rest
+/// argument.
+fn enter(s: &mut State, argc: usize) -> Result<(), String> {
+    let fp = s.heap.stack.len() - argc - 1;
+    let id = try!(closure::function(&s.heap.stack[fp]));
+    let function = s.functions[id];
+    if argc < function.nargs || argc > function.nargs && !function.rest {
+        return Err(format!("wrong number of arguments: expected {}{}, got {}",
+                           if function.rest { "at least " } else { "" },
+                           function.nargs,
+                           argc))
+    }
+    if function.rest {
+        let first = fp + 1 + function.nargs;
+        s.heap.stack.push(value::Value::nil());
+        for i in (first..fp + 1 + argc).rev() {
+            let top = s.heap.stack.len() - 1;
+            try!(s.heap.alloc_pair(i, top));
+            s.heap.stack[top] = s.heap.stack.pop().unwrap();
+        }
+        let rest = s.heap.stack.pop().unwrap();
+        s.heap.stack.truncate(first);
+        s.heap.stack.push(rest)
+    }
+    s.heap.stack.resize(fp + function.frame_size, value::Value::unspecified());
+    s.frame_pointer = fp;
+    s.function = Some(id);
+    s.base = function.entry;
+    s.program_counter = function.entry;
+    Ok(())
+}
 
-            Opcode::Power => {
-                // See above.
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                heap.stack[dst] = arith::exponential(fst, snd);
-                *pc += 1;
-            }
+/// The current function, which some instructions require.
+fn current_function(s: &State) -> Result<bytecode::Function, String> {
+    s.function
+     .map(|function| s.functions[function])
+     .ok_or_else(|| "instruction requires a closure".to_owned())
+}
 
-            Opcode::Closure => {
-                heap.alloc_closure(src as u8, src2 as u8, dst);
-                let len = heap.stack.len();
-                heap.environment = unsafe { heap.stack[len - 1].as_ptr() } as *mut value::Vector;
-                *pc += 1;
+/// Disassembles the function of `closure`, followed by the functions that
+/// it creates closures of, and so on (see `bytecode::disassemble_code`).
+pub fn disassemble(s: &State, closure: &value::Value) -> Result<String, String> {
+    let mut ids = vec![try!(closure::function(closure))];
+    let mut out = String::new();
+    let mut i = 0;
+    while i < ids.len() {
+        let id = ids[i];
+        let function = s.functions[id];
+        let end = s.functions.get(id + 1).map_or(s.bytecode.len(), |next| next.entry);
+        let code = &s.bytecode[function.entry..end];
+        for op in code {
+            if let Opcode::Closure = op.opcode {
+                let nested = function.first + (op.src as usize | (op.src2 as usize) << 8);
+                if nested < s.functions.len() && !ids.contains(&nested) {
+                    ids.push(nested)
+                }
             }
+        }
+        let constant = |i| {
+            let index = try!(function.constants.get(i));
+            closure::constant(closure, index).map(|x| bytecode::describe_constant(&x))
+        };
+        out.push_str(&format!("function {} ({} arguments{}, {} slots):\n",
+                              id,
+                              function.nargs,
+                              if function.rest { " and a rest list" } else { "" },
+                              function.frame_size));
+        out.push_str(&bytecode::disassemble_code(code, &|i| constant(i).ok()));
+        i += 1
+    }
+    Ok(out)
+}
 
-            Opcode::MakeArray => {
-                alloc::Heap::alloc_vector(heap, src, src2);
-                *pc += 1;
-            }
+/// This function interprets the Scheme bytecode, until the current frame
+/// returns.
+pub fn interpret_bytecode(s: &mut State) -> Result<(), String> {
+    let depth = s.control_stack.len();
+    let res = run_until(s, depth);
+    if res.is_err() {
+        record_backtrace(s, depth)
+    }
+    res
+}
 
-            Opcode::SetArray => {
-                let index = try!(heap.stack[src].as_fixnum());
-                try!(heap.stack[dst].array_set(index, &heap.stack[src2]));
-                *pc += 1;
-            }
+/// Adds the frames of the code that has just failed, down to the frame
+/// that the control stack was `depth` deep below, to the backtrace.  Code
+/// run by a native procedure fails first, so the frames of the Scheme code
+/// that called the native procedure come after its frames.
+fn record_backtrace(s: &mut State, depth: usize) {
+    let mut frames = vec![(s.function, s.program_counter)];
+    frames.extend(s.control_stack[depth..]
+                      .iter()
+                      .rev()
+                      .map(|record| (record.function, record.return_address - 1)));
+    for (function, pc) in frames {
+        let frame = frame_at(s, function, pc);
+        s.backtrace.push(frame)
+    }
+}
 
-            Opcode::GetArray => {
-                let index = try!(heap.stack[src].as_fixnum());
-                heap.stack[dst] = try!(heap.stack[src2]
-                                           .array_get(index)
-                                           .map(|ptr| unsafe { (*ptr).clone() }));
-                *pc += 1;
-            }
+/// The frame of `function`, running the instruction at `pc`.
+fn frame_at(s: &State, function: Option<usize>, pc: usize) -> Frame {
+    let entry = function.map_or(0, |function| s.functions[function].entry);
+    Frame { function: function, pc: pc - entry }
+}
 
-            // Frame layout: activation record below rest of data
-            Opcode::Call => {
-                let frame_pointer = *sp - src - 1;
-                s.control_stack.push(ActivationRecord {
-                    return_address: *pc,
-                    frame_pointer: frame_pointer,
-                    captured: !heap.environment.is_null(),
-                });
-                *pc = 0;
-                *sp = heap.stack.len();
-                fp = frame_pointer;
-            }
+/// The frames of the code that is running, innermost first, with their
+/// frame pointers.
+fn live_frames(s: &State) -> Vec<(Frame, usize)> {
+    let mut frames = vec![(frame_at(s, s.function, s.program_counter), s.frame_pointer)];
+    frames.extend(s.control_stack.iter().rev().map(|record| {
+        (frame_at(s, record.function, record.return_address - 1), record.frame_pointer)
+    }));
+    frames
+}
 
-            Opcode::LoadFalse => {
-                heap.stack.push(value::Value::new(value::FALSE));
-            }
+/// The frames of the Scheme code that is running, innermost first, for
+/// debuggers.  The frames that called the native procedures that called
+/// the innermost code are not among them.
+pub fn frames(s: &State) -> Vec<Frame> {
+    live_frames(s).into_iter().map(|(frame, _)| frame).collect()
+}
 
-            Opcode::LoadTrue => {
-                heap.stack.push(value::Value::new(value::TRUE));
-            }
+/// The closure and its function running in frame `depth` of `frames`.
+fn frame_function(s: &State, depth: usize) -> Option<(&value::Value, bytecode::Function)> {
+    live_frames(s).get(depth).and_then(|&(ref frame, fp)| {
+        frame.function.map(|function| (&s.heap.stack[fp], s.functions[function]))
+    })
+}
 
-            Opcode::LoadNil => heap.stack.push(value::Value::new(value::NIL)),
-            Opcode::TailCall => {
-                let (first, rest) = heap.stack.split_at_mut(*sp - src - 1);
-                *pc = 0;
-                *sp = fp + src + 1;
-                first[fp..*sp].clone_from_slice(rest);
-            }
+/// The slots of frame `depth` of `frames` after the closure: the
+/// arguments, then the local variables and temporaries.
+pub fn locals(s: &State, depth: usize) -> Option<&[value::Value]> {
+    live_frames(s).get(depth).and_then(|&(ref frame, fp)| {
+        frame.function.map(|function| {
+            &s.heap.stack[fp + 1..fp + s.functions[function].frame_size]
+        })
+    })
+}
 
-            Opcode::Return => {
-                if let Some(return_frame) = s.control_stack.pop() {
-                    *sp = fp;
-                    *pc = return_frame.return_address;
-                    fp = return_frame.frame_pointer
-                } else {
-                    return Ok(());
-                }
-            }
+/// The upvalues of the closure running in frame `depth` of `frames`.
+pub fn environment(s: &State, depth: usize) -> Option<Vec<value::Value>> {
+    frame_function(s, depth).map(|(closure, function)| {
+        (0..function.upvalues).filter_map(|i| closure::upvalue(closure, i).ok()).collect()
+    })
+}
 
-            Opcode::LoadEnvironment => {
-                let to_be_pushed = if heap.environment.is_null() {
-                    heap.stack[src + fp].clone()
-                } else {
-                    unsafe {
-                        (*value::Value::raw_array_get(heap.environment as *const _, src).unwrap())
-                            .clone()
-                    }
-                };
-                heap.stack.push(to_be_pushed.clone());
-                *pc += 1;
-            }
+/// The constants of the function running in frame `depth` of `frames`.
+pub fn constants(s: &State, depth: usize) -> Option<Vec<value::Value>> {
+    frame_function(s, depth).map(|(closure, function)| {
+        (0..function.constants.len)
+            .filter_map(|i| function.constants.get(i).ok())
+            .filter_map(|index| closure::constant(closure, index).ok())
+            .collect()
+    })
+}
 
-            Opcode::LoadConstant => {
-                let x = unsafe {
-                    (*value::Value::raw_array_get(heap.constants, src).unwrap()).clone()
-                };
-                heap.stack.push(x);
-                *pc += 1;
-            }
+/// Forgets the backtrace and the raised value of the last error.
+pub fn clear_error(s: &mut State) {
+    s.backtrace.clear();
+    if let Some((root, _)) = s.raised.take() {
+        s.heap.persistent.release(root)
+    }
+}
+
+/// Interprets the bytecode until the frame that the control stack was
+/// `depth` deep below returns.
+fn run_until(s: &mut State, depth: usize) -> Result<(), String> {
+    loop {
+        if s.countdown == 0 {
+            try!(check_limits(s))
+        }
+        s.countdown -= 1;
+        let Bytecode { opcode, src, src2, dst } = s.bytecode[s.program_counter];
+        let operands = ops::Operands {
+            src: src.into(),
+            src2: src2.into(),
+            dst: dst.into(),
+            fp: s.frame_pointer,
+            depth: depth,
+        };
+        if try!(ops::DISPATCH[opcode as usize](s, operands)) {
+            return Ok(())
+        }
+    }
+}
+
+/// The instructions, one function each, and the dispatch table of them
+/// (see the module documentation).
+mod ops {
+    use alloc;
+    use value;
+    use arith;
+    use builtins;
+    use closure;
+    use record;
+
+    use super::{ActivationRecord, State, current_function, enter, fused_back_edge,
+                resolve_apply, return_from, spread};
+
+    /// The operands of an instruction, and where it runs: the frame
+    /// pointer, and the depth of the control stack when
+    /// `interpret_bytecode` was entered.
+    #[derive(Copy, Clone)]
+    pub struct Operands {
+        pub src: usize,
+        pub src2: usize,
+        pub dst: usize,
+        pub fp: usize,
+        pub depth: usize,
+    }
+
+    /// Executes one instruction.  Returns `true` if it returned from the
+    /// frame that `interpret_bytecode` was entered with.
+    pub type Handler = fn(&mut State, Operands) -> Result<bool, String>;
 
-            Opcode::LoadArgument => {
-                let x = heap.stack[fp + src].clone();
-                heap.stack.push(x);
-                *pc += 1;
+    /// The handlers of the opcodes, in the order of `bytecode::OPCODES`.
+    pub static DISPATCH: [Handler; 45] = [
+        cons, car, cdr, set_car, set_cdr, is_pair,
+        add, subtract, multiply, divide, power,
+        make_array, set_array, get_array, is_array, array_len,
+        call, tail_call, ret, make_closure, set,
+        load_constant, load_environment, set, load_global,
+        load_false, load_true, load_nil, store_environment,
+        set, store_global, less, num_equal, jump,
+        jump_if_false, jump_if_true, record_ref, record_set, pop,
+        load_unspecified, apply, load_constant_global, add_const,
+        less_jump_if_false, num_equal_jump_if_false,
+    ];
+
+    /// Stores `x` in slot `dst`, and goes on to the next instruction.
+    fn store(s: &mut State, o: Operands, x: value::Value) -> Result<bool, String> {
+        s.heap.stack[o.fp + o.dst] = x;
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn unspecified() -> value::Value {
+        value::Value::unspecified()
+    }
+
+    fn cons(s: &mut State, o: Operands) -> Result<bool, String> {
+        try!(s.heap.alloc_pair(o.fp + o.src, o.fp + o.src2));
+        s.heap.stack[o.fp + o.dst] = s.heap.stack.pop().unwrap();
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn car(s: &mut State, o: Operands) -> Result<bool, String> {
+        s.heap.stack[o.fp + o.dst] = try!(s.heap.stack[o.fp + o.src]
+                                               .car()
+                                               .map_err(|()| {
+                                                   "Attempt to take the car of a non-pair"
+                                                       .to_owned()
+                                               }));
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn cdr(s: &mut State, o: Operands) -> Result<bool, String> {
+        s.heap.stack[o.fp + o.dst] = try!(s.heap.stack[o.fp + o.src]
+                                               .cdr()
+                                               .map_err(|()| {
+                                                   "Attempt to take the cdr of a non-pair"
+                                                       .to_owned()
+                                               }));
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn set_car(s: &mut State, o: Operands) -> Result<bool, String> {
+        try!(s.heap.stack[o.fp + o.dst]
+                   .set_car(s.heap.stack[o.fp + o.src].clone())
+                   .map_err(|()| "Attempt to set the car of a non-pair".to_owned()));
+        let (object, new) = (s.heap.stack[o.fp + o.dst].clone(),
+                             s.heap.stack[o.fp + o.src].clone());
+        s.heap.write_barrier(&object, &new);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn set_cdr(s: &mut State, o: Operands) -> Result<bool, String> {
+        try!(s.heap.stack[o.fp + o.dst]
+                   .set_cdr(s.heap.stack[o.fp + o.src].clone())
+                   .map_err(|()| "Attempt to set the cdr of a non-pair".to_owned()));
+        let (object, new) = (s.heap.stack[o.fp + o.dst].clone(),
+                             s.heap.stack[o.fp + o.src].clone());
+        s.heap.write_barrier(&object, &new);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn is_pair(s: &mut State, o: Operands) -> Result<bool, String> {
+        let pair = s.heap.stack[o.fp + o.src].pairp();
+        store(s, o, value::Value::boolean(pair))
+    }
+
+    fn is_array(s: &mut State, o: Operands) -> Result<bool, String> {
+        let vector = ::equal::vector_elements(&s.heap.stack[o.fp + o.src]).is_some();
+        store(s, o, value::Value::boolean(vector))
+    }
+
+    fn array_len(s: &mut State, o: Operands) -> Result<bool, String> {
+        let len = try!(::equal::vector_elements(&s.heap.stack[o.fp + o.src])
+                           .map(|elements| elements.len())
+                           .ok_or("Attempt to take the length of a non-vector"));
+        store(s, o, value::Value::new(len << 2))
+    }
+
+    fn set(s: &mut State, o: Operands) -> Result<bool, String> {
+        s.heap.stack[o.fp + o.dst] = s.heap.stack[o.fp + o.src].clone();
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn add(s: &mut State, o: Operands) -> Result<bool, String> {
+        if fused_back_edge(s) {
+            return Ok(false)
+        }
+        // The hot paths are fixnums and flonums.  They are inlined.
+        // Most scripts probably do not heavily use complex numbers.
+        // Bignums or rationals will always be slow.
+        let (fst, snd) = (s.heap.stack[o.fp + o.src].clone(), s.heap.stack[o.fp + o.src2].clone());
+        s.heap.stack[o.fp + o.dst] = if fst.both_fixnums(&snd) {
+            match (fst.get() as isize).checked_add(snd.get() as isize) {
+                Some(res) => value::Value::new(res as usize),
+                None => try!(arith::add(&mut s.heap, &fst, &snd)),
             }
+        } else {
+            let frame = s.heap.scratch_frame();
+            let (fst, snd) = (frame.root(fst), frame.root(snd));
+            try!(arith::add(&mut s.heap, fst, snd))
+        };
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn subtract(s: &mut State, o: Operands) -> Result<bool, String> {
+        if fused_back_edge(s) {
+            return Ok(false)
+        }
+        // The operands are rooted, since the slow path may allocate.
+        let frame = s.heap.scratch_frame();
+        let (fst, snd) = (frame.root(s.heap.stack[o.fp + o.src].clone()),
+                          frame.root(s.heap.stack[o.fp + o.src2].clone()));
+        s.heap.stack[o.fp + o.dst] = try!(arith::subtract(&mut s.heap, fst, snd));
+        s.program_counter += 1;
+        Ok(false)
+    }
 
-            Opcode::StoreArgument => {
-                let x = heap.stack.pop().unwrap();
-                heap.stack[fp + src] = x;
-                *pc += 1;
+    fn multiply(s: &mut State, o: Operands) -> Result<bool, String> {
+        // See above.
+        let frame = s.heap.scratch_frame();
+        let (fst, snd) = (frame.root(s.heap.stack[o.fp + o.src].clone()),
+                          frame.root(s.heap.stack[o.fp + o.src2].clone()));
+        s.heap.stack[o.fp + o.dst] = try!(arith::multiply(&mut s.heap, fst, snd));
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn divide(s: &mut State, o: Operands) -> Result<bool, String> {
+        // See above.
+        let frame = s.heap.scratch_frame();
+        let (fst, snd) = (frame.root(s.heap.stack[o.fp + o.src].clone()),
+                          frame.root(s.heap.stack[o.fp + o.src2].clone()));
+        s.heap.stack[o.fp + o.dst] = try!(arith::divide(&mut s.heap, fst, snd));
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn power(s: &mut State, o: Operands) -> Result<bool, String> {
+        // See above.
+        let frame = s.heap.scratch_frame();
+        let (fst, snd) = (frame.root(s.heap.stack[o.fp + o.src].clone()),
+                          frame.root(s.heap.stack[o.fp + o.src2].clone()));
+        s.heap.stack[o.fp + o.dst] = try!(arith::exponential(&mut s.heap, fst, snd));
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn less(s: &mut State, o: Operands) -> Result<bool, String> {
+        let frame = s.heap.scratch_frame();
+        let (fst, snd) = (frame.root(s.heap.stack[o.fp + o.src].clone()),
+                          frame.root(s.heap.stack[o.fp + o.src2].clone()));
+        let truth = try!(arith::less(&mut s.heap, fst, snd));
+        s.heap.stack[o.fp + o.dst] = value::Value::boolean(truth);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn num_equal(s: &mut State, o: Operands) -> Result<bool, String> {
+        let frame = s.heap.scratch_frame();
+        let (fst, snd) = (frame.root(s.heap.stack[o.fp + o.src].clone()),
+                          frame.root(s.heap.stack[o.fp + o.src2].clone()));
+        let truth = try!(arith::num_equal(&mut s.heap, fst, snd));
+        s.heap.stack[o.fp + o.dst] = value::Value::boolean(truth);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn jump(s: &mut State, _: Operands) -> Result<bool, String> {
+        s.program_counter = s.base + s.bytecode[s.program_counter].jump_target();
+        Ok(false)
+    }
+
+    /// Jumps if slot `dst` is true, if `when` is, or false otherwise.
+    fn jump_if(s: &mut State, o: Operands, when: bool) -> Result<bool, String> {
+        let truth = s.heap.stack[o.fp + o.dst].is_true();
+        s.program_counter = if truth == when {
+            s.base + s.bytecode[s.program_counter].jump_target()
+        } else {
+            s.program_counter + 1
+        };
+        Ok(false)
+    }
+
+    fn add_const(s: &mut State, o: Operands) -> Result<bool, String> {
+        let x = s.heap.stack[o.fp + o.src].clone();
+        let constant = ((o.src2 as u8 as i8 as isize) << 2) as usize;
+        s.heap.stack[o.fp + o.dst] = match (x.get() as isize).checked_add(constant as isize) {
+            Some(res) if x.fixnump() => value::Value::new(res as usize),
+            _ => {
+                let frame = s.heap.scratch_frame();
+                let (x, constant) = (frame.root(x), frame.root(value::Value::new(constant)));
+                try!(arith::add(&mut s.heap, x, constant))
             }
+        };
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    /// Jumps unless `compare` holds of slots `dst` and `dst + 1`.  Fixnums
+    /// are compared inline.
+    fn compare_jump(s: &mut State,
+                    o: Operands,
+                    compare: fn(&mut alloc::Heap, &value::Value, &value::Value)
+                                -> Result<bool, String>,
+                    fixnums: fn(isize, isize) -> bool)
+                    -> Result<bool, String> {
+        let (fst, snd) = (s.heap.stack[o.fp + o.dst].clone(),
+                          s.heap.stack[o.fp + o.dst + 1].clone());
+        let truth = if fst.both_fixnums(&snd) {
+            fixnums(fst.get() as isize, snd.get() as isize)
+        } else {
+            let frame = s.heap.scratch_frame();
+            let (fst, snd) = (frame.root(fst), frame.root(snd));
+            try!(compare(&mut s.heap, fst, snd))
+        };
+        s.program_counter = if truth {
+            s.program_counter + 1
+        } else {
+            s.base + s.bytecode[s.program_counter].jump_target()
+        };
+        Ok(false)
+    }
+
+    fn less_jump_if_false(s: &mut State, o: Operands) -> Result<bool, String> {
+        compare_jump(s, o, arith::less, |x, y| x < y)
+    }
+
+    fn num_equal_jump_if_false(s: &mut State, o: Operands) -> Result<bool, String> {
+        compare_jump(s, o, arith::num_equal, |x, y| x == y)
+    }
 
-            Opcode::StoreEnvironment => {
-                let to_be_stored = heap.stack.pop().unwrap();
-                if heap.environment.is_null() {
-                    heap.stack[src] = to_be_stored
-                } else {
-                    unsafe {
-                        value::Value::raw_array_set(heap.environment, src, to_be_stored).unwrap()
-                    }
+    fn jump_if_false(s: &mut State, o: Operands) -> Result<bool, String> {
+        jump_if(s, o, false)
+    }
+
+    fn jump_if_true(s: &mut State, o: Operands) -> Result<bool, String> {
+        jump_if(s, o, true)
+    }
+
+    fn record_ref(s: &mut State, o: Operands) -> Result<bool, String> {
+        let offset = try!(s.field_cache.lookup(s.program_counter,
+                                                 &s.heap.stack[o.fp + o.src],
+                                                 &s.heap.stack[o.fp + o.src2]));
+        s.heap.stack[o.fp + o.dst] = record::get(&s.heap.stack[o.fp + o.src], offset);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn record_set(s: &mut State, o: Operands) -> Result<bool, String> {
+        let offset = try!(s.field_cache.lookup(s.program_counter,
+                                                 &s.heap.stack[o.fp + o.dst],
+                                                 &s.heap.stack[o.fp + o.src2]));
+        let (object, new) = (s.heap.stack[o.fp + o.dst].clone(),
+                             s.heap.stack[o.fp + o.src].clone());
+        record::set(&object, offset, new.clone());
+        s.heap.write_barrier(&object, &new);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    /// `Closure`.  The upvalues are copied to the top of the stack, below
+    /// the constants vector, where `alloc_closure` expects them.
+    fn make_closure(s: &mut State, o: Operands) -> Result<bool, String> {
+        let function = try!(current_function(s)).first + (o.src | o.src2 << 8);
+        let upvalues = try!(s.functions
+                             .get(function)
+                             .map(|function| function.upvalues)
+                             .ok_or_else(|| "closure of a nonexistent function".to_owned()));
+        let len = s.heap.stack.len();
+        for i in 0..upvalues {
+            let x = s.heap.stack[o.fp + o.dst + i].clone();
+            s.heap.stack.push(x)
+        }
+        let constants = closure::constants(&s.heap.stack[o.fp]);
+        s.heap.stack.push(constants);
+        try!(s.heap.alloc_closure(function, len + upvalues, upvalues));
+        let closure = s.heap.stack.pop().unwrap();
+        s.heap.stack.truncate(len);
+        store(s, o, closure)
+    }
+
+    fn make_array(s: &mut State, o: Operands) -> Result<bool, String> {
+        try!(s.heap.alloc_vector(o.fp + o.src, o.fp + o.src2));
+        let vector = s.heap.stack.pop().unwrap();
+        store(s, o, vector)
+    }
+
+    fn set_array(s: &mut State, o: Operands) -> Result<bool, String> {
+        let index = try!(s.heap.stack[o.fp + o.src].as_fixnum());
+        try!(s.heap.stack[o.fp + o.dst].array_set(index, &s.heap.stack[o.fp + o.src2]));
+        let (object, new) = (s.heap.stack[o.fp + o.dst].clone(),
+                             s.heap.stack[o.fp + o.src2].clone());
+        s.heap.write_barrier(&object, &new);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn get_array(s: &mut State, o: Operands) -> Result<bool, String> {
+        let index = try!(s.heap.stack[o.fp + o.src].as_fixnum());
+        s.heap.stack[o.fp + o.dst] = try!(s.heap.stack[o.fp + o.src2]
+                                               .array_get(index)
+                                               .map(|ptr| unsafe { (*ptr).clone() }));
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    /// Cuts the stack off after the procedure in slot `src` and the `argc`
+    /// slots above it, so that they are at its top, where calls expect
+    /// them.  Returns the old length of the stack – the end of the frame.
+    fn call_site(s: &mut State, o: Operands, argc: usize) -> usize {
+        let end = s.heap.stack.len();
+        s.heap.stack.truncate(o.fp + o.src + argc + 1);
+        end
+    }
+
+    /// Calls the procedure below the topmost `argc` values on the stack,
+    /// which `call_site` cut off at `end`.  A tail call replaces the current
+    /// frame with the callee and its arguments.  A native procedure is
+    /// called with the frame still in place, after which its result is
+    /// stored in the slot of the procedure (or returned).
+    fn call_procedure(s: &mut State,
+                      o: Operands,
+                      end: usize,
+                      argc: usize,
+                      tail: bool)
+                      -> Result<bool, String> {
+        let argc = try!(resolve_apply(s, argc));
+        let start = s.heap.stack.len() - argc - 1;
+        let native = s.heap.stack[start].tag() == value::Tags::RustFunc;
+        match (native, tail) {
+            (true, false) => {
+                try!(builtins::call_native(s, argc));
+                s.heap.stack.resize(end, unspecified());
+                s.program_counter += 1;
+            }
+            (false, false) => {
+                if s.control_stack.len() >= s.max_frames {
+                    return Err("maximum recursion depth exceeded".to_owned())
                 }
-                *pc += 1;
+                let record = ActivationRecord {
+                    return_address: s.program_counter + 1,
+                    frame_pointer: o.fp,
+                    function: s.function,
+                    window_end: end,
+                };
+                try!(enter(s, argc));
+                s.control_stack.push(record);
             }
-
-            Opcode::LoadGlobal => {
-                *pc += 1;
-                try!(heap.load_global())
+            (true, true) => {
+                try!(builtins::call_native(s, argc));
+                let value = s.heap.stack.pop().unwrap();
+                return Ok(return_from(s, o.depth, value))
             }
-
-            Opcode::StoreGlobal => {
-                *pc += 1;
-                try!(heap.store_global())
+            (false, true) => {
+                for i in 0..argc + 1 {
+                    s.heap.stack[o.fp + i] = s.heap.stack[start + i].clone()
+                }
+                s.heap.stack.truncate(o.fp + argc + 1);
+                try!(enter(s, argc))
             }
-            _ => unimplemented!(),
         }
+        Ok(false)
+    }
+
+    fn call(s: &mut State, o: Operands) -> Result<bool, String> {
+        let end = call_site(s, o, o.src2);
+        call_procedure(s, o, end, o.src2, false)
+    }
+
+    fn tail_call(s: &mut State, o: Operands) -> Result<bool, String> {
+        let end = call_site(s, o, o.src2);
+        call_procedure(s, o, end, o.src2, true)
+    }
+
+    fn apply(s: &mut State, o: Operands) -> Result<bool, String> {
+        let end = call_site(s, o, o.src2);
+        let argc = try!(spread(s, o.src2));
+        call_procedure(s, o, end, argc, o.dst != 0)
+    }
+
+    fn load_false(s: &mut State, o: Operands) -> Result<bool, String> {
+        store(s, o, value::Value::new(value::FALSE))
+    }
+
+    fn load_true(s: &mut State, o: Operands) -> Result<bool, String> {
+        store(s, o, value::Value::new(value::TRUE))
+    }
+
+    fn load_nil(s: &mut State, o: Operands) -> Result<bool, String> {
+        store(s, o, value::Value::nil())
+    }
+
+    fn load_unspecified(s: &mut State, o: Operands) -> Result<bool, String> {
+        store(s, o, unspecified())
+    }
+
+    fn pop(s: &mut State, _: Operands) -> Result<bool, String> {
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    fn ret(s: &mut State, o: Operands) -> Result<bool, String> {
+        let value = s.heap.stack[o.fp + o.src].clone();
+        Ok(return_from(s, o.depth, value))
+    }
+
+    fn load_environment(s: &mut State, o: Operands) -> Result<bool, String> {
+        let x = try!(closure::upvalue(&s.heap.stack[o.fp], o.src));
+        store(s, o, x)
+    }
+
+    fn load_constant(s: &mut State, o: Operands) -> Result<bool, String> {
+        let index = try!(try!(current_function(s)).constants.get(o.src));
+        let x = try!(closure::constant(&s.heap.stack[o.fp], index));
+        store(s, o, x)
+    }
+
+    fn store_environment(s: &mut State, o: Operands) -> Result<bool, String> {
+        let (new, closure) = (s.heap.stack[o.fp + o.src].clone(), s.heap.stack[o.fp].clone());
+        try!(closure::set_upvalue(&closure, o.dst, new.clone()));
+        s.heap.write_barrier(&closure, &new);
+        s.program_counter += 1;
+        Ok(false)
+    }
+
+    /// Loads the value of the global `name` into `dst`, defining it first
+    /// if it is a native procedure that has not been registered yet.
+    fn global(s: &mut State, o: Operands, name: value::Value) -> Result<bool, String> {
+        s.heap.stack.push(name);
+        try!(s.builtins.resolve_global(&mut s.heap));
+        try!(s.heap.load_global());
+        let x = s.heap.stack.pop().unwrap();
+        store(s, o, x)
+    }
+
+    fn load_global(s: &mut State, o: Operands) -> Result<bool, String> {
+        let name = s.heap.stack[o.fp + o.src].clone();
+        global(s, o, name)
+    }
+
+    fn load_constant_global(s: &mut State, o: Operands) -> Result<bool, String> {
+        let site = s.program_counter;
+        if let Some(x) = s.global_cache.lookup(site, &s.heap.symbol_table) {
+            return store(s, o, x)
+        }
+        let index = try!(try!(current_function(s)).constants.get(o.src));
+        let name = try!(closure::constant(&s.heap.stack[o.fp], index));
+        try!(global(s, o, name.clone()));
+        s.global_cache.insert(site, &name, &s.heap.symbol_table);
+        Ok(false)
+    }
+
+    fn store_global(s: &mut State, o: Operands) -> Result<bool, String> {
+        let index = try!(try!(current_function(s)).constants.get(o.src2));
+        let name = try!(closure::constant(&s.heap.stack[o.fp], index));
+        let x = s.heap.stack[o.fp + o.src].clone();
+        s.heap.stack.push(x);
+        s.heap.stack.push(name);
+        try!(s.heap.store_global());
+        s.program_counter += 1;
+        Ok(false)
     }
 }
 
+/// Returns `value` from the current frame, replacing the frame with it.
+/// Returns `true` if the frame was the one `interpret_bytecode` was entered
+/// with, whose caller is not Scheme code (and which leaves `value` on top of
+/// the stack), and `false` if execution continues in the caller, whose
+/// frame is restored.
+///
+/// Bytecode that is not part of a function has no frame, and leaves the
+/// stack alone when it returns.
+fn return_from(s: &mut State, depth: usize, value: value::Value) -> bool {
+    if s.function.is_some() {
+        s.heap.stack.truncate(s.frame_pointer);
+        s.heap.stack.push(value);
+    }
+    if s.control_stack.len() == depth {
+        return true
+    }
+    let record = s.control_stack.pop().unwrap();
+    s.heap.stack.resize(record.window_end, value::Value::unspecified());
+    s.program_counter = record.return_address;
+    s.frame_pointer = record.frame_pointer;
+    s.function = record.function;
+    s.base = record.function.map_or(0, |function| s.functions[function].entry);
+    false
+}
+
 
 
 
@@ -308,6 +1630,7 @@ pub fn interpret_bytecode(s: &mut State) -> Result<(), String> {
 mod tests {
     use value::Value;
     use std::cell::Cell;
+    use alloc;
     use bytecode::{Opcode, Bytecode};
     #[test]
     fn can_cons() {
@@ -329,4 +1652,295 @@ mod tests {
         });
         assert!(super::interpret_bytecode(&mut bco).is_ok());
     }
+
+    fn op(opcode: Opcode, src: u8, src2: u8, dst: u8) -> Bytecode {
+        Bytecode { opcode: opcode, src: src, src2: src2, dst: dst }
+    }
+
+    /// Counts slot 0 up from zero in steps of slot 1 while it is less than
+    /// slot 2.  If `fusable` is false, a no-op breaks up the back edge.
+    fn count_up(limit: usize, fusable: bool) -> super::State {
+        let mut s = counting_loop(limit, fusable);
+        super::interpret_bytecode(&mut s).unwrap();
+        s
+    }
+
+    /// The state that `count_up` runs, before it runs.
+    fn counting_loop(limit: usize, fusable: bool) -> super::State {
+        let mut s = super::new();
+        s.heap.stack.push(Value::new(0));
+        s.heap.stack.push(Value::new(1 << 2));
+        s.heap.stack.push(Value::new(limit << 2));
+        s.heap.stack.push(Value::new(::value::FALSE));
+        s.bytecode.push(op(Opcode::Add, 0, 1, 0));
+        if !fusable {
+            s.bytecode.push(op(Opcode::Set, 3, 0, 3));
+        }
+        let head = 0;
+        s.bytecode.push(op(Opcode::Less, 0, 2, 3));
+        s.bytecode.push(Bytecode::jump(Opcode::JumpIfTrue, head, 3));
+        s.bytecode.push(op(Opcode::Return, 0, 0, 0));
+        s
+    }
+
+    #[test]
+    fn fused_loops_match_unfused_ones() {
+        for &fusable in &[true, false] {
+            let s = count_up(1000, fusable);
+            assert_eq!(s.heap.stack[0].as_fixnum(), Ok(1000));
+            assert_eq!(s.heap.stack[3].get(), ::value::FALSE);
+        }
+    }
+
+    #[test]
+    fn fused_loops_use_fuel_for_every_instruction() {
+        // 100 trips around the loop run 300 instructions, and the return one.
+        for &fusable in &[true, false] {
+            let mut s = counting_loop(100, fusable);
+            let fuel = if fusable { 300 } else { 400 };
+            s.fuel = Some(fuel);
+            s.countdown = 0;
+            assert_eq!(super::interpret_bytecode(&mut s), Err("out of fuel".to_owned()));
+            let mut s = counting_loop(100, fusable);
+            s.fuel = Some(fuel + 1);
+            s.countdown = 0;
+            assert!(super::interpret_bytecode(&mut s).is_ok());
+        }
+    }
+
+    #[test]
+    fn fused_loops_fall_back_on_non_fixnums() {
+        let mut s = super::new();
+        s.heap.stack.push(Value::new(::value::TRUE));
+        s.heap.stack.push(Value::new(1 << 2));
+        s.bytecode.push(op(Opcode::Add, 0, 1, 0));
+        s.bytecode.push(op(Opcode::NumEqual, 0, 1, 0));
+        s.bytecode.push(Bytecode::jump(Opcode::JumpIfFalse, 0, 0));
+        assert!(super::fused_back_edge(&mut s) == false);
+        assert!(super::interpret_bytecode(&mut s).is_err());
+    }
+
+    #[test]
+    fn pairs_and_vectors_are_tested_and_measured() {
+        let mut s = super::new();
+        s.heap.stack.push(Value::new(4));
+        s.heap.stack.push(Value::new(8));
+        s.heap.alloc_vector(0, 2).unwrap();
+        s.heap.alloc_pair(0, 1).unwrap();
+        s.heap.stack.push(Value::nil());
+        s.bytecode.push(op(Opcode::IsArray, 2, 0, 4));
+        s.bytecode.push(op(Opcode::ArrayLen, 2, 0, 0));
+        s.bytecode.push(op(Opcode::IsPair, 3, 0, 3));
+        s.bytecode.push(op(Opcode::IsPair, 2, 0, 2));
+        s.bytecode.push(op(Opcode::ArrayLen, 1, 0, 1));
+        super::interpret_bytecode(&mut s).unwrap_err();
+        let slots: Vec<_> = s.heap.stack[..5].iter().map(|x| x.get()).collect();
+        assert_eq!(slots, vec![8, 8, ::value::FALSE, ::value::TRUE, ::value::TRUE]);
+    }
+
+    /// Queues a call of the procedure in slot 0 with the fixnums `args`.
+    fn enqueue(s: &mut super::State, args: &[usize]) {
+        let procedure = s.heap.stack[0].clone();
+        s.heap.stack.push(procedure);
+        for &x in args {
+            s.heap.stack.push(Value::new(x << 2));
+        }
+        super::enqueue(s, args.len()).unwrap();
+    }
+
+    #[test]
+    fn pending_calls_run_when_polled() {
+        let mut s = super::new();
+        let source = "(define total 0) (lambda (n) (set! total (+ total (* n n))))";
+        let procedure = super::run(&mut s, source).unwrap();
+        s.heap.stack.push(procedure);
+        for n in 1..4 {
+            enqueue(&mut s, &[n]);
+        }
+        s.heap.stack.push(Value::new(::value::TRUE));
+        assert!(super::enqueue(&mut s, 0).is_err());
+        s.heap.stack.pop();
+        assert_eq!(super::pending_work(&s), 3);
+        alloc::collect(&mut s.heap);
+        assert_eq!(super::poll_pending_work(&mut s), Ok(3));
+        assert_eq!(super::poll_pending_work(&mut s), Ok(0));
+        s.heap.intern("total");
+        s.heap.load_global().unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(14));
+        assert!(s.heap.persistent.is_empty());
+
+        // A failing call stops the poll.
+        enqueue(&mut s, &[]);
+        enqueue(&mut s, &[4]);
+        assert!(super::poll_pending_work(&mut s).is_err());
+        assert_eq!(s.heap.stack.len(), 1);
+        assert_eq!(super::poll_pending_work(&mut s), Ok(1));
+    }
+
+    #[test]
+    fn every_opcode_has_a_handler() {
+        let len = super::ops::DISPATCH.len();
+        assert!(Opcode::from_u8(len as u8 - 1).is_some());
+        assert!(Opcode::from_u8(len as u8).is_none());
+    }
+
+    #[test]
+    fn deep_recursion_is_an_error() {
+        let mut s = super::new();
+        s.max_frames = 1000;
+        let source = "(define (f n) (if (= n 0) 0 (+ 1 (f (- n 1))))) f";
+        let procedure = super::run(&mut s, source).unwrap();
+        s.heap.stack.push(procedure);
+        enqueue(&mut s, &[500]);
+        assert_eq!(super::poll_pending_work(&mut s), Ok(1));
+        enqueue(&mut s, &[5000]);
+        assert_eq!(super::poll_pending_work(&mut s),
+                   Err("maximum recursion depth exceeded".to_owned()));
+        // Native procedures that call back into Scheme recurse in Rust.
+        let source = "(define (g v) (vector-map! (lambda (x) (g v)) v)) (g (vector 1))";
+        assert_eq!(super::run(&mut s, source), Err("maximum recursion depth exceeded".to_owned()));
+        assert_eq!(s.nested_calls, 0);
+        s.heap.stack.truncate(1);
+        enqueue(&mut s, &[10]);
+        assert_eq!(super::poll_pending_work(&mut s), Ok(1));
+    }
+
+    #[test]
+    fn runaway_loops_can_be_stopped() {
+        use std::sync::atomic::Ordering;
+
+        let mut s = super::new();
+        let source = "(define (spin n) (if (< n 0) n (spin (+ n 1)))) spin";
+        let procedure = super::run(&mut s, source).unwrap();
+        s.heap.stack.push(procedure);
+        let procedure = s.heap.stack[0].clone();
+        s.heap.stack.push(procedure);
+        s.heap.stack.push(Value::new(0));
+        assert_eq!(super::run_with_fuel(&mut s, 1, 100_000), Err("out of fuel".to_owned()));
+        // The fuel only applies to that call.
+        s.heap.stack.truncate(1);
+        let source = "(define (count n) (if (= n 0) 'done (count (- n 1)))) (count 10000)";
+        let done = super::run(&mut s, source).unwrap();
+        s.heap.intern("done");
+        assert_eq!(done.get(), s.heap.stack.pop().unwrap().get());
+        s.interrupt.store(true, Ordering::SeqCst);
+        enqueue(&mut s, &[0]);
+        assert_eq!(super::poll_pending_work(&mut s), Err("interrupted".to_owned()));
+        // The flag is cleared once it has interrupted the code.
+        assert!(!s.interrupt.load(Ordering::SeqCst));
+        assert_eq!(s.heap.stack.len(), 1);
+    }
+
+    #[test]
+    fn calls_can_be_paused_and_resumed() {
+        use super::Outcome;
+
+        let mut s = super::new();
+        let source = "(define (sum n acc) (if (= n 0) acc (sum (- n 1) (+ acc n))))
+                      (lambda (n) (cons 'sum (sum n 0)))";
+        let procedure = super::run(&mut s, source).unwrap();
+        s.heap.stack.push(procedure);
+        let start = |s: &mut super::State, budget| {
+            let procedure = s.heap.stack[0].clone();
+            s.heap.stack.push(procedure);
+            s.heap.stack.push(Value::new(1000 << 2));
+            super::eval_with_budget(s, 1, budget).unwrap()
+        };
+        let mut outcome = start(&mut s, 500);
+        let mut slices = 1;
+        while let Outcome::Suspended(paused) = outcome {
+            assert_eq!(s.heap.stack.len(), 1);
+            // Anything can run in between.
+            super::run(&mut s, "(define garbage (cons 1 2))").unwrap();
+            alloc::collect(&mut s.heap);
+            outcome = super::resume(&mut s, paused, 500).unwrap();
+            slices += 1
+        }
+        assert!(slices > 2);
+        assert_eq!(s.heap.stack.pop().unwrap().cdr().unwrap().as_fixnum(), Ok(500500));
+        assert!(s.heap.persistent.is_empty());
+        match start(&mut s, 100) {
+            Outcome::Suspended(paused) => super::cancel(&mut s, paused),
+            Outcome::Done => panic!("the call should not fit in 100 instructions"),
+        }
+        assert!(s.heap.persistent.is_empty());
+        assert_eq!(s.heap.stack.len(), 1);
+    }
+
+    #[test]
+    fn cached_globals_see_redefinitions() {
+        let mut s = super::new();
+        let source = "(define x 1) (lambda () x)";
+        let procedure = super::run(&mut s, source).unwrap();
+        s.heap.stack.push(procedure);
+        let get = |s: &mut super::State| {
+            let procedure = s.heap.stack[0].clone();
+            s.heap.stack.push(procedure);
+            super::call(s, 0).unwrap();
+            s.heap.stack.pop().unwrap().as_fixnum()
+        };
+        assert_eq!(get(&mut s), Ok(1));
+        assert_eq!(get(&mut s), Ok(1));
+        s.heap.stack.push(Value::new(2 << 2));
+        s.heap.intern("x");
+        s.heap.store_global().unwrap();
+        assert_eq!(get(&mut s), Ok(2));
+        alloc::collect(&mut s.heap);
+        assert_eq!(get(&mut s), Ok(2));
+    }
+
+    #[test]
+    fn the_debugger_stops_steps_and_traces() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use debugger::Resume;
+
+        let mut s = super::new();
+        let source = "(define result #f) (define (f x y) (set! result (+ (* x y) 1))) f";
+        let procedure = super::run(&mut s, source).unwrap();
+        s.heap.stack.push(procedure);
+        let function = ::closure::function(&s.heap.stack[0]).unwrap();
+        let stops = Rc::new(RefCell::new(vec![]));
+        let traced = Rc::new(Cell::new(0));
+        {
+            let stops = stops.clone();
+            let traced = traced.clone();
+            let debugger = super::debugger(&mut s);
+            assert!(debugger.set_breakpoint(function, 0));
+            debugger.set_handler(Some(Box::new(move |s: &mut super::State, frame: &super::Frame| {
+                if frame.pc == 0 {
+                    let locals = super::locals(s, 0).unwrap();
+                    assert_eq!((locals[0].as_fixnum(), locals[1].as_fixnum()), (Ok(3), Ok(4)));
+                }
+                assert_eq!(super::frames(s)[0].function, frame.function);
+                stops.borrow_mut().push(frame.pc);
+                if stops.borrow().len() < 3 { Resume::Step } else { Resume::Continue }
+            })));
+            let trace = move |_: &super::State, _: &super::Frame, _: &Bytecode| {
+                traced.set(traced.get() + 1)
+            };
+            debugger.set_trace(Some(Box::new(trace)));
+            assert!(debugger.is_active());
+        }
+        enqueue(&mut s, &[3, 4]);
+        assert_eq!(super::poll_pending_work(&mut s), Ok(1));
+        s.heap.intern("result");
+        s.heap.load_global().unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(13));
+        assert_eq!(*stops.borrow(), vec![0, 1, 2]);
+        assert!(traced.get() > 3);
+        assert!(!super::debugger(&mut s).is_stepping());
+
+        // Aborting fails the call.
+        super::debugger(&mut s).set_handler(Some(Box::new(|_: &mut super::State, _: &super::Frame| {
+            Resume::Abort
+        })));
+        enqueue(&mut s, &[1, 2]);
+        assert_eq!(super::poll_pending_work(&mut s),
+                   Err("aborted by the debugger".to_owned()));
+        let debugger = super::debugger(&mut s);
+        assert!(debugger.clear_breakpoint(function, 0));
+        debugger.set_trace(None);
+        assert!(!debugger.is_active());
+    }
 }
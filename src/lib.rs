@@ -32,15 +32,32 @@ macro_rules! bug {
 mod value;
 mod state;
 mod arith;
+mod audit;
+mod binary;
 mod bytecode;
 mod string;
+mod flonum;
+mod ratio;
 mod alloc;
 mod symbol;
 mod interp;
+mod record;
+mod closure;
+mod compiler;
+mod debugger;
+mod profiler;
+mod equal;
+mod print;
+mod repl;
+mod prelude;
+mod shared;
+mod snapshot;
+mod library;
+mod builtins;
 mod read;
 mod api;
 pub use api::*;
-pub use bytecode::{Opcode, BCO};
+pub use bytecode::{opcode_table, Opcode, BCO};
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,319 @@
+//! Loading of Scheme libraries.
+//!
+//! A library is loaded by first loading all of the libraries it imports, and
+//! then instantiating it.  The `Loader` does the ordering and bookkeeping;
+//! finding a library's source, and instantiating it, is left to a
+//! `Resolver`.
+//!
+//! Imports must not be circular.  The loader walks the import graph with an
+//! explicit stack (so that deep import chains cannot overflow the Rust
+//! stack), and reports a cycle as soon as it finds one, along with the
+//! location of every import form that is part of it:
+//!
+//! ```text
+//! import cycle: (a) → (b) → (c) → (a)
+//!   (a) imports (b) at a.sld:2:11
+//!   (b) imports (c) at b.sld:3:11
+//!   (c) imports (a) at c.sld:2:11
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The name of a library, such as `["rusty", "base"]` for `(rusty base)`.
+pub type Name = Vec<String>;
+
+/// Formats a library name the way it is written in Scheme.
+struct DisplayName<'a>(&'a [String]);
+
+impl<'a> fmt::Display for DisplayName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({})", self.0.join(" "))
+    }
+}
+
+/// A location in a source file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The name of the file.
+    pub file: String,
+
+    /// The line number, starting at 1.
+    pub line: u32,
+
+    /// The column number, starting at 1.
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// One library imported by another.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Import {
+    /// The name of the imported library.
+    pub library: Name,
+
+    /// The location of the import form.
+    pub location: Location,
+}
+
+/// Finds and instantiates libraries for a `Loader`.
+pub trait Resolver {
+    /// The imports of the library `name`, in the order they appear.
+    fn imports(&mut self, name: &[String]) -> Result<Vec<Import>, String>;
+
+    /// Instantiates the library `name`.  Called exactly once per library,
+    /// after all of its imports have been instantiated.
+    fn instantiate(&mut self, name: &[String]) -> Result<(), String>;
+}
+
+/// One step of an import cycle: `library` imports the next library in the
+/// cycle at `location`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    /// The importing library.
+    pub library: Name,
+
+    /// The location of the import form.
+    pub location: Location,
+}
+
+/// An error loading a library.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The imports are circular.  The last link imports the library of the
+    /// first.
+    Cycle(Vec<Link>),
+
+    /// The resolver failed on `library`.
+    Library {
+        /// The library that could not be loaded.
+        library: Name,
+
+        /// The resolver's error message.
+        message: String,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Cycle(ref links) => {
+                try!(write!(f, "import cycle:"));
+                for (i, link) in links.iter().enumerate() {
+                    let arrow = if i == 0 { "" } else { " →" };
+                    try!(write!(f, "{} {}", arrow, DisplayName(&link.library)))
+                }
+                try!(write!(f, " → {}", DisplayName(&links[0].library)));
+                for (i, link) in links.iter().enumerate() {
+                    let next = &links[(i + 1) % links.len()].library;
+                    try!(write!(f,
+                                "\n  {} imports {} at {}",
+                                DisplayName(&link.library),
+                                DisplayName(next),
+                                link.location))
+                }
+                Ok(())
+            }
+            LoadError::Library { ref library, ref message } => {
+                write!(f, "error loading library {}: {}", DisplayName(library), message)
+            }
+        }
+    }
+}
+
+impl From<LoadError> for String {
+    fn from(err: LoadError) -> String {
+        err.to_string()
+    }
+}
+
+/// A library whose imports are being loaded.
+struct Frame {
+    name: Name,
+    imports: Vec<Import>,
+
+    /// The index of the next import to load.
+    next: usize,
+}
+
+/// Loads libraries in dependency order, each at most once.
+#[derive(Debug, Default)]
+pub struct Loader {
+    /// The libraries that have been instantiated.
+    loaded: HashSet<Name>,
+}
+
+impl Loader {
+    /// Has the library `name` been instantiated?
+    pub fn is_loaded(&self, name: &[String]) -> bool {
+        self.loaded.contains(name)
+    }
+
+    /// Loads the library `name` and everything it imports, unless it has
+    /// already been loaded.
+    ///
+    /// If loading fails, libraries that were instantiated before the
+    /// failure stay loaded.
+    pub fn load<R: Resolver>(&mut self, resolver: &mut R, name: &[String]) -> Result<(), LoadError> {
+        if self.is_loaded(name) {
+            return Ok(())
+        }
+        let mut stack = vec![try!(frame(resolver, name.to_vec()))];
+        // Maps each library on `stack` to its index.
+        let mut active: HashMap<Name, usize> = HashMap::new();
+        active.insert(name.to_vec(), 0);
+        while let Some(next) = next_import(&mut stack) {
+            let import = match next {
+                Some(import) => import,
+                None => {
+                    let done = stack.pop().unwrap().name;
+                    active.remove(&done);
+                    try!(resolver.instantiate(&done).map_err(|message| {
+                        LoadError::Library {
+                            library: done.clone(),
+                            message: message,
+                        }
+                    }));
+                    self.loaded.insert(done);
+                    continue;
+                }
+            };
+            if self.loaded.contains(&import.library) {
+                continue;
+            }
+            if let Some(&start) = active.get(&import.library) {
+                return Err(LoadError::Cycle(stack[start..]
+                                                .iter()
+                                                .map(|frame| {
+                                                    Link {
+                                                        library: frame.name.clone(),
+                                                        location: frame.imports[frame.next - 1]
+                                                                      .location
+                                                                      .clone(),
+                                                    }
+                                                })
+                                                .collect()));
+            }
+            active.insert(import.library.clone(), stack.len());
+            let frame = try!(frame(resolver, import.library));
+            stack.push(frame)
+        }
+        Ok(())
+    }
+}
+
+fn frame<R: Resolver>(resolver: &mut R, name: Name) -> Result<Frame, LoadError> {
+    match resolver.imports(&name) {
+        Ok(imports) => {
+            Ok(Frame {
+                name: name,
+                imports: imports,
+                next: 0,
+            })
+        }
+        Err(message) => {
+            Err(LoadError::Library {
+                library: name,
+                message: message,
+            })
+        }
+    }
+}
+
+/// Advances the innermost frame of `stack`.  Returns `None` if the stack is
+/// empty, `Some(None)` if the innermost library has no more imports, and
+/// `Some(Some(import))` otherwise.
+fn next_import(stack: &mut Vec<Frame>) -> Option<Option<Import>> {
+    stack.last_mut().map(|frame| {
+        frame.imports.get(frame.next).cloned().map(|import| {
+            frame.next += 1;
+            import
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    /// Libraries named by single letters, each in a file of its own.
+    struct Sources {
+        imports: HashMap<&'static str, &'static [&'static str]>,
+        instantiated: Vec<String>,
+    }
+
+    fn libraries(libraries: &[(&'static str, &'static [&'static str])]) -> Sources {
+        Sources {
+            imports: libraries.iter().cloned().collect(),
+            instantiated: vec![],
+        }
+    }
+
+    fn name(s: &str) -> Name {
+        vec![s.to_owned()]
+    }
+
+    impl Resolver for Sources {
+        fn imports(&mut self, name: &[String]) -> Result<Vec<Import>, String> {
+            let imports = try!(self.imports
+                                   .get(&*name[0])
+                                   .ok_or_else(|| "not found".to_owned()));
+            Ok(imports.iter()
+                      .enumerate()
+                      .map(|(i, library)| {
+                          Import {
+                              library: vec![library.to_string()],
+                              location: Location {
+                                  file: format!("{}.sld", name[0]),
+                                  line: i as u32 + 2,
+                                  column: 11,
+                              },
+                          }
+                      })
+                      .collect())
+        }
+
+        fn instantiate(&mut self, name: &[String]) -> Result<(), String> {
+            self.instantiated.push(name[0].clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn libraries_are_loaded_once_in_dependency_order() {
+        let mut sources = libraries(&[("a", &["b", "c"]), ("b", &["d"]), ("c", &["d"]), ("d", &[])]);
+        let mut loader = Loader::default();
+        loader.load(&mut sources, &name("a")).unwrap();
+        assert_eq!(sources.instantiated, ["d", "b", "c", "a"]);
+        loader.load(&mut sources, &name("c")).unwrap();
+        assert_eq!(sources.instantiated.len(), 4);
+        let err = loader.load(&mut sources, &name("e")).unwrap_err();
+        assert_eq!(err.to_string(), "error loading library (e): not found");
+    }
+
+    #[test]
+    fn cycles_are_reported_with_their_import_forms() {
+        let mut sources = libraries(&[("a", &["d", "b"]), ("b", &["c"]), ("c", &["a"]), ("d", &[])]);
+        let mut loader = Loader::default();
+        let err = loader.load(&mut sources, &name("a")).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "import cycle: (a) → (b) → (c) → (a)\n  \
+                    (a) imports (b) at a.sld:3:11\n  \
+                    (b) imports (c) at b.sld:2:11\n  \
+                    (c) imports (a) at c.sld:2:11");
+        assert_eq!(sources.instantiated, ["d"]);
+        assert!(loader.is_loaded(&name("d")) && !loader.is_loaded(&name("a")));
+
+        let mut sources = libraries(&[("a", &["a"])]);
+        match loader.load(&mut sources, &name("a")) {
+            Err(LoadError::Cycle(ref links)) if links.len() == 1 => (),
+            x => panic!("expected a cycle, got {:?}", x),
+        }
+    }
+}
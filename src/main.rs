@@ -0,0 +1,38 @@
+//! The `rusty_scheme` command.
+//!
+//! `rusty_scheme` (or `rusty_scheme repl`) runs a REPL on the standard
+//! input and output, and `rusty_scheme opcodes` prints the opcode reference
+//! (see `rusty_scheme::opcode_table`), for tools that document the
+//! instruction set.
+
+extern crate rusty_scheme;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use rusty_scheme::{ReplOptions, State};
+
+const USAGE: &'static str = "usage: rusty_scheme [repl | opcodes]";
+
+fn repl() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut state = State::new();
+    state.run_repl(stdin.lock(), io::stdout(), &ReplOptions::default())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match (args.get(0).map(|arg| &arg[..]), args.len()) {
+        (None, _) | (Some("repl"), 1) => repl(),
+        (Some("opcodes"), 1) => io::stdout().write_all(rusty_scheme::opcode_table().as_bytes()),
+        _ => {
+            let _ = writeln!(io::stderr(), "{}", USAGE);
+            process::exit(2)
+        }
+    };
+    if let Err(e) = res {
+        let _ = writeln!(io::stderr(), "rusty_scheme: {}", e);
+        process::exit(1)
+    }
+}
@@ -0,0 +1,73 @@
+//! The prelude, the part of the standard library written in Scheme.
+//!
+//! The source, `prelude.scm`, is compiled into the crate.  It is run the
+//! first time a program is loaded into a `State`, before that program, so
+//! all code but the prelude itself can use what it defines.  Creating a
+//! `State` stays cheap, as for the builtins.
+
+use std::io::Read;
+
+use compiler;
+use interp::{self, State};
+
+static SOURCE: &'static str = include_str!("prelude.scm");
+
+/// Runs the prelude in `s`.
+pub fn load(s: &mut State) -> Result<(), String> {
+    let forms = try!(compiler::read_all(&mut SOURCE.as_bytes().bytes().peekable())
+                         .map_err(|e| format!("prelude: read error: {:?}", e)));
+    let program = try!(compiler::compile(&forms).map_err(|e| format!("prelude: {}", e)));
+    try!(interp::load(s, &program));
+    try!(interp::call(s, 0).map_err(|e| format!("prelude: {}", e)));
+    s.heap.stack.pop();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use interp::{self, run};
+    use value;
+
+    #[test]
+    fn the_prelude_runs_before_user_code() {
+        let mut s = interp::new();
+        assert_eq!(run(&mut s, "(length (list 1 2 3))").unwrap().as_fixnum(), Ok(3));
+        assert!(s.heap.stack.is_empty());
+        assert_eq!(run(&mut s, "(list-ref (append '(1) '() (list 2 3)) 2)").unwrap().as_fixnum(),
+                   Ok(3));
+        let square = run(&mut s, "(car (cdr (map (lambda (x) (* x x)) '(1 2 3))))").unwrap();
+        assert_eq!(square.as_fixnum(), Ok(4));
+        assert_eq!(run(&mut s, "(cdr (car (map cons '(1 2) '(3))))").unwrap().as_fixnum(), Ok(3));
+        assert_eq!(run(&mut s, "(cdr (assq 'b '((a . 1) (b . 2))))").unwrap().as_fixnum(), Ok(2));
+        let found = run(&mut s, "(member '(1) '(2 (1)))").unwrap();
+        assert_eq!(found.car().unwrap().car().unwrap().as_fixnum(), Ok(1));
+        // The prelude only runs once, so user definitions replace its own.
+        run(&mut s, "(define (length xs) 'mine)").unwrap();
+        assert_eq!(run(&mut s, "(eq? (length '()) 'mine)").unwrap().get(), value::TRUE);
+    }
+
+    #[test]
+    fn association_lists_and_hash_tables() {
+        let mut s = interp::new();
+        run(&mut s, "(define options (list (cons 'a 1) (cons 'b 2)))
+                     (define copy (alist-copy options))
+                     (set! options (assq-set! (assq-set! options 'b 3) 'c 4))").unwrap();
+        assert_eq!(run(&mut s, "(cdr (assq 'b options))").unwrap().as_fixnum(), Ok(3));
+        assert_eq!(run(&mut s, "(cdr (car options))").unwrap().as_fixnum(), Ok(4));
+        assert_eq!(run(&mut s, "(cdr (assq 'b copy))").unwrap().as_fixnum(), Ok(2));
+        assert_eq!(run(&mut s, "(length (del-assq 'a options))").unwrap().as_fixnum(), Ok(2));
+        assert_eq!(run(&mut s, "(length options)").unwrap().as_fixnum(), Ok(3));
+        run(&mut s, "(define table (alist->hash-table '((\"x\" . 1) (y . 2) (\"x\" . 3))))
+                     (let loop ((i 0))
+                       (if (< i 100) (begin (hash-table-set! table i (* i i)) (loop (+ i 1)))))
+                     (hash-table-delete! table 'y)").unwrap();
+        assert_eq!(run(&mut s, "(hash-table-ref/default table \"x\" #f)").unwrap().as_fixnum(),
+                   Ok(1));
+        assert_eq!(run(&mut s, "(hash-table-ref/default table 99 #f)").unwrap().as_fixnum(),
+                   Ok(9801));
+        assert_eq!(run(&mut s, "(hash-table-ref/default table 'y #f)").unwrap().get(),
+                   value::FALSE);
+        assert_eq!(run(&mut s, "(hash-table-count table)").unwrap().as_fixnum(), Ok(101));
+        assert_eq!(run(&mut s, "(length (hash-table->alist table))").unwrap().as_fixnum(), Ok(101));
+    }
+}
@@ -0,0 +1,204 @@
+;;; The prelude: the part of the standard library written in Scheme.
+;;;
+;;; This is compiled into the interpreter, and runs before any other code
+;;; in a State.  It can use the builtins and the core forms, but no macros
+;;; that are not defined here, and no procedure defined after it.
+
+(define (list . xs) xs)
+
+(define (length xs)
+  (let loop ((xs xs) (n 0))
+    (if (null? xs) n (loop (cdr xs) (+ n 1)))))
+
+(define (reverse xs)
+  (let loop ((xs xs) (acc '()))
+    (if (null? xs) acc (loop (cdr xs) (cons (car xs) acc)))))
+
+(define (append . lists)
+  (letrec ((append2 (lambda (xs ys)
+                      (if (null? xs) ys (cons (car xs) (append2 (cdr xs) ys))))))
+    (if (null? lists)
+        '()
+        (let loop ((lists (cdr (reverse lists))) (acc (car (reverse lists))))
+          (if (null? lists) acc (loop (cdr lists) (append2 (car lists) acc)))))))
+
+(define (list-tail xs k)
+  (if (= k 0) xs (list-tail (cdr xs) (- k 1))))
+
+(define (list-ref xs k)
+  (car (list-tail xs k)))
+
+(define (list-copy xs)
+  (if (pair? xs) (cons (car xs) (list-copy (cdr xs))) xs))
+
+(define (memq x xs)
+  (if (null? xs) #f (if (eq? x (car xs)) xs (memq x (cdr xs)))))
+
+(define (memv x xs)
+  (if (null? xs) #f (if (eqv? x (car xs)) xs (memv x (cdr xs)))))
+
+(define (member x xs)
+  (if (null? xs) #f (if (equal? x (car xs)) xs (member x (cdr xs)))))
+
+(define (assq x alist)
+  (if (null? alist) #f (if (eq? x (car (car alist))) (car alist) (assq x (cdr alist)))))
+
+(define (assv x alist)
+  (if (null? alist) #f (if (eqv? x (car (car alist))) (car alist) (assv x (cdr alist)))))
+
+(define (assoc x alist)
+  (if (null? alist) #f (if (equal? x (car (car alist))) (car alist) (assoc x (cdr alist)))))
+
+;; With several lists, `map` and `for-each` stop at the end of the
+;; shortest.
+(define (map f xs . rest)
+  (if (null? rest)
+      (let loop ((xs xs) (acc '()))
+        (if (null? xs) (reverse acc) (loop (cdr xs) (cons (f (car xs)) acc))))
+      (let loop ((lists (cons xs rest)) (acc '()))
+        (if (let any ((ls lists)) (if (null? ls) #f (if (null? (car ls)) #t (any (cdr ls)))))
+            (reverse acc)
+            (loop (map cdr lists) (cons (apply f (map car lists)) acc))))))
+
+(define (for-each f xs . rest)
+  (if (null? rest)
+      (let loop ((xs xs))
+        (if (pair? xs) (begin (f (car xs)) (loop (cdr xs)))))
+      (let loop ((lists (cons xs rest)))
+        (if (let any ((ls lists)) (if (null? ls) #f (if (null? (car ls)) #t (any (cdr ls)))))
+            (if #f #f)
+            (begin (apply f (map car lists)) (loop (map cdr lists)))))))
+
+;; `alist-copy` and `del-assq` return new lists.  `assq-set!` changes the
+;; pair of `key` if there is one, and otherwise returns `alist` with a new
+;; pair in front, so its result must be used, as in
+;; `(set! options (assq-set! options 'verbose #t))`.
+(define (alist-copy alist)
+  (map (lambda (pair) (cons (car pair) (cdr pair))) alist))
+
+(define (del-assq key alist)
+  (let loop ((alist alist) (acc '()))
+    (cond ((null? alist) (reverse acc))
+          ((eq? key (car (car alist))) (loop (cdr alist) acc))
+          (else (loop (cdr alist) (cons (car alist) acc))))))
+
+(define (assq-set! alist key value)
+  (let ((pair (assq key alist)))
+    (if pair
+        (begin (set-cdr! pair value) alist)
+        (cons (cons key value) alist))))
+
+;; Hash tables, whose keys are compared with `equal?`.  A hash table is a
+;; record of the number of its entries and a vector of buckets, each an
+;; association list, which doubles in size when there are more than twice
+;; as many entries as buckets.
+(define %hash-table (make-record-type 'hash-table 'count 'buckets))
+
+(define (make-hash-table) (make-record %hash-table 0 (make-vector 8 '())))
+
+(define (hash-table-count table) (record-ref table 'count))
+
+(define (%hash-table-index table key)
+  (equal-hash key (vector-length (record-ref table 'buckets))))
+
+(define (hash-table-ref/default table key default)
+  (let ((pair (assoc key (vector-ref (record-ref table 'buckets) (%hash-table-index table key)))))
+    (if pair (cdr pair) default)))
+
+(define (hash-table-set! table key value)
+  (let* ((buckets (record-ref table 'buckets))
+         (i (%hash-table-index table key))
+         (pair (assoc key (vector-ref buckets i))))
+    (if pair
+        (set-cdr! pair value)
+        (begin
+          (vector-set! buckets i (cons (cons key value) (vector-ref buckets i)))
+          (record-set! table 'count (+ (record-ref table 'count) 1))
+          (if (< (* 2 (vector-length buckets)) (record-ref table 'count))
+              (let ((entries (hash-table->alist table)))
+                (record-set! table 'buckets (make-vector (* 2 (vector-length buckets)) '()))
+                (record-set! table 'count 0)
+                (for-each (lambda (pair) (hash-table-set! table (car pair) (cdr pair)))
+                          entries)))))))
+
+(define (hash-table-delete! table key)
+  (let* ((buckets (record-ref table 'buckets))
+         (i (%hash-table-index table key)))
+    (if (assoc key (vector-ref buckets i))
+        (begin
+          (vector-set! buckets i (let loop ((bucket (vector-ref buckets i)))
+                                   (if (equal? key (car (car bucket)))
+                                       (cdr bucket)
+                                       (cons (car bucket) (loop (cdr bucket))))))
+          (record-set! table 'count (- (record-ref table 'count) 1))))))
+
+;; The entries of `table` as a new association list, in no particular
+;; order.
+(define (hash-table->alist table)
+  (let ((buckets (record-ref table 'buckets)))
+    (let loop ((i 0) (acc '()))
+      (if (= i (vector-length buckets))
+          acc
+          (loop (+ i 1) (append (alist-copy (vector-ref buckets i)) acc))))))
+
+;; A key that is in `alist` more than once has its first value, as `assoc`
+;; finds it.
+(define (alist->hash-table alist)
+  (let ((table (make-hash-table)))
+    (for-each (lambda (pair) (hash-table-set! table (car pair) (cdr pair))) (reverse alist))
+    table))
+
+(define (zero? n) (= n 0))
+(define (positive? n) (< 0 n))
+(define (negative? n) (< n 0))
+
+;; Multiple values.  Several values are a record of the list of them, but
+;; one value is itself, so that returning it costs nothing.  A continuation
+;; that is not a consumer of `call-with-values` receives the record.
+(define %values (make-record-type 'values 'list))
+
+(define (values . xs)
+  (if (and (pair? xs) (null? (cdr xs))) (car xs) (make-record %values xs)))
+
+(define (call-with-values producer consumer)
+  (let ((x (producer)))
+    (if (and (record? x) (eq? (record-type x) %values))
+        (apply consumer (record-ref x 'list))
+        (consumer x))))
+
+(define (floor/ n d) (values (floor-quotient n d) (floor-remainder n d)))
+(define (truncate/ n d) (values (truncate-quotient n d) (truncate-remainder n d)))
+
+;; Random numbers, from a default source (see `builtins::random`).  It is
+;; made on first use, since a State holding a source cannot be saved.
+(define %default-random-source #f)
+(define (%random-source)
+  (if (not %default-random-source)
+      (set! %default-random-source (make-random-source)))
+  %default-random-source)
+(define (random-integer n) (random-source-integer (%random-source) n))
+(define (random-real) (random-source-real (%random-source)))
+
+;; `(time expr)` calls `%time` with a thunk of `expr`, and `expr` itself
+;; (see `compiler::syntax` and `builtins::time`).
+(define (%time thunk expr)
+  (let* ((bytes (%bytes-allocated))
+         (start (current-jiffy))
+         (result (thunk))
+         (jiffies (- (current-jiffy) start)))
+    (%report-time expr jiffies (- (%bytes-allocated) bytes))
+    result))
+
+;; How much of a large object the printer prints: at most `*print-length*`
+;; elements of each list, vector, and record, nested at most
+;; `*print-depth*` deep.  `#f` means no limit.
+(define *print-length* #f)
+(define *print-depth* #f)
+
+;; A parameter is a procedure whose only free variable is its cell (see
+;; `builtins::parameters`).
+(define (make-parameter value . converter)
+  (let ((cell (if (null? converter)
+                  (%parameter-cell value #f)
+                  (%parameter-cell ((car converter) value) (car converter)))))
+    (lambda () (%parameter-value cell))))
@@ -1 +1,582 @@
-fn print(
+//! The printer, behind `write` and `display`.
+//!
+//! Records whose type has a printer (`define-record-printer`) are printed
+//! by calling it with the record.  If it returns a string, the string is
+//! printed as is; otherwise what it returns is printed in place of the
+//! record.  Other records are printed in their external syntax, `#[name
+//! field ...]`, if their type has one (see `record`), and as `#<name>`
+//! otherwise.
+//!
+//! Printers are Scheme code, which can allocate, so the GC may run in the
+//! middle of printing.  The printer therefore never holds on to a `Value`
+//! across a call: everything it is in the middle of printing is on the
+//! stack, and is referred to by its index there.
+//!
+//! The printer terminates on any object, even one that printers make
+//! infinite.  An object that contains itself is printed as `...` where it
+//! recurs.  It keeps track of what it is in the middle of printing in a
+//! work list, not on the Rust stack, so it prints objects of any depth.
+//!
+//! How much of a large object is printed can be limited further, by setting
+//! the globals `*print-length*` and `*print-depth*` to fixnums (they are
+//! `#f`, for no limit, to begin with).  Only the first `*print-length*`
+//! elements of each list, vector, and record are printed, followed by
+//! `...`, and objects nested more than `*print-depth*` deep are printed as
+//! `...`.  This keeps the REPL usable after evaluating a huge list.
+//!
+//! `write` prints a symbol that would not read back as itself, such as one
+//! made by `string->symbol` with a space in its name, between bars, like
+//! `|foo bar|` (see `write_symbol`).
+
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+
+use arith::{self, Parsed};
+use equal;
+use flonum;
+use interp::{self, State};
+use ratio;
+use record;
+use string;
+use value::{self, Value, Tags};
+use builtins::Native;
+
+/// Prints the value on top of the stack, as `write` does if `display` is
+/// false and as `display` does otherwise.  Leaves the stack unchanged.
+pub fn print(s: &mut State, display: bool) -> Result<String, String> {
+    let len = s.heap.stack.len();
+    if len == 0 {
+        return Err("Attempt to print from empty stack".to_owned())
+    }
+    let mut printer = Printer {
+        display: display,
+        out: String::new(),
+        ancestors: vec![],
+        seen: HashSet::new(),
+        frames: vec![],
+        length: limit(s, "*print-length*"),
+        depth: limit(s, "*print-depth*"),
+    };
+    let res = printer.value(s, len - 1);
+    s.heap.stack.truncate(len);
+    res.map(|()| printer.out)
+}
+
+/// The value of the global `name`, if it is a fixnum.
+fn limit(s: &mut State, name: &str) -> Option<usize> {
+    s.heap.intern(name);
+    match s.heap.stack.pop().unwrap().kind() {
+        value::Kind::Symbol(ptr) => unsafe { (*(*ptr).contents.get()).as_fixnum().ok() },
+        _ => None,
+    }
+}
+
+fn symbol_name(x: &Value) -> &str {
+    match x.kind() {
+        value::Kind::Symbol(ptr) => unsafe { (*ptr).name() },
+        _ => "#<non-symbol>",
+    }
+}
+
+/// Does the symbol `name` have to be written between bars to read back as
+/// itself?  It does if it is empty, looks like a number or a dot, starts
+/// with `#` (other than `#!key` and the like), or contains whitespace, a
+/// delimiter, or a character that the reader treats specially.
+fn needs_bars(name: &str) -> bool {
+    name.is_empty() || name == "." || arith::parse(name, 10) != Parsed::Invalid ||
+    name.starts_with('#') && !(name.starts_with("#!") && name.len() > 2) ||
+    name.chars().any(|c| {
+        c.is_whitespace() || c.is_control() || "()[]{}\"';`,|\\".contains(c)
+    })
+}
+
+/// The characters that `write` prints by name, after `#\`, as the reader
+/// reads them.
+pub const CHAR_NAMES: [(char, &'static str); 9] = [('\x07', "alarm"),
+                                                   ('\x08', "backspace"),
+                                                   ('\x7f', "delete"),
+                                                   ('\x1b', "escape"),
+                                                   ('\n', "newline"),
+                                                   ('\0', "null"),
+                                                   ('\r', "return"),
+                                                   (' ', "space"),
+                                                   ('\t', "tab")];
+
+/// Writes the character `c` to `out` as `write` does: as `#\` followed by
+/// its name, if it has one, by `x` and its code point in hexadecimal, if it
+/// is another control or whitespace character, and by itself otherwise.
+pub fn write_char<W: Write>(out: &mut W, c: char) -> fmt::Result {
+    try!(out.write_str("#\\"));
+    match CHAR_NAMES.iter().find(|&&(named, _)| named == c) {
+        Some(&(_, name)) => out.write_str(name),
+        None if c.is_control() || c.is_whitespace() => write!(out, "x{:x}", c as u32),
+        None => out.write_char(c),
+    }
+}
+
+/// Writes the symbol `name` to `out` as `write` does, between bars if it
+/// needs them, with `|` and `\` escaped by a backslash.
+pub fn write_symbol<W: Write>(out: &mut W, name: &str) -> fmt::Result {
+    if !needs_bars(name) {
+        return out.write_str(name)
+    }
+    try!(out.write_char('|'));
+    for c in name.chars() {
+        try!(match c {
+            '|' => out.write_str("\\|"),
+            '\\' => out.write_str("\\\\"),
+            '\n' => out.write_str("\\n"),
+            '\t' => out.write_str("\\t"),
+            c if c.is_control() => write!(out, "\\x{:x};", c as u32),
+            c => out.write_char(c),
+        })
+    }
+    out.write_char('|')
+}
+
+struct Printer {
+    display: bool,
+    out: String,
+
+    /// The stack indices of the objects being printed, outermost first.
+    ancestors: Vec<usize>,
+
+    /// The addresses of the `ancestors`, so that an object that contains
+    /// itself is found without walking them.  Objects only move when a
+    /// record printer runs, after which it is rebuilt.
+    seen: HashSet<usize>,
+
+    /// The lists, vectors, and records being printed, innermost last.
+    frames: Vec<Frame>,
+
+    /// The number of elements of each list, vector, and record to print.
+    length: Option<usize>,
+
+    /// The deepest nesting to print.
+    depth: Option<usize>,
+}
+
+/// A list, vector, or record being printed, whose elements are printed in
+/// turn.  The printer keeps these in a work list rather than recursing,
+/// so however deeply an object is nested, printing it cannot overflow the
+/// Rust stack.
+struct Frame {
+    /// The stack index of the object.
+    index: usize,
+
+    /// The length of the stack before the frame's slots were pushed.
+    base: usize,
+
+    /// The number of elements printed so far.
+    printed: usize,
+
+    kind: FrameKind,
+}
+
+enum FrameKind {
+    /// A list.  Its slots are the pair that the current one is compared
+    /// with, the current pair, and its car.  A list whose cdrs form a cycle
+    /// is cut short with `...`; the cycle is found with Brent's algorithm.
+    /// `tail` is set once the cdr of an improper list is being printed.
+    List { power: usize, steps: usize, tail: bool },
+
+    /// A vector.  Its slot is the element being printed.
+    Vector,
+
+    /// The fields of a record, in its external syntax.  Its slot is the
+    /// field being printed.
+    Fields,
+
+    /// What a record printer returned in place of the record, which is in
+    /// its slot.
+    Printed,
+}
+
+impl Printer {
+    /// Prints the value at `index` on the stack.
+    fn value(&mut self, s: &mut State, index: usize) -> Result<(), String> {
+        let mut next = Some(index);
+        loop {
+            if let Some(index) = next.take() {
+                next = try!(self.open(s, index));
+                if next.is_some() {
+                    continue
+                }
+            }
+            match self.frames.pop() {
+                Some(frame) => next = self.step(s, frame),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Prints the value at `index`, if it is an atom, or starts printing it
+    /// otherwise.  Returns the index of a value to print next, if any.
+    fn open(&mut self, s: &mut State, index: usize) -> Result<Option<usize>, String> {
+        let x = s.heap.stack[index].clone();
+        let special = match x.get() {
+            value::FALSE => "#f",
+            value::TRUE => "#t",
+            value::NIL => "()",
+            value::EOF => "#<eof>",
+            value::UNSPECIFIED => "#<unspecified>",
+            _ => "",
+        };
+        if !special.is_empty() {
+            self.out.push_str(special);
+            return Ok(None)
+        }
+        if x.fixnump() {
+            let _ = write!(self.out, "{}", x.get() as isize >> 2);
+            return Ok(None)
+        }
+        if let Some(x) = flonum::as_f64(&x) {
+            self.out.push_str(&flonum::format(x));
+            return Ok(None)
+        }
+        if let Some((numerator, denominator)) = ratio::as_ratio(&x) {
+            self.out.push_str(&ratio::format(numerator, denominator, 10));
+            return Ok(None)
+        }
+        if let Some(string) = string::as_str(&x) {
+            self.string(string);
+            return Ok(None)
+        }
+        if let Some(c) = x.as_char() {
+            if self.display {
+                self.out.push(c)
+            } else {
+                let _ = write_char(&mut self.out, c);
+            }
+            return Ok(None)
+        }
+        if self.depth.map_or(false, |depth| self.ancestors.len() >= depth) ||
+           self.seen.contains(&x.get()) {
+            self.out.push_str("...");
+            return Ok(None)
+        }
+        match x.tag() {
+            Tags::Symbol if self.display => self.out.push_str(symbol_name(&x)),
+            Tags::Symbol => {
+                let _ = write_symbol(&mut self.out, symbol_name(&x));
+            }
+            Tags::Pair => {
+                self.out.push('(');
+                let kind = FrameKind::List { power: 1, steps: 0, tail: false };
+                self.push_frame(s, index, kind, 3)
+            }
+            Tags::Function => self.out.push_str("#<procedure>"),
+            Tags::RustFunc => {
+                let native = unsafe { &*(x.as_ptr() as *const Native) };
+                let _ = write!(self.out, "#<procedure {}>", native.name);
+            }
+            Tags::Vector if !x.immediatep() => {
+                if record::is_record(&x) {
+                    return self.record(s, index)
+                } else if record::is_record_type(&x) {
+                    let name = try!(record::record_type_name(&x));
+                    let _ = write!(self.out, "#<record-type {}>", symbol_name(&name));
+                } else if equal::vector_elements(&x).is_some() {
+                    self.out.push_str("#(");
+                    self.push_frame(s, index, FrameKind::Vector, 1)
+                } else {
+                    self.out.push_str("#<object>")
+                }
+            }
+            _ => self.out.push_str("#<object>"),
+        }
+        Ok(None)
+    }
+
+    /// Starts printing the elements of the object at `index`, with `slots`
+    /// slots on the stack, each starting out as the object.
+    fn push_frame(&mut self, s: &mut State, index: usize, kind: FrameKind, slots: usize) {
+        self.push_ancestor(s, index);
+        let base = s.heap.stack.len();
+        let x = s.heap.stack[index].clone();
+        s.heap.stack.extend(::std::iter::repeat(x).take(slots));
+        self.frames.push(Frame {
+            index: index,
+            base: base,
+            printed: 0,
+            kind: kind,
+        })
+    }
+
+    fn push_ancestor(&mut self, s: &State, index: usize) {
+        self.ancestors.push(index);
+        self.seen.insert(s.heap.stack[index].get());
+    }
+
+    /// Moves on to the next element of `frame`.  Returns the index of the
+    /// element to print, or `None` if there are no more, after finishing
+    /// the frame.
+    fn step(&mut self, s: &mut State, mut frame: Frame) -> Option<usize> {
+        let length = self.length;
+        match frame.kind {
+            FrameKind::List { ref mut power, ref mut steps, tail } => {
+                let (saved, current, car) = (frame.base, frame.base + 1, frame.base + 2);
+                if tail {
+                    return self.close(s, frame)
+                }
+                if frame.printed > 0 {
+                    let cdr = s.heap.stack[current].cdr().unwrap();
+                    s.heap.stack[current] = cdr.clone();
+                    match cdr.tag() {
+                        Tags::Pair if cdr.get() == s.heap.stack[saved].get() => {
+                            self.out.push_str(" ...");
+                            return self.close(s, frame)
+                        }
+                        Tags::Pair => {
+                            self.out.push(' ');
+                            *steps += 1;
+                            if *steps == *power {
+                                s.heap.stack[saved] = cdr;
+                                *power *= 2;
+                                *steps = 0
+                            }
+                        }
+                        _ if cdr.is_nil() => return self.close(s, frame),
+                        _ => {
+                            self.out.push_str(" . ");
+                            frame.kind = FrameKind::List {
+                                power: *power,
+                                steps: *steps,
+                                tail: true,
+                            };
+                            self.frames.push(frame);
+                            return Some(current)
+                        }
+                    }
+                }
+                if Some(frame.printed) == length {
+                    self.out.push_str("...");
+                    return self.close(s, frame)
+                }
+                s.heap.stack[car] = s.heap.stack[current].car().unwrap();
+            }
+            FrameKind::Vector | FrameKind::Fields => {
+                // The object may have moved since the last element was
+                // printed.
+                let element = {
+                    let x = &s.heap.stack[frame.index];
+                    let elements = match frame.kind {
+                        FrameKind::Vector => equal::vector_elements(x),
+                        _ => record::fields(x).ok(),
+                    };
+                    elements.and_then(|elements| elements.get(frame.printed).cloned())
+                };
+                let element = match element {
+                    Some(element) => element,
+                    None => return self.close(s, frame),
+                };
+                if frame.printed > 0 || frame.kind.is_fields() {
+                    self.out.push(' ')
+                }
+                if Some(frame.printed) == length {
+                    self.out.push_str("...");
+                    return self.close(s, frame)
+                }
+                s.heap.stack[frame.base] = element;
+            }
+            FrameKind::Printed => return self.close(s, frame),
+        }
+        frame.printed += 1;
+        let slot = match frame.kind {
+            FrameKind::List { .. } => frame.base + 2,
+            _ => frame.base,
+        };
+        self.frames.push(frame);
+        Some(slot)
+    }
+
+    /// Finishes printing `frame`, and pops its slots.
+    fn close(&mut self, s: &mut State, frame: Frame) -> Option<usize> {
+        s.heap.stack.truncate(frame.base);
+        match frame.kind {
+            FrameKind::List { .. } | FrameKind::Vector => self.out.push(')'),
+            FrameKind::Fields => self.out.push(']'),
+            FrameKind::Printed => {}
+        }
+        let index = self.ancestors.pop().unwrap();
+        self.seen.remove(&s.heap.stack[index].get());
+        None
+    }
+
+    fn string(&mut self, string: &str) {
+        if self.display {
+            return self.out.push_str(string)
+        }
+        self.out.push('"');
+        for c in string.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\t' => self.out.push_str("\\t"),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"')
+    }
+
+    /// Starts printing the record at `index`, with its type's printer if it
+    /// has one.  Returns the index of what the printer returned, if that is
+    /// to be printed next.
+    fn record(&mut self, s: &mut State, index: usize) -> Result<Option<usize>, String> {
+        let x = s.heap.stack[index].clone();
+        let printer = match s.printers.get(&s.heap, &x) {
+            Some(printer) => printer,
+            None => {
+                let rtd = try!(record::record_type(&x));
+                let name = try!(record::record_type_name(&rtd));
+                if s.readable.contains(&s.heap, &rtd) {
+                    let _ = write!(self.out, "#[{}", symbol_name(&name));
+                    self.push_frame(s, index, FrameKind::Fields, 1)
+                } else {
+                    let _ = write!(self.out, "#<{}>", symbol_name(&name));
+                }
+                return Ok(None)
+            }
+        };
+        s.heap.stack.push(printer);
+        s.heap.stack.push(x);
+        try!(interp::call(s, 1));
+        // The printer may have moved the objects being printed.
+        self.seen = self.ancestors.iter().map(|&i| s.heap.stack[i].get()).collect();
+        let top = s.heap.stack.len() - 1;
+        if let Some(string) = string::as_str(&s.heap.stack[top]) {
+            self.out.push_str(string);
+            s.heap.stack.pop();
+            return Ok(None)
+        }
+        // Anything the printer returns that contains the record prints the
+        // record as `...`.
+        self.push_ancestor(s, index);
+        self.frames.push(Frame {
+            index: index,
+            base: top,
+            printed: 0,
+            kind: FrameKind::Printed,
+        });
+        Ok(Some(top))
+    }
+}
+
+impl FrameKind {
+    fn is_fields(&self) -> bool {
+        match *self {
+            FrameKind::Fields => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use interp::{self, State};
+    use super::*;
+
+    /// Runs `source`, and pushes its value to be printed.
+    fn push(s: &mut State, source: &str) {
+        let value = interp::run(s, source).unwrap();
+        s.heap.stack.push(value)
+    }
+
+    fn write(source: &str) -> String {
+        let mut s = interp::new();
+        push(&mut s, source);
+        print(&mut s, false).unwrap()
+    }
+
+    #[test]
+    fn data_print_as_they_are_read() {
+        assert_eq!(write("'(1 (2 #t) #(a \"b\\n\") . c)"), "(1 (2 #t) #(a \"b\\n\") . c)");
+        assert_eq!(write("'()"), "()");
+        assert_eq!(write("(- 0 3)"), "-3");
+        assert_eq!(write("'(1.5 -0.0 #i3 1e100)"), "(1.5 -0.0 3.0 1e100)");
+        assert_eq!(write("car"), "#<procedure car>");
+        assert_eq!(write("'(#\\a #\\space #\\x7 #\\x3000)"), "(#\\a #\\space #\\alarm #\\x3000)");
+        let mut s = interp::new();
+        push(&mut s, "\"a \\\"b\\\"\"");
+        assert_eq!(print(&mut s, true).unwrap(), "a \"b\"");
+        push(&mut s, "'(#\\a #\\space)");
+        assert_eq!(print(&mut s, true).unwrap(), "(a  )");
+    }
+
+    #[test]
+    fn symbols_that_would_not_read_back_are_written_between_bars() {
+        assert_eq!(write("'(|foo bar| |a\\|b| |12| |#t| || #!key a->b)"),
+                   "(|foo bar| |a\\|b| |12| |#t| || #!key a->b)");
+        assert_eq!(write("'|a\\x41;\\nb\\\\|"), "|aA\\nb\\\\|");
+        let mut s = interp::new();
+        push(&mut s, "'|foo bar|");
+        assert_eq!(print(&mut s, true).unwrap(), "foo bar");
+    }
+
+    #[test]
+    fn cycles_are_cut_short() {
+        let mut s = interp::new();
+        push(&mut s, "(cons 1 (cons 2 '()))");
+        let list = s.heap.stack.last().unwrap().clone();
+        list.cdr().unwrap().set_cdr(list.clone()).unwrap();
+        assert_eq!(print(&mut s, false).unwrap(), "(1 2 1 ...)");
+        list.set_car(list.clone()).unwrap();
+        assert_eq!(print(&mut s, false), Ok("(... 2 ... ...)".to_owned()));
+        assert_eq!(s.heap.stack.len(), 1);
+    }
+
+    #[test]
+    fn print_length_and_depth_limit_what_is_printed() {
+        let mut s = interp::new();
+        push(&mut s, "(set! *print-length* 2) '(1 2 3)");
+        assert_eq!(print(&mut s, false).unwrap(), "(1 2 ...)");
+        push(&mut s, "'#(1 2 3)");
+        assert_eq!(print(&mut s, false).unwrap(), "#(1 2 ...)");
+        push(&mut s, "'(1 2)");
+        assert_eq!(print(&mut s, false).unwrap(), "(1 2)");
+        push(&mut s, "(set! *print-length* #f) (set! *print-depth* 2) '(1 (2 (3)) #(#(4)))");
+        assert_eq!(print(&mut s, false).unwrap(), "(1 (2 ...) #(...))");
+        push(&mut s, "(set! *print-depth* 0) '(1)");
+        assert_eq!(print(&mut s, true).unwrap(), "...");
+        push(&mut s, "\"a\"");
+        assert_eq!(print(&mut s, true).unwrap(), "a");
+    }
+
+    #[test]
+    fn deep_nesting_is_printed_in_full() {
+        let mut s = interp::new();
+        push(&mut s, "(let loop ((i 0) (x 1))
+                        (if (< i 10000) (loop (+ i 1) (vector (cons x '()))) x))");
+        let out = print(&mut s, false).unwrap();
+        assert_eq!(out, format!("{}1{}", "#((".repeat(10000), "))".repeat(10000)));
+        push(&mut s, "(set! *print-depth* 100)
+                      (let loop ((i 0) (x 1)) (if (< i 80) (loop (+ i 1) (cons x '())) x))");
+        let out = print(&mut s, false).unwrap();
+        assert_eq!(out, format!("{}1{}", "(".repeat(80), ")".repeat(80)));
+    }
+
+    #[test]
+    fn records_print_with_their_printers() {
+        let mut s = interp::new();
+        push(&mut s, "(define point (make-record-type 'point 'x 'y))
+                      (make-record point 1 2)");
+        assert_eq!(print(&mut s, false).unwrap(), "#<point>");
+        push(&mut s, "(define-record-printer point
+                        (lambda (p) (cons 'point (cons (record-ref p 'x) '()))))
+                      (make-record point 3 4)");
+        assert_eq!(print(&mut s, false).unwrap(), "(point 3)");
+        push(&mut s, "(define-record-printer point (lambda (p) \"#<pt>\"))
+                      (cons (make-record point 3 4) '())");
+        assert_eq!(print(&mut s, false).unwrap(), "(#<pt>)");
+        // A printer that returns the record, or something containing it,
+        // does not recurse forever.
+        push(&mut s, "(define-record-printer point (lambda (p) (cons p '())))
+                      (make-record point 5 6)");
+        assert_eq!(print(&mut s, false).unwrap(), "(...)");
+        push(&mut s, "(define-record-printer point (lambda (p) (car p)))
+                      (make-record point 5 6)");
+        assert!(print(&mut s, false).is_err());
+        assert_eq!(s.heap.stack.len(), 5);
+    }
+}
@@ -0,0 +1,169 @@
+//! The profiler: instructions run and memory allocated, per function.
+//!
+//! While profiling is on (see `interp::set_profiling`), the dispatch loop
+//! tells the `Profiler` about every instruction before it runs it, as it
+//! does the debugger.  The instruction is counted against the function
+//! running it (a BCO, as an index into `State::functions`), and the bytes
+//! allocated since the instruction before it, by that instruction or the
+//! native procedures that it called, against the function of that one.
+//!
+//! `interp::profile` takes the counts so far, as a `Profile` of the
+//! functions from the most costly down, which prints as a table.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// What a function has cost, so far.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cost {
+    /// The instructions run in the function.
+    pub instructions: u64,
+
+    /// The bytes allocated by its instructions.
+    pub bytes: u64,
+}
+
+/// The counts of the profiler of a `State`.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    enabled: bool,
+
+    /// The costs, by function.  `None` is bytecode outside of functions.
+    costs: HashMap<Option<usize>, Cost>,
+
+    /// The function of the last instruction counted, and the bytes that
+    /// had been allocated before it ran.
+    last: Option<(Option<usize>, u64)>,
+}
+
+impl Profiler {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Starts or stops counting.  `allocated` is the bytes allocated so
+    /// far, which are counted against the last instruction on stopping.
+    pub fn set_enabled(&mut self, enabled: bool, allocated: u64) {
+        self.flush(allocated);
+        self.enabled = enabled
+    }
+
+    /// Counts an instruction of `function`, when `allocated` bytes have
+    /// been allocated.
+    pub fn count(&mut self, function: Option<usize>, allocated: u64) {
+        self.flush(allocated);
+        self.costs.entry(function).or_insert_with(Cost::default).instructions += 1;
+        self.last = Some((function, allocated))
+    }
+
+    /// Counts what has been allocated since the last instruction against
+    /// it, which is not counted against any instruction after that.
+    fn flush(&mut self, allocated: u64) {
+        if let Some((function, before)) = self.last.take() {
+            let cost = self.costs.entry(function).or_insert_with(Cost::default);
+            cost.bytes += allocated - before
+        }
+    }
+
+    /// Takes the costs counted so far, and starts counting from zero.
+    pub fn take(&mut self, allocated: u64) -> HashMap<Option<usize>, Cost> {
+        self.flush(allocated);
+        self.costs.drain().collect()
+    }
+}
+
+/// A function of a `Profile`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProfileEntry {
+    /// The function, as an index into `State::functions`, or `None` for
+    /// bytecode outside of functions.
+    pub function: Option<usize>,
+
+    /// What the function is, and where it came from (see
+    /// `bytecode::Source`).
+    pub name: String,
+    pub cost: Cost,
+}
+
+/// The costs of the functions that ran while profiling, most instructions
+/// first, then most bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub entries: Vec<ProfileEntry>,
+}
+
+impl Profile {
+    /// The profile of `costs`, naming each function with `name`.
+    pub fn new<F>(costs: HashMap<Option<usize>, Cost>, name: F) -> Self
+        where F: Fn(Option<usize>) -> String
+    {
+        let mut entries: Vec<_> = costs.into_iter()
+                                       .map(|(function, cost)| {
+                                           ProfileEntry {
+                                               function: function,
+                                               name: name(function),
+                                               cost: cost,
+                                           }
+                                       })
+                                       .collect();
+        entries.sort_by(|x, y| {
+            (y.cost.instructions, y.cost.bytes, &x.name, x.function)
+                .cmp(&(x.cost.instructions, x.cost.bytes, &y.name, y.function))
+        });
+        Profile { entries: entries }
+    }
+
+    /// The sum of the costs of all of the functions.
+    pub fn total(&self) -> Cost {
+        self.entries.iter().fold(Cost::default(), |total, entry| {
+            Cost {
+                instructions: total.instructions + entry.cost.instructions,
+                bytes: total.bytes + entry.cost.bytes,
+            }
+        })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "{:>12} {:>12}  {}", "instructions", "bytes", "function"));
+        for entry in &self.entries {
+            try!(writeln!(f,
+                          "{:>12} {:>12}  {}",
+                          entry.cost.instructions,
+                          entry.cost.bytes,
+                          entry.name))
+        }
+        let total = self.total();
+        write!(f, "{:>12} {:>12}  total", total.instructions, total.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn costs_are_counted_and_sorted() {
+        let mut profiler = Profiler::default();
+        profiler.set_enabled(true, 0);
+        profiler.count(Some(1), 0);
+        profiler.count(Some(2), 16);
+        profiler.count(Some(2), 16);
+        profiler.count(None, 48);
+        profiler.set_enabled(false, 64);
+        let name = |function: Option<usize>| format!("{:?}", function);
+        let profile = Profile::new(profiler.take(64), &name);
+        let costs: Vec<_> = profile.entries
+                                   .iter()
+                                   .map(|entry| (entry.function, entry.cost))
+                                   .collect();
+        assert_eq!(costs,
+                   vec![(Some(2), Cost { instructions: 2, bytes: 32 }),
+                        (None, Cost { instructions: 1, bytes: 16 }),
+                        (Some(1), Cost { instructions: 1, bytes: 16 })]);
+        assert_eq!(profile.total(), Cost { instructions: 4, bytes: 64 });
+        assert!(profile.to_string().ends_with("           4           64  total"));
+        assert!(Profile::new(profiler.take(80), &name).entries.is_empty());
+    }
+}
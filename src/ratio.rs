@@ -0,0 +1,60 @@
+//! Ratios: exact rational numbers that are not integers.
+//!
+//! A ratio is a `RustData` object of four words: its header, its type word,
+//! `RATIO`, and its numerator and denominator, as signed words.  They are
+//! in lowest terms, the denominator is greater than 1, and both are in the
+//! range of fixnums, so that `numerator` and `denominator` are fixnums.  A
+//! result whose parts would not be is an overflow until there are bignums
+//! (see `arith::rational`, which makes every ratio).  Like flonums, ratios
+//! are immutable, and `eqv?` compares them by value (see `equal`).
+
+use arith;
+use value::{self, Value, HEADER_TAG};
+
+/// The type word of a ratio.  (Strings are 0, see `string`, weak boxes 1,
+/// see `alloc::weak`, and flonums 2, see `flonum`.)
+pub const RATIO: usize = 3;
+
+/// The size of a ratio in words.
+pub const SIZEOF_RATIO: usize = 4;
+
+/// The numerator and denominator of `x`, if it is a ratio.
+pub fn as_ratio(x: &Value) -> Option<(isize, isize)> {
+    if x.tag() != value::Tags::RustData {
+        return None
+    }
+    unsafe {
+        let object = x.as_ptr();
+        if (*object).get() & HEADER_TAG == value::HeaderTag::RustData as usize &&
+           (*object.offset(1)).get() == RATIO {
+            Some(((*object.offset(2)).get() as isize, (*object.offset(3)).get() as isize))
+        } else {
+            None
+        }
+    }
+}
+
+/// The ratio `numerator/denominator` in the external syntax of numbers, in
+/// radix `radix`.
+pub fn format(numerator: isize, denominator: isize, radix: u32) -> String {
+    format!("{}/{}", arith::format(numerator, radix), arith::format(denominator, radix))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::Heap;
+    use super::*;
+
+    #[test]
+    fn ratios_hold_their_parts_across_collections() {
+        let mut heap = Heap::new(1 << 4);
+        heap.alloc_ratio(-3, 4).unwrap();
+        ::alloc::collect(&mut heap);
+        assert_eq!(as_ratio(&heap.stack.pop().unwrap()), Some((-3, 4)));
+        heap.alloc_flonum(0.75).unwrap();
+        assert_eq!(as_ratio(&heap.stack.pop().unwrap()), None);
+        assert_eq!(as_ratio(&Value::new(3 << 2)), None);
+        assert_eq!(format(-3, 4, 10), "-3/4");
+        assert_eq!(format(255, 16, 16), "ff/10");
+    }
+}
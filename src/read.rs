@@ -1,9 +1,12 @@
 use std::io;
 use std::io::prelude::*;
 use std::char;
+use std::usize;
 use std::iter::Peekable;
+use super::arith::{self, Parsed};
 use super::interp;
 use super::api;
+use super::print;
 #[derive(Debug)]
 pub enum ReadError {
     /// EOF in list
@@ -30,6 +33,9 @@ pub enum ReadError {
     /// Bad sharpsign read macro
     BadSharpMacro([char; 2]),
 
+    /// Bad character name after `#\\`, such as `spcae`
+    BadCharName(String),
+
     /// Unexpected close parentheses
     UnexpectedCloseParen,
 
@@ -49,8 +55,8 @@ pub enum ReadError {
     /// `|` in symbol unescaped
     PipeInSymbol,
 
-    /// Bad hex number
-    BadHexNumber,
+    /// Bad number after a radix or exactness prefix, such as `#x1g`
+    BadNumber(String),
 
     /// Integer overflow
     Overflow,
@@ -64,6 +70,18 @@ pub enum ReadError {
     /// Host-set memory limit exceeded
     MemLimitExceeded,
 
+    /// `#.` used, but read-time evaluation is not enabled
+    ReadEvalDisabled,
+
+    /// Error evaluating a `#.` datum
+    ReadEvalFailed(String),
+
+    /// `#[` used, but reading records is not enabled
+    RecordsDisabled,
+
+    /// Error making the record of a `#[...]`
+    BadRecord(String),
+
     /// Not yet implemented
     NYI,
 }
@@ -88,18 +106,24 @@ pub enum Event {
     /// Character `#\\x`
     Char(char),
 
-    /// Integer `12311324`
-    Int(usize),
+    /// Integer `12311324`, `-1` or `#xff`
+    Int(isize),
 
-    /// Floating-point numbers (not yet implemented)
+    /// Floating-point numbers `1.5` or `#i1`
     Float(f64),
 
+    /// Ratios `3/4` or `#e1.5`, as numerator and denominator in lowest terms
+    Ratio(isize, isize),
+
     /// Start of a list `(` (false) or `[` (true)
     StartList(bool),
 
     /// Start of a vector `#(`
     StartVec,
 
+    /// Start of a record `#[`
+    StartRecord,
+
     /// End of token `)` (false) or `]` (true)
     EndList(bool),
 
@@ -143,6 +167,8 @@ enum StringOrSymbol {
     Symbol,
 }
 use self::ReadError::IoError;
+/// Reads the rest of the UTF-8 encoding of a character whose first byte is
+/// `unicode_char`.
 fn finish_char<R: BufRead>(file: &mut Peekable<Bytes<R>>,
                            unicode_char: u8)
                            -> Result<char, ReadError> {
@@ -153,17 +179,49 @@ fn finish_char<R: BufRead>(file: &mut Peekable<Bytes<R>>,
     match len {
         1 | 5...8 => Err(ReadError::InvalidUtf8((unicode_char as u32) << 24)),
         len @ 2...4 => {
-            let len = len - 1;
-            let mut value: u32 = (unicode_char >> (len + 2)).into();
-            value <<= len * 6;
-            for (count, val) in &mut file.take(len.into()).enumerate() {
-                value &= (try!(val.map_err(IoError)) as u32) << (len - count as u8)
+            // The first byte has `len` leading ones, then a zero, then the
+            // highest bits; each of the others has `10` and then six bits.
+            let mut value = (unicode_char & (0x7F >> len)) as u32;
+            for _ in 1..len {
+                let byte = match file.next() {
+                    Some(byte) => try!(byte.map_err(IoError)),
+                    None => return Err(ReadError::InvalidUtf8(value)),
+                };
+                if byte & 0xC0 != 0x80 {
+                    return Err(ReadError::InvalidUtf8(value))
+                }
+                value = value << 6 | (byte & 0x3F) as u32
+            }
+            // Overlong encodings are invalid.
+            let least = [0x80, 0x800, 0x10000][len as usize - 2];
+            if value < least {
+                return Err(ReadError::InvalidUtf8(value))
             }
             char::from_u32(value).ok_or_else(|| ReadError::InvalidUtf8(value))
         }
         _ => unreachable!(),
     }
 }
+/// The character that `#\\name` is: the only character of `name`, the
+/// character that R7RS names `name` (such as `space` or `newline`, see
+/// `print::CHAR_NAMES`), or the one whose code point is `xN`, for
+/// hexadecimal `N`.
+fn char_named(name: &str) -> Result<char, ReadError> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(c)
+    }
+    if let Some(&(c, _)) = print::CHAR_NAMES.iter().find(|&&(_, named)| named == name) {
+        return Ok(c)
+    }
+    let code = if name.starts_with('x') && name[1..].chars().all(|c| c.is_digit(16)) {
+        u32::from_str_radix(&name[1..], 16).ok()
+    } else {
+        None
+    };
+    code.and_then(char::from_u32).ok_or_else(|| ReadError::BadCharName(name.to_owned()))
+}
+
 macro_rules! next {
     ($exp: expr, $err: expr) => {
         try!(try!($exp.next().ok_or($err)).map_err(ReadError::IoError))
@@ -174,9 +232,9 @@ type ReadResult = Result<char, ReadError>;
 
 use std::io::Bytes;
 fn handle_unicode_escape<R: BufRead>(file: &mut Peekable<Bytes<R>>) -> ReadResult {
+    let mut escaped_char = 0;
     loop {
         let eof = ReadError::BadEscape;
-        let mut escaped_char = 0;
         let next_character = next!(file, eof);
         let subtract_amount = match next_character {
             b'a'...b'f' => 87,
@@ -298,18 +356,12 @@ impl<'a, R: BufRead> EventSource<'a, R> {
             Some(Err(a)) => Err(ReadError::IoError(a)),
         }
     }
-    fn read_hex(&mut self) -> Item<R> {
-        let mut buf = String::new();
-        for i in &mut self.file {
-            match try!(i.map_err(ReadError::IoError)) {
-                i @ b'0'...b'9' | i @ b'A'...b'F' | i @ b'a'...b'f' => buf.push(i as char),
-                _ => return Err(ReadError::BadHexNumber),
-            }
-        }
-        if let Ok(x) = buf.parse() {
-            Ok(Event::Int(x))
-        } else {
-            Err(ReadError::Overflow)
+    /// The number `#prefix...`, whose prefix has been read.
+    fn read_prefixed_number(&mut self, prefix: u8) -> Item<R> {
+        let text = format!("#{}{}", prefix as char, try!(self.read_token(None)));
+        match number(&text) {
+            Some(number) => number,
+            None => Err(ReadError::BadNumber(text)),
         }
     }
     fn process_sharpsign(&mut self) -> ItemOption<R> {
@@ -317,24 +369,37 @@ impl<'a, R: BufRead> EventSource<'a, R> {
             b'.' => Event::ReadEval,
             b'\\' => {
                 let byte = iter_next!(self.file, ReadError::EOFAfterSharpBackslash);
-                Event::Char(my_try!(finish_char(self.file, byte)))
+                let c = my_try!(finish_char(self.file, byte));
+                if c.is_alphanumeric() {
+                    // A name, such as `space`, may follow.
+                    let name = my_try!(self.read_token(Some(c)));
+                    Event::Char(my_try!(char_named(&name)))
+                } else {
+                    Event::Char(c)
+                }
             }
             b't' => Event::True,
             b'f' => Event::False,
-            b'x' => my_try!(self.read_hex()),
+            prefix @ b'x' | prefix @ b'X' | prefix @ b'o' | prefix @ b'O' | prefix @ b'b' |
+            prefix @ b'B' | prefix @ b'd' | prefix @ b'D' | prefix @ b'e' | prefix @ b'E' |
+            prefix @ b'i' | prefix @ b'I' => my_try!(self.read_prefixed_number(prefix)),
             b'\'' => Event::Syntax,
             b'`' => Event::Quasisyntax,
             b',' => my_try!(self.handle_splicing(Event::Unsyntax, Event::UnsyntaxSplicing)),
             b'(' => Event::StartVec,
+            b'[' => Event::StartRecord,
+            // `#!optional`, `#!key` and `#!rest`, in lambda lists.
+            b'!' => Event::Symbol(format!("#!{}", my_try!(self.read_token(None)))),
             dispatch_char => {
                 return Some(Err(ReadError::BadSharpMacro([dispatch_char as char, '\0'])))
             }
         }))
     }
+    /// Reads the rest of a symbol or number, after `start`.
     #[cfg_attr(feature = "clippy", allow(while_let_on_iterator))]
-    fn read_symbol(&mut self, start: char) -> Result<Event, ReadError> {
+    fn read_token(&mut self, start: Option<char>) -> Result<String, ReadError> {
         let mut buf = String::new();
-        buf.push(start);
+        buf.extend(start);
         while let Some(x) = self.file.next() {
             match try!(x.map_err(ReadError::IoError)) {
                 b'\\' => buf.push(try!(process_escape(self.file))),
@@ -348,7 +413,8 @@ impl<'a, R: BufRead> EventSource<'a, R> {
                 a @ b']' |
                 a @ b')' |
                 a @ b'{' |
-                a @ b'}' => {
+                a @ b'}' |
+                a @ b';' => {
                     self.last_chr = Some(a);
                     break;
                 }
@@ -362,11 +428,37 @@ impl<'a, R: BufRead> EventSource<'a, R> {
                 }
             }
         }
-        Ok(if &buf == "." {
-            Event::Dot
-        } else {
-            Event::Symbol(buf)
-        })
+        Ok(buf)
+    }
+    /// Skips the rest of a line comment, up to and including its newline.
+    fn skip_line(&mut self) -> Result<(), ReadError> {
+        while let Some(x) = self.file.next() {
+            if try!(x.map_err(ReadError::IoError)) == b'\n' {
+                break
+            }
+        }
+        Ok(())
+    }
+    fn read_symbol(&mut self, start: char) -> Result<Event, ReadError> {
+        let buf = try!(self.read_token(Some(start)));
+        if &buf == "." {
+            return Ok(Event::Dot)
+        }
+        match number(&buf) {
+            Some(number) => number,
+            None => Ok(Event::Symbol(buf)),
+        }
+    }
+}
+
+/// The number that `text` is, if it is one.
+fn number(text: &str) -> Option<Result<Event, ReadError>> {
+    match arith::parse(text, 10) {
+        Parsed::Fixnum(n) => Some(Ok(Event::Int(n))),
+        Parsed::Flonum(x) => Some(Ok(Event::Float(x))),
+        Parsed::Bignum => Some(Err(ReadError::Overflow)),
+        Parsed::Ratio(n, d) => Some(Ok(Event::Ratio(n, d))),
+        Parsed::Invalid => None,
     }
 }
 
@@ -398,6 +490,10 @@ impl<'a, R: BufRead> Iterator for EventSource<'a, R> {
                 b'"' => Event::Str(my_try!(read_escaped(self.file, StringOrSymbol::String))),
                 b'|' => Event::Symbol(my_try!(read_escaped(self.file, StringOrSymbol::Symbol))),
                 b'\t'...b'\r' | b' ' => continue, // ASCII whitespace
+                b';' => {
+                    my_try!(self.skip_line());
+                    continue
+                }
                 val => {
                     let chr = if val < 0x7F {
                         val as char
@@ -414,7 +510,34 @@ impl<'a, R: BufRead> Iterator for EventSource<'a, R> {
     }
 }
 
+/// An evaluator for `#.`: evaluates the datum on top of the stack, and
+/// replaces it with the result.
+pub type ReadEvaluator<'a> = &'a mut FnMut(&mut api::State) -> Result<(), String>;
+
+/// Options for `read_with_options`.
+#[derive(Default)]
+pub struct ReadOptions<'a> {
+    /// The evaluator for `#.`, which determines the environment that `#.`
+    /// data are evaluated in.  If `None` (the default), `#.` is a read
+    /// error, since evaluating untrusted input is unsafe.
+    pub read_eval: Option<ReadEvaluator<'a>>,
+
+    /// Whether `#[name field ...]` is read as a record, of the readable
+    /// record type named `name` (see `record`).  If false (the default),
+    /// `#[` is a read error.
+    pub records: bool,
+}
+
+/// Reads a datum, and pushes it.  `#.` is not allowed.
 pub fn read<R: BufRead>(s: &mut api::State, r: &mut Peekable<Bytes<R>>) -> Result<(), ReadError> {
+    read_with_options(s, r, &mut ReadOptions::default())
+}
+
+/// Reads a datum using `options`, and pushes it.
+pub fn read_with_options<R: BufRead>(s: &mut api::State,
+                                     r: &mut Peekable<Bytes<R>>,
+                                     options: &mut ReadOptions)
+                                     -> Result<(), ReadError> {
     #[derive(Copy, Clone, Debug)]
     enum State {
         List {
@@ -428,23 +551,29 @@ pub fn read<R: BufRead>(s: &mut api::State, r: &mut Peekable<Bytes<R>>) -> Resul
         Vec {
             depth: usize,
         },
+        Record {
+            depth: usize,
+        },
         ReaderMacro,
+        ReadEval,
     }
     let mut read_stack: Vec<State> = Vec::new();
     let mut source = EventSource::new(r);
-    loop {
+    'read: loop {
         let i = match source.next() {
             None => return Ok(()),
             Some(x) => x,
         };
         match try!(i) {
-            Event::Char(_) => unimplemented!(),
+            Event::Char(c) => try!(s.push(c).map_err(|()| ReadError::MemLimitExceeded)),
             Event::Int(x) => {
-                s.push(x).unwrap();
+                // The fixnum, as `value::Kind::Fixnum` has it.
+                let x = x as usize & usize::MAX >> 2;
+                try!(s.push(x).map_err(|()| ReadError::MemLimitExceeded));
                 // try!(execute_macros(source))
             }
             Event::Str(st) => {
-                s.push(st).unwrap();
+                try!(s.push(st).map_err(|()| ReadError::MemLimitExceeded));
                 // try!(execute_macros(source))
             }
             Event::Symbol(st) => {
@@ -472,7 +601,7 @@ pub fn read<R: BufRead>(s: &mut api::State, r: &mut Peekable<Bytes<R>>) -> Resul
             Event::EndList(is_square) => {
                 if let Some(state) = read_stack.pop() {
                     match state {
-                        State::DottedList { .. } | State::ReaderMacro =>
+                        State::DottedList { .. } | State::ReaderMacro | State::ReadEval =>
                             return Err(ReadError::UnexpectedCloseParen),
                         State::Vec { depth } => {
                             debug_assert!(depth > 0);
@@ -482,6 +611,12 @@ pub fn read<R: BufRead>(s: &mut api::State, r: &mut Peekable<Bytes<R>>) -> Resul
                                 s.vector(1, depth).expect("Out of mem!")
                             }
                         }
+                        State::Record { depth } => {
+                            if !is_square {
+                                return Err(ReadError::BadCloseParen)
+                            }
+                            try!(s.readable_record(depth).map_err(ReadError::BadRecord))
+                        }
                         State::List { is_square: square, depth } => {
                             if square == is_square {
                                 s.list(depth).expect("Out of mem!")
@@ -498,6 +633,13 @@ pub fn read<R: BufRead>(s: &mut api::State, r: &mut Peekable<Bytes<R>>) -> Resul
                 read_stack.push(State::Vec { depth: 0 });
                 continue;
             }
+            Event::StartRecord => {
+                if !options.records {
+                    return Err(ReadError::RecordsDisabled)
+                }
+                read_stack.push(State::Record { depth: 0 });
+                continue;
+            }
             Event::StartList(x) => {
                 read_stack.push(State::List {
                     is_square: x,
@@ -520,43 +662,70 @@ pub fn read<R: BufRead>(s: &mut api::State, r: &mut Peekable<Bytes<R>>) -> Resul
                 read_stack.push(State::ReaderMacro);
                 continue;
             }
+            Event::ReadEval => {
+                if options.read_eval.is_none() {
+                    return Err(ReadError::ReadEvalDisabled)
+                }
+                read_stack.push(State::ReadEval);
+                continue;
+            }
             _ => return Err(ReadError::NYI),
         }
-        let last = read_stack.len().wrapping_sub(1);
-        if let Some(&x) = read_stack.get(last) {
-            match x {
-                State::ReaderMacro => {
-                    try!(s.list(2).map_err(|_| ReadError::MemLimitExceeded));
-                    read_stack.pop();
-                }
-                State::List { depth, is_square } => {
-                    read_stack[last] = State::List {
-                        depth: depth + 1,
-                        is_square: is_square,
+        // A datum is complete.  Wrap it in any pending reader macros, and
+        // add it to the enclosing list or vector.
+        loop {
+            let last = read_stack.len().wrapping_sub(1);
+            if let Some(&x) = read_stack.get(last) {
+                match x {
+                    State::ReaderMacro => {
+                        try!(s.list(2).map_err(|_| ReadError::MemLimitExceeded));
+                        read_stack.pop();
+                        continue;
                     }
-                }
-                State::Vec { depth } => {
-                    read_stack[last] = State::Vec {
-                        depth: depth + 1,
+                    State::ReadEval => {
+                        let eval = options.read_eval.as_mut().expect("#. without an evaluator");
+                        try!(eval(s).map_err(ReadError::ReadEvalFailed));
+                        read_stack.pop();
+                        continue;
                     }
-                }
-                State::DottedList { depth, is_square } => {
-                    try!(s.list_with_tail(depth).map_err(|_| ReadError::MemLimitExceeded));
-                    if let Some(token) = source.next() {
-                        debug!("Token that must be close paren: {:?}\n", token);
-                        match try!(token) {
-                            Event::EndList(x) if x == is_square => continue,
-                            Event::EndList(_) => return Err(ReadError::ParenMismatch),
-                            _ => return Err(ReadError::MissingCloseParen),
+                    State::List { depth, is_square } => {
+                        read_stack[last] = State::List {
+                            depth: depth + 1,
+                            is_square: is_square,
+                        }
+                    }
+                    State::Vec { depth } => {
+                        read_stack[last] = State::Vec {
+                            depth: depth + 1,
+                        }
+                    }
+                    State::Record { depth } => {
+                        read_stack[last] = State::Record {
+                            depth: depth + 1,
+                        }
+                    }
+                    State::DottedList { depth, is_square } => {
+                        try!(s.list_with_tail(depth).map_err(|_| ReadError::MemLimitExceeded));
+                        if let Some(token) = source.next() {
+                            debug!("Token that must be close paren: {:?}\n", token);
+                            match try!(token) {
+                                Event::EndList(x) if x == is_square => {
+                                    read_stack.pop();
+                                    continue;
+                                }
+                                Event::EndList(_) => return Err(ReadError::ParenMismatch),
+                                _ => return Err(ReadError::MissingCloseParen),
+                            }
+                        } else {
+                            s.drop().expect("Empty stack after list_with_tail?");
+                            return Err(ReadError::BadDot);
                         }
-                    } else {
-                        s.drop().expect("Empty stack after list_with_tail?");
-                        return Err(ReadError::BadDot);
                     }
                 }
+            } else {
+                return Ok(());
             }
-        } else {
-            return Ok(());
+            continue 'read;
         }
     }
 }
@@ -582,4 +751,122 @@ mod test {
         let mut iter = b"#(a b c d)".bytes().peekable();
         super::read(&mut interp, &mut iter).unwrap();
     }
+
+    #[test]
+    fn numbers_are_read_with_prefixes() {
+        let mut interp = api::State::new();
+        let mut iter = b"(10 -10 #xff #X-F #o17 #b101 #d9 #e#x10 +1 - 1+ ...)".bytes().peekable();
+        super::read(&mut interp, &mut iter).unwrap();
+        assert_eq!(interp.write().unwrap(), "(10 -10 255 -15 15 5 9 16 1 - 1+ ...)");
+        for &bad in &["#x1g", "#x", "#b2", "#e#e1", "#q1"] {
+            match super::read(&mut interp, &mut bad.as_bytes().bytes().peekable()) {
+                Err(super::ReadError::BadNumber(_)) | Err(super::ReadError::BadSharpMacro(_)) => (),
+                x => panic!("expected a bad number for {}, got {:?}", bad, x),
+            }
+        }
+        match super::read(&mut interp, &mut b"99999999999999999999999".bytes().peekable()) {
+            Err(super::ReadError::Overflow) => (),
+            x => panic!("expected Overflow, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn characters_are_read_by_name() {
+        let mut interp = api::State::new();
+        let source = "(#\\a #\\space #\\newline #\\x3bb #\\( #\\) #\\\u{3bb} #\\x)";
+        super::read(&mut interp, &mut source.as_bytes().bytes().peekable()).unwrap();
+        let written = "(#\\a #\\space #\\newline #\\\u{3bb} #\\( #\\) #\\\u{3bb} #\\x)";
+        assert_eq!(interp.write().unwrap(), written);
+        let forms = ::compiler::read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+        assert_eq!(forms[0].to_string(), written);
+        for &bad in &["#\\spcae", "#\\xd800", "#\\x+41"] {
+            match super::read(&mut interp, &mut bad.as_bytes().bytes().peekable()) {
+                Err(super::ReadError::BadCharName(_)) => (),
+                x => panic!("expected a bad character name for {}, got {:?}", bad, x),
+            }
+        }
+    }
+
+    #[test]
+    fn line_comments_are_skipped() {
+        let source = b";;; a comment\n(a ; (b\n c;d\n) ; e\n\"; f\" ;";
+        let forms = ::compiler::read_all(&mut source.bytes().peekable()).unwrap();
+        let forms: Vec<_> = forms.iter().map(|form| form.to_string()).collect();
+        assert_eq!(forms, ["(a c)", "\"; f\""]);
+    }
+
+    #[test]
+    fn utf8_is_decoded() {
+        let source = "(\"h\u{e9}llo\" \u{3bb} \"\u{20ac}\u{1f600}\")";
+        let forms = ::compiler::read_all(&mut source.as_bytes().bytes().peekable()).unwrap();
+        assert_eq!(forms[0].to_string(), source);
+        for bad in &[&b"\"\xe9llo\""[..], b"\"\xc3\"", b"\"\xc0\xaf\"", b"\"\xed\xa0\x80\""] {
+            match ::compiler::read_all(&mut bad.bytes().peekable()) {
+                Err(super::ReadError::InvalidUtf8(_)) => (),
+                x => panic!("expected InvalidUtf8, got {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn read_eval_is_disabled_by_default() {
+        let mut interp = api::State::new();
+        let mut iter = b"(a #.(b) c)".bytes().peekable();
+        match super::read(&mut interp, &mut iter) {
+            Err(super::ReadError::ReadEvalDisabled) => (),
+            x => panic!("expected ReadEvalDisabled, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn read_eval_replaces_the_datum() {
+        let mut interp = api::State::new();
+        let mut calls = 0;
+        {
+            let mut eval = |s: &mut api::State| {
+                calls += 1;
+                try!(s.drop());
+                s.push(42usize).map_err(|()| "out of memory".to_owned())
+            };
+            let mut options = super::ReadOptions {
+                read_eval: Some(&mut eval),
+                ..Default::default()
+            };
+            let mut iter = b"(a #.(b) c)".bytes().peekable();
+            super::read_with_options(&mut interp, &mut iter, &mut options).unwrap();
+        }
+        assert_eq!(calls, 1);
+        assert_eq!(interp.len(), 1);
+        interp.cdr().unwrap();
+        let evaluated = interp.car().unwrap();
+        assert_eq!(evaluated.get(), 42 << 2);
+    }
+
+    #[test]
+    fn records_are_read_back_as_written() {
+        let mut interp = api::State::new();
+        interp.eval_interactive("(define point (make-record-type 'point 'x 'y))
+                                 (register-record-type point)
+                                 (make-record point 1 (make-record point 2 '()))")
+              .unwrap();
+        let written = interp.write().unwrap();
+        assert_eq!(written, "#[point 1 #[point 2 ()]]");
+        interp.drop().unwrap();
+        match super::read(&mut interp, &mut written.as_bytes().bytes().peekable()) {
+            Err(super::ReadError::RecordsDisabled) => (),
+            x => panic!("expected RecordsDisabled, got {:?}", x),
+        }
+        let mut options = super::ReadOptions { records: true, ..Default::default() };
+        let len = interp.len();
+        super::read_with_options(&mut interp, &mut written.as_bytes().bytes().peekable(),
+                                 &mut options)
+            .unwrap();
+        assert_eq!(interp.len(), len + 1);
+        assert_eq!(interp.write().unwrap(), written);
+        for bad in &["#[line 1 2]", "#[point 1]", "#[point 1 2)"] {
+            assert!(super::read_with_options(&mut interp, &mut bad.as_bytes().bytes().peekable(),
+                                             &mut options)
+                        .is_err());
+        }
+    }
 }
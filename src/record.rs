@@ -0,0 +1,454 @@
+//! Records and record types.
+//!
+//! A record is a vector-like object (tag `VECTOR_TAG`) whose header has the
+//! `HeaderTag::Record` tag.  The first word after the header is the record
+//! type, and the remaining words are the fields:
+//!
+//! | header | record type | field 0 | field 1 | ... |
+//!
+//! A record type is itself a record, whose record type is `#f`:
+//!
+//! | header | `#f` | id | name | field name 0 | field name 1 | ... |
+//!
+//! The id is a fixnum unique to the record type.  Unlike the address of the
+//! record type, it does not change when the GC moves the record type.  Names
+//! are symbols.
+//!
+//! ### Field access caching
+//!
+//! Fields are accessed by name (`Opcode::RecordRef` and `Opcode::RecordSet`),
+//! so that accessors like a generic `get-name` work on any record with a
+//! `name` field.  Looking the name up in the record type every time would be
+//! slow, but a given access site almost always sees the same record type.
+//! So each site remembers the id of the last record type it saw and the
+//! offset of the field in it, in a `FieldCache`.  Only a miss searches the
+//! record type.
+//!
+//! ### Printers
+//!
+//! A record type can have a Scheme procedure that prints its records (see
+//! `print`).  Printers are kept in `Printers`, by record type id, as
+//! persistent roots.
+//!
+//! ### External syntax
+//!
+//! Records of the record types in `Readable` are written as `#[name field
+//! ...]`, where `name` is the name of the record type, and can be read back
+//! if reading records is enabled (`read::ReadOptions::records`).  Only one
+//! record type of each name can be readable at a time.
+
+use std::collections::HashMap;
+use std::slice;
+
+use alloc;
+use value::{self, Value, Tags, HEADER_TAG};
+
+const RECORD: usize = value::HeaderTag::Record as usize;
+
+/// The offset of the record type in a record, in words.
+const TYPE_OFFSET: usize = 1;
+
+/// The offset of the id in a record type, in words.
+const ID_OFFSET: usize = 2;
+
+/// The offset of the name in a record type, in words.
+const NAME_OFFSET: usize = 3;
+
+/// The offset of the first field name in a record type, in words.
+const FIELDS_OFFSET: usize = 4;
+
+/// The words of the record-like object `x`, including the header, if
+/// it is one.
+fn words(x: &Value) -> Option<&[Value]> {
+    if x.tag() != Tags::Vector || x.immediatep() {
+        return None
+    }
+    unsafe {
+        let ptr = x.as_ptr();
+        let header = (*ptr).get();
+        if header & HEADER_TAG != RECORD {
+            return None
+        }
+        Some(slice::from_raw_parts(ptr, header & !HEADER_TAG))
+    }
+}
+
+/// The words of the record type `x`, if it is one.
+fn type_words(x: &Value) -> Option<&[Value]> {
+    words(x).and_then(|words| if words[TYPE_OFFSET].get() == value::FALSE {
+        Some(words)
+    } else {
+        None
+    })
+}
+
+/// Is `x` a record (and not a record type)?
+pub fn is_record(x: &Value) -> bool {
+    words(x).map_or(false, |words| words[TYPE_OFFSET].get() != value::FALSE)
+}
+
+/// Is `x` a record type?
+pub fn is_record_type(x: &Value) -> bool {
+    type_words(x).is_some()
+}
+
+/// The record type of the record `x`.
+pub fn record_type(x: &Value) -> Result<Value, String> {
+    match words(x) {
+        Some(words) if words[TYPE_OFFSET].get() != value::FALSE => {
+            Ok(words[TYPE_OFFSET].clone())
+        }
+        _ => Err("not a record".to_owned()),
+    }
+}
+
+/// Is `x` a record whose type is named `name`, as a host type is?
+pub fn is_named(x: &Value, name: &str) -> bool {
+    match record_type(x).and_then(|rtd| record_type_name(&rtd)) {
+        Ok(type_name) => symbol_name(&type_name).ok() == Some(name),
+        Err(_) => false,
+    }
+}
+
+/// The number of fields of instances of the record type `rtd`.
+pub fn field_count(rtd: &Value) -> Result<usize, String> {
+    type_words(rtd)
+        .map(|words| words.len() - FIELDS_OFFSET)
+        .ok_or_else(|| "not a record type".to_owned())
+}
+
+/// The fields of the record `x`.
+pub fn fields(x: &Value) -> Result<&[Value], String> {
+    match words(x) {
+        Some(words) if words[TYPE_OFFSET].get() != value::FALSE => Ok(&words[TYPE_OFFSET + 1..]),
+        _ => Err("not a record".to_owned()),
+    }
+}
+
+/// The offset of the field named `name` in instances of `rtd`, in words.
+fn field_offset(rtd: &[Value], name: &Value) -> Option<usize> {
+    rtd[FIELDS_OFFSET..]
+        .iter()
+        .position(|field| field.get() == name.get())
+        .map(|i| TYPE_OFFSET + 1 + i)
+}
+
+fn no_such_field(name: &Value) -> String {
+    match name.kind() {
+        value::Kind::Symbol(ptr) => format!("no such field: {}", unsafe { (*ptr).name() }),
+        _ => "record field names must be symbols".to_owned(),
+    }
+}
+
+/// Creates a record type from the values `heap.stack[start..end]`, which
+/// are the name and the field names.  Pushes the new record type.
+pub fn make_record_type(heap: &mut alloc::Heap, start: usize, end: usize) -> Result<(), String> {
+    assert!(end > start);
+    for x in &heap.stack[start..end] {
+        if x.tag() != Tags::Symbol {
+            return Err("record type and field names must be symbols".to_owned())
+        }
+    }
+    heap.record_type_count += 1;
+    let base = heap.stack.len();
+    heap.stack.push(Value::new(value::FALSE));
+    heap.stack.push(Value::new(heap.record_type_count << 2));
+    for i in start..end {
+        let x = heap.stack[i].clone();
+        heap.stack.push(x)
+    }
+    let len = heap.stack.len();
+    let res = heap.alloc_record(base, len);
+    let rtd = heap.stack.pop();
+    heap.stack.truncate(base);
+    try!(res);
+    heap.stack.push(rtd.unwrap());
+    Ok(())
+}
+
+/// Creates a record from the values `heap.stack[start..end]`, which are the
+/// record type and the fields.  Pushes the new record.
+pub fn make_record(heap: &mut alloc::Heap, start: usize, end: usize) -> Result<(), String> {
+    assert!(end > start);
+    let fields = try!(field_count(&heap.stack[start]));
+    if end - start - 1 != fields {
+        return Err(format!("wrong number of fields: expected {}, got {}",
+                           fields,
+                           end - start - 1))
+    }
+    try!(heap.alloc_record(start, end));
+    Ok(())
+}
+
+/// The per-site caches of field offsets.  See the module documentation.
+#[derive(Debug, Default)]
+pub struct FieldCache {
+    /// Indexed by program counter.  Each entry is a record type id (zero
+    /// if the site has not been used) and a field offset.
+    sites: Vec<(usize, usize)>,
+}
+
+impl FieldCache {
+    /// The offset of the field named `name` in `record`, at access site
+    /// `site`.
+    pub fn lookup(&mut self, site: usize, record: &Value, name: &Value) -> Result<usize, String> {
+        let rtd = try!(record_type(record));
+        let rtd = type_words(&rtd).expect("record type of a record is not a record type");
+        let id = rtd[ID_OFFSET].get();
+        if let Some(&(cached_id, offset)) = self.sites.get(site) {
+            if cached_id == id {
+                return Ok(offset)
+            }
+        }
+        let offset = try!(field_offset(rtd, name).ok_or_else(|| no_such_field(name)));
+        if self.sites.len() <= site {
+            self.sites.resize(site + 1, (0, 0))
+        }
+        self.sites[site] = (id, offset);
+        Ok(offset)
+    }
+
+    /// Forgets all cached offsets.  Must be called when the code that the
+    /// sites refer to is replaced.
+    pub fn clear(&mut self) {
+        self.sites.clear()
+    }
+}
+
+/// The record printers of a `State`.  See the module documentation.
+#[derive(Debug, Default)]
+pub struct Printers {
+    /// Maps record type ids to persistent roots of the printers.
+    roots: HashMap<usize, usize>,
+}
+
+impl Printers {
+    /// Makes `printer` the printer of records of type `rtd`, or removes
+    /// their printer if it is `None`.
+    pub fn set(&mut self,
+               heap: &alloc::Heap,
+               rtd: &Value,
+               printer: Option<Value>)
+               -> Result<(), String> {
+        let id = try!(type_words(rtd).ok_or_else(|| "not a record type".to_owned()))[ID_OFFSET]
+                     .get();
+        if let Some(old) = self.roots.remove(&id) {
+            heap.persistent.release(old)
+        }
+        if let Some(printer) = printer {
+            self.roots.insert(id, heap.persistent.add(printer));
+        }
+        Ok(())
+    }
+
+    /// The printer of the record `record`, if it has one.
+    pub fn get(&self, heap: &alloc::Heap, record: &Value) -> Option<Value> {
+        let rtd = match record_type(record) {
+            Ok(rtd) => rtd,
+            Err(_) => return None,
+        };
+        let id = type_words(&rtd).expect("record type of a record is not a record type")[ID_OFFSET]
+                     .get();
+        self.roots.get(&id).map(|&root| heap.persistent.get(root))
+    }
+}
+
+/// The record types with an external syntax.  See the module
+/// documentation.
+#[derive(Debug, Default)]
+pub struct Readable {
+    /// Maps record type names to persistent roots of the record types.
+    roots: HashMap<String, usize>,
+}
+
+fn symbol_name(x: &Value) -> Result<&str, String> {
+    match x.kind() {
+        value::Kind::Symbol(ptr) => Ok(unsafe { (*ptr).name() }),
+        _ => Err("record type names must be symbols".to_owned()),
+    }
+}
+
+impl Readable {
+    /// Gives records of type `rtd` an external syntax, replacing any record
+    /// type of the same name.
+    pub fn register(&mut self, heap: &alloc::Heap, rtd: &Value) -> Result<(), String> {
+        let name = try!(symbol_name(&try!(record_type_name(rtd)))).to_owned();
+        if let Some(old) = self.roots.insert(name, heap.persistent.add(rtd.clone())) {
+            heap.persistent.release(old)
+        }
+        Ok(())
+    }
+
+    /// The record type named `name`, if it has an external syntax.
+    pub fn get(&self, heap: &alloc::Heap, name: &Value) -> Result<Value, String> {
+        let name = try!(symbol_name(name));
+        match self.roots.get(name) {
+            Some(&root) => Ok(heap.persistent.get(root)),
+            None => Err(format!("no record type named {} can be read", name)),
+        }
+    }
+
+    /// Does the record type `rtd` have an external syntax?
+    pub fn contains(&self, heap: &alloc::Heap, rtd: &Value) -> bool {
+        let name = match record_type_name(rtd) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        self.get(heap, &name).map_or(false, |registered| registered.get() == rtd.get())
+    }
+}
+
+/// The record types of Rust types (see `api::Record`), by name.  Each is
+/// made the first time a value of its Rust type is converted, and kept as a
+/// persistent root.
+#[derive(Debug, Default)]
+pub struct HostTypes {
+    roots: HashMap<&'static str, usize>,
+}
+
+impl HostTypes {
+    /// Pushes the record type named `name`, whose field names are
+    /// `fields`.
+    pub fn push(&mut self,
+                heap: &mut alloc::Heap,
+                name: &'static str,
+                fields: &[&str])
+                -> Result<(), String> {
+        if let Some(&root) = self.roots.get(name) {
+            let rtd = heap.persistent.get(root);
+            return Ok(heap.stack.push(rtd))
+        }
+        let base = heap.stack.len();
+        heap.intern(name);
+        for field in fields {
+            heap.intern(field)
+        }
+        let len = heap.stack.len();
+        let res = make_record_type(heap, base, len);
+        let rtd = if res.is_ok() { heap.stack.pop() } else { None };
+        heap.stack.truncate(base);
+        try!(res);
+        let rtd = rtd.unwrap();
+        self.roots.insert(name, heap.persistent.add(rtd.clone()));
+        Ok(heap.stack.push(rtd))
+    }
+}
+
+/// The offset of the field named `name` of `record`, without caching.
+fn named_offset(record: &Value, name: &str) -> Result<usize, String> {
+    let rtd = try!(record_type(record));
+    let rtd = type_words(&rtd).expect("record type of a record is not a record type");
+    rtd[FIELDS_OFFSET..]
+        .iter()
+        .position(|field| symbol_name(field).ok() == Some(name))
+        .map(|i| TYPE_OFFSET + 1 + i)
+        .ok_or_else(|| format!("no such field: {}", name))
+}
+
+/// The field named `name` of `record`, without caching.
+pub fn field(record: &Value, name: &str) -> Result<Value, String> {
+    named_offset(record, name).map(|offset| get(record, offset))
+}
+
+/// Sets the field named `name` of `record` to `new`, without caching.  The
+/// caller must call the write barrier.
+pub fn set_field(record: &Value, name: &str, new: Value) -> Result<(), String> {
+    named_offset(record, name).map(|offset| set(record, offset, new))
+}
+
+/// The name of the record type `rtd`.
+pub fn record_type_name(rtd: &Value) -> Result<Value, String> {
+    type_words(rtd)
+        .map(|words| words[NAME_OFFSET].clone())
+        .ok_or_else(|| "not a record type".to_owned())
+}
+
+/// Gets the field of `record` at `offset`, as returned by
+/// `FieldCache::lookup` or `lookup`.
+pub fn get(record: &Value, offset: usize) -> Value {
+    words(record).expect("not a record")[offset].clone()
+}
+
+/// Sets the field of `record` at `offset` to `new`.  The caller must call
+/// the write barrier.
+pub fn set(record: &Value, offset: usize, new: Value) {
+    words(record).expect("not a record")[offset].set(new)
+}
+
+/// The offset of the field named `name` in `record`, without caching.
+pub fn lookup(record: &Value, name: &Value) -> Result<usize, String> {
+    let rtd = try!(record_type(record));
+    let rtd = type_words(&rtd).expect("record type of a record is not a record type");
+    field_offset(rtd, name).ok_or_else(|| no_such_field(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{self, Heap};
+    use value::{Value, NIL};
+    use super::*;
+
+    fn record_type(heap: &mut Heap, names: &[&str]) -> Value {
+        let base = heap.stack.len();
+        for name in names {
+            heap.intern(name)
+        }
+        make_record_type(heap, base, base + names.len()).unwrap();
+        let rtd = heap.stack.pop().unwrap();
+        heap.stack.truncate(base);
+        rtd
+    }
+
+    #[test]
+    fn field_cache_handles_polymorphic_sites() {
+        let mut heap = Heap::new(1 << 8);
+        let person = record_type(&mut heap, &["person", "name", "age"]);
+        heap.stack.push(person);
+        let dog = record_type(&mut heap, &["dog", "owner", "name"]);
+        heap.stack.push(dog);
+        // A person's name is its first field, and a dog's its second.
+        for &(rtd, fields) in &[(0, [1 << 2, NIL]), (1, [NIL, 2 << 2])] {
+            let rtd = heap.stack[rtd].clone();
+            heap.stack.push(rtd);
+            heap.stack.push(Value::new(fields[0]));
+            heap.stack.push(Value::new(fields[1]));
+            let len = heap.stack.len();
+            make_record(&mut heap, len - 3, len).unwrap();
+            let record = heap.stack.pop().unwrap();
+            heap.stack.truncate(len - 3);
+            heap.stack.push(record);
+        }
+        alloc::collect(&mut heap);
+        heap.intern("name");
+        let name = heap.stack.pop().unwrap();
+        let mut cache = FieldCache::default();
+        for _ in 0..2 {
+            for (i, &expected) in [1 << 2, 2 << 2].iter().enumerate() {
+                let ref record = heap.stack[2 + i];
+                assert!(is_record(record));
+                let offset = cache.lookup(0, record, &name).unwrap();
+                assert_eq!(get(record, offset).get(), expected);
+            }
+        }
+        let ref person = heap.stack[0];
+        assert!(is_record_type(person) && !is_record(person));
+        assert_eq!(field_count(person), Ok(2));
+        heap.intern("weight");
+        let weight = heap.stack.pop().unwrap();
+        assert!(lookup(&heap.stack[2], &weight).is_err());
+    }
+
+    #[test]
+    fn make_record_checks_its_arguments() {
+        let mut heap = Heap::new(1 << 8);
+        let point = record_type(&mut heap, &["point", "x", "y"]);
+        heap.stack.push(point);
+        heap.stack.push(Value::new(0));
+        assert!(make_record(&mut heap, 0, 2).is_err());
+        heap.stack.push(Value::new(0));
+        make_record(&mut heap, 0, 3).unwrap();
+        let point = heap.stack.pop().unwrap();
+        assert!(is_record(&point));
+        assert!(make_record(&mut heap, 1, 3).is_err());
+    }
+}
@@ -0,0 +1,452 @@
+//! Support for read-eval-print loops.
+//!
+//! `eval` evaluates a line of input and remembers its value in the
+//! `History`.  Like in other Lisp REPLs, the most recent values are bound
+//! to `*1` (also `$it`), `*2`, and `*3`, and `(history n)` returns the `n`th
+//! most recent value, as far back as the history goes.  Values that are
+//! unspecified, such as that of a `define`, are not remembered.  Macros
+//! that an input defines at top level can be used by the inputs after it.
+//!
+//! `,disasm expr` evaluates `expr`, which must be a closure, and returns
+//! the disassembly of its code as a string, instead of remembering it.
+//! `,opcodes` returns the opcode reference (see `bytecode::opcode_table`)
+//! as a string.  `,profile expr ...` evaluates the `expr`s with the
+//! profiler on, and returns its report as a string (see `profiler`).
+//!
+//! `run` is a whole REPL, for hosts that embed a console: it reads lines
+//! from a `LineSource` until it runs out, and writes the banner, prompts,
+//! values, and errors (as `ReplOptions` say) to a `Write`.  Input that ends
+//! inside a list, vector, or string is continued on the next line.  An
+//! error is followed by its backtrace, a frame per line, innermost first.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+
+use alloc::Heap;
+use api::SchemeValue;
+use audit;
+use bytecode;
+use compiler;
+use interp::{self, State};
+use print;
+use read::ReadError;
+use value::Value;
+
+/// The number of values remembered by default.
+const DEFAULT_LENGTH: usize = 10;
+
+/// The most frames of a backtrace that `run` writes.
+const MAX_BACKTRACE: usize = 20;
+
+/// The most recent values, as persistent roots, most recent first.
+#[derive(Debug)]
+pub struct History {
+    roots: VecDeque<usize>,
+    length: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            roots: VecDeque::new(),
+            length: DEFAULT_LENGTH,
+        }
+    }
+}
+
+impl History {
+    /// Remembers at most `length` values, forgetting the oldest ones if
+    /// there are more.
+    pub fn set_length(&mut self, heap: &Heap, length: usize) {
+        self.length = length;
+        while self.roots.len() > length {
+            heap.persistent.release(self.roots.pop_back().unwrap())
+        }
+    }
+
+    /// The number of values remembered.
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// Is the history empty?
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// Remembers `value` as the most recent value.
+    pub fn push(&mut self, heap: &Heap, value: Value) {
+        if self.length == 0 {
+            return
+        }
+        if self.roots.len() == self.length {
+            heap.persistent.release(self.roots.pop_back().unwrap())
+        }
+        self.roots.push_front(heap.persistent.add(value))
+    }
+
+    /// The `n`th most recent value, counting from 1.
+    pub fn get(&self, heap: &Heap, n: usize) -> Option<Value> {
+        if n == 0 {
+            return None
+        }
+        self.roots.get(n - 1).map(|&root| heap.persistent.get(root))
+    }
+}
+
+/// The variables bound to the most recent values, most recent first.
+static VARIABLES: &'static [&'static [&'static str]] = &[&["*1", "$it"], &["*2"], &["*3"]];
+
+/// Evaluates the forms in `source`, and pushes the value of the last one.
+/// The value is remembered in the history, unless it is unspecified.
+pub fn eval(s: &mut State, source: &str) -> Result<(), String> {
+    try!(s.audit.run(&audit::Event::Eval(source)));
+    let trimmed = source.trim();
+    if trimmed.starts_with(",disasm") {
+        return disasm(s, &trimmed[",disasm".len()..])
+    }
+    if trimmed.starts_with(",profile") {
+        return profile(s, &trimmed[",profile".len()..])
+    }
+    if trimmed == ",opcodes" {
+        let table = try!(bytecode::opcode_table().to_value(&mut s.heap));
+        s.heap.stack.push(table);
+        return Ok(())
+    }
+    let (forms, lines) = try!(compiler::read_lines(source.as_bytes())
+                                  .map_err(|e| format!("read error: {:?}", e)));
+    let program = try!(compiler::compile_toplevel(&mut s.libraries, &mut s.macros, &forms, &lines));
+    try!(interp::load(s, &program));
+    try!(interp::call(s, 0));
+    let result = s.heap.stack.last().unwrap().clone();
+    if result.is_unspecified() {
+        return Ok(())
+    }
+    s.history.push(&s.heap, result);
+    for (i, names) in VARIABLES.iter().enumerate() {
+        if let Some(x) = s.history.get(&s.heap, i + 1) {
+            for name in names.iter() {
+                s.heap.stack.push(x.clone());
+                s.heap.intern(name);
+                try!(s.heap.store_global())
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where the input of `run` comes from, a line at a time.
+pub trait LineSource {
+    /// Reads a line, or returns `None` at the end of the input.
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+}
+
+impl<R: BufRead> LineSource for R {
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match try!(BufRead::read_line(self, &mut line)) {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+}
+
+/// What `run` writes, besides values.
+pub struct ReplOptions {
+    /// Written once, before the first prompt.
+    pub banner: String,
+
+    /// Written before each line of new input.
+    pub prompt: String,
+
+    /// Written before each line that continues unfinished input.
+    pub continuation: String,
+
+    /// Turns an error into the line written for it.
+    pub format_error: Box<Fn(&str) -> String>,
+
+    /// Are the backtraces of errors written?
+    pub backtraces: bool,
+}
+
+impl Default for ReplOptions {
+    fn default() -> Self {
+        ReplOptions {
+            banner: "RustyScheme\n".to_owned(),
+            prompt: "> ".to_owned(),
+            continuation: "... ".to_owned(),
+            format_error: Box::new(|e| format!("error: {}", e)),
+            backtraces: true,
+        }
+    }
+}
+
+/// Does `input` end in the middle of a datum?
+fn is_unfinished(input: &str) -> bool {
+    match compiler::read_all(&mut input.as_bytes().bytes().peekable()) {
+        Err(ReadError::EOFInList) |
+        Err(ReadError::EOFInVector) |
+        Err(ReadError::EOFInString) => true,
+        _ => false,
+    }
+}
+
+/// Evaluates `input`, and writes its value (unless it is unspecified) or
+/// error to `out`.  Leaves the stack as it was.
+fn eval_and_print<W: Write>(s: &mut State,
+                            input: &str,
+                            out: &mut W,
+                            options: &ReplOptions)
+                            -> io::Result<()> {
+    let base = s.heap.stack.len();
+    // An error before the code runs has no backtrace.
+    interp::clear_error(s);
+    // Disassemblies, profiles and the opcode reference are strings, which
+    // are more readable displayed.
+    let trimmed = input.trim();
+    let display = trimmed.starts_with(",disasm") || trimmed.starts_with(",profile") ||
+                  trimmed == ",opcodes";
+    let printed = eval(s, input).and_then(|()| {
+        if s.heap.stack[base].is_unspecified() {
+            return Ok(None)
+        }
+        print::print(s, display).map(Some)
+    });
+    s.heap.stack.truncate(base);
+    match printed {
+        Ok(Some(text)) => writeln!(out, "{}", text),
+        Ok(None) => Ok(()),
+        Err(e) => {
+            try!(writeln!(out, "{}", (options.format_error)(&e)));
+            if options.backtraces {
+                try!(write_backtrace(s, out))
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Writes the backtrace of the last error, as far as `MAX_BACKTRACE`
+/// frames.
+fn write_backtrace<W: Write>(s: &State, out: &mut W) -> io::Result<()> {
+    for frame in s.backtrace.iter().take(MAX_BACKTRACE) {
+        try!(writeln!(out, "  in {}", frame.describe(s)))
+    }
+    if s.backtrace.len() > MAX_BACKTRACE {
+        try!(writeln!(out, "  ... {} more", s.backtrace.len() - MAX_BACKTRACE))
+    }
+    Ok(())
+}
+
+/// Runs a REPL on `s`, reading from `lines` and writing to `out`, until
+/// `lines` runs out.  Errors in the input are written to `out`, and do not
+/// stop the REPL; only I/O errors do.
+pub fn run<L: LineSource, W: Write>(s: &mut State,
+                                    mut lines: L,
+                                    mut out: W,
+                                    options: &ReplOptions)
+                                    -> io::Result<()> {
+    try!(out.write_all(options.banner.as_bytes()));
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { &options.prompt } else { &options.continuation };
+        try!(out.write_all(prompt.as_bytes()));
+        try!(out.flush());
+        match try!(lines.read_line()) {
+            Some(line) => input.push_str(&line),
+            None => break,
+        }
+        if !input.ends_with('\n') {
+            input.push('\n')
+        }
+        if input.trim().is_empty() {
+            input.clear()
+        } else if !is_unfinished(&input) {
+            try!(eval_and_print(s, &input, &mut out, options));
+            input.clear()
+        }
+    }
+    // Unfinished input is evaluated anyway, to report the error.
+    if !input.is_empty() {
+        try!(eval_and_print(s, &input, &mut out, options))
+    }
+    out.flush()
+}
+
+/// Evaluates the forms in `source`, and replaces the value of the last
+/// one, which must be a closure, with its disassembly.
+fn disasm(s: &mut State, source: &str) -> Result<(), String> {
+    let forms = try!(compiler::read_all(&mut source.as_bytes().bytes().peekable())
+                         .map_err(|e| format!("read error: {:?}", e)));
+    let program = try!(compiler::compile_toplevel(&mut s.libraries, &mut s.macros, &forms, &[]));
+    try!(interp::load(s, &program));
+    try!(interp::call(s, 0));
+    let closure = s.heap.stack.pop().unwrap();
+    let text = try!(interp::disassemble(s, &closure).map_err(|_| "disasm: not a closure"));
+    let text = try!(text.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned()));
+    s.heap.stack.push(text);
+    Ok(())
+}
+
+/// Evaluates the forms in `source` with the profiler on, and pushes its
+/// report.  What the profiler had counted before is dropped.
+fn profile(s: &mut State, source: &str) -> Result<(), String> {
+    let (forms, lines) = try!(compiler::read_lines(source.as_bytes())
+                                  .map_err(|e| format!("read error: {:?}", e)));
+    let program = try!(compiler::compile_toplevel(&mut s.libraries, &mut s.macros, &forms, &lines));
+    try!(interp::load(s, &program));
+    interp::profile(s);
+    interp::set_profiling(s, true);
+    let res = interp::call(s, 0);
+    interp::set_profiling(s, false);
+    let report = interp::profile(s).to_string();
+    try!(res);
+    s.heap.stack.pop();
+    let report = try!(report.to_value(&mut s.heap).map_err(|_| "out of memory".to_owned()));
+    s.heap.stack.push(report);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use interp;
+    use super::*;
+
+    #[test]
+    fn recent_values_are_remembered() {
+        let mut s = interp::new();
+        for source in &["1", "(define x 2)", "x", "(cons 3 '())"] {
+            eval(&mut s, source).unwrap();
+            s.heap.stack.pop();
+        }
+        assert_eq!(s.history.len(), 3);
+        eval(&mut s, "(+ (car $it) (+ *2 *3))").unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(6));
+        eval(&mut s, "(history 2)").unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().car().unwrap().as_fixnum(), Ok(3));
+        assert!(eval(&mut s, "(history 6)").is_err());
+        s.history.set_length(&s.heap, 1);
+        assert_eq!(s.history.len(), 1);
+        assert_eq!(s.history.get(&s.heap, 1).unwrap().car().unwrap().as_fixnum(), Ok(3));
+    }
+
+    #[test]
+    fn macros_are_kept_from_one_input_to_the_next() {
+        let mut s = interp::new();
+        eval(&mut s, "(define-syntax swap!
+                        (syntax-rules () ((_ a b) (let ((t a)) (set! a b) (set! b t)))))")
+            .unwrap();
+        eval(&mut s, "(define x 1) (define y 2)").unwrap();
+        eval(&mut s, "(swap! x y) (cons x y)").unwrap();
+        let pair = s.heap.stack.pop().unwrap();
+        assert_eq!((pair.car().unwrap().as_fixnum(), pair.cdr().unwrap().as_fixnum()),
+                   (Ok(2), Ok(1)));
+        // An input that fails to compile leaves the macros as they were.
+        assert!(eval(&mut s, "(define-syntax swap! (syntax-rules ())) (if)").is_err());
+        eval(&mut s, "(swap! x y) x").unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(1));
+        // Redefining the name as a variable forgets the macro.
+        eval(&mut s, "(define (swap! a b) (list b a))").unwrap();
+        eval(&mut s, "(car (swap! 1 2))").unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(2));
+    }
+
+    #[test]
+    fn procedural_macros_are_kept_from_one_input_to_the_next() {
+        let mut s = interp::new();
+        eval(&mut s, "(define-macro (twice x) (list 'begin x x))").unwrap();
+        eval(&mut s, "(define-macro (thrice x) (list 'begin x (list 'twice x)))").unwrap();
+        eval(&mut s, "(define n 0) (thrice (set! n (+ n 1))) n").unwrap();
+        assert_eq!(s.heap.stack.pop().unwrap().as_fixnum(), Ok(3));
+    }
+
+    #[test]
+    fn closures_can_be_disassembled() {
+        let mut s = interp::new();
+        eval(&mut s, "(define (f x) (if x (lambda () \"yes\") 'no))").unwrap();
+        eval(&mut s, ",disasm f").unwrap();
+        let text = ::string::as_str(&s.heap.stack.pop().unwrap()).unwrap().to_owned();
+        assert!(text.contains("JumpIfFalse"), "{}", text);
+        assert!(text.contains("; -> "), "{}", text);
+        assert!(text.contains("; \"yes\""), "{}", text);
+        assert!(text.contains("Closure"), "{}", text);
+        assert_eq!(text.matches("function ").count(), 3, "{}", text);
+        assert!(eval(&mut s, ",disasm 1").is_err());
+        assert_eq!(s.history.len(), 0);
+    }
+
+    #[test]
+    fn profiles_put_the_costliest_functions_first() {
+        let mut s = interp::new();
+        eval(&mut s, ",profile (define (f n) (if (= n 0) (list n) (f (- n 1))))\n(f 100)").unwrap();
+        let text = ::string::as_str(&s.heap.stack.pop().unwrap()).unwrap().to_owned();
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines[0].ends_with("function"), "{}", text);
+        assert!(lines[1].ends_with("  f at line 1"), "{}", text);
+        assert!(lines.last().unwrap().ends_with("  total"), "{}", text);
+        assert!(!s.profiler.is_enabled());
+        assert_eq!(s.history.len(), 0);
+        assert!(eval(&mut s, ",profile (car 1)").is_err());
+        assert!(!s.profiler.is_enabled());
+    }
+
+    #[test]
+    fn the_opcode_reference_has_every_opcode() {
+        let mut s = interp::new();
+        eval(&mut s, ",opcodes").unwrap();
+        let text = ::string::as_str(&s.heap.stack.pop().unwrap()).unwrap().to_owned();
+        assert!(text.contains("| 42 | AddConst | slot | immediate | slot | dst |"), "{}", text);
+        eval(&mut s, "(length (opcode-table))").unwrap();
+        let rows = s.heap.stack.pop().unwrap().as_fixnum().unwrap();
+        assert_eq!(text.lines().count(), rows + 2);
+        eval(&mut s, "(vector-ref (car (opcode-table)) 2)").unwrap();
+        eval(&mut s, "(vector-ref (car (opcode-table)) 6)").unwrap();
+        let summary = s.heap.stack.pop().unwrap();
+        assert_eq!(::string::as_str(&summary), Some("dst = (cons src src2)"));
+        let src = s.heap.stack.pop().unwrap();
+        assert_eq!(s.heap.stack.len(), 0);
+        s.heap.intern("slot");
+        assert_eq!(s.heap.stack.pop().unwrap().get(), src.get());
+    }
+
+    #[test]
+    fn run_continues_unfinished_input_and_survives_errors() {
+        let mut s = interp::new();
+        let input = "(define x 2)\n(+ x\n 1)\n\n(car 1)\n\"a\nb\"\n(cons 1";
+        let options = ReplOptions {
+            banner: "hi\n".to_owned(),
+            prompt: "$ ".to_owned(),
+            continuation: "| ".to_owned(),
+            format_error: Box::new(|e| format!("oops ({})", e.len() > 0)),
+            backtraces: false,
+        };
+        let mut out = vec![];
+        run(&mut s, input.as_bytes(), &mut out, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out,
+                   "hi\n$ $ | 3\n$ $ oops (true)\n$ | \"a\\nb\"\n$ | oops (true)\n");
+        assert_eq!(s.heap.stack.len(), 0);
+        assert_eq!(s.history.len(), 2);
+    }
+
+    #[test]
+    fn run_writes_the_backtraces_of_errors() {
+        let mut s = interp::new();
+        let input = "(define (f x) (+ 1 (car x)))\n(define (g x)\n  (+ 1 (f x)))\n(g 1)\n(car";
+        let options = ReplOptions {
+            banner: String::new(),
+            prompt: String::new(),
+            continuation: String::new(),
+            ..ReplOptions::default()
+        };
+        let mut out = vec![];
+        run(&mut s, input.as_bytes(), &mut out, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert!(lines[0].starts_with("error: "), "{}", out);
+        assert_eq!(&lines[1..3], &["  in f at line 1", "  in g at line 1"]);
+        // The read error has no backtrace of its own.
+        assert!(lines.last().unwrap().starts_with("error: read error"), "{}", out);
+        assert_eq!(out.matches("  in g").count(), 1, "{}", out);
+    }
+}
@@ -0,0 +1,107 @@
+//! Shared constants: strings and symbol names that any number of `State`s,
+//! on any threads, can refer to without each having its own copy.
+//!
+//! A `SharedTable` is built once and never changes, so it is shared with an
+//! `Arc`.  A heap that uses one (see `Heap::share`) holds a reference to
+//! it, so the table outlives every value pointing into it.
+//!
+//! The strings have the layout of heap strings (see `string`), but live
+//! outside of every GC heap.  The GC only moves objects in the spaces it
+//! evacuates, and does not scan strings, so it leaves them alone; and
+//! `string-copy!` refuses to change shared strings, so no heap ever writes
+//! to them.  String literals that are in the table are not copied into the
+//! heap when a program is loaded.
+//!
+//! Symbols cannot be shared, since a symbol holds the value of a global of
+//! its `State`.  Their names can: a symbol table uses the table's copy of a
+//! name instead of copying it into its own arena.
+
+use std::collections::{HashMap, HashSet};
+use std::slice;
+
+use string;
+use value::{self, Value};
+
+/// An immutable table of shared strings and symbol names.
+#[derive(Debug, Default)]
+pub struct SharedTable {
+    /// The strings, back to back, each laid out like a heap string.
+    words: Vec<usize>,
+
+    /// Maps each string to its offset in `words`.
+    strings: HashMap<String, usize>,
+
+    /// The symbol names.
+    names: HashSet<Box<str>>,
+}
+
+impl SharedTable {
+    /// Creates a table of the strings `strings`, and the symbol names
+    /// `names`.  Duplicates are ignored.
+    pub fn new<'a, S, N>(strings: S, names: N) -> Self
+        where S: IntoIterator<Item = &'a str>,
+              N: IntoIterator<Item = &'a str>
+    {
+        let mut table = SharedTable::default();
+        for s in strings {
+            if !table.strings.contains_key(s) {
+                table.strings.insert(s.to_owned(), table.words.len());
+                string::write_words(s, &mut table.words)
+            }
+        }
+        table.names = names.into_iter().map(|name| name.to_owned().into_boxed_str()).collect();
+        table
+    }
+
+    /// The shared string equal to `s`, if there is one.
+    pub fn string(&self, s: &str) -> Option<Value> {
+        self.strings.get(s).map(|&offset| {
+            Value::new(&self.words[offset] as *const usize as usize | value::RUST_DATA_TAG)
+        })
+    }
+
+    /// The shared copy of the symbol name `name`, if there is one.
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.names.get(name).map(|name| &**name)
+    }
+
+    /// The strings, as a space that values may point into (for the debug
+    /// checks of the GC).
+    pub fn space(&self) -> &[Value] {
+        // `Value` is a `usize` in a `Cell`, but none of these are ever set.
+        unsafe { slice::from_raw_parts(self.words.as_ptr() as *const Value, self.words.len()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use alloc;
+    use interp::{self, run};
+    use value::Value;
+    use super::*;
+
+    #[test]
+    fn states_share_strings_and_names_without_copying_them() {
+        let table = Arc::new(SharedTable::new(vec!["shared"], vec!["greeting"]));
+        let mut states: Vec<_> = (0..2).map(|_| interp::new()).collect();
+        let mut strings = vec![];
+        for s in &mut states {
+            s.heap.share(table.clone());
+            let string = run(s, "(define greeting \"shared\") greeting").unwrap();
+            // The collector leaves shared strings where they are.
+            s.heap.stack.push(string);
+            alloc::collect(&mut s.heap);
+            strings.push(s.heap.stack.pop().unwrap().get());
+        }
+        assert_eq!(strings[0], strings[1]);
+        assert_eq!(strings[0], table.string("shared").unwrap().get());
+        assert_eq!(::string::as_str(&Value::new(strings[0])), Some("shared"));
+        let shared_name = table.name("greeting").unwrap().as_ptr();
+        let symbol = states[0].heap.symbol_table.intern("greeting");
+        let name = unsafe { (*symbol).name().as_ptr() };
+        assert_eq!(name, shared_name);
+        assert!(::string::as_str(&run(&mut states[0], "\"not shared\"").unwrap()).is_some());
+    }
+}
@@ -0,0 +1,187 @@
+//! Snapshots: a whole interpreter saved to a file, and restored into a fresh
+//! one, so that a long bootstrap only has to run once.
+//!
+//! A snapshot holds the heap image (see `alloc::image`), the code of every
+//! function loaded so far, which builtin libraries are registered, and the
+//! libraries defined with `define-library`.  It does not hold what only
+//! makes sense in the process that saved it: the stack (which must be empty
+//! when saving), handles, record printers and readable record types (which
+//! are held by persistent handles), the REPL history, and pending calls.
+//!
+//! Heap images contain raw words, so a snapshot can only be restored on a
+//! machine with the same word size and byte order as the one that saved it.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use alloc::Image;
+use binary::{Reader, write_str, write_u64};
+use bytecode;
+use compiler;
+use interp::{self, State};
+
+const MAGIC: &'static [u8; 4] = b"RSSN";
+const VERSION: u64 = 3;
+
+/// Describes the machine, which must be the same when restoring.
+fn machine() -> [u8; 2] {
+    [size_of!(usize) as u8, cfg!(target_endian = "little") as u8]
+}
+
+fn write_strings<'a, W, I>(w: &mut W, strings: I) -> io::Result<()>
+    where W: Write,
+          I: ExactSizeIterator<Item = &'a String>
+{
+    try!(write_u64(w, strings.len() as u64));
+    for s in strings {
+        try!(write_str(w, s))
+    }
+    Ok(())
+}
+
+fn write_exports<W: Write>(w: &mut W, exports: &compiler::Exports) -> io::Result<()> {
+    try!(write_u64(w, exports.len() as u64));
+    for (id, global) in exports {
+        try!(write_str(w, id));
+        try!(write_str(w, global))
+    }
+    Ok(())
+}
+
+/// Saves a snapshot of `s` to `w`.  Collects all garbage first.
+pub fn save<W: Write>(s: &mut State, w: &mut W) -> Result<(), String> {
+    if !s.heap.stack.is_empty() {
+        return Err("can't save a snapshot with values on the stack".to_owned())
+    }
+    let image = try!(s.heap.image());
+    write(s, &image, w).map_err(|e| e.to_string())
+}
+
+fn write<W: Write>(s: &State, image: &Image, w: &mut W) -> io::Result<()> {
+    try!(w.write_all(MAGIC));
+    try!(write_u64(w, VERSION));
+    try!(w.write_all(&machine()));
+    try!(write_u64(w, s.prelude_loaded as u64));
+    let loaded = s.builtins.loaded();
+    try!(write_u64(w, loaded.len() as u64));
+    for name in loaded {
+        try!(write_str(w, &name.join(" ")))
+    }
+    let defined = s.libraries.defined();
+    try!(write_u64(w, defined.len() as u64));
+    for (name, exports) in defined {
+        try!(write_strings(w, name.iter()));
+        try!(write_exports(w, exports))
+    }
+    try!(write_exports(w, &s.libraries.imports));
+    try!(compiler::write_code(w, &s.functions, &s.bytecode));
+    try!(write_u64(w, image.record_type_count as u64));
+    try!(write_strings(w, image.natives.iter()));
+    try!(write_u64(w, image.symbols.len() as u64));
+    for &(ref name, value, plist) in &image.symbols {
+        try!(write_str(w, name));
+        try!(write_u64(w, value as u64));
+        try!(write_u64(w, plist as u64))
+    }
+    try!(write_u64(w, image.words.len() as u64));
+    for &word in &image.words {
+        try!(write_u64(w, word as u64))
+    }
+    Ok(())
+}
+
+/// Saves a snapshot of `s` to the file `path`.
+pub fn save_file(s: &mut State, path: &Path) -> Result<(), String> {
+    let error = |e| format!("can't write {}: {}", path.display(), e);
+    let mut w = BufWriter::new(try!(File::create(path).map_err(&error)));
+    try!(save(s, &mut w));
+    w.flush().map_err(&error)
+}
+
+fn read_strings<R: Read>(r: &mut Reader<R>) -> Result<Vec<String>, String> {
+    (0..try!(r.usize(::std::usize::MAX))).map(|_| r.string()).collect()
+}
+
+fn read_exports<R: Read>(r: &mut Reader<R>) -> Result<compiler::Exports, String> {
+    (0..try!(r.usize(::std::usize::MAX)))
+        .map(|_| Ok((try!(r.string()), try!(r.string()))))
+        .collect()
+}
+
+/// Restores a snapshot saved by `save` into a new interpreter.
+pub fn restore<R: Read>(r: R) -> Result<State, String> {
+    let mut r = Reader::new(r, "snapshot");
+    let max = ::std::usize::MAX;
+    if &try!(r.bytes(4))[..] != MAGIC {
+        return Err(r.error("not a snapshot"))
+    }
+    let version = try!(r.u64());
+    if version != VERSION {
+        return Err(r.error(&format!("unsupported version {}", version)))
+    }
+    if try!(r.bytes(2)) != machine() {
+        return Err(r.error("saved on a different kind of machine"))
+    }
+    let mut s = interp::new();
+    s.prelude_loaded = try!(r.usize(1)) == 1;
+    for name in try!(read_strings(&mut r)) {
+        try!(s.builtins.set_loaded(&name.split(' ').collect::<Vec<_>>()))
+    }
+    for _ in 0..try!(r.usize(max)) {
+        let name = try!(read_strings(&mut r));
+        let exports = try!(read_exports(&mut r));
+        s.libraries.define(name, exports)
+    }
+    s.libraries.imports = try!(read_exports(&mut r));
+    let (functions, code) = try!(compiler::read_code(&mut r));
+    s.sources = vec![bytecode::Source::default(); functions.len()];
+    s.functions = functions;
+    s.bytecode = code;
+    let mut image = Image { record_type_count: try!(r.usize(max)), ..Image::default() };
+    image.natives = try!(read_strings(&mut r));
+    for _ in 0..try!(r.usize(max)) {
+        image.symbols.push((try!(r.string()), try!(r.usize(max)), try!(r.usize(max))))
+    }
+    for _ in 0..try!(r.usize(max)) {
+        image.words.push(try!(r.usize(max)))
+    }
+    try!(s.heap.restore(&image));
+    Ok(s)
+}
+
+/// Restores the snapshot saved in the file `path`.
+pub fn restore_file(path: &Path) -> Result<State, String> {
+    let file = try!(File::open(path).map_err(|e| format!("can't read {}: {}", path.display(), e)));
+    restore(BufReader::new(file)).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use interp::{self, run};
+    use value::Value;
+    use super::*;
+
+    #[test]
+    fn restored_snapshots_carry_on_where_they_were_saved() {
+        let mut s = interp::new();
+        run(&mut s,
+            "(define-library (counter) (export next) (import (rusty base))
+               (begin (define n 0) (define (next) (set! n (+ n 1)) n)))
+             (import (prefix (counter) counter-))
+             (define greeting \"hello world\")
+             (define pairs (list (cons 'a 1) (cons car (vector 1 2))))
+             (counter-next)").unwrap();
+        let mut snapshot = vec![];
+        save(&mut s, &mut snapshot).unwrap();
+        let mut s = restore(&snapshot[..]).unwrap();
+        assert_eq!(run(&mut s, "(counter-next)").unwrap().as_fixnum(), Ok(2));
+        assert_eq!(::string::as_str(&run(&mut s, "greeting").unwrap()), Some("hello world"));
+        assert_eq!(run(&mut s, "((car (car (cdr pairs))) (car pairs))").unwrap().get(),
+                   run(&mut s, "'a").unwrap().get());
+        assert_eq!(run(&mut s, "(cdr (assq 'a pairs))").unwrap().as_fixnum(), Ok(1));
+        assert!(restore(&snapshot[..snapshot.len() - 1]).is_err());
+        s.heap.stack.push(Value::new(0));
+        assert!(save(&mut s, &mut vec![]).is_err());
+    }
+}
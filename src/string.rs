@@ -17,41 +17,73 @@ pub struct SchemeStr {
 }
 
 unsafe impl api::SchemeValue for String {
-    fn to_value(&self, heap: &mut alloc::Heap) -> value::Value {
+    fn to_value(&self, heap: &mut alloc::Heap) -> Result<value::Value, alloc::OutOfMemory> {
         assert!(size_of!(SchemeStr) == 3 * size_of!(usize));
         let object_len: usize = ((size_of!(SchemeStr) + self.len() +
                           0b111) & !0b111)/size_of!(usize);
-        let (value_ptr, _) = heap.alloc_raw(object_len,
-                                                    value::HeaderTag::RustData);
+        let (value_ptr, final_len) = try!(heap.alloc_raw(object_len,
+                                                         value::HeaderTag::RustData));
         let ptr = value_ptr as usize | value::RUST_DATA_TAG;
         unsafe {
+            heap.finish_raw(final_len);
             let real_ptr = value_ptr as *mut usize;
             ptr::copy_nonoverlapping(
                 self.as_ptr(),
                 (value_ptr as usize + size_of!(SchemeStr)) as *mut u8,
                 self.len());
-            (*real_ptr) = (object_len * size_of!(usize)) |
-            value::HeaderTag::RustData as usize;
+            (*real_ptr) = object_len | value::HeaderTag::RustData as usize;
             (*real_ptr.offset(1)) = 0; // String
             (*real_ptr.offset(2)) = self.len();
         }
-        value::Value::new(ptr)
+        Ok(value::Value::new(ptr))
     }
     fn of_value(val: &value::Value) -> Result<Self, String> {
-        if val.raw_tag() != value::RUST_DATA_TAG {
-            return Err("Value is not a string".to_owned())
-        }
-        unsafe {
-            let scheme_str_ptr = val.as_ptr() as usize;
-            if *((scheme_str_ptr + size_of!(usize)) as *const u8) != 0 {
-                return Err("Value is not a string".to_owned())
-            }
-            let ptr = val.as_ptr() as *const u8;
-            Ok(str::from_utf8(
-                slice::from_raw_parts(
-                    ptr.offset(size_of!(SchemeStr) as isize),
-                    (*(ptr as *const SchemeStr)).len)).expect(
-                "String not valid UTF-8???").to_owned())
+        as_str(val).map(|s| s.to_owned()).ok_or_else(|| "Value is not a string".to_owned())
+    }
+}
+
+/// Appends the words of a string object holding `s` to `words`, for
+/// strings that are not on the GC heap (see `shared`).
+pub fn write_words(s: &str, words: &mut Vec<usize>) {
+    let start = words.len();
+    let object_len = ((size_of!(SchemeStr) + s.len() + 0b111) & !0b111) / size_of!(usize);
+    words.extend_from_slice(&[object_len | value::HeaderTag::RustData as usize, 0, s.len()]);
+    words.resize(start + object_len, 0);
+    unsafe {
+        ptr::copy_nonoverlapping(s.as_ptr(),
+                                 words.as_mut_ptr().offset(start as isize + 3) as *mut u8,
+                                 s.len())
+    }
+}
+
+/// Overwrites the bytes of the string `val` from byte offset `at` with
+/// `bytes`, which may be part of `val` itself.
+///
+/// Unsafe because the string must stay valid UTF-8, and must be on the GC
+/// heap: shared strings (see `shared`) are immutable.
+pub unsafe fn overwrite(val: &value::Value, at: usize, bytes: &[u8]) {
+    let len = as_str(val).expect("overwrite: not a string").len();
+    assert!(at + bytes.len() <= len);
+    let ptr = (val.as_ptr() as *mut u8).offset((size_of!(SchemeStr) + at) as isize);
+    ptr::copy(bytes.as_ptr(), ptr, bytes.len())
+}
+
+/// The contents of `val`, if it is a string.
+pub fn as_str(val: &value::Value) -> Option<&str> {
+    if val.raw_tag() != value::RUST_DATA_TAG {
+        return None
+    }
+    unsafe {
+        let ptr = val.as_ptr() as *const u8;
+        let header = *(ptr as *const usize);
+        if header & value::HEADER_TAG != value::HeaderTag::RustData as usize ||
+           (*(ptr as *const SchemeStr)).ty != 0 {
+            return None
         }
+        Some(str::from_utf8(
+            slice::from_raw_parts(
+                ptr.offset(size_of!(SchemeStr) as isize),
+                (*(ptr as *const SchemeStr)).len)).expect(
+            "String not valid UTF-8???"))
     }
 }
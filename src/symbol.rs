@@ -1,22 +1,39 @@
 use value;
+use std::borrow::Borrow;
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::cell::{UnsafeCell, Cell};
-use std::rc::Rc;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::slice;
+use std::str;
+use std::sync::Arc;
+
+use shared::SharedTable;
 
 pub type StackElement = usize;
 
 /// This struct stores a symbol.
 ///
 /// Symbols are never allocated on the GC heap.  They are instead stored
-/// on the Rust heap in `SymbolTable` objects, which contain a `HashMap<Rc<str>, Symbol>`
-/// that stores the actual symbols.  Each symbol contains a name.
+/// on the Rust heap in `SymbolTable` objects, which contain a `HashMap<Name, Symbol>`
+/// that stores the actual symbols.  Each symbol contains a name, whose bytes
+/// are in the symbol table's `NameArena`.
 ///
 /// Symbols always have tag `value::SYMBOL_TAG`.
+///
+/// A symbol has two slots.  Its `contents` are the value of the global
+/// variable of that name, so looking up a global is a load from the
+/// symbol, with no hashing (and `GlobalCache` saves even finding the
+/// symbol).  Its `plist` is its property list (see `builtins::symbols`).
+/// Both are roots of every collection, and a symbol with either is always
+/// alive.  Only a symbol with neither is reclaimed once nothing refers to
+/// it.
 #[derive(Debug)]
 pub struct Symbol {
     /// The name of the symbol
-    name: Rc<String>,
+    name: Name,
 
     /// A stack used for unspecified purposes in the compiler, such as scope handling.
     /// Must not contain Scheme values.
@@ -25,17 +42,24 @@ pub struct Symbol {
     /// The contents
     pub contents: UnsafeCell<value::Value>,
 
+    /// The property list: a list of properties alternating with their
+    /// values.
+    pub plist: UnsafeCell<value::Value>,
+
     /// Is this alive?
     pub alive: Cell<bool>,
 }
 
 impl Symbol {
-    pub fn name(&self) -> Rc<String> {
-        self.name.clone()
+    /// The name of the symbol.  Only valid until the next garbage
+    /// collection, which may compact the name arena.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
     }
-    pub fn new(name: Rc<String>) -> Self {
+    pub fn new(name: Name) -> Self {
         Symbol {
-            contents: UnsafeCell::new(value::Value::new(value::FALSE)),
+            contents: UnsafeCell::new(value::Value::new(value::UNBOUND)),
+            plist: UnsafeCell::new(value::Value::nil()),
             name: name,
             stack: vec![],
             alive: Cell::new(false),
@@ -43,43 +67,300 @@ impl Symbol {
     }
 }
 
+/// The name of a symbol: a reference to bytes in a `NameArena`.
+///
+/// Hashes and compares like the `str` it refers to.
+#[derive(Copy, Clone)]
+pub struct Name {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Name {
+    pub fn as_str(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(self.ptr, self.len)) }
+    }
+}
+
+impl ::std::fmt::Debug for Name {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl Borrow<str> for Name {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+/// The size of a chunk of a `NameArena`, in bytes.
+const NAME_CHUNK_SIZE: usize = 1 << 16;
+
+/// Storage for the bytes of symbol names.
+///
+/// Names are stored back to back in large chunks, rather than in one Rust
+/// allocation each.  The chunks are never reallocated, so a `Name` stays
+/// valid until the arena is compacted.  The names of dead symbols are not
+/// freed individually: they are just counted, and once they take up more
+/// space than the live names, `SymbolTable::fixup` compacts the arena.
+///
+/// Names in the shared table, if there is one, are not copied at all.
+#[derive(Debug, Default)]
+pub struct NameArena {
+    chunks: Vec<String>,
+
+    /// The shared constants, whose names are used instead of copies.
+    shared: Option<Arc<SharedTable>>,
+
+    /// Bytes used by the names of live symbols.
+    live: usize,
+
+    /// Bytes used by the names of dead symbols.
+    dead: usize,
+}
+
+impl NameArena {
+    /// Uses the names in `table` from now on.
+    pub fn share(&mut self, table: Arc<SharedTable>) {
+        self.shared = Some(table)
+    }
+
+    /// The shared copy of `name`, if there is one.
+    fn shared(&self, name: &str) -> Option<Name> {
+        self.shared
+            .as_ref()
+            .and_then(|table| table.name(name))
+            .map(|name| Name { ptr: name.as_ptr(), len: name.len() })
+    }
+
+    /// Copies `name` into the arena, unless it is shared.
+    pub fn alloc(&mut self, name: &str) -> Name {
+        if let Some(name) = self.shared(name) {
+            return name
+        }
+        let fits = self.chunks
+                       .last()
+                       .map_or(false, |chunk| chunk.capacity() - chunk.len() >= name.len());
+        if !fits {
+            self.chunks.push(String::with_capacity(cmp::max(NAME_CHUNK_SIZE, name.len())))
+        }
+        let chunk = self.chunks.last_mut().unwrap();
+        let start = chunk.len();
+        chunk.push_str(name);
+        self.live += name.len();
+        Name {
+            ptr: unsafe { chunk.as_ptr().offset(start as isize) },
+            len: name.len(),
+        }
+    }
+
+    /// Records that the name `name` is no longer used.
+    fn free(&mut self, name: &Name) {
+        if self.shared(name.as_str()).map_or(false, |shared| shared.ptr == name.ptr) {
+            return
+        }
+        self.live -= name.len;
+        self.dead += name.len
+    }
+
+    /// The number of bytes held by the arena.
+    pub fn capacity(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.capacity()).sum()
+    }
+}
+
 /// A symbol table.
 ///
-/// The symbol table consists of raw pointers to the strings,
-/// which are stored on the GC heap.
+/// The symbol table owns the symbols, and the arena holding their names.
+/// A symbol that the GC did not find alive is removed by `fixup`, so symbols
+/// are interned weakly; symbols with a value or a property list are always
+/// alive.
 ///
 /// WARNING: keep this in sync with the GC!  This code does manual relocation
 /// of heap pointers!
 #[derive(Debug)]
 pub struct SymbolTable {
-    pub contents: HashMap<Rc<String>, Box<Symbol>>,
+    pub contents: HashMap<Name, Box<Symbol>>,
+    pub names: NameArena,
+
+    /// The number of times `fixup` has removed symbols (see `GlobalCache`).
+    generation: usize,
 }
 
 impl SymbolTable {
+    /// Interns `name`, returning its symbol.
+    pub fn intern(&mut self, name: &str) -> *mut Symbol {
+        if let Some(symbol) = self.contents.get_mut(name) {
+            return &mut **symbol
+        }
+        let name = self.names.alloc(name);
+        let symbol = self.contents.entry(name).or_insert_with(|| Box::new(Symbol::new(name)));
+        &mut **symbol
+    }
+
     pub fn fixup(&mut self) {
         let mut vec = vec![];
         for (i, sym) in &self.contents {
             if sym.alive.get() {
                 sym.alive.set(false)
             } else {
-                vec.push(i.clone())
+                vec.push(*i)
             }
         }
         // Loop through the dead objects and remove them from the hash table.
+        if !vec.is_empty() {
+            self.generation += 1
+        }
         for i in vec {
-            match self.contents.entry(i.clone()) {
-                Entry::Occupied(o) => drop(o.remove()),
+            match self.contents.entry(i) {
+                Entry::Occupied(o) => {
+                    self.names.free(&i);
+                    drop(o.remove())
+                }
                 Entry::Vacant(_) => {
                     bug!("SymbolTable::fixup: entry \
                           to be deleted is already vacant")
                 }
             }
         }
+        if self.names.dead > self.names.live && self.names.dead >= NAME_CHUNK_SIZE {
+            self.compact()
+        }
+    }
+
+    /// Copies the names of all symbols into a fresh arena, releasing the
+    /// space used by the names of dead symbols.
+    fn compact(&mut self) {
+        debug!("Compacting symbol names: {} live bytes, {} dead",
+               self.names.live,
+               self.names.dead);
+        let mut names = NameArena { shared: self.names.shared.clone(), ..NameArena::default() };
+        let mut contents = HashMap::with_capacity(self.contents.len());
+        for (_, mut symbol) in self.contents.drain() {
+            symbol.name = names.alloc(symbol.name.as_str());
+            contents.insert(symbol.name, symbol);
+        }
+        self.contents = contents;
+        // The old arena must outlive the copying above.
+        drop(mem::replace(&mut self.names, names))
+    }
+}
+
+/// The per-site caches of the symbols of global variables.
+///
+/// A `LoadConstantGlobal` site always loads the same global, but finding
+/// its symbol means going through the function table and the constants
+/// vector of the running closure.  So each site remembers the symbol it
+/// found, and only a miss (or an unbound global) takes the slow path.  The
+/// cell of a global is its symbol, so redefining the global does not
+/// change what a site should load, but a symbol that dies and is interned
+/// again is a new cell: entries are only valid while the symbol table is
+/// in the generation they were found in.
+#[derive(Debug, Default)]
+pub struct GlobalCache {
+    /// Indexed by program counter.  Each entry is a symbol (null if the
+    /// site has not been used) and the generation it was found in.
+    sites: Vec<(*const Symbol, usize)>,
+}
+
+impl GlobalCache {
+    /// The value of the global cached for `site`, if there is one and it
+    /// is bound.
+    pub fn lookup(&self, site: usize, table: &SymbolTable) -> Option<value::Value> {
+        match self.sites.get(site) {
+            Some(&(symbol, generation)) if !symbol.is_null() && generation == table.generation => {
+                let contents = unsafe { &*(*symbol).contents.get() };
+                if contents.get() == value::UNBOUND {
+                    None
+                } else {
+                    Some(contents.clone())
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Remembers that `site` loads the global `name`, if it is a symbol of
+    /// `table`.
+    pub fn insert(&mut self, site: usize, name: &value::Value, table: &SymbolTable) {
+        if let value::Kind::Symbol(symbol) = name.kind() {
+            if self.sites.len() <= site {
+                self.sites.resize(site + 1, (::std::ptr::null(), 0))
+            }
+            self.sites[site] = (symbol as *const Symbol, table.generation)
+        }
     }
 }
 
 impl Default for SymbolTable {
     fn default() -> Self {
-        SymbolTable { contents: HashMap::new() }
+        SymbolTable {
+            contents: HashMap::new(),
+            names: NameArena::default(),
+            generation: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning_is_idempotent() {
+        let mut table = SymbolTable::default();
+        let foo = table.intern("foo");
+        assert_eq!(table.intern("foo"), foo);
+        assert!(table.intern("bar") != foo);
+        assert_eq!(table.names.live, 6);
+        assert_eq!(unsafe { (*foo).name() }, "foo");
+    }
+
+    #[test]
+    fn dead_names_are_compacted_away() {
+        let mut table = SymbolTable::default();
+        let keep = table.intern("keep");
+        for i in 0..10000 {
+            table.intern(&format!("symbol-{}", i));
+        }
+        let capacity = table.names.capacity();
+        unsafe { (*keep).alive.set(true) };
+        table.fixup();
+        assert_eq!(table.contents.len(), 1);
+        assert!(table.names.capacity() < capacity);
+        assert_eq!(table.names.dead, 0);
+        let keep = table.contents.get("keep").map(|symbol| &**symbol as *const Symbol);
+        assert_eq!(keep.map(|keep| unsafe { (*keep).name() }), Some("keep"));
+        assert!(table.contents.get("symbol-0").is_none());
+    }
+
+    #[test]
+    fn global_caches_forget_dead_symbols() {
+        let mut table = SymbolTable::default();
+        let mut cache = GlobalCache::default();
+        let x = table.intern("x");
+        unsafe { *(*x).contents.get() = value::Value::new(4) };
+        cache.insert(3, &value::Value::new(x as usize | value::SYMBOL_TAG), &table);
+        assert_eq!(cache.lookup(3, &table).map(|x| x.get()), Some(4));
+        assert!(cache.lookup(2, &table).is_none());
+        unsafe { *(*x).contents.get() = value::Value::new(8) };
+        assert_eq!(cache.lookup(3, &table).map(|x| x.get()), Some(8));
+        table.fixup();
+        assert!(cache.lookup(3, &table).is_none());
     }
 }
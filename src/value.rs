@@ -7,13 +7,34 @@
 //! | Type      | Representation |
 //! |-----------|----------------|
 //! |Fixnum     | As an immediate pointer, with tag 0 or 4.|
-//! |Flonums    | As a pointer to a (boxed) floating-point number, with tag 1.|
+//! |Flonums    | As a `RustData` object holding the bits of an `f64` (see `flonum`).|
+//! |Ratios     | As a `RustData` object holding a numerator and a denominator (see `ratio`).|
 //! |Pairs| As a pointer to a 2-tuple, with pointer tag 3. |
 //! |Arrays| As an untagged, aligned pointer to a Rust slice. |
 //! |Records| As a pointer to a Rust slice, with a special header for the GC that indicates how it should be marked.|
 //! |Resources  | As a pointer into a 3-tuple, consisting of a GC header, a pointer to a `struct` that contains an object ID and custom equality, hashing, and other functions, and a pointer into memory not managed by the GC. |
+//! |Booleans and other special immediates| As a word below `0x100` with tag 3, such as `FALSE` and `TRUE`.|
+//! |Characters | As an immediate with the low byte `CHAR_TAG`, the code point above it, and the top bit set.|
+//!
+//! A fixnum `n` is `n << 2`, so both tag 0 and tag 4 (`NUM_TAG_2`) are for
+//! fixnums.  The special immediates (`#f`, `#t`, `()`, the EOF object, the
+//! unspecified value, and the markers `UNBOUND` and `BROKEN_WEAK`) are
+//! instead the words `8 * k + 3`: they have the tag of vectors, but no
+//! vector is at an address below `0x100`, so `immediatep` tells them
+//! apart, and `kind` is `Kind::Immediate` for them.
+//!
+//! A character is the unused special immediate `CHAR_TAG` (`8 * 7 + 3`),
+//! with its code point shifted left by 8 bits and the top bit of the word
+//! set.  No heap address has the top bit set, so characters are immediates
+//! too, and are compared, like the other immediates, by their words: equal
+//! characters are `eq?`.
+//!
+//! The only false value is `#f`, which `is_true` and the conditional jumps
+//! of the interpreter test for by comparing a word with `FALSE`.  Every
+//! true value that a primitive returns is `#t`, as `Value::boolean` makes.
 
 use std::cell::Cell;
+use builtins;
 use symbol;
 
 /// A Scheme value.
@@ -119,10 +140,10 @@ pub struct Instruction {
     pub dst: u8,
 }
 
-/// The Scheme immediate `#f`
+/// The Scheme immediate `#f`, the only false value
 pub const FALSE: usize = 0x3;
 
-/// The Scheme immediate `#t`
+/// The Scheme immediate `#t`, the canonical true value
 pub const TRUE: usize = 0xB;
 
 /// The Scheme empty list `()`
@@ -134,6 +155,19 @@ pub const EOF: usize = 0x1B;
 /// The Scheme object representing an unspecified value
 pub const UNSPECIFIED: usize = 0x23;
 
+/// The contents of a symbol with no global binding.  Never visible to
+/// Scheme code.
+pub const UNBOUND: usize = 0x2B;
+
+/// The referent of a weak box whose referent has been collected.
+pub const BROKEN_WEAK: usize = 0x33;
+
+/// The low byte of a character (see `Value::character`).
+pub const CHAR_TAG: usize = 0x3B;
+
+/// The top bit of a word, which is set in characters.
+const CHAR_BIT: usize = !(::std::usize::MAX >> 1);
+
 pub struct SymbolValue {
     backing: *mut Value,
 }
@@ -143,6 +177,17 @@ pub enum Kind {
     Vector(*mut Vector),
     Fixnum(usize),
     Symbol(*mut symbol::Symbol),
+    Native(*const builtins::Native),
+
+    /// A closure.
+    Function(*mut Function),
+
+    /// A heap object whose contents are not values, such as a string, a
+    /// flonum, or a resource, by a pointer to its header.
+    RustData(*const usize),
+
+    /// A special immediate, such as `FALSE` or `NIL`.
+    Immediate(usize),
 }
 
 /// An object containing compiled Scheme bytecode.  Subject to garbage collection.
@@ -174,11 +219,11 @@ impl Value {
     }
 
     /// The heap size of `self`, not including `self`.  Returns `None` for
-    /// immediate objects.
+    /// immediate objects and native procedures, which are not on the heap.
     pub fn size(&self) -> Option<usize> {
         if self.tag() == Tags::Symbol {
             Some(0)
-        } else if self.immediatep() {
+        } else if self.immediatep() || self.tag() == Tags::RustFunc {
             None
         } else {
             Some(unsafe { *((self.contents.get() & !0b111) as *const usize) & !HEADER_TAG })
@@ -221,34 +266,85 @@ impl Value {
     pub fn new(contents: usize) -> Self {
         Value { contents: Cell::new(contents) }
     }
+
+    /// `#t` or `#f`.
+    pub fn boolean(b: bool) -> Self {
+        Value::new(if b { TRUE } else { FALSE })
+    }
+
+    /// Is `self` anything but `#f`?
+    pub fn is_true(&self) -> bool {
+        self.get() != FALSE
+    }
+
+    /// The character `c`.
+    pub fn character(c: char) -> Self {
+        Value::new(CHAR_BIT | (c as usize) << 8 | CHAR_TAG)
+    }
+
+    /// The character that `self` is, if it is one.
+    pub fn as_char(&self) -> Option<char> {
+        let x = self.get();
+        if x & (CHAR_BIT | 0xFF) == CHAR_BIT | CHAR_TAG {
+            ::std::char::from_u32(((x & !CHAR_BIT) >> 8) as u32)
+        } else {
+            None
+        }
+    }
+
+    /// The empty list.
+    pub fn nil() -> Self {
+        Value::new(NIL)
+    }
+
+    /// The EOF object.
+    pub fn eof() -> Self {
+        Value::new(EOF)
+    }
+
+    /// The value of expressions whose value is unspecified, such as
+    /// `(set! x 1)`.
+    pub fn unspecified() -> Self {
+        Value::new(UNSPECIFIED)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.get() == NIL
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.get() == EOF
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        self.get() == UNSPECIFIED
+    }
     pub fn set(&self, other: Self) -> () {
         self.contents.set(other.contents.get())
     }
     pub fn get(&self) -> usize {
         self.contents.get()
     }
+    /// Sets element `index` of the vector `self` to `other`.  The caller
+    /// must call the write barrier.
     pub fn array_set(&self, index: usize, other: &Value) -> Result<(), String> {
         match self.kind() {
             Kind::Vector(vec) => unsafe { Self::raw_array_set(vec, index, other.clone()) },
             _ => Err("can't index a non-vector".to_owned()),
         }
     }
+
+    /// Sets element `index` of `vec` to `other`.  Fails if `vec` is a
+    /// record, or `index` is out of bounds.
     pub unsafe fn raw_array_set(vec: *mut Vector,
                                 index: usize,
                                 other: Value)
                                 -> Result<(), String> {
-        if (*vec).header >= index {
-            Err((if (*vec).header & HEADER_TAG == 0 {
-                    "index out of bounds"
-                } else {
-                    "can't index a non-record"
-                })
-                .to_owned())
-        } else {
-            (*((vec as usize + index) as *const Value)).set(other);
-            Ok(())
-        }
+        let element = try!(Self::array_element(vec, index));
+        Ok((*element).set(other))
     }
+
+    /// A pointer to element `index` of the vector `self`.
     pub fn array_get(&self, index: usize) -> Result<*const Self, String> {
         match self.kind() {
             Kind::Vector(vec) => unsafe { Self::raw_array_get(vec, index) },
@@ -256,27 +352,36 @@ impl Value {
         }
     }
 
+    /// A pointer to element `index` of `vec`.  Fails if `vec` is a record,
+    /// or `index` is out of bounds.
     pub unsafe fn raw_array_get(vec: *const Vector, index: usize) -> Result<*const Self, String> {
-        let index = index + 2;
-        if (*vec).header >= index {
-            Err((if (*vec).header & HEADER_TAG == 0 {
-                    "index out of bounds"
-                } else {
-                    "can't index a non-record"
-                })
-                .to_owned())
-        } else {
-            Ok((vec as usize + index) as *const Value)
+        Self::array_element(vec, index).map(|element| element as *const Self)
+    }
+
+    /// A pointer to element `index` of `vec`.  The length in the header of
+    /// a vector counts the header and the word after it, which come before
+    /// the elements (see `alloc::Heap::alloc_vector`), one word each.
+    unsafe fn array_element(vec: *const Vector, index: usize) -> Result<*mut Self, String> {
+        let header = (*vec).header;
+        if header & HEADER_TAG != HeaderTag::Vector as usize {
+            return Err("can't index a non-vector".to_owned())
         }
+        if index >= (header & !HEADER_TAG) - 2 {
+            return Err("index out of bounds".to_owned())
+        }
+        Ok((vec as usize + (index + 2) * SIZEOF_PTR) as *mut Self)
     }
 
     pub fn kind(&self) -> Kind {
         match self.tag() {
             Tags::Pair => Kind::Pair(unsafe { self.as_ptr() } as *mut Pair),
+            Tags::Vector if self.immediatep() => Kind::Immediate(self.get()),
             Tags::Vector => Kind::Vector(unsafe { self.as_ptr() } as *mut Vector),
             Tags::Num | Tags::Num2 => Kind::Fixnum(self.contents.get() >> 2),
             Tags::Symbol => Kind::Symbol(unsafe { self.as_ptr() } as *mut symbol::Symbol),
-            _ => unimplemented!(),
+            Tags::RustFunc => Kind::Native(unsafe { self.as_ptr() } as *const builtins::Native),
+            Tags::Function => Kind::Function(unsafe { self.as_ptr() } as *mut Function),
+            Tags::RustData => Kind::RustData(unsafe { self.as_ptr() } as *const usize),
         }
     }
 
@@ -309,7 +414,6 @@ pub unsafe fn float_val(val: &Value) -> f64 {
 
 pub struct HashTable;
 pub struct IOPort;
-pub struct RustData;
 
 // Same set used by Femtolisp
 /// The tag of `fixnum`s
@@ -324,8 +428,8 @@ pub const FUNCTION_TAG: usize = 0b010;
 /// The tag of Scheme vectors, records, and closures.
 pub const VECTOR_TAG: usize = 0b011;
 
-/// The tag of non-`fixnum` immediates, such as the empty list,
-/// end-of-file object, the undefined value, and characters.
+/// The other tag of `fixnum`s, whose bit 2 is the lowest bit of the
+/// number.  The special immediates have `VECTOR_TAG` instead.
 pub const NUM_TAG_2: usize = 0b100;
 
 /// The tag of `RustData` – Rust values stored on the Scheme heap.
@@ -435,15 +539,15 @@ impl Value {
     pub fn pairp(&self) -> bool {
         self.tag() == Tags::Pair
     }
-    #[inline(always)]
     pub fn flonump(&self) -> bool {
-        unimplemented!()
+        ::flonum::as_f64(self).is_some()
     }
 
     // n#[inline(always)]
     pub fn immediatep(&self) -> bool {
         let val = self.get();
-        val & 0b11 == 0 || val <= 0xFF // special immediates
+        val & 0b11 == 0 || val <= 0xFF || // special immediates
+        val & (CHAR_BIT | 0xFF) == CHAR_BIT | CHAR_TAG
     }
 }
 
@@ -452,3 +556,105 @@ macro_rules! size_of {
         ::std::mem::size_of::<$ty>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::Heap;
+    use super::*;
+
+    /// A xorshift generator, so that the fuzzed indices are the same on
+    /// every run.
+    fn indices(mut state: u64) -> Box<Iterator<Item = usize>> {
+        Box::new((0..).map(move |_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        }))
+    }
+
+    #[test]
+    fn vectors_are_indexed_by_element() {
+        let mut heap = Heap::new(1 << 10);
+        for len in 0..20 {
+            heap.stack.clear();
+            heap.stack.extend((0..len).map(|i| Value::new(i << 2)));
+            heap.alloc_vector(0, len).unwrap();
+            let vector = heap.stack.pop().unwrap();
+            let small = 0..len + 3;
+            let fuzzed = indices(len as u64 + 1).take(100).flat_map(|i| vec![i, i % (len + 1)]);
+            for index in small.chain(fuzzed) {
+                let got = vector.array_get(index).map(|element| unsafe { (*element).get() });
+                let new = Value::new(index << 3);
+                if index < len {
+                    assert_eq!(got, Ok(index << 2));
+                    vector.array_set(index, &new).unwrap();
+                    assert_eq!(::equal::vector_elements(&vector).unwrap()[index].get(), new.get());
+                    vector.array_set(index, &Value::new(index << 2)).unwrap();
+                } else {
+                    assert_eq!(got, Err("index out of bounds".to_owned()));
+                    assert_eq!(vector.array_set(index, &new),
+                               Err("index out of bounds".to_owned()));
+                }
+            }
+            let elements: Vec<_> = ::equal::vector_elements(&vector)
+                                       .unwrap()
+                                       .iter()
+                                       .map(|x| x.get())
+                                       .collect();
+            assert_eq!(elements, (0..len).map(|i| i << 2).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn only_vectors_can_be_indexed() {
+        let mut heap = Heap::new(1 << 8);
+        heap.stack.push(Value::nil());
+        heap.stack.push(Value::new(1 << 2));
+        heap.alloc_record(0, 2).unwrap();
+        heap.alloc_pair(0, 1).unwrap();
+        for x in &heap.stack[2..] {
+            assert_eq!(x.array_get(0).map(|_| ()), Err("can't index a non-vector".to_owned()));
+            assert_eq!(x.array_set(0, &Value::nil()),
+                       Err("can't index a non-vector".to_owned()));
+        }
+    }
+
+    #[test]
+    fn special_immediates_are_not_objects() {
+        let specials = [FALSE, TRUE, NIL, EOF, UNSPECIFIED, UNBOUND, BROKEN_WEAK];
+        for (i, &special) in specials.iter().enumerate() {
+            let x = Value::new(special);
+            assert_eq!(special, 8 * i + 3);
+            assert!(x.immediatep() && !x.fixnump() && !x.pairp());
+            assert_eq!(x.size(), None);
+            match x.kind() {
+                Kind::Immediate(word) => assert_eq!(word, special),
+                _ => panic!("0x{:x} is not an immediate", special),
+            }
+            assert!(x.car().is_err());
+            assert_eq!(x.array_get(0).map(|_| ()), Err("can't index a non-vector".to_owned()));
+            assert_eq!(x.is_true(), special != FALSE);
+        }
+        assert_eq!(Value::boolean(true).get(), TRUE);
+        assert_eq!(Value::boolean(false).get(), FALSE);
+        assert!(Value::new(0).is_true());
+    }
+
+    #[test]
+    fn characters_are_immediates() {
+        for &c in &['\0', 'a', 'λ', '\u{10FFFF}'] {
+            let x = Value::character(c);
+            assert_eq!(x.as_char(), Some(c));
+            assert!(x.immediatep() && x.size().is_none());
+            match x.kind() {
+                Kind::Immediate(word) => assert_eq!(word, x.get()),
+                _ => panic!("{:?} is not an immediate", c),
+            }
+        }
+        for &word in &[FALSE, NIL, CHAR_TAG, 0x6100 | CHAR_TAG] {
+            assert_eq!(Value::new(word).as_char(), None);
+        }
+    }
+}
+